@@ -0,0 +1,195 @@
+// A decoded value that can be changed and written back out with everything that wasn't changed
+// kept exactly as it was read. `encode` sorts dict keys and drops anything odd, which is right
+// for data we make but not for data we're passing along: a torrent's info dict is hashed as it
+// was written, whatever its key order, so re-encoding one that wasn't canonical changes the
+// torrent. Here, a value that's still equal to what was decoded is copied from the input, and
+// only the dicts and lists on the way down to a change are encoded again.
+// List items are matched up by index, so inserting or removing anywhere but the end re-encodes
+// everything after it.
+use std::collections::BTreeMap;
+
+use crate::{BencodeValue, DecodeError, DecodeErrorKind, decode_values, encode_to};
+
+// Where a value is in the input, and where its items are
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub(crate) struct Span {
+    start: usize,
+    end: usize,
+    children: SpanChildren,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub(crate) enum SpanChildren {
+    None,
+    List(Vec<Span>),
+    Dict(BTreeMap<Vec<u8>, Span>),
+}
+
+impl Span {
+    pub(crate) fn new(start: usize, end: usize, children: SpanChildren) -> Self {
+        Span {
+            start,
+            end,
+            children,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Document {
+    buf: Vec<u8>,
+    span: Span,
+    // As decoded, to tell what was changed
+    original: BencodeValue,
+    value: BencodeValue,
+}
+
+impl Document {
+    // `buf` has to be exactly one value
+    pub fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+        let (mut values, mut spans, _) = decode_values(buf, false, true)?;
+        if values.len() != 1 {
+            let pos = spans.get(1).map_or(0, |span| span.start);
+            return Err(DecodeError::new(DecodeErrorKind::Empty, pos, None));
+        }
+        let value = values.pop().unwrap();
+        Ok(Document {
+            buf: buf.to_vec(),
+            span: spans.pop().unwrap(),
+            original: value.clone(),
+            value,
+        })
+    }
+
+    pub fn root(&self) -> &BencodeValue {
+        &self.value
+    }
+
+    pub fn root_mut(&mut self) -> &mut BencodeValue {
+        &mut self.value
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode_to(&mut |bytes: &[u8]| out.extend_from_slice(bytes));
+        out
+    }
+
+    pub fn encode_to(&self, out: &mut impl FnMut(&[u8])) {
+        preserving(&self.value, &self.original, &self.span, &self.buf, out);
+    }
+
+    // One of the root dict's values the way `encode` writes it, e.g. a torrent's info dict to
+    // hash
+    pub fn encode_entry(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let value = self.value.get(key)?;
+        let mut out = vec![];
+        let mut write = |bytes: &[u8]| out.extend_from_slice(bytes);
+        match (self.original.get(key), &self.span.children) {
+            (Some(original), SpanChildren::Dict(spans)) if spans.contains_key(key) => {
+                preserving(value, original, &spans[key], &self.buf, &mut write)
+            }
+            _ => encode_to(value, &mut write),
+        }
+        Some(out)
+    }
+}
+
+fn preserving(
+    value: &BencodeValue,
+    original: &BencodeValue,
+    span: &Span,
+    buf: &[u8],
+    out: &mut impl FnMut(&[u8]),
+) {
+    if value == original {
+        out(&buf[span.start..span.end]);
+        return;
+    }
+    match (value, original, &span.children) {
+        (BencodeValue::Dict(dict), BencodeValue::Dict(originals), SpanChildren::Dict(spans)) => {
+            out(b"d");
+            for (key, value) in dict {
+                encode_to(&BencodeValue::ByteStr(key.clone()), out);
+                match (originals.get(key), spans.get(key)) {
+                    (Some(original), Some(span)) => preserving(value, original, span, buf, out),
+                    _ => encode_to(value, out),
+                }
+            }
+            out(b"e");
+        }
+        (BencodeValue::List(items), BencodeValue::List(originals), SpanChildren::List(spans)) => {
+            out(b"l");
+            for (i, value) in items.iter().enumerate() {
+                match (originals.get(i), spans.get(i)) {
+                    (Some(original), Some(span)) => preserving(value, original, span, buf, out),
+                    _ => encode_to(value, out),
+                }
+            }
+            out(b"e");
+        }
+        _ => encode_to(value, out),
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::{decode, encode};
+
+    // Nothing in order: the root's keys, the info dict's keys and a duplicate
+    const TORRENT: &[u8] =
+        b"d8:announce5:http:4:infod4:name1:x6:lengthi5e4:name1:ye7:comment2:hi3:fooli1ei2eee";
+
+    #[test]
+    fn test_unchanged() {
+        let doc = Document::decode(TORRENT).unwrap();
+        assert_eq!(doc.encode(), TORRENT);
+        assert_ne!(encode(doc.root()), TORRENT);
+        assert_eq!(
+            doc.encode_entry(b"info").unwrap(),
+            b"d4:name1:x6:lengthi5e4:name1:ye"
+        );
+        assert_eq!(doc.encode_entry(b"missing"), None);
+
+        assert_eq!(
+            Document::decode(b"i1ei2e").unwrap_err().kind,
+            DecodeErrorKind::Empty
+        );
+        assert!(Document::decode(b"").is_err());
+    }
+
+    #[test]
+    fn test_changed() {
+        let mut doc = Document::decode(TORRENT).unwrap();
+        doc.root_mut().insert(b"announce", "udp://tracker:80");
+        doc.root_mut().get_mut(b"foo").unwrap().push(3i64);
+        // The changed values and the root around them are written out anew, the info dict isn't
+        assert_eq!(
+            doc.encode(),
+            b"d8:announce16:udp://tracker:807:comment2:hi3:fooli1ei2ei3ee4:infod4:name1:x6:lengthi5e4:name1:yee"
+        );
+
+        doc.root_mut()
+            .get_mut(b"info")
+            .unwrap()
+            .insert(b"private", 1i64);
+        let encoded = doc.encode();
+        assert_eq!(
+            doc.encode_entry(b"info").unwrap(),
+            b"d6:lengthi5e4:name1:y7:privatei1ee"
+        );
+        assert_eq!(decode(&encoded).unwrap(), [doc.root().clone()]);
+
+        // Back as it was is as good as untouched
+        doc.root_mut().insert(b"announce", "http:");
+        doc.root_mut()
+            .get_mut(b"foo")
+            .unwrap()
+            .as_list_mut()
+            .unwrap()
+            .pop();
+        doc.root_mut().get_mut(b"info").unwrap().remove(b"private");
+        assert_eq!(doc.encode(), TORRENT);
+    }
+}
@@ -0,0 +1,81 @@
+use crate::BencodeValue;
+
+pub fn encode(value: &BencodeValue) -> Vec<u8> {
+    let mut buf = Vec::new();
+    encode_to(value, &mut |bytes: &[u8]| buf.extend_from_slice(bytes));
+    buf
+}
+
+// Streams the encoding out in pieces instead of building a buffer, e.g. straight into a hasher
+pub fn encode_to(value: &BencodeValue, out: &mut impl FnMut(&[u8])) {
+    match value {
+        BencodeValue::Int(i) => {
+            out(b"i");
+            out(i.to_string().as_bytes());
+            out(b"e");
+        }
+        BencodeValue::ByteStr(bytes) => encode_bytestr(bytes, out),
+        BencodeValue::List(items) => {
+            out(b"l");
+            for item in items {
+                encode_to(item, out);
+            }
+            out(b"e");
+        }
+        BencodeValue::Dict(dict) => {
+            // BTreeMap iterates in key order, which is exactly the sorting the spec requires
+            out(b"d");
+            for (key, val) in dict {
+                encode_bytestr(key, out);
+                encode_to(val, out);
+            }
+            out(b"e");
+        }
+    }
+}
+
+fn encode_bytestr(bytes: &[u8], out: &mut impl FnMut(&[u8])) {
+    out(bytes.len().to_string().as_bytes());
+    out(b":");
+    out(bytes);
+}
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::decode;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_encode_int() {
+        assert_eq!(encode(&BencodeValue::Int(-42)), b"i-42e");
+        assert_eq!(encode(&BencodeValue::Int(0)), b"i0e");
+    }
+
+    #[test]
+    fn test_encode_bstr() {
+        assert_eq!(encode(&BencodeValue::ByteStr(b"hey".to_vec())), b"3:hey");
+        assert_eq!(encode(&BencodeValue::ByteStr(vec![])), b"0:");
+    }
+
+    #[test]
+    fn test_encode_dict_sorted() {
+        let dict = BencodeValue::Dict(BTreeMap::from([
+            (b"zoo".to_vec(), BencodeValue::Int(1)),
+            (
+                b"abc".to_vec(),
+                BencodeValue::List(vec![BencodeValue::Int(2)]),
+            ),
+        ]));
+
+        assert_eq!(encode(&dict), b"d3:abcli2ee3:zooi1ee");
+    }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        let str = "d4:userld4:name4:John3:agei30eee4:metad5:admin0:ee";
+        let ret = decode(str.as_bytes()).unwrap();
+
+        // Keys in the input above aren't sorted, so compare against the decoded value instead
+        assert_eq!(decode(&encode(&ret[0])).unwrap(), ret);
+    }
+}
@@ -0,0 +1,135 @@
+use std::fmt;
+
+// What was wrong with the input
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum DecodeErrorKind {
+    DuplicateStartToken,
+    InvalidToken(char),
+    // A byte string length with no ':' after it
+    InvalidLength,
+    ByteStrEOF,
+    NoEndToken,
+    NoStartToken,
+    // An 'e' with no list or dict to end
+    InvalidEndToken,
+    // A key without a value
+    InvalidDict,
+    Empty,
+    LeadingZero,
+    NegativeZero,
+    Overflow,
+    // Lists and dicts nested past `MAX_DEPTH`
+    TooDeep,
+}
+
+impl fmt::Display for DecodeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeErrorKind::DuplicateStartToken => write!(f, "a second start token"),
+            DecodeErrorKind::InvalidToken(c) => write!(f, "unexpected {:?}", c),
+            DecodeErrorKind::InvalidLength => write!(f, "a length with no ':' after it"),
+            DecodeErrorKind::ByteStrEOF => write!(f, "a byte string running past the end"),
+            DecodeErrorKind::NoEndToken => write!(f, "no end token"),
+            DecodeErrorKind::NoStartToken => write!(f, "no start token"),
+            DecodeErrorKind::InvalidEndToken => write!(f, "an end token with nothing to end"),
+            DecodeErrorKind::InvalidDict => write!(f, "a key without a value"),
+            DecodeErrorKind::Empty => write!(f, "nothing"),
+            DecodeErrorKind::LeadingZero => write!(f, "a leading zero"),
+            DecodeErrorKind::NegativeZero => write!(f, "a negative zero"),
+            DecodeErrorKind::Overflow => write!(f, "a number too big for 64 bits"),
+            DecodeErrorKind::TooDeep => write!(f, "lists and dicts nested too deep"),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ValueKind {
+    Int,
+    ByteStr,
+    List,
+    Dict,
+}
+
+impl fmt::Display for ValueKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValueKind::Int => write!(f, "an int"),
+            ValueKind::ByteStr => write!(f, "a byte string"),
+            ValueKind::List => write!(f, "a list"),
+            ValueKind::Dict => write!(f, "a dict"),
+        }
+    }
+}
+
+// One step down from the root: a dict key or a list index
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum PathSegment {
+    Key(Vec<u8>),
+    Index(usize),
+}
+
+// Where in a document something is, written the way you'd reach it in code: info.files[3].length
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Path(pub Vec<PathSegment>);
+
+impl Path {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, segment) in self.0.iter().enumerate() {
+            match segment {
+                PathSegment::Key(key) if i == 0 => write!(f, "{}", String::from_utf8_lossy(key))?,
+                PathSegment::Key(key) => write!(f, ".{}", String::from_utf8_lossy(key))?,
+                PathSegment::Index(index) => write!(f, "[{}]", index)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct DecodeError {
+    pub kind: DecodeErrorKind,
+    // Offset into the input where decoding stopped
+    pub pos: usize,
+    // The value that was being decoded, None when the problem was between values
+    pub parsing: Option<ValueKind>,
+    // The dict keys and list indices leading to it, empty at the top level
+    pub path: Path,
+}
+
+impl DecodeError {
+    pub(crate) fn new(kind: DecodeErrorKind, pos: usize, parsing: Option<ValueKind>) -> Self {
+        DecodeError {
+            kind,
+            pos,
+            parsing,
+            path: Path::default(),
+        }
+    }
+
+    pub(crate) fn at(mut self, path: Path) -> Self {
+        self.path = path;
+        self
+    }
+}
+
+// e.g. "a leading zero in an int while parsing info.files[3].length at byte 1042"
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(parsing) = self.parsing {
+            write!(f, " in {}", parsing)?;
+        }
+        if !self.path.is_empty() {
+            write!(f, " while parsing {}", self.path)?;
+        }
+        write!(f, " at byte {}", self.pos)
+    }
+}
+
+impl std::error::Error for DecodeError {}
@@ -0,0 +1,42 @@
+// Random values for the fuzz targets in fuzz/. Nesting stops well short of `MAX_DEPTH`, so
+// anything made here encodes to something `decode` takes back.
+use std::collections::BTreeMap;
+use std::ops::ControlFlow;
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+use crate::BencodeValue;
+
+const DEPTH: usize = 8;
+const MAX_ITEMS: u32 = 16;
+
+impl<'a> Arbitrary<'a> for BencodeValue {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        value(u, DEPTH)
+    }
+}
+
+fn value(u: &mut Unstructured, depth: usize) -> Result<BencodeValue> {
+    // No lists or dicts at the bottom
+    let kinds = if depth == 0 { 2 } else { 4 };
+    Ok(match u.choose_index(kinds)? {
+        0 => BencodeValue::Int(u.arbitrary()?),
+        1 => BencodeValue::ByteStr(u.arbitrary()?),
+        2 => {
+            let mut items = vec![];
+            u.arbitrary_loop(None, Some(MAX_ITEMS), |u| {
+                items.push(value(u, depth - 1)?);
+                Ok(ControlFlow::Continue(()))
+            })?;
+            BencodeValue::List(items)
+        }
+        _ => {
+            let mut dict = BTreeMap::new();
+            u.arbitrary_loop(None, Some(MAX_ITEMS), |u| {
+                dict.insert(u.arbitrary()?, value(u, depth - 1)?);
+                Ok(ControlFlow::Continue(()))
+            })?;
+            BencodeValue::Dict(dict)
+        }
+    })
+}
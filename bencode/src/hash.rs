@@ -0,0 +1,79 @@
+// Hashing a value's canonical encoding, which is how info-hashes and DHT item targets are defined.
+// The encoding is streamed into the hasher, so hashing a big info dict doesn't need a copy of it.
+use sha1::Sha1;
+use sha2::Sha256;
+use sha2::digest::Digest as _;
+
+use crate::BencodeValue;
+use crate::encode::encode_to;
+
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum HashAlgo {
+    // v1 info-hashes, BEP 44 targets
+    Sha1,
+    // v2 info-hashes (BEP 52)
+    Sha256,
+}
+
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum Digest {
+    Sha1([u8; 20]),
+    Sha256([u8; 32]),
+}
+
+impl Digest {
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Digest::Sha1(hash) => hash,
+            Digest::Sha256(hash) => hash,
+        }
+    }
+}
+
+pub fn hash_value(value: &BencodeValue, algo: HashAlgo) -> Digest {
+    match algo {
+        HashAlgo::Sha1 => {
+            let mut hasher = Sha1::new();
+            encode_to(value, &mut |bytes: &[u8]| hasher.update(bytes));
+            Digest::Sha1(hasher.finalize().into())
+        }
+        HashAlgo::Sha256 => {
+            let mut hasher = Sha256::new();
+            encode_to(value, &mut |bytes: &[u8]| hasher.update(bytes));
+            Digest::Sha256(hasher.finalize().into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::{decode, encode};
+
+    #[test]
+    fn test_matches_buffered_hash() {
+        let value = &decode(b"d4:infod6:lengthi5e4:name1:xe3:numli1ei-2eee").unwrap()[0];
+        let buf = encode(value);
+
+        assert_eq!(
+            hash_value(value, HashAlgo::Sha1).as_bytes(),
+            Sha1::digest(&buf).as_slice()
+        );
+        assert_eq!(
+            hash_value(value, HashAlgo::Sha256).as_bytes(),
+            Sha256::digest(&buf).as_slice()
+        );
+    }
+
+    #[test]
+    fn test_known_digest() {
+        // SHA-1 of "12:Hello World!", the immutable item example from BEP 44
+        let value = BencodeValue::ByteStr(b"Hello World!".to_vec());
+        let Digest::Sha1(hash) = hash_value(&value, HashAlgo::Sha1) else {
+            panic!("expected a SHA-1 digest");
+        };
+
+        assert_eq!(hash[..4], [0xe5, 0xf9, 0x6f, 0x6f]);
+        assert_eq!(hash[19], 0xdb);
+    }
+}
@@ -0,0 +1,911 @@
+use std::collections::BTreeMap;
+
+mod document;
+mod encode;
+mod error;
+#[cfg(feature = "arbitrary")]
+mod fuzzing;
+#[cfg(feature = "hash")]
+mod hash;
+#[cfg(test)]
+mod proptests;
+
+use document::{Span, SpanChildren};
+
+pub use document::Document;
+pub use encode::{encode, encode_to};
+pub use error::{DecodeError, DecodeErrorKind, Path, PathSegment, ValueKind};
+#[cfg(feature = "hash")]
+pub use hash::{Digest, HashAlgo, hash_value};
+
+// Deeper than any real document goes. Values are dropped and encoded recursively, so without a
+// limit a few kilobytes of "l" would be enough to overflow the stack
+pub const MAX_DEPTH: usize = 512;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum BencodeValue {
+    Int(i64),
+    ByteStr(Vec<u8>),
+    List(Vec<BencodeValue>),
+    Dict(BTreeMap<Vec<u8>, BencodeValue>),
+}
+
+impl BencodeValue {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            BencodeValue::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            BencodeValue::ByteStr(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[BencodeValue]> {
+        match self {
+            BencodeValue::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, BencodeValue>> {
+        match self {
+            BencodeValue::Dict(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    // Shorthand for looking up a key when this value is a dict
+    pub fn get(&self, key: &[u8]) -> Option<&BencodeValue> {
+        self.as_dict()?.get(key)
+    }
+
+    pub fn as_list_mut(&mut self) -> Option<&mut Vec<BencodeValue>> {
+        match self {
+            BencodeValue::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict_mut(&mut self) -> Option<&mut BTreeMap<Vec<u8>, BencodeValue>> {
+        match self {
+            BencodeValue::Dict(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut BencodeValue> {
+        self.as_dict_mut()?.get_mut(key)
+    }
+
+    // Sets `key` when this value is a dict, and does nothing otherwise. Returns the old value
+    pub fn insert(&mut self, key: &[u8], value: impl Into<BencodeValue>) -> Option<BencodeValue> {
+        self.as_dict_mut()?.insert(key.to_vec(), value.into())
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<BencodeValue> {
+        self.as_dict_mut()?.remove(key)
+    }
+
+    // Appends when this value is a list, and does nothing otherwise
+    pub fn push(&mut self, value: impl Into<BencodeValue>) {
+        if let Some(list) = self.as_list_mut() {
+            list.push(value.into());
+        }
+    }
+
+    // Builder versions of the above, for putting values together in one expression:
+    // BencodeValue::dict().with(b"announce", "http://tracker/announce").with(b"private", 1i64)
+    pub fn dict() -> Self {
+        BencodeValue::Dict(BTreeMap::new())
+    }
+
+    pub fn list() -> Self {
+        BencodeValue::List(vec![])
+    }
+
+    pub fn with(mut self, key: &[u8], value: impl Into<BencodeValue>) -> Self {
+        self.insert(key, value);
+        self
+    }
+
+    pub fn without(mut self, key: &[u8]) -> Self {
+        self.remove(key);
+        self
+    }
+
+    pub fn with_item(mut self, value: impl Into<BencodeValue>) -> Self {
+        self.push(value);
+        self
+    }
+}
+
+impl From<i64> for BencodeValue {
+    fn from(i: i64) -> Self {
+        BencodeValue::Int(i)
+    }
+}
+
+impl From<&[u8]> for BencodeValue {
+    fn from(bytes: &[u8]) -> Self {
+        BencodeValue::ByteStr(bytes.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for BencodeValue {
+    fn from(bytes: Vec<u8>) -> Self {
+        BencodeValue::ByteStr(bytes)
+    }
+}
+
+impl From<&str> for BencodeValue {
+    fn from(s: &str) -> Self {
+        BencodeValue::ByteStr(s.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for BencodeValue {
+    fn from(s: String) -> Self {
+        BencodeValue::ByteStr(s.into_bytes())
+    }
+}
+
+impl From<Vec<BencodeValue>> for BencodeValue {
+    fn from(items: Vec<BencodeValue>) -> Self {
+        BencodeValue::List(items)
+    }
+}
+
+fn decode_int(enc_str: &[u8], start_pos: usize) -> Result<(i64, usize), DecodeError> {
+    // All bencoded ints start have format `i<base_10_int>e`
+    let error = |kind, pos| DecodeError::new(kind, pos, Some(ValueKind::Int));
+    let mut pos: usize = start_pos;
+    let mut started = false;
+    let mut ended = false;
+    let mut digits = 0;
+    let mut value: i64 = 0;
+    let mut negative = false;
+
+    while pos < enc_str.len() {
+        match enc_str[pos] {
+            b'i' => {
+                if started {
+                    return Err(error(DecodeErrorKind::DuplicateStartToken, pos));
+                }
+                started = true;
+                pos += 1;
+            }
+            b'0'..=b'9' => {
+                // Only 0 itself starts with a 0, and there's no -0
+                if digits > 0 && value == 0 {
+                    return Err(error(DecodeErrorKind::LeadingZero, pos));
+                }
+                if negative && digits == 0 && enc_str[pos] == b'0' {
+                    return Err(error(DecodeErrorKind::NegativeZero, pos));
+                }
+                // Built up on the side of the sign, so that i64::MIN fits
+                let digit = (enc_str[pos] - b'0') as i64;
+                value = value
+                    .checked_mul(10)
+                    .and_then(|v| match negative {
+                        true => v.checked_sub(digit),
+                        false => v.checked_add(digit),
+                    })
+                    .ok_or_else(|| error(DecodeErrorKind::Overflow, pos))?;
+                digits += 1;
+                pos += 1;
+            }
+            b'-' => {
+                // Can't have more than one sign (e.g. double negative), and it has to come first
+                if negative || digits > 0 {
+                    return Err(error(
+                        DecodeErrorKind::InvalidToken(enc_str[pos] as char),
+                        pos,
+                    ));
+                }
+                negative = true;
+                pos += 1;
+            }
+            b'e' => {
+                if digits == 0 {
+                    return Err(error(DecodeErrorKind::Empty, pos));
+                }
+                ended = true;
+                pos += 1;
+                break;
+            }
+            _ => {
+                return Err(error(
+                    DecodeErrorKind::InvalidToken(enc_str[pos] as char),
+                    pos,
+                ));
+            }
+        }
+    }
+
+    if !ended {
+        return Err(error(DecodeErrorKind::NoEndToken, pos));
+    }
+
+    Ok((value, pos - start_pos))
+}
+
+fn decode_bytestr(enc_str: &[u8], start_pos: usize) -> Result<(Vec<u8>, usize), DecodeError> {
+    let error = |kind, pos| DecodeError::new(kind, pos, Some(ValueKind::ByteStr));
+    // Step 1: parse the length of the byte string
+    let mut pos: usize = start_pos;
+    let mut str_sz: usize = 0;
+    let mut valid_len = false;
+
+    while pos < enc_str.len() {
+        match enc_str[pos] {
+            b'0'..=b'9' => {
+                if pos > start_pos && str_sz == 0 {
+                    return Err(error(DecodeErrorKind::LeadingZero, pos));
+                }
+
+                let digit = enc_str[pos] - b'0';
+                str_sz = str_sz
+                    .checked_mul(10)
+                    .and_then(|sz| sz.checked_add(digit as usize))
+                    .ok_or_else(|| error(DecodeErrorKind::Overflow, pos))?;
+                pos += 1;
+            }
+            b':' => {
+                valid_len = true;
+                pos += 1;
+                break;
+            }
+            _ => {
+                return Err(error(
+                    DecodeErrorKind::InvalidToken(enc_str[pos] as char),
+                    pos,
+                ));
+            }
+        }
+    }
+
+    if !valid_len {
+        return Err(error(DecodeErrorKind::InvalidLength, pos));
+    }
+
+    // Early return for zero-length string
+    if str_sz == 0 {
+        return Ok((vec![], pos - start_pos));
+    }
+
+    // Step 2: parse the byte string
+    if str_sz > enc_str.len() - pos {
+        return Err(error(DecodeErrorKind::ByteStrEOF, pos));
+    }
+
+    let ret = enc_str[pos..pos + str_sz].to_vec();
+    pos += str_sz;
+
+    Ok((ret, pos - start_pos))
+}
+
+enum ScopeType {
+    Root,
+    List,
+    Dict,
+}
+
+struct Scope {
+    stype: ScopeType,
+    items: Vec<BencodeValue>,
+    // Where `items` are in the input, when asked to record that
+    spans: Vec<Span>,
+    start: usize,
+}
+
+impl Scope {
+    fn new(stype: ScopeType, start: usize) -> Self {
+        Scope {
+            stype,
+            items: vec![],
+            spans: vec![],
+            start,
+        }
+    }
+
+    fn push(&mut self, item: BencodeValue, span: Option<Span>) {
+        self.items.push(item);
+        self.spans.extend(span);
+    }
+
+    // Where the next item goes: a list index, or the key just read when a dict is waiting for its
+    // value
+    fn next_segment(&self) -> Option<PathSegment> {
+        match self.stype {
+            ScopeType::Root => None,
+            ScopeType::List => Some(PathSegment::Index(self.items.len())),
+            ScopeType::Dict => match self.items.last() {
+                Some(BencodeValue::ByteStr(key)) if self.items.len() % 2 == 1 => {
+                    Some(PathSegment::Key(key.clone()))
+                }
+                _ => None,
+            },
+        }
+    }
+
+    fn kind(&self) -> Option<ValueKind> {
+        match self.stype {
+            ScopeType::Root => None,
+            ScopeType::List => Some(ValueKind::List),
+            ScopeType::Dict => Some(ValueKind::Dict),
+        }
+    }
+}
+
+// The path to the next value in the innermost scope
+fn path(stack: &[Scope]) -> Path {
+    Path(stack.iter().filter_map(Scope::next_segment).collect())
+}
+
+pub fn decode(buf: &[u8]) -> Result<Vec<BencodeValue>, DecodeError> {
+    decode_values(buf, false, false).map(|(values, _, _)| values)
+}
+
+// Decodes the value at the front of `buf` and returns it with its encoded length, for protocols
+// that follow a bencoded header with raw data (e.g. BEP 9 metadata pieces)
+pub fn decode_prefix(buf: &[u8]) -> Result<(BencodeValue, usize), DecodeError> {
+    let (mut values, _, len) = decode_values(buf, true, false)?;
+    values
+        .pop()
+        .map(|value| (value, len))
+        .ok_or(DecodeError::new(DecodeErrorKind::Empty, 0, None))
+}
+
+// With `record`, also where each value is in `buf`, see `Document`
+fn decode_values(
+    buf: &[u8],
+    first_only: bool,
+    record: bool,
+) -> Result<(Vec<BencodeValue>, Vec<Span>, usize), DecodeError> {
+    let mut pos: usize = 0;
+    let leaf = |start, len| record.then(|| Span::new(start, start + len, SpanChildren::None));
+
+    // Maintain a stack for dealing with lists and dicts
+    let mut stack: Vec<Scope> = vec![Scope::new(ScopeType::Root, 0)];
+
+    while pos < buf.len() {
+        match buf[pos] {
+            b'i' => {
+                let (item, item_len) = decode_int(buf, pos).map_err(|err| err.at(path(&stack)))?;
+                let span = leaf(pos, item_len);
+                stack
+                    .last_mut()
+                    .unwrap()
+                    .push(BencodeValue::Int(item), span);
+                pos += item_len;
+            }
+            b'0'..=b'9' => {
+                let (item, item_len) =
+                    decode_bytestr(buf, pos).map_err(|err| err.at(path(&stack)))?;
+                let span = leaf(pos, item_len);
+                stack
+                    .last_mut()
+                    .unwrap()
+                    .push(BencodeValue::ByteStr(item), span);
+                pos += item_len;
+            }
+            b'l' | b'd' => {
+                // The root is on the stack too
+                if stack.len() > MAX_DEPTH {
+                    let kind = DecodeErrorKind::TooDeep;
+                    return Err(DecodeError::new(kind, pos, None).at(path(&stack)));
+                }
+                // Start a "new scope"
+                let stype = match buf[pos] {
+                    b'l' => ScopeType::List,
+                    _ => ScopeType::Dict,
+                };
+                stack.push(Scope::new(stype, pos));
+                pos += 1;
+            }
+            b'e' => {
+                // We'll only see 'e' when we're in a scope (parsing a list or dict) because the
+                // 'e' that occurs in int parsing is consumed by the int decoding function
+                // So if we're not in a scope, it's an error. Otherwise we simply exit the scope
+                // TODO: what does this mean for dicts
+                let scope = stack.pop().unwrap();
+                let (item, children) = match scope.stype {
+                    ScopeType::List => (
+                        BencodeValue::List(scope.items),
+                        SpanChildren::List(scope.spans),
+                    ),
+                    ScopeType::Dict => {
+                        if !scope.items.len().is_multiple_of(2) {
+                            // TODO: change this to MissingKey and MissingValue errors
+                            let err = DecodeError::new(
+                                DecodeErrorKind::InvalidDict,
+                                pos,
+                                Some(ValueKind::Dict),
+                            );
+                            // Popped, so the dict is the next value of the scope it's in
+                            return Err(err.at(path(&stack)));
+                        }
+
+                        // Iterate over pairs and create a BTreeMap from them. A repeated key
+                        // keeps its last value, and its span goes with it
+                        let mut dict_item: BTreeMap<Vec<u8>, BencodeValue> = BTreeMap::new();
+                        let mut dict_spans = BTreeMap::new();
+                        let mut spans = scope.spans.into_iter().skip(1).step_by(2);
+                        let mut iter = scope.items.into_iter();
+                        while let (Some(key_item), Some(val_item)) = (iter.next(), iter.next()) {
+                            let span = spans.next();
+                            if let BencodeValue::ByteStr(key) = key_item {
+                                if let Some(span) = span {
+                                    dict_spans.insert(key.clone(), span);
+                                }
+                                dict_item.insert(key, val_item);
+                            }
+                        }
+
+                        // TODO: check for lexicographic order after map is created
+                        (
+                            BencodeValue::Dict(dict_item),
+                            SpanChildren::Dict(dict_spans),
+                        )
+                    }
+                    ScopeType::Root => {
+                        let kind = DecodeErrorKind::InvalidEndToken;
+                        return Err(DecodeError::new(kind, pos, None));
+                    }
+                };
+                let span = record.then(|| Span::new(scope.start, pos + 1, children));
+                stack.last_mut().unwrap().push(item, span);
+                pos += 1;
+            }
+            _ => {
+                let kind = DecodeErrorKind::InvalidToken(buf[pos] as char);
+                return Err(DecodeError::new(kind, pos, None).at(path(&stack)));
+            }
+        }
+
+        if first_only && stack.len() == 1 && !stack[0].items.is_empty() {
+            break;
+        }
+    }
+
+    // If there's still unclosed scopes, we're missing an end token somewhere
+    // We want to end parsing with just the root scope
+    if stack.len() > 1 {
+        let scope = stack.pop().unwrap();
+        let err = DecodeError::new(DecodeErrorKind::NoEndToken, pos, scope.kind());
+        return Err(err.at(path(&stack)));
+    }
+
+    let root = stack.pop().unwrap();
+    Ok((root.items, root.spans, pos))
+}
+
+#[cfg(test)]
+mod integration_tests {
+    use super::*;
+
+    #[test]
+    fn test_real_torrent_file() {
+        use std::fs;
+
+        let torrent_bytes = fs::read("tests/fixtures/sample.torrent").unwrap();
+
+        let result = decode(&torrent_bytes).expect("Failed to decode torrent file");
+
+        assert_eq!(result.len(), 1);
+        assert_eq!(
+            result[0].get(b"announce"),
+            Some(&BencodeValue::ByteStr(
+                b"http://tracker.example.com:6969/announce".to_vec()
+            ))
+        );
+
+        let info = result[0].get(b"info").unwrap();
+        assert_eq!(info.get(b"length"), Some(&BencodeValue::Int(25000)));
+        assert_eq!(
+            info.get(b"pieces")
+                .and_then(|p| p.as_bytes())
+                .unwrap()
+                .len(),
+            40
+        );
+
+        // Re-encoding a canonical file must give back the exact same bytes
+        assert_eq!(encode(&result[0]), torrent_bytes);
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn failure<T>(result: Result<T, DecodeError>) -> Option<(DecodeErrorKind, usize)> {
+        result.err().map(|err| (err.kind, err.pos))
+    }
+
+    #[test]
+    fn test_dict_ok() {
+        let str = "d3:heyi69ee";
+        let ret = decode(str.as_bytes()).unwrap();
+
+        assert_eq!(
+            ret,
+            vec![BencodeValue::Dict(BTreeMap::from([(
+                b"hey".to_vec(),
+                BencodeValue::Int(69)
+            ),]))]
+        )
+    }
+
+    #[test]
+    fn test_dict_nested() {
+        let str = "d3:heyd3:food3:bari420eeee";
+        let ret = decode(str.as_bytes()).unwrap();
+
+        assert_eq!(
+            ret,
+            vec![BencodeValue::Dict(BTreeMap::from([(
+                b"hey".to_vec(),
+                BencodeValue::Dict(BTreeMap::from([(
+                    b"foo".to_vec(),
+                    BencodeValue::Dict(BTreeMap::from(
+                        [(b"bar".to_vec(), BencodeValue::Int(420)),]
+                    ))
+                ),]))
+            ),]))]
+        )
+    }
+
+    #[test]
+    fn test_complex_nested() {
+        // The big kahuna!
+        // Represents:
+        // {
+        //   "user": [
+        //     {"name": "John", "age": 30, "scores": [100, [95, 88]]}
+        //   ],
+        //   "meta": {"admin": "", "1": "y", "active": "1"}
+        // }
+        let str = "d4:userld4:name4:John3:agei30e6:scoresli100eli95ei88eeeee4:metad5:admin0:1:11:y6:active1:1ee";
+        let ret = decode(str.as_bytes()).unwrap();
+
+        assert_eq!(
+            ret,
+            vec![BencodeValue::Dict(BTreeMap::from([
+                (
+                    b"user".to_vec(),
+                    BencodeValue::List(vec![BencodeValue::Dict(BTreeMap::from([
+                        (b"name".to_vec(), BencodeValue::ByteStr(b"John".to_vec())),
+                        (b"age".to_vec(), BencodeValue::Int(30)),
+                        (
+                            b"scores".to_vec(),
+                            BencodeValue::List(vec![
+                                BencodeValue::Int(100),
+                                BencodeValue::List(vec![
+                                    BencodeValue::Int(95),
+                                    BencodeValue::Int(88),
+                                ]),
+                            ])
+                        ),
+                    ])),])
+                ),
+                (
+                    b"meta".to_vec(),
+                    BencodeValue::Dict(BTreeMap::from([
+                        (b"admin".to_vec(), BencodeValue::ByteStr(b"".to_vec())),
+                        (b"1".to_vec(), BencodeValue::ByteStr(b"y".to_vec())),
+                        (b"active".to_vec(), BencodeValue::ByteStr(b"1".to_vec())),
+                    ]))
+                ),
+            ]))]
+        );
+    }
+
+    #[test]
+    fn test_list_ok() {
+        let str = "li420ei69ee";
+        let ret = decode(str.as_bytes()).unwrap();
+
+        assert_eq!(
+            ret,
+            vec![BencodeValue::List(vec![
+                BencodeValue::Int(420),
+                BencodeValue::Int(69),
+            ])]
+        )
+    }
+    #[test]
+    fn test_list_empty() {
+        let str = "le";
+        let ret = decode(str.as_bytes()).unwrap();
+
+        assert_eq!(ret, vec![BencodeValue::List(vec![]),])
+    }
+
+    #[test]
+    fn test_list_nested() {
+        let str = "llli420eeee";
+        let ret = decode(str.as_bytes()).unwrap();
+
+        assert_eq!(
+            ret,
+            vec![BencodeValue::List(vec![BencodeValue::List(vec![
+                BencodeValue::List(vec![BencodeValue::Int(420),])
+            ])])]
+        )
+    }
+
+    #[test]
+    fn test_list_complex() {
+        let str = "lli420el3:heyeel5:Helloee";
+        let ret = decode(str.as_bytes()).unwrap();
+
+        assert_eq!(
+            ret,
+            vec![BencodeValue::List(vec![
+                BencodeValue::List(vec![
+                    BencodeValue::Int(420),
+                    BencodeValue::List(vec![BencodeValue::ByteStr("hey".as_bytes().to_vec())])
+                ]),
+                BencodeValue::List(vec![BencodeValue::ByteStr("Hello".as_bytes().to_vec())])
+            ])]
+        )
+    }
+
+    #[test]
+    fn test_int_ok() {
+        let str = "i1234567890e";
+        let (item, pos) = decode_int(str.as_bytes(), 0).unwrap();
+
+        assert_eq!(pos, 12);
+        assert_eq!(item, 1234567890);
+    }
+
+    #[test]
+    fn test_int_neg() {
+        let str = "i-125e";
+        let (item, pos) = decode_int(str.as_bytes(), 0).unwrap();
+
+        assert_eq!(pos, 6);
+        assert_eq!(item, -125);
+    }
+
+    #[test]
+    fn test_int_double_neg() {
+        let str = "i--69e";
+        let result = decode_int(str.as_bytes(), 0);
+
+        assert_eq!(
+            failure(result),
+            Some((DecodeErrorKind::InvalidToken('-'), 2))
+        )
+    }
+
+    #[test]
+    fn test_int_empty() {
+        let str = "ie";
+        let result = decode_int(str.as_bytes(), 0);
+
+        assert_eq!(failure(result), Some((DecodeErrorKind::Empty, 1)));
+    }
+
+    #[test]
+    fn test_int_invalid() {
+        let str = "iBe";
+        let result = decode_int(str.as_bytes(), 0);
+
+        assert_eq!(
+            failure(result),
+            Some((DecodeErrorKind::InvalidToken('B'), 1))
+        );
+    }
+
+    #[test]
+    fn test_int_noend() {
+        let str = "i420";
+        let result = decode_int(str.as_bytes(), 0);
+
+        assert_eq!(failure(result), Some((DecodeErrorKind::NoEndToken, 4)));
+    }
+
+    #[test]
+    fn test_int_duplicate_start() {
+        let str = "ii420";
+        let result = decode_int(str.as_bytes(), 0);
+
+        assert_eq!(
+            failure(result),
+            Some((DecodeErrorKind::DuplicateStartToken, 1))
+        );
+    }
+
+    #[test]
+    fn test_int_large() {
+        // Multi-gigabyte file lengths are common in torrents
+        let str = "i8589934592e";
+        let (item, _) = decode_int(str.as_bytes(), 0).unwrap();
+
+        assert_eq!(item, 8589934592);
+    }
+
+    #[test]
+    fn test_int_overflow() {
+        let str = "i99999999999999999999e";
+        let result = decode_int(str.as_bytes(), 0);
+
+        assert_eq!(failure(result), Some((DecodeErrorKind::Overflow, 19)));
+    }
+
+    #[test]
+    fn test_int_edges() {
+        let cases: &[(&[u8], DecodeErrorKind, usize)] = &[
+            (b"i01e", DecodeErrorKind::LeadingZero, 2),
+            (b"i00e", DecodeErrorKind::LeadingZero, 2),
+            (b"i-0e", DecodeErrorKind::NegativeZero, 2),
+            (b"i-01e", DecodeErrorKind::NegativeZero, 2),
+            (b"i1-2e", DecodeErrorKind::InvalidToken('-'), 2),
+            (b"i-e", DecodeErrorKind::Empty, 2),
+            (b"i-9223372036854775809e", DecodeErrorKind::Overflow, 20),
+        ];
+        for (buf, kind, pos) in cases {
+            assert_eq!(failure(decode_int(buf, 0)), Some((*kind, *pos)));
+        }
+        assert_eq!(decode_int(b"i0e", 0), Ok((0, 3)));
+        assert_eq!(decode_int(b"i-10e", 0), Ok((-10, 5)));
+        let min = encode(&BencodeValue::Int(i64::MIN));
+        assert_eq!(decode_int(&min, 0), Ok((i64::MIN, min.len())));
+    }
+
+    #[test]
+    fn test_bstr_edges() {
+        assert_eq!(
+            failure(decode_bytestr(b"01:a", 0)),
+            Some((DecodeErrorKind::LeadingZero, 1))
+        );
+        assert_eq!(
+            failure(decode_bytestr(b"99999999999999999999999:a", 0)),
+            Some((DecodeErrorKind::Overflow, 19))
+        );
+        assert_eq!(
+            failure(decode_bytestr(b"18446744073709551615:a", 0)),
+            Some((DecodeErrorKind::ByteStrEOF, 21))
+        );
+    }
+
+    #[test]
+    fn test_depth() {
+        let nested = |depth| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        assert!(decode(&nested(MAX_DEPTH)).is_ok());
+        let err = decode(&nested(MAX_DEPTH + 1)).unwrap_err();
+        assert_eq!(err.kind, DecodeErrorKind::TooDeep);
+        assert_eq!(err.pos, MAX_DEPTH);
+        // Deep enough to overflow the stack when dropped, had it decoded
+        assert!(decode(&vec![b'l'; 1 << 20]).is_err());
+    }
+
+    #[test]
+    fn test_bstr_ok() {
+        let str = "3:hey";
+        let (item, pos) = decode_bytestr(str.as_bytes(), 0).unwrap();
+
+        assert_eq!(pos, 5);
+        assert_eq!(item, b"hey");
+    }
+
+    #[test]
+    fn test_bstr_long() {
+        let str = "44:The quick brown fox jumped over the lazy dog";
+        let (item, pos) = decode_bytestr(str.as_bytes(), 0).unwrap();
+
+        assert_eq!(pos, 47);
+        assert_eq!(item, b"The quick brown fox jumped over the lazy dog");
+    }
+
+    #[test]
+    fn test_bstr_bytes() {
+        let str = &[b'4', b':', 0xAA, 0xBB, 0xCC, 0xDD];
+        let (item, pos) = decode_bytestr(str, 0).unwrap();
+
+        assert_eq!(pos, 6);
+        assert_eq!(item, &[0xAA, 0xBB, 0xCC, 0xDD]);
+    }
+
+    #[test]
+    fn test_bstr_empty() {
+        let str = "0:";
+        let (item, pos) = decode_bytestr(str.as_bytes(), 0).unwrap();
+
+        assert_eq!(pos, 2);
+        assert_eq!(item, b"");
+    }
+
+    #[test]
+    fn test_bstr_backtoback() {
+        let str = "5:admin0:3:hey10:abcdefghij";
+        let result = decode(str.as_bytes()).unwrap();
+
+        assert_eq!(
+            result,
+            vec![
+                BencodeValue::ByteStr("admin".as_bytes().to_vec()),
+                BencodeValue::ByteStr("".as_bytes().to_vec()),
+                BencodeValue::ByteStr("hey".as_bytes().to_vec()),
+                BencodeValue::ByteStr("abcdefghij".as_bytes().to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_prefix() {
+        let buf = b"d8:msg_typei1e5:piecei0eeraw bytes ee";
+        let (value, len) = decode_prefix(buf).unwrap();
+
+        assert_eq!(len, 25);
+        assert_eq!(value.get(b"piece"), Some(&BencodeValue::Int(0)));
+        assert_eq!(&buf[len..], b"raw bytes ee");
+        assert_eq!(
+            failure(decode_prefix(b"")),
+            Some((DecodeErrorKind::Empty, 0))
+        );
+    }
+
+    #[test]
+    fn test_error_context() {
+        let buf = b"d4:infod5:filesld6:lengthi1eed6:lengthi1xeeeee";
+        let err = decode(buf).unwrap_err();
+        assert_eq!(err.kind, DecodeErrorKind::InvalidToken('x'));
+        assert_eq!(err.pos, 40);
+        assert_eq!(err.parsing, Some(ValueKind::Int));
+        assert_eq!(err.path.to_string(), "info.files[1].length");
+        assert_eq!(
+            err.to_string(),
+            "unexpected 'x' in an int while parsing info.files[1].length at byte 40"
+        );
+
+        // Unclosed containers point at themselves
+        let err = decode(b"d4:listli1e").unwrap_err();
+        assert_eq!(err.kind, DecodeErrorKind::NoEndToken);
+        assert_eq!(err.parsing, Some(ValueKind::List));
+        assert_eq!(err.path.to_string(), "list");
+
+        let err = decode(b"d1:ad1:bee").unwrap_err();
+        assert_eq!(err.kind, DecodeErrorKind::InvalidDict);
+        assert_eq!(err.path.to_string(), "a");
+
+        let err = decode(b"l1:ax").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unexpected 'x' while parsing [1] at byte 4"
+        );
+        assert_eq!(
+            decode(b"e").unwrap_err().to_string(),
+            "an end token with nothing to end at byte 0"
+        );
+    }
+
+    #[test]
+    fn test_builders() {
+        let value = BencodeValue::dict()
+            .with(b"announce", "http://tracker/announce")
+            .with(
+                b"list",
+                BencodeValue::list().with_item(1i64).with_item(&b"x"[..]),
+            )
+            .with(b"gone", 0i64)
+            .without(b"gone");
+        assert_eq!(
+            encode(&value),
+            b"d8:announce23:http://tracker/announce4:listli1e1:xee"
+        );
+        // Dict and list methods do nothing to other values
+        let mut int = BencodeValue::Int(1);
+        assert_eq!(int.insert(b"key", 2i64), None);
+        int.push(3i64);
+        assert_eq!(int, BencodeValue::Int(1));
+    }
+}
@@ -0,0 +1,70 @@
+// Properties over random values and random bytes, on top of the hand-picked cases in the unit
+// tests. The fuzz targets in fuzz/ check the same things, for longer.
+use proptest::collection::{btree_map, vec};
+use proptest::prelude::*;
+
+use crate::{BencodeValue, Document, decode, decode_prefix, encode};
+
+fn value() -> impl Strategy<Value = BencodeValue> {
+    let leaf = prop_oneof![
+        any::<i64>().prop_map(BencodeValue::Int),
+        vec(any::<u8>(), 0..24).prop_map(BencodeValue::ByteStr),
+    ];
+    leaf.prop_recursive(4, 64, 8, |inner| {
+        prop_oneof![
+            vec(inner.clone(), 0..8).prop_map(BencodeValue::List),
+            btree_map(vec(any::<u8>(), 0..8), inner, 0..8).prop_map(BencodeValue::Dict),
+        ]
+    })
+}
+
+// Valid encodings with one byte changed, which get much further into the decoder than noise
+fn mangled() -> impl Strategy<Value = Vec<u8>> {
+    (value(), any::<prop::sample::Index>(), any::<u8>()).prop_map(|(value, index, byte)| {
+        let mut buf = encode(&value);
+        let i = index.index(buf.len());
+        buf[i] = byte;
+        buf
+    })
+}
+
+proptest! {
+    #[test]
+    fn encode_decode_is_identity(value in value()) {
+        let buf = encode(&value);
+        prop_assert_eq!(decode(&buf).unwrap(), [value.clone()]);
+        // Canonical input comes back out as it went in
+        prop_assert_eq!(encode(&decode(&buf).unwrap()[0]), buf.clone());
+        prop_assert_eq!(Document::decode(&buf).unwrap().encode(), buf.clone());
+
+        let mut trailing = buf.clone();
+        trailing.extend_from_slice(b"junk");
+        prop_assert_eq!(decode_prefix(&trailing).unwrap(), (value, buf.len()));
+    }
+
+    #[test]
+    fn decode_never_panics(buf in prop_oneof![vec(any::<u8>(), 0..64), mangled()]) {
+        match decode(&buf) {
+            // Whatever decodes, re-encodes to something that decodes the same
+            Ok(values) => {
+                for value in values {
+                    prop_assert_eq!(decode(&encode(&value)).unwrap(), [value]);
+                }
+                // And a document writes back exactly what it read, canonical or not
+                if let Ok(doc) = Document::decode(&buf) {
+                    prop_assert_eq!(doc.encode(), buf.clone());
+                }
+            }
+            Err(err) => prop_assert!(err.pos <= buf.len()),
+        }
+    }
+
+    #[test]
+    fn truncation_is_an_error_where_it_was_cut(value in value(), cut in any::<prop::sample::Index>()) {
+        let buf = encode(&value);
+        let len = 1 + cut.index(buf.len() - 1);
+        prop_assume!(len < buf.len());
+        let err = decode(&buf[..len]).unwrap_err();
+        prop_assert!(err.pos <= len, "{} past the end of {} bytes", err, len);
+    }
+}
@@ -0,0 +1,5 @@
+fn main() {
+    // Only needed for the Node.js addon, where it sets up the linker flags napi expects
+    #[cfg(feature = "node")]
+    napi_build::setup();
+}
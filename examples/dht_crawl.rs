@@ -0,0 +1,89 @@
+// Crawls the DHT for info-hashes with BEP 51 sample_infohashes, the way a DHT search engine would.
+// Info-hashes other nodes look up through us are picked up too.
+//
+//     cargo run --example dht_crawl -- [seconds]
+use std::collections::{HashSet, VecDeque};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::process::exit;
+use std::time::{Duration, Instant};
+
+use hurricane::dht::node::{DhtConfig, DhtEvent};
+use hurricane::dht::sample::{Indexer, Source};
+use hurricane::dht::socket::DhtSocket;
+
+// Nodes we sample per poll, to keep the packet rate polite
+const SAMPLES_PER_ROUND: usize = 8;
+
+fn main() {
+    let secs = match std::env::args().nth(1).map(|s| s.parse()) {
+        None => 60,
+        Some(Ok(secs)) => secs,
+        Some(Err(_)) => {
+            eprintln!("usage: dht_crawl [seconds]");
+            exit(2);
+        }
+    };
+
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    let mut socket = match DhtSocket::start(addr.into(), DhtConfig::default(), None) {
+        Ok(socket) => socket,
+        Err(err) => {
+            eprintln!("couldn't start the DHT: {}", err);
+            exit(1);
+        }
+    };
+    let (indexer, discovered) = Indexer::new(100_000);
+    socket.dht().set_indexer(indexer);
+
+    let deadline = Instant::now() + Duration::from_secs(secs);
+    let mut queue = VecDeque::new();
+    let mut sampled = HashSet::new();
+    let mut found = 0;
+    while Instant::now() < deadline {
+        let events = match socket.poll(Duration::from_millis(200)) {
+            Ok(events) => events,
+            Err(err) => {
+                eprintln!("DHT socket failed: {}", err);
+                exit(1);
+            }
+        };
+        for event in events {
+            if let DhtEvent::Sampled { next, .. } = event {
+                queue.extend(next.into_iter().map(|node| node.addr));
+            }
+        }
+
+        // Until samples point us elsewhere, start from the routing table
+        if queue.is_empty() {
+            let dht = socket.dht();
+            queue.extend(dht.table().nodes().map(|node| node.addr));
+        }
+        let now = Instant::now();
+        let mut sent = 0;
+        while sent < SAMPLES_PER_ROUND {
+            let Some(addr) = queue.pop_front() else {
+                break;
+            };
+            if sampled.insert(addr) {
+                socket.dht().sample(addr, now);
+                sent += 1;
+            }
+        }
+
+        for hit in discovered.try_iter() {
+            found += 1;
+            let source = match hit.source {
+                Source::GetPeers => "get_peers",
+                Source::AnnouncePeer => "announce",
+                Source::Sample => "sample",
+            };
+            println!("{}  {:<9} {}", hit.info_hash, source, hit.from);
+        }
+    }
+    eprintln!(
+        "{} info-hashes from {} sampled nodes in {}s",
+        found,
+        sampled.len(),
+        secs
+    );
+}
@@ -0,0 +1,285 @@
+// Turns a magnet link into a .torrent file: finds peers through the link's trackers, its x.pe
+// peers and the DHT, then downloads the info dict from them over ut_metadata (BEP 9).
+//
+//     cargo run --example fetch_magnet_metadata -- 'magnet:?xt=urn:btih:...'
+//
+// Plain blocking sockets, one peer at a time. A real client would talk to many at once.
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream, ToSocketAddrs};
+use std::process::exit;
+use std::time::{Duration, Instant};
+
+use hurricane::dht::node::{DhtConfig, DhtEvent};
+use hurricane::dht::socket::DhtSocket;
+use hurricane::infohash::InfoHash;
+use hurricane::metainfo::{MagnetLink, Metainfo};
+use hurricane::peer::extension::{self, ExtensionHandshake, UT_METADATA};
+use hurricane::peer::handshake::{Feature, HANDSHAKE_LEN, Handshake, Reserved, generate_peer_id};
+use hurricane::peer::message::{MAX_MESSAGE_LEN, Message, MessageError};
+use hurricane::peer::metadata::{MetadataDownload, MetadataMessage};
+use hurricane::rng::Rng;
+use hurricane::tracker::{self, AnnounceEvent, AnnounceRequest};
+
+const CLIENT: &str = concat!("Hurricane ", env!("CARGO_PKG_VERSION"));
+
+// Enough candidates that a few dead ones don't matter
+const WANTED_PEERS: usize = 50;
+const DHT_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn main() {
+    let Some(uri) = std::env::args().nth(1) else {
+        eprintln!("usage: fetch_magnet_metadata <magnet link>");
+        exit(2);
+    };
+    let magnet = match MagnetLink::parse(&uri) {
+        Ok(magnet) => magnet,
+        Err(err) => {
+            eprintln!("bad magnet link: {:?}", err);
+            exit(2);
+        }
+    };
+    let peer_id = generate_peer_id(&mut Rng::new());
+
+    // Peers named by the link and its trackers first, the DHT only if none of them come through
+    let mut tried = HashSet::new();
+    let mut download = None;
+    let sources: [&dyn Fn() -> Vec<SocketAddr>; 2] = [&|| known_peers(&magnet, peer_id), &|| {
+        dht_peers(magnet.info_hash)
+    }];
+    for source in sources {
+        let peers: Vec<SocketAddr> = source()
+            .into_iter()
+            .filter(|addr| tried.insert(*addr))
+            .collect();
+        eprintln!("{} candidate peers", peers.len());
+        for addr in peers {
+            match fetch(addr, magnet.info_hash, peer_id, &mut download) {
+                Ok(info_bytes) => {
+                    save(&magnet, &info_bytes);
+                    return;
+                }
+                Err(err) => eprintln!("{}: {}", addr, err),
+            }
+        }
+    }
+    eprintln!("no peer sent the metadata");
+    exit(1);
+}
+
+// The link's x.pe peers plus whatever its trackers know of
+fn known_peers(magnet: &MagnetLink, peer_id: [u8; 20]) -> Vec<SocketAddr> {
+    let mut peers: Vec<SocketAddr> = magnet
+        .peers
+        .iter()
+        .filter_map(|peer| peer.to_socket_addrs().ok()?.next())
+        .collect();
+
+    let request = AnnounceRequest {
+        info_hash: magnet.info_hash,
+        peer_id,
+        port: 6881,
+        uploaded: 0,
+        downloaded: 0,
+        // We don't know the size yet. Anything but 0 so the tracker doesn't take us for a seed
+        left: 1,
+        event: AnnounceEvent::Started,
+        num_want: Some(WANTED_PEERS as u32),
+        key: Rng::new().next_u64() as u32,
+        ipv4: None,
+        ipv6: None,
+    };
+    for url in &magnet.trackers {
+        match tracker::announce(url, &request, Duration::from_secs(10)) {
+            Ok(response) => peers.extend(response.peers),
+            Err(err) => eprintln!("{}: {:?}", url, err),
+        }
+    }
+    peers
+}
+
+fn dht_peers(info_hash: InfoHash) -> Vec<SocketAddr> {
+    let mut peers = vec![];
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    let mut dht = match DhtSocket::start(addr.into(), DhtConfig::default(), None) {
+        Ok(dht) => dht,
+        Err(err) => {
+            eprintln!("DHT: {}", err);
+            return peers;
+        }
+    };
+    // The lookup can only start once bootstrapping has found some nodes
+    let deadline = Instant::now() + DHT_TIMEOUT;
+    let mut looking = false;
+    while Instant::now() < deadline && peers.len() < WANTED_PEERS {
+        let events = match dht.poll(Duration::from_millis(200)) {
+            Ok(events) => events,
+            Err(err) => {
+                eprintln!("DHT: {}", err);
+                break;
+            }
+        };
+        for event in events {
+            match event {
+                DhtEvent::Bootstrapped if !looking => {
+                    dht.dht().get_peers(info_hash, Instant::now());
+                    looking = true;
+                }
+                DhtEvent::Peers { peers: found, .. } => {
+                    peers.extend(found);
+                }
+                DhtEvent::LookupDone { .. } => return peers,
+                _ => {}
+            }
+        }
+    }
+    peers
+}
+
+fn fetch(
+    addr: SocketAddr,
+    info_hash: InfoHash,
+    peer_id: [u8; 20],
+    download: &mut Option<MetadataDownload>,
+) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(5))?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+
+    let reserved = Reserved::default().with(Feature::Extended);
+    stream.write_all(&Handshake::new(reserved, info_hash, peer_id).encode())?;
+    let mut buf = [0; HANDSHAKE_LEN];
+    stream.read_exact(&mut buf)?;
+    let theirs = match Handshake::decode(&buf) {
+        Ok(Some(theirs)) if theirs.info_hash == info_hash => theirs,
+        _ => return Err(error("bad handshake")),
+    };
+    if !theirs.negotiated(&reserved, Feature::Extended) {
+        return Err(error("no extension protocol"));
+    }
+
+    let ours = ExtensionHandshake::ours(None, CLIENT, false);
+    // What the peer tags the ut_metadata messages it sends us with
+    let incoming_id = ours.id_for(UT_METADATA).unwrap();
+    send(
+        &mut stream,
+        Message::Extended {
+            id: extension::HANDSHAKE_ID,
+            payload: ours.encode(),
+        },
+    )?;
+
+    loop {
+        match read_message(&mut stream)? {
+            Message::Extended {
+                id: extension::HANDSHAKE_ID,
+                payload,
+            } => {
+                let theirs = ExtensionHandshake::decode(&payload)
+                    .map_err(|_| error("bad extension handshake"))?;
+                let outgoing_id = theirs.id_for(UT_METADATA).ok_or(error("no ut_metadata"))?;
+                // The first peer to tell us the size decides it. A liar only costs us a hash
+                // mismatch, after which the next peer gets to try
+                if download.is_none() {
+                    *download = theirs
+                        .metadata_size
+                        .and_then(|size| MetadataDownload::new(info_hash, size));
+                }
+                let download = download.as_mut().ok_or(error("no metadata size"))?;
+                for _ in 0..download.num_pieces() {
+                    let Some(piece) = download.next_request() else {
+                        break;
+                    };
+                    let payload = MetadataMessage::Request(piece).encode();
+                    send(
+                        &mut stream,
+                        Message::Extended {
+                            id: outgoing_id,
+                            payload,
+                        },
+                    )?;
+                }
+            }
+            Message::Extended { id, payload } if id == incoming_id => {
+                let Some(download) = download.as_mut() else {
+                    continue;
+                };
+                match MetadataMessage::decode(&payload) {
+                    Ok(MetadataMessage::Data { piece, data, .. }) => {
+                        match download.on_data(piece, &data) {
+                            Ok(Some(info_bytes)) => return Ok(info_bytes),
+                            Ok(None) => {}
+                            Err(err) => return Err(error(&format!("{:?}", err))),
+                        }
+                    }
+                    Ok(MetadataMessage::Reject(piece)) => {
+                        download.on_reject(piece);
+                        return Err(error("metadata request rejected"));
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn save(magnet: &MagnetLink, info_bytes: &[u8]) {
+    let metainfo = match Metainfo::from_info_bytes(info_bytes) {
+        Ok(metainfo) => metainfo,
+        Err(err) => {
+            eprintln!("metadata doesn't parse: {:?}", err);
+            exit(1);
+        }
+    };
+    let info = &metainfo.info;
+    println!("{}", info.name);
+    println!(
+        "{} bytes in {} pieces of {}",
+        info.total_length(),
+        info.num_pieces(),
+        info.piece_length
+    );
+    for file in &info.files {
+        println!("  {:>12}  {}", file.length, file.path.join("/"));
+    }
+
+    // A .torrent is the info dict plus where to find peers; the magnet's trackers will do
+    let mut torrent = b"d".to_vec();
+    if let Some(tracker) = magnet.trackers.first() {
+        torrent.extend_from_slice(format!("8:announce{}:{}", tracker.len(), tracker).as_bytes());
+    }
+    torrent.extend_from_slice(b"4:info");
+    torrent.extend_from_slice(info_bytes);
+    torrent.push(b'e');
+    let path = format!("{}.torrent", magnet.info_hash);
+    match std::fs::write(&path, torrent) {
+        Ok(()) => println!("saved {}", path),
+        Err(err) => eprintln!("couldn't write {}: {}", path, err),
+    }
+}
+
+fn send(stream: &mut TcpStream, message: Message) -> io::Result<()> {
+    stream.write_all(&message.encode())
+}
+
+fn read_message(stream: &mut TcpStream) -> io::Result<Message> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let body_len = u32::from_be_bytes(len) as usize;
+    if body_len > MAX_MESSAGE_LEN {
+        return Err(error("message too large"));
+    }
+    let mut buf = len.to_vec();
+    buf.resize(4 + body_len, 0);
+    stream.read_exact(&mut buf[4..])?;
+    match Message::decode(&buf) {
+        Ok(Some((message, _))) => Ok(message),
+        // Messages from extensions we don't know are skipped
+        Err(MessageError::UnknownId(_)) => Ok(Message::KeepAlive),
+        _ => Err(error("bad message")),
+    }
+}
+
+fn error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
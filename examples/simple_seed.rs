@@ -0,0 +1,268 @@
+// Seeds a torrent from data already on disk: rechecks it, announces to the torrent's trackers and
+// serves blocks and metadata to whoever connects.
+//
+//     cargo run --example simple_seed -- <file.torrent> <download dir> [port]
+//
+// One thread per peer and everyone gets unchoked, which is fine for a handful of peers.
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::process::exit;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use hurricane::bitfield::Bitfield;
+use hurricane::disk::Storage;
+use hurricane::metainfo::Metainfo;
+use hurricane::peer::Block;
+use hurricane::peer::extension::{self, ExtensionHandshake, UT_METADATA};
+use hurricane::peer::handshake::{Feature, HANDSHAKE_LEN, Handshake, Reserved, generate_peer_id};
+use hurricane::peer::listen::{IpFamilies, Listeners};
+use hurricane::peer::message::{MAX_MESSAGE_LEN, Message, MessageError};
+use hurricane::peer::metadata::{MetadataMessage, serve_piece};
+use hurricane::rng::Rng;
+use hurricane::tracker::{self, AnnounceEvent, AnnounceRequest};
+
+const CLIENT: &str = concat!("Hurricane ", env!("CARGO_PKG_VERSION"));
+
+// Nobody asks for more than this in one request
+const MAX_REQUEST: u32 = 128 * 1024;
+
+struct Seed {
+    metainfo: Metainfo,
+    storage: Storage,
+    have: Bitfield,
+    peer_id: [u8; 20],
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 2 {
+        eprintln!("usage: simple_seed <file.torrent> <download dir> [port]");
+        exit(2);
+    }
+    let port = match args.get(2).map(|p| p.parse()) {
+        None => 6881,
+        Some(Ok(port)) => port,
+        Some(Err(_)) => {
+            eprintln!("bad port: {}", args[2]);
+            exit(2);
+        }
+    };
+
+    let metainfo = match std::fs::read(&args[0]).map(|buf| Metainfo::from_bytes(&buf)) {
+        Ok(Ok(metainfo)) => metainfo,
+        Ok(Err(err)) => {
+            eprintln!("{}: {:?}", args[0], err);
+            exit(1);
+        }
+        Err(err) => {
+            eprintln!("{}: {}", args[0], err);
+            exit(1);
+        }
+    };
+    let storage = Storage::new(&metainfo.info, args[1].as_ref());
+    eprintln!("checking {}...", metainfo.info.name);
+    let have = match storage.check() {
+        Ok(have) => have,
+        Err(err) => {
+            eprintln!("recheck failed: {}", err);
+            exit(1);
+        }
+    };
+    eprintln!("have {}/{} pieces", have.count_ones(), have.len());
+
+    let mut rng = Rng::new();
+    let seed = Arc::new(Seed {
+        metainfo,
+        storage,
+        have,
+        peer_id: generate_peer_id(&mut rng),
+    });
+
+    let listeners = match Listeners::bind(port, IpFamilies::default()) {
+        Ok(listeners) => listeners,
+        Err(err) => {
+            eprintln!("can't listen on {}: {}", port, err);
+            exit(1);
+        }
+    };
+    let announcer = seed.clone();
+    let key = rng.next_u64() as u32;
+    thread::spawn(move || announce_loop(&announcer, port, key));
+
+    eprintln!("listening on {:?}", listeners.local_addrs());
+    loop {
+        let (stream, addr) = match listeners.accept() {
+            Ok(Some(accepted)) => accepted,
+            Ok(None) => {
+                thread::sleep(Duration::from_millis(50));
+                continue;
+            }
+            Err(err) => {
+                eprintln!("accept: {}", err);
+                continue;
+            }
+        };
+        let seed = seed.clone();
+        thread::spawn(move || match serve(stream, &seed) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {}
+            Err(err) => eprintln!("{}: {}", addr, err),
+        });
+    }
+}
+
+// Re-announces on the tracker's interval so we stay in its peer lists
+fn announce_loop(seed: &Seed, port: u16, key: u32) {
+    let trackers: Vec<String> = seed.metainfo.trackers().into_iter().flatten().collect();
+    if trackers.is_empty() {
+        eprintln!("no trackers, waiting for peers to find us");
+        return;
+    }
+    let left = (0..seed.storage.num_pieces())
+        .filter(|piece| !seed.have.get(*piece as usize))
+        .map(|piece| seed.storage.piece_size(piece) as u64)
+        .sum();
+    let mut request = AnnounceRequest {
+        info_hash: seed.metainfo.info_hash,
+        peer_id: seed.peer_id,
+        port,
+        uploaded: 0,
+        downloaded: 0,
+        left,
+        event: AnnounceEvent::Started,
+        num_want: Some(0),
+        key,
+        ipv4: None,
+        ipv6: None,
+    };
+    loop {
+        let mut interval = Duration::from_secs(30 * 60);
+        for url in &trackers {
+            match tracker::announce(url, &request, Duration::from_secs(10)) {
+                Ok(response) => {
+                    eprintln!(
+                        "{}: {} seeders, {} leechers",
+                        url,
+                        response.seeders.unwrap_or(0),
+                        response.leechers.unwrap_or(0)
+                    );
+                    interval = interval.min(response.interval);
+                }
+                Err(err) => eprintln!("{}: {:?}", url, err),
+            }
+        }
+        request.event = AnnounceEvent::None;
+        thread::sleep(interval.max(Duration::from_secs(60)));
+    }
+}
+
+fn serve(mut stream: TcpStream, seed: &Seed) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(120)))?;
+    let mut buf = [0; HANDSHAKE_LEN];
+    stream.read_exact(&mut buf)?;
+    let theirs = match Handshake::decode(&buf) {
+        Ok(Some(theirs)) if theirs.info_hash == seed.metainfo.info_hash => theirs,
+        _ => return Err(error("bad handshake")),
+    };
+    let reserved = Reserved::default().with(Feature::Extended);
+    let ours = Handshake::new(reserved, seed.metainfo.info_hash, seed.peer_id);
+    stream.write_all(&ours.encode())?;
+
+    let extended = theirs.negotiated(&reserved, Feature::Extended);
+    let mut ext = ExtensionHandshake::ours(None, CLIENT, seed.metainfo.info.private);
+    if extended {
+        ext.metadata_size = Some(seed.metainfo.info_bytes.len() as u32);
+        send(
+            &mut stream,
+            Message::Extended {
+                id: extension::HANDSHAKE_ID,
+                payload: ext.encode(),
+            },
+        )?;
+    }
+    send(
+        &mut stream,
+        Message::Bitfield(seed.have.as_bytes().to_vec()),
+    )?;
+
+    // The ID they want their ut_metadata messages tagged with
+    let mut metadata_id = None;
+    loop {
+        match read_message(&mut stream)? {
+            Message::Interested => send(&mut stream, Message::Unchoke)?,
+            Message::Request(block) => {
+                let data = read_block(seed, block)?;
+                let piece = Message::Piece {
+                    piece: block.piece,
+                    offset: block.offset,
+                    data,
+                };
+                send(&mut stream, piece)?;
+            }
+            Message::Extended {
+                id: extension::HANDSHAKE_ID,
+                payload,
+            } if extended => {
+                let theirs = ExtensionHandshake::decode(&payload)
+                    .map_err(|_| error("bad extension handshake"))?;
+                metadata_id = theirs.id_for(UT_METADATA);
+            }
+            Message::Extended { id, payload } if Some(id) == ext.id_for(UT_METADATA) => {
+                let (Some(reply_id), Ok(MetadataMessage::Request(piece))) =
+                    (metadata_id, MetadataMessage::decode(&payload))
+                else {
+                    continue;
+                };
+                let reply = serve_piece(&seed.metainfo.info_bytes, piece);
+                send(
+                    &mut stream,
+                    Message::Extended {
+                        id: reply_id,
+                        payload: reply.encode(),
+                    },
+                )?;
+            }
+            _ => {}
+        }
+    }
+}
+
+// Requests for pieces we don't have or that run off the end of the piece end the connection
+fn read_block(seed: &Seed, block: Block) -> io::Result<Vec<u8>> {
+    let valid = block.piece < seed.storage.num_pieces()
+        && seed.have.get(block.piece as usize)
+        && block.length <= MAX_REQUEST
+        && block.offset as u64 + block.length as u64 <= seed.storage.piece_size(block.piece) as u64;
+    if !valid {
+        return Err(error("bad request"));
+    }
+    seed.storage.read(block.piece, block.offset, block.length)
+}
+
+fn send(stream: &mut TcpStream, message: Message) -> io::Result<()> {
+    stream.write_all(&message.encode())
+}
+
+fn read_message(stream: &mut TcpStream) -> io::Result<Message> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let body_len = u32::from_be_bytes(len) as usize;
+    if body_len > MAX_MESSAGE_LEN {
+        return Err(error("message too large"));
+    }
+    let mut buf = len.to_vec();
+    buf.resize(4 + body_len, 0);
+    stream.read_exact(&mut buf[4..])?;
+    match Message::decode(&buf) {
+        Ok(Some((message, _))) => Ok(message),
+        // Messages from extensions we don't know are skipped
+        Err(MessageError::UnknownId(_)) => Ok(Message::KeepAlive),
+        _ => Err(error("bad message")),
+    }
+}
+
+fn error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
@@ -0,0 +1,46 @@
+// Asks a tracker how many seeders and leechers it knows of for some torrents.
+//
+//     cargo run --example tracker_scrape -- udp://tracker.opentrackr.org:1337/announce <info-hash>...
+//
+// Either the announce URL or the scrape URL works for HTTP trackers; UDP trackers only have one.
+use std::process::exit;
+use std::time::Duration;
+
+use hurricane::infohash::InfoHash;
+use hurricane::tracker;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 2 {
+        eprintln!("usage: tracker_scrape <tracker url> <info-hash>...");
+        exit(2);
+    }
+
+    let mut info_hashes = vec![];
+    for hex in &args[1..] {
+        match InfoHash::from_hex(hex) {
+            Some(info_hash) => info_hashes.push(info_hash),
+            None => {
+                eprintln!("not an info-hash: {}", hex);
+                exit(2);
+            }
+        }
+    }
+
+    let stats = match tracker::scrape(&args[0], &info_hashes, Duration::from_secs(10)) {
+        Ok(stats) => stats,
+        Err(err) => {
+            eprintln!("scrape failed: {:?}", err);
+            exit(1);
+        }
+    };
+    for info_hash in &info_hashes {
+        match stats.get(info_hash) {
+            Some(s) => println!(
+                "{}  seeders {:>6}  leechers {:>6}  completed {:>8}",
+                info_hash, s.seeders, s.leechers, s.completed
+            ),
+            None => println!("{}  not tracked", info_hash),
+        }
+    }
+}
@@ -0,0 +1,20 @@
+// Any bytes: decoding mustn't panic, errors point inside the input, and anything that decodes
+// comes back out of a document exactly as it went in
+#![no_main]
+
+use bencode::{Document, decode, encode};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|buf: &[u8]| {
+    match decode(buf) {
+        Ok(values) => {
+            for value in &values {
+                assert_eq!(decode(&encode(value)).unwrap(), std::slice::from_ref(value));
+            }
+            if values.len() == 1 {
+                assert_eq!(Document::decode(buf).unwrap().encode(), buf);
+            }
+        }
+        Err(err) => assert!(err.pos <= buf.len(), "{err} in {} bytes", buf.len()),
+    }
+});
@@ -0,0 +1,17 @@
+// Any bytes as a .torrent: parsing and editing mustn't panic
+#![no_main]
+
+use hurricane::metainfo::Metainfo;
+use hurricane::metainfo::edit::TorrentEditor;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|buf: &[u8]| {
+    let parsed = Metainfo::from_bytes(buf);
+    if let Ok(mut editor) = TorrentEditor::from_bytes(buf) {
+        assert!(parsed.is_ok());
+        let info_hash = editor.info_hash();
+        editor.set_comment(Some("fuzz"));
+        assert_eq!(editor.info_hash(), info_hash);
+        assert!(Metainfo::from_bytes(&editor.to_bytes()).is_ok());
+    }
+});
@@ -0,0 +1,15 @@
+// Any value: encoding then decoding gives it back, with and without trailing bytes
+#![no_main]
+
+use bencode::{BencodeValue, decode, decode_prefix, encode};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: (BencodeValue, Vec<u8>)| {
+    let (value, trailing) = input;
+    let mut buf = encode(&value);
+    assert_eq!(decode(&buf).unwrap(), std::slice::from_ref(&value));
+
+    let len = buf.len();
+    buf.extend_from_slice(&trailing);
+    assert_eq!(decode_prefix(&buf).unwrap(), (value, len));
+});
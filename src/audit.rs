@@ -0,0 +1,90 @@
+// Enforcement audit for private torrents (BEP 27). A private torrent's peers must come from its
+// trackers only, so its info-hash must never show up in a DHT lookup, a PEX message or an LSD
+// broadcast. Torrents register here when they learn they're private, and every one of those send
+// paths calls `check_public` first. In debug builds that panics on a private info-hash, so a
+// regression in the discovery plumbing fails the tests instead of quietly leaking the torrent.
+// Release builds skip the check, and the callers are expected to have filtered already.
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::infohash::InfoHash;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Channel {
+    Dht,
+    Pex,
+    Lsd,
+}
+
+// Counted, since the same torrent can be held twice for a moment (a duplicate being merged)
+static PRIVATE: Mutex<BTreeMap<InfoHash, usize>> = Mutex::new(BTreeMap::new());
+
+// A panic elsewhere while holding the lock doesn't make the map wrong
+fn private() -> MutexGuard<'static, BTreeMap<InfoHash, usize>> {
+    PRIVATE.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+pub fn register_private(info_hash: InfoHash) {
+    *private().entry(info_hash).or_insert(0) += 1;
+}
+
+pub fn unregister_private(info_hash: InfoHash) {
+    let mut private = private();
+    if let Some(count) = private.get_mut(&info_hash) {
+        *count -= 1;
+        if *count == 0 {
+            private.remove(&info_hash);
+        }
+    }
+}
+
+pub fn is_private(info_hash: InfoHash) -> bool {
+    private().contains_key(&info_hash)
+}
+
+// Call right before sending anything on `channel` that names `info_hash`
+pub fn check_public(info_hash: InfoHash, channel: Channel) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    // Looked up first so the lock isn't held (and poisoned) by the panic
+    let private = is_private(info_hash);
+    assert!(
+        !private,
+        "private torrent {} leaked to {:?}",
+        info_hash.to_hex(),
+        channel
+    );
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    // Every test in the crate shares the registry, so these hashes are used nowhere else
+
+    #[test]
+    fn test_register_counts() {
+        let info_hash = InfoHash([0xa1; 20]);
+        assert!(!is_private(info_hash));
+
+        register_private(info_hash);
+        register_private(info_hash);
+        unregister_private(info_hash);
+        assert!(is_private(info_hash));
+        unregister_private(info_hash);
+        assert!(!is_private(info_hash));
+        // Unregistering something that never was is harmless
+        unregister_private(info_hash);
+        check_public(info_hash, Channel::Dht);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "leaked to Pex")]
+    fn test_private_leak_panics() {
+        let info_hash = InfoHash([0xa2; 20]);
+        register_private(info_hash);
+        check_public(info_hash, Channel::Pex);
+    }
+}
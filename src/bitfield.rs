@@ -0,0 +1,139 @@
+// One bit per piece, high bit of the first byte is piece 0. Same layout as the `bitfield` message
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Bitfield {
+            bits: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Bitfield::new(len);
+        for i in 0..len {
+            bitfield.set(i);
+        }
+        bitfield
+    }
+
+    // Fails if the buffer is the wrong size or any of the spare bits at the end are set, both of
+    // which the spec says should get the peer dropped
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Option<Self> {
+        if bytes.len() != len.div_ceil(8) {
+            return None;
+        }
+
+        let bitfield = Bitfield {
+            bits: bytes.to_vec(),
+            len,
+        };
+        if (len..bytes.len() * 8).any(|i| bitfield.bit(i)) {
+            return None;
+        }
+        Some(bitfield)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, i: usize) -> bool {
+        i < self.len && self.bit(i)
+    }
+
+    pub fn set(&mut self, i: usize) {
+        assert!(i < self.len, "bit {} out of range", i);
+        self.bits[i / 8] |= 0x80 >> (i % 8);
+    }
+
+    pub fn clear(&mut self, i: usize) {
+        assert!(i < self.len, "bit {} out of range", i);
+        self.bits[i / 8] &= !(0x80 >> (i % 8));
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.bits.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn all(&self) -> bool {
+        self.count_ones() == self.len
+    }
+
+    pub fn none(&self) -> bool {
+        self.bits.iter().all(|b| *b == 0)
+    }
+
+    pub fn iter_ones(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|i| self.bit(*i))
+    }
+
+    fn bit(&self, i: usize) -> bool {
+        self.bits[i / 8] & (0x80 >> (i % 8)) != 0
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_bitfield_set_get() {
+        let mut bitfield = Bitfield::new(10);
+        bitfield.set(0);
+        bitfield.set(9);
+
+        assert_eq!(bitfield.as_bytes(), &[0b1000_0000, 0b0100_0000]);
+        assert!(bitfield.get(0));
+        assert!(!bitfield.get(1));
+        assert!(bitfield.get(9));
+        assert!(!bitfield.get(10));
+        assert_eq!(bitfield.count_ones(), 2);
+    }
+
+    #[test]
+    fn test_bitfield_clear() {
+        let mut bitfield = Bitfield::full(3);
+        bitfield.clear(1);
+
+        assert_eq!(bitfield.iter_ones().collect::<Vec<_>>(), vec![0, 2]);
+        assert!(!bitfield.all());
+    }
+
+    #[test]
+    fn test_bitfield_full() {
+        let bitfield = Bitfield::full(9);
+
+        assert!(bitfield.all());
+        assert_eq!(bitfield.as_bytes(), &[0xff, 0x80]);
+    }
+
+    #[test]
+    fn test_bitfield_from_bytes() {
+        let bitfield = Bitfield::from_bytes(&[0xff, 0x80], 9).unwrap();
+
+        assert!(bitfield.all());
+    }
+
+    #[test]
+    fn test_bitfield_from_bytes_bad_len() {
+        assert_eq!(Bitfield::from_bytes(&[0xff], 9), None);
+    }
+
+    #[test]
+    fn test_bitfield_from_bytes_spare_bits() {
+        assert_eq!(Bitfield::from_bytes(&[0xff, 0x40], 9), None);
+    }
+}
@@ -0,0 +1,388 @@
+// IP blocklists: address ranges we won't talk to, the way PeerGuardian and eMule users have been
+// publishing them for years. Both common formats are read, a line at a time, and may be mixed:
+//
+//     # eMule ipfilter.dat: range, access level, description. Levels over 127 are let through
+//     001.002.004.000 - 001.002.004.255 , 000 , Some company
+//     # P2P plaintext (PeerGuardian): description, a colon, the range
+//     Some company:1.2.4.0-1.2.4.255
+//
+// IPv6 ranges work in the .dat format. Files may be gzipped, which is told by their first bytes
+// rather than their name. Lines that don't parse are skipped and counted, since published lists
+// always have a few; a file where nothing parses is an error.
+// Ranges are merged and kept sorted, so a lookup is a binary search however long the list is.
+// `IpFilter` is the list as the rest of the client sees it: cheap to clone, shared by every
+// torrent's peer list and the code that accepts and makes connections, replaced in place on a
+// reload, and counting what it turned away.
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use flate2::read::GzDecoder;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// eMule blocks ranges with an access level up to this
+const MAX_BLOCKED_LEVEL: u32 = 127;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum BlocklistError {
+    Io(io::ErrorKind),
+    // Not a single range in it. `line` is the first one that didn't parse
+    Invalid { line: usize },
+}
+
+impl fmt::Display for BlocklistError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlocklistError::Io(kind) => write!(f, "{}", kind),
+            BlocklistError::Invalid { line } => write!(f, "line {}: not a blocklist", line),
+        }
+    }
+}
+
+impl From<io::Error> for BlocklistError {
+    fn from(err: io::Error) -> Self {
+        BlocklistError::Io(err.kind())
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Blocklist {
+    // Inclusive, sorted and merged, so none overlap or touch
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+    // Lines skipped because they didn't parse
+    invalid_lines: usize,
+}
+
+impl Blocklist {
+    pub fn new() -> Self {
+        Blocklist::default()
+    }
+
+    pub fn load(path: &Path) -> Result<Self, BlocklistError> {
+        let mut bytes = fs::read(path)?;
+        if bytes.starts_with(&GZIP_MAGIC) {
+            let mut decoded = vec![];
+            GzDecoder::new(&bytes[..]).read_to_end(&mut decoded)?;
+            bytes = decoded;
+        }
+        // Descriptions are in whatever encoding the publisher used, the addresses are ASCII
+        Blocklist::parse(&String::from_utf8_lossy(&bytes))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, BlocklistError> {
+        let mut list = Blocklist::new();
+        let mut first_invalid = None;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            match parse_line(line) {
+                Some(Some((start, end))) => list.push(start, end),
+                // Let through
+                Some(None) => {}
+                None => {
+                    list.invalid_lines += 1;
+                    first_invalid.get_or_insert(i + 1);
+                }
+            }
+        }
+        list.normalize();
+        if list.is_empty() && list.invalid_lines > 0 {
+            return Err(BlocklistError::Invalid {
+                line: first_invalid.unwrap(),
+            });
+        }
+        Ok(list)
+    }
+
+    // Both ends are blocked. A range with ends of different families, or backwards, is ignored
+    pub fn insert(&mut self, start: IpAddr, end: IpAddr) {
+        self.push(start, end);
+        self.normalize();
+    }
+
+    fn push(&mut self, start: IpAddr, end: IpAddr) {
+        match (start, end) {
+            (IpAddr::V4(start), IpAddr::V4(end)) if start <= end => {
+                self.v4.push((start.into(), end.into()))
+            }
+            (IpAddr::V6(start), IpAddr::V6(end)) if start <= end => {
+                self.v6.push((start.into(), end.into()))
+            }
+            _ => {}
+        }
+    }
+
+    fn normalize(&mut self) {
+        normalize(&mut self.v4, |end, start| end.checked_add(1) == Some(start));
+        normalize(&mut self.v6, |end, start| end.checked_add(1) == Some(start));
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => contains(&self.v4, ip.into()),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => contains(&self.v4, ip.into()),
+                None => contains(&self.v6, ip.into()),
+            },
+        }
+    }
+
+    // Ranges left after merging
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn invalid_lines(&self) -> usize {
+        self.invalid_lines
+    }
+}
+
+// Some(None) for a range that's let through, None for a line that isn't a range
+type ParsedLine = Option<Option<(IpAddr, IpAddr)>>;
+
+fn parse_line(line: &str) -> ParsedLine {
+    // P2P: the description may have colons of its own, the range never does
+    if let Some((_, range)) = line.rsplit_once(':')
+        && let Some(range) = parse_range(range)
+    {
+        return Some(Some(range));
+    }
+    let mut fields = line.split(',');
+    let range = parse_range(fields.next()?)?;
+    match fields.next().map(|level| level.trim().parse::<u32>()) {
+        None => Some(Some(range)),
+        Some(Ok(level)) if level <= MAX_BLOCKED_LEVEL => Some(Some(range)),
+        Some(Ok(_)) => Some(None),
+        Some(Err(_)) => None,
+    }
+}
+
+fn parse_range(text: &str) -> Option<(IpAddr, IpAddr)> {
+    let (start, end) = text.split_once('-')?;
+    Some((parse_ip(start.trim())?, parse_ip(end.trim())?))
+}
+
+// .dat files pad each IPv4 octet to three digits, which std won't take
+fn parse_ip(text: &str) -> Option<IpAddr> {
+    if text.contains(':') {
+        return text.parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+    let mut octets = [0; 4];
+    let mut parts = text.split('.');
+    for octet in &mut octets {
+        let part = parts.next()?;
+        if part.is_empty() || part.len() > 3 || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        *octet = part.parse().ok()?;
+    }
+    match parts.next() {
+        Some(_) => None,
+        None => Some(Ipv4Addr::from(octets).into()),
+    }
+}
+
+// Sorts `ranges` and merges those that overlap or touch
+fn normalize<T: Ord + Copy>(ranges: &mut Vec<(T, T)>, touches: fn(T, T) -> bool) {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 || touches(last.1, start) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    let i = ranges.partition_point(|(start, _)| *start <= ip);
+    i > 0 && ranges[i - 1].1 >= ip
+}
+
+// What the filter turned away
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Attempt {
+    // Connecting to a peer
+    Dial,
+    // A peer connecting to us
+    Accept,
+    // A peer handed to us by a tracker, the DHT, PEX, ...
+    Discovered,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct BlockCounts {
+    pub dials: u64,
+    pub accepts: u64,
+    pub discovered: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    list: RwLock<Arc<Blocklist>>,
+    dials: AtomicU64,
+    accepts: AtomicU64,
+    discovered: AtomicU64,
+}
+
+impl IpFilter {
+    // Blocks nothing until it's given a list
+    pub fn new() -> Self {
+        IpFilter::default()
+    }
+
+    pub fn list(&self) -> Arc<Blocklist> {
+        self.shared.list.read().unwrap().clone()
+    }
+
+    // Takes effect for every clone at once. Peers already listed or connected are left alone,
+    // they're checked again before we next dial them
+    pub fn set_list(&self, list: Blocklist) {
+        *self.shared.list.write().unwrap() = Arc::new(list);
+    }
+
+    // Loads `path` in place of the current list, which stays if it fails. Returns the list's
+    // number of ranges
+    pub fn load(&self, path: &Path) -> Result<usize, BlocklistError> {
+        let list = Blocklist::load(path)?;
+        let len = list.len();
+        self.set_list(list);
+        Ok(len)
+    }
+
+    // False, and counted, when `ip` is blocked
+    pub fn allows(&self, ip: IpAddr, attempt: Attempt) -> bool {
+        if !self.shared.list.read().unwrap().contains(ip) {
+            return true;
+        }
+        let counter = match attempt {
+            Attempt::Dial => &self.shared.dials,
+            Attempt::Accept => &self.shared.accepts,
+            Attempt::Discovered => &self.shared.discovered,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    // Without counting it
+    pub fn blocks(&self, ip: IpAddr) -> bool {
+        self.shared.list.read().unwrap().contains(ip)
+    }
+
+    pub fn counts(&self) -> BlockCounts {
+        BlockCounts {
+            dials: self.shared.dials.load(Ordering::Relaxed),
+            accepts: self.shared.accepts.load(Ordering::Relaxed),
+            discovered: self.shared.discovered.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use std::io::Write;
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let text = "\
+# eMule
+001.002.004.000 - 001.002.004.255 , 000 , Some company
+001.002.005.000 - 001.002.005.010 , 100 , Touches the one before
+010.000.000.000 - 010.255.255.255 , 200 , Let through
+2001:db8:: - 2001:db8::ffff , 0 , Documentation
+
+// P2P
+Bogon: the sequel:192.168.0.0-192.168.255.255
+not a range at all
+";
+        let list = Blocklist::parse(text).unwrap();
+        assert_eq!(list.len(), 3);
+        assert_eq!(list.invalid_lines(), 1);
+        for blocked in [
+            "1.2.4.0",
+            "1.2.5.10",
+            "192.168.7.7",
+            "::ffff:1.2.4.9",
+            "2001:db8::1",
+        ] {
+            assert!(list.contains(ip(blocked)), "{}", blocked);
+        }
+        for allowed in ["1.2.3.255", "1.2.5.11", "10.1.1.1", "2001:db8::1:0"] {
+            assert!(!list.contains(ip(allowed)), "{}", allowed);
+        }
+
+        assert_eq!(Blocklist::parse(""), Ok(Blocklist::new()));
+        assert_eq!(
+            Blocklist::parse("# header\n<html>\n"),
+            Err(BlocklistError::Invalid { line: 2 })
+        );
+        // Octets past 255, or more than four of them
+        assert_eq!(parse_ip("1.2.3.256"), None);
+        assert_eq!(parse_ip("1.2.3.4.5"), None);
+    }
+
+    #[test]
+    fn test_filter() {
+        let path =
+            std::env::temp_dir().join(format!("hurricane-blocklist-{}.p2p.gz", std::process::id()));
+        let mut gz = GzEncoder::new(vec![], Compression::default());
+        gz.write_all(b"Lab:10.0.0.0-10.0.0.255\n").unwrap();
+        fs::write(&path, gz.finish().unwrap()).unwrap();
+
+        let filter = IpFilter::new();
+        let clone = filter.clone();
+        assert!(filter.allows(ip("10.0.0.1"), Attempt::Dial));
+        assert_eq!(clone.load(&path), Ok(1));
+        assert!(!filter.allows(ip("10.0.0.1"), Attempt::Dial));
+        assert!(!filter.allows(ip("10.0.0.2"), Attempt::Discovered));
+        assert!(filter.allows(ip("10.0.1.1"), Attempt::Accept));
+        assert_eq!(
+            clone.counts(),
+            BlockCounts {
+                dials: 1,
+                accepts: 0,
+                discovered: 1,
+            }
+        );
+
+        // A bad reload keeps the list there was
+        fs::write(&path, b"garbage").unwrap();
+        assert!(matches!(
+            filter.load(&path),
+            Err(BlocklistError::Invalid { line: 1 })
+        ));
+        assert!(filter.blocks(ip("10.0.0.1")));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            filter.load(&path),
+            Err(BlocklistError::Io(io::ErrorKind::NotFound))
+        );
+    }
+}
@@ -0,0 +1,178 @@
+// Compact peer and node formats shared by trackers (BEP 23, BEP 7), the DHT (BEP 5, BEP 32) and
+// PEX. A peer is its IP followed by a big-endian port: 6 bytes for IPv4, 18 for IPv6. A node is a
+// 20 byte ID followed by a compact peer: 26 or 38 bytes.
+// Lists are plain concatenations, so a length that isn't a multiple of the entry size means the
+// whole thing is garbage and gets rejected rather than silently truncated.
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+#[derive(PartialEq, Debug)]
+pub enum CompactError {
+    // Length of the buffer we were given
+    BadLength(usize),
+}
+
+// An address with a fixed-size compact form
+pub trait CompactAddr: Sized + Copy {
+    const LEN: usize;
+
+    // `buf` must be exactly LEN bytes
+    fn parse(buf: &[u8]) -> Option<Self>;
+    fn write(&self, out: &mut Vec<u8>);
+}
+
+impl CompactAddr for SocketAddrV4 {
+    const LEN: usize = 6;
+
+    fn parse(buf: &[u8]) -> Option<Self> {
+        let buf: &[u8; 6] = buf.try_into().ok()?;
+        let ip = Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
+        Some(SocketAddrV4::new(ip, u16::from_be_bytes([buf[4], buf[5]])))
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.ip().octets());
+        out.extend_from_slice(&self.port().to_be_bytes());
+    }
+}
+
+impl CompactAddr for SocketAddrV6 {
+    const LEN: usize = 18;
+
+    fn parse(buf: &[u8]) -> Option<Self> {
+        let buf: &[u8; 18] = buf.try_into().ok()?;
+        let ip: [u8; 16] = buf[..16].try_into().unwrap();
+        let port = u16::from_be_bytes([buf[16], buf[17]]);
+        Some(SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0))
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.ip().octets());
+        out.extend_from_slice(&self.port().to_be_bytes());
+    }
+}
+
+pub struct CompactPeers;
+
+impl CompactPeers {
+    pub fn parse<A: CompactAddr>(buf: &[u8]) -> Result<Vec<A>, CompactError> {
+        if !buf.len().is_multiple_of(A::LEN) {
+            return Err(CompactError::BadLength(buf.len()));
+        }
+        Ok(buf
+            .chunks_exact(A::LEN)
+            .map(|chunk| A::parse(chunk).unwrap())
+            .collect())
+    }
+
+    pub fn write<A: CompactAddr>(peers: &[A]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(peers.len() * A::LEN);
+        for peer in peers {
+            peer.write(&mut out);
+        }
+        out
+    }
+
+    // A single peer of either family, told apart by length (as in BEP 5 `values`)
+    pub fn parse_one(buf: &[u8]) -> Result<SocketAddr, CompactError> {
+        match buf.len() {
+            6 => Ok(SocketAddrV4::parse(buf).unwrap().into()),
+            18 => Ok(SocketAddrV6::parse(buf).unwrap().into()),
+            len => Err(CompactError::BadLength(len)),
+        }
+    }
+
+    pub fn write_one(peer: &SocketAddr) -> Vec<u8> {
+        let mut out = vec![];
+        match peer {
+            SocketAddr::V4(addr) => addr.write(&mut out),
+            SocketAddr::V6(addr) => addr.write(&mut out),
+        }
+        out
+    }
+}
+
+pub struct CompactNodes;
+
+impl CompactNodes {
+    pub fn parse<A: CompactAddr>(buf: &[u8]) -> Result<Vec<([u8; 20], A)>, CompactError> {
+        let len = 20 + A::LEN;
+        if !buf.len().is_multiple_of(len) {
+            return Err(CompactError::BadLength(buf.len()));
+        }
+        Ok(buf
+            .chunks_exact(len)
+            .map(|chunk| {
+                let id = chunk[..20].try_into().unwrap();
+                (id, A::parse(&chunk[20..]).unwrap())
+            })
+            .collect())
+    }
+
+    pub fn write<A: CompactAddr>(nodes: &[([u8; 20], A)]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(nodes.len() * (20 + A::LEN));
+        for (id, addr) in nodes {
+            out.extend_from_slice(id);
+            addr.write(&mut out);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_peers_v4() {
+        let peers: Vec<SocketAddrV4> = vec!["1.2.3.4:6881".parse().unwrap()];
+        let buf = CompactPeers::write(&peers);
+
+        assert_eq!(buf, [1, 2, 3, 4, 0x1a, 0xe1]);
+        assert_eq!(CompactPeers::parse::<SocketAddrV4>(&buf), Ok(peers));
+        assert_eq!(
+            CompactPeers::parse::<SocketAddrV4>(&buf[..5]),
+            Err(CompactError::BadLength(5))
+        );
+    }
+
+    #[test]
+    fn test_peers_v6() {
+        let peers: Vec<SocketAddrV6> = vec!["[2001:db8::1]:51413".parse().unwrap()];
+        let buf = CompactPeers::write(&peers);
+
+        assert_eq!(buf.len(), 18);
+        assert_eq!(CompactPeers::parse::<SocketAddrV6>(&buf), Ok(peers));
+        // 18 bytes is three IPv4 peers, but 17 is nothing
+        assert_eq!(CompactPeers::parse::<SocketAddrV4>(&buf).unwrap().len(), 3);
+        assert!(CompactPeers::parse::<SocketAddrV6>(&buf[1..]).is_err());
+    }
+
+    #[test]
+    fn test_parse_one() {
+        let v6: SocketAddr = "[::1]:80".parse().unwrap();
+
+        assert_eq!(
+            CompactPeers::parse_one(&CompactPeers::write_one(&v6)),
+            Ok(v6)
+        );
+        assert_eq!(
+            CompactPeers::parse_one(&[0; 7]),
+            Err(CompactError::BadLength(7))
+        );
+    }
+
+    #[test]
+    fn test_nodes() {
+        let v4 = vec![([7; 20], "1.2.3.4:6881".parse::<SocketAddrV4>().unwrap())];
+        let v6 = vec![([8; 20], "[::2]:6881".parse::<SocketAddrV6>().unwrap())];
+
+        assert_eq!(CompactNodes::write(&v4).len(), 26);
+        assert_eq!(CompactNodes::write(&v6).len(), 38);
+        assert_eq!(CompactNodes::parse(&CompactNodes::write(&v4)), Ok(v4));
+        assert_eq!(CompactNodes::parse(&CompactNodes::write(&v6)), Ok(v6));
+        assert_eq!(
+            CompactNodes::parse::<SocketAddrV4>(&[0; 25]),
+            Err(CompactError::BadLength(25))
+        );
+    }
+}
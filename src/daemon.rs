@@ -0,0 +1,1874 @@
+// `hurricane daemon`: the session, run headless and driven over a local control socket, so GUIs
+// and scripts can do what they'd do with transmission-daemon.
+// The protocol is JSON-RPC 2.0, one message per line over TCP, or over TLS when `rpc.tls_cert` and
+// `rpc.tls_key` are set (see `tls`). Every request goes through `RpcGuard`: listening off loopback
+// needs TLS and a token, which a client hands over once with `auth`.
+// Methods, with their params:
+//   add {torrent: path | magnet: uri, save_path?, label?, paused?} -> {handle, merged}
+//   remove {handles, delete_files?}, pause {handles}, resume {handles} -> [null | error]
+//   set_limits {handles, download_rate?, upload_rate?, max_peers?} -> [null | error]
+//   set_queue_limits {downloading?, seeding?}, set_bandwidth_schedule {schedule} -> null
+//   set_queue_position {handle, position}, move_in_queue {handles, direction} -> null | [..]
+//   status {handles?} -> [torrent], session {} -> totals and the limits in effect
+//   stats {} -> the session's metrics and each torrent's, see `metrics`
+//   peers {handle} -> [peer], each connection's client, flags, rates and progress
+//   reload_blocklist {} -> {ranges}, reading `blocklist` again after it was updated
+//   subscribe {types?} -> true, then `event` notifications, see `event_json`. `types` picks
+//   which, e.g. ["completed", "error"], all of them by default
+//   shutdown {} -> true, then `shutdown::run`
+// Batch methods answer with one entry per handle: null when it worked, the error otherwise.
+// Limits left out of `set_limits` or `set_queue_limits`, or null, are lifted.
+// Torrents dropped into the watch directories are added too, see `watch`. The same metrics are
+// served to Prometheus on `metrics_listen`, when it's set. With `stream` set, the files of every
+// torrent that has its info dict can be played over HTTP while they download, see `stream`; a
+// torrent's status has its URL. A magnet link can be a BEP 46 one: the session follows it in a DHT
+// node of the daemon's own, and its handle gets each new version of the torrent, see
+// `Session::follow`.
+// The session state is saved to the state directory every so often and on the way out, and
+// restored on startup: torrents with valid resume data pick up where they were, the rest are
+// checked on a thread of their own so the socket stays responsive meanwhile.
+// Every torrent that's downloading or seeding has a `Download` attached, running its peers, its
+// announces and its DHT lookups. They all listen on the one peer port: the daemon reads each
+// incoming connection's handshake and hands it to the torrent it's for. With `port_mapping` the
+// router is asked to forward that port and each torrent's DHT port to us, see `portmap`.
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use rustls::ServerConfig;
+use serde_json::{Value, json};
+
+use crate::dht::{DhtConfig, DhtSocket};
+use crate::disk::{DiskScheduler, IoCacheStats, Validated, recover};
+use crate::download::{Download, DownloadConfig, DownloadState, PeerStream, local_utc_offset};
+use crate::events::{Event, EventKind, Subscription};
+use crate::metainfo::magnet::MagnetError;
+use crate::metainfo::{MagnetLink, Metainfo, MutableMagnet};
+use crate::metrics::{MetricsServer, SessionMetrics};
+use crate::peer::handshake::{Handshake, generate_peer_id};
+use crate::peer::listen::{IpFamilies, Listeners};
+use crate::peer::stats::PeerStats;
+use crate::persist;
+use crate::portmap::{
+    MappingState, Method, PortMapClient, PortMapHandle, PortMapper, Protocol, natpmp,
+};
+use crate::rng::Rng;
+use crate::rpc::{RpcConfig, RpcError, RpcGuard};
+use crate::schedule::{LocalTime, RateLimits};
+use crate::session::{
+    AddDefaults, Added, QueueLimits, QueueMove, RemoveOptions, Session, SessionError, TorrentHandle,
+};
+use crate::shutdown::{self, ShutdownConfig, ShutdownHooks, ShutdownReport};
+use crate::stream::{StreamConfig, StreamServer};
+use crate::suspend::SuspendDetector;
+use crate::tls::{self, RpcStream};
+use crate::torrent::{SeedAction, SeedGoal, Torrent, TorrentLimits, TorrentStatus};
+use crate::watch::{WatchDir, Watcher};
+
+pub use crate::rpc::DEFAULT_PORT;
+
+// For the proxy's UDP relay, when the DHT that follows BEP 46 links goes through one
+const PROXY_TIMEOUT: Duration = Duration::from_secs(5);
+// How long `run` sleeps between polls
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+// A client sending longer lines than this is dropped
+const MAX_LINE: usize = 1 << 20;
+// And so is one that doesn't read what it asked for, e.g. a subscriber that went away
+const MAX_PENDING: usize = 16 << 20;
+// Threads reading from disk for streams
+const STREAM_READERS: usize = 2;
+
+// JSON-RPC error codes. The spec's own, then ours
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const UNAUTHORIZED: i64 = -32001;
+const RATE_LIMITED: i64 = -32002;
+const FAILED: i64 = -32003;
+
+#[derive(Debug, Clone)]
+pub struct DaemonConfig {
+    // None serves no control socket, for a program that makes its `call`s itself, like the
+    // Python bindings
+    pub listen: Option<SocketAddr>,
+    pub rpc: RpcConfig,
+    // Where the session is saved. None keeps it in memory only
+    pub state_dir: Option<PathBuf>,
+    // For torrents added without a save path of their own
+    pub save_path: PathBuf,
+    // What each torrent's peers run with: the port we listen on and announce, max_peers, DHT,
+    // the proxy and so on. Its `output_dir`, `seed_after` and `schedule` don't apply, the
+    // torrent's save path and the session's seed goals and schedule do
+    pub peers: DownloadConfig,
+    pub save_interval: Duration,
+    pub shutdown_timeout: Duration,
+    // Scanned every tick for new .torrent and .magnet files
+    pub watch_dirs: Vec<WatchDir>,
+    // Where `/metrics` is served. Bound like `listen`, but there's no token to ask for, so off
+    // loopback it only needs to be in `rpc.allowed_binds`
+    pub metrics_listen: Option<SocketAddr>,
+    // Where torrents' files are streamed. Off loopback it needs to be in `rpc.allowed_binds` like
+    // the metrics, anyone who reaches it can read them
+    pub stream: Option<StreamConfig>,
+    // Forward the peer port and the DHT's on the router, over NAT-PMP or UPnP. Never behind a
+    // strict proxy, there's nothing to forward to
+    pub port_mapping: bool,
+    // An IP blocklist, see `blocklist`. Loaded on startup, a list that fails to load is an error
+    pub blocklist: Option<PathBuf>,
+}
+
+impl DaemonConfig {
+    pub fn new(save_path: PathBuf) -> Self {
+        DaemonConfig {
+            listen: Some(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), DEFAULT_PORT)),
+            rpc: RpcConfig::default(),
+            state_dir: None,
+            peers: DownloadConfig::new(save_path.clone()),
+            save_path,
+            save_interval: Duration::from_secs(300),
+            shutdown_timeout: Duration::from_secs(10),
+            watch_dirs: vec![],
+            metrics_listen: None,
+            stream: None,
+            port_mapping: false,
+            blocklist: None,
+        }
+    }
+}
+
+// Why a call failed, sent back as a JSON-RPC error
+#[derive(PartialEq, Debug)]
+pub struct RpcFault {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcFault {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcFault {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn params(message: impl Into<String>) -> Self {
+        RpcFault::new(INVALID_PARAMS, message)
+    }
+}
+
+impl From<RpcError> for RpcFault {
+    fn from(err: RpcError) -> Self {
+        match err {
+            RpcError::RateLimited => RpcFault::new(RATE_LIMITED, "rate limited"),
+            _ => RpcFault::new(UNAUTHORIZED, "unauthorized"),
+        }
+    }
+}
+
+impl From<SessionError> for RpcFault {
+    fn from(err: SessionError) -> Self {
+        RpcFault::new(FAILED, session_error(&err))
+    }
+}
+
+type Checked = (TorrentHandle, io::Result<Validated>);
+// A peer's connection, with the handshake that says which torrent it's for
+type Incoming = (PeerStream, SocketAddr, Handshake);
+
+struct Client {
+    stream: RpcStream,
+    ip: IpAddr,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    // As `RpcGuard::authorize` takes it, from the client's `auth` call
+    authorization: Option<String>,
+    subscribed: bool,
+    // Empty for all of them
+    types: Vec<String>,
+    closed: bool,
+}
+
+pub struct Daemon {
+    config: DaemonConfig,
+    session: Session,
+    guard: RpcGuard,
+    listener: Option<TcpListener>,
+    // None serves the socket in the clear, only allowed on loopback
+    tls: Option<Arc<ServerConfig>>,
+    metrics: Option<MetricsServer>,
+    stream: Option<StreamServer>,
+    // The torrents `stream` has been told about
+    streamed: BTreeSet<TorrentHandle>,
+    clients: Vec<Client>,
+    // Where peers connect, for every torrent. None behind a strict proxy
+    peer_listeners: Option<Listeners>,
+    peer_port: u16,
+    portmap: Option<PortMapHandle>,
+    // The engines' DHT ports that `portmap` has been asked for
+    mapped_dht_ports: BTreeSet<u16>,
+    // Connections whose handshake came in, from the threads reading them
+    incoming: (Sender<Incoming>, Receiver<Incoming>),
+    // What runs the peers of each torrent that's downloading or seeding
+    engines: BTreeMap<TorrentHandle, Download>,
+    // Looks up the BEP 46 links the session follows, started with the first of them
+    feeds_dht: Option<DhtSocket>,
+    // Every torrent announces as us
+    peer_id: [u8; 20],
+    key: u32,
+    // File checks running on their threads report back here
+    checks: (Sender<Checked>, Receiver<Checked>),
+    // The status of every torrent as of the last tick, to tell subscribers what changed
+    subscription: Subscription<Event>,
+    // Not sent to subscribers yet
+    events: Vec<Value>,
+    rate_limits: RateLimits,
+    // What the disk caches of the engines stopped so far did, see `DiskTotals`
+    retired: DiskTotals,
+    watcher: Watcher,
+    suspend: SuspendDetector,
+    next_tick: Instant,
+    next_save: Instant,
+}
+
+impl Daemon {
+    // Restores the session from the state directory, if there's one to restore, and starts
+    // listening
+    pub fn bind(config: DaemonConfig) -> io::Result<Daemon> {
+        let guard = RpcGuard::new(config.rpc.clone());
+        let tls = tls::server_config(&config.rpc)?;
+        let listener = match config.listen {
+            Some(addr) => {
+                guard.check_bind(&addr).map_err(|err| {
+                    io::Error::new(io::ErrorKind::PermissionDenied, format!("{:?}", err))
+                })?;
+                let listener = TcpListener::bind(addr)?;
+                listener.set_nonblocking(true)?;
+                Some(listener)
+            }
+            None => None,
+        };
+        let metrics = match config.metrics_listen {
+            Some(addr) => {
+                check_open_bind(&config.rpc, addr, "metrics")?;
+                Some(MetricsServer::bind(addr)?)
+            }
+            None => None,
+        };
+        let stream = match &config.stream {
+            Some(stream) => {
+                check_open_bind(&config.rpc, stream.addr, "streams")?;
+                let scheduler = Arc::new(DiskScheduler::new(STREAM_READERS));
+                Some(StreamServer::bind(stream.clone(), scheduler)?)
+            }
+            None => None,
+        };
+        let peer_listeners = match &config.peers.proxy {
+            Some(proxy) if !proxy.allows_direct() => None,
+            _ => Some(Listeners::bind(config.peers.port, IpFamilies::default())?),
+        };
+        let peer_port = peer_listeners
+            .iter()
+            .flat_map(Listeners::local_addrs)
+            .next()
+            .map_or(config.peers.port, |addr| addr.port());
+        let portmap = (config.port_mapping && peer_listeners.is_some()).then(|| {
+            let client = PortMapClient::new(PortMapper::new(), natpmp::default_gateway());
+            let portmap = client.spawn();
+            portmap.add(Protocol::Tcp, peer_port);
+            portmap
+        });
+
+        // Torrents from a watch directory without a save path of its own go where the rest do
+        let mut watch_dirs = config.watch_dirs.clone();
+        for dir in &mut watch_dirs {
+            dir.defaults
+                .save_path
+                .get_or_insert_with(|| config.save_path.clone());
+        }
+
+        let now = Instant::now();
+        let mut rng = Rng::new();
+        let mut session = Session::new();
+        if let Some(path) = &config.blocklist {
+            session.ip_filter().load(path).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("loading {}: {}", path.display(), err),
+                )
+            })?;
+        }
+        let subscription = session.subscribe();
+        let mut daemon = Daemon {
+            session,
+            guard,
+            listener,
+            tls,
+            metrics,
+            stream,
+            streamed: BTreeSet::new(),
+            clients: vec![],
+            peer_listeners,
+            peer_port,
+            portmap,
+            mapped_dht_ports: BTreeSet::new(),
+            incoming: mpsc::channel(),
+            engines: BTreeMap::new(),
+            feeds_dht: None,
+            peer_id: generate_peer_id(&mut rng),
+            key: rng.next_u64() as u32,
+            checks: mpsc::channel(),
+            subscription,
+            events: vec![],
+            rate_limits: RateLimits::default(),
+            retired: DiskTotals::default(),
+            watcher: Watcher::new(watch_dirs),
+            suspend: SuspendDetector::default(),
+            next_tick: now,
+            next_save: now + config.save_interval,
+            config,
+        };
+        if let Some(dir) = daemon.config.state_dir.clone()
+            && let Some(restored) = persist::load(&dir, now)?
+        {
+            daemon.restore(restored, now)?;
+        }
+        Ok(daemon)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match &self.listener {
+            Some(listener) => listener.local_addr(),
+            None => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    // Where peers connect, with the port every torrent announces
+    pub fn peer_port(&self) -> u16 {
+        self.peer_port
+    }
+
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics.as_ref()?.local_addr().ok()
+    }
+
+    pub fn stream_addr(&self) -> Option<SocketAddr> {
+        Some(self.stream.as_ref()?.local_addr())
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    pub fn session_mut(&mut self) -> &mut Session {
+        &mut self.session
+    }
+
+    // Serves clients until a shutdown is requested, by the `shutdown` call or a signal, then
+    // shuts down in order
+    pub fn run(mut self) -> ShutdownReport {
+        while !shutdown::requested() {
+            self.poll(Instant::now());
+            thread::sleep(POLL_INTERVAL);
+        }
+        self.shutdown()
+    }
+
+    // The torrents' peers stop, the trackers hear we're leaving and the session is saved, see
+    // `shutdown::run`
+    pub fn shutdown(mut self) -> ShutdownReport {
+        let mut config = ShutdownConfig::new(self.peer_id, self.peer_port, self.key);
+        config.timeout = self.config.shutdown_timeout;
+        config.state_dir = self.config.state_dir.clone();
+        config.proxy = self.config.peers.proxy.clone();
+        let mut engines = Engines {
+            engines: std::mem::take(&mut self.engines),
+            portmap: self.portmap.take(),
+        };
+        shutdown::run(&self.session, &config, &mut engines)
+    }
+
+    // One round of everything: new clients, their requests, finished checks, and once a second
+    // the torrents' tick and the events it brings
+    pub fn poll(&mut self, now: Instant) {
+        if self.suspend.check(now, SystemTime::now()).is_some() {
+            self.on_wake(now);
+        }
+        while let Some(listener) = &self.listener {
+            match listener.accept() {
+                Ok((stream, addr)) => {
+                    if stream.set_nonblocking(true).is_err() {
+                        continue;
+                    }
+                    if let Ok(stream) = RpcStream::accept(stream, self.tls.as_ref()) {
+                        self.clients.push(Client {
+                            stream,
+                            ip: addr.ip(),
+                            input: vec![],
+                            output: vec![],
+                            authorization: None,
+                            subscribed: false,
+                            types: vec![],
+                            closed: false,
+                        });
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => break,
+            }
+        }
+
+        let mut clients = std::mem::take(&mut self.clients);
+        for client in &mut clients {
+            self.serve(client, now);
+        }
+        self.clients = clients;
+        if let Some(metrics) = &self.metrics {
+            metrics.poll(|| self.metrics().to_prometheus());
+        }
+
+        while let Ok((handle, checked)) = self.checks.1.try_recv() {
+            let Some(mut torrent) = self.session.get_mut(handle) else {
+                continue;
+            };
+            match checked {
+                Ok(checked) => torrent.apply_check(&checked),
+                Err(err) => torrent.set_error(format!("checking files: {}", err)),
+            }
+            drop(torrent);
+            self.session.update_queue();
+        }
+
+        self.accept_peers();
+        self.run_engines(now);
+        self.follow_feeds(now);
+        self.map_dht_ports();
+        // What streams are about to read goes first
+        if let Some(stream) = &self.stream {
+            while let Some(boost) = stream.poll_boost() {
+                if let Some(mut torrent) = self.session.get_mut(boost.handle) {
+                    torrent.set_streaming(Some(boost.pieces));
+                }
+            }
+        }
+        if now >= self.next_tick {
+            self.next_tick = now + TICK_INTERVAL;
+            self.tick(now);
+        }
+
+        let events = std::mem::take(&mut self.events);
+        for client in &mut self.clients {
+            if client.subscribed {
+                for event in &events {
+                    let wanted = client.types.is_empty()
+                        || client.types.iter().any(|t| event["type"] == t.as_str());
+                    if !wanted {
+                        continue;
+                    }
+                    let notification = json!({
+                        "jsonrpc": "2.0",
+                        "method": "event",
+                        "params": event,
+                    });
+                    send(client, &notification);
+                }
+            }
+            flush(client);
+        }
+        self.clients.retain(|client| !client.closed);
+    }
+
+    // Handles one call. `subscribe` and `auth` are about the connection, so the socket deals
+    // with them; this is everything else
+    pub fn call(&mut self, method: &str, params: &Value, now: Instant) -> Result<Value, RpcFault> {
+        match method {
+            "add" => self.add(params, now),
+            "remove" => {
+                let handles = handles(params)?;
+                let options = RemoveOptions {
+                    delete_files: flag(params, "delete_files")?,
+                };
+                // Before their files go
+                self.stop_engines(|handle| handles.contains(&handle));
+                Ok(results(self.session.remove(&handles, options)))
+            }
+            "pause" => {
+                let done = self.session.pause(&handles(params)?);
+                self.session.update_queue();
+                Ok(results(done))
+            }
+            "resume" => {
+                let done = self.session.resume(&handles(params)?);
+                self.session.update_queue();
+                Ok(results(done))
+            }
+            "set_limits" => {
+                let limits = TorrentLimits {
+                    download_rate: integer(params, "download_rate")?,
+                    upload_rate: integer(params, "upload_rate")?,
+                    max_peers: integer(params, "max_peers")?.map(|n| n as usize),
+                };
+                Ok(results(self.session.set_limits(&handles(params)?, limits)))
+            }
+            "set_queue_limits" => {
+                self.session.set_queue_limits(QueueLimits {
+                    downloading: integer(params, "downloading")?.map(|n| n as usize),
+                    seeding: integer(params, "seeding")?.map(|n| n as usize),
+                });
+                self.session.update_queue();
+                Ok(Value::Null)
+            }
+            "set_bandwidth_schedule" => {
+                let text = string(params, "schedule")?
+                    .ok_or_else(|| RpcFault::params("missing schedule"))?;
+                let schedule = text
+                    .parse()
+                    .map_err(|err| RpcFault::params(format!("{}", err)))?;
+                self.session.set_bandwidth_schedule(schedule);
+                Ok(Value::Null)
+            }
+            "set_queue_position" => {
+                let handle = TorrentHandle(
+                    integer(params, "handle")?.ok_or_else(|| RpcFault::params("missing handle"))?,
+                );
+                let position = integer(params, "position")?
+                    .ok_or_else(|| RpcFault::params("missing position"))?;
+                self.session.set_queue_position(handle, position as usize)?;
+                self.session.update_queue();
+                Ok(Value::Null)
+            }
+            "move_in_queue" => {
+                let direction = match string(params, "direction")? {
+                    Some("up") => QueueMove::Up,
+                    Some("down") => QueueMove::Down,
+                    Some("top") => QueueMove::Top,
+                    Some("bottom") => QueueMove::Bottom,
+                    _ => return Err(RpcFault::params("direction is up, down, top or bottom")),
+                };
+                let done = self.session.move_in_queue(&handles(params)?, direction);
+                self.session.update_queue();
+                Ok(results(done))
+            }
+            "status" => {
+                let handles = match params.get("handles") {
+                    Some(_) => handles(params)?,
+                    None => self.session.handles().collect(),
+                };
+                Ok(Value::Array(
+                    handles
+                        .into_iter()
+                        .map(|handle| match self.session.contains(handle) {
+                            true => self.torrent_status(handle, now),
+                            false => Value::Null,
+                        })
+                        .collect(),
+                ))
+            }
+            "session" => Ok(self.session_status()),
+            "peers" => {
+                let handle = TorrentHandle(
+                    integer(params, "handle")?.ok_or_else(|| RpcFault::params("missing handle"))?,
+                );
+                let torrent = self
+                    .session
+                    .get(handle)
+                    .ok_or(SessionError::UnknownTorrent)?;
+                Ok(Value::Array(
+                    torrent.peer_stats().iter().map(peer_json).collect(),
+                ))
+            }
+            "reload_blocklist" => {
+                let Some(path) = &self.config.blocklist else {
+                    return Err(RpcFault::new(FAILED, "no blocklist configured"));
+                };
+                match self.session.ip_filter().load(path) {
+                    Ok(ranges) => Ok(json!({"ranges": ranges})),
+                    Err(err) => Err(RpcFault::new(FAILED, err.to_string())),
+                }
+            }
+            "stats" => Ok(stats_json(&self.metrics())),
+            "shutdown" => {
+                shutdown::request();
+                Ok(Value::Bool(true))
+            }
+            _ => Err(RpcFault::new(METHOD_NOT_FOUND, "no such method")),
+        }
+    }
+
+    fn add(&mut self, params: &Value, now: Instant) -> Result<Value, RpcFault> {
+        let mut torrent = match (string(params, "torrent")?, string(params, "magnet")?) {
+            (Some(path), None) => {
+                let buf = std::fs::read(path).map_err(SessionError::from)?;
+                let metainfo = Metainfo::from_bytes(&buf).map_err(SessionError::InvalidTorrent)?;
+                Torrent::new(metainfo, now)
+            }
+            (None, Some(uri)) => match MagnetLink::parse(uri) {
+                Ok(magnet) => Torrent::from_magnet(&magnet, now),
+                // A BEP 46 link, whose torrent comes once the DHT has its current version
+                Err(MagnetError::MissingInfoHash) => {
+                    let magnet = MutableMagnet::parse(uri)
+                        .map_err(|err| RpcFault::params(err.to_string()))?;
+                    let defaults = AddDefaults {
+                        save_path: Some(self.save_path(params)?),
+                        label: string(params, "label")?.map(str::to_string),
+                        paused: flag(params, "paused")?,
+                        ..AddDefaults::default()
+                    };
+                    let handle = self.session.follow(magnet, defaults, now);
+                    return Ok(json!({"handle": handle.0, "merged": false}));
+                }
+                Err(err) => return Err(RpcFault::params(err.to_string())),
+            },
+            _ => return Err(RpcFault::params("one of torrent or magnet")),
+        };
+        let save_path = self.save_path(params)?;
+        torrent.set_save_path(save_path);
+        torrent.set_label(string(params, "label")?.map(str::to_string));
+        if flag(params, "paused")? {
+            torrent.set_status(TorrentStatus::Paused);
+        }
+
+        let added = self.session.add(torrent, now);
+        if let Added::New(handle) = added {
+            self.check(handle);
+        }
+        self.session.update_queue();
+        Ok(json!({
+            "handle": added.handle().0,
+            "merged": matches!(added, Added::Merged(_)),
+        }))
+    }
+
+    fn save_path(&self, params: &Value) -> Result<PathBuf, RpcFault> {
+        Ok(match string(params, "save_path")? {
+            Some(path) => PathBuf::from(path),
+            None => self.config.save_path.clone(),
+        })
+    }
+
+    // What was there before isn't news: events start from here
+    fn restore(&mut self, restored: persist::Restored, now: Instant) -> io::Result<()> {
+        self.session = restored.session;
+        let mut resume: BTreeMap<_, _> = restored.resume.into_iter().collect();
+        let handles: Vec<TorrentHandle> = self.session.handles().collect();
+        for handle in handles {
+            let mut torrent = self.session.get_mut(handle).unwrap();
+            if torrent.save_path().is_none() {
+                torrent.set_save_path(self.config.save_path.clone());
+            }
+            let Some(storage) = torrent.storage() else {
+                continue;
+            };
+            let validated = match resume.get(&handle) {
+                Some(data) => data.validate(torrent.info_hash(), &storage)?,
+                None => None,
+            };
+            match (validated, resume.remove(&handle)) {
+                (Some(validated), Some(data)) => torrent.apply_resume(&data, &validated, now),
+                _ => {
+                    drop(torrent);
+                    self.check(handle);
+                }
+            }
+        }
+        self.session.update_queue();
+        self.session.publish_events();
+        self.subscription = self.session.subscribe();
+        Ok(())
+    }
+
+    // Reads the handshake of each new connection on a thread of its own, after MSE if it's
+    // encrypted, and hands the ones that have theirs in to the torrent they're for. Ones for
+    // torrents that aren't running are dropped
+    fn accept_peers(&mut self) {
+        let Some(listeners) = &self.peer_listeners else {
+            return;
+        };
+        while let Ok(Some((stream, addr))) = listeners.accept() {
+            let sender = self.incoming.0.clone();
+            let torrents = self.engines.values().map(Download::info_hash).collect();
+            let policy = self.config.peers.encryption;
+            thread::spawn(move || {
+                if let Ok((stream, theirs)) = PeerStream::accept(stream, torrents, policy) {
+                    let _ = sender.send((stream, addr, theirs));
+                }
+            });
+        }
+        while let Ok((stream, addr, theirs)) = self.incoming.1.try_recv() {
+            let engine = self
+                .session
+                .find(theirs.info_hash)
+                .and_then(|handle| self.engines.get_mut(&handle));
+            if let Some(engine) = engine {
+                engine.accept(stream, addr, theirs);
+            }
+        }
+    }
+
+    // Starts the peers of every torrent that's downloading or seeding, stops those of the ones
+    // that aren't any more, and polls the rest. A torrent whose peers fail goes to `Error`
+    fn run_engines(&mut self, now: Instant) {
+        let (mut active, mut checking) = (vec![], vec![]);
+        for handle in self.session.handles() {
+            match self.session.get(handle).unwrap().status() {
+                TorrentStatus::DownloadingMetadata
+                | TorrentStatus::Downloading
+                | TorrentStatus::Seeding => active.push(handle),
+                // Its peers just brought the info dict and are checking what of it is on disk.
+                // Checks of our own stop them, see `check`
+                TorrentStatus::CheckingFiles => checking.push(handle),
+                _ => {}
+            }
+        }
+        self.stop_engines(|handle| !active.contains(&handle) && !checking.contains(&handle));
+        for handle in active {
+            if self.engines.contains_key(&handle) {
+                continue;
+            }
+            let torrent = self.session.shared(handle).unwrap();
+            let mut config = self.config.peers.clone();
+            config.port = self.peer_port;
+            config.ip_filter = self.session.ip_filter().clone();
+            if let Some(max_peers) = torrent.lock().unwrap().limits().max_peers {
+                config.max_peers = max_peers;
+            }
+            let engine = Download::attach(torrent, config, self.peer_id, self.key);
+            self.engines.insert(handle, engine);
+        }
+        for (handle, engine) in &mut self.engines {
+            if let DownloadState::Failed(reason) = engine.poll(now) {
+                let mut torrent = self.session.get_mut(*handle).unwrap();
+                if torrent.status() != TorrentStatus::Error {
+                    torrent.set_error(reason);
+                }
+            }
+        }
+    }
+
+    // Back from a suspend: every torrent announces again, and its engine has its DHT node and its
+    // peers' requests catch up. The router may have forgotten our mappings too
+    fn on_wake(&mut self, now: Instant) {
+        self.session.on_wake(now);
+        for engine in self.engines.values() {
+            engine.on_wake(now);
+        }
+        if let Some(portmap) = &self.portmap {
+            portmap.on_wake();
+        }
+        if let Some(dht) = &mut self.feeds_dht {
+            dht.dht().on_wake(now);
+        }
+    }
+
+    // A torrent that moved to a new version starts over: its engine and its stream were for the
+    // old info-hash
+    fn follow_feeds(&mut self, now: Instant) {
+        let Some(dht) = &mut self.feeds_dht else {
+            return;
+        };
+        self.session.poll_feeds(dht.dht(), now);
+        let events = match dht.poll(Duration::ZERO) {
+            Ok(events) => events,
+            // Started again on the next tick
+            Err(_) => {
+                self.feeds_dht = None;
+                return;
+            }
+        };
+        let mut updated = vec![];
+        for event in &events {
+            updated.extend(self.session.on_dht_event(event, now));
+        }
+        if updated.is_empty() {
+            return;
+        }
+        self.stop_engines(|handle| updated.contains(&handle));
+        if let Some(stream) = &self.stream {
+            for handle in &updated {
+                if self.streamed.remove(handle) {
+                    stream.remove(*handle);
+                }
+            }
+        }
+        self.session.update_queue();
+    }
+
+    // Like each engine's, through the proxy when there is one
+    fn start_feeds_dht(&mut self) {
+        if self.feeds_dht.is_some() || !self.session.has_feeds() {
+            return;
+        }
+        let proxy = &self.config.peers.proxy;
+        let proxied = proxy
+            .as_ref()
+            .map(|proxy| DhtSocket::start_via(proxy, DhtConfig::default(), PROXY_TIMEOUT));
+        self.feeds_dht = match proxied {
+            Some(Ok(socket)) => Some(socket),
+            Some(Err(_)) if !proxy.as_ref().unwrap().allows_direct() => None,
+            _ => {
+                let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+                DhtSocket::start(addr, DhtConfig::default(), None).ok()
+            }
+        };
+    }
+
+    // Every engine runs a DHT node of its own, which gets a mapping as long as it runs
+    fn map_dht_ports(&mut self) {
+        let Some(portmap) = &self.portmap else {
+            return;
+        };
+        let feeds_port = self
+            .feeds_dht
+            .as_ref()
+            .filter(|_| self.config.peers.proxy.is_none())
+            .and_then(|dht| dht.local_addr().ok())
+            .map(|addr| addr.port());
+        let ports: BTreeSet<u16> = self
+            .engines
+            .values()
+            .filter_map(Download::dht_port)
+            .chain(feeds_port)
+            .collect();
+        for port in ports.difference(&self.mapped_dht_ports) {
+            portmap.add(Protocol::Udp, *port);
+        }
+        for port in self.mapped_dht_ports.difference(&ports) {
+            portmap.remove(Protocol::Udp, *port);
+        }
+        self.mapped_dht_ports = ports;
+    }
+
+    // Each on a thread of its own, so a tracker that's slow with the `stopped` announce doesn't
+    // hold up the socket
+    fn stop_engines(&mut self, stop: impl Fn(TorrentHandle) -> bool) {
+        let handles: Vec<TorrentHandle> =
+            self.engines.keys().copied().filter(|h| stop(*h)).collect();
+        for handle in handles {
+            let engine = self.engines.remove(&handle).unwrap();
+            self.retired.add(&engine);
+            thread::spawn(move || engine.stop());
+        }
+    }
+
+    // Checks what of the torrent's data is on disk, on a thread of its own
+    fn check(&mut self, handle: TorrentHandle) {
+        self.stop_engines(|h| h == handle);
+        let Some(storage) = self.session.get(handle).and_then(|t| t.storage()) else {
+            return;
+        };
+        let sender = self.checks.0.clone();
+        thread::spawn(move || {
+            let _ = sender.send((handle, recover(&storage)));
+        });
+    }
+
+    fn tick(&mut self, now: Instant) {
+        let handles: Vec<TorrentHandle> = self.session.handles().collect();
+        for handle in &handles {
+            self.session.get_mut(*handle).unwrap().tick(now);
+        }
+        // Those with data on disk already are in `due_checks`
+        self.watcher.scan(&mut self.session, now);
+        for handle in self.session.due_checks(now) {
+            self.check(handle);
+        }
+        self.start_feeds_dht();
+        let at = LocalTime::from_system(SystemTime::now(), local_utc_offset());
+        if let Some(limits) = self.session.poll_rate_limits(at) {
+            self.rate_limits = limits;
+            self.config.peers.limiter.set_limits(limits, now);
+        }
+        self.session.check_seed_goals(now);
+        self.session.update_queue();
+
+        self.update_streams();
+        self.session.publish_events();
+        for event in self.subscription.try_iter() {
+            if let (Some(stream), EventKind::PieceVerified(piece)) = (&self.stream, &event.kind) {
+                stream.piece_done(event.handle, *piece);
+            }
+            self.events.push(event_json(&event));
+        }
+
+        self.guard.prune(now);
+        if now >= self.next_save {
+            self.next_save = now + self.config.save_interval;
+            if let Some(dir) = &self.config.state_dir {
+                // Tried again next time, and on the way out
+                let _ = persist::save(&self.session, dir);
+            }
+        }
+    }
+
+    // Torrents can be streamed once they have their info dict, until they're removed
+    fn update_streams(&mut self) {
+        let Some(stream) = &self.stream else {
+            return;
+        };
+        for handle in self.session.handles() {
+            if self.streamed.contains(&handle) {
+                continue;
+            }
+            let torrent = self.session.get(handle).unwrap();
+            let (Some(metainfo), Some(storage), Some(picker)) =
+                (torrent.metainfo(), torrent.storage(), torrent.picker())
+            else {
+                continue;
+            };
+            stream.add(handle, &metainfo.info, Arc::new(storage), picker.have());
+            self.streamed.insert(handle);
+        }
+        let session = &self.session;
+        self.streamed.retain(|handle| {
+            let kept = session.get(*handle).is_some();
+            if !kept {
+                stream.remove(*handle);
+            }
+            kept
+        });
+    }
+
+    fn serve(&mut self, client: &mut Client, now: Instant) {
+        let mut buf = [0; 4096];
+        loop {
+            match client.stream.read(&mut buf) {
+                Ok(0) => {
+                    client.closed = true;
+                    break;
+                }
+                Ok(n) => client.input.extend_from_slice(&buf[..n]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => {
+                    client.closed = true;
+                    break;
+                }
+            }
+        }
+
+        while let Some(end) = client.input.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = client.input.drain(..=end).collect();
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            if let Some(response) = self.handle_line(client, &line, now) {
+                send(client, &response);
+            }
+        }
+        if client.input.len() > MAX_LINE {
+            client.closed = true;
+        }
+    }
+
+    // The response to one line, None for notifications
+    fn handle_line(&mut self, client: &mut Client, line: &[u8], now: Instant) -> Option<Value> {
+        let request: Value = match serde_json::from_slice(line) {
+            Ok(request) => request,
+            Err(_) => {
+                return Some(error(
+                    Value::Null,
+                    RpcFault::new(PARSE_ERROR, "parse error"),
+                ));
+            }
+        };
+        let id = request.get("id").cloned();
+        let (Some(method), params) = (
+            request.get("method").and_then(Value::as_str),
+            request.get("params").cloned().unwrap_or(json!({})),
+        ) else {
+            let fault = RpcFault::new(INVALID_REQUEST, "invalid request");
+            return Some(error(id.unwrap_or(Value::Null), fault));
+        };
+
+        let result = self.handle_call(client, method, &params, now);
+        let id = id?;
+        Some(match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err(fault) => error(id, fault),
+        })
+    }
+
+    fn handle_call(
+        &mut self,
+        client: &mut Client,
+        method: &str,
+        params: &Value,
+        now: Instant,
+    ) -> Result<Value, RpcFault> {
+        if !params.is_object() {
+            return Err(RpcFault::params("params must be an object"));
+        }
+        if method == "auth" {
+            let token = string(params, "token")?.unwrap_or("");
+            let authorization = format!("Bearer {}", token);
+            self.guard.authorize(client.ip, Some(&authorization), now)?;
+            client.authorization = Some(authorization);
+            return Ok(Value::Bool(true));
+        }
+        self.guard
+            .authorize(client.ip, client.authorization.as_deref(), now)?;
+        match method {
+            "subscribe" => {
+                let types = match field(params, "types") {
+                    None | Some(Value::Null) => Some(vec![]),
+                    Some(types) => types.as_array().and_then(|types| {
+                        types
+                            .iter()
+                            .map(|t| t.as_str().filter(|t| EVENT_TYPES.contains(t)))
+                            .map(|t| t.map(str::to_string))
+                            .collect()
+                    }),
+                };
+                let Some(types) = types else {
+                    let names = EVENT_TYPES.join(", ");
+                    return Err(RpcFault::params(format!(
+                        "types must be a list of: {}",
+                        names
+                    )));
+                };
+                client.types = types;
+                client.subscribed = true;
+                Ok(Value::Bool(true))
+            }
+            _ => self.call(method, params, now),
+        }
+    }
+
+    fn torrent_status(&self, handle: TorrentHandle, now: Instant) -> Value {
+        let torrent = self.session.get(handle).unwrap();
+        let limits = torrent.limits();
+        json!({
+            "handle": handle.0,
+            "info_hash": torrent.info_hash().to_hex(),
+            "name": torrent.name(),
+            "label": torrent.label(),
+            "save_path": torrent.save_path().map(|path| path.display().to_string()),
+            "status": status_name(torrent.status()),
+            "force_start": torrent.is_force_started(),
+            "queue_position": self.session.queue_position(handle),
+            "progress": torrent.progress(),
+            "total_length": torrent.total_length(),
+            "bytes_done": torrent.bytes_done(),
+            "download_rate": torrent.download_rate().get() as u64,
+            "upload_rate": torrent.upload_rate().get() as u64,
+            "downloaded": torrent.total_downloaded(),
+            "uploaded": torrent.total_uploaded(),
+            "ratio": torrent.ratio(),
+            "seeding_time": torrent.seeding_time().as_secs(),
+            "idle_time": torrent.idle_time(now).as_secs(),
+            "eta": torrent.eta().map(|eta| eta.as_secs()),
+            "peers": torrent.num_peers(),
+            "seeds": torrent.num_seeds(),
+            "stream_url": self.streamed.contains(&handle).then(|| {
+                self.stream.as_ref().unwrap().url(handle, None)
+            }),
+            "limits": {
+                "download_rate": limits.download_rate,
+                "upload_rate": limits.upload_rate,
+                "max_peers": limits.max_peers,
+            },
+        })
+    }
+
+    // The session's, and what the engines know that it doesn't: how their disk caches did. And
+    // how the port mappings are going
+    fn metrics(&self) -> SessionMetrics {
+        let mut metrics = SessionMetrics::collect(&self.session);
+        let mut totals = self.retired;
+        for engine in self.engines.values() {
+            totals.add(engine);
+        }
+        let hashed = totals.hashed_cached + totals.hashed_disk;
+        if hashed > 0 {
+            metrics.cache_hit_ratio = Some(totals.hashed_cached as f64 / hashed as f64);
+        }
+        metrics.io_cache = Some(totals.io);
+        if let Some(portmap) = &self.portmap {
+            metrics.port_mappings = portmap.status();
+        }
+        metrics
+    }
+
+    fn session_status(&self) -> Value {
+        let (mut download_rate, mut upload_rate) = (0.0, 0.0);
+        for handle in self.session.handles() {
+            let torrent = self.session.get(handle).unwrap();
+            download_rate += torrent.download_rate().get();
+            upload_rate += torrent.upload_rate().get();
+        }
+        let queue_limits = self.session.queue_limits();
+        let filter = self.session.ip_filter();
+        let blocked = filter.counts();
+        json!({
+            "torrents": self.session.len(),
+            "download_rate": download_rate as u64,
+            "upload_rate": upload_rate as u64,
+            "rate_limits": {
+                "download": self.rate_limits.download,
+                "upload": self.rate_limits.upload,
+            },
+            "bandwidth_schedule": self.session.bandwidth_schedule().to_string(),
+            "queue_limits": {
+                "downloading": queue_limits.downloading,
+                "seeding": queue_limits.seeding,
+            },
+            "blocklist": {
+                "ranges": filter.list().len(),
+                "blocked": {
+                    "dials": blocked.dials,
+                    "accepts": blocked.accepts,
+                    "discovered": blocked.discovered,
+                },
+            },
+        })
+    }
+}
+
+// What the engines' disk caches have done between them. The daemon adds up those of the engines
+// it stops, so the totals only go up
+#[derive(Default, Clone, Copy)]
+struct DiskTotals {
+    // Bytes of finished pieces hashed from the write cache, and read back from disk
+    hashed_cached: u64,
+    hashed_disk: u64,
+    io: IoCacheStats,
+}
+
+impl DiskTotals {
+    fn add(&mut self, engine: &Download) {
+        let (cached, disk) = engine.hashed_bytes();
+        self.hashed_cached += cached;
+        self.hashed_disk += disk;
+        self.io += engine.io_stats().unwrap_or_default();
+    }
+}
+
+// The peers are closed on the way out without a `stopped` announce of their own: `shutdown::run`
+// sends those
+struct Engines {
+    engines: BTreeMap<TorrentHandle, Download>,
+    portmap: Option<PortMapHandle>,
+}
+
+impl ShutdownHooks for Engines {
+    fn close_peers(&mut self) {
+        for engine in std::mem::take(&mut self.engines).into_values() {
+            engine.close();
+        }
+    }
+
+    fn remove_port_mappings(&mut self, deadline: Instant) -> usize {
+        match &mut self.portmap {
+            Some(portmap) => portmap.shutdown(deadline),
+            None => 0,
+        }
+    }
+}
+
+// Listening off loopback is for addresses the RPC config allows
+fn check_open_bind(rpc: &RpcConfig, addr: SocketAddr, what: &str) -> io::Result<()> {
+    let ip = addr.ip();
+    if !ip.is_loopback() && !rpc.allowed_binds.contains(&ip) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} may not listen on {}", what, ip),
+        ));
+    }
+    Ok(())
+}
+
+fn send(client: &mut Client, message: &Value) {
+    client
+        .output
+        .extend_from_slice(message.to_string().as_bytes());
+    client.output.push(b'\n');
+    if client.output.len() > MAX_PENDING {
+        client.closed = true;
+    }
+}
+
+fn flush(client: &mut Client) {
+    while !client.output.is_empty() && !client.closed {
+        match client.stream.write(&client.output) {
+            Ok(0) => client.closed = true,
+            Ok(n) => {
+                client.output.drain(..n);
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => client.closed = true,
+        }
+    }
+}
+
+fn error(id: Value, fault: RpcFault) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {"code": fault.code, "message": fault.message},
+    })
+}
+
+fn results(results: Vec<Result<(), SessionError>>) -> Value {
+    Value::Array(
+        results
+            .iter()
+            .map(|result| match result {
+                Ok(()) => Value::Null,
+                Err(err) => Value::String(session_error(err)),
+            })
+            .collect(),
+    )
+}
+
+fn session_error(err: &SessionError) -> String {
+    match err {
+        SessionError::UnknownTorrent => "unknown torrent".to_string(),
+        SessionError::InvalidTorrent(err) => format!("invalid torrent: {}", err),
+        SessionError::InvalidMagnet(err) => format!("invalid magnet link: {}", err),
+        SessionError::MissingMetadata => "missing metadata".to_string(),
+        SessionError::Io(kind) => kind.to_string(),
+    }
+}
+
+const EVENT_TYPES: [&str; 12] = [
+    "added",
+    "removed",
+    "status",
+    "metadata",
+    "piece",
+    "completed",
+    "error",
+    "tracker_warning",
+    "tracker_error",
+    "peer_banned",
+    "seed_goal",
+    "updated",
+];
+
+// Every event has its `type`, the torrent's `handle` and `info_hash`, and what else its type has
+pub fn event_json(event: &Event) -> Value {
+    let mut value = match &event.kind {
+        EventKind::Added { name } => json!({"type": "added", "name": name}),
+        EventKind::Removed => json!({"type": "removed"}),
+        EventKind::StatusChanged(status) => {
+            json!({"type": "status", "status": status_name(*status)})
+        }
+        EventKind::MetadataReceived => json!({"type": "metadata"}),
+        EventKind::PieceVerified(piece) => json!({"type": "piece", "piece": piece}),
+        EventKind::Completed => json!({"type": "completed"}),
+        EventKind::Errored(message) => json!({"type": "error", "message": message}),
+        EventKind::TrackerWarning { url, message } => {
+            json!({"type": "tracker_warning", "url": url, "message": message})
+        }
+        EventKind::TrackerError { url, message } => {
+            json!({"type": "tracker_error", "url": url, "message": message})
+        }
+        EventKind::PeerBanned(addr) => json!({"type": "peer_banned", "addr": addr.to_string()}),
+        EventKind::SeedGoalReached { goal, action } => json!({
+            "type": "seed_goal",
+            "goal": match goal {
+                SeedGoal::Ratio => "ratio",
+                SeedGoal::SeedTime => "seed_time",
+                SeedGoal::IdleTime => "idle_time",
+            },
+            "action": match action {
+                SeedAction::Pause => "pause",
+                SeedAction::Remove => "remove",
+            },
+        }),
+        EventKind::Updated { previous } => {
+            json!({"type": "updated", "previous": previous.to_hex()})
+        }
+    };
+    value["handle"] = json!(event.handle.0);
+    value["info_hash"] = json!(event.info_hash.to_hex());
+    value
+}
+
+fn peer_json(peer: &PeerStats) -> Value {
+    json!({
+        "addr": peer.addr.to_string(),
+        "client": peer.client,
+        "flags": peer.flags.to_string(),
+        "incoming": peer.flags.incoming,
+        "encrypted": peer.flags.encrypted,
+        "utp": peer.flags.utp,
+        "choked": peer.flags.choked,
+        "interested": peer.flags.interested,
+        "snubbed": peer.flags.snubbed,
+        "download_rate": peer.download_rate() as u64,
+        "upload_rate": peer.upload_rate() as u64,
+        "downloaded": peer.downloaded(),
+        "uploaded": peer.uploaded(),
+        "progress": peer.progress(),
+        "queued_requests": peer.queued_requests,
+    })
+}
+
+fn stats_json(metrics: &SessionMetrics) -> Value {
+    let torrents: Vec<Value> = metrics
+        .torrents
+        .iter()
+        .map(|t| {
+            json!({
+                "handle": t.handle.0,
+                "info_hash": t.info_hash.to_hex(),
+                "name": t.name,
+                "downloaded": t.downloaded,
+                "uploaded": t.uploaded,
+                "download_rate": t.download_rate,
+                "upload_rate": t.upload_rate,
+                "connected_peers": t.connected_peers,
+                "known_peers": t.known_peers,
+                "hash_failures": t.hash_failures,
+                "progress": t.progress,
+            })
+        })
+        .collect();
+    json!({
+        "downloaded": metrics.downloaded,
+        "uploaded": metrics.uploaded,
+        "download_rate": metrics.download_rate,
+        "upload_rate": metrics.upload_rate,
+        "connected_peers": metrics.connected_peers,
+        "known_peers": metrics.known_peers,
+        "hash_failures": metrics.hash_failures,
+        "blocked": {
+            "dials": metrics.blocked.dials,
+            "accepts": metrics.blocked.accepts,
+            "discovered": metrics.blocked.discovered,
+        },
+        "dht_nodes": metrics.dht_nodes,
+        "cache_hit_ratio": metrics.cache_hit_ratio,
+        "io_cache": metrics.io_cache.map(|io| json!({
+            "read_hits": io.read_hits,
+            "read_misses": io.read_misses,
+            "blocks_written": io.blocks_written,
+            "write_runs": io.write_runs,
+            "coalescing": io.coalescing(),
+            "read_ahead_blocks": io.read_ahead_blocks,
+            "evicted_blocks": io.evicted_blocks,
+        })),
+        "port_mappings": metrics.port_mappings.iter().map(|mapping| {
+            let (state, method, external_port, error) = match &mapping.state {
+                MappingState::Pending => ("pending", None, None, None),
+                MappingState::Mapped { method, external_port, .. } => {
+                    let method = match method {
+                        Method::NatPmp => "natpmp",
+                        Method::Upnp => "upnp",
+                    };
+                    ("mapped", Some(method), Some(*external_port), None)
+                }
+                MappingState::Failed(err) => ("failed", None, None, Some(format!("{:?}", err))),
+            };
+            json!({
+                "protocol": match mapping.protocol {
+                    Protocol::Tcp => "tcp",
+                    Protocol::Udp => "udp",
+                },
+                "port": mapping.port,
+                "state": state,
+                "method": method,
+                "external_port": external_port,
+                "error": error,
+            })
+        }).collect::<Vec<Value>>(),
+        "torrents": torrents,
+    })
+}
+
+fn status_name(status: TorrentStatus) -> &'static str {
+    match status {
+        TorrentStatus::DownloadingMetadata => "downloading_metadata",
+        TorrentStatus::CheckingFiles => "checking_files",
+        TorrentStatus::Downloading => "downloading",
+        TorrentStatus::Seeding => "seeding",
+        TorrentStatus::Queued => "queued",
+        TorrentStatus::Paused => "paused",
+        TorrentStatus::Error => "error",
+    }
+}
+
+fn field<'a>(params: &'a Value, key: &str) -> Option<&'a Value> {
+    params.get(key)
+}
+
+fn handles(params: &Value) -> Result<Vec<TorrentHandle>, RpcFault> {
+    field(params, "handles")
+        .and_then(Value::as_array)
+        .ok_or_else(|| RpcFault::params("handles must be a list"))?
+        .iter()
+        .map(|handle| {
+            handle
+                .as_u64()
+                .map(TorrentHandle)
+                .ok_or_else(|| RpcFault::params("handles are integers"))
+        })
+        .collect()
+}
+
+fn string<'a>(params: &'a Value, key: &str) -> Result<Option<&'a str>, RpcFault> {
+    match field(params, key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s)),
+        Some(_) => Err(RpcFault::params(format!("{} must be a string", key))),
+    }
+}
+
+fn integer(params: &Value, key: &str) -> Result<Option<u64>, RpcFault> {
+    match field(params, key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value
+            .as_u64()
+            .map(Some)
+            .ok_or_else(|| RpcFault::params(format!("{} must be a whole number", key))),
+    }
+}
+
+fn flag(params: &Value, key: &str) -> Result<bool, RpcFault> {
+    match field(params, key) {
+        None | Some(Value::Null) => Ok(false),
+        Some(Value::Bool(b)) => Ok(*b),
+        Some(_) => Err(RpcFault::params(format!("{} must be true or false", key))),
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpStream;
+
+    use sha1::{Digest, Sha1};
+
+    use super::*;
+    use crate::infohash::InfoHash;
+    use crate::peer::handshake::{HANDSHAKE_LEN, Reserved};
+
+    fn daemon() -> Daemon {
+        let mut config = DaemonConfig::new(std::env::temp_dir());
+        config.peers.port = 0;
+        config.listen = Some("127.0.0.1:0".parse().unwrap());
+        Daemon::bind(config).unwrap()
+    }
+
+    fn magnet(byte: u8) -> String {
+        format!("magnet:?xt=urn:btih:{}", InfoHash([byte; 20]).to_hex())
+    }
+
+    // A seed for `data`, in 16 KiB pieces, out of `dir`
+    fn seed(dir: &std::path::Path, data: &[u8]) -> Download {
+        let pieces: Vec<u8> = data
+            .chunks(16384)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        let mut info = format!(
+            "d6:lengthi{}e4:name8:data.bin12:piece lengthi16384e6:pieces{}:",
+            data.len(),
+            pieces.len()
+        )
+        .into_bytes();
+        info.extend_from_slice(&pieces);
+        info.push(b'e');
+        let metainfo = Metainfo::from_info_bytes(&info).unwrap();
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join("data.bin"), data).unwrap();
+        let mut config = DownloadConfig::new(dir.to_path_buf());
+        (config.port, config.dht) = (0, false);
+        Download::start(Torrent::new(metainfo, Instant::now()), config).unwrap()
+    }
+
+    #[test]
+    fn test_calls() {
+        let mut daemon = daemon();
+        let now = Instant::now();
+        let added = daemon
+            .call("add", &json!({"magnet": magnet(1), "label": "iso"}), now)
+            .unwrap();
+        assert_eq!(added, json!({"handle": 0, "merged": false}));
+        daemon
+            .call("add", &json!({"magnet": magnet(2), "paused": true}), now)
+            .unwrap();
+        let again = daemon.call("add", &json!({"magnet": magnet(1)}), now);
+        assert_eq!(again.unwrap()["merged"], true);
+
+        let status = daemon.call("status", &json!({}), now).unwrap();
+        assert_eq!(status.as_array().unwrap().len(), 2);
+        assert_eq!(status[0]["label"], "iso");
+        assert_eq!(status[0]["status"], "downloading_metadata");
+        assert_eq!(status[1]["status"], "paused");
+
+        let results = daemon.call("pause", &json!({"handles": [0, 7]}), now);
+        assert_eq!(results.unwrap(), json!([null, "unknown torrent"]));
+        daemon
+            .call(
+                "move_in_queue",
+                &json!({"handles": [1], "direction": "top"}),
+                now,
+            )
+            .unwrap();
+        assert_eq!(
+            daemon.session().queue(),
+            [TorrentHandle(1), TorrentHandle(0)]
+        );
+        daemon
+            .call(
+                "set_limits",
+                &json!({"handles": [1], "upload_rate": 1000}),
+                now,
+            )
+            .unwrap();
+        let limits = daemon.session().get(TorrentHandle(1)).unwrap().limits();
+        assert_eq!(limits.upload_rate, Some(1000));
+
+        let schedule = json!({"schedule": "default up=1M\nmon-fri 09:00-17:00 down=1M"});
+        daemon
+            .call("set_bandwidth_schedule", &schedule, now)
+            .unwrap();
+        let session = daemon.call("session", &json!({}), now).unwrap();
+        assert_eq!(session["torrents"], 2);
+        let stats = daemon.call("stats", &json!({}), now).unwrap();
+        assert_eq!(stats["torrents"][0]["name"], InfoHash([2; 20]).to_hex());
+        assert_eq!(stats["hash_failures"], 0);
+        assert_eq!(stats["dht_nodes"], Value::Null);
+        assert_eq!(stats["io_cache"]["write_runs"], 0);
+
+        assert_eq!(
+            daemon.call("peers", &json!({"handle": 9}), now),
+            Err(RpcFault::new(FAILED, "unknown torrent"))
+        );
+
+        assert_eq!(
+            daemon.call("status", &json!({"handles": "all"}), now),
+            Err(RpcFault::params("handles must be a list"))
+        );
+        assert_eq!(
+            daemon.call("add", &json!({}), now).unwrap_err().code,
+            INVALID_PARAMS
+        );
+        assert_eq!(
+            daemon.call("frobnicate", &json!({}), now).unwrap_err().code,
+            METHOD_NOT_FOUND
+        );
+        let removed = daemon.call("remove", &json!({"handles": [0]}), now);
+        assert_eq!(removed.unwrap(), json!([null]));
+        assert_eq!(daemon.session().len(), 1);
+    }
+
+    #[test]
+    fn test_download() {
+        let dir = std::env::temp_dir().join(format!("hurricane-daemon-dl-{}", std::process::id()));
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut seed = seed(&dir.join("seed"), &data);
+        let info_hash = seed.torrent().info_hash();
+
+        let mut config = DaemonConfig::new(dir.join("leech"));
+        (config.peers.port, config.peers.dht) = (0, false);
+        config.listen = Some("127.0.0.1:0".parse().unwrap());
+        config.stream = Some(StreamConfig::default());
+        let mut daemon = Daemon::bind(config).unwrap();
+        let magnet = format!(
+            "magnet:?xt=urn:btih:{}&x.pe=127.0.0.1:{}",
+            info_hash.to_hex(),
+            seed.port()
+        );
+        daemon
+            .call("add", &json!({"magnet": magnet}), Instant::now())
+            .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(20);
+        let status = loop {
+            seed.poll(Instant::now());
+            daemon.poll(Instant::now());
+            let status = daemon.session().get(TorrentHandle(0)).unwrap().status();
+            if status == TorrentStatus::Seeding || Instant::now() > deadline {
+                break status;
+            }
+            thread::sleep(Duration::from_millis(20));
+        };
+        assert_eq!(status, TorrentStatus::Seeding);
+        assert_eq!(std::fs::read(dir.join("leech/data.bin")).unwrap(), data);
+        assert!(seed.torrent().total_uploaded() >= data.len() as u64);
+
+        // And it can be streamed, from the next tick on
+        let url = loop {
+            let status = daemon.call("status", &json!({}), Instant::now()).unwrap();
+            if let Some(url) = status[0]["stream_url"].as_str() {
+                break url.to_string();
+            }
+            assert!(Instant::now() < deadline);
+            daemon.poll(Instant::now());
+            thread::sleep(Duration::from_millis(20));
+        };
+        let addr = daemon.stream_addr().unwrap();
+        assert_eq!(url, format!("http://{}/torrent/0", addr));
+        let reader = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(
+                stream,
+                "GET /torrent/0 HTTP/1.1\r\nRange: bytes=-1000\r\n\r\n"
+            )
+            .unwrap();
+            let mut response = vec![];
+            stream.read_to_end(&mut response).unwrap();
+            response
+        });
+        while !reader.is_finished() {
+            daemon.poll(Instant::now());
+            thread::sleep(Duration::from_millis(20));
+        }
+        let response = reader.join().unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 206"));
+        assert!(response.ends_with(&data[data.len() - 1000..]));
+
+        // Pausing it stops its peers
+        daemon
+            .call("pause", &json!({"handles": [0]}), Instant::now())
+            .unwrap();
+        daemon.poll(Instant::now());
+        assert!(daemon.engines.is_empty());
+        seed.stop();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_peers() {
+        let mut daemon = daemon();
+        daemon
+            .call("add", &json!({"magnet": magnet(3)}), Instant::now())
+            .unwrap();
+        daemon.poll(Instant::now());
+
+        // A peer that found us on its own, through a tracker or the DHT
+        let mut stream = TcpStream::connect(("127.0.0.1", daemon.peer_port())).unwrap();
+        let handshake = Handshake::new(
+            Reserved::default(),
+            InfoHash([3; 20]),
+            *b"-TR4000-abcdefghijkl",
+        );
+        stream.write_all(&handshake.encode()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let peers = loop {
+            daemon.poll(Instant::now());
+            let peers = daemon
+                .call("peers", &json!({"handle": 0}), Instant::now())
+                .unwrap();
+            if !peers[0]["client"].is_null() || Instant::now() > deadline {
+                break peers;
+            }
+            thread::sleep(Duration::from_millis(20));
+        };
+        assert_eq!(peers.as_array().unwrap().len(), 1);
+        assert_eq!(peers[0]["addr"], stream.local_addr().unwrap().to_string());
+        assert!(
+            peers[0]["client"]
+                .as_str()
+                .unwrap()
+                .starts_with("Transmission")
+        );
+        assert_eq!(peers[0]["incoming"], true);
+        assert_eq!(peers[0]["choked"], true);
+        let status = daemon.call("status", &json!({"handles": [0]}), Instant::now());
+        assert_eq!(status.unwrap()[0]["peers"], 1);
+
+        // And it got the torrent's handshake back
+        let mut buf = [0; HANDSHAKE_LEN];
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.read_exact(&mut buf).unwrap();
+        let theirs = Handshake::decode(&buf).unwrap().unwrap();
+        assert_eq!(theirs.info_hash, InfoHash([3; 20]));
+        assert_eq!(theirs.peer_id, daemon.peer_id);
+
+        // One for a torrent we don't have is dropped
+        let mut stream = TcpStream::connect(("127.0.0.1", daemon.peer_port())).unwrap();
+        let handshake = Handshake::new(Reserved::default(), InfoHash([4; 20]), [1; 20]);
+        stream.write_all(&handshake.encode()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let read = loop {
+            daemon.poll(Instant::now());
+            match stream.read(&mut buf) {
+                Err(err)
+                    if err.kind() == io::ErrorKind::WouldBlock && Instant::now() < deadline => {}
+                read => break read.map_err(|err| err.kind()),
+            }
+        };
+        assert_eq!(read, Ok(0));
+    }
+
+    #[test]
+    fn test_blocklist() {
+        let path =
+            std::env::temp_dir().join(format!("hurricane-daemon-blocklist-{}", std::process::id()));
+        std::fs::write(&path, "Lab:10.0.0.0-10.0.0.255\n").unwrap();
+        let mut config = DaemonConfig::new(std::env::temp_dir());
+        config.peers.port = 0;
+        config.listen = Some("127.0.0.1:0".parse().unwrap());
+        config.blocklist = Some(path.clone());
+        let mut daemon = Daemon::bind(config.clone()).unwrap();
+        let now = Instant::now();
+
+        let magnet = format!("{}&x.pe=10.0.0.1:6881&x.pe=10.0.1.1:6881", magnet(1));
+        daemon.call("add", &json!({"magnet": magnet}), now).unwrap();
+        let torrent = daemon.session().get(TorrentHandle(0)).unwrap();
+        let peers: Vec<_> = torrent.peer_list().iter().map(|(addr, _)| *addr).collect();
+        assert_eq!(peers, ["10.0.1.1:6881".parse().unwrap()]);
+        drop(torrent);
+        let session = daemon.call("session", &json!({}), now).unwrap();
+        assert_eq!(session["blocklist"]["ranges"], 1);
+        assert_eq!(session["blocklist"]["blocked"]["discovered"], 1);
+
+        std::fs::write(&path, "Lab:10.0.0.0-10.0.0.255\nMore:10.0.1.1-10.0.1.9\n").unwrap();
+        let reloaded = daemon.call("reload_blocklist", &json!({}), now).unwrap();
+        assert_eq!(reloaded["ranges"], 2);
+        std::fs::write(&path, "not a blocklist\n").unwrap();
+        let fault = daemon
+            .call("reload_blocklist", &json!({}), now)
+            .unwrap_err();
+        assert_eq!(fault.message, "line 1: not a blocklist");
+        assert!(
+            daemon
+                .session()
+                .ip_filter()
+                .blocks("10.0.1.1".parse().unwrap())
+        );
+
+        // A list that won't load keeps the daemon from starting
+        assert_eq!(
+            Daemon::bind(config).err().unwrap().kind(),
+            io::ErrorKind::InvalidData
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_socket() {
+        let mut config = DaemonConfig::new(std::env::temp_dir());
+        config.peers.port = 0;
+        config.listen = Some("127.0.0.1:0".parse().unwrap());
+        config.rpc.token = Some("secret".to_string());
+        // Metrics off loopback need the address allowed, a token isn't enough
+        config.metrics_listen = Some("0.0.0.0:0".parse().unwrap());
+        let refused = Daemon::bind(config.clone()).err().unwrap();
+        assert_eq!(refused.kind(), io::ErrorKind::PermissionDenied);
+        config.metrics_listen = Some("127.0.0.1:0".parse().unwrap());
+        let mut daemon = Daemon::bind(config).unwrap();
+        assert!(daemon.metrics_addr().is_some());
+        let stream = TcpStream::connect(daemon.local_addr().unwrap()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+
+        // Polls until the daemon has answered
+        let mut next_line = |daemon: &mut Daemon| {
+            let mut line = String::new();
+            for _ in 0..100 {
+                daemon.poll(Instant::now());
+                if reader.read_line(&mut line).is_ok() && line.ends_with('\n') {
+                    return serde_json::from_str::<Value>(&line).unwrap();
+                }
+            }
+            panic!("no answer");
+        };
+
+        writer
+            .write_all(b"{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"status\"}\n")
+            .unwrap();
+        assert_eq!(next_line(&mut daemon)["error"]["code"], UNAUTHORIZED);
+
+        writer.write_all(b"not json\n").unwrap();
+        assert_eq!(next_line(&mut daemon)["error"]["code"], PARSE_ERROR);
+
+        let requests = [
+            json!({"jsonrpc": "2.0", "id": 2, "method": "auth", "params": {"token": "secret"}}),
+            json!({"jsonrpc": "2.0", "id": 3, "method": "subscribe", "params": {"types": ["bogus"]}}),
+            json!({"jsonrpc": "2.0", "id": 4, "method": "subscribe", "params": {"types": ["added"]}}),
+            json!({"jsonrpc": "2.0", "id": 5, "method": "add", "params": {"magnet": magnet(3)}}),
+        ];
+        for request in requests {
+            writer
+                .write_all(format!("{}\n", request).as_bytes())
+                .unwrap();
+        }
+        assert_eq!(next_line(&mut daemon)["result"], true);
+        assert_eq!(next_line(&mut daemon)["error"]["code"], INVALID_PARAMS);
+        assert_eq!(next_line(&mut daemon)["result"], true);
+        assert_eq!(next_line(&mut daemon)["result"]["handle"], 0);
+
+        // The next tick tells subscribers about it, and leaves out the status change they
+        // didn't ask for
+        daemon.next_tick = Instant::now();
+        let event = next_line(&mut daemon);
+        assert_eq!(event["method"], "event");
+        assert_eq!(event["params"]["type"], "added");
+        assert_eq!(event["params"]["handle"], 0);
+        assert_eq!(event["params"]["info_hash"], InfoHash([3; 20]).to_hex());
+        // Answered before anything else comes
+        let requests = [
+            json!({"jsonrpc": "2.0", "id": 6, "method": "pause", "params": {"handles": [0]}}),
+            json!({"jsonrpc": "2.0", "id": 7, "method": "session"}),
+        ];
+        writer
+            .write_all(format!("{}\n", requests[0]).as_bytes())
+            .unwrap();
+        assert_eq!(next_line(&mut daemon)["result"], json!([null]));
+        daemon.next_tick = Instant::now();
+        daemon.poll(Instant::now());
+        writer
+            .write_all(format!("{}\n", requests[1]).as_bytes())
+            .unwrap();
+        assert_eq!(next_line(&mut daemon)["id"], 7);
+    }
+
+    #[test]
+    fn test_tls_socket() {
+        let mut config = DaemonConfig::new(std::env::temp_dir());
+        config.peers.port = 0;
+        config.listen = Some("127.0.0.1:0".parse().unwrap());
+        config.rpc = tls::unit_tests::rpc_config("daemon");
+        let mut daemon = Daemon::bind(config.clone()).unwrap();
+        let addr = daemon.local_addr().unwrap();
+
+        // The handshake blocks the client, so it runs on a thread while the daemon polls
+        let (sender, receiver) = mpsc::channel();
+        let rpc = config.rpc.clone();
+        thread::spawn(move || {
+            let stream = RpcStream::connect(addr, &rpc, Duration::from_secs(5)).unwrap();
+            let mut stream = BufReader::new(stream);
+            let request = json!({"jsonrpc": "2.0", "id": 1, "method": "session"});
+            writeln!(stream.get_mut(), "{}", request).unwrap();
+            let mut line = String::new();
+            stream.read_line(&mut line).unwrap();
+            sender.send(line).unwrap();
+        });
+        let line = loop {
+            daemon.poll(Instant::now());
+            if let Ok(line) = receiver.recv_timeout(Duration::from_millis(10)) {
+                break line;
+            }
+        };
+        let response: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(response["result"]["torrents"], 0);
+
+        // Off loopback it's TLS or nothing
+        config.listen = Some("0.0.0.0:0".parse().unwrap());
+        config.rpc.allowed_binds = vec![Ipv4Addr::UNSPECIFIED.into()];
+        config.rpc.token = Some("secret".to_string());
+        assert!(Daemon::bind(config.clone()).is_ok());
+        config.rpc.tls_key = None;
+        let refused = Daemon::bind(config).err().unwrap();
+        assert_eq!(refused.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
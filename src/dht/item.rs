@@ -0,0 +1,389 @@
+// Arbitrary data in the DHT (BEP 44). Immutable items are stored under the SHA-1 of their bencoded
+// value, so they can't change without moving. Mutable items are stored under the SHA-1 of an
+// ed25519 public key (plus an optional salt) and carry a signed sequence number, so only the key
+// owner can replace them with a newer version.
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use bencode::{BencodeValue, Digest as BencodeDigest, HashAlgo};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha1::{Digest, Sha1};
+
+use super::NodeId;
+use super::krpc::{
+    ERROR_CAS_MISMATCH, ERROR_INVALID_SIGNATURE, ERROR_MESSAGE_TOO_BIG, ERROR_SALT_TOO_BIG,
+    ERROR_SEQ_TOO_LOW, ERROR_SERVER,
+};
+
+// Limits mandated by BEP 44, on the bencoded value and the raw salt
+pub const MAX_VALUE_LEN: usize = 1000;
+pub const MAX_SALT_LEN: usize = 64;
+
+// How often one IP may put to us within `PUT_WINDOW`
+pub const PUT_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ItemError {
+    TooBig,
+    SaltTooBig,
+    InvalidSignature,
+    // The put's `cas` didn't match the sequence number we have
+    CasMismatch,
+    SeqTooLow,
+    RateLimited,
+    StorageFull,
+}
+
+impl ItemError {
+    pub fn code(&self) -> i32 {
+        match self {
+            ItemError::TooBig => ERROR_MESSAGE_TOO_BIG,
+            ItemError::SaltTooBig => ERROR_SALT_TOO_BIG,
+            ItemError::InvalidSignature => ERROR_INVALID_SIGNATURE,
+            ItemError::CasMismatch => ERROR_CAS_MISMATCH,
+            ItemError::SeqTooLow => ERROR_SEQ_TOO_LOW,
+            ItemError::RateLimited | ItemError::StorageFull => ERROR_SERVER,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            ItemError::TooBig => "message (v field) too big",
+            ItemError::SaltTooBig => "salt (salt field) too big",
+            ItemError::InvalidSignature => "invalid signature",
+            ItemError::CasMismatch => "the CAS hash mismatched, re-read value and try again",
+            ItemError::SeqTooLow => "sequence number less than current",
+            ItemError::RateLimited => "too many puts",
+            ItemError::StorageFull => "storage full",
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct MutableItem {
+    pub key: [u8; 32],
+    pub salt: Vec<u8>,
+    pub seq: i64,
+    // Bencoded
+    pub value: Vec<u8>,
+    pub signature: [u8; 64],
+}
+
+impl MutableItem {
+    // `secret_key` is the 32 byte ed25519 seed
+    pub fn sign(secret_key: &[u8; 32], salt: &[u8], seq: i64, value: &BencodeValue) -> Self {
+        let signing_key = SigningKey::from_bytes(secret_key);
+        let value = bencode::encode(value);
+        let signature = signing_key.sign(&signed_buffer(salt, seq, &value));
+        MutableItem {
+            key: signing_key.verifying_key().to_bytes(),
+            salt: salt.to_vec(),
+            seq,
+            value,
+            signature: signature.to_bytes(),
+        }
+    }
+
+    pub fn verify(&self) -> bool {
+        let Ok(key) = VerifyingKey::from_bytes(&self.key) else {
+            return false;
+        };
+        let signature = Signature::from_bytes(&self.signature);
+        key.verify(
+            &signed_buffer(&self.salt, self.seq, &self.value),
+            &signature,
+        )
+        .is_ok()
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Item {
+    // Bencoded value
+    Immutable(Vec<u8>),
+    Mutable(MutableItem),
+}
+
+impl Item {
+    pub fn immutable(value: &BencodeValue) -> Item {
+        Item::Immutable(bencode::encode(value))
+    }
+
+    pub fn target(&self) -> NodeId {
+        match self {
+            Item::Immutable(value) => NodeId(Sha1::digest(value).into()),
+            Item::Mutable(item) => mutable_target(&item.key, &item.salt),
+        }
+    }
+
+    // Bencoded value
+    pub fn value(&self) -> &[u8] {
+        match self {
+            Item::Immutable(value) => value,
+            Item::Mutable(item) => &item.value,
+        }
+    }
+
+    pub fn seq(&self) -> Option<i64> {
+        match self {
+            Item::Immutable(_) => None,
+            Item::Mutable(item) => Some(item.seq),
+        }
+    }
+
+    // Size limits and, for mutable items, the signature
+    pub fn validate(&self) -> Result<(), ItemError> {
+        if self.value().len() > MAX_VALUE_LEN {
+            return Err(ItemError::TooBig);
+        }
+        if let Item::Mutable(item) = self {
+            if item.salt.len() > MAX_SALT_LEN {
+                return Err(ItemError::SaltTooBig);
+            }
+            if !item.verify() {
+                return Err(ItemError::InvalidSignature);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Where `Item::immutable(value)` would be stored, without encoding it first
+pub fn immutable_target(value: &BencodeValue) -> NodeId {
+    let BencodeDigest::Sha1(hash) = bencode::hash_value(value, HashAlgo::Sha1) else {
+        unreachable!("asked for SHA-1");
+    };
+    NodeId(hash)
+}
+
+pub fn mutable_target(key: &[u8; 32], salt: &[u8]) -> NodeId {
+    let mut hasher = Sha1::new();
+    hasher.update(key);
+    hasher.update(salt);
+    NodeId(hasher.finalize().into())
+}
+
+// What actually gets signed: the bencoded salt, seq and v entries of the put, without the
+// surrounding dict
+fn signed_buffer(salt: &[u8], seq: i64, value: &[u8]) -> Vec<u8> {
+    let mut buf = vec![];
+    if !salt.is_empty() {
+        buf.extend_from_slice(format!("4:salt{}:", salt.len()).as_bytes());
+        buf.extend_from_slice(salt);
+    }
+    buf.extend_from_slice(format!("3:seqi{}e1:v", seq).as_bytes());
+    buf.extend_from_slice(value);
+    buf
+}
+
+// Items other nodes have put to us
+#[derive(Debug)]
+pub struct ItemStore {
+    items: HashMap<NodeId, (Item, Instant)>,
+    // IP -> start of its current window and how many puts it has made in it
+    puts: HashMap<IpAddr, (Instant, usize)>,
+    max_items: usize,
+    ttl: Duration,
+    max_puts: usize,
+}
+
+impl ItemStore {
+    pub fn new(max_items: usize, ttl: Duration, max_puts: usize) -> Self {
+        ItemStore {
+            items: HashMap::new(),
+            puts: HashMap::new(),
+            max_items,
+            ttl,
+            max_puts,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn get(&self, target: &NodeId) -> Option<&Item> {
+        self.items.get(target).map(|(item, _)| item)
+    }
+
+    pub fn put(
+        &mut self,
+        item: Item,
+        cas: Option<i64>,
+        from: IpAddr,
+        now: Instant,
+    ) -> Result<(), ItemError> {
+        item.validate()?;
+
+        let (window, count) = self.puts.entry(from).or_insert((now, 0));
+        if now.saturating_duration_since(*window) >= PUT_WINDOW {
+            *window = now;
+            *count = 0;
+        }
+        if *count >= self.max_puts {
+            return Err(ItemError::RateLimited);
+        }
+        *count += 1;
+
+        let target = item.target();
+        match (self.items.get(&target), &item) {
+            (Some((Item::Mutable(stored), _)), Item::Mutable(new)) => {
+                if cas.is_some_and(|cas| cas != stored.seq) {
+                    return Err(ItemError::CasMismatch);
+                }
+                // Re-putting the same version just refreshes it
+                if new.seq < stored.seq || (new.seq == stored.seq && new.value != stored.value) {
+                    return Err(ItemError::SeqTooLow);
+                }
+            }
+            (Some(_), _) => {}
+            (None, _) if self.items.len() >= self.max_items => return Err(ItemError::StorageFull),
+            (None, _) => {}
+        }
+
+        self.items.insert(target, (item, now));
+        Ok(())
+    }
+
+    // Items have to be put again before `ttl` runs out to stay around
+    pub fn expire(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        self.items
+            .retain(|_, (_, stored)| now.saturating_duration_since(*stored) < ttl);
+        self.puts
+            .retain(|_, (window, _)| now.saturating_duration_since(*window) < PUT_WINDOW);
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn hex<const N: usize>(s: &str) -> [u8; N] {
+        let bytes: Vec<u8> = (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect();
+        bytes.try_into().unwrap()
+    }
+
+    // Test vectors from BEP 44
+    fn bep44_item(salt: &[u8], signature: &str) -> MutableItem {
+        MutableItem {
+            key: hex("77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548"),
+            salt: salt.to_vec(),
+            seq: 1,
+            value: b"12:Hello World!".to_vec(),
+            signature: hex(signature),
+        }
+    }
+
+    fn hello() -> BencodeValue {
+        BencodeValue::ByteStr(b"Hello World!".to_vec())
+    }
+
+    #[test]
+    fn test_bep44_vectors() {
+        let item = bep44_item(
+            b"",
+            "305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01",
+        );
+        assert!(item.verify());
+        assert_eq!(
+            Item::Mutable(item).target(),
+            NodeId(hex("4a533d47ec9c7d95b1ad75f576cffc641853b750"))
+        );
+
+        let salted = bep44_item(
+            b"foobar",
+            "6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17ddf9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08",
+        );
+        assert!(salted.verify());
+        assert_eq!(
+            Item::Mutable(salted).target(),
+            NodeId(hex("411eba73b6f087ca51a3795d9c8c938d365e32c1"))
+        );
+
+        assert_eq!(
+            Item::immutable(&hello()).target(),
+            NodeId(hex("e5f96f6f38320f0f33959cb4d3d656452117aadb"))
+        );
+        assert_eq!(
+            immutable_target(&hello()),
+            Item::immutable(&hello()).target()
+        );
+    }
+
+    #[test]
+    fn test_sign_and_tamper() {
+        let mut item = MutableItem::sign(&[7; 32], b"salt", 3, &hello());
+        assert!(item.verify());
+
+        item.seq = 4;
+        assert!(!item.verify());
+        assert_eq!(
+            Item::Mutable(item).validate(),
+            Err(ItemError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let big = BencodeValue::ByteStr(vec![0; MAX_VALUE_LEN]);
+        assert_eq!(Item::immutable(&big).validate(), Err(ItemError::TooBig));
+
+        let item = MutableItem::sign(&[7; 32], &[0; MAX_SALT_LEN + 1], 1, &hello());
+        assert_eq!(Item::Mutable(item).validate(), Err(ItemError::SaltTooBig));
+    }
+
+    #[test]
+    fn test_store_sequence_rules() {
+        let now = Instant::now();
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let mut store = ItemStore::new(10, Duration::from_secs(60), 100);
+        let v2 = MutableItem::sign(&[7; 32], b"", 2, &hello());
+        let target = Item::Mutable(v2.clone()).target();
+        store.put(Item::Mutable(v2), None, ip, now).unwrap();
+
+        let v1 = MutableItem::sign(&[7; 32], b"", 1, &hello());
+        assert_eq!(
+            store.put(Item::Mutable(v1), None, ip, now),
+            Err(ItemError::SeqTooLow)
+        );
+
+        let v3 = MutableItem::sign(&[7; 32], b"", 3, &hello());
+        assert_eq!(
+            store.put(Item::Mutable(v3.clone()), Some(1), ip, now),
+            Err(ItemError::CasMismatch)
+        );
+        store.put(Item::Mutable(v3), Some(2), ip, now).unwrap();
+        assert_eq!(store.get(&target).unwrap().seq(), Some(3));
+
+        store.expire(now + Duration::from_secs(60));
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_store_limits() {
+        let now = Instant::now();
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let mut store = ItemStore::new(1, Duration::from_secs(60), 2);
+        let item = |i: i64| Item::immutable(&BencodeValue::Int(i));
+
+        store.put(item(1), None, ip, now).unwrap();
+        assert_eq!(
+            store.put(item(2), None, ip, now),
+            Err(ItemError::StorageFull)
+        );
+        assert_eq!(
+            store.put(item(1), None, ip, now),
+            Err(ItemError::RateLimited)
+        );
+        store.put(item(1), None, ip, now + PUT_WINDOW).unwrap();
+    }
+}
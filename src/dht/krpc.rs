@@ -0,0 +1,669 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use bencode::{BencodeValue, DecodeError};
+
+use super::item::{Item, MutableItem};
+use super::{NodeId, NodeInfo, parse_compact_nodes, write_compact_nodes};
+use crate::compact::CompactPeers;
+use crate::infohash::InfoHash;
+
+// Standard KRPC error codes from BEP 5
+pub const ERROR_GENERIC: i32 = 201;
+pub const ERROR_SERVER: i32 = 202;
+pub const ERROR_PROTOCOL: i32 = 203;
+pub const ERROR_METHOD_UNKNOWN: i32 = 204;
+// And the ones BEP 44 adds for put
+pub const ERROR_MESSAGE_TOO_BIG: i32 = 205;
+pub const ERROR_INVALID_SIGNATURE: i32 = 206;
+pub const ERROR_SALT_TOO_BIG: i32 = 207;
+pub const ERROR_CAS_MISMATCH: i32 = 301;
+pub const ERROR_SEQ_TOO_LOW: i32 = 302;
+
+#[derive(PartialEq, Debug)]
+pub enum KrpcError {
+    Decode(DecodeError),
+    NotADict,
+    MissingField(&'static str),
+    InvalidField(&'static str),
+    UnknownType(Vec<u8>),
+    UnknownMethod(Vec<u8>),
+}
+
+impl From<DecodeError> for KrpcError {
+    fn from(err: DecodeError) -> Self {
+        KrpcError::Decode(err)
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Message {
+    pub transaction_id: Vec<u8>,
+    pub body: Body,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Body {
+    Query(Query),
+    Response(Response),
+    Error { code: i32, message: String },
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Query {
+    Ping {
+        id: NodeId,
+    },
+    FindNode {
+        id: NodeId,
+        target: NodeId,
+    },
+    GetPeers {
+        id: NodeId,
+        info_hash: InfoHash,
+    },
+    AnnouncePeer {
+        id: NodeId,
+        info_hash: InfoHash,
+        port: u16,
+        token: Vec<u8>,
+        // Use the port the packet came from instead of `port` (for peers behind NAT)
+        implied_port: bool,
+    },
+    // BEP 51
+    SampleInfohashes {
+        id: NodeId,
+        target: NodeId,
+    },
+    // BEP 44
+    Get {
+        id: NodeId,
+        target: NodeId,
+        // Only send the value if it's newer than this
+        seq: Option<i64>,
+    },
+    Put {
+        id: NodeId,
+        token: Vec<u8>,
+        item: Item,
+        // Only replace a mutable item if its current seq is this
+        cas: Option<i64>,
+    },
+}
+
+impl Query {
+    pub fn id(&self) -> &NodeId {
+        match self {
+            Query::Ping { id }
+            | Query::FindNode { id, .. }
+            | Query::GetPeers { id, .. }
+            | Query::AnnouncePeer { id, .. }
+            | Query::SampleInfohashes { id, .. }
+            | Query::Get { id, .. }
+            | Query::Put { id, .. } => id,
+        }
+    }
+
+    pub fn method(&self) -> &'static [u8] {
+        match self {
+            Query::Ping { .. } => b"ping",
+            Query::FindNode { .. } => b"find_node",
+            Query::GetPeers { .. } => b"get_peers",
+            Query::AnnouncePeer { .. } => b"announce_peer",
+            Query::SampleInfohashes { .. } => b"sample_infohashes",
+            Query::Get { .. } => b"get",
+            Query::Put { .. } => b"put",
+        }
+    }
+}
+
+// Responses aren't self-describing (you need the query to know what they mean), so this is the
+// union of every field any response can carry. Empty lists are omitted on the wire
+#[derive(PartialEq, Debug, Clone)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+    pub values: Vec<SocketAddr>,
+    pub token: Option<Vec<u8>>,
+    // BEP 51 fields
+    pub samples: Vec<InfoHash>,
+    pub interval: Option<u32>,
+    pub num: Option<u32>,
+    // BEP 44 fields. `value` is bencoded, and left out when the querier already has this `seq`
+    pub value: Option<Vec<u8>>,
+    pub key: Option<[u8; 32]>,
+    pub signature: Option<[u8; 64]>,
+    pub seq: Option<i64>,
+}
+
+impl Response {
+    pub fn new(id: NodeId) -> Self {
+        Response {
+            id,
+            nodes: vec![],
+            values: vec![],
+            token: None,
+            samples: vec![],
+            interval: None,
+            num: None,
+            value: None,
+            key: None,
+            signature: None,
+            seq: None,
+        }
+    }
+}
+
+impl Message {
+    pub fn query(transaction_id: Vec<u8>, query: Query) -> Self {
+        Message {
+            transaction_id,
+            body: Body::Query(query),
+        }
+    }
+
+    pub fn response(transaction_id: Vec<u8>, response: Response) -> Self {
+        Message {
+            transaction_id,
+            body: Body::Response(response),
+        }
+    }
+
+    pub fn error(transaction_id: Vec<u8>, code: i32, message: &str) -> Self {
+        Message {
+            transaction_id,
+            body: Body::Error {
+                code,
+                message: message.to_string(),
+            },
+        }
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Message, KrpcError> {
+        let values = bencode::decode(buf)?;
+        let root = values.first().ok_or(KrpcError::NotADict)?;
+        if root.as_dict().is_none() {
+            return Err(KrpcError::NotADict);
+        }
+
+        let transaction_id = get_bytes(root, "t")?.to_vec();
+        let body = match get_bytes(root, "y")? {
+            b"q" => Body::Query(decode_query(root)?),
+            b"r" => Body::Response(decode_response(get(root, "r")?)?),
+            b"e" => {
+                let err = get(root, "e")?
+                    .as_list()
+                    .ok_or(KrpcError::InvalidField("e"))?;
+                match err {
+                    [BencodeValue::Int(code), BencodeValue::ByteStr(msg), ..] => Body::Error {
+                        code: i32::try_from(*code).map_err(|_| KrpcError::InvalidField("e"))?,
+                        message: String::from_utf8_lossy(msg).into_owned(),
+                    },
+                    _ => return Err(KrpcError::InvalidField("e")),
+                }
+            }
+            other => return Err(KrpcError::UnknownType(other.to_vec())),
+        };
+
+        Ok(Message {
+            transaction_id,
+            body,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut root = BTreeMap::new();
+        root.insert(b"t".to_vec(), bytes(&self.transaction_id));
+
+        match &self.body {
+            Body::Query(query) => {
+                root.insert(b"y".to_vec(), bytes(b"q"));
+                root.insert(b"q".to_vec(), bytes(query.method()));
+                root.insert(b"a".to_vec(), encode_query_args(query));
+            }
+            Body::Response(response) => {
+                root.insert(b"y".to_vec(), bytes(b"r"));
+                root.insert(b"r".to_vec(), encode_response(response));
+            }
+            Body::Error { code, message } => {
+                root.insert(b"y".to_vec(), bytes(b"e"));
+                root.insert(
+                    b"e".to_vec(),
+                    BencodeValue::List(vec![
+                        BencodeValue::Int(*code as i64),
+                        bytes(message.as_bytes()),
+                    ]),
+                );
+            }
+        }
+
+        bencode::encode(&BencodeValue::Dict(root))
+    }
+}
+
+fn decode_query(root: &BencodeValue) -> Result<Query, KrpcError> {
+    let args = get(root, "a")?;
+    let id = get_node_id(args, "id")?;
+
+    let query = match get_bytes(root, "q")? {
+        b"ping" => Query::Ping { id },
+        b"find_node" => Query::FindNode {
+            id,
+            target: get_node_id(args, "target")?,
+        },
+        b"get_peers" => Query::GetPeers {
+            id,
+            info_hash: get_info_hash(args, "info_hash")?,
+        },
+        b"announce_peer" => {
+            let port = get(args, "port")?
+                .as_int()
+                .and_then(|p| u16::try_from(p).ok())
+                .ok_or(KrpcError::InvalidField("port"))?;
+            let implied_port = args
+                .get(b"implied_port")
+                .and_then(|v| v.as_int())
+                .is_some_and(|v| v != 0);
+
+            Query::AnnouncePeer {
+                id,
+                info_hash: get_info_hash(args, "info_hash")?,
+                port,
+                token: get_bytes(args, "token")?.to_vec(),
+                implied_port,
+            }
+        }
+        b"sample_infohashes" => Query::SampleInfohashes {
+            id,
+            target: get_node_id(args, "target")?,
+        },
+        b"get" => Query::Get {
+            id,
+            target: get_node_id(args, "target")?,
+            seq: get_opt_int(args, "seq")?,
+        },
+        b"put" => {
+            let value = bencode::encode(get(args, "v")?);
+            let item = match args.get(b"k") {
+                None => Item::Immutable(value),
+                Some(key) => Item::Mutable(MutableItem {
+                    key: key
+                        .as_bytes()
+                        .and_then(|k| k.try_into().ok())
+                        .ok_or(KrpcError::InvalidField("k"))?,
+                    salt: match args.get(b"salt") {
+                        Some(salt) => salt
+                            .as_bytes()
+                            .ok_or(KrpcError::InvalidField("salt"))?
+                            .to_vec(),
+                        None => vec![],
+                    },
+                    seq: get_opt_int(args, "seq")?.ok_or(KrpcError::MissingField("seq"))?,
+                    value,
+                    signature: get_bytes(args, "sig")?
+                        .try_into()
+                        .map_err(|_| KrpcError::InvalidField("sig"))?,
+                }),
+            };
+
+            Query::Put {
+                id,
+                token: get_bytes(args, "token")?.to_vec(),
+                item,
+                cas: get_opt_int(args, "cas")?,
+            }
+        }
+        other => return Err(KrpcError::UnknownMethod(other.to_vec())),
+    };
+
+    Ok(query)
+}
+
+fn decode_response(r: &BencodeValue) -> Result<Response, KrpcError> {
+    let mut response = Response::new(get_node_id(r, "id")?);
+
+    if let Some(nodes) = r.get(b"nodes") {
+        response.nodes = nodes
+            .as_bytes()
+            .and_then(|b| parse_compact_nodes(b, false))
+            .ok_or(KrpcError::InvalidField("nodes"))?;
+    }
+    if let Some(nodes) = r.get(b"nodes6") {
+        let nodes = nodes
+            .as_bytes()
+            .and_then(|b| parse_compact_nodes(b, true))
+            .ok_or(KrpcError::InvalidField("nodes6"))?;
+        response.nodes.extend(nodes);
+    }
+
+    if let Some(values) = r.get(b"values") {
+        let values = values.as_list().ok_or(KrpcError::InvalidField("values"))?;
+        for value in values {
+            let peer = value
+                .as_bytes()
+                .and_then(|b| CompactPeers::parse_one(b).ok())
+                .ok_or(KrpcError::InvalidField("values"))?;
+            response.values.push(peer);
+        }
+    }
+
+    if let Some(token) = r.get(b"token") {
+        let token = token.as_bytes().ok_or(KrpcError::InvalidField("token"))?;
+        response.token = Some(token.to_vec());
+    }
+
+    if let Some(samples) = r.get(b"samples") {
+        let samples = samples
+            .as_bytes()
+            .filter(|s| s.len().is_multiple_of(20))
+            .ok_or(KrpcError::InvalidField("samples"))?;
+        response.samples = samples
+            .chunks_exact(20)
+            .map(|s| InfoHash::from_bytes(s).unwrap())
+            .collect();
+    }
+
+    response.interval = get_opt_u32(r, "interval")?;
+    response.num = get_opt_u32(r, "num")?;
+
+    response.value = r.get(b"v").map(bencode::encode);
+    if let Some(key) = r.get(b"k") {
+        let key = key.as_bytes().and_then(|k| k.try_into().ok());
+        response.key = Some(key.ok_or(KrpcError::InvalidField("k"))?);
+    }
+    if let Some(signature) = r.get(b"sig") {
+        let signature = signature.as_bytes().and_then(|s| s.try_into().ok());
+        response.signature = Some(signature.ok_or(KrpcError::InvalidField("sig"))?);
+    }
+    response.seq = get_opt_int(r, "seq")?;
+
+    Ok(response)
+}
+
+fn encode_query_args(query: &Query) -> BencodeValue {
+    let mut args = BTreeMap::new();
+    args.insert(b"id".to_vec(), bytes(&query.id().0));
+
+    match query {
+        Query::Ping { .. } => {}
+        Query::FindNode { target, .. } | Query::SampleInfohashes { target, .. } => {
+            args.insert(b"target".to_vec(), bytes(&target.0));
+        }
+        Query::Get { target, seq, .. } => {
+            args.insert(b"target".to_vec(), bytes(&target.0));
+            if let Some(seq) = seq {
+                args.insert(b"seq".to_vec(), BencodeValue::Int(*seq));
+            }
+        }
+        Query::Put {
+            token, item, cas, ..
+        } => {
+            args.insert(b"token".to_vec(), bytes(token));
+            args.insert(b"v".to_vec(), raw(item.value()));
+            if let Item::Mutable(item) = item {
+                args.insert(b"k".to_vec(), bytes(&item.key));
+                args.insert(b"seq".to_vec(), BencodeValue::Int(item.seq));
+                args.insert(b"sig".to_vec(), bytes(&item.signature));
+                if !item.salt.is_empty() {
+                    args.insert(b"salt".to_vec(), bytes(&item.salt));
+                }
+            }
+            if let Some(cas) = cas {
+                args.insert(b"cas".to_vec(), BencodeValue::Int(*cas));
+            }
+        }
+        Query::GetPeers { info_hash, .. } => {
+            args.insert(b"info_hash".to_vec(), bytes(&info_hash.0));
+        }
+        Query::AnnouncePeer {
+            info_hash,
+            port,
+            token,
+            implied_port,
+            ..
+        } => {
+            args.insert(b"info_hash".to_vec(), bytes(&info_hash.0));
+            args.insert(b"port".to_vec(), BencodeValue::Int(*port as i64));
+            args.insert(b"token".to_vec(), bytes(token));
+            if *implied_port {
+                args.insert(b"implied_port".to_vec(), BencodeValue::Int(1));
+            }
+        }
+    }
+
+    BencodeValue::Dict(args)
+}
+
+fn encode_response(response: &Response) -> BencodeValue {
+    let mut r = BTreeMap::new();
+    r.insert(b"id".to_vec(), bytes(&response.id.0));
+
+    if response.nodes.iter().any(|n| n.addr.is_ipv4()) {
+        r.insert(
+            b"nodes".to_vec(),
+            bytes(&write_compact_nodes(&response.nodes, false)),
+        );
+    }
+    if response.nodes.iter().any(|n| n.addr.is_ipv6()) {
+        r.insert(
+            b"nodes6".to_vec(),
+            bytes(&write_compact_nodes(&response.nodes, true)),
+        );
+    }
+    if !response.values.is_empty() {
+        let values = response
+            .values
+            .iter()
+            .map(|peer| bytes(&CompactPeers::write_one(peer)))
+            .collect();
+        r.insert(b"values".to_vec(), BencodeValue::List(values));
+    }
+    if let Some(token) = &response.token {
+        r.insert(b"token".to_vec(), bytes(token));
+    }
+    if !response.samples.is_empty() {
+        let samples: Vec<u8> = response.samples.iter().flat_map(|s| s.0).collect();
+        r.insert(b"samples".to_vec(), bytes(&samples));
+    }
+    if let Some(interval) = response.interval {
+        r.insert(b"interval".to_vec(), BencodeValue::Int(interval as i64));
+    }
+    if let Some(num) = response.num {
+        r.insert(b"num".to_vec(), BencodeValue::Int(num as i64));
+    }
+    if let Some(value) = &response.value {
+        r.insert(b"v".to_vec(), raw(value));
+    }
+    if let Some(key) = &response.key {
+        r.insert(b"k".to_vec(), bytes(key));
+    }
+    if let Some(signature) = &response.signature {
+        r.insert(b"sig".to_vec(), bytes(signature));
+    }
+    if let Some(seq) = response.seq {
+        r.insert(b"seq".to_vec(), BencodeValue::Int(seq));
+    }
+
+    BencodeValue::Dict(r)
+}
+
+fn bytes(b: &[u8]) -> BencodeValue {
+    BencodeValue::ByteStr(b.to_vec())
+}
+
+// Item values are kept bencoded, and only ever built by encoding a value, so they always decode
+fn raw(value: &[u8]) -> BencodeValue {
+    bencode::decode(value)
+        .ok()
+        .and_then(|mut values| values.pop())
+        .unwrap_or_else(|| bytes(b""))
+}
+
+fn get<'a>(dict: &'a BencodeValue, key: &'static str) -> Result<&'a BencodeValue, KrpcError> {
+    dict.get(key.as_bytes()).ok_or(KrpcError::MissingField(key))
+}
+
+fn get_bytes<'a>(dict: &'a BencodeValue, key: &'static str) -> Result<&'a [u8], KrpcError> {
+    get(dict, key)?
+        .as_bytes()
+        .ok_or(KrpcError::InvalidField(key))
+}
+
+fn get_node_id(dict: &BencodeValue, key: &'static str) -> Result<NodeId, KrpcError> {
+    NodeId::from_bytes(get_bytes(dict, key)?).ok_or(KrpcError::InvalidField(key))
+}
+
+fn get_info_hash(dict: &BencodeValue, key: &'static str) -> Result<InfoHash, KrpcError> {
+    InfoHash::from_bytes(get_bytes(dict, key)?).ok_or(KrpcError::InvalidField(key))
+}
+
+fn get_opt_int(dict: &BencodeValue, key: &'static str) -> Result<Option<i64>, KrpcError> {
+    match dict.get(key.as_bytes()) {
+        None => Ok(None),
+        Some(value) => value.as_int().map(Some).ok_or(KrpcError::InvalidField(key)),
+    }
+}
+
+fn get_opt_u32(dict: &BencodeValue, key: &'static str) -> Result<Option<u32>, KrpcError> {
+    match dict.get(key.as_bytes()) {
+        None => Ok(None),
+        Some(value) => value
+            .as_int()
+            .and_then(|i| u32::try_from(i).ok())
+            .map(Some)
+            .ok_or(KrpcError::InvalidField(key)),
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_ping_query() {
+        // Example straight from BEP 5
+        let raw = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        let msg = Message::decode(raw).unwrap();
+
+        assert_eq!(
+            msg,
+            Message::query(
+                b"aa".to_vec(),
+                Query::Ping {
+                    id: NodeId(*b"abcdefghij0123456789")
+                }
+            )
+        );
+        assert_eq!(msg.encode(), raw);
+    }
+
+    #[test]
+    fn test_get_peers_response() {
+        let raw = b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re";
+        let msg = Message::decode(raw).unwrap();
+
+        let Body::Response(response) = &msg.body else {
+            panic!("expected a response");
+        };
+        assert_eq!(response.token, Some(b"aoeusnth".to_vec()));
+        assert_eq!(response.values.len(), 2);
+        assert_eq!(response.values[0], "97.120.106.101:11893".parse().unwrap());
+        assert_eq!(msg.encode(), raw);
+    }
+
+    #[test]
+    fn test_announce_peer_roundtrip() {
+        let msg = Message::query(
+            b"xy".to_vec(),
+            Query::AnnouncePeer {
+                id: NodeId([1; 20]),
+                info_hash: InfoHash([2; 20]),
+                port: 6881,
+                token: b"tok".to_vec(),
+                implied_port: true,
+            },
+        );
+
+        assert_eq!(Message::decode(&msg.encode()).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_error_message() {
+        let raw = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+        let msg = Message::decode(raw).unwrap();
+
+        assert_eq!(
+            msg,
+            Message::error(b"aa".to_vec(), ERROR_GENERIC, "A Generic Error Ocurred")
+        );
+        assert_eq!(msg.encode(), raw);
+    }
+
+    #[test]
+    fn test_sample_infohashes_roundtrip() {
+        let mut response = Response::new(NodeId([1; 20]));
+        response.samples = vec![InfoHash([3; 20]), InfoHash([4; 20])];
+        response.interval = Some(21600);
+        response.num = Some(2);
+        let msg = Message::response(b"t1".to_vec(), response);
+
+        assert_eq!(Message::decode(&msg.encode()).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_put_roundtrip() {
+        let value = BencodeValue::List(vec![BencodeValue::Int(1), bytes(b"x")]);
+        let item = MutableItem::sign(&[5; 32], b"salt", 9, &value);
+        let msg = Message::query(
+            b"pp".to_vec(),
+            Query::Put {
+                id: NodeId([1; 20]),
+                token: b"tok".to_vec(),
+                item: Item::Mutable(item),
+                cas: Some(8),
+            },
+        );
+
+        assert_eq!(Message::decode(&msg.encode()).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_get_response_roundtrip() {
+        let mut response = Response::new(NodeId([1; 20]));
+        response.token = Some(b"tok".to_vec());
+        response.value = Some(b"12:Hello World!".to_vec());
+        response.key = Some([2; 32]);
+        response.signature = Some([3; 64]);
+        response.seq = Some(4);
+        let msg = Message::response(b"gg".to_vec(), response);
+
+        assert_eq!(Message::decode(&msg.encode()).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_unknown_method() {
+        let raw = b"d1:ad2:id20:abcdefghij0123456789e1:q3:foo1:t2:aa1:y1:qe";
+
+        assert_eq!(
+            Message::decode(raw),
+            Err(KrpcError::UnknownMethod(b"foo".to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_missing_id() {
+        let raw = b"d1:ade1:q4:ping1:t2:aa1:y1:qe";
+
+        assert_eq!(Message::decode(raw), Err(KrpcError::MissingField("id")));
+    }
+
+    #[test]
+    fn test_bad_samples_len() {
+        let raw = b"d1:rd2:id20:abcdefghij01234567897:samples3:abce1:t2:aa1:y1:re";
+
+        assert_eq!(
+            Message::decode(raw),
+            Err(KrpcError::InvalidField("samples"))
+        );
+    }
+}
@@ -0,0 +1,184 @@
+// Iterative Kademlia lookup.
+// Starting from the closest nodes we know, keep asking the closest nodes we've heard of about the
+// target until the K closest have all answered. Used for find_node (bootstrap, bucket refresh) and
+// get_peers (finding peers, and the nodes we then announce to).
+use std::collections::HashSet;
+use std::net::SocketAddr;
+
+use super::routing::K;
+use super::{NodeId, NodeInfo};
+
+// Queries in flight at once
+pub const ALPHA: usize = 3;
+
+// How many candidates to keep track of. Anything further away than this is never going to matter
+const MAX_CANDIDATES: usize = K * 8;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum State {
+    Fresh,
+    Queried,
+    Responded,
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+struct Candidate {
+    info: NodeInfo,
+    state: State,
+    // get_peers token, needed to announce to this node later
+    token: Option<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub struct Lookup {
+    target: NodeId,
+    // Sorted by distance to the target
+    candidates: Vec<Candidate>,
+    seen: HashSet<SocketAddr>,
+}
+
+impl Lookup {
+    pub fn new(target: NodeId, seeds: &[NodeInfo]) -> Self {
+        let mut lookup = Lookup {
+            target,
+            candidates: vec![],
+            seen: HashSet::new(),
+        };
+        lookup.add_nodes(seeds);
+        lookup
+    }
+
+    pub fn target(&self) -> NodeId {
+        self.target
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.candidates
+            .iter()
+            .filter(|c| c.state == State::Queried)
+            .count()
+    }
+
+    // Nodes to query next. They're marked as queried, so every call returns new ones
+    pub fn next_queries(&mut self) -> Vec<NodeInfo> {
+        let budget = ALPHA.saturating_sub(self.in_flight());
+        let mut out = vec![];
+        for candidate in self.live().take(K).collect::<Vec<_>>() {
+            if out.len() == budget {
+                break;
+            }
+            let candidate = &mut self.candidates[candidate];
+            if candidate.state == State::Fresh {
+                candidate.state = State::Queried;
+                out.push(candidate.info);
+            }
+        }
+        out
+    }
+
+    pub fn on_response(&mut self, addr: SocketAddr, nodes: &[NodeInfo], token: Option<Vec<u8>>) {
+        if let Some(candidate) = self.candidates.iter_mut().find(|c| c.info.addr == addr) {
+            candidate.state = State::Responded;
+            candidate.token = token;
+        }
+        self.add_nodes(nodes);
+    }
+
+    pub fn on_failure(&mut self, addr: SocketAddr) {
+        if let Some(candidate) = self.candidates.iter_mut().find(|c| c.info.addr == addr) {
+            candidate.state = State::Failed;
+        }
+    }
+
+    // Done once nothing is in flight and the K closest live nodes have all answered
+    pub fn is_done(&self) -> bool {
+        self.in_flight() == 0
+            && self
+                .live()
+                .take(K)
+                .all(|i| self.candidates[i].state == State::Responded)
+    }
+
+    // The closest nodes that answered, along with their tokens for announcing
+    pub fn closest_responded(&self) -> Vec<(NodeInfo, Option<Vec<u8>>)> {
+        self.candidates
+            .iter()
+            .filter(|c| c.state == State::Responded)
+            .take(K)
+            .map(|c| (c.info, c.token.clone()))
+            .collect()
+    }
+
+    fn live(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.candidates.len()).filter(|i| self.candidates[*i].state != State::Failed)
+    }
+
+    fn add_nodes(&mut self, nodes: &[NodeInfo]) {
+        for node in nodes {
+            if !self.seen.insert(node.addr) {
+                continue;
+            }
+            self.candidates.push(Candidate {
+                info: *node,
+                state: State::Fresh,
+                token: None,
+            });
+        }
+
+        let target = self.target;
+        self.candidates.sort_by_key(|c| c.info.id.distance(&target));
+        self.candidates.truncate(MAX_CANDIDATES);
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn node(i: u8) -> NodeInfo {
+        NodeInfo {
+            id: NodeId([i; 20]),
+            addr: SocketAddr::new([10, 0, 0, i].into(), 6881),
+        }
+    }
+
+    #[test]
+    fn test_queries_closest_first() {
+        let mut lookup = Lookup::new(NodeId([0; 20]), &[node(9), node(1), node(5), node(3)]);
+
+        assert_eq!(lookup.next_queries(), vec![node(1), node(3), node(5)]);
+        // Already at ALPHA in flight
+        assert!(lookup.next_queries().is_empty());
+    }
+
+    #[test]
+    fn test_converges() {
+        let mut lookup = Lookup::new(NodeId([0; 20]), &[node(9)]);
+        assert_eq!(lookup.next_queries(), vec![node(9)]);
+        assert!(!lookup.is_done());
+
+        lookup.on_response(node(9).addr, &[node(2), node(4)], None);
+        assert_eq!(lookup.next_queries(), vec![node(2), node(4)]);
+
+        lookup.on_response(node(2).addr, &[node(4)], Some(b"tok".to_vec()));
+        lookup.on_failure(node(4).addr);
+
+        assert!(lookup.next_queries().is_empty());
+        assert!(lookup.is_done());
+        assert_eq!(
+            lookup.closest_responded(),
+            vec![(node(2), Some(b"tok".to_vec())), (node(9), None)]
+        );
+    }
+
+    #[test]
+    fn test_duplicates_ignored() {
+        let mut lookup = Lookup::new(NodeId([0; 20]), &[node(1)]);
+        lookup.next_queries();
+        lookup.on_response(node(1).addr, &[node(1)], None);
+
+        assert!(lookup.next_queries().is_empty());
+        assert!(lookup.is_done());
+    }
+}
@@ -0,0 +1,156 @@
+pub mod item;
+pub mod krpc;
+pub mod lookup;
+pub mod node;
+pub mod persist;
+pub mod routing;
+pub mod sample;
+pub mod socket;
+pub mod subscription;
+
+pub use node::{Dht, DhtConfig, DhtEvent, DhtStats};
+pub use socket::DhtSocket;
+pub use subscription::Subscription;
+
+use std::fmt;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+
+use crate::compact::{CompactAddr, CompactNodes};
+use crate::infohash::InfoHash;
+use crate::rng::Rng;
+
+// 160-bit identifier in the same keyspace as info-hashes
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    pub fn random(rng: &mut Rng) -> NodeId {
+        let mut id = [0u8; 20];
+        rng.fill(&mut id);
+        NodeId(id)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<NodeId> {
+        Some(NodeId(bytes.try_into().ok()?))
+    }
+
+    // Kademlia XOR metric. Comparing the results byte-wise orders nodes by closeness
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        let mut out = [0u8; 20];
+        for (i, byte) in out.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
+        }
+        out
+    }
+}
+
+impl From<InfoHash> for NodeId {
+    fn from(hash: InfoHash) -> Self {
+        NodeId(hash.0)
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NodeId(")?;
+        for b in &self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        write!(f, ")")
+    }
+}
+
+// A node as it appears in `nodes` (IPv4) and `nodes6` (IPv6, BEP 32) lists: its ID and where to
+// reach it
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+// `ipv6` picks the `nodes6` format
+pub(crate) fn parse_compact_nodes(buf: &[u8], ipv6: bool) -> Option<Vec<NodeInfo>> {
+    if ipv6 {
+        parse_nodes::<SocketAddrV6>(buf)
+    } else {
+        parse_nodes::<SocketAddrV4>(buf)
+    }
+}
+
+fn parse_nodes<A: CompactAddr + Into<SocketAddr>>(buf: &[u8]) -> Option<Vec<NodeInfo>> {
+    let nodes = CompactNodes::parse::<A>(buf).ok()?;
+    Some(
+        nodes
+            .into_iter()
+            .map(|(id, addr)| NodeInfo {
+                id: NodeId(id),
+                addr: addr.into(),
+            })
+            .collect(),
+    )
+}
+
+// Only the nodes of the one family, the others are left out
+pub(crate) fn write_compact_nodes(nodes: &[NodeInfo], ipv6: bool) -> Vec<u8> {
+    let mut out = vec![];
+    for node in nodes.iter().filter(|n| n.addr.is_ipv6() == ipv6) {
+        out.extend_from_slice(&node.id.0);
+        match node.addr {
+            SocketAddr::V4(addr) => addr.write(&mut out),
+            SocketAddr::V6(addr) => addr.write(&mut out),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_distance() {
+        let a = NodeId([0xff; 20]);
+        let b = NodeId([0x0f; 20]);
+
+        assert_eq!(a.distance(&b), [0xf0; 20]);
+        assert_eq!(a.distance(&a), [0; 20]);
+    }
+
+    #[test]
+    fn test_compact_nodes_roundtrip() {
+        let nodes = vec![NodeInfo {
+            id: NodeId([7; 20]),
+            addr: "1.2.3.4:6881".parse().unwrap(),
+        }];
+        let buf = write_compact_nodes(&nodes, false);
+
+        assert_eq!(buf.len(), 26);
+        assert_eq!(parse_compact_nodes(&buf, false), Some(nodes));
+    }
+
+    #[test]
+    fn test_compact_nodes6_roundtrip() {
+        let nodes = vec![
+            NodeInfo {
+                id: NodeId([7; 20]),
+                addr: "1.2.3.4:6881".parse().unwrap(),
+            },
+            NodeInfo {
+                id: NodeId([8; 20]),
+                addr: "[2001:db8::1]:6881".parse().unwrap(),
+            },
+        ];
+
+        // Each list only carries its own family
+        assert_eq!(write_compact_nodes(&nodes, false).len(), 26);
+        let buf = write_compact_nodes(&nodes, true);
+        assert_eq!(buf.len(), 38);
+        assert_eq!(parse_compact_nodes(&buf, true), Some(vec![nodes[1]]));
+    }
+
+    #[test]
+    fn test_compact_nodes_bad_len() {
+        assert_eq!(parse_compact_nodes(&[0; 25], false), None);
+        assert_eq!(parse_compact_nodes(&[0; 26], true), None);
+    }
+}
@@ -0,0 +1,1402 @@
+// A mainline DHT node (BEP 5).
+// Sans-IO like the rest of the crate: feed it packets with `handle_packet`, drive timers with
+// `tick`, and drain `poll_transmit` / `poll_event`. `socket::DhtSocket` wires it up to a real
+// UDP socket.
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::BuildHasher;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use super::item::{Item, ItemError, ItemStore, MutableItem, mutable_target};
+use super::krpc::{Body, ERROR_PROTOCOL, Message, Query, Response};
+use super::lookup::Lookup;
+use super::persist::SavedState;
+use super::routing::{K, RoutingTable};
+use super::sample::{Indexer, MAX_INTERVAL, sample_response};
+use super::{NodeId, NodeInfo};
+use crate::audit::{self, Channel};
+use crate::bencode::BencodeValue;
+use crate::infohash::InfoHash;
+use crate::rng::Rng;
+
+// Well-known nodes that exist to get new nodes into the network
+pub const DEFAULT_ROUTERS: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+    "dht.libtorrent.org:25401",
+];
+
+// How long one IP's query count runs before it starts over
+const QUERY_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    // How long to wait for an answer before counting a query as failed
+    pub query_timeout: Duration,
+    // How long announced peers are kept. BEP 5 suggests 30 minutes
+    pub peer_ttl: Duration,
+    // Tokens stay valid for one to two of these
+    pub token_rotation: Duration,
+    // Most peers handed out per get_peers response, to stay under the MTU
+    pub max_values: usize,
+    // Most peers stored per info-hash. A new one takes the place of the one that announced
+    // longest ago
+    pub max_peers_per_torrent: usize,
+    // Most info-hashes stored. New ones take the place of the ones with the fewest peers
+    pub max_torrents: usize,
+    // Queries one IP gets answered per second. Any more are dropped without a reply, and their
+    // senders don't make it into the routing table
+    pub max_queries_per_ip: usize,
+    // Bootstrap routers as host:port. Resolving them is up to whoever does the IO
+    pub routers: Vec<String>,
+    // Below this many nodes in the table we go back to the routers for more
+    pub min_nodes: usize,
+    // Minimum time between bootstrap attempts, and the base for a router's backoff after it
+    // stops answering
+    pub bootstrap_interval: Duration,
+    // BEP 44 storage: how long put items are kept without being put again, how many we hold, and
+    // how many puts one IP gets per minute
+    pub item_ttl: Duration,
+    pub max_items: usize,
+    pub max_puts_per_ip: usize,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        DhtConfig {
+            query_timeout: Duration::from_secs(5),
+            peer_ttl: Duration::from_secs(30 * 60),
+            token_rotation: Duration::from_secs(5 * 60),
+            max_values: 50,
+            max_peers_per_torrent: 1000,
+            max_torrents: 2000,
+            max_queries_per_ip: 50,
+            routers: DEFAULT_ROUTERS.iter().map(|r| r.to_string()).collect(),
+            min_nodes: 16,
+            bootstrap_interval: Duration::from_secs(60),
+            item_ttl: Duration::from_secs(2 * 60 * 60),
+            max_items: 1000,
+            max_puts_per_ip: 10,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum DhtEvent {
+    // Peers found by a get_peers lookup. Can fire several times per lookup as answers come in
+    Peers {
+        info_hash: InfoHash,
+        peers: Vec<SocketAddr>,
+    },
+    // A get_peers lookup (and its announce, if one was asked for) has finished
+    LookupDone {
+        info_hash: InfoHash,
+    },
+    // The bootstrap lookup for our own ID finished
+    Bootstrapped,
+    // A verified item found by `get`. For mutable items this fires again whenever a newer
+    // sequence number turns up
+    Item {
+        target: NodeId,
+        item: Item,
+    },
+    // A `get` or put lookup has finished
+    ItemDone {
+        target: NodeId,
+    },
+    // A node answered `sample`. `next` are the nodes it pointed us at that can be sampled now
+    Sampled {
+        from: SocketAddr,
+        next: Vec<NodeInfo>,
+    },
+}
+
+// Counters since the node started, for metrics
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct DhtStats {
+    pub packets_in: u64,
+    pub bytes_in: u64,
+    pub packets_out: u64,
+    pub bytes_out: u64,
+    // Queries answered, by method
+    pub queries: BTreeMap<&'static str, u64>,
+    // Queries over `max_queries_per_ip`
+    pub rate_limited: u64,
+    // Packets that weren't KRPC
+    pub invalid: u64,
+    // Our own queries that went unanswered
+    pub timeouts: u64,
+    // Stored peers and torrents that made room for new ones
+    pub evicted_peers: u64,
+    pub evicted_torrents: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum LookupKind {
+    // Bootstrapping, or refreshing a bucket
+    FindNode {
+        bootstrap: bool,
+    },
+    GetPeers {
+        info_hash: InfoHash,
+        // Port to announce once the lookup converges
+        announce: Option<u16>,
+    },
+    // BEP 44 get, and put if there's something in `item_lookups` to put. The rest of the state
+    // lives there since it isn't Copy
+    Item,
+}
+
+#[derive(Debug)]
+struct ItemLookup {
+    // Needed to check that a mutable item really belongs at the target
+    salt: Vec<u8>,
+    found: Option<Item>,
+    // Stored on the closest nodes once the lookup converges, with its cas
+    put: Option<(Item, Option<i64>)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum PendingKind {
+    Ping,
+    // Bootstrap routers don't get an ID until they answer
+    Bootstrap,
+    Lookup(u64),
+    // announce_peer and put, which we don't care about the answers to
+    Announce,
+    Sample,
+}
+
+#[derive(Debug)]
+struct Pending {
+    addr: SocketAddr,
+    // None for bootstrap routers whose ID we don't know yet
+    id: Option<NodeId>,
+    sent: Instant,
+    kind: PendingKind,
+}
+
+#[derive(Debug)]
+struct Router {
+    addr: SocketAddr,
+    failures: u32,
+    retry_at: Instant,
+}
+
+#[derive(Debug)]
+pub struct Dht {
+    config: DhtConfig,
+    table: RoutingTable,
+    rng: Rng,
+    next_transaction: u16,
+    pending: HashMap<Vec<u8>, Pending>,
+    next_lookup: u64,
+    lookups: HashMap<u64, (Lookup, LookupKind)>,
+    item_lookups: HashMap<u64, ItemLookup>,
+    // info-hash -> peers that announced it, with when they did
+    storage: HashMap<InfoHash, HashMap<SocketAddr, Instant>>,
+    items: ItemStore,
+    // IP -> start of its current window and how many queries it has sent in it
+    queries: HashMap<IpAddr, (Instant, usize)>,
+    stats: DhtStats,
+    secrets: [RandomState; 2],
+    secret_rotated: Instant,
+    routers: Vec<Router>,
+    next_bootstrap: Instant,
+    outbox: VecDeque<(SocketAddr, Vec<u8>)>,
+    events: VecDeque<DhtEvent>,
+    indexer: Option<Indexer>,
+}
+
+impl Dht {
+    pub fn new(config: DhtConfig, now: Instant) -> Self {
+        let mut rng = Rng::new();
+        let id = NodeId::random(&mut rng);
+        Dht::with_id(id, config, rng, now)
+    }
+
+    pub fn with_id(id: NodeId, config: DhtConfig, rng: Rng, now: Instant) -> Self {
+        Dht {
+            table: RoutingTable::new(id, now),
+            rng,
+            next_transaction: 0,
+            pending: HashMap::new(),
+            next_lookup: 0,
+            lookups: HashMap::new(),
+            item_lookups: HashMap::new(),
+            storage: HashMap::new(),
+            items: ItemStore::new(config.max_items, config.item_ttl, config.max_puts_per_ip),
+            queries: HashMap::new(),
+            stats: DhtStats::default(),
+            secrets: [RandomState::new(), RandomState::new()],
+            secret_rotated: now,
+            routers: vec![],
+            next_bootstrap: now,
+            outbox: VecDeque::new(),
+            events: VecDeque::new(),
+            indexer: None,
+            config,
+        }
+    }
+
+    // Picks up where a previous run left off: same ID, and the saved nodes get asked for our
+    // neighbourhood just like bootstrap routers would be. Only the ones that answer make it into
+    // the table
+    pub fn from_state(state: &SavedState, config: DhtConfig, now: Instant) -> Self {
+        let mut dht = Dht::with_id(state.id, config, Rng::new(), now);
+        let id = dht.id();
+        for node in &state.nodes {
+            let query = Query::FindNode { id, target: id };
+            dht.send_query(node.addr, Some(node.id), query, PendingKind::Bootstrap, now);
+        }
+        dht
+    }
+
+    pub fn save_state(&self) -> SavedState {
+        SavedState {
+            id: self.id(),
+            nodes: self.table.nodes().copied().collect(),
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.table.own_id()
+    }
+
+    pub fn table(&self) -> &RoutingTable {
+        &self.table
+    }
+
+    // Resolved addresses of the bootstrap routers in the config
+    pub fn set_routers(&mut self, routers: &[SocketAddr], now: Instant) {
+        self.routers = routers
+            .iter()
+            .map(|addr| Router {
+                addr: *addr,
+                failures: 0,
+                retry_at: now,
+            })
+            .collect();
+    }
+
+    // Whether we know enough nodes to do useful lookups. `tick` bootstraps again on its own
+    // whenever this drops to false
+    pub fn is_healthy(&self) -> bool {
+        self.table.len() >= self.config.min_nodes
+    }
+
+    // Routers that answered last time we asked, or whose backoff has run out
+    pub fn routers_up(&self, now: Instant) -> usize {
+        self.routers.iter().filter(|r| r.retry_at <= now).count()
+    }
+
+    // Joins the network through the bootstrap routers. We don't know their IDs, so they get a
+    // find_node for our own ID and whatever they answer with seeds a proper lookup. Routers that
+    // keep failing are backed off rather than asked every time
+    pub fn bootstrap(&mut self, now: Instant) {
+        let id = self.id();
+        let routers: Vec<SocketAddr> = self
+            .routers
+            .iter()
+            .filter(|r| r.retry_at <= now)
+            .map(|r| r.addr)
+            .collect();
+        for addr in routers {
+            let query = Query::FindNode { id, target: id };
+            self.send_query(addr, None, query, PendingKind::Bootstrap, now);
+        }
+        self.next_bootstrap = now + self.config.bootstrap_interval;
+        if !self.table.is_empty() {
+            self.start_lookup(id, LookupKind::FindNode { bootstrap: true }, now);
+        }
+    }
+
+    // Looks for peers on a torrent. Results come back as `DhtEvent::Peers`
+    pub fn get_peers(&mut self, info_hash: InfoHash, now: Instant) {
+        audit::check_public(info_hash, Channel::Dht);
+        let kind = LookupKind::GetPeers {
+            info_hash,
+            announce: None,
+        };
+        self.start_lookup(info_hash.into(), kind, now);
+    }
+
+    // Like `get_peers`, then tells the closest nodes we're downloading the torrent on `port`
+    pub fn announce(&mut self, info_hash: InfoHash, port: u16, now: Instant) {
+        audit::check_public(info_hash, Channel::Dht);
+        let kind = LookupKind::GetPeers {
+            info_hash,
+            announce: Some(port),
+        };
+        self.start_lookup(info_hash.into(), kind, now);
+    }
+
+    // Looks up a BEP 44 item. `salt` only matters for mutable items, where the target is
+    // `mutable_target(key, salt)`. Results come back as `DhtEvent::Item`
+    pub fn get(&mut self, target: NodeId, salt: &[u8], now: Instant) {
+        self.start_item_lookup(target, salt.to_vec(), None, now);
+    }
+
+    // Stores a value under its own hash, which is returned
+    pub fn put_immutable(
+        &mut self,
+        value: &BencodeValue,
+        now: Instant,
+    ) -> Result<NodeId, ItemError> {
+        let item = Item::immutable(value);
+        item.validate()?;
+        let target = item.target();
+        self.start_item_lookup(target, vec![], Some((item, None)), now);
+        Ok(target)
+    }
+
+    // Stores an item signed with `MutableItem::sign`. With `cas`, nodes only take it if they
+    // currently have that sequence number
+    pub fn put_mutable(
+        &mut self,
+        item: MutableItem,
+        cas: Option<i64>,
+        now: Instant,
+    ) -> Result<NodeId, ItemError> {
+        let salt = item.salt.clone();
+        let item = Item::Mutable(item);
+        item.validate()?;
+        let target = item.target();
+        self.start_item_lookup(target, salt, Some((item, cas)), now);
+        Ok(target)
+    }
+
+    // Items others have put to us
+    pub fn items(&self) -> &ItemStore {
+        &self.items
+    }
+
+    // BEP 51 indexing: info-hashes from incoming queries and `sample` answers go to the indexer
+    pub fn set_indexer(&mut self, indexer: Indexer) {
+        self.indexer = Some(indexer);
+    }
+
+    // Asks a node for a sample of the info-hashes it stores. Answers come back as
+    // `DhtEvent::Sampled`, with the samples themselves going to the indexer
+    pub fn sample(&mut self, addr: SocketAddr, now: Instant) {
+        let query = Query::SampleInfohashes {
+            id: self.id(),
+            target: NodeId::random(&mut self.rng),
+        };
+        self.send_query(addr, None, query, PendingKind::Sample, now);
+    }
+
+    pub fn poll_transmit(&mut self) -> Option<(SocketAddr, Vec<u8>)> {
+        self.outbox.pop_front()
+    }
+
+    pub fn poll_event(&mut self) -> Option<DhtEvent> {
+        self.events.pop_front()
+    }
+
+    pub fn stats(&self) -> &DhtStats {
+        &self.stats
+    }
+
+    pub fn handle_packet(&mut self, buf: &[u8], from: SocketAddr, now: Instant) {
+        self.stats.packets_in += 1;
+        self.stats.bytes_in += buf.len() as u64;
+        // Garbage is common on a public UDP port and not worth an error reply
+        let Ok(msg) = Message::decode(buf) else {
+            self.stats.invalid += 1;
+            return;
+        };
+
+        match msg.body {
+            Body::Query(query) => {
+                if !self.allow_query(from.ip(), now) {
+                    self.stats.rate_limited += 1;
+                    return;
+                }
+                let method = std::str::from_utf8(query.method()).unwrap_or("unknown");
+                *self.stats.queries.entry(method).or_default() += 1;
+                self.handle_query(msg.transaction_id, query, from, now)
+            }
+            Body::Response(response) => {
+                self.handle_response(&msg.transaction_id, response, from, now)
+            }
+            Body::Error { .. } => self.handle_failure(&msg.transaction_id, Some(from), now),
+        }
+    }
+
+    // Back from a suspend: queries still out get a fresh timeout rather than all failing at once
+    // and taking their nodes down with them, every node in the table is pinged to see who's still
+    // there, and the routers are tried again if that leaves us short
+    pub fn on_wake(&mut self, now: Instant) {
+        for pending in self.pending.values_mut() {
+            pending.sent = now;
+        }
+        for router in &mut self.routers {
+            router.failures = 0;
+            router.retry_at = now;
+        }
+        let id = self.id();
+        let nodes: Vec<NodeInfo> = self.table.nodes().copied().collect();
+        for node in nodes {
+            let pinging = self.pending.values().any(|p| p.addr == node.addr);
+            if !pinging {
+                self.send_query(
+                    node.addr,
+                    Some(node.id),
+                    Query::Ping { id },
+                    PendingKind::Ping,
+                    now,
+                );
+            }
+        }
+        self.next_bootstrap = now;
+    }
+
+    pub fn tick(&mut self, now: Instant) {
+        let timed_out: Vec<Vec<u8>> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| now.saturating_duration_since(p.sent) >= self.config.query_timeout)
+            .map(|(tid, _)| tid.clone())
+            .collect();
+        self.stats.timeouts += timed_out.len() as u64;
+        for tid in timed_out {
+            self.handle_failure(&tid, None, now);
+        }
+
+        if now.saturating_duration_since(self.secret_rotated) >= self.config.token_rotation {
+            self.secrets.swap(0, 1);
+            self.secrets[0] = RandomState::new();
+            self.secret_rotated = now;
+        }
+
+        let ttl = self.config.peer_ttl;
+        self.storage.retain(|_, peers| {
+            peers.retain(|_, announced| now.saturating_duration_since(*announced) < ttl);
+            !peers.is_empty()
+        });
+        self.items.expire(now);
+        self.queries
+            .retain(|_, (window, _)| now.saturating_duration_since(*window) < QUERY_WINDOW);
+        if let Some(indexer) = &mut self.indexer {
+            indexer.expire(now);
+        }
+
+        let id = self.id();
+        for node in self.table.questionable(now) {
+            let pinging = self.pending.values().any(|p| p.addr == node.addr);
+            if !pinging {
+                self.send_query(
+                    node.addr,
+                    Some(node.id),
+                    Query::Ping { id },
+                    PendingKind::Ping,
+                    now,
+                );
+            }
+        }
+        for target in self.table.refresh_targets(now, &mut self.rng) {
+            self.start_lookup(target, LookupKind::FindNode { bootstrap: false }, now);
+        }
+
+        let bootstrapping = self
+            .pending
+            .values()
+            .any(|p| matches!(p.kind, PendingKind::Bootstrap));
+        if !self.is_healthy() && !bootstrapping && now >= self.next_bootstrap {
+            self.bootstrap(now);
+        }
+    }
+
+    // Info-hashes we're currently storing peers for
+    pub fn stored(&self) -> Vec<InfoHash> {
+        self.storage.keys().copied().collect()
+    }
+
+    // Peers stored across all of them
+    pub fn stored_peers(&self) -> usize {
+        self.storage.values().map(HashMap::len).sum()
+    }
+
+    fn handle_query(&mut self, tid: Vec<u8>, query: Query, from: SocketAddr, now: Instant) {
+        self.table.insert(
+            NodeInfo {
+                id: *query.id(),
+                addr: from,
+            },
+            now,
+        );
+        if let Some(indexer) = &mut self.indexer {
+            indexer.observe_query(&query, from);
+        }
+
+        let id = self.id();
+        let reply = match query {
+            Query::Ping { .. } => Message::response(tid, Response::new(id)),
+            Query::FindNode { target, .. } => {
+                let mut response = Response::new(id);
+                response.nodes = self.table.closest(&target, K);
+                Message::response(tid, response)
+            }
+            Query::GetPeers { info_hash, .. } => {
+                let mut response = Response::new(id);
+                response.token = Some(self.token(from.ip(), 0));
+                response.values = self
+                    .storage
+                    .get(&info_hash)
+                    .map(|peers| peers.keys().take(self.config.max_values).copied().collect())
+                    .unwrap_or_default();
+                if response.values.is_empty() {
+                    response.nodes = self.table.closest(&info_hash.into(), K);
+                }
+                Message::response(tid, response)
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                token,
+                implied_port,
+                ..
+            } => {
+                if !self.valid_token(from.ip(), &token) {
+                    self.reply(from, Message::error(tid, ERROR_PROTOCOL, "bad token"));
+                    return;
+                }
+
+                let port = if implied_port { from.port() } else { port };
+                self.store_peer(info_hash, SocketAddr::new(from.ip(), port), now);
+                Message::response(tid, Response::new(id))
+            }
+            Query::SampleInfohashes { target, .. } => {
+                let nodes = self.table.closest(&target, K);
+                let stored = self.stored();
+                Message::response(
+                    tid,
+                    sample_response(id, &stored, nodes, MAX_INTERVAL, &mut self.rng),
+                )
+            }
+            Query::Get { target, seq, .. } => {
+                let mut response = Response::new(id);
+                response.token = Some(self.token(from.ip(), 0));
+                response.nodes = self.table.closest(&target, K);
+                match self.items.get(&target) {
+                    Some(Item::Immutable(value)) => response.value = Some(value.clone()),
+                    Some(Item::Mutable(item)) => {
+                        response.key = Some(item.key);
+                        response.signature = Some(item.signature);
+                        response.seq = Some(item.seq);
+                        if seq.is_none_or(|seq| seq < item.seq) {
+                            response.value = Some(item.value.clone());
+                        }
+                    }
+                    None => {}
+                }
+                Message::response(tid, response)
+            }
+            Query::Put {
+                token, item, cas, ..
+            } => {
+                if !self.valid_token(from.ip(), &token) {
+                    self.reply(from, Message::error(tid, ERROR_PROTOCOL, "bad token"));
+                    return;
+                }
+                match self.items.put(item, cas, from.ip(), now) {
+                    Ok(()) => Message::response(tid, Response::new(id)),
+                    Err(err) => Message::error(tid, err.code(), err.message()),
+                }
+            }
+        };
+        self.reply(from, reply);
+    }
+
+    fn handle_response(
+        &mut self,
+        tid: &[u8],
+        mut response: Response,
+        from: SocketAddr,
+        now: Instant,
+    ) {
+        // Only trust answers to questions we actually asked, from who we asked
+        let Some(pending) = self.pending.remove(tid) else {
+            return;
+        };
+        if pending.addr != from || pending.id.is_some_and(|id| id != response.id) {
+            self.pending.insert(tid.to_vec(), pending);
+            return;
+        }
+
+        self.table.insert(
+            NodeInfo {
+                id: response.id,
+                addr: from,
+            },
+            now,
+        );
+
+        // Each family has its own table (BEP 32), so `nodes6` on an IPv4 socket is of no use to us
+        response
+            .nodes
+            .retain(|n| n.addr.is_ipv6() == from.is_ipv6());
+
+        match pending.kind {
+            PendingKind::Ping | PendingKind::Announce => {}
+            PendingKind::Sample => {
+                if let Some(indexer) = &mut self.indexer {
+                    let next = indexer.observe_samples(&response, from, now);
+                    self.events.push_back(DhtEvent::Sampled { from, next });
+                }
+            }
+            PendingKind::Bootstrap => {
+                if let Some(router) = self.routers.iter_mut().find(|r| r.addr == from) {
+                    router.failures = 0;
+                }
+                for node in &response.nodes {
+                    self.table.insert(*node, now);
+                }
+                let id = self.id();
+                let bootstrapping = self
+                    .lookups
+                    .values()
+                    .any(|(_, kind)| matches!(kind, LookupKind::FindNode { bootstrap: true }));
+                if !bootstrapping {
+                    self.start_lookup(id, LookupKind::FindNode { bootstrap: true }, now);
+                }
+            }
+            PendingKind::Lookup(lookup_id) => {
+                let Some((lookup, kind)) = self.lookups.get_mut(&lookup_id) else {
+                    return;
+                };
+                let target = lookup.target();
+                lookup.on_response(from, &response.nodes, response.token.clone());
+                match kind {
+                    LookupKind::GetPeers { info_hash, .. } if !response.values.is_empty() => {
+                        self.events.push_back(DhtEvent::Peers {
+                            info_hash: *info_hash,
+                            peers: response.values,
+                        });
+                    }
+                    LookupKind::Item => self.on_item_response(lookup_id, target, response),
+                    _ => {}
+                }
+                self.advance_lookup(lookup_id, now);
+            }
+        }
+    }
+
+    fn handle_failure(&mut self, tid: &[u8], from: Option<SocketAddr>, now: Instant) {
+        let Some(pending) = self.pending.get(tid) else {
+            return;
+        };
+        if from.is_some_and(|from| from != pending.addr) {
+            return;
+        }
+        let pending = self.pending.remove(tid).unwrap();
+
+        if let Some(id) = pending.id {
+            self.table.failed(&id);
+        }
+        if let PendingKind::Bootstrap = pending.kind
+            && let Some(router) = self.routers.iter_mut().find(|r| r.addr == pending.addr)
+        {
+            router.failures += 1;
+            router.retry_at = now + self.config.bootstrap_interval * (1 << router.failures.min(5));
+        }
+        if let PendingKind::Lookup(lookup_id) = pending.kind
+            && let Some((lookup, _)) = self.lookups.get_mut(&lookup_id)
+        {
+            lookup.on_failure(pending.addr);
+            self.advance_lookup(lookup_id, now);
+        }
+    }
+
+    // Keeps the newest item a response carries, as long as it checks out
+    fn on_item_response(&mut self, lookup_id: u64, target: NodeId, response: Response) {
+        let Some(state) = self.item_lookups.get_mut(&lookup_id) else {
+            return;
+        };
+        let Some(value) = response.value else {
+            return;
+        };
+        let item = match (response.key, response.signature, response.seq) {
+            (Some(key), Some(signature), Some(seq)) => {
+                if mutable_target(&key, &state.salt) != target {
+                    return;
+                }
+                Item::Mutable(MutableItem {
+                    key,
+                    salt: state.salt.clone(),
+                    seq,
+                    value,
+                    signature,
+                })
+            }
+            _ => Item::Immutable(value),
+        };
+        if item.target() != target || item.validate().is_err() {
+            return;
+        }
+        let newer = match &state.found {
+            None => true,
+            Some(found) => item.seq() > found.seq(),
+        };
+        if newer {
+            state.found = Some(item.clone());
+            self.events.push_back(DhtEvent::Item { target, item });
+        }
+    }
+
+    fn start_item_lookup(
+        &mut self,
+        target: NodeId,
+        salt: Vec<u8>,
+        put: Option<(Item, Option<i64>)>,
+        now: Instant,
+    ) {
+        let state = ItemLookup {
+            salt,
+            found: None,
+            put,
+        };
+        self.item_lookups.insert(self.next_lookup, state);
+        self.start_lookup(target, LookupKind::Item, now);
+    }
+
+    fn start_lookup(&mut self, target: NodeId, kind: LookupKind, now: Instant) {
+        let seeds = self.table.closest(&target, K);
+        let lookup_id = self.next_lookup;
+        self.next_lookup += 1;
+        self.lookups
+            .insert(lookup_id, (Lookup::new(target, &seeds), kind));
+        self.advance_lookup(lookup_id, now);
+    }
+
+    // Sends the next round of queries, or wraps the lookup up if it has converged
+    fn advance_lookup(&mut self, lookup_id: u64, now: Instant) {
+        let id = self.id();
+        let Some((lookup, kind)) = self.lookups.get_mut(&lookup_id) else {
+            return;
+        };
+        let kind = *kind;
+        let target = lookup.target();
+
+        for node in lookup.next_queries() {
+            let query = match kind {
+                LookupKind::FindNode { .. } => Query::FindNode { id, target },
+                LookupKind::GetPeers { info_hash, .. } => Query::GetPeers { id, info_hash },
+                LookupKind::Item => Query::Get {
+                    id,
+                    target,
+                    seq: None,
+                },
+            };
+            self.send_query(
+                node.addr,
+                Some(node.id),
+                query,
+                PendingKind::Lookup(lookup_id),
+                now,
+            );
+        }
+
+        let (lookup, _) = &self.lookups[&lookup_id];
+        if !lookup.is_done() {
+            return;
+        }
+        let (lookup, _) = self.lookups.remove(&lookup_id).unwrap();
+
+        match kind {
+            LookupKind::FindNode { bootstrap: true } => {
+                self.events.push_back(DhtEvent::Bootstrapped)
+            }
+            LookupKind::FindNode { bootstrap: false } => {}
+            LookupKind::GetPeers {
+                info_hash,
+                announce,
+            } => {
+                if let Some(port) = announce {
+                    for (node, token) in lookup.closest_responded() {
+                        let Some(token) = token else {
+                            continue;
+                        };
+                        let query = Query::AnnouncePeer {
+                            id,
+                            info_hash,
+                            port,
+                            token,
+                            implied_port: false,
+                        };
+                        self.send_query(
+                            node.addr,
+                            Some(node.id),
+                            query,
+                            PendingKind::Announce,
+                            now,
+                        );
+                    }
+                }
+                self.events.push_back(DhtEvent::LookupDone { info_hash });
+            }
+            LookupKind::Item => {
+                let state = self.item_lookups.remove(&lookup_id);
+                if let Some((item, cas)) = state.and_then(|s| s.put) {
+                    for (node, token) in lookup.closest_responded() {
+                        let Some(token) = token else {
+                            continue;
+                        };
+                        let query = Query::Put {
+                            id,
+                            token,
+                            item: item.clone(),
+                            cas,
+                        };
+                        self.send_query(
+                            node.addr,
+                            Some(node.id),
+                            query,
+                            PendingKind::Announce,
+                            now,
+                        );
+                    }
+                }
+                self.events.push_back(DhtEvent::ItemDone { target });
+            }
+        }
+    }
+
+    fn send_query(
+        &mut self,
+        addr: SocketAddr,
+        id: Option<NodeId>,
+        query: Query,
+        kind: PendingKind,
+        now: Instant,
+    ) {
+        let tid = self.next_transaction.to_be_bytes().to_vec();
+        self.next_transaction = self.next_transaction.wrapping_add(1);
+
+        self.reply(addr, Message::query(tid.clone(), query));
+        self.pending.insert(
+            tid,
+            Pending {
+                addr,
+                id,
+                sent: now,
+                kind,
+            },
+        );
+    }
+
+    fn reply(&mut self, addr: SocketAddr, msg: Message) {
+        let buf = msg.encode();
+        self.stats.packets_out += 1;
+        self.stats.bytes_out += buf.len() as u64;
+        self.outbox.push_back((addr, buf));
+    }
+
+    fn allow_query(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let (window, count) = self.queries.entry(ip).or_insert((now, 0));
+        if now.saturating_duration_since(*window) >= QUERY_WINDOW {
+            *window = now;
+            *count = 0;
+        }
+        *count += 1;
+        *count <= self.config.max_queries_per_ip
+    }
+
+    // Announces always get in: fresh peers are worth more than ones that may have left already
+    fn store_peer(&mut self, info_hash: InfoHash, peer: SocketAddr, now: Instant) {
+        if !self.storage.contains_key(&info_hash)
+            && self.storage.len() >= self.config.max_torrents.max(1)
+        {
+            // Fewest peers first, and of those the ones nobody has announced to for longest. A
+            // sixteenth go at once, so a full store isn't sorted through on every new info-hash
+            let mut candidates: Vec<_> = self
+                .storage
+                .iter()
+                .map(|(info_hash, peers)| (peers.len(), peers.values().max().copied(), *info_hash))
+                .collect();
+            let evict = candidates.len() / 16 + 1;
+            candidates.select_nth_unstable(evict - 1);
+            for (.., info_hash) in &candidates[..evict] {
+                self.storage.remove(info_hash);
+            }
+            self.stats.evicted_torrents += evict as u64;
+        }
+
+        let peers = self.storage.entry(info_hash).or_default();
+        if !peers.contains_key(&peer) && peers.len() >= self.config.max_peers_per_torrent.max(1) {
+            let oldest = peers
+                .iter()
+                .min_by_key(|(_, announced)| **announced)
+                .map(|(addr, _)| *addr);
+            if let Some(oldest) = oldest {
+                peers.remove(&oldest);
+                self.stats.evicted_peers += 1;
+            }
+        }
+        peers.insert(peer, now);
+    }
+
+    // Tokens are a keyed hash of the querier's IP, so we don't have to remember which ones we gave
+    // out. The key rotates, and the previous one is still accepted
+    fn token(&self, ip: IpAddr, generation: usize) -> Vec<u8> {
+        self.secrets[generation].hash_one(ip).to_be_bytes().to_vec()
+    }
+
+    fn valid_token(&self, ip: IpAddr, token: &[u8]) -> bool {
+        (0..self.secrets.len()).any(|generation| self.token(ip, generation) == token)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::dht::sample::{Discovered, Source};
+
+    fn addr(i: u8) -> SocketAddr {
+        SocketAddr::new([10, 0, 0, i].into(), 6881)
+    }
+
+    fn dht(i: u8, now: Instant) -> Dht {
+        Dht::with_id(
+            NodeId([i; 20]),
+            DhtConfig::default(),
+            Rng::with_seed(i as u64),
+            now,
+        )
+    }
+
+    // Delivers packets between nodes until everyone goes quiet
+    fn run(nodes: &mut [(SocketAddr, Dht)], now: Instant) {
+        loop {
+            let mut sent = vec![];
+            for (from, node) in nodes.iter_mut() {
+                while let Some((to, buf)) = node.poll_transmit() {
+                    sent.push((*from, to, buf));
+                }
+            }
+            if sent.is_empty() {
+                return;
+            }
+            for (from, to, buf) in sent {
+                if let Some((_, node)) = nodes.iter_mut().find(|(a, _)| *a == to) {
+                    node.handle_packet(&buf, from, now);
+                }
+            }
+        }
+    }
+
+    fn events(node: &mut Dht) -> Vec<DhtEvent> {
+        std::iter::from_fn(|| node.poll_event()).collect()
+    }
+
+    #[test]
+    fn test_answers_ping() {
+        let now = Instant::now();
+        let mut node = dht(1, now);
+        let ping = Message::query(
+            b"aa".to_vec(),
+            Query::Ping {
+                id: NodeId([2; 20]),
+            },
+        );
+        node.handle_packet(&ping.encode(), addr(2), now);
+
+        let (to, buf) = node.poll_transmit().unwrap();
+        assert_eq!(to, addr(2));
+        assert_eq!(
+            Message::decode(&buf).unwrap(),
+            Message::response(b"aa".to_vec(), Response::new(NodeId([1; 20])))
+        );
+        assert!(node.table().contains(&NodeId([2; 20])));
+    }
+
+    #[test]
+    fn test_bad_token_rejected() {
+        let now = Instant::now();
+        let mut node = dht(1, now);
+        let announce = Query::AnnouncePeer {
+            id: NodeId([2; 20]),
+            info_hash: InfoHash([7; 20]),
+            port: 1234,
+            token: b"forged".to_vec(),
+            implied_port: false,
+        };
+        node.handle_packet(
+            &Message::query(b"aa".to_vec(), announce).encode(),
+            addr(2),
+            now,
+        );
+
+        let (_, buf) = node.poll_transmit().unwrap();
+        assert!(matches!(
+            Message::decode(&buf).unwrap().body,
+            Body::Error {
+                code: ERROR_PROTOCOL,
+                ..
+            }
+        ));
+        assert!(node.stored().is_empty());
+    }
+
+    #[test]
+    fn test_token_survives_one_rotation() {
+        let now = Instant::now();
+        let node = dht(1, now);
+        let ip = IpAddr::from([10, 0, 0, 2]);
+        let token = node.token(ip, 0);
+
+        let mut node = node;
+        node.tick(now + node.config.token_rotation);
+        assert!(node.valid_token(ip, &token));
+        node.tick(now + node.config.token_rotation * 2);
+        assert!(!node.valid_token(ip, &token));
+        assert!(!node.valid_token([10, 0, 0, 3].into(), &node.token(ip, 0)));
+    }
+
+    #[test]
+    fn test_announce_then_get_peers() {
+        let now = Instant::now();
+        let info_hash = InfoHash([0x42; 20]);
+        let mut nodes: Vec<(SocketAddr, Dht)> =
+            (1..=6).map(|i| (addr(i), dht(i * 40, now))).collect();
+
+        // Everyone bootstraps off node 1
+        for (_, node) in nodes.iter_mut().skip(1) {
+            node.set_routers(&[addr(1)], now);
+            node.bootstrap(now);
+        }
+        run(&mut nodes, now);
+        for (_, node) in nodes.iter_mut().skip(1) {
+            assert!(events(node).contains(&DhtEvent::Bootstrapped));
+        }
+
+        nodes[2].1.announce(info_hash, 5000, now);
+        run(&mut nodes, now);
+        assert!(events(&mut nodes[2].1).contains(&DhtEvent::LookupDone { info_hash }));
+
+        nodes[5].1.get_peers(info_hash, now);
+        run(&mut nodes, now);
+        let found = events(&mut nodes[5].1);
+        assert!(found.contains(&DhtEvent::Peers {
+            info_hash,
+            peers: vec![SocketAddr::new([10, 0, 0, 3].into(), 5000)],
+        }));
+    }
+
+    #[test]
+    fn test_sample_feeds_indexer() {
+        let now = Instant::now();
+        let info_hash = InfoHash([0x42; 20]);
+        let mut nodes: Vec<(SocketAddr, Dht)> =
+            (1..=6).map(|i| (addr(i), dht(i * 40, now))).collect();
+        for (_, node) in nodes.iter_mut().skip(1) {
+            node.set_routers(&[addr(1)], now);
+            node.bootstrap(now);
+        }
+        run(&mut nodes, now);
+        nodes[2].1.announce(info_hash, 5000, now);
+        run(&mut nodes, now);
+
+        let (indexer, discovered) = Indexer::new(100);
+        nodes[5].1.set_indexer(indexer);
+        for i in 1..=5 {
+            nodes[5].1.sample(addr(i), now);
+        }
+        run(&mut nodes, now);
+
+        let sampled = events(&mut nodes[5].1)
+            .into_iter()
+            .filter(|e| matches!(e, DhtEvent::Sampled { .. }))
+            .count();
+        assert_eq!(sampled, 5);
+        let found: Vec<Discovered> = discovered.try_iter().collect();
+        assert!(
+            found
+                .iter()
+                .any(|d| d.info_hash == info_hash && d.source == Source::Sample)
+        );
+    }
+
+    #[test]
+    fn test_put_then_get() {
+        let now = Instant::now();
+        let mut nodes: Vec<(SocketAddr, Dht)> =
+            (1..=6).map(|i| (addr(i), dht(i * 40, now))).collect();
+        for (_, node) in nodes.iter_mut().skip(1) {
+            node.set_routers(&[addr(1)], now);
+            node.bootstrap(now);
+        }
+        run(&mut nodes, now);
+
+        let value = BencodeValue::ByteStr(b"Hello World!".to_vec());
+        let target = nodes[2].1.put_immutable(&value, now).unwrap();
+        let v1 = MutableItem::sign(&[9; 32], b"salt", 1, &value);
+        let mutable = nodes[2].1.put_mutable(v1, None, now).unwrap();
+        run(&mut nodes, now);
+        let v2 = MutableItem::sign(&[9; 32], b"salt", 2, &value);
+        nodes[3].1.put_mutable(v2.clone(), Some(1), now).unwrap();
+        run(&mut nodes, now);
+        assert!(nodes.iter().any(|(_, node)| !node.items().is_empty()));
+
+        nodes[5].1.get(target, b"", now);
+        nodes[5].1.get(mutable, b"salt", now);
+        run(&mut nodes, now);
+        let found = events(&mut nodes[5].1);
+        assert!(found.contains(&DhtEvent::Item {
+            target,
+            item: Item::immutable(&value),
+        }));
+        assert!(found.contains(&DhtEvent::Item {
+            target: mutable,
+            item: Item::Mutable(v2),
+        }));
+        assert!(found.contains(&DhtEvent::ItemDone { target: mutable }));
+    }
+
+    #[test]
+    fn test_put_rejects_bad_signature() {
+        let now = Instant::now();
+        let mut node = dht(1, now);
+        let mut item = MutableItem::sign(&[9; 32], b"", 1, &BencodeValue::Int(1));
+        item.seq = 2;
+        let put = Query::Put {
+            id: NodeId([2; 20]),
+            token: node.token([10, 0, 0, 2].into(), 0),
+            item: Item::Mutable(item.clone()),
+            cas: None,
+        };
+        node.handle_packet(&Message::query(b"aa".to_vec(), put).encode(), addr(2), now);
+
+        let (_, buf) = node.poll_transmit().unwrap();
+        assert!(matches!(
+            Message::decode(&buf).unwrap().body,
+            Body::Error { code: 206, .. }
+        ));
+        assert!(node.items().is_empty());
+        assert_eq!(
+            node.put_mutable(item, None, now),
+            Err(ItemError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn test_timeout_fails_lookup_node() {
+        let now = Instant::now();
+        let mut node = dht(1, now);
+        // Keep the automatic re-bootstrap out of the way
+        node.config.min_nodes = 0;
+        node.table.insert(
+            NodeInfo {
+                id: NodeId([2; 20]),
+                addr: addr(2),
+            },
+            now,
+        );
+        node.get_peers(InfoHash([3; 20]), now);
+        assert!(node.poll_transmit().is_some());
+
+        node.tick(now + node.config.query_timeout);
+        assert_eq!(
+            events(&mut node),
+            vec![DhtEvent::LookupDone {
+                info_hash: InfoHash([3; 20])
+            }]
+        );
+        assert!(node.pending.is_empty());
+    }
+
+    #[test]
+    fn test_router_backoff() {
+        let now = Instant::now();
+        let mut node = dht(1, now);
+        node.set_routers(&[addr(9)], now);
+        node.bootstrap(now);
+        assert_eq!(node.poll_transmit().unwrap().0, addr(9));
+
+        // The router never answers, so it's left alone for a while
+        let later = now + node.config.query_timeout;
+        node.tick(later);
+        assert_eq!(node.routers_up(later), 0);
+        assert!(node.poll_transmit().is_none());
+
+        let retry = later + node.config.bootstrap_interval * 2;
+        assert_eq!(node.routers_up(retry), 1);
+        node.tick(retry);
+        assert_eq!(node.poll_transmit().unwrap().0, addr(9));
+    }
+
+    #[test]
+    fn test_wake() {
+        let now = Instant::now();
+        let mut node = dht(1, now);
+        node.config.min_nodes = 0;
+        node.set_routers(&[addr(9)], now);
+        node.bootstrap(now);
+        node.table.insert(
+            NodeInfo {
+                id: NodeId([2; 20]),
+                addr: addr(2),
+            },
+            now,
+        );
+        while node.poll_transmit().is_some() {}
+
+        // Long past the router query's timeout by the clock, but it gets a fresh one instead of
+        // counting as a failure
+        let woke = now + Duration::from_secs(3600);
+        node.on_wake(woke);
+        let sent: Vec<SocketAddr> = std::iter::from_fn(|| node.poll_transmit())
+            .map(|(to, _)| to)
+            .collect();
+        assert_eq!(sent, vec![addr(2)]);
+        let later = woke + Duration::from_secs(1);
+        node.tick(later);
+        assert_eq!(node.routers_up(later), 1);
+    }
+
+    #[test]
+    fn test_rebootstraps_when_unhealthy() {
+        let now = Instant::now();
+        let mut node = dht(1, now);
+        node.set_routers(&[addr(9)], now);
+        assert!(!node.is_healthy());
+
+        node.tick(now);
+        assert_eq!(node.poll_transmit().unwrap().0, addr(9));
+        // Still waiting on the router, so no second attempt yet
+        node.tick(now + Duration::from_secs(1));
+        assert!(node.poll_transmit().is_none());
+    }
+
+    #[test]
+    fn test_from_state() {
+        let now = Instant::now();
+        let mut nodes = vec![(addr(1), dht(10, now)), (addr(2), dht(20, now))];
+        nodes[0].1.set_routers(&[addr(2)], now);
+        nodes[0].1.bootstrap(now);
+        run(&mut nodes, now);
+        let state = nodes[0].1.save_state();
+        assert_eq!(state.nodes.len(), 1);
+
+        let restored = Dht::from_state(&state, DhtConfig::default(), now);
+        nodes[0].1 = restored;
+        run(&mut nodes, now);
+
+        assert_eq!(nodes[0].1.id(), NodeId([10; 20]));
+        assert!(nodes[0].1.table().contains(&NodeId([20; 20])));
+        assert!(events(&mut nodes[0].1).contains(&DhtEvent::Bootstrapped));
+    }
+
+    #[test]
+    fn test_ignores_unsolicited_response() {
+        let now = Instant::now();
+        let mut node = dht(1, now);
+        let response = Message::response(b"zz".to_vec(), Response::new(NodeId([2; 20])));
+        node.handle_packet(&response.encode(), addr(2), now);
+
+        assert!(node.table().is_empty());
+    }
+
+    #[test]
+    fn test_keeps_to_own_family() {
+        let now = Instant::now();
+        let mut node = dht(1, now);
+        node.set_routers(&[addr(9)], now);
+        node.bootstrap(now);
+        let (_, buf) = node.poll_transmit().unwrap();
+        let tid = Message::decode(&buf).unwrap().transaction_id;
+
+        let mut response = Response::new(NodeId([9; 20]));
+        response.nodes = vec![
+            NodeInfo {
+                id: NodeId([3; 20]),
+                addr: addr(3),
+            },
+            NodeInfo {
+                id: NodeId([4; 20]),
+                addr: "[2001:db8::4]:6881".parse().unwrap(),
+            },
+        ];
+        node.handle_packet(&Message::response(tid, response).encode(), addr(9), now);
+
+        assert!(node.table().contains(&NodeId([3; 20])));
+        assert!(!node.table().contains(&NodeId([4; 20])));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let now = Instant::now();
+        let mut node = dht(1, now);
+        let ping = |i: u8| {
+            Message::query(
+                vec![i],
+                Query::Ping {
+                    id: NodeId([i; 20]),
+                },
+            )
+            .encode()
+        };
+        for i in 0..60 {
+            node.handle_packet(&ping(i), addr(2), now);
+        }
+        node.handle_packet(&ping(60), addr(3), now);
+        node.handle_packet(b"junk", addr(3), now);
+
+        // Another IP, or the same one a second later, gets answered again
+        let max = node.config.max_queries_per_ip;
+        assert_eq!(std::iter::from_fn(|| node.poll_transmit()).count(), max + 1);
+        node.tick(now + QUERY_WINDOW);
+        node.handle_packet(&ping(61), addr(2), now + QUERY_WINDOW);
+        assert!(node.poll_transmit().is_some());
+
+        let stats = node.stats();
+        assert_eq!(stats.packets_in, 63);
+        assert_eq!(stats.packets_out, max as u64 + 2);
+        assert_eq!(stats.queries[&"ping"], max as u64 + 2);
+        assert_eq!(stats.rate_limited, 60 - max as u64);
+        assert_eq!(stats.invalid, 1);
+    }
+
+    #[test]
+    fn test_storage_eviction() {
+        let now = Instant::now();
+        let config = DhtConfig {
+            max_peers_per_torrent: 2,
+            max_torrents: 2,
+            ..DhtConfig::default()
+        };
+        let mut node = Dht::with_id(NodeId([1; 20]), config, Rng::with_seed(1), now);
+        let announce = |node: &mut Dht, info_hash: u8, from: u8, at: u64| {
+            let query = Query::AnnouncePeer {
+                id: NodeId([from; 20]),
+                info_hash: InfoHash([info_hash; 20]),
+                port: 5000,
+                token: node.token(addr(from).ip(), 0),
+                implied_port: false,
+            };
+            let at = now + Duration::from_secs(at);
+            node.handle_packet(&Message::query(vec![from], query).encode(), addr(from), at);
+        };
+
+        // The peer that announced longest ago makes room
+        announce(&mut node, 7, 2, 0);
+        announce(&mut node, 7, 3, 1);
+        announce(&mut node, 7, 4, 2);
+        assert_eq!(node.stored_peers(), 2);
+        assert!(
+            !node.storage[&InfoHash([7; 20])].contains_key(&SocketAddr::new(addr(2).ip(), 5000))
+        );
+
+        // And the torrent with the fewest peers
+        announce(&mut node, 8, 5, 3);
+        announce(&mut node, 9, 6, 4);
+        let mut stored = node.stored();
+        stored.sort();
+        assert_eq!(stored, [InfoHash([7; 20]), InfoHash([9; 20])]);
+        assert_eq!(node.stats().evicted_peers, 1);
+        assert_eq!(node.stats().evicted_torrents, 1);
+    }
+}
@@ -0,0 +1,107 @@
+// Saving the routing table between runs. Keeping our node ID stable means the nodes that already
+// know us keep routing to us, and reloading the table means we can rejoin through nodes we know
+// instead of hammering the public bootstrap routers every start.
+// The file is a small bencoded dict: {"id": <20 bytes>, "nodes": <compact node info>}, plus
+// "nodes6" when there are IPv6 nodes to keep
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+use bencode::BencodeValue;
+
+use super::{NodeId, NodeInfo, parse_compact_nodes, write_compact_nodes};
+use crate::statefile;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct SavedState {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+}
+
+impl SavedState {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut root = BTreeMap::new();
+        root.insert(b"id".to_vec(), BencodeValue::ByteStr(self.id.0.to_vec()));
+        root.insert(
+            b"nodes".to_vec(),
+            BencodeValue::ByteStr(write_compact_nodes(&self.nodes, false)),
+        );
+        if self.nodes.iter().any(|n| n.addr.is_ipv6()) {
+            root.insert(
+                b"nodes6".to_vec(),
+                BencodeValue::ByteStr(write_compact_nodes(&self.nodes, true)),
+            );
+        }
+        bencode::encode(&BencodeValue::Dict(root))
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<SavedState> {
+        let values = bencode::decode(buf).ok()?;
+        let root = values.first()?;
+        let mut nodes = parse_compact_nodes(root.get(b"nodes")?.as_bytes()?, false)?;
+        if let Some(nodes6) = root.get(b"nodes6") {
+            nodes.extend(parse_compact_nodes(nodes6.as_bytes()?, true)?);
+        }
+        Some(SavedState {
+            id: NodeId::from_bytes(root.get(b"id")?.as_bytes()?)?,
+            nodes,
+        })
+    }
+
+    // A missing or corrupt file isn't an error, we just start from scratch (or from the backup
+    // of the save before)
+    pub fn load(path: &Path) -> io::Result<Option<SavedState>> {
+        statefile::load(path, SavedState::decode)
+    }
+
+    // See `statefile::save`: a crash halfway through can't leave a truncated table
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        statefile::save(path, &self.encode())
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use std::fs;
+
+    fn state() -> SavedState {
+        SavedState {
+            id: NodeId([1; 20]),
+            nodes: vec![
+                NodeInfo {
+                    id: NodeId([2; 20]),
+                    addr: "10.0.0.2:6881".parse().unwrap(),
+                },
+                NodeInfo {
+                    id: NodeId([3; 20]),
+                    addr: "[2001:db8::2]:6881".parse().unwrap(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        assert_eq!(SavedState::decode(&state().encode()), Some(state()));
+    }
+
+    #[test]
+    fn test_corrupt() {
+        assert_eq!(SavedState::decode(b"d2:id3:abce"), None);
+        assert_eq!(SavedState::decode(b"garbage"), None);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("hurricane-dht-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dht.dat");
+
+        assert_eq!(SavedState::load(&path).unwrap(), None);
+        state().save(&path).unwrap();
+        assert_eq!(SavedState::load(&path).unwrap(), Some(state()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
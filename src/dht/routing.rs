@@ -0,0 +1,373 @@
+// Kademlia routing table.
+// Buckets are indexed by how many leading bits a node's ID shares with ours. We start with a single
+// bucket covering the whole keyspace and split the last one (the one our own ID falls in) whenever
+// it fills up, so we end up knowing lots of nodes near us and a few far away.
+use std::time::{Duration, Instant};
+
+use super::{NodeId, NodeInfo};
+use crate::rng::Rng;
+
+// Nodes per bucket
+pub const K: usize = 8;
+
+// A node that hasn't been heard from in this long should be pinged before we trust it again, and a
+// bucket that hasn't changed in this long gets refreshed with a lookup
+pub const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+// Unanswered queries in a row before a node is considered bad and can be replaced
+const MAX_FAILURES: u32 = 3;
+
+#[derive(Debug, Clone)]
+struct Entry {
+    info: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+impl Entry {
+    fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    nodes: Vec<Entry>,
+    last_changed: Instant,
+}
+
+impl Bucket {
+    fn new(now: Instant) -> Self {
+        Bucket {
+            nodes: Vec::with_capacity(K),
+            last_changed: now,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Bucket>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId, now: Instant) -> Self {
+        RoutingTable {
+            own_id,
+            buckets: vec![Bucket::new(now)],
+        }
+    }
+
+    pub fn own_id(&self) -> NodeId {
+        self.own_id
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.nodes.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn num_buckets(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn contains(&self, id: &NodeId) -> bool {
+        let bucket = &self.buckets[self.bucket_index(id)];
+        bucket.nodes.iter().any(|e| e.info.id == *id)
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &NodeInfo> {
+        self.buckets
+            .iter()
+            .flat_map(|b| b.nodes.iter().map(|e| &e.info))
+    }
+
+    // Call whenever a node proves it's alive (answers us, or sends us a query). Returns whether the
+    // node is in the table afterwards
+    pub fn insert(&mut self, node: NodeInfo, now: Instant) -> bool {
+        if node.id == self.own_id {
+            return false;
+        }
+
+        loop {
+            let index = self.bucket_index(&node.id);
+            let last = index == self.buckets.len() - 1;
+            let bucket = &mut self.buckets[index];
+
+            if let Some(entry) = bucket.nodes.iter_mut().find(|e| e.info.id == node.id) {
+                // Same ID from a new address is more likely spoofing than a node moving
+                if entry.info.addr != node.addr {
+                    return false;
+                }
+                entry.last_seen = now;
+                entry.failures = 0;
+                bucket.last_changed = now;
+                return true;
+            }
+
+            let entry = Entry {
+                info: node,
+                last_seen: now,
+                failures: 0,
+            };
+            if bucket.nodes.len() < K {
+                bucket.nodes.push(entry);
+                bucket.last_changed = now;
+                return true;
+            }
+            if let Some(bad) = bucket.nodes.iter_mut().find(|e| e.is_bad()) {
+                *bad = entry;
+                bucket.last_changed = now;
+                return true;
+            }
+
+            // Only the bucket covering our own ID is allowed to split. Anything else that's full of
+            // good nodes stays that way, since long-lived nodes tend to stay up
+            if !last || self.buckets.len() >= 160 {
+                return false;
+            }
+            self.split_last(now);
+        }
+    }
+
+    // A query to this node timed out
+    pub fn failed(&mut self, id: &NodeId) {
+        let index = self.bucket_index(id);
+        if let Some(entry) = self.buckets[index]
+            .nodes
+            .iter_mut()
+            .find(|e| e.info.id == *id)
+        {
+            entry.failures += 1;
+        }
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        let index = self.bucket_index(id);
+        self.buckets[index].nodes.retain(|e| e.info.id != *id);
+    }
+
+    // The `count` known-good nodes closest to `target`
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self
+            .buckets
+            .iter()
+            .flat_map(|b| b.nodes.iter())
+            .filter(|e| !e.is_bad())
+            .map(|e| e.info)
+            .collect();
+        nodes.sort_by_key(|n| n.id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    // Nodes we haven't heard from in a while and should ping
+    pub fn questionable(&self, now: Instant) -> Vec<NodeInfo> {
+        self.buckets
+            .iter()
+            .flat_map(|b| b.nodes.iter())
+            .filter(|e| {
+                !e.is_bad() && now.saturating_duration_since(e.last_seen) >= QUESTIONABLE_AFTER
+            })
+            .map(|e| e.info)
+            .collect()
+    }
+
+    // Random targets inside each bucket that's gone quiet. Looking them up repopulates the bucket.
+    // The buckets count as refreshed from here on so we don't keep asking
+    pub fn refresh_targets(&mut self, now: Instant, rng: &mut Rng) -> Vec<NodeId> {
+        let mut targets = vec![];
+        for index in 0..self.buckets.len() {
+            let bucket = &mut self.buckets[index];
+            if now.saturating_duration_since(bucket.last_changed) < QUESTIONABLE_AFTER {
+                continue;
+            }
+            bucket.last_changed = now;
+            targets.push(self.random_id_in(index, rng));
+        }
+        targets
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> usize {
+        leading_zeros(&self.own_id.distance(id)).min(self.buckets.len() - 1)
+    }
+
+    fn split_last(&mut self, now: Instant) {
+        let index = self.buckets.len() - 1;
+        let mut new = Bucket::new(now);
+        let own_id = self.own_id;
+        let old = &mut self.buckets[index];
+        old.last_changed = now;
+
+        // Nodes that share more than `index` bits with us move to the new bucket
+        let (stay, moved) = old
+            .nodes
+            .drain(..)
+            .partition(|e| leading_zeros(&own_id.distance(&e.info.id)) == index);
+        old.nodes = stay;
+        new.nodes = moved;
+        self.buckets.push(new);
+    }
+
+    fn random_id_in(&self, index: usize, rng: &mut Rng) -> NodeId {
+        let mut id = [0u8; 20];
+        rng.fill(&mut id);
+
+        // Keep the first `index` bits of our own ID, and for every bucket but the last flip the
+        // next one so the ID lands in that bucket rather than a deeper one
+        for bit in 0..index {
+            set_bit(&mut id, bit, get_bit(&self.own_id.0, bit));
+        }
+        if index < self.buckets.len() - 1 {
+            set_bit(&mut id, index, !get_bit(&self.own_id.0, index));
+        }
+        NodeId(id)
+    }
+}
+
+fn leading_zeros(distance: &[u8; 20]) -> usize {
+    for (i, byte) in distance.iter().enumerate() {
+        if *byte != 0 {
+            return i * 8 + byte.leading_zeros() as usize;
+        }
+    }
+    160
+}
+
+fn get_bit(id: &[u8; 20], bit: usize) -> bool {
+    id[bit / 8] & (0x80 >> (bit % 8)) != 0
+}
+
+fn set_bit(id: &mut [u8; 20], bit: usize, value: bool) {
+    if value {
+        id[bit / 8] |= 0x80 >> (bit % 8);
+    } else {
+        id[bit / 8] &= !(0x80 >> (bit % 8));
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    // A node whose ID shares exactly `prefix` leading bits with the all-zero ID
+    fn node(prefix: usize, tag: u8) -> NodeInfo {
+        let mut id = [0u8; 20];
+        set_bit(&mut id, prefix, true);
+        id[19] = tag;
+        NodeInfo {
+            id: NodeId(id),
+            addr: format!("10.0.{}.{}:6881", prefix, tag).parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_leading_zeros() {
+        let mut d = [0u8; 20];
+        assert_eq!(leading_zeros(&d), 160);
+        d[1] = 0x10;
+        assert_eq!(leading_zeros(&d), 11);
+    }
+
+    #[test]
+    fn test_insert_and_closest() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(NodeId([0; 20]), now);
+        for i in 0..5 {
+            assert!(table.insert(node(i, 1), now));
+        }
+
+        assert_eq!(table.len(), 5);
+        let closest = table.closest(&NodeId([0; 20]), 2);
+        assert_eq!(closest, vec![node(4, 1), node(3, 1)]);
+    }
+
+    #[test]
+    fn test_own_bucket_splits() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(NodeId([0; 20]), now);
+        for tag in 0..K as u8 {
+            table.insert(node(0, tag), now);
+        }
+        assert_eq!(table.num_buckets(), 1);
+
+        // Bucket is full, but the new node is closer to us so the bucket splits to make room
+        assert!(table.insert(node(5, 0), now));
+        assert_eq!(table.num_buckets(), 2);
+        assert_eq!(table.len(), K + 1);
+    }
+
+    #[test]
+    fn test_far_bucket_full() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(NodeId([0; 20]), now);
+        table.insert(node(5, 0), now);
+        for tag in 0..K as u8 {
+            table.insert(node(0, tag), now);
+        }
+
+        // Bucket 0 can't split any further, so newcomers are turned away
+        assert!(!table.insert(node(0, 100), now));
+    }
+
+    #[test]
+    fn test_bad_nodes_replaced() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(NodeId([0; 20]), now);
+        table.insert(node(5, 0), now);
+        for tag in 0..K as u8 {
+            table.insert(node(0, tag), now);
+        }
+        for _ in 0..MAX_FAILURES {
+            table.failed(&node(0, 3).id);
+        }
+
+        assert!(!table.closest(&node(0, 3).id, 20).contains(&node(0, 3)));
+        assert!(table.insert(node(0, 100), now));
+        assert!(!table.contains(&node(0, 3).id));
+    }
+
+    #[test]
+    fn test_refuses_moved_node() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(NodeId([0; 20]), now);
+        let mut moved = node(1, 1);
+        table.insert(moved, now);
+        moved.addr = "1.1.1.1:1".parse().unwrap();
+
+        assert!(!table.insert(moved, now));
+    }
+
+    #[test]
+    fn test_questionable_and_refresh() {
+        let now = Instant::now();
+        let later = now + QUESTIONABLE_AFTER;
+        let mut table = RoutingTable::new(NodeId([0; 20]), now);
+        table.insert(node(2, 1), now);
+        let mut rng = Rng::with_seed(1);
+
+        assert_eq!(table.questionable(later), vec![node(2, 1)]);
+        let targets = table.refresh_targets(later, &mut rng);
+        assert_eq!(targets.len(), 1);
+        assert!(table.refresh_targets(later, &mut rng).is_empty());
+    }
+
+    #[test]
+    fn test_refresh_target_lands_in_bucket() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(NodeId([0; 20]), now);
+        for tag in 0..K as u8 {
+            table.insert(node(0, tag), now);
+        }
+        table.insert(node(3, 0), now);
+        let mut rng = Rng::with_seed(7);
+
+        let targets = table.refresh_targets(now + QUESTIONABLE_AFTER, &mut rng);
+        assert_eq!(table.bucket_index(&targets[0]), 0);
+        assert_eq!(table.bucket_index(&targets[1]), 1);
+    }
+}
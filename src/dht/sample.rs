@@ -0,0 +1,256 @@
+// BEP 51: DHT infohash indexing.
+// Nodes can be asked for a random sample of the info-hashes they store, which lets indexers walk
+// the keyspace without resorting to sybil tricks. On top of that we can passively record every
+// info-hash other nodes mention in their get_peers/announce_peer queries to us
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+use super::krpc::{Query, Response};
+use super::{NodeId, NodeInfo};
+use crate::infohash::InfoHash;
+use crate::rng::Rng;
+
+// Keeps a response comfortably under a typical UDP MTU
+pub const MAX_SAMPLES: usize = 20;
+
+// BEP 51 caps the interval at 6 hours, which is also libtorrent's default
+pub const MAX_INTERVAL: u32 = 6 * 60 * 60;
+
+// Builds our answer to a `sample_infohashes` query from the info-hashes we're storing peers for
+pub fn sample_response(
+    id: NodeId,
+    stored: &[InfoHash],
+    nodes: Vec<NodeInfo>,
+    interval: u32,
+    rng: &mut Rng,
+) -> Response {
+    let mut samples = stored.to_vec();
+    rng.shuffle(&mut samples);
+    samples.truncate(MAX_SAMPLES);
+
+    let mut response = Response::new(id);
+    response.samples = samples;
+    response.nodes = nodes;
+    response.interval = Some(interval.min(MAX_INTERVAL));
+    response.num = Some(stored.len() as u32);
+    response
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Source {
+    GetPeers,
+    AnnouncePeer,
+    Sample,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Discovered {
+    pub info_hash: InfoHash,
+    pub from: SocketAddr,
+    pub source: Source,
+}
+
+// Collects info-hashes from incoming traffic and sample responses, and hands each new one to
+// whoever holds the receiving end of the channel
+#[derive(Debug)]
+pub struct Indexer {
+    tx: Sender<Discovered>,
+    // Bounded memory of what we've already reported so the stream is mostly free of repeats
+    seen: HashSet<InfoHash>,
+    seen_order: VecDeque<InfoHash>,
+    capacity: usize,
+    // Nodes tell us how long to wait before sampling them again
+    next_sample: HashMap<SocketAddr, Instant>,
+}
+
+impl Indexer {
+    pub fn new(capacity: usize) -> (Indexer, Receiver<Discovered>) {
+        let (tx, rx) = mpsc::channel();
+        let indexer = Indexer {
+            tx,
+            seen: HashSet::new(),
+            seen_order: VecDeque::new(),
+            capacity,
+            next_sample: HashMap::new(),
+        };
+        (indexer, rx)
+    }
+
+    // Passive mode: call this with every query we receive
+    pub fn observe_query(&mut self, query: &Query, from: SocketAddr) {
+        match query {
+            Query::GetPeers { info_hash, .. } => self.record(*info_hash, from, Source::GetPeers),
+            Query::AnnouncePeer { info_hash, .. } => {
+                self.record(*info_hash, from, Source::AnnouncePeer)
+            }
+            _ => {}
+        }
+    }
+
+    // Active mode: record the samples from a `sample_infohashes` response. Returns the nodes it
+    // pointed us at that we're allowed to sample next
+    pub fn observe_samples(
+        &mut self,
+        response: &Response,
+        from: SocketAddr,
+        now: Instant,
+    ) -> Vec<NodeInfo> {
+        for info_hash in &response.samples {
+            self.record(*info_hash, from, Source::Sample);
+        }
+
+        let interval = response.interval.unwrap_or(0).min(MAX_INTERVAL);
+        self.next_sample
+            .insert(from, now + Duration::from_secs(interval as u64));
+
+        response
+            .nodes
+            .iter()
+            .filter(|node| self.can_sample(&node.addr, now))
+            .copied()
+            .collect()
+    }
+
+    pub fn can_sample(&self, addr: &SocketAddr, now: Instant) -> bool {
+        self.next_sample.get(addr).is_none_or(|next| now >= *next)
+    }
+
+    // Drop backoff entries that have expired so the map doesn't grow forever while crawling
+    pub fn expire(&mut self, now: Instant) {
+        self.next_sample.retain(|_, next| *next > now);
+    }
+
+    fn record(&mut self, info_hash: InfoHash, from: SocketAddr, source: Source) {
+        if !self.seen.insert(info_hash) {
+            return;
+        }
+
+        self.seen_order.push_back(info_hash);
+        if self.seen_order.len() > self.capacity
+            && let Some(oldest) = self.seen_order.pop_front()
+        {
+            self.seen.remove(&oldest);
+        }
+
+        // Nobody listening isn't an error, we just keep indexing into the void
+        let _ = self.tx.send(Discovered {
+            info_hash,
+            from,
+            source,
+        });
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_sample_response_caps_samples() {
+        let stored: Vec<InfoHash> = (0..50u8).map(|i| InfoHash([i; 20])).collect();
+        let mut rng = Rng::with_seed(3);
+        let response = sample_response(NodeId([0; 20]), &stored, vec![], 99999, &mut rng);
+
+        assert_eq!(response.samples.len(), MAX_SAMPLES);
+        assert_eq!(response.num, Some(50));
+        assert_eq!(response.interval, Some(MAX_INTERVAL));
+    }
+
+    #[test]
+    fn test_passive_records_get_peers_and_announce() {
+        let (mut indexer, rx) = Indexer::new(100);
+        let get_peers = Query::GetPeers {
+            id: NodeId([1; 20]),
+            info_hash: InfoHash([9; 20]),
+        };
+        let announce = Query::AnnouncePeer {
+            id: NodeId([1; 20]),
+            info_hash: InfoHash([8; 20]),
+            port: 1,
+            token: vec![],
+            implied_port: false,
+        };
+        indexer.observe_query(&get_peers, addr(1));
+        indexer.observe_query(&announce, addr(2));
+        indexer.observe_query(
+            &Query::Ping {
+                id: NodeId([1; 20]),
+            },
+            addr(3),
+        );
+
+        let found: Vec<Discovered> = rx.try_iter().collect();
+        assert_eq!(
+            found,
+            vec![
+                Discovered {
+                    info_hash: InfoHash([9; 20]),
+                    from: addr(1),
+                    source: Source::GetPeers
+                },
+                Discovered {
+                    info_hash: InfoHash([8; 20]),
+                    from: addr(2),
+                    source: Source::AnnouncePeer
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duplicates_suppressed() {
+        let (mut indexer, rx) = Indexer::new(100);
+        let query = Query::GetPeers {
+            id: NodeId([1; 20]),
+            info_hash: InfoHash([9; 20]),
+        };
+        indexer.observe_query(&query, addr(1));
+        indexer.observe_query(&query, addr(2));
+
+        assert_eq!(rx.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn test_seen_is_bounded() {
+        let (mut indexer, rx) = Indexer::new(2);
+        for i in [1u8, 2, 3, 1] {
+            let query = Query::GetPeers {
+                id: NodeId([0; 20]),
+                info_hash: InfoHash([i; 20]),
+            };
+            indexer.observe_query(&query, addr(1));
+        }
+
+        // 1 fell out of the window when 3 showed up, so it gets reported again
+        assert_eq!(rx.try_iter().count(), 4);
+    }
+
+    #[test]
+    fn test_samples_respect_interval() {
+        let now = Instant::now();
+        let (mut indexer, rx) = Indexer::new(100);
+        let mut response = Response::new(NodeId([1; 20]));
+        response.samples = vec![InfoHash([5; 20])];
+        response.interval = Some(60);
+        response.nodes = vec![NodeInfo {
+            id: NodeId([2; 20]),
+            addr: "10.0.0.2:6881".parse().unwrap(),
+        }];
+
+        let next = indexer.observe_samples(&response, addr(1), now);
+
+        assert_eq!(next.len(), 1);
+        assert_eq!(rx.try_recv().unwrap().source, Source::Sample);
+        assert!(!indexer.can_sample(&addr(1), now + Duration::from_secs(59)));
+        assert!(indexer.can_sample(&addr(1), now + Duration::from_secs(60)));
+
+        indexer.expire(now + Duration::from_secs(61));
+        assert!(indexer.next_sample.is_empty());
+    }
+}
@@ -0,0 +1,261 @@
+// Runs a `Dht` over a real UDP socket. Meant to be driven from its own thread (or a loop that
+// doesn't mind blocking for `poll`'s timeout); torrents pick up peers from the returned events.
+// Behind a SOCKS5 proxy everything goes through its UDP relay instead. A socket is either IPv4 or
+// IPv6; dual-stack clients run one of each with the same node ID (BEP 32)
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use super::node::{Dht, DhtConfig, DhtEvent};
+use super::persist::SavedState;
+use crate::proxy::{ProxyConfig, Target, UdpRelay, udp_socket_for};
+
+// Biggest KRPC message we'll accept. Real ones are well under a typical MTU
+const MAX_PACKET: usize = 2048;
+
+#[derive(Debug)]
+pub struct DhtSocket {
+    socket: UdpSocket,
+    relay: Option<UdpRelay>,
+    dht: Dht,
+    buf: Vec<u8>,
+}
+
+impl DhtSocket {
+    pub fn bind(addr: SocketAddr, dht: Dht) -> io::Result<Self> {
+        Ok(DhtSocket {
+            socket: UdpSocket::bind(addr)?,
+            relay: None,
+            dht,
+            buf: vec![0; MAX_PACKET],
+        })
+    }
+
+    // Through the proxy's UDP relay. Nodes only ever see the relay's address, so few will find
+    // us on their own; what we look up still works
+    pub fn bind_via(proxy: &ProxyConfig, dht: Dht, timeout: Duration) -> io::Result<Self> {
+        let socket = udp_socket_for(proxy)?;
+        let relay = proxy.associate(&socket, timeout)?;
+        Ok(DhtSocket {
+            socket,
+            relay: Some(relay),
+            dht,
+            buf: vec![0; MAX_PACKET],
+        })
+    }
+
+    // Binds and starts joining the network. With a saved state from a previous run we rejoin
+    // through the nodes we knew; the routers only get asked if too few of those answer
+    pub fn start(
+        addr: SocketAddr,
+        config: DhtConfig,
+        state_path: Option<&Path>,
+    ) -> io::Result<Self> {
+        let now = Instant::now();
+        let routers = resolve_routers(&config.routers, addr.is_ipv6());
+        let saved = match state_path {
+            Some(path) => SavedState::load(path)?,
+            None => None,
+        };
+
+        let mut dht = match &saved {
+            Some(state) => Dht::from_state(state, config, now),
+            None => Dht::new(config, now),
+        };
+        dht.set_routers(&routers, now);
+        if saved.is_none_or(|s| s.nodes.is_empty()) {
+            dht.bootstrap(now);
+        }
+        DhtSocket::bind(addr, dht)
+    }
+
+    // `start` through the proxy's UDP relay, see `bind_via`. Over IPv4, and from scratch: nodes
+    // saved from a direct run would only know us by an address we no longer use
+    pub fn start_via(
+        proxy: &ProxyConfig,
+        config: DhtConfig,
+        timeout: Duration,
+    ) -> io::Result<Self> {
+        let now = Instant::now();
+        let routers = resolve_routers(&config.routers, false);
+        let mut dht = Dht::new(config, now);
+        dht.set_routers(&routers, now);
+        dht.bootstrap(now);
+        DhtSocket::bind_via(proxy, dht, timeout)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        self.dht.save_state().save(path)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn dht(&mut self) -> &mut Dht {
+        &mut self.dht
+    }
+
+    // Flushes queued packets, waits up to `timeout` for incoming ones, runs timers and returns
+    // whatever happened
+    pub fn poll(&mut self, timeout: Duration) -> io::Result<Vec<DhtEvent>> {
+        self.flush()?;
+
+        self.socket
+            .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.socket.recv_from(&mut self.buf) {
+                Ok((len, from)) => {
+                    let packet = match &self.relay {
+                        Some(relay) => relay.unwrap(from, &self.buf[..len]),
+                        None => Some((from, &self.buf[..len])),
+                    };
+                    if let Some((from, packet)) = packet {
+                        self.dht.handle_packet(packet, from, Instant::now());
+                        self.flush()?;
+                    }
+                }
+                // Interrupted is a signal: back to the caller, which may be shutting down
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::Interrupted
+                    ) =>
+                {
+                    break;
+                }
+                // ICMP port unreachable from some dead node shows up here on some platforms
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => {}
+                Err(e) => return Err(e),
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            self.socket.set_read_timeout(Some(deadline - now))?;
+        }
+
+        self.dht.tick(Instant::now());
+        self.flush()?;
+        Ok(std::iter::from_fn(|| self.dht.poll_event()).collect())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while let Some((to, buf)) = self.dht.poll_transmit() {
+            let sent = match &self.relay {
+                Some(relay) => relay.send_to(&self.socket, &buf, &Target::Addr(to)),
+                None => self.socket.send_to(&buf, to),
+            };
+            if let Err(e) = sent {
+                // One unreachable node shouldn't take the whole DHT down
+                if e.kind() != io::ErrorKind::ConnectionRefused {
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Routers that don't resolve (no network, typo in the config) are skipped, and so are addresses
+// of the other family
+pub fn resolve_routers(routers: &[String], ipv6: bool) -> Vec<SocketAddr> {
+    routers
+        .iter()
+        .filter_map(|router| router.to_socket_addrs().ok())
+        .flat_map(|addrs| addrs.filter(|addr| addr.is_ipv6() == ipv6))
+        .collect()
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::dht::node::DhtConfig;
+
+    #[test]
+    fn test_bootstrap_over_udp() {
+        let localhost: SocketAddr = ([127, 0, 0, 1], 0).into();
+        let now = Instant::now();
+        let mut router = DhtSocket::bind(localhost, Dht::new(DhtConfig::default(), now)).unwrap();
+        let mut node = DhtSocket::bind(localhost, Dht::new(DhtConfig::default(), now)).unwrap();
+        let router_addr = router.local_addr().unwrap();
+
+        node.dht().set_routers(&[router_addr], now);
+        node.dht().bootstrap(now);
+        let mut bootstrapped = false;
+        for _ in 0..20 {
+            router.poll(Duration::from_millis(10)).unwrap();
+            if node
+                .poll(Duration::from_millis(10))
+                .unwrap()
+                .contains(&DhtEvent::Bootstrapped)
+            {
+                bootstrapped = true;
+                break;
+            }
+        }
+
+        assert!(bootstrapped);
+        assert_eq!(node.dht().table().len(), 1);
+    }
+
+    #[test]
+    fn test_resolve_routers() {
+        let routers = vec![
+            "127.0.0.1:6881".to_string(),
+            "[::1]:6881".to_string(),
+            "not an address".to_string(),
+        ];
+
+        assert_eq!(
+            resolve_routers(&routers, false),
+            vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(
+            resolve_routers(&routers, true),
+            vec!["[::1]:6881".parse::<SocketAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_restart_from_saved_state() {
+        let localhost: SocketAddr = ([127, 0, 0, 1], 0).into();
+        let now = Instant::now();
+        let path = std::env::temp_dir().join(format!("hurricane-dht-{}.dat", std::process::id()));
+        let config = DhtConfig {
+            routers: vec![],
+            ..DhtConfig::default()
+        };
+
+        let mut peer = DhtSocket::bind(localhost, Dht::new(config.clone(), now)).unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        let peer_id = peer.dht().id();
+
+        let mut first = DhtSocket::start(localhost, config.clone(), Some(&path)).unwrap();
+        first.dht().set_routers(&[peer_addr], now);
+        first.dht().bootstrap(now);
+        for _ in 0..10 {
+            peer.poll(Duration::from_millis(10)).unwrap();
+            first.poll(Duration::from_millis(10)).unwrap();
+        }
+        let id = first.dht().id();
+        first.save(&path).unwrap();
+        drop(first);
+
+        // Same ID, and the peer from last time is back in the table without any routers
+        let mut second = DhtSocket::start(localhost, config, Some(&path)).unwrap();
+        for _ in 0..10 {
+            second.poll(Duration::from_millis(10)).unwrap();
+            peer.poll(Duration::from_millis(10)).unwrap();
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(second.dht().id(), id);
+        assert!(second.dht().table().contains(&peer_id));
+    }
+}
@@ -0,0 +1,174 @@
+// Updatable torrents (BEP 46). A publisher keeps a mutable item under their key whose value is
+// {"ih": <info-hash>}, and bumps its sequence number for every new version. Subscribers poll the
+// item and switch over to whatever info-hash the newest sequence number points at.
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use bencode::BencodeValue;
+
+use super::NodeId;
+use super::item::{Item, MutableItem, mutable_target};
+use super::node::{Dht, DhtEvent};
+use crate::infohash::InfoHash;
+
+// How often subscribers look for a new version. Items live for two hours without a refresh, so
+// publishers should put again well within that
+pub const UPDATE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+// The item a publisher puts for version `seq` of their torrent
+pub fn torrent_item(
+    secret_key: &[u8; 32],
+    salt: &[u8],
+    seq: i64,
+    info_hash: InfoHash,
+) -> MutableItem {
+    let mut value = BTreeMap::new();
+    value.insert(b"ih".to_vec(), BencodeValue::ByteStr(info_hash.0.to_vec()));
+    MutableItem::sign(secret_key, salt, seq, &BencodeValue::Dict(value))
+}
+
+// Following one publisher's key
+#[derive(Debug)]
+pub struct Subscription {
+    public_key: [u8; 32],
+    salt: Vec<u8>,
+    target: NodeId,
+    seq: Option<i64>,
+    info_hash: Option<InfoHash>,
+    next_poll: Instant,
+}
+
+impl Subscription {
+    pub fn new(public_key: [u8; 32], salt: &[u8], now: Instant) -> Self {
+        Subscription {
+            public_key,
+            salt: salt.to_vec(),
+            target: mutable_target(&public_key, salt),
+            seq: None,
+            info_hash: None,
+            next_poll: now,
+        }
+    }
+
+    pub fn public_key(&self) -> &[u8; 32] {
+        &self.public_key
+    }
+
+    pub fn target(&self) -> NodeId {
+        self.target
+    }
+
+    // The newest version we've seen, if any
+    pub fn info_hash(&self) -> Option<InfoHash> {
+        self.info_hash
+    }
+
+    pub fn seq(&self) -> Option<i64> {
+        self.seq
+    }
+
+    // Starts a lookup whenever one is due. Call it regularly, e.g. alongside `Dht::tick`
+    pub fn poll(&mut self, dht: &mut Dht, now: Instant) {
+        if now < self.next_poll {
+            return;
+        }
+        dht.get(self.target, &self.salt, now);
+        self.next_poll = now + UPDATE_INTERVAL;
+    }
+
+    // Feed every DHT event through here. Returns the new info-hash when the publisher has moved on
+    // to a newer version (or on the first one we find)
+    pub fn on_event(&mut self, event: &DhtEvent) -> Option<InfoHash> {
+        let DhtEvent::Item {
+            target,
+            item: Item::Mutable(item),
+        } = event
+        else {
+            return None;
+        };
+        // The DHT already checked the signature and that the key and salt match the target
+        if *target != self.target || self.seq.is_some_and(|seq| item.seq <= seq) {
+            return None;
+        }
+
+        let values = bencode::decode(&item.value).ok()?;
+        let info_hash = values
+            .first()?
+            .get(b"ih")?
+            .as_bytes()
+            .and_then(InfoHash::from_bytes)?;
+        self.seq = Some(item.seq);
+        if self.info_hash == Some(info_hash) {
+            return None;
+        }
+        self.info_hash = Some(info_hash);
+        Some(info_hash)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::dht::DhtConfig;
+    use crate::rng::Rng;
+
+    fn event(item: MutableItem) -> DhtEvent {
+        DhtEvent::Item {
+            target: Item::Mutable(item.clone()).target(),
+            item: Item::Mutable(item),
+        }
+    }
+
+    #[test]
+    fn test_follows_newest_version() {
+        let now = Instant::now();
+        let v1 = torrent_item(&[3; 32], b"", 1, InfoHash([1; 20]));
+        let mut subscription = Subscription::new(v1.key, b"", now);
+
+        assert_eq!(
+            subscription.on_event(&event(v1.clone())),
+            Some(InfoHash([1; 20]))
+        );
+        // Seen already, or older
+        assert_eq!(subscription.on_event(&event(v1)), None);
+
+        let v3 = torrent_item(&[3; 32], b"", 3, InfoHash([3; 20]));
+        let v2 = torrent_item(&[3; 32], b"", 2, InfoHash([2; 20]));
+        assert_eq!(subscription.on_event(&event(v3)), Some(InfoHash([3; 20])));
+        assert_eq!(subscription.on_event(&event(v2)), None);
+        assert_eq!(subscription.seq(), Some(3));
+    }
+
+    #[test]
+    fn test_ignores_other_targets() {
+        let now = Instant::now();
+        let mut subscription = Subscription::new([3; 32], b"other salt", now);
+        let item = torrent_item(&[3; 32], b"", 1, InfoHash([1; 20]));
+
+        assert_eq!(subscription.on_event(&event(item)), None);
+    }
+
+    #[test]
+    fn test_poll_interval() {
+        let now = Instant::now();
+        let mut dht = Dht::with_id(
+            NodeId([1; 20]),
+            DhtConfig::default(),
+            Rng::with_seed(1),
+            now,
+        );
+        let mut subscription = Subscription::new([3; 32], b"", now);
+
+        subscription.poll(&mut dht, now);
+        assert_eq!(
+            dht.poll_event(),
+            Some(DhtEvent::ItemDone {
+                target: subscription.target()
+            })
+        );
+        subscription.poll(&mut dht, now + UPDATE_INTERVAL / 2);
+        assert_eq!(dht.poll_event(), None);
+        subscription.poll(&mut dht, now + UPDATE_INTERVAL);
+        assert!(dht.poll_event().is_some());
+    }
+}
@@ -0,0 +1,192 @@
+// Write cache of downloaded blocks. Blocks stay in memory after being written until their piece
+// has been verified, so hashing a finished piece doesn't have to read back what we wrote a moment
+// ago. The blocks are refcounted: the verify job gets a `PieceBuffer` of references, not a copy,
+// and the cache can drop its side (or evict) while the job still runs. When memory runs short
+// the oldest pieces go first, and whatever's missing at verify time is read from disk instead.
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::peer::BLOCK_SIZE;
+
+// The blocks of one piece as the cache had them, by block index. None where the cache didn't have
+// the block any more and it has to come from disk
+#[derive(Debug, Clone)]
+pub struct PieceBuffer {
+    pub piece: u32,
+    pub blocks: Vec<Option<Arc<[u8]>>>,
+}
+
+impl PieceBuffer {
+    // Everything in memory, no disk reads needed
+    pub fn is_complete(&self) -> bool {
+        self.blocks.iter().all(Option::is_some)
+    }
+}
+
+// Where verified bytes came from. Shared with the verify jobs, hence the atomics
+#[derive(Debug, Default)]
+pub struct VerifyStats {
+    cached_bytes: AtomicU64,
+    disk_bytes: AtomicU64,
+}
+
+impl VerifyStats {
+    pub fn new() -> Self {
+        VerifyStats::default()
+    }
+
+    pub fn add(&self, cached: u64, disk: u64) {
+        self.cached_bytes.fetch_add(cached, Ordering::Relaxed);
+        self.disk_bytes.fetch_add(disk, Ordering::Relaxed);
+    }
+
+    pub fn cached_bytes(&self) -> u64 {
+        self.cached_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn disk_bytes(&self) -> u64 {
+        self.disk_bytes.load(Ordering::Relaxed)
+    }
+
+    // Share of hashed bytes that didn't have to be read back, 0.0 to 1.0
+    pub fn hit_ratio(&self) -> f64 {
+        let cached = self.cached_bytes();
+        match cached + self.disk_bytes() {
+            0 => 0.0,
+            total => cached as f64 / total as f64,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BlockCache {
+    pieces: HashMap<u32, Vec<Option<Arc<[u8]>>>>,
+    // Pieces in the order they were first written to, oldest first
+    order: VecDeque<u32>,
+    capacity: usize,
+    used: usize,
+}
+
+impl BlockCache {
+    // `capacity` in bytes
+    pub fn new(capacity: usize) -> Self {
+        BlockCache {
+            pieces: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+            used: 0,
+        }
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    // A block that was just written. Only 16 KiB blocks (or a short last one) at 16 KiB offsets
+    // are kept, which is every block unless the request size was changed. Verifying reads the
+    // rest back from disk
+    pub fn insert(&mut self, piece: u32, offset: u32, data: Arc<[u8]>) {
+        if !offset.is_multiple_of(BLOCK_SIZE) || data.len() > BLOCK_SIZE as usize {
+            return;
+        }
+        let index = (offset / BLOCK_SIZE) as usize;
+        let blocks = self.pieces.entry(piece).or_insert_with(|| {
+            self.order.push_back(piece);
+            vec![]
+        });
+        if blocks.len() <= index {
+            blocks.resize(index + 1, None);
+        }
+        self.used += data.len();
+        if let Some(old) = blocks[index].replace(data) {
+            self.used -= old.len();
+        }
+        self.evict(piece);
+    }
+
+    pub fn get(&self, piece: u32, offset: u32) -> Option<Arc<[u8]>> {
+        self.pieces
+            .get(&piece)?
+            .get((offset / BLOCK_SIZE) as usize)?
+            .clone()
+    }
+
+    // References to the piece's blocks for verification. Nothing is copied
+    pub fn piece_buffer(&self, piece: u32, num_blocks: usize) -> PieceBuffer {
+        let mut blocks = self.pieces.get(&piece).cloned().unwrap_or_default();
+        blocks.resize(num_blocks, None);
+        PieceBuffer { piece, blocks }
+    }
+
+    // Once the piece is verified (or failed) the cache has no more use for it. Buffers handed
+    // out keep their blocks alive until they're done
+    pub fn remove(&mut self, piece: u32) {
+        if let Some(blocks) = self.pieces.remove(&piece) {
+            self.used -= blocks.iter().flatten().map(|b| b.len()).sum::<usize>();
+            self.order.retain(|p| *p != piece);
+        }
+    }
+
+    // Drops the oldest pieces until we fit again. The piece being written to goes last
+    fn evict(&mut self, keep: u32) {
+        while self.used > self.capacity {
+            let Some(oldest) = self.order.iter().copied().find(|p| *p != keep) else {
+                return;
+            };
+            self.remove(oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn block(byte: u8) -> Arc<[u8]> {
+        vec![byte; BLOCK_SIZE as usize].into()
+    }
+
+    #[test]
+    fn test_buffer_shares_blocks() {
+        let mut cache = BlockCache::new(4 * BLOCK_SIZE as usize);
+        cache.insert(0, 0, block(1));
+        cache.insert(0, BLOCK_SIZE, block(2));
+
+        let buffer = cache.piece_buffer(0, 2);
+        assert!(buffer.is_complete());
+        assert!(Arc::ptr_eq(
+            buffer.blocks[1].as_ref().unwrap(),
+            &cache.get(0, BLOCK_SIZE).unwrap()
+        ));
+
+        // The buffer outlives the cache's copy
+        cache.remove(0);
+        assert_eq!(cache.used(), 0);
+        assert_eq!(buffer.blocks[0].as_ref().unwrap()[0], 1);
+    }
+
+    #[test]
+    fn test_evicts_oldest_piece() {
+        let mut cache = BlockCache::new(3 * BLOCK_SIZE as usize);
+        cache.insert(0, 0, block(0));
+        cache.insert(1, 0, block(1));
+        cache.insert(1, BLOCK_SIZE, block(1));
+        cache.insert(1, 2 * BLOCK_SIZE, block(1));
+
+        assert_eq!(cache.get(0, 0), None);
+        assert_eq!(cache.used(), 3 * BLOCK_SIZE as usize);
+        let buffer = cache.piece_buffer(1, 4);
+        assert!(!buffer.is_complete());
+        assert!(buffer.blocks[3].is_none());
+    }
+
+    #[test]
+    fn test_hit_ratio() {
+        let stats = VerifyStats::new();
+        assert_eq!(stats.hit_ratio(), 0.0);
+
+        stats.add(300, 100);
+        assert_eq!(stats.hit_ratio(), 0.75);
+    }
+}
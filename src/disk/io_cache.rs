@@ -0,0 +1,475 @@
+// Block cache in front of `Storage` for the session's disk traffic, as opposed to `BlockCache`,
+// which only holds blocks until their piece is hashed.
+// Writes are held back for a little while so blocks that arrive out of order (as they do from a
+// dozen peers at once) can go out as one long sequential write instead of many scattered 16 KiB
+// ones. Once flushed, blocks stay on as clean cache entries, so a piece we just finished is still
+// in memory when peers start asking for it; reads served from disk can be added too. A peer that
+// requests blocks in order gets the next few read ahead. Clean entries are evicted least recently
+// used first; dirty ones never are, they go out when their timer runs out or there are too many.
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::ops::AddAssign;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::peer::Block;
+
+#[derive(Debug, Clone)]
+pub struct IoCacheConfig {
+    // Clean blocks kept around for reads, in bytes
+    pub capacity: usize,
+    // Dirty bytes past which everything is flushed right away
+    pub max_dirty: usize,
+    // How long a block may wait for its neighbours before it's written anyway
+    pub flush_after: Duration,
+    // Longest single write, in bytes. A run that reaches it goes out without waiting
+    pub max_write: usize,
+    // Blocks to read ahead for a peer reading sequentially
+    pub read_ahead: u32,
+}
+
+impl Default for IoCacheConfig {
+    fn default() -> Self {
+        IoCacheConfig {
+            capacity: 64 * 1024 * 1024,
+            max_dirty: 16 * 1024 * 1024,
+            flush_after: Duration::from_secs(2),
+            max_write: 1024 * 1024,
+            read_ahead: 8,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct IoCacheStats {
+    pub read_hits: u64,
+    pub read_misses: u64,
+    pub blocks_written: u64,
+    // Writes actually issued, after coalescing
+    pub write_runs: u64,
+    pub read_ahead_blocks: u64,
+    pub evicted_blocks: u64,
+}
+
+impl IoCacheStats {
+    // Blocks per write issued, 1.0 meaning no coalescing happened
+    pub fn coalescing(&self) -> f64 {
+        match self.write_runs {
+            0 => 0.0,
+            runs => self.blocks_written as f64 / runs as f64,
+        }
+    }
+}
+
+// For totals over several caches
+impl AddAssign for IoCacheStats {
+    fn add_assign(&mut self, other: IoCacheStats) {
+        self.read_hits += other.read_hits;
+        self.read_misses += other.read_misses;
+        self.blocks_written += other.blocks_written;
+        self.write_runs += other.write_runs;
+        self.read_ahead_blocks += other.read_ahead_blocks;
+        self.evicted_blocks += other.evicted_blocks;
+    }
+}
+
+// Adjacent blocks to write in one go. `offset` is within `piece`, the data may run on into the
+// pieces after it, which `Storage::write` handles
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct WriteRun {
+    pub piece: u32,
+    pub offset: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Dirty {
+    data: Arc<[u8]>,
+    since: Instant,
+}
+
+#[derive(Debug)]
+pub struct IoCache<P> {
+    config: IoCacheConfig,
+    piece_length: u32,
+    total_length: u64,
+    // By position in the torrent, so adjacent blocks sit next to each other
+    dirty: BTreeMap<u64, Dirty>,
+    dirty_bytes: usize,
+    // Position -> data and when it was last used
+    clean: HashMap<u64, (Arc<[u8]>, u64)>,
+    // Last use -> position, oldest first
+    lru: BTreeMap<u64, u64>,
+    clock: u64,
+    clean_bytes: usize,
+    // Where each peer's next request would be if it keeps reading in order
+    next_read: HashMap<P, u64>,
+    stats: IoCacheStats,
+}
+
+impl<P: Eq + Hash> IoCache<P> {
+    pub fn new(config: IoCacheConfig, piece_length: u32, total_length: u64) -> Self {
+        IoCache {
+            config,
+            piece_length,
+            total_length,
+            dirty: BTreeMap::new(),
+            dirty_bytes: 0,
+            clean: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            clean_bytes: 0,
+            next_read: HashMap::new(),
+            stats: IoCacheStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> IoCacheStats {
+        self.stats
+    }
+
+    pub fn dirty_bytes(&self) -> usize {
+        self.dirty_bytes
+    }
+
+    pub fn clean_bytes(&self) -> usize {
+        self.clean_bytes
+    }
+
+    // A block we downloaded. It goes to disk with the next `flush` that finds it due
+    pub fn write(&mut self, piece: u32, offset: u32, data: Arc<[u8]>, now: Instant) {
+        let pos = self.position(piece, offset);
+        self.remove_clean(pos);
+        self.dirty_bytes += data.len();
+        if let Some(old) = self.dirty.insert(pos, Dirty { data, since: now }) {
+            self.dirty_bytes -= old.data.len();
+        }
+    }
+
+    // Runs that are due: any that's reached `max_write`, any with a block older than
+    // `flush_after`, or all of them once there's more than `max_dirty` waiting
+    pub fn flush(&mut self, now: Instant) -> Vec<WriteRun> {
+        let everything = self.dirty_bytes > self.config.max_dirty;
+        let due: Vec<Vec<u64>> = self
+            .runs()
+            .into_iter()
+            .filter(|run| {
+                let len: usize = run.iter().map(|pos| self.dirty[pos].data.len()).sum();
+                everything
+                    || len >= self.config.max_write
+                    || run.iter().any(|pos| {
+                        now.saturating_duration_since(self.dirty[pos].since)
+                            >= self.config.flush_after
+                    })
+            })
+            .collect();
+        due.into_iter().map(|run| self.take_run(&run)).collect()
+    }
+
+    // Everything, due or not, e.g. before saving resume data or shutting down
+    pub fn flush_all(&mut self) -> Vec<WriteRun> {
+        self.runs()
+            .into_iter()
+            .map(|run| self.take_run(&run))
+            .collect()
+    }
+
+    // One piece's blocks, due or not: a piece that's complete goes to disk before it's hashed,
+    // since the hash check reads back whatever its own cache lost
+    pub fn flush_piece(&mut self, piece: u32) -> Vec<WriteRun> {
+        let start = self.position(piece, 0);
+        let end = start + self.piece_length as u64;
+        let runs: Vec<Vec<u64>> = self
+            .runs()
+            .into_iter()
+            .map(|run| {
+                run.into_iter()
+                    .filter(|pos| (start..end).contains(pos))
+                    .collect()
+            })
+            .filter(|run: &Vec<u64>| !run.is_empty())
+            .collect();
+        runs.into_iter().map(|run| self.take_run(&run)).collect()
+    }
+
+    // When the oldest dirty block will be due
+    pub fn next_flush(&self) -> Option<Instant> {
+        self.dirty
+            .values()
+            .map(|d| d.since + self.config.flush_after)
+            .min()
+    }
+
+    // A block from the cache, if it's there. Misses are read from disk by the caller, who can
+    // hand the data back with `insert`. Entries keep the length they came in with, which needn't
+    // be what a peer is asking for now
+    pub fn get(&mut self, piece: u32, offset: u32) -> Option<Arc<[u8]>> {
+        let pos = self.position(piece, offset);
+        if let Some(dirty) = self.dirty.get(&pos) {
+            self.stats.read_hits += 1;
+            return Some(Arc::clone(&dirty.data));
+        }
+        let Some((data, used)) = self.clean.get_mut(&pos) else {
+            self.stats.read_misses += 1;
+            return None;
+        };
+        self.lru.remove(used);
+        self.clock += 1;
+        *used = self.clock;
+        self.lru.insert(self.clock, pos);
+        self.stats.read_hits += 1;
+        Some(Arc::clone(data))
+    }
+
+    // A block read from disk, on a miss or for read-ahead
+    pub fn insert(&mut self, piece: u32, offset: u32, data: Arc<[u8]>) {
+        let pos = self.position(piece, offset);
+        if !self.dirty.contains_key(&pos) {
+            self.insert_clean(pos, data);
+        }
+    }
+
+    // Called for each request a peer sends. Once it has asked for two blocks in a row, returns
+    // the blocks after this one to read ahead (minus what's cached already), the same size as the
+    // ones it's asking for so they're what `get` will be asked for next
+    pub fn on_request(&mut self, peer: P, block: &Block) -> Vec<Block> {
+        let pos = self.position(block.piece, block.offset);
+        let end = pos + block.length as u64;
+        let sequential = self.next_read.insert(peer, end) == Some(pos);
+        if !sequential {
+            return vec![];
+        }
+
+        let mut ahead = vec![];
+        let mut at = end;
+        for _ in 0..self.config.read_ahead {
+            if at >= self.total_length {
+                break;
+            }
+            let piece = (at / self.piece_length as u64) as u32;
+            let offset = (at % self.piece_length as u64) as u32;
+            let piece_end = (piece as u64 + 1) * self.piece_length as u64;
+            let length = (block.length as u64).min(piece_end.min(self.total_length) - at) as u32;
+            if !self.dirty.contains_key(&at) && !self.clean.contains_key(&at) {
+                ahead.push(Block::new(piece, offset, length));
+            }
+            at += length as u64;
+        }
+        self.stats.read_ahead_blocks += ahead.len() as u64;
+        ahead
+    }
+
+    pub fn remove_peer(&mut self, peer: &P) {
+        self.next_read.remove(peer);
+    }
+
+    fn position(&self, piece: u32, offset: u32) -> u64 {
+        piece as u64 * self.piece_length as u64 + offset as u64
+    }
+
+    // Dirty positions grouped into contiguous runs no longer than `max_write`
+    fn runs(&self) -> Vec<Vec<u64>> {
+        let mut runs: Vec<Vec<u64>> = vec![];
+        let mut run_end = 0;
+        let mut run_len = 0;
+        for (pos, dirty) in &self.dirty {
+            let len = dirty.data.len();
+            match runs.last_mut() {
+                Some(run) if *pos == run_end && run_len + len <= self.config.max_write => {
+                    run.push(*pos);
+                    run_len += len;
+                }
+                _ => {
+                    runs.push(vec![*pos]);
+                    run_len = len;
+                }
+            }
+            run_end = pos + len as u64;
+        }
+        runs
+    }
+
+    fn take_run(&mut self, run: &[u64]) -> WriteRun {
+        let start = run[0];
+        let mut data = vec![];
+        for pos in run {
+            let dirty = self.dirty.remove(pos).unwrap();
+            self.dirty_bytes -= dirty.data.len();
+            data.extend_from_slice(&dirty.data);
+            self.insert_clean(*pos, dirty.data);
+        }
+        self.stats.blocks_written += run.len() as u64;
+        self.stats.write_runs += 1;
+        WriteRun {
+            piece: (start / self.piece_length as u64) as u32,
+            offset: (start % self.piece_length as u64) as u32,
+            data,
+        }
+    }
+
+    fn insert_clean(&mut self, pos: u64, data: Arc<[u8]>) {
+        self.remove_clean(pos);
+        self.clock += 1;
+        self.clean_bytes += data.len();
+        self.clean.insert(pos, (data, self.clock));
+        self.lru.insert(self.clock, pos);
+        while self.clean_bytes > self.config.capacity {
+            let Some((_, oldest)) = self.lru.pop_first() else {
+                break;
+            };
+            let (data, _) = self.clean.remove(&oldest).unwrap();
+            self.clean_bytes -= data.len();
+            self.stats.evicted_blocks += 1;
+        }
+    }
+
+    fn remove_clean(&mut self, pos: u64) {
+        if let Some((data, used)) = self.clean.remove(&pos) {
+            self.lru.remove(&used);
+            self.clean_bytes -= data.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::peer::BLOCK_SIZE;
+
+    const PIECE: u32 = 4 * BLOCK_SIZE;
+
+    fn block(byte: u8) -> Arc<[u8]> {
+        vec![byte; BLOCK_SIZE as usize].into()
+    }
+
+    fn cache(config: IoCacheConfig) -> IoCache<u8> {
+        IoCache::new(config, PIECE, 4 * PIECE as u64)
+    }
+
+    #[test]
+    fn test_coalesces_adjacent_writes() {
+        let now = Instant::now();
+        let mut cache = cache(IoCacheConfig::default());
+        // Out of order, across a piece boundary, with a gap
+        cache.write(1, 0, block(4), now);
+        cache.write(0, 3 * BLOCK_SIZE, block(3), now);
+        cache.write(0, 2 * BLOCK_SIZE, block(2), now);
+        cache.write(1, 2 * BLOCK_SIZE, block(6), now);
+
+        assert!(cache.flush(now).is_empty());
+        assert_eq!(cache.next_flush(), Some(now + Duration::from_secs(2)));
+        let runs = cache.flush(now + Duration::from_secs(2));
+        assert_eq!(runs.len(), 2);
+        assert_eq!((runs[0].piece, runs[0].offset), (0, 2 * BLOCK_SIZE));
+        assert_eq!(runs[0].data.len(), 3 * BLOCK_SIZE as usize);
+        assert_eq!(runs[0].data[2 * BLOCK_SIZE as usize], 4);
+        assert_eq!((runs[1].piece, runs[1].offset), (1, 2 * BLOCK_SIZE));
+        assert_eq!(cache.stats().coalescing(), 2.0);
+        assert_eq!(cache.dirty_bytes(), 0);
+
+        // Still there to upload from
+        assert_eq!(cache.get(0, 3 * BLOCK_SIZE).unwrap()[0], 3);
+        assert_eq!(cache.get(0, 0), None);
+        assert_eq!(cache.stats().read_hits, 1);
+        assert_eq!(cache.stats().read_misses, 1);
+    }
+
+    #[test]
+    fn test_flushes_early() {
+        let now = Instant::now();
+        let mut cache = cache(IoCacheConfig {
+            max_write: 2 * BLOCK_SIZE as usize,
+            max_dirty: 3 * BLOCK_SIZE as usize,
+            ..IoCacheConfig::default()
+        });
+        cache.write(0, 0, block(0), now);
+        cache.write(0, BLOCK_SIZE, block(1), now);
+        cache.write(2, 0, block(2), now);
+
+        // The first run is as long as a write gets, the other one can wait
+        let runs = cache.flush(now);
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].offset, 0);
+
+        cache.write(3, 0, block(3), now);
+        cache.write(3, 2 * BLOCK_SIZE, block(3), now);
+        cache.write(3, 3 * BLOCK_SIZE, block(3), now);
+        assert_eq!(cache.flush(now).len(), 3);
+        assert!(cache.flush_all().is_empty());
+    }
+
+    #[test]
+    fn test_flushes_one_piece() {
+        let now = Instant::now();
+        let mut cache = cache(IoCacheConfig::default());
+        cache.write(0, 3 * BLOCK_SIZE, block(3), now);
+        cache.write(1, 0, block(4), now);
+        cache.write(1, BLOCK_SIZE, block(5), now);
+        cache.write(2, 0, block(8), now);
+
+        // Cut off where the piece ends, even though the run goes on
+        let runs = cache.flush_piece(1);
+        assert_eq!(runs.len(), 1);
+        assert_eq!((runs[0].piece, runs[0].offset), (1, 0));
+        assert_eq!(runs[0].data.len(), 2 * BLOCK_SIZE as usize);
+        assert!(cache.flush_piece(1).is_empty());
+        assert_eq!(cache.dirty_bytes(), 2 * BLOCK_SIZE as usize);
+        assert_eq!(cache.flush_all().len(), 2);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut cache = cache(IoCacheConfig {
+            capacity: 2 * BLOCK_SIZE as usize,
+            ..IoCacheConfig::default()
+        });
+        cache.insert(0, 0, block(0));
+        cache.insert(0, BLOCK_SIZE, block(1));
+        cache.get(0, 0);
+        cache.insert(0, 2 * BLOCK_SIZE, block(2));
+
+        assert!(cache.get(0, 0).is_some());
+        assert!(cache.get(0, BLOCK_SIZE).is_none());
+        assert_eq!(cache.clean_bytes(), 2 * BLOCK_SIZE as usize);
+        assert_eq!(cache.stats().evicted_blocks, 1);
+    }
+
+    #[test]
+    fn test_read_ahead_for_sequential_peer() {
+        let mut cache = cache(IoCacheConfig {
+            read_ahead: 3,
+            ..IoCacheConfig::default()
+        });
+        cache.insert(1, 0, block(4));
+
+        assert!(
+            cache
+                .on_request(1, &Block::new(0, 2 * BLOCK_SIZE, BLOCK_SIZE))
+                .is_empty()
+        );
+        // In order now, and what's already cached is skipped
+        let ahead = cache.on_request(1, &Block::new(0, 3 * BLOCK_SIZE, BLOCK_SIZE));
+        assert_eq!(
+            ahead,
+            vec![
+                Block::new(1, BLOCK_SIZE, BLOCK_SIZE),
+                Block::new(1, 2 * BLOCK_SIZE, BLOCK_SIZE),
+            ]
+        );
+        // A random reader gets nothing
+        assert!(
+            cache
+                .on_request(2, &Block::new(3, 0, BLOCK_SIZE))
+                .is_empty()
+        );
+        assert!(
+            cache
+                .on_request(2, &Block::new(1, 0, BLOCK_SIZE))
+                .is_empty()
+        );
+        // Nothing past the end of the torrent
+        cache.on_request(3, &Block::new(3, 2 * BLOCK_SIZE, BLOCK_SIZE));
+        let ahead = cache.on_request(3, &Block::new(3, 3 * BLOCK_SIZE, BLOCK_SIZE));
+        assert!(ahead.is_empty());
+    }
+}
@@ -0,0 +1,256 @@
+// Non-blocking block I/O for the peer layer. A `request` from a peer becomes a read, a `piece` we
+// received becomes a write; both run on the scheduler's threads and come back through `poll`, so
+// a connection loop never sits waiting on the disk. Each job carries a tag (usually which peer it
+// was for) that is handed back with the result.
+use std::io;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+
+use super::queue::{DiskScheduler, IoClass};
+use super::storage::Storage;
+use crate::peer::Block;
+
+#[derive(PartialEq, Debug)]
+pub enum Completion<T> {
+    Read {
+        tag: T,
+        block: Block,
+        data: Result<Vec<u8>, io::ErrorKind>,
+    },
+    Written {
+        tag: T,
+        block: Block,
+        result: Result<(), io::ErrorKind>,
+    },
+}
+
+// A job that came in while paused
+enum Held<T> {
+    Read(T, Block, IoClass),
+    Write(T, u32, u32, Vec<u8>),
+}
+
+pub struct DiskIo<T> {
+    storage: Arc<Storage>,
+    scheduler: Arc<DiskScheduler>,
+    done_tx: Sender<Completion<T>>,
+    done_rx: Receiver<Completion<T>>,
+    in_flight: usize,
+    // Some while paused
+    held: Option<Vec<Held<T>>>,
+}
+
+impl<T: Send + 'static> DiskIo<T> {
+    // The scheduler is usually shared by every torrent in the session
+    pub fn new(storage: Arc<Storage>, scheduler: Arc<DiskScheduler>) -> Self {
+        let (done_tx, done_rx) = mpsc::channel();
+        DiskIo {
+            storage,
+            scheduler,
+            done_tx,
+            done_rx,
+            in_flight: 0,
+            held: None,
+        }
+    }
+
+    pub fn storage(&self) -> &Arc<Storage> {
+        &self.storage
+    }
+
+    // Jobs submitted but not yet handed back by `poll`. Held jobs don't count
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    // Holds new jobs back instead of running them, e.g. while the torrent's files are moved.
+    // Jobs already submitted still finish; once `wait` runs out of them nothing touches the files
+    pub fn pause(&mut self) {
+        self.held.get_or_insert_with(Vec::new);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.held.is_some()
+    }
+
+    // Where jobs from now on go, after the files moved. Best done while paused
+    pub fn set_storage(&mut self, storage: Arc<Storage>) {
+        self.storage = storage;
+    }
+
+    // Submits everything held since `pause`, in the order it came in
+    pub fn resume(&mut self) {
+        for job in self.held.take().unwrap_or_default() {
+            match job {
+                Held::Read(tag, block, class) => self.read(tag, block, class),
+                Held::Write(tag, piece, offset, data) => self.write(tag, piece, offset, data),
+            }
+        }
+    }
+
+    // Serving a peer's `request`. Seeding reads usually go in as `IoClass::Bulk`
+    pub fn read(&mut self, tag: T, block: Block, class: IoClass) {
+        if let Some(held) = &mut self.held {
+            held.push(Held::Read(tag, block, class));
+            return;
+        }
+        let storage = Arc::clone(&self.storage);
+        let done = self.done_tx.clone();
+        self.in_flight += 1;
+        self.scheduler.submit(class, move || {
+            let data = storage
+                .read(block.piece, block.offset, block.length)
+                .map_err(|e| e.kind());
+            let _ = done.send(Completion::Read { tag, block, data });
+        });
+    }
+
+    // Storing a block from a `piece` message
+    pub fn write(&mut self, tag: T, piece: u32, offset: u32, data: Vec<u8>) {
+        if let Some(held) = &mut self.held {
+            held.push(Held::Write(tag, piece, offset, data));
+            return;
+        }
+        let storage = Arc::clone(&self.storage);
+        let done = self.done_tx.clone();
+        let block = Block::new(piece, offset, data.len() as u32);
+        self.in_flight += 1;
+        self.scheduler.submit(IoClass::Normal, move || {
+            let result = storage.write(piece, offset, &data).map_err(|e| e.kind());
+            let _ = done.send(Completion::Written { tag, block, result });
+        });
+    }
+
+    // The next finished job, if there is one
+    pub fn poll(&mut self) -> Option<Completion<T>> {
+        let completion = self.done_rx.try_recv().ok()?;
+        self.in_flight -= 1;
+        Some(completion)
+    }
+
+    // Like `poll`, but waits up to `timeout` for a job to finish
+    pub fn wait(&mut self, timeout: Duration) -> Option<Completion<T>> {
+        if self.in_flight == 0 {
+            return None;
+        }
+        let completion = self.done_rx.recv_timeout(timeout).ok()?;
+        self.in_flight -= 1;
+        Some(completion)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::metainfo::{FileEntry, Info};
+    use sha1::{Digest, Sha1};
+    use std::fs;
+
+    fn disk_io(dir: &std::path::Path, data: &[u8]) -> DiskIo<u32> {
+        DiskIo::new(
+            Arc::new(Storage::new(&info(data), dir)),
+            Arc::new(DiskScheduler::new(2)),
+        )
+    }
+
+    fn info(data: &[u8]) -> Info {
+        Info {
+            name: "t".to_string(),
+            piece_length: 16,
+            pieces: data.chunks(16).map(|p| Sha1::digest(p).into()).collect(),
+            files: vec![
+                FileEntry {
+                    path: vec!["a.bin".to_string()],
+                    length: 10,
+                    pieces_root: None,
+                },
+                FileEntry {
+                    path: vec!["b.bin".to_string()],
+                    length: data.len() as u64 - 10,
+                    pieces_root: None,
+                },
+            ],
+            private: false,
+        }
+    }
+
+    #[test]
+    fn test_write_then_read() {
+        let dir = std::env::temp_dir().join(format!("hurricane-jobs-{}", std::process::id()));
+        let data: Vec<u8> = (0..32).collect();
+        let mut disk = disk_io(&dir, &data);
+
+        disk.write(1, 0, 0, data[..16].to_vec());
+        disk.write(2, 1, 0, data[16..].to_vec());
+        let mut written = vec![];
+        while let Some(completion) = disk.wait(Duration::from_secs(5)) {
+            let Completion::Written { tag, result, .. } = completion else {
+                panic!("unexpected read");
+            };
+            assert_eq!(result, Ok(()));
+            written.push(tag);
+        }
+        written.sort();
+        assert_eq!(written, vec![1, 2]);
+
+        disk.read(3, Block::new(0, 8, 4), IoClass::Bulk);
+        assert_eq!(
+            disk.wait(Duration::from_secs(5)),
+            Some(Completion::Read {
+                tag: 3,
+                block: Block::new(0, 8, 4),
+                data: Ok(vec![8, 9, 10, 11]),
+            })
+        );
+        assert_eq!(disk.in_flight(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_missing() {
+        let dir = std::env::temp_dir().join(format!("hurricane-jobs-none-{}", std::process::id()));
+        let mut disk = disk_io(&dir, &[0; 32]);
+
+        disk.read(7, Block::new(1, 0, 16), IoClass::Streaming);
+        let Some(Completion::Read { tag, data, .. }) = disk.wait(Duration::from_secs(5)) else {
+            panic!("no read");
+        };
+        assert_eq!(tag, 7);
+        assert_eq!(data, Err(io::ErrorKind::NotFound));
+        assert_eq!(disk.poll(), None);
+    }
+
+    #[test]
+    fn test_pause_and_retarget() {
+        let dir = std::env::temp_dir().join(format!("hurricane-jobs-move-{}", std::process::id()));
+        let data: Vec<u8> = (0..32).collect();
+        let mut disk = disk_io(&dir.join("a"), &data);
+
+        disk.pause();
+        disk.write(1, 0, 0, data[..16].to_vec());
+        disk.read(2, Block::new(0, 0, 4), IoClass::Normal);
+        assert_eq!(disk.in_flight(), 0);
+        assert_eq!(disk.wait(Duration::from_secs(5)), None);
+
+        // The files moved while paused, so the held jobs go to the new place
+        disk.set_storage(Arc::new(Storage::new(&info(&data), &dir.join("b"))));
+        disk.resume();
+        assert!(!disk.is_paused());
+        let mut tags = vec![];
+        while let Some(completion) = disk.wait(Duration::from_secs(5)) {
+            match completion {
+                Completion::Written { tag, result, .. } => {
+                    assert_eq!(result, Ok(()));
+                    tags.push(tag);
+                }
+                Completion::Read { tag, .. } => tags.push(tag),
+            }
+        }
+        tags.sort();
+        assert_eq!(tags, vec![1, 2]);
+        assert!(dir.join("b/b.bin").exists());
+        assert!(!dir.join("a").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+// Memory-mapped file access for `Storage`'s mmap backend. Reads and writes become copies in and
+// out of the page cache with no syscall per block, which is what a seed serving lots of small
+// random reads off fast disks spends most of its time on. Only built with the `mmap` feature on
+// 64-bit unix; anywhere else, and for any file that fails to map, `Storage` quietly does
+// positioned I/O instead.
+// A file is mapped read-only the first time it's read, provided it's already full size, and
+// read-write the first time it's written, which grows it to full size first (sparse on most
+// filesystems). A mapped file truncated behind our back makes the next access to the lost pages
+// crash the process with SIGBUS, the usual price of mmap.
+use std::fs::File;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone)]
+enum Slot {
+    Unmapped,
+    Mapped(Arc<sys::Mapping>),
+    // Mapping it failed once, so it's positioned I/O from now on
+    Failed,
+}
+
+#[derive(Debug)]
+pub struct MappedFiles {
+    files: Mutex<Vec<Slot>>,
+}
+
+impl MappedFiles {
+    // None where mapping isn't supported at all
+    pub fn new(num_files: usize) -> Option<Self> {
+        sys::SUPPORTED.then(|| MappedFiles {
+            files: Mutex::new(vec![Slot::Unmapped; num_files]),
+        })
+    }
+
+    // Copies from the mapping of `file`, mapping it first if needed. Ok(false) means the caller
+    // should read it the usual way
+    pub fn read(
+        &self,
+        file: usize,
+        open: impl FnOnce() -> io::Result<File>,
+        length: u64,
+        buf: &mut [u8],
+        offset: u64,
+    ) -> io::Result<bool> {
+        let Some(mapping) = self.mapping(file, open, length, false) else {
+            return Ok(false);
+        };
+        mapping.read(buf, offset)?;
+        Ok(true)
+    }
+
+    // Like `read`, but `open` must give a writable handle
+    pub fn write(
+        &self,
+        file: usize,
+        open: impl FnOnce() -> io::Result<File>,
+        length: u64,
+        data: &[u8],
+        offset: u64,
+    ) -> io::Result<bool> {
+        let Some(mapping) = self.mapping(file, open, length, true) else {
+            return Ok(false);
+        };
+        mapping.write(data, offset)?;
+        Ok(true)
+    }
+
+    // Has the kernel write back every dirty page, for when data must survive a crash (before
+    // saving resume data, say). It writes them back on its own eventually anyway
+    pub fn flush(&self) -> io::Result<()> {
+        let files = self.files.lock().unwrap_or_else(|err| err.into_inner());
+        for slot in files.iter() {
+            if let Slot::Mapped(mapping) = slot {
+                mapping.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_mapped(&self, file: usize) -> bool {
+        let files = self.files.lock().unwrap_or_else(|err| err.into_inner());
+        matches!(files[file], Slot::Mapped(_))
+    }
+
+    // Errors opening the file aren't mapping failures: a file that isn't there yet (or is still
+    // short, for reads) may well map fine later
+    fn mapping(
+        &self,
+        file: usize,
+        open: impl FnOnce() -> io::Result<File>,
+        length: u64,
+        writable: bool,
+    ) -> Option<Arc<sys::Mapping>> {
+        let mut files = self.files.lock().unwrap_or_else(|err| err.into_inner());
+        match &files[file] {
+            Slot::Failed => return None,
+            Slot::Mapped(mapping) if mapping.writable() || !writable => {
+                return Some(mapping.clone());
+            }
+            // Mapped read-only and now written to: swap in a writable mapping. Readers still
+            // holding the old one see the same pages, both being shared mappings
+            _ => {}
+        }
+
+        let handle = open().ok()?;
+        let size = handle.metadata().ok()?.len();
+        if size < length {
+            if !writable {
+                return None;
+            }
+            if handle.set_len(length).is_err() {
+                files[file] = Slot::Failed;
+                return None;
+            }
+        }
+        match sys::Mapping::new(&handle, length, writable) {
+            Ok(mapping) => {
+                let mapping = Arc::new(mapping);
+                files[file] = Slot::Mapped(mapping.clone());
+                Some(mapping)
+            }
+            Err(_) => {
+                files[file] = Slot::Failed;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(all(feature = "mmap", unix, target_pointer_width = "64"))]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+
+    pub const SUPPORTED: bool = true;
+
+    #[derive(Debug)]
+    pub struct Mapping {
+        ptr: *mut u8,
+        len: usize,
+        writable: bool,
+    }
+
+    // The pointer is only used for copies in and out, which any thread may do. Two threads
+    // writing the same bytes at once race, same as two pwrites would
+    unsafe impl Send for Mapping {}
+    unsafe impl Sync for Mapping {}
+
+    impl Mapping {
+        pub fn new(file: &File, len: u64, writable: bool) -> io::Result<Self> {
+            let len =
+                usize::try_from(len).map_err(|_| io::Error::from(io::ErrorKind::Unsupported))?;
+            let prot = if writable {
+                libc::PROT_READ | libc::PROT_WRITE
+            } else {
+                libc::PROT_READ
+            };
+            // SAFETY: a fresh mapping of an open file, we don't touch any existing memory
+            let ptr = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    len,
+                    prot,
+                    libc::MAP_SHARED,
+                    file.as_raw_fd(),
+                    0,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Mapping {
+                ptr: ptr.cast(),
+                len,
+                writable,
+            })
+        }
+
+        pub fn writable(&self) -> bool {
+            self.writable
+        }
+
+        pub fn read(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            let start = self.check(offset, buf.len())?;
+            // SAFETY: in bounds per `check`, and `buf` can't overlap a mapping we made
+            unsafe { ptr::copy_nonoverlapping(self.ptr.add(start), buf.as_mut_ptr(), buf.len()) };
+            Ok(())
+        }
+
+        pub fn write(&self, data: &[u8], offset: u64) -> io::Result<()> {
+            if !self.writable {
+                return Err(io::ErrorKind::PermissionDenied.into());
+            }
+            let start = self.check(offset, data.len())?;
+            // SAFETY: as in `read`, and the pages are mapped writable
+            unsafe { ptr::copy_nonoverlapping(data.as_ptr(), self.ptr.add(start), data.len()) };
+            Ok(())
+        }
+
+        pub fn flush(&self) -> io::Result<()> {
+            if !self.writable {
+                return Ok(());
+            }
+            // SAFETY: exactly the range we mapped
+            if unsafe { libc::msync(self.ptr.cast(), self.len, libc::MS_SYNC) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        fn check(&self, offset: u64, len: usize) -> io::Result<usize> {
+            match usize::try_from(offset) {
+                Ok(start) if start.checked_add(len).is_some_and(|end| end <= self.len) => Ok(start),
+                _ => Err(io::ErrorKind::UnexpectedEof.into()),
+            }
+        }
+    }
+
+    impl Drop for Mapping {
+        fn drop(&mut self) {
+            // SAFETY: exactly the range we mapped, and nothing borrows it past `read`/`write`
+            unsafe { libc::munmap(self.ptr.cast(), self.len) };
+        }
+    }
+}
+
+#[cfg(not(all(feature = "mmap", unix, target_pointer_width = "64")))]
+mod sys {
+    use std::convert::Infallible;
+    use std::fs::File;
+    use std::io;
+
+    pub const SUPPORTED: bool = false;
+
+    #[derive(Debug)]
+    pub struct Mapping(Infallible);
+
+    impl Mapping {
+        pub fn new(_file: &File, _len: u64, _writable: bool) -> io::Result<Self> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+
+        pub fn writable(&self) -> bool {
+            match self.0 {}
+        }
+
+        pub fn read(&self, _buf: &mut [u8], _offset: u64) -> io::Result<()> {
+            match self.0 {}
+        }
+
+        pub fn write(&self, _data: &[u8], _offset: u64) -> io::Result<()> {
+            match self.0 {}
+        }
+
+        pub fn flush(&self) -> io::Result<()> {
+            match self.0 {}
+        }
+    }
+}
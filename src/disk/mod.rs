@@ -0,0 +1,20 @@
+pub mod cache;
+pub mod io_cache;
+pub mod jobs;
+pub mod mmap;
+pub mod queue;
+pub mod relocate;
+pub mod resume;
+pub mod storage;
+pub mod template;
+pub mod verify;
+
+pub use cache::{BlockCache, PieceBuffer, VerifyStats};
+pub use io_cache::{IoCache, IoCacheConfig, IoCacheStats, WriteRun};
+pub use jobs::{Completion, DiskIo};
+pub use queue::{DiskScheduler, IoClass};
+pub use relocate::{MoveProgress, Relocation};
+pub use resume::{FileStamp, ResumeData, Validated, recover};
+pub use storage::{Allocation, Backend, FileSlice, PieceOnDisk, Storage};
+pub use template::SavePathTemplate;
+pub use verify::{Verified, Verifier};
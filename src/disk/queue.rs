@@ -0,0 +1,243 @@
+// Disk job scheduling.
+// Every disk operation is tagged with a class. Someone watching a video through the streaming API
+// notices a 200ms stall, a peer waiting on a seeding read does not, so latency-sensitive jobs jump
+// the queue. Bulk jobs still get a turn every so often so heavy streaming can't starve seeding.
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub enum IoClass {
+    // Reads backing a stream that someone is actively consuming
+    Streaming,
+    // Writes of downloaded blocks and hash checks
+    Normal,
+    // Reads to serve peers while seeding
+    Bulk,
+}
+
+const CLASSES: [IoClass; 3] = [IoClass::Streaming, IoClass::Normal, IoClass::Bulk];
+
+// After this many jobs jump ahead of a waiting lower class job, that job gets to go
+pub const DEFAULT_STARVATION_LIMIT: usize = 16;
+
+#[derive(Debug)]
+pub struct DiskQueue<T> {
+    queues: [VecDeque<T>; 3],
+    // How many times each class has been passed over while it had work waiting
+    skipped: [usize; 3],
+    starvation_limit: usize,
+    served: [u64; 3],
+}
+
+impl<T> DiskQueue<T> {
+    pub fn new(starvation_limit: usize) -> Self {
+        DiskQueue {
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            skipped: [0; 3],
+            starvation_limit,
+            served: [0; 3],
+        }
+    }
+
+    pub fn push(&mut self, class: IoClass, job: T) {
+        self.queues[class as usize].push_back(job);
+    }
+
+    pub fn pop(&mut self) -> Option<(IoClass, T)> {
+        // Anyone who has waited long enough goes first, lowest class first since it's the one
+        // most likely to be starving
+        let class = CLASSES
+            .iter()
+            .rev()
+            .find(|c| {
+                !self.queues[**c as usize].is_empty()
+                    && self.skipped[**c as usize] >= self.starvation_limit
+            })
+            .or_else(|| {
+                CLASSES
+                    .iter()
+                    .find(|c| !self.queues[**c as usize].is_empty())
+            })
+            .copied()?;
+
+        for other in CLASSES {
+            if other == class {
+                self.skipped[other as usize] = 0;
+            } else if !self.queues[other as usize].is_empty() {
+                self.skipped[other as usize] += 1;
+            }
+        }
+
+        self.served[class as usize] += 1;
+        let job = self.queues[class as usize].pop_front().unwrap();
+        Some((class, job))
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(|q| q.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn queued(&self, class: IoClass) -> usize {
+        self.queues[class as usize].len()
+    }
+
+    // Total jobs handed out for a class
+    pub fn served(&self, class: IoClass) -> u64 {
+        self.served[class as usize]
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct Shared {
+    queue: Mutex<(DiskQueue<Job>, bool)>,
+    ready: Condvar,
+}
+
+// A small pool of threads that run blocking disk jobs in class order
+pub struct DiskScheduler {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl DiskScheduler {
+    pub fn new(threads: usize) -> Self {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new((DiskQueue::new(DEFAULT_STARVATION_LIMIT), false)),
+            ready: Condvar::new(),
+        });
+
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let shared = Arc::clone(&shared);
+                thread::spawn(move || worker(&shared))
+            })
+            .collect();
+
+        DiskScheduler { shared, workers }
+    }
+
+    pub fn submit<F>(&self, class: IoClass, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut guard = self.shared.queue.lock().unwrap();
+        guard.0.push(class, Box::new(job));
+        self.shared.ready.notify_one();
+    }
+
+    pub fn queued(&self, class: IoClass) -> usize {
+        self.shared.queue.lock().unwrap().0.queued(class)
+    }
+
+    pub fn served(&self, class: IoClass) -> u64 {
+        self.shared.queue.lock().unwrap().0.served(class)
+    }
+
+    // Runs whatever is still queued, then stops the workers
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        self.shared.queue.lock().unwrap().1 = true;
+        self.shared.ready.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for DiskScheduler {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn worker(shared: &Shared) {
+    loop {
+        let job = {
+            let mut guard = shared.queue.lock().unwrap();
+            loop {
+                if let Some((_, job)) = guard.0.pop() {
+                    break job;
+                }
+                if guard.1 {
+                    return;
+                }
+                guard = shared.ready.wait(guard).unwrap();
+            }
+        };
+        job();
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_queue_priority_order() {
+        let mut queue = DiskQueue::new(DEFAULT_STARVATION_LIMIT);
+        queue.push(IoClass::Bulk, 1);
+        queue.push(IoClass::Normal, 2);
+        queue.push(IoClass::Streaming, 3);
+
+        assert_eq!(queue.pop(), Some((IoClass::Streaming, 3)));
+        assert_eq!(queue.pop(), Some((IoClass::Normal, 2)));
+        assert_eq!(queue.pop(), Some((IoClass::Bulk, 1)));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn test_queue_fifo_within_class() {
+        let mut queue = DiskQueue::new(DEFAULT_STARVATION_LIMIT);
+        queue.push(IoClass::Bulk, 1);
+        queue.push(IoClass::Bulk, 2);
+
+        assert_eq!(queue.pop(), Some((IoClass::Bulk, 1)));
+        assert_eq!(queue.pop(), Some((IoClass::Bulk, 2)));
+    }
+
+    #[test]
+    fn test_queue_starvation_guard() {
+        let mut queue = DiskQueue::new(2);
+        queue.push(IoClass::Bulk, 0);
+        for i in 1..=5 {
+            queue.push(IoClass::Streaming, i);
+        }
+
+        let order: Vec<i32> = std::iter::from_fn(|| queue.pop().map(|(_, j)| j)).collect();
+
+        assert_eq!(order, vec![1, 2, 0, 3, 4, 5]);
+        assert_eq!(queue.served(IoClass::Streaming), 5);
+        assert_eq!(queue.served(IoClass::Bulk), 1);
+    }
+
+    #[test]
+    fn test_scheduler_runs_streaming_first() {
+        let scheduler = DiskScheduler::new(1);
+        let (gate_tx, gate_rx) = mpsc::channel::<()>();
+        let (tx, rx) = mpsc::channel();
+
+        // Park the only worker so the queue fills up behind it
+        scheduler.submit(IoClass::Normal, move || gate_rx.recv().unwrap());
+        while scheduler.queued(IoClass::Normal) > 0 {
+            thread::yield_now();
+        }
+
+        let bulk_tx = tx.clone();
+        scheduler.submit(IoClass::Bulk, move || bulk_tx.send("bulk").unwrap());
+        scheduler.submit(IoClass::Streaming, move || tx.send("stream").unwrap());
+        gate_tx.send(()).unwrap();
+        scheduler.shutdown();
+
+        assert_eq!(rx.iter().collect::<Vec<_>>(), vec!["stream", "bulk"]);
+    }
+}
@@ -0,0 +1,212 @@
+// Moving a torrent's files to a new save path. Within a filesystem that's a rename per file;
+// across filesystems each file is copied and the original removed once the copy is safely on
+// disk. Either way a move is all or nothing: if any file fails, the ones already moved are put
+// back, so the torrent's data is never left split between two places.
+// Nothing here stops the torrent from writing while its files move. `DiskIo::pause` and
+// `DiskIo::resume` hold its disk jobs meanwhile and point it at the new location afterwards.
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::metainfo::FileEntry;
+
+// Copies are reported on in steps this big
+const COPY_CHUNK: usize = 1 << 20;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Relocation {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+// How far along a move is. Renamed files count in full at once, copied ones as they go
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct MoveProgress {
+    pub files_done: usize,
+    pub files_total: usize,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+}
+
+impl Relocation {
+    // Moves every file that exists under `from` to the same place under `to`. Files that haven't
+    // been created yet are skipped
+    pub fn apply(&self, files: &[FileEntry]) -> io::Result<()> {
+        self.apply_with_progress(files, |_| {})
+    }
+
+    // Like `apply`, calling `progress` as it goes. Nothing under `to` is ever overwritten: if one
+    // of the files is there already, the move fails before anything is touched
+    pub fn apply_with_progress(
+        &self,
+        files: &[FileEntry],
+        mut progress: impl FnMut(MoveProgress),
+    ) -> io::Result<()> {
+        let mut present = vec![];
+        for file in files {
+            let relative: PathBuf = file.path.iter().collect();
+            match fs::metadata(self.from.join(&relative)) {
+                Ok(metadata) => present.push((relative, metadata.len())),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        if present
+            .iter()
+            .any(|(relative, _)| self.to.join(relative).exists())
+        {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+
+        let mut state = MoveProgress {
+            files_total: present.len(),
+            bytes_total: present.iter().map(|(_, size)| size).sum(),
+            ..MoveProgress::default()
+        };
+        progress(state);
+        for (done, (relative, size)) in present.iter().enumerate() {
+            let start = state.bytes_done;
+            let result = move_file(&self.from.join(relative), &self.to.join(relative), |n| {
+                state.bytes_done = start + n;
+                progress(state);
+            });
+            if let Err(err) = result {
+                self.roll_back(present[..done].iter().map(|(relative, _)| relative));
+                return Err(err);
+            }
+            state.files_done += 1;
+            state.bytes_done = start + size;
+            progress(state);
+        }
+
+        // Directories the files were in go too, once they're empty
+        for (relative, _) in &present {
+            for dir in relative.ancestors().skip(1) {
+                if dir.as_os_str().is_empty() || fs::remove_dir(self.from.join(dir)).is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Best effort: a file that can't be put back is still in one piece where it was moved to
+    fn roll_back<'a>(&self, moved: impl DoubleEndedIterator<Item = &'a PathBuf>) {
+        for relative in moved.rev() {
+            let _ = move_file(&self.to.join(relative), &self.from.join(relative), |_| {});
+        }
+    }
+}
+
+// `copied` is called with the bytes copied so far, when the file has to be copied
+fn move_file(from: &Path, to: &Path, copied: impl FnMut(u64)) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::rename(from, to) {
+        // rename can't cross filesystems, so fall back to a copy
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            if let Err(err) = copy_file(from, to, copied) {
+                let _ = fs::remove_file(to);
+                return Err(err);
+            }
+            fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+// The original is only removed once this returns, so the copy is synced first
+fn copy_file(from: &Path, to: &Path, mut copied: impl FnMut(u64)) -> io::Result<()> {
+    let mut input = File::open(from)?;
+    let mut output = File::create_new(to)?;
+    let mut buf = vec![0; COPY_CHUNK];
+    let mut total = 0;
+    loop {
+        let n = input.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        output.write_all(&buf[..n])?;
+        total += n as u64;
+        copied(total);
+    }
+    output.sync_all()
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn files(paths: &[&str]) -> Vec<FileEntry> {
+        paths
+            .iter()
+            .map(|path| FileEntry {
+                path: path.split('/').map(str::to_string).collect(),
+                length: 0,
+                pieces_root: None,
+            })
+            .collect()
+    }
+
+    fn dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("hurricane-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_move_with_progress() {
+        let dir = dir("relocate");
+        let relocation = Relocation {
+            from: dir.join("a"),
+            to: dir.join("b"),
+        };
+        fs::create_dir_all(dir.join("a/t/sub")).unwrap();
+        fs::write(dir.join("a/t/1.bin"), [1; 10]).unwrap();
+        fs::write(dir.join("a/t/sub/2.bin"), [2; 30]).unwrap();
+
+        let mut reports = vec![];
+        relocation
+            .apply_with_progress(&files(&["t/1.bin", "t/sub/2.bin", "t/3.bin"]), |p| {
+                reports.push(p)
+            })
+            .unwrap();
+        assert_eq!(fs::read(dir.join("b/t/sub/2.bin")).unwrap(), [2; 30]);
+        assert!(!dir.join("a/t").exists());
+        assert!(dir.join("a").exists());
+        let last = *reports.last().unwrap();
+        assert_eq!((last.files_done, last.files_total), (2, 2));
+        assert_eq!((last.bytes_done, last.bytes_total), (40, 40));
+        assert_eq!(reports[0].bytes_done, 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_move_rolls_back() {
+        let dir = dir("relocate-fail");
+        let relocation = Relocation {
+            from: dir.join("a"),
+            to: dir.join("b"),
+        };
+        fs::create_dir_all(dir.join("a/t/sub")).unwrap();
+        fs::write(dir.join("a/t/1.bin"), [1; 10]).unwrap();
+        fs::write(dir.join("a/t/sub/2.bin"), [2; 30]).unwrap();
+        let files = files(&["t/1.bin", "t/sub/2.bin"]);
+
+        // A file where the second one's directory should go: the first is moved, then put back
+        fs::create_dir_all(dir.join("b/t")).unwrap();
+        fs::write(dir.join("b/t/sub"), b"in the way").unwrap();
+        assert!(relocation.apply(&files).is_err());
+        assert_eq!(fs::read(dir.join("a/t/1.bin")).unwrap(), [1; 10]);
+        assert!(!dir.join("b/t/1.bin").exists());
+
+        // Something already at the destination is never overwritten
+        fs::remove_file(dir.join("b/t/sub")).unwrap();
+        fs::write(dir.join("b/t/1.bin"), b"mine").unwrap();
+        assert_eq!(
+            relocation.apply(&files).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert!(dir.join("a/t/sub/2.bin").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
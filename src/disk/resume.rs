@@ -0,0 +1,396 @@
+// Fast resume. Rechecking hundreds of gigabytes on every start takes hours, so what we knew about
+// a torrent when it was last saved goes in a small bencoded file: which pieces were verified, the
+// blocks of unfinished pieces already on disk, the size and mtime of each file, plus trackers,
+// peers and transfer totals. On startup the files are stat'ed and compared to what was saved.
+// Pieces that only touch unchanged files are taken as they are; pieces on a file that changed
+// (or went missing) are hashed again, and only those.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use bencode::BencodeValue;
+
+use super::storage::{PieceOnDisk, Storage};
+use crate::bitfield::Bitfield;
+use crate::compact::CompactPeers;
+use crate::infohash::InfoHash;
+use crate::statefile;
+
+// What a file looked like when we saved. A file that didn't exist yet has no stamp
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct FileStamp {
+    pub size: u64,
+    // Seconds since the epoch
+    pub mtime: u64,
+}
+
+impl FileStamp {
+    pub fn of(path: &Path) -> io::Result<Option<FileStamp>> {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Ok(Some(FileStamp {
+            size: metadata.len(),
+            mtime,
+        }))
+    }
+}
+
+// Every file of the torrent, in order. Take these after the write cache has been flushed, or the
+// mtimes will be stale by the time the next start looks at them
+pub fn stamp_files(storage: &Storage) -> io::Result<Vec<Option<FileStamp>>> {
+    (0..storage.num_files())
+        .map(|file| FileStamp::of(&storage.path(file)))
+        .collect()
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct ResumeData {
+    pub info_hash: InfoHash,
+    pub have: Bitfield,
+    // Unfinished pieces and which of their blocks are on disk, see `PiecePicker::partial_progress`
+    pub partial: Vec<(u32, Bitfield)>,
+    pub files: Vec<Option<FileStamp>>,
+    pub trackers: Vec<Vec<String>>,
+    pub peers: Vec<SocketAddr>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    // Seconds spent seeding, for seed time goals
+    pub seeding_time: u64,
+}
+
+// What's left of resume data after checking it against the disk
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Validated {
+    pub have: Bitfield,
+    pub partial: Vec<(u32, Bitfield)>,
+    // Pieces that were all there and failed the hash check, in order
+    pub corrupt: Vec<u32>,
+    // Pieces that had to be hashed again
+    pub rechecked: usize,
+}
+
+impl ResumeData {
+    pub fn encode(&self) -> Vec<u8> {
+        let int = |n: u64| BencodeValue::Int(n as i64);
+        let mut root = BTreeMap::new();
+        root.insert(
+            b"info-hash".to_vec(),
+            BencodeValue::ByteStr(self.info_hash.0.to_vec()),
+        );
+        root.insert(b"num pieces".to_vec(), int(self.have.len() as u64));
+        root.insert(
+            b"pieces".to_vec(),
+            BencodeValue::ByteStr(self.have.as_bytes().to_vec()),
+        );
+        let partial = self
+            .partial
+            .iter()
+            .map(|(piece, blocks)| {
+                BencodeValue::List(vec![
+                    int(*piece as u64),
+                    int(blocks.len() as u64),
+                    BencodeValue::ByteStr(blocks.as_bytes().to_vec()),
+                ])
+            })
+            .collect();
+        root.insert(b"partial".to_vec(), BencodeValue::List(partial));
+        // [size, mtime] per file, or an empty list for one that wasn't there
+        let files = self
+            .files
+            .iter()
+            .map(|stamp| match stamp {
+                Some(stamp) => BencodeValue::List(vec![int(stamp.size), int(stamp.mtime)]),
+                None => BencodeValue::List(vec![]),
+            })
+            .collect();
+        root.insert(b"files".to_vec(), BencodeValue::List(files));
+        let trackers = self
+            .trackers
+            .iter()
+            .map(|tier| {
+                BencodeValue::List(
+                    tier.iter()
+                        .map(|url| BencodeValue::ByteStr(url.as_bytes().to_vec()))
+                        .collect(),
+                )
+            })
+            .collect();
+        root.insert(b"trackers".to_vec(), BencodeValue::List(trackers));
+        let mut v4: Vec<SocketAddrV4> = vec![];
+        let mut v6: Vec<SocketAddrV6> = vec![];
+        for peer in &self.peers {
+            match peer {
+                SocketAddr::V4(addr) => v4.push(*addr),
+                SocketAddr::V6(addr) => v6.push(*addr),
+            }
+        }
+        root.insert(
+            b"peers".to_vec(),
+            BencodeValue::ByteStr(CompactPeers::write(&v4)),
+        );
+        root.insert(
+            b"peers6".to_vec(),
+            BencodeValue::ByteStr(CompactPeers::write(&v6)),
+        );
+        root.insert(b"uploaded".to_vec(), int(self.uploaded));
+        root.insert(b"downloaded".to_vec(), int(self.downloaded));
+        root.insert(b"seeding time".to_vec(), int(self.seeding_time));
+        bencode::encode(&BencodeValue::Dict(root))
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<ResumeData> {
+        let values = bencode::decode(buf).ok()?;
+        let root = values.first()?;
+        let uint = |value: &BencodeValue| value.as_int().and_then(|n| u64::try_from(n).ok());
+
+        let info_hash = InfoHash(root.get(b"info-hash")?.as_bytes()?.try_into().ok()?);
+        let num_pieces = uint(root.get(b"num pieces")?)? as usize;
+        let have = Bitfield::from_bytes(root.get(b"pieces")?.as_bytes()?, num_pieces)?;
+
+        let mut partial = vec![];
+        for entry in root.get(b"partial")?.as_list()? {
+            let [piece, len, blocks] = entry.as_list()? else {
+                return None;
+            };
+            let blocks = Bitfield::from_bytes(blocks.as_bytes()?, uint(len)? as usize)?;
+            partial.push((u32::try_from(uint(piece)?).ok()?, blocks));
+        }
+
+        let mut files = vec![];
+        for entry in root.get(b"files")?.as_list()? {
+            files.push(match entry.as_list()? {
+                [] => None,
+                [size, mtime] => Some(FileStamp {
+                    size: uint(size)?,
+                    mtime: uint(mtime)?,
+                }),
+                _ => return None,
+            });
+        }
+
+        let trackers = root
+            .get(b"trackers")?
+            .as_list()?
+            .iter()
+            .map(|tier| {
+                tier.as_list()?
+                    .iter()
+                    .map(|url| Some(String::from_utf8_lossy(url.as_bytes()?).into_owned()))
+                    .collect()
+            })
+            .collect::<Option<_>>()?;
+
+        let mut peers: Vec<SocketAddr> =
+            CompactPeers::parse::<SocketAddrV4>(root.get(b"peers")?.as_bytes()?)
+                .ok()?
+                .into_iter()
+                .map(SocketAddr::V4)
+                .collect();
+        peers.extend(
+            CompactPeers::parse::<SocketAddrV6>(root.get(b"peers6")?.as_bytes()?)
+                .ok()?
+                .into_iter()
+                .map(SocketAddr::V6),
+        );
+
+        Some(ResumeData {
+            info_hash,
+            have,
+            partial,
+            files,
+            trackers,
+            peers,
+            uploaded: uint(root.get(b"uploaded")?)?,
+            downloaded: uint(root.get(b"downloaded")?)?,
+            // Files from before seed goals don't have it
+            seeding_time: root.get(b"seeding time").and_then(uint).unwrap_or(0),
+        })
+    }
+
+    // A missing or unreadable file just means a full check, same as a first start
+    pub fn load(path: &Path) -> io::Result<Option<ResumeData>> {
+        statefile::load(path, ResumeData::decode)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        statefile::save(path, &self.encode())
+    }
+
+    // None if this resume data is for some other torrent (or another version of it), in which
+    // case the caller falls back to a full `Storage::check`
+    pub fn validate(
+        &self,
+        info_hash: InfoHash,
+        storage: &Storage,
+    ) -> io::Result<Option<Validated>> {
+        if info_hash != self.info_hash
+            || self.have.len() != storage.num_pieces() as usize
+            || self.files.len() != storage.num_files()
+        {
+            return Ok(None);
+        }
+        let now = stamp_files(storage)?;
+        // Anything written after the save (blocks that made it to disk before a crash, say) shows
+        // up as a changed file too
+        let changed: Vec<bool> = now.iter().zip(&self.files).map(|(a, b)| a != b).collect();
+        let untouched = |piece: u32| {
+            storage
+                .slices(piece, 0, storage.piece_size(piece))
+                .iter()
+                .all(|slice| !changed[slice.file])
+        };
+
+        let mut validated = Validated::empty(self.have.len());
+        for piece in 0..storage.num_pieces() {
+            if !untouched(piece) {
+                recheck(storage, piece, &mut validated)?;
+            } else if self.have.get(piece as usize) {
+                validated.have.set(piece as usize);
+            } else if let Some(saved) = self.partial.iter().find(|(p, _)| *p == piece) {
+                validated.partial.push(saved.clone());
+            }
+        }
+        Ok(Some(validated))
+    }
+}
+
+// What we can make of the files without resume data, e.g. after a crash before the first save.
+// Every piece is hashed, and the blocks of those that fail are looked at one by one so an
+// unfinished piece only needs its missing blocks downloaded
+pub fn recover(storage: &Storage) -> io::Result<Validated> {
+    let mut validated = Validated::empty(storage.num_pieces() as usize);
+    for piece in 0..storage.num_pieces() {
+        recheck(storage, piece, &mut validated)?;
+    }
+    Ok(validated)
+}
+
+impl Validated {
+    fn empty(num_pieces: usize) -> Self {
+        Validated {
+            have: Bitfield::new(num_pieces),
+            partial: vec![],
+            corrupt: vec![],
+            rechecked: 0,
+        }
+    }
+}
+
+fn recheck(storage: &Storage, piece: u32, validated: &mut Validated) -> io::Result<()> {
+    validated.rechecked += 1;
+    match storage.recover_piece(piece)? {
+        PieceOnDisk::Verified => validated.have.set(piece as usize),
+        PieceOnDisk::Partial(blocks) => validated.partial.push((piece, blocks)),
+        PieceOnDisk::Corrupt => validated.corrupt.push(piece),
+        PieceOnDisk::Missing => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::metainfo::{FileEntry, Info};
+    use sha1::{Digest, Sha1};
+
+    fn storage(dir: &Path, data: &[u8]) -> Storage {
+        let info = Info {
+            name: "t".to_string(),
+            piece_length: 16,
+            pieces: data.chunks(16).map(|p| Sha1::digest(p).into()).collect(),
+            files: vec![
+                FileEntry {
+                    path: vec!["a.bin".to_string()],
+                    length: 16,
+                    pieces_root: None,
+                },
+                FileEntry {
+                    path: vec!["b.bin".to_string()],
+                    length: data.len() as u64 - 16,
+                    pieces_root: None,
+                },
+            ],
+            private: false,
+        };
+        Storage::new(&info, dir)
+    }
+
+    fn resume(have: &[usize], files: Vec<Option<FileStamp>>) -> ResumeData {
+        let mut bits = Bitfield::new(3);
+        for piece in have {
+            bits.set(*piece);
+        }
+        ResumeData {
+            info_hash: InfoHash([1; 20]),
+            have: bits,
+            partial: vec![(2, Bitfield::from_bytes(&[0x80], 1).unwrap())],
+            files,
+            trackers: vec![vec!["http://a/announce".to_string()]],
+            peers: vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "[2001:db8::1]:6881".parse().unwrap(),
+            ],
+            uploaded: 100,
+            downloaded: 48,
+            seeding_time: 3600,
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let stamp = FileStamp {
+            size: 16,
+            mtime: 1_700_000_000,
+        };
+        let data = resume(&[0, 1], vec![Some(stamp), None]);
+
+        assert_eq!(ResumeData::decode(&data.encode()), Some(data));
+        assert_eq!(ResumeData::decode(b"d4:infoi1ee"), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        let dir = std::env::temp_dir().join(format!("hurricane-resume-{}", std::process::id()));
+        let data: Vec<u8> = (0..40).collect();
+        let storage = storage(&dir, &data);
+        storage.write(0, 0, &data[..16]).unwrap();
+        storage.write(1, 0, &data[16..32]).unwrap();
+
+        let saved = resume(&[0, 1], stamp_files(&storage).unwrap());
+        let validated = saved
+            .validate(InfoHash([1; 20]), &storage)
+            .unwrap()
+            .unwrap();
+        assert_eq!(validated.have, saved.have);
+        assert_eq!(validated.partial, saved.partial);
+        assert_eq!(validated.rechecked, 0);
+
+        // b.bin changed behind our back: its pieces get checked again, and the partial piece on it
+        // turns out to have nothing written
+        storage.write(1, 0, &[0; 16]).unwrap();
+        let files = vec![saved.files[0], Some(FileStamp { size: 1, mtime: 0 })];
+        let saved = resume(&[0, 1], files);
+        let validated = saved
+            .validate(InfoHash([1; 20]), &storage)
+            .unwrap()
+            .unwrap();
+        assert!(validated.have.get(0));
+        assert!(!validated.have.get(1));
+        assert!(validated.partial.is_empty());
+        assert_eq!(validated.rechecked, 2);
+
+        assert_eq!(saved.validate(InfoHash([2; 20]), &storage).unwrap(), None);
+        assert_eq!(recover(&storage).unwrap().have, validated.have);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,847 @@
+// Piece data on disk. A torrent is one long byte stream cut into pieces, laid over its files
+// back to back, so a block can start in one file and end in the next. `slices` does that mapping
+// and the read/write helpers follow it with positioned I/O (pread/pwrite on unix), so several
+// threads can work on the same file without fighting over a shared cursor. Files are created
+// lazily on first write, or all at once with `create_files`, and sized up front according to the
+// `Allocation` mode. With `Backend::Mmap` the same helpers go through memory mappings instead
+// wherever those work.
+// Pieces of hybrid torrents are checked against their v2 merkle trees as well as their SHA-1
+// hashes, once a tree knows the piece's hash. Trees missing their piece layers get them from
+// peers' hash messages, through `on_hashes`.
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use sha1::{Digest, Sha1};
+
+use super::cache::{PieceBuffer, VerifyStats};
+use super::mmap::MappedFiles;
+use crate::bitfield::Bitfield;
+use crate::metainfo::merkle::{Hash256, MERKLE_BLOCK};
+use crate::metainfo::{HashRequest, Info, MerkleFile};
+use crate::peer::BLOCK_SIZE;
+
+// Part of one file that a range of the torrent covers
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct FileSlice {
+    // Index into `Info::files`
+    pub file: usize,
+    pub offset: u64,
+    pub len: u64,
+}
+
+// What a block-by-block recheck makes of one piece
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum PieceOnDisk {
+    Verified,
+    // Blocks that look written: there to read and not all zeros, which is what a preallocated or
+    // sparse file holds where nothing was written yet. They can't be checked on their own, the
+    // piece hash settles it once the rest arrive
+    Partial(Bitfield),
+    // Every block there and the hash still wrong. There's no way of telling the bad blocks from
+    // the good ones, so it's downloaded again in full
+    Corrupt,
+    Missing,
+}
+
+// How a torrent's files are read and written, picked per torrent
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum Backend {
+    #[default]
+    Positioned,
+    // Copies in and out of mapped files. Without the `mmap` feature, or on a platform it doesn't
+    // support, this is the same as `Positioned`
+    Mmap,
+}
+
+// What a file gets when it's created, picked per torrent
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum Allocation {
+    // Files grow as blocks are written. Cheapest, and what copy-on-write filesystems want anyway
+    #[default]
+    None,
+    // Full size right away, but as a hole: no space is reserved
+    Sparse,
+    // Every byte reserved on disk up front, so the disk filling up is an error when the files
+    // are created instead of halfway through the download, and the file is less fragmented
+    Full,
+}
+
+#[derive(Debug, Clone)]
+pub struct Storage {
+    root: PathBuf,
+    // (path relative to root, start in the torrent, length)
+    files: Vec<(PathBuf, u64, u64)>,
+    piece_length: u32,
+    pieces: Vec<[u8; 20]>,
+    total_length: u64,
+    // Some with `Backend::Mmap` where mapping is supported. Clones share the mappings
+    mapped: Option<Arc<MappedFiles>>,
+    allocation: Allocation,
+    // Files the user doesn't want. They still get the parts of pieces they share with wanted
+    // files, but are never created or allocated for their own sake
+    skipped: Vec<bool>,
+    // BEP 52 trees per file, for hybrid torrents. Clones share them, so a piece layer one
+    // connection fetched counts for all of them
+    merkle: Arc<Mutex<Vec<Option<MerkleFile>>>>,
+}
+
+impl Storage {
+    // `root` is the directory the torrent's name goes in
+    pub fn new(info: &Info, root: &Path) -> Self {
+        Storage::with_backend(info, root, Backend::Positioned)
+    }
+
+    pub fn with_backend(info: &Info, root: &Path, backend: Backend) -> Self {
+        let mut start = 0;
+        let files = info
+            .files
+            .iter()
+            .map(|file| {
+                let entry = (file.path.iter().collect(), start, file.length);
+                start += file.length;
+                entry
+            })
+            .collect();
+        Storage {
+            root: root.to_path_buf(),
+            files,
+            piece_length: info.piece_length,
+            pieces: info.pieces.clone(),
+            total_length: info.total_length(),
+            mapped: match backend {
+                Backend::Positioned => None,
+                Backend::Mmap => MappedFiles::new(info.files.len()).map(Arc::new),
+            },
+            allocation: Allocation::None,
+            skipped: vec![false; info.files.len()],
+            merkle: Arc::new(Mutex::new(vec![None; info.files.len()])),
+        }
+    }
+
+    // Trees from `Metainfo::merkle_files`, in file order
+    pub fn set_merkle(&mut self, files: Vec<Option<MerkleFile>>) {
+        self.merkle = Arc::new(Mutex::new(files));
+    }
+
+    // What to ask peers for to complete the trees' piece layers
+    pub fn hash_requests(&self) -> Vec<HashRequest> {
+        let trees = self.merkle.lock().unwrap();
+        trees
+            .iter()
+            .flatten()
+            .flat_map(MerkleFile::layer_requests)
+            .collect()
+    }
+
+    // A peer's answer to one of `hash_requests`. False if it doesn't check out, which is as bad
+    // as sending a bad block
+    pub fn on_hashes(&self, request: &HashRequest, hashes: &[Hash256]) -> bool {
+        let mut trees = self.merkle.lock().unwrap();
+        trees
+            .iter_mut()
+            .flatten()
+            .find(|tree| tree.root() == request.pieces_root)
+            .is_some_and(|tree| tree.on_hashes(request, hashes).is_some())
+    }
+
+    // Our answer to a peer's hash request. Anything below the piece layer is hashed from the
+    // piece it falls in, read back from disk. None is a reject
+    pub fn answer_hashes(&self, request: &HashRequest) -> Option<Vec<Hash256>> {
+        let (file, tree) = {
+            let trees = self.merkle.lock().unwrap();
+            trees.iter().enumerate().find_map(|(file, tree)| {
+                let tree = tree.as_ref().filter(|t| t.root() == request.pieces_root)?;
+                Some((file, tree.clone()))
+            })?
+        };
+        let piece_layer = (self.piece_length / MERKLE_BLOCK).trailing_zeros();
+        let data = match request.base_layer < piece_layer {
+            // The tree takes no more than one piece's worth, so the first hash says which
+            true => {
+                let index = request.index as u64 >> (piece_layer - request.base_layer);
+                self.file_piece(file, index)
+                    .and_then(|(piece, len)| self.read(piece, 0, len).ok())
+            }
+            false => None,
+        };
+        tree.answer(request, data.as_deref())
+    }
+
+    // The torrent's piece that a file's `index`th piece is, and how much of it is the file's.
+    // Only for files that start on a piece boundary, as every file of a hybrid torrent does
+    fn file_piece(&self, file: usize, index: u64) -> Option<(u32, u32)> {
+        let (_, start, length) = self.files[file];
+        let offset = index.checked_mul(self.piece_length as u64)?;
+        if !start.is_multiple_of(self.piece_length as u64) || offset >= length {
+            return None;
+        }
+        let piece = ((start + offset) / self.piece_length as u64) as u32;
+        Some((
+            piece,
+            (length - offset).min(self.piece_length as u64) as u32,
+        ))
+    }
+
+    // Whether a v2 tree covers the piece: its file, its index in the file and how much of it is
+    // the file's, since a file's last piece can run on into padding
+    fn tree_piece(&self, piece: u32) -> Option<(usize, u32, u32)> {
+        let slice = *self.slices(piece, 0, 1).first()?;
+        let index = slice.offset / self.piece_length as u64;
+        let (_, len) = self.file_piece(slice.file, index)?;
+        self.merkle.lock().unwrap()[slice.file].as_ref()?;
+        Some((slice.file, index as u32, len))
+    }
+
+    // The v2 tree's word on a piece. None where no tree covers it or the tree doesn't know its
+    // hash yet, which leaves it to SHA-1
+    fn verify_v2(&self, piece: u32, data: &[u8]) -> Option<bool> {
+        let (file, index, len) = self.tree_piece(piece)?;
+        let trees = self.merkle.lock().unwrap();
+        let data = &data[..(len as usize).min(data.len())];
+        trees[file].as_ref()?.verify_piece(index, data)
+    }
+
+    pub fn allocation(&self) -> Allocation {
+        self.allocation
+    }
+
+    pub fn set_allocation(&mut self, allocation: Allocation) {
+        self.allocation = allocation;
+    }
+
+    pub fn set_skipped(&mut self, file: usize, skipped: bool) {
+        self.skipped[file] = skipped;
+    }
+
+    // The backend actually in use, which is `Positioned` if mapping isn't supported here
+    pub fn backend(&self) -> Backend {
+        match self.mapped {
+            Some(_) => Backend::Mmap,
+            None => Backend::Positioned,
+        }
+    }
+
+    pub fn num_pieces(&self) -> u32 {
+        self.pieces.len() as u32
+    }
+
+    // The last piece is usually short
+    pub fn piece_size(&self, piece: u32) -> u32 {
+        let start = piece as u64 * self.piece_length as u64;
+        (self.total_length.saturating_sub(start)).min(self.piece_length as u64) as u32
+    }
+
+    // Position of a byte of a piece in the torrent as a whole
+    pub fn offset_of(&self, piece: u32, offset: u32) -> u64 {
+        piece as u64 * self.piece_length as u64 + offset as u64
+    }
+
+    pub fn num_files(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn path(&self, file: usize) -> PathBuf {
+        self.root.join(&self.files[file].0)
+    }
+
+    // Files the given range of a piece lands in, in order. Empty files never show up
+    pub fn slices(&self, piece: u32, offset: u32, len: u32) -> Vec<FileSlice> {
+        let mut at = self.offset_of(piece, offset);
+        let end = (at + len as u64).min(self.total_length);
+        let mut slices = vec![];
+        for (i, (_, start, length)) in self.files.iter().enumerate() {
+            if at >= end {
+                break;
+            }
+            let file_end = start + length;
+            if at >= file_end || *length == 0 {
+                continue;
+            }
+            let slice_end = end.min(file_end);
+            slices.push(FileSlice {
+                file: i,
+                offset: at - start,
+                len: slice_end - at,
+            });
+            at = slice_end;
+        }
+        slices
+    }
+
+    pub fn read(&self, piece: u32, offset: u32, len: u32) -> io::Result<Vec<u8>> {
+        let slices = self.slices(piece, offset, len);
+        if slices.iter().map(|s| s.len).sum::<u64>() != len as u64 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut buf = vec![0; len as usize];
+        let mut at = 0;
+        for slice in slices {
+            let end = at + slice.len as usize;
+            self.read_slice(slice, &mut buf[at..end])?;
+            at = end;
+        }
+        Ok(buf)
+    }
+
+    pub fn write(&self, piece: u32, offset: u32, data: &[u8]) -> io::Result<()> {
+        let mut data = data;
+        for slice in self.slices(piece, offset, data.len() as u32) {
+            let (now, rest) = data.split_at(slice.len as usize);
+            self.write_slice(slice, now)?;
+            data = rest;
+        }
+        Ok(())
+    }
+
+    // Makes everything written so far durable. Positioned writes are already in the kernel's
+    // hands, so only mapped files have anything to do
+    pub fn flush(&self) -> io::Result<()> {
+        match &self.mapped {
+            Some(mapped) => mapped.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn read_slice(&self, slice: FileSlice, buf: &mut [u8]) -> io::Result<()> {
+        let path = self.path(slice.file);
+        if let Some(mapped) = &self.mapped {
+            let length = self.files[slice.file].2;
+            if mapped.read(slice.file, || File::open(&path), length, buf, slice.offset)? {
+                return Ok(());
+            }
+        }
+        read_at(&File::open(path)?, buf, slice.offset)
+    }
+
+    fn write_slice(&self, slice: FileSlice, data: &[u8]) -> io::Result<()> {
+        // Mapping a file for writing grows it to full size, which a skipped file shouldn't be
+        if let Some(mapped) = self.mapped.as_ref().filter(|_| !self.skipped[slice.file]) {
+            let length = self.files[slice.file].2;
+            let open = || self.open_for_write(slice.file);
+            if mapped.write(slice.file, open, length, data, slice.offset)? {
+                return Ok(());
+            }
+        }
+        write_at(&self.open_for_write(slice.file)?, data, slice.offset)
+    }
+
+    // Lays out the whole directory tree up front, allocating every file as configured. Zero-length
+    // files never get a block written to them, so this is the only way they show up on disk.
+    // With `Allocation::Full`, running out of space shows up here. Skipped files are left out
+    pub fn create_files(&self) -> io::Result<()> {
+        for file in 0..self.files.len() {
+            if !self.skipped[file] {
+                self.open_for_write(file)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn open_for_write(&self, file: usize) -> io::Result<File> {
+        let path = self.path(file);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Readable too, or a writable shared mapping of it is refused
+        let handle = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)?;
+        let length = self.files[file].2;
+        let allocate_file = self.allocation != Allocation::None && !self.skipped[file];
+        if allocate_file && handle.metadata()?.len() < length {
+            match self.allocation {
+                Allocation::Sparse => handle.set_len(length)?,
+                _ => allocate(&handle, length)?,
+            }
+        }
+        Ok(handle)
+    }
+
+    // Whether the piece on disk matches its hash. Missing files just mean it doesn't
+    pub fn verify(&self, piece: u32) -> io::Result<bool> {
+        match self.read(piece, 0, self.piece_size(piece)) {
+            Ok(data) => Ok(Sha1::digest(&data)[..] == self.pieces[piece as usize]
+                && self.verify_v2(piece, &data) != Some(false)),
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::UnexpectedEof
+                ) =>
+            {
+                Ok(false)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // Like `verify`, but hashing the blocks the write cache still holds straight from memory.
+    // Only blocks it already dropped are read back
+    pub fn verify_buffer(&self, buffer: &PieceBuffer, stats: &VerifyStats) -> io::Result<bool> {
+        let piece = buffer.piece;
+        let size = self.piece_size(piece);
+        let mut hasher = Sha1::new();
+        // The whole piece too, if a v2 tree is going to want it
+        let mut whole = self
+            .tree_piece(piece)
+            .map(|_| Vec::with_capacity(size as usize));
+        let (mut cached, mut disk) = (0, 0);
+        for (index, offset) in (0..size).step_by(BLOCK_SIZE as usize).enumerate() {
+            let len = BLOCK_SIZE.min(size - offset);
+            match buffer.blocks.get(index).and_then(Option::as_ref) {
+                Some(block) if block.len() == len as usize => {
+                    hasher.update(block);
+                    whole
+                        .iter_mut()
+                        .for_each(|whole| whole.extend_from_slice(block));
+                    cached += len as u64;
+                }
+                _ => match self.read(piece, offset, len) {
+                    Ok(data) => {
+                        hasher.update(&data);
+                        whole
+                            .iter_mut()
+                            .for_each(|whole| whole.extend_from_slice(&data));
+                        disk += len as u64;
+                    }
+                    Err(err)
+                        if matches!(
+                            err.kind(),
+                            io::ErrorKind::NotFound | io::ErrorKind::UnexpectedEof
+                        ) =>
+                    {
+                        stats.add(cached, disk);
+                        return Ok(false);
+                    }
+                    Err(err) => return Err(err),
+                },
+            }
+        }
+        stats.add(cached, disk);
+        Ok(hasher.finalize()[..] == self.pieces[piece as usize]
+            && whole.is_none_or(|whole| self.verify_v2(piece, &whole) != Some(false)))
+    }
+
+    // Like `verify`, but reading block by block so an unfinished piece isn't thrown away whole
+    pub fn recover_piece(&self, piece: u32) -> io::Result<PieceOnDisk> {
+        let size = self.piece_size(piece);
+        let num_blocks = size.div_ceil(BLOCK_SIZE) as usize;
+        let mut present = Bitfield::new(num_blocks);
+        let mut hasher = Sha1::new();
+        let mut whole = self
+            .tree_piece(piece)
+            .map(|_| Vec::with_capacity(size as usize));
+        for (index, offset) in (0..size).step_by(BLOCK_SIZE as usize).enumerate() {
+            let len = BLOCK_SIZE.min(size - offset);
+            match self.read(piece, offset, len) {
+                Ok(data) => {
+                    hasher.update(&data);
+                    whole
+                        .iter_mut()
+                        .for_each(|whole| whole.extend_from_slice(&data));
+                    if data.iter().any(|b| *b != 0) {
+                        present.set(index);
+                    }
+                }
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::NotFound | io::ErrorKind::UnexpectedEof
+                    ) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        // Blocks that couldn't be read leave `whole` short, but SHA-1 has said no by then
+        if hasher.finalize()[..] == self.pieces[piece as usize]
+            && whole.is_none_or(|whole| self.verify_v2(piece, &whole) != Some(false))
+        {
+            Ok(PieceOnDisk::Verified)
+        } else if present.none() {
+            Ok(PieceOnDisk::Missing)
+        } else if present.all() {
+            Ok(PieceOnDisk::Corrupt)
+        } else {
+            Ok(PieceOnDisk::Partial(present))
+        }
+    }
+
+    // Full recheck of what's already on disk, e.g. before seeding
+    pub fn check(&self) -> io::Result<Bitfield> {
+        let mut have = Bitfield::new(self.pieces.len());
+        for piece in 0..self.num_pieces() {
+            if self.verify(piece)? {
+                have.set(piece as usize);
+            }
+        }
+        Ok(have)
+    }
+}
+
+// Reserves space for the file up to `length` without touching what's already in it
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn allocate(file: &File, length: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let length = libc::off_t::try_from(length).map_err(|_| io::ErrorKind::FileTooLarge)?;
+    // SAFETY: plain syscall on a file descriptor we own
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, length) } {
+        0 => Ok(()),
+        // Some filesystems (and libcs) can't do it, same as everywhere else then
+        libc::EOPNOTSUPP | libc::EINVAL => fill_zeroes(file, length as u64),
+        err => Err(io::Error::from_raw_os_error(err)),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn allocate(file: &File, length: u64) -> io::Result<()> {
+    fill_zeroes(file, length)
+}
+
+// The portable way to get space allocated: write it. Only the part past the end is filled, any
+// holes already in the file stay holes
+fn fill_zeroes(file: &File, length: u64) -> io::Result<()> {
+    let zeroes = vec![0; 1 << 20];
+    let mut at = file.metadata()?.len();
+    while at < length {
+        let len = (length - at).min(zeroes.len() as u64) as usize;
+        write_at(file, &zeroes[..len], at)?;
+        at += len as u64;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+// No pread on Windows, but seek_read/seek_write do the same job in one call. They move the
+// cursor as a side effect, which nothing here relies on
+#[cfg(windows)]
+fn read_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset)? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::metainfo::FileEntry;
+
+    fn info(lengths: &[u64], piece_length: u32, data: &[u8]) -> Info {
+        Info {
+            name: "t".to_string(),
+            piece_length,
+            pieces: data
+                .chunks(piece_length as usize)
+                .map(|piece| Sha1::digest(piece).into())
+                .collect(),
+            files: lengths
+                .iter()
+                .enumerate()
+                .map(|(i, length)| FileEntry {
+                    path: vec!["t".to_string(), format!("{}.bin", i)],
+                    length: *length,
+                    pieces_root: None,
+                })
+                .collect(),
+            private: false,
+        }
+    }
+
+    #[test]
+    fn test_slices_span_files() {
+        let data = vec![0; 30];
+        let storage = Storage::new(&info(&[10, 0, 15, 5], 16, &data), Path::new("/x"));
+
+        assert_eq!(
+            storage.slices(0, 4, 12),
+            vec![
+                FileSlice {
+                    file: 0,
+                    offset: 4,
+                    len: 6
+                },
+                FileSlice {
+                    file: 2,
+                    offset: 0,
+                    len: 6
+                },
+            ]
+        );
+        assert_eq!(storage.piece_size(1), 14);
+        assert_eq!(
+            storage.slices(1, 8, 16),
+            vec![
+                FileSlice {
+                    file: 2,
+                    offset: 14,
+                    len: 1
+                },
+                FileSlice {
+                    file: 3,
+                    offset: 0,
+                    len: 5
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merkle() {
+        use crate::metainfo::merkle::{self, hash_block};
+
+        let dir = std::env::temp_dir().join(format!("hurricane-merkle-{}", std::process::id()));
+        let piece_length = 2 * MERKLE_BLOCK;
+        let tree = |data: &[u8]| {
+            let leaves: Vec<Hash256> = data.chunks(MERKLE_BLOCK as usize).map(hash_block).collect();
+            let layer: Vec<Hash256> = leaves
+                .chunks(2)
+                .map(|leaves| merkle::root(leaves, 2, [0; 32]))
+                .collect();
+            let root = merkle::root(&leaves, 4, [0; 32]);
+            (
+                MerkleFile::new(root, data.len() as u64, piece_length),
+                layer,
+            )
+        };
+        let data: Vec<u8> = (0..3 * MERKLE_BLOCK).map(|i| (i / 100) as u8).collect();
+        let info = info(&[data.len() as u64], piece_length, &data);
+        let (mut full, layer) = tree(&data);
+        let bare = full.clone();
+        full.set_piece_layer(&layer);
+
+        let mut ours = Storage::new(&info, &dir);
+        ours.set_merkle(vec![Some(full)]);
+        ours.write(0, 0, &data[..piece_length as usize]).unwrap();
+        ours.write(1, 0, &data[piece_length as usize..]).unwrap();
+        assert!(ours.check().unwrap().all());
+        assert!(ours.hash_requests().is_empty());
+
+        // SHA-1 alone would pass these
+        let mut other = data.clone();
+        other[5] ^= 1;
+        let (mut wrong, layer) = tree(&other);
+        wrong.set_piece_layer(&layer);
+        let mut mismatched = Storage::new(&info, &dir);
+        mismatched.set_merkle(vec![Some(wrong)]);
+        assert!(!mismatched.verify(0).unwrap());
+        assert_eq!(mismatched.recover_piece(0).unwrap(), PieceOnDisk::Corrupt);
+        assert!(mismatched.verify(1).unwrap());
+
+        // A copy without the piece layer gets it from our answer
+        let mut theirs = Storage::new(&info, &dir);
+        theirs.set_merkle(vec![Some(bare)]);
+        let requests = theirs.hash_requests();
+        assert_eq!(requests.len(), 1);
+        let hashes = ours.answer_hashes(&requests[0]).unwrap();
+        let mut forged = hashes.clone();
+        forged[1] = [0; 32];
+        assert!(!theirs.on_hashes(&requests[0], &forged));
+        assert!(theirs.on_hashes(&requests[0], &hashes));
+        assert!(theirs.hash_requests().is_empty());
+
+        // Leaves are hashed from what's on disk
+        let leaf_request = HashRequest {
+            pieces_root: requests[0].pieces_root,
+            base_layer: 0,
+            index: 2,
+            length: 2,
+            proof_layers: 0,
+        };
+        let leaves = ours.answer_hashes(&leaf_request).unwrap();
+        assert_eq!(leaves, vec![hash_block(&data[32768..]), [0; 32]]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_read_verify() {
+        let dir = std::env::temp_dir().join(format!("hurricane-storage-{}", std::process::id()));
+        let data: Vec<u8> = (0..40u8).collect();
+        let storage = Storage::new(&info(&[7, 33], 16, &data), &dir);
+
+        assert!(!storage.check().unwrap().get(0));
+        for piece in 0..storage.num_pieces() {
+            let start = piece as usize * 16;
+            let end = start + storage.piece_size(piece) as usize;
+            storage.write(piece, 0, &data[start..end]).unwrap();
+        }
+
+        assert!(storage.check().unwrap().all());
+
+        // Block 0 of piece 0 from memory, nothing else cached so piece 2 comes from disk
+        let stats = VerifyStats::new();
+        let mut buffer = PieceBuffer {
+            piece: 0,
+            blocks: vec![Some(data[..16].into())],
+        };
+        assert!(storage.verify_buffer(&buffer, &stats).unwrap());
+        buffer.piece = 2;
+        buffer.blocks = vec![None];
+        assert!(storage.verify_buffer(&buffer, &stats).unwrap());
+        assert_eq!((stats.cached_bytes(), stats.disk_bytes()), (16, 8));
+        assert_eq!(storage.read(0, 5, 4).unwrap(), vec![5, 6, 7, 8]);
+        assert_eq!(fs::read(dir.join("t/0.bin")).unwrap(), data[..7]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_create_files() {
+        let dir = std::env::temp_dir().join(format!("hurricane-layout-{}", std::process::id()));
+        let data = vec![1; 20];
+        let storage = Storage::new(&info(&[10, 0, 10], 16, &data), &dir);
+
+        storage.create_files().unwrap();
+        assert_eq!(fs::metadata(dir.join("t/1.bin")).unwrap().len(), 0);
+        // Nothing written yet, so reads come up short instead of returning zeroes
+        assert_eq!(
+            storage.read(0, 0, 16).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        // Writes land in the right place no matter the order they arrive in
+        storage.write(1, 0, &data[16..]).unwrap();
+        storage.write(0, 0, &data[..16]).unwrap();
+        assert!(storage.check().unwrap().all());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_mmap_backend() {
+        let dir = std::env::temp_dir().join(format!("hurricane-mmap-{}", std::process::id()));
+        let data: Vec<u8> = (0..40u8).collect();
+        let info = info(&[7, 0, 33], 16, &data);
+        let storage = Storage::with_backend(&info, &dir, Backend::Mmap);
+        if cfg!(all(feature = "mmap", unix, target_pointer_width = "64")) {
+            assert_eq!(storage.backend(), Backend::Mmap);
+        }
+
+        // Not there yet, and a read must not create it
+        assert_eq!(
+            storage.read(0, 0, 4).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        for piece in (0..storage.num_pieces()).rev() {
+            let start = piece as usize * 16;
+            let end = start + storage.piece_size(piece) as usize;
+            storage.write(piece, 0, &data[start..end]).unwrap();
+        }
+        storage.flush().unwrap();
+        assert!(storage.check().unwrap().all());
+        assert_eq!(storage.read(0, 5, 4).unwrap(), vec![5, 6, 7, 8]);
+        if let Some(mapped) = &storage.mapped {
+            assert!(mapped.is_mapped(0) && mapped.is_mapped(2));
+        }
+
+        // What went through the mappings is what a plain reader sees
+        let plain = Storage::new(&info, &dir);
+        assert_eq!(plain.read(1, 0, 16).unwrap(), data[16..32]);
+        assert_eq!(fs::read(dir.join("t/2.bin")).unwrap(), data[7..]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_allocation() {
+        let dir = std::env::temp_dir().join(format!("hurricane-alloc-{}", std::process::id()));
+        let data: Vec<u8> = (0..40u8).collect();
+        let mut storage = Storage::new(&info(&[7, 0, 33], 16, &data), &dir);
+
+        for allocation in [Allocation::Sparse, Allocation::Full] {
+            storage.set_allocation(allocation);
+            storage.create_files().unwrap();
+            assert_eq!(fs::metadata(dir.join("t/0.bin")).unwrap().len(), 7);
+            assert_eq!(fs::metadata(dir.join("t/1.bin")).unwrap().len(), 0);
+            assert_eq!(fs::metadata(dir.join("t/2.bin")).unwrap().len(), 33);
+            // Allocated, not written: reads work but the pieces don't check out
+            assert_eq!(storage.read(0, 0, 4).unwrap(), vec![0; 4]);
+            assert!(storage.check().unwrap().none());
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        // Lazily created files are allocated on first write, and writes don't shrink them
+        storage.write(2, 0, &data[32..]).unwrap();
+        assert_eq!(fs::metadata(dir.join("t/2.bin")).unwrap().len(), 33);
+        storage.write(0, 0, &data[..16]).unwrap();
+        storage.write(1, 0, &data[16..32]).unwrap();
+        assert!(storage.check().unwrap().all());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_skipped_files() {
+        let dir = std::env::temp_dir().join(format!("hurricane-skipped-{}", std::process::id()));
+        let data: Vec<u8> = (0..40u8).collect();
+        let mut storage =
+            Storage::with_backend(&info(&[7, 20, 13], 16, &data), &dir, Backend::Mmap);
+        storage.set_allocation(Allocation::Full);
+        storage.set_skipped(1, true);
+
+        storage.create_files().unwrap();
+        assert!(!dir.join("t/1.bin").exists());
+        // Piece 0 is shared with the first file, so its part of the skipped file is written, but
+        // only that part
+        storage.write(0, 0, &data[..16]).unwrap();
+        assert_eq!(fs::metadata(dir.join("t/1.bin")).unwrap().len(), 9);
+        assert_eq!(storage.read(0, 0, 16).unwrap(), data[..16]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_recover_piece() {
+        let dir = std::env::temp_dir().join(format!("hurricane-recover-{}", std::process::id()));
+        let block = BLOCK_SIZE as usize;
+        let data: Vec<u8> = (0..4 * block).map(|i| (i % 251) as u8 + 1).collect();
+        let storage = Storage::new(&info(&[data.len() as u64], 2 * BLOCK_SIZE, &data), &dir);
+
+        // A crash left piece 0 finished and only the second block of piece 1 written, with a
+        // hole of zeroes before it
+        storage.write(0, 0, &data[..2 * block]).unwrap();
+        storage.write(1, BLOCK_SIZE, &data[3 * block..]).unwrap();
+        assert_eq!(storage.recover_piece(0).unwrap(), PieceOnDisk::Verified);
+        let mut second = Bitfield::new(2);
+        second.set(1);
+        assert_eq!(
+            storage.recover_piece(1).unwrap(),
+            PieceOnDisk::Partial(second)
+        );
+
+        // Every block there but the hash wrong: no telling which one is bad
+        storage.write(0, 0, &[9; 16]).unwrap();
+        assert_eq!(storage.recover_piece(0).unwrap(), PieceOnDisk::Corrupt);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+// Save-path templates like `/data/{label}/{tracker_host}/{name}`, so big collections sort
+// themselves into directories as torrents are added.
+// Variables are substituted per path component and the result is made safe to use as a single
+// component. A component that comes out empty (no label, say) is dropped instead of leaving an
+// empty directory level behind.
+use std::path::PathBuf;
+
+use super::relocate::Relocation;
+use crate::torrent::Torrent;
+
+pub const VARIABLES: &[&str] = &["name", "label", "tracker_host", "info_hash"];
+
+#[derive(PartialEq, Debug)]
+pub enum TemplateError {
+    UnknownVariable(String),
+    // A `{` without a matching `}`
+    Unclosed,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct SavePathTemplate {
+    template: String,
+}
+
+impl SavePathTemplate {
+    pub fn parse(template: &str) -> Result<SavePathTemplate, TemplateError> {
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..].find('}').ok_or(TemplateError::Unclosed)? + start;
+            let variable = &rest[start + 1..end];
+            if !VARIABLES.contains(&variable) {
+                return Err(TemplateError::UnknownVariable(variable.to_string()));
+            }
+            rest = &rest[end + 1..];
+        }
+        Ok(SavePathTemplate {
+            template: template.to_string(),
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.template
+    }
+
+    pub fn resolve(&self, torrent: &Torrent) -> PathBuf {
+        let tracker_host = torrent
+            .metainfo()
+            .and_then(|m| m.trackers().into_iter().flatten().next())
+            .and_then(|url| tracker_host(&url))
+            .unwrap_or_default();
+
+        let mut path = PathBuf::new();
+        if self.template.starts_with('/') {
+            path.push("/");
+        }
+        for component in self.template.split('/') {
+            let mut out = String::new();
+            let mut rest = component;
+            while let Some(start) = rest.find('{') {
+                out.push_str(&rest[..start]);
+                // parse() already checked every brace is closed
+                let end = rest[start..].find('}').unwrap() + start;
+                let value = match &rest[start + 1..end] {
+                    "name" => torrent.name().to_string(),
+                    "label" => torrent.label().unwrap_or_default().to_string(),
+                    "tracker_host" => tracker_host.clone(),
+                    "info_hash" => torrent.info_hash().to_hex(),
+                    _ => String::new(),
+                };
+                out.push_str(&value);
+                rest = &rest[end + 1..];
+            }
+            out.push_str(rest);
+
+            if let Some(component) = sanitize(&out) {
+                path.push(component);
+            }
+        }
+        path
+    }
+
+    // Re-resolves the template after something it depends on (usually the label) changed. If the
+    // torrent's save path moves, it's updated and the move its data needs is returned, for the
+    // caller to carry out if it wants files to follow automatically
+    pub fn relocate(&self, torrent: &mut Torrent) -> Option<Relocation> {
+        let to = self.resolve(torrent);
+        let from = torrent.save_path()?.to_path_buf();
+        if from == to {
+            return None;
+        }
+        torrent.set_save_path(to.clone());
+        Some(Relocation { from, to })
+    }
+}
+
+// Host part of a tracker URL, without userinfo or port
+fn tracker_host(url: &str) -> Option<String> {
+    let (_, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?']).next()?;
+    let host = authority.rsplit('@').next()?;
+    let host = match host.strip_prefix('[') {
+        // IPv6 literal
+        Some(v6) => v6.split(']').next()?,
+        None => host.split(':').next()?,
+    };
+    (!host.is_empty()).then(|| host.to_ascii_lowercase())
+}
+
+// Substituted values come from torrents and trackers, so nothing in them gets to add directory
+// levels or climb out of the base path
+fn sanitize(component: &str) -> Option<String> {
+    let component = component.replace(['/', '\\', '\0'], "_");
+    match component.trim() {
+        "" => None,
+        "." | ".." => Some("_".to_string()),
+        _ => Some(component),
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use std::time::Instant;
+
+    use std::fs;
+
+    use super::*;
+    use crate::metainfo::Metainfo;
+
+    fn torrent() -> Torrent {
+        let buf = include_bytes!("../../bencode/tests/fixtures/sample.torrent");
+        Torrent::new(Metainfo::from_bytes(buf).unwrap(), Instant::now())
+    }
+
+    #[test]
+    fn test_resolve() {
+        let template = SavePathTemplate::parse("/data/{label}/{tracker_host}/{name}").unwrap();
+        let mut torrent = torrent();
+        assert_eq!(
+            template.resolve(&torrent),
+            PathBuf::from("/data/tracker.example.com/sample.txt")
+        );
+
+        torrent.set_label(Some("linux isos".to_string()));
+        assert_eq!(
+            template.resolve(&torrent),
+            PathBuf::from("/data/linux isos/tracker.example.com/sample.txt")
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            SavePathTemplate::parse("/data/{nope}"),
+            Err(TemplateError::UnknownVariable("nope".to_string()))
+        );
+        assert_eq!(
+            SavePathTemplate::parse("/data/{label"),
+            Err(TemplateError::Unclosed)
+        );
+    }
+
+    #[test]
+    fn test_values_cant_escape() {
+        let template = SavePathTemplate::parse("base/{label}").unwrap();
+        let mut torrent = torrent();
+        torrent.set_label(Some("..".to_string()));
+        assert_eq!(template.resolve(&torrent), PathBuf::from("base/_"));
+
+        torrent.set_label(Some("a/../../etc".to_string()));
+        assert_eq!(
+            template.resolve(&torrent),
+            PathBuf::from("base/a_.._.._etc")
+        );
+    }
+
+    #[test]
+    fn test_tracker_host() {
+        assert_eq!(
+            tracker_host("udp://user:pw@Tracker.Example.org:1337/announce"),
+            Some("tracker.example.org".to_string())
+        );
+        assert_eq!(
+            tracker_host("http://[::1]:80/announce"),
+            Some("::1".to_string())
+        );
+        assert_eq!(tracker_host("not a url"), None);
+    }
+
+    #[test]
+    fn test_relocate_on_label_change() {
+        let dir = std::env::temp_dir().join(format!("hurricane-template-{}", std::process::id()));
+        let template =
+            SavePathTemplate::parse(&format!("{}/{{label}}", dir.to_str().unwrap())).unwrap();
+        let mut torrent = torrent();
+        torrent.set_save_path(template.resolve(&torrent));
+        assert_eq!(template.relocate(&mut torrent), None);
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("sample.txt"), b"data").unwrap();
+
+        torrent.set_label(Some("done".to_string()));
+        let relocation = template.relocate(&mut torrent).unwrap();
+        assert_eq!(torrent.save_path(), Some(dir.join("done").as_path()));
+        relocation
+            .apply(&torrent.metainfo().unwrap().info.files)
+            .unwrap();
+        assert_eq!(fs::read(dir.join("done/sample.txt")).unwrap(), b"data");
+        assert!(!dir.join("sample.txt").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
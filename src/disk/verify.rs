@@ -0,0 +1,215 @@
+// Hash checks of finished pieces, off the network thread. At gigabit rates SHA-1 alone keeps a
+// core busy, so pieces are hashed on a pool of their own and the results picked up with `poll`.
+// A piece that fails goes back to the picker to be downloaded again, and every peer that sent
+// us part of it gets a strike. A peer that sent all of a bad piece, or keeps turning up in bad
+// ones, is reported back so the caller can disconnect and ban it.
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use super::cache::{PieceBuffer, VerifyStats};
+use super::queue::{DiskScheduler, IoClass};
+use super::storage::Storage;
+use crate::picker::PiecePicker;
+
+// Bad pieces a peer can have a hand in before we give up on it
+pub const MAX_HASH_STRIKES: u32 = 3;
+
+#[derive(PartialEq, Debug)]
+pub struct Verified<P> {
+    pub piece: u32,
+    // Ok(false) is a hash mismatch. An error reading back what the cache no longer had counts as
+    // a failure too, the piece gets downloaded again either way
+    pub result: Result<bool, io::ErrorKind>,
+    // Peers to disconnect and ban because of this piece
+    pub banned: Vec<P>,
+}
+
+struct Job<P> {
+    piece: u32,
+    result: Result<bool, io::ErrorKind>,
+    peers: Vec<P>,
+}
+
+pub struct Verifier<P> {
+    storage: Arc<Storage>,
+    pool: DiskScheduler,
+    stats: Arc<VerifyStats>,
+    done_tx: Sender<Job<P>>,
+    done_rx: Receiver<Job<P>>,
+    in_flight: usize,
+    strikes: HashMap<P, u32>,
+}
+
+impl<P: Eq + Hash + Clone + Send + 'static> Verifier<P> {
+    pub fn new(storage: Arc<Storage>, threads: usize) -> Self {
+        let (done_tx, done_rx) = mpsc::channel();
+        Verifier {
+            storage,
+            pool: DiskScheduler::new(threads),
+            stats: Arc::new(VerifyStats::new()),
+            done_tx,
+            done_rx,
+            in_flight: 0,
+            strikes: HashMap::new(),
+        }
+    }
+
+    pub fn stats(&self) -> &VerifyStats {
+        &self.stats
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    pub fn strikes(&self, peer: &P) -> u32 {
+        self.strikes.get(peer).copied().unwrap_or(0)
+    }
+
+    // Queues a piece the picker just reported complete. `peers` is everyone who sent us a block
+    // of it, duplicates are fine
+    pub fn submit(&mut self, buffer: PieceBuffer, peers: Vec<P>) {
+        let storage = Arc::clone(&self.storage);
+        let stats = Arc::clone(&self.stats);
+        let done = self.done_tx.clone();
+        self.in_flight += 1;
+        self.pool.submit(IoClass::Normal, move || {
+            let result = storage.verify_buffer(&buffer, &stats).map_err(|e| e.kind());
+            let _ = done.send(Job {
+                piece: buffer.piece,
+                result,
+                peers,
+            });
+        });
+    }
+
+    // The next finished check, already applied to the picker: a good piece is marked as had, a
+    // bad one is started over. The caller still has to drop the piece from its write cache
+    pub fn poll(&mut self, picker: &mut PiecePicker) -> Option<Verified<P>> {
+        let job = self.done_rx.try_recv().ok()?;
+        self.in_flight -= 1;
+
+        let mut banned = vec![];
+        match job.result {
+            Ok(true) => picker.piece_verified(job.piece),
+            Ok(false) => {
+                picker.piece_failed(job.piece);
+                banned = self.strike(job.peers);
+            }
+            // Nobody's fault, so no strikes
+            Err(_) => picker.piece_failed(job.piece),
+        }
+        Some(Verified {
+            piece: job.piece,
+            result: job.result,
+            banned,
+        })
+    }
+
+    // Returns who's out
+    fn strike(&mut self, peers: Vec<P>) -> Vec<P> {
+        let mut unique: Vec<P> = vec![];
+        for peer in peers {
+            if !unique.contains(&peer) {
+                unique.push(peer);
+            }
+        }
+        let sole = unique.len() == 1;
+        let mut banned = vec![];
+        for peer in unique {
+            let strikes = self.strikes.entry(peer.clone()).or_insert(0);
+            *strikes += 1;
+            if sole || *strikes >= MAX_HASH_STRIKES {
+                self.strikes.remove(&peer);
+                banned.push(peer);
+            }
+        }
+        banned
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::metainfo::{FileEntry, Info};
+    use sha1::{Digest, Sha1};
+    use std::time::{Duration, Instant};
+
+    fn verifier(data: &[u8]) -> (Verifier<u8>, PiecePicker) {
+        let info = Info {
+            name: "t".to_string(),
+            piece_length: 16,
+            pieces: data.chunks(16).map(|p| Sha1::digest(p).into()).collect(),
+            files: vec![FileEntry {
+                path: vec!["t.bin".to_string()],
+                length: data.len() as u64,
+                pieces_root: None,
+            }],
+            private: false,
+        };
+        let storage = Storage::new(&info, &std::env::temp_dir().join("hurricane-verify-none"));
+        let picker = PiecePicker::new(info.pieces.len(), 16, data.len() as u64);
+        (Verifier::new(Arc::new(storage), 2), picker)
+    }
+
+    fn buffer(piece: u32, data: &[u8]) -> PieceBuffer {
+        PieceBuffer {
+            piece,
+            blocks: vec![Some(data.into())],
+        }
+    }
+
+    fn wait(verifier: &mut Verifier<u8>, picker: &mut PiecePicker) -> Verified<u8> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if let Some(verified) = verifier.poll(picker) {
+                return verified;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!("no result");
+    }
+
+    #[test]
+    fn test_good_and_bad_pieces() {
+        let data: Vec<u8> = (0..32).collect();
+        let (mut verifier, mut picker) = verifier(&data);
+
+        verifier.submit(buffer(0, &data[..16]), vec![1, 2]);
+        let verified = wait(&mut verifier, &mut picker);
+        assert_eq!(verified.result, Ok(true));
+        assert!(picker.have().get(0));
+
+        verifier.submit(buffer(1, &[0; 16]), vec![1, 2]);
+        let verified = wait(&mut verifier, &mut picker);
+        assert_eq!(verified.result, Ok(false));
+        assert!(verified.banned.is_empty());
+        assert!(!picker.have().get(1));
+        assert_eq!(verifier.strikes(&1), 1);
+        assert_eq!(verifier.in_flight(), 0);
+        assert_eq!(verifier.stats().cached_bytes(), 32);
+    }
+
+    #[test]
+    fn test_bans() {
+        let data: Vec<u8> = (0..32).collect();
+        let (mut verifier, mut picker) = verifier(&data);
+
+        // All of it came from one peer, so there's no doubt whose fault it was
+        verifier.submit(buffer(1, &[0; 16]), vec![3, 3]);
+        assert_eq!(wait(&mut verifier, &mut picker).banned, vec![3]);
+
+        for round in 1..=MAX_HASH_STRIKES {
+            verifier.submit(buffer(1, &[0; 16]), vec![1, 2]);
+            let banned = wait(&mut verifier, &mut picker).banned;
+            if round < MAX_HASH_STRIKES {
+                assert!(banned.is_empty());
+            } else {
+                assert_eq!(banned, vec![1, 2]);
+            }
+        }
+    }
+}
@@ -0,0 +1,358 @@
+// Hostname lookups for trackers and web seeds, and dialing what they return.
+// Lookups go through a process-wide cache, so a swarm's worth of torrents on the same tracker
+// cost one lookup rather than one per announce. The system resolver doesn't tell us record TTLs,
+// so answers are kept for `TTL` and failures for `NEGATIVE_TTL`. Past its TTL an answer is still
+// used, for up to `MAX_STALE`, while a fresh lookup runs in the background: a slow or dead
+// resolver delays nothing once a host has been seen.
+// Hosts with addresses in both families are dialed Happy Eyeballs style (RFC 8305): addresses
+// alternate between families, starting with the one the resolver put first, and each attempt
+// gets `STAGGER` before the next one starts alongside it. The first to succeed wins. A broken
+// IPv6 route then costs a quarter of a second instead of a connect timeout. `race` does this
+// for any kind of attempt, e.g. a UDP tracker's connect exchange. Peers come to us as addresses
+// rather than names, one family each, so there's nothing to race when dialing them.
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const TTL: Duration = Duration::from_secs(5 * 60);
+pub const NEGATIVE_TTL: Duration = Duration::from_secs(30);
+pub const MAX_STALE: Duration = Duration::from_secs(60 * 60);
+
+// RFC 8305's recommended connection attempt delay
+pub const STAGGER: Duration = Duration::from_millis(250);
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Lookup {
+    Fresh(Vec<SocketAddr>),
+    // Past its TTL. Use it, but look the host up again
+    Stale(Vec<SocketAddr>),
+    // Failed recently, no point asking again yet
+    Failed(io::ErrorKind),
+    Miss,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    result: Result<Vec<SocketAddr>, io::ErrorKind>,
+    at: Instant,
+    // A background lookup is on its way
+    refreshing: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct DnsCache {
+    entries: BTreeMap<(String, u16), Entry>,
+}
+
+impl DnsCache {
+    pub const fn new() -> Self {
+        DnsCache {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, host: &str, port: u16, now: Instant) -> Lookup {
+        let Some(entry) = self.entries.get(&(host.to_string(), port)) else {
+            return Lookup::Miss;
+        };
+        let age = now.saturating_duration_since(entry.at);
+        match &entry.result {
+            Ok(addrs) if age < TTL => Lookup::Fresh(addrs.clone()),
+            Ok(addrs) if age < MAX_STALE => Lookup::Stale(addrs.clone()),
+            Err(kind) if age < NEGATIVE_TTL => Lookup::Failed(*kind),
+            _ => Lookup::Miss,
+        }
+    }
+
+    // A failure doesn't replace an answer that's still usable, stale or not
+    pub fn insert(
+        &mut self,
+        host: &str,
+        port: u16,
+        result: Result<Vec<SocketAddr>, io::ErrorKind>,
+        now: Instant,
+    ) {
+        let key = (host.to_string(), port);
+        if result.is_err()
+            && let Some(entry) = self.entries.get_mut(&key)
+            && entry.result.is_ok()
+            && now.saturating_duration_since(entry.at) < MAX_STALE
+        {
+            entry.refreshing = false;
+            return;
+        }
+        self.entries.insert(
+            key,
+            Entry {
+                result,
+                at: now,
+                refreshing: false,
+            },
+        );
+    }
+
+    // Whether the caller should start the background lookup for a stale entry. Only the first
+    // to ask gets to
+    fn claim_refresh(&mut self, host: &str, port: u16) -> bool {
+        match self.entries.get_mut(&(host.to_string(), port)) {
+            Some(entry) if !entry.refreshing => {
+                entry.refreshing = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Drops what's too old to be used even stale
+    pub fn expire(&mut self, now: Instant) {
+        self.entries.retain(|_, entry| {
+            let age = now.saturating_duration_since(entry.at);
+            match entry.result {
+                Ok(_) => age < MAX_STALE,
+                Err(_) => age < NEGATIVE_TTL,
+            }
+        });
+    }
+}
+
+static CACHE: Mutex<DnsCache> = Mutex::new(DnsCache::new());
+
+// A panic elsewhere while holding the lock doesn't make the cache wrong
+fn cache() -> MutexGuard<'static, DnsCache> {
+    CACHE.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn lookup(host: &str, port: u16) -> Result<Vec<SocketAddr>, io::ErrorKind> {
+    match (host, port).to_socket_addrs() {
+        Ok(addrs) => {
+            let addrs: Vec<SocketAddr> = addrs.collect();
+            match addrs.is_empty() {
+                true => Err(io::ErrorKind::NotFound),
+                false => Ok(addrs),
+            }
+        }
+        Err(err) => Err(err.kind()),
+    }
+}
+
+// `host`'s addresses, through the cache. IP literals skip it
+pub fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    if let Ok(ip) = host.parse() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let now = Instant::now();
+    {
+        let mut cached = cache();
+        cached.expire(now);
+        match cached.get(host, port, now) {
+            Lookup::Fresh(addrs) => return Ok(addrs),
+            Lookup::Stale(addrs) => {
+                if cached.claim_refresh(host, port) {
+                    let host = host.to_string();
+                    thread::spawn(move || {
+                        let result = self::lookup(&host, port);
+                        cache().insert(&host, port, result, Instant::now());
+                    });
+                }
+                return Ok(addrs);
+            }
+            Lookup::Failed(kind) => return Err(kind.into()),
+            Lookup::Miss => {}
+        }
+    }
+    // Not holding the cache while we wait on the resolver
+    let result = self::lookup(host, port);
+    cache().insert(host, port, result.clone(), Instant::now());
+    result.map_err(io::Error::from)
+}
+
+// RFC 8305 order: alternating families, starting with the first address's
+pub fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return vec![];
+    };
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addrs
+        .iter()
+        .copied()
+        .partition(|addr| addr.is_ipv6() == first.is_ipv6());
+    preferred.reverse();
+    other.reverse();
+    let mut sorted = Vec::with_capacity(addrs.len());
+    while let Some(addr) = preferred.pop() {
+        sorted.push(addr);
+        sorted.extend(other.pop());
+    }
+    sorted.extend(other.into_iter().rev());
+    sorted
+}
+
+// Runs `attempt` for each address in `interleave` order, starting the next one `STAGGER` after
+// the last, or as soon as it fails, until one succeeds. Losing attempts run to the end on their
+// own threads and are dropped, so `attempt` has to give up by itself eventually. Otherwise the
+// last error
+pub fn race<T, E, F>(addrs: &[SocketAddr], attempt: F) -> Result<T, E>
+where
+    T: Send + 'static,
+    E: From<io::Error> + Send + 'static,
+    F: Fn(SocketAddr) -> Result<T, E> + Send + Sync + 'static,
+{
+    let addrs = interleave(addrs);
+    match addrs[..] {
+        [] => return Err(io::Error::from(io::ErrorKind::NotFound).into()),
+        [addr] => return attempt(addr),
+        _ => {}
+    }
+    let attempt = Arc::new(attempt);
+    let (results, finished) = mpsc::channel();
+    let mut started = 0;
+    let mut failed = 0;
+    loop {
+        // Up front, once the last one has had its head start, or as soon as one fails
+        if started < addrs.len() {
+            let attempt = attempt.clone();
+            let results = results.clone();
+            let addr = addrs[started];
+            thread::spawn(move || {
+                let _ = results.send(attempt(addr));
+            });
+            started += 1;
+        }
+        let result = match started < addrs.len() {
+            true => match finished.recv_timeout(STAGGER) {
+                Ok(result) => result,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => unreachable!(),
+            },
+            // Every attempt ends on its own
+            false => finished.recv().unwrap(),
+        };
+        match result {
+            Ok(value) => return Ok(value),
+            Err(err) => {
+                failed += 1;
+                if failed == addrs.len() {
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+// A TCP connection to `host`, racing its addresses. `timeout` is for each address
+pub fn connect(host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+    let addrs = resolve(host, port)?;
+    race(&addrs, move |addr| {
+        TcpStream::connect_timeout(&addr, timeout)
+    })
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    fn addr(text: &str) -> SocketAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cache() {
+        let now = Instant::now();
+        let addrs = vec![addr("192.0.2.1:80")];
+        let mut cache = DnsCache::new();
+        assert_eq!(cache.get("tracker", 80, now), Lookup::Miss);
+        cache.insert("tracker", 80, Ok(addrs.clone()), now);
+        assert_eq!(cache.get("tracker", 80, now), Lookup::Fresh(addrs.clone()));
+        assert_eq!(cache.get("tracker", 81, now), Lookup::Miss);
+        assert_eq!(
+            cache.get("tracker", 80, now + TTL),
+            Lookup::Stale(addrs.clone())
+        );
+        assert!(cache.claim_refresh("tracker", 80));
+        assert!(!cache.claim_refresh("tracker", 80));
+
+        // A failed refresh keeps the stale answer, and lets the next one try again
+        let later = now + TTL * 2;
+        cache.insert("tracker", 80, Err(io::ErrorKind::TimedOut), later);
+        assert_eq!(cache.get("tracker", 80, later), Lookup::Stale(addrs));
+        assert!(cache.claim_refresh("tracker", 80));
+        assert_eq!(cache.get("tracker", 80, now + MAX_STALE), Lookup::Miss);
+
+        cache.insert("gone", 80, Err(io::ErrorKind::NotFound), now);
+        assert_eq!(
+            cache.get("gone", 80, now),
+            Lookup::Failed(io::ErrorKind::NotFound)
+        );
+        cache.expire(now + NEGATIVE_TTL);
+        assert_eq!(cache.len(), 1);
+        cache.expire(now + MAX_STALE);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_interleave() {
+        let v6 = [addr("[2001:db8::1]:80"), addr("[2001:db8::2]:80")];
+        let v4 = [
+            addr("192.0.2.1:80"),
+            addr("192.0.2.2:80"),
+            addr("192.0.2.3:80"),
+        ];
+        assert_eq!(
+            interleave(&[v6[0], v6[1], v4[0], v4[1], v4[2]]),
+            [v6[0], v4[0], v6[1], v4[1], v4[2]]
+        );
+        assert_eq!(interleave(&[v4[0], v6[0], v4[1]]), [v4[0], v6[0], v4[1]]);
+        assert_eq!(interleave(&[]), []);
+    }
+
+    #[test]
+    fn test_race() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let good = listener.local_addr().unwrap();
+        // Nothing answers there, the way a broken IPv6 route doesn't
+        let dead = addr("[2001:db8::1]:80");
+        let start = Instant::now();
+        let stream = race(&[dead, good], move |addr| {
+            if addr == dead {
+                thread::sleep(Duration::from_secs(2));
+                return Err(io::Error::from(io::ErrorKind::TimedOut));
+            }
+            TcpStream::connect_timeout(&addr, Duration::from_secs(1))
+        })
+        .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), good);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // Failures don't wait out the stagger, and the last one is what's returned
+        let start = Instant::now();
+        let result: io::Result<()> = race(&[dead, good, dead], |addr| {
+            Err(io::Error::other(addr.to_string()))
+        });
+        assert!(start.elapsed() < STAGGER);
+        assert!(result.is_err());
+        assert_eq!(
+            race::<(), io::Error, _>(&[], |_| Ok(()))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+
+        assert_eq!(
+            resolve("192.0.2.7", 6969).unwrap(),
+            [addr("192.0.2.7:6969")]
+        );
+    }
+}
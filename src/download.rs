@@ -0,0 +1,2794 @@
+// One torrent, downloaded start to finish: what `hurricane download` runs. Peers come from the
+// torrent's trackers, the DHT, a magnet link's x.pe peers and whoever connects to us; a torrent
+// added by magnet link gets its info dict from them first (BEP 9).
+// Like the examples it's plain blocking sockets and a thread per peer, with the torrent behind a
+// mutex they share. `poll` runs the choker over every connection, batches our haves and picks up
+// the hash checks of finished pieces, which run on a `Verifier` pool off the peer threads; each
+// peer's thread keeps its requests in a `RequestPipeline` and answers requests the way BEP 6 has
+// it. Good for one torrent and a few dozen peers, which is all a foreground download needs.
+// Once complete it keeps seeding until the `seed_after` goals are met, if there are any.
+// Connections use MSE as `encryption` says, and go through the proxy if there is one; so do the
+// announces and the DHT. Public torrents also find peers through PEX and LSD, and a torrent
+// with web seeds (BEP 19) gets runs of pieces from them over HTTP too. A PEX peer we can't reach
+// gets a rendezvous through a peer that does ut_holepunch (BEP 55), and we relay theirs. The rate limits
+// are token buckets the peer threads draw from: requests are paced to the download limit, and
+// serving a block waits for the upload one. What else we send has a small bucket of its own, and
+// only takes from the upload limit beyond that.
+// The daemon runs one of these for each of its session's active torrents, `attach`ed to the
+// torrent in the session: the daemon listens for all of them and hands each its connections,
+// and the session keeps ticking the torrent and publishing its events.
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, mpsc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::bitfield::Bitfield;
+use crate::blocklist::{Attempt, IpFilter};
+use crate::dht::node::{DhtConfig, DhtEvent};
+use crate::dht::socket::DhtSocket;
+use crate::disk::{
+    BlockCache, IoCache, IoCacheConfig, IoCacheStats, PieceBuffer, Storage, Verifier, WriteRun,
+    recover,
+};
+use crate::infohash::InfoHash;
+use crate::limiter::{RateLimiter, Traffic};
+use crate::lsd::Lsd;
+use crate::lsd::socket::LsdSocket;
+use crate::metainfo::{HashRequest, Metainfo};
+use crate::peer::candidates::{BanReason, PeerSource};
+use crate::peer::choker::{ChokeCandidate, Choker, ChokerConfig};
+use crate::peer::client_ident::{self, Quirks};
+use crate::peer::extension::{self, ExtensionHandshake, UT_HOLEPUNCH, UT_METADATA, UT_PEX};
+use crate::peer::fast::{FastState, RequestAction};
+use crate::peer::handshake::{Feature, HANDSHAKE_LEN, Handshake, Reserved, generate_peer_id};
+use crate::peer::have::{HaveBroadcaster, HaveConfig};
+use crate::peer::holepunch::{self, ErrorCode, HolepunchEvent, HolepunchMessage, Holepunches};
+use crate::peer::listen::{IpFamilies, Listeners};
+use crate::peer::message::{Message, MessageError};
+use crate::peer::metadata::{MetadataDownload, MetadataMessage, serve_piece};
+use crate::peer::mse::{Cipher, EncryptionPolicy, MseHandshake};
+use crate::peer::pex::{PEX_INTERVAL, PexFlags, PexState};
+use crate::peer::pipeline::{PipelineConfig, RequestPipeline};
+use crate::peer::stats::{PeerFlags, PeerStats};
+use crate::peer::{BLOCK_SIZE, Block, DEFAULT_MAX_SERVED_REQUEST};
+use crate::proxy::{self, ProxyConfig, Target};
+use crate::rng::Rng;
+use crate::schedule::{BandwidthSchedule, BandwidthScheduler, LocalTime, RateLimits};
+use crate::suspend::SuspendDetector;
+use crate::torrent::{SeedGoals, Torrent, TorrentStatus};
+use crate::tracker::{self, AnnounceEvent, AnnounceRequest};
+use crate::webseed::{self, RangeRequest, WebSeedError, WebSeeds};
+
+const CLIENT: &str = concat!("Hurricane ", env!("CARGO_PKG_VERSION"));
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// How long a peer thread blocks on a read before looking around for haves to send
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+// A peer that sends nothing at all for this long is dropped
+const IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+const KEEP_ALIVE: Duration = Duration::from_secs(90);
+const DHT_LOOKUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// Threads hashing finished pieces
+const HASH_THREADS: usize = 2;
+// Bytes of blocks kept for them meanwhile
+const WRITE_CACHE: usize = 16 << 20;
+// Pieces a web seed is asked for at once, as few range requests as the files allow
+const WEB_SEED_PIECES: usize = 4;
+const WEB_SEED_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct DownloadConfig {
+    pub output_dir: PathBuf,
+    // Where we listen for peers, and what we announce. 0 picks a free port
+    pub port: u16,
+    pub max_peers: usize,
+    pub dht: bool,
+    // Seed once complete until one of these is met. None stops as soon as it's complete
+    pub seed_after: Option<SeedGoals>,
+    // Checked for every address we hear of, dial or get a connection from
+    pub ip_filter: IpFilter,
+    // Upload slots and how often they're handed out
+    pub choker: ChokerConfig,
+    // How many requests each peer gets, and when one that sends nothing is snubbing us
+    pub pipeline: PipelineConfig,
+    // How our haves are batched, and whether new peers get a lazy bitfield
+    pub haves: HaveConfig,
+    // How long downloaded blocks wait to be written along with their neighbours, and how much is
+    // kept in memory for peers' reads
+    pub io_cache: IoCacheConfig,
+    // MSE (BEP 8) for the connections we make, and what we take from those we accept
+    pub encryption: EncryptionPolicy,
+    // Peer exchange (BEP 11) and local service discovery (BEP 14), never for private torrents
+    pub pex: bool,
+    pub lsd: bool,
+    // What peers, trackers and the DHT are reached through, for torrents without a proxy of
+    // their own. Behind a strict one we don't listen, and LSD stays off
+    pub proxy: Option<ProxyConfig>,
+    // Session-wide rates, shared with whoever else has a clone
+    pub limiter: RateLimiter,
+    // The limiter's limits over the week, applied on `poll`. Attached downloads leave that to
+    // the session
+    pub schedule: Option<BandwidthSchedule>,
+}
+
+impl DownloadConfig {
+    pub fn new(output_dir: PathBuf) -> Self {
+        DownloadConfig {
+            output_dir,
+            port: 6881,
+            max_peers: 40,
+            dht: true,
+            seed_after: None,
+            ip_filter: IpFilter::new(),
+            choker: ChokerConfig::default(),
+            pipeline: PipelineConfig::default(),
+            haves: HaveConfig::default(),
+            io_cache: IoCacheConfig::default(),
+            encryption: EncryptionPolicy::default(),
+            pex: true,
+            lsd: true,
+            proxy: None,
+            limiter: RateLimiter::default(),
+            schedule: None,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum DownloadState {
+    Running,
+    // Complete, and seeded as long as it was asked to
+    Done,
+    // Something went wrong that retrying won't fix, e.g. the disk is full
+    Failed(String),
+}
+
+struct Shared {
+    info_hash: InfoHash,
+    peer_id: [u8; 20],
+    port: u16,
+    torrent: Arc<Mutex<Torrent>>,
+    // Set once we have the info dict and know what's on disk already
+    storage: OnceLock<Arc<Storage>>,
+    // Hashes the pieces peers finish, set along with `storage`. Locked after `torrent`
+    verifier: OnceLock<Mutex<Verifier<SocketAddr>>>,
+    // The blocks of pieces that haven't been verified yet, so hashing them needn't read them
+    // back. Locked last, after whatever else is needed
+    cache: Mutex<BlockCache>,
+    // In front of `storage` for everything else, set along with it: writes wait there to be
+    // coalesced, reads are answered from it. Locked last too
+    io_cache: OnceLock<Mutex<IoCache<SocketAddr>>>,
+    io_cache_config: IoCacheConfig,
+    // The torrent's web seeds, once `web_seed_loop` has the info dict. Locked after `torrent` and
+    // `verifier`
+    web_seeds: OnceLock<Mutex<WebSeeding>>,
+    metadata: Mutex<Option<MetadataDownload>>,
+    // Locked after `torrent` when both are needed, never before
+    swarm: Mutex<Swarm>,
+    pipeline: PipelineConfig,
+    encryption: EncryptionPolicy,
+    pex: bool,
+    proxy: Option<ProxyConfig>,
+    // The session's limits, and this torrent's own from its `TorrentLimits`
+    limiter: RateLimiter,
+    own_limiter: RateLimiter,
+    // Who sent blocks of each piece that's yet to pass its hash check
+    senders: Mutex<HashMap<u32, Vec<SocketAddr>>>,
+    // Locked after `torrent` and `swarm`
+    holepunch: Mutex<Holepunching>,
+    failure: Mutex<Option<String>>,
+    // Where our DHT node listens, once it does. 0 before that, and behind a proxy
+    dht_port: AtomicU16,
+    // Bumped by `Download::on_wake`. Each thread keeps the last one it saw
+    wakes: AtomicU64,
+    stop: AtomicBool,
+    // Stopping without a `stopped` announce, someone else sends it
+    quiet: AtomicBool,
+}
+
+// Web seeds and what their pieces are up to
+struct WebSeeding {
+    seeds: WebSeeds,
+    // Who sent blocks of each piece that's yet to pass its hash check, like `Shared::senders`
+    senders: HashMap<u32, Vec<usize>>,
+    // Pieces a seed sent bad data for that it gets to fetch again itself
+    refetch: Vec<(usize, u32)>,
+}
+
+// Our rendezvous in flight, and the peers relays told us to connect to, for `Download::poll`
+#[derive(Default)]
+struct Holepunching {
+    holepunches: Holepunches,
+    dial: Vec<SocketAddr>,
+}
+
+// Where a block came from, to be blamed or credited once its piece is hashed
+#[derive(Clone, Copy)]
+enum Source {
+    Peer(SocketAddr),
+    WebSeed(usize),
+}
+
+// What's decided for every connection at once
+struct Swarm {
+    // Connected peers, or ones we're connecting to
+    peers: HashMap<SocketAddr, Connection>,
+    choker: Choker<SocketAddr>,
+    haves: HaveBroadcaster,
+}
+
+// What a peer's thread and `Download::poll` share about its connection. The thread keeps its own
+// copy of the rest and brings this up to date as it goes
+struct Connection {
+    stats: PeerStats,
+    // They want something we have
+    interested: bool,
+    // Their pieces, once they've been told ours, for smart haves
+    has: Option<Bitfield>,
+    // The choker's say, which the thread passes on
+    unchoked: bool,
+    // Haves for the thread to send
+    haves: Vec<u32>,
+    // They do ut_holepunch, so we can introduce others to them
+    holepunch: bool,
+    // For the thread to send too: our rendezvous when they're the relay, and connects from us
+    // when we're the relay introducing someone to them
+    holepunch_messages: Vec<HolepunchMessage>,
+}
+
+impl Connection {
+    fn new(stats: PeerStats) -> Self {
+        Connection {
+            stats,
+            interested: false,
+            has: None,
+            unchoked: false,
+            haves: vec![],
+            holepunch: false,
+            holepunch_messages: vec![],
+        }
+    }
+}
+
+pub struct Download {
+    shared: Arc<Shared>,
+    config: DownloadConfig,
+    // Connections come through `accept` instead
+    attached: bool,
+    // None when attached, or behind a strict proxy
+    listeners: Option<Listeners>,
+    bandwidth: Option<BandwidthScheduler>,
+    announcer: Option<JoinHandle<()>>,
+    // The last peer dropped to make room for a better candidate, until its thread is gone
+    dropping: Option<SocketAddr>,
+    // Run on our own, noticing a suspend is up to us
+    suspend: SuspendDetector,
+}
+
+impl Download {
+    // Checks what's in `output_dir` already, then starts looking for peers. The torrent's save
+    // path is set to `output_dir`
+    pub fn start(mut torrent: Torrent, config: DownloadConfig) -> io::Result<Download> {
+        let listeners = match &config.proxy {
+            Some(proxy) if !proxy.allows_direct() => None,
+            _ => Some(Listeners::bind(config.port, IpFamilies::default())?),
+        };
+        let port = listeners
+            .iter()
+            .flat_map(Listeners::local_addrs)
+            .next()
+            .map_or(config.port, |addr| addr.port());
+        torrent.set_save_path(config.output_dir.clone());
+        torrent.peer_list_mut().set_filter(config.ip_filter.clone());
+        let mut rng = Rng::new();
+        let peer_id = generate_peer_id(&mut rng);
+        let key = rng.next_u64() as u32;
+        let torrent = Arc::new(Mutex::new(torrent));
+        let shared = Shared::new(torrent, &config, peer_id, port);
+        shared.check_files()?;
+        Ok(Download::launch(shared, config, false, listeners, key))
+    }
+
+    // Runs the peers of a torrent that lives in a session, as `config.port` and announcing with
+    // `peer_id` and `key`, like every other torrent of the session. The session should have
+    // checked its files already. Hand it the connections for it with `accept`; its status, ticks
+    // and events stay the session's business, so `poll` only reports failures
+    pub fn attach(
+        torrent: Arc<Mutex<Torrent>>,
+        config: DownloadConfig,
+        peer_id: [u8; 20],
+        key: u32,
+    ) -> Download {
+        let storage = torrent.lock().unwrap().storage();
+        let shared = Shared::new(torrent, &config, peer_id, config.port);
+        if let Some(storage) = storage {
+            shared.set_storage(storage);
+            shared.verify_received();
+        }
+        Download::launch(shared, config, true, None, key)
+    }
+
+    fn launch(
+        shared: Arc<Shared>,
+        config: DownloadConfig,
+        attached: bool,
+        listeners: Option<Listeners>,
+        key: u32,
+    ) -> Download {
+        let (has_trackers, private) = {
+            let torrent = shared.torrent();
+            (!torrent.trackers().tiers().is_empty(), torrent.is_private())
+        };
+        let direct = shared.proxy().is_none_or(|proxy| proxy.allows_direct());
+        let announcer = has_trackers.then(|| {
+            let shared = shared.clone();
+            thread::spawn(move || announce_loop(&shared, key))
+        });
+        if config.dht && !private {
+            let shared = shared.clone();
+            thread::spawn(move || dht_loop(&shared));
+        }
+        if config.lsd && !private && direct {
+            let shared = shared.clone();
+            thread::spawn(move || lsd_loop(&shared));
+        }
+        if direct {
+            let shared = shared.clone();
+            thread::spawn(move || web_seed_loop(&shared));
+        }
+        let bandwidth = match &config.schedule {
+            Some(schedule) if !attached => Some(BandwidthScheduler::new(schedule.clone())),
+            _ => None,
+        };
+        Download {
+            shared,
+            config,
+            attached,
+            listeners,
+            bandwidth,
+            announcer,
+            dropping: None,
+            suspend: SuspendDetector::default(),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.shared.port
+    }
+
+    // The UDP port of the torrent's DHT node, for port mapping. None until it's listening, or
+    // when it talks through a proxy
+    pub fn dht_port(&self) -> Option<u16> {
+        Some(self.shared.dht_port.load(Ordering::SeqCst)).filter(|port| *port != 0)
+    }
+
+    // Hold it briefly: every peer thread needs it too
+    pub fn torrent(&self) -> MutexGuard<'_, Torrent> {
+        self.shared.torrent()
+    }
+
+    // Call a few times a second. Takes incoming connections, connects to more peers while there's
+    // room, makes room for better ones when there isn't, and keeps the torrent's rates and peer
+    // counts current
+    pub fn poll(&mut self, now: Instant) -> DownloadState {
+        if !self.attached && self.suspend.check(now, SystemTime::now()).is_some() {
+            self.on_wake(now);
+        }
+        while let Some(Ok(Some((stream, addr)))) = self.listeners.as_ref().map(Listeners::accept) {
+            self.on_incoming(addr, Dial::Accepted(stream), now);
+        }
+        if let Some(bandwidth) = &mut self.bandwidth {
+            let at = LocalTime::from_system(SystemTime::now(), local_utc_offset());
+            if let Some(limits) = bandwidth.poll(at) {
+                self.config.limiter.set_limits(limits, now);
+            }
+        }
+        self.on_verified(now);
+        self.shared.flush_writes(now);
+        self.dial_holepunched(now);
+
+        let room = self
+            .config
+            .max_peers
+            .saturating_sub(self.shared.num_peers());
+        let candidates = self.shared.torrent().peer_list().next_candidates(room, now);
+        for addr in candidates {
+            // Blocked since it was listed, by a reload
+            if !self.config.ip_filter.allows(addr.ip(), Attempt::Dial) {
+                self.shared.torrent().peer_list_mut().remove(&addr);
+                continue;
+            }
+            self.shared.torrent().peer_list_mut().on_connecting(&addr);
+            let stats = PeerStats::new(addr, PeerFlags::default(), now);
+            self.shared
+                .swarm()
+                .peers
+                .insert(addr, Connection::new(stats));
+            self.spawn_peer(addr, Dial::Connect);
+        }
+        // Its thread sees it's been dropped within a second, and the slot goes to the best
+        // candidate on a later poll. Until then the candidate still looks like it wants a slot,
+        // so nobody else is dropped for it
+        let dropping = self
+            .dropping
+            .is_some_and(|addr| self.shared.swarm().peers.contains_key(&addr));
+        if room == 0 && !dropping {
+            let mut torrent = self.shared.torrent();
+            if let Some((worst, _)) = torrent.peer_list().replacement(now) {
+                torrent.peer_list_mut().on_disconnected(&worst, now);
+                self.dropping = Some(worst);
+            }
+        }
+
+        let seeding = self.shared.torrent().status() == TorrentStatus::Seeding;
+        let mut stats: Vec<PeerStats> = {
+            let mut swarm = self.shared.swarm();
+            let swarm = &mut *swarm;
+            for conn in swarm.peers.values_mut() {
+                conn.stats.tick(now);
+            }
+            self.rechoke(swarm, seeding, now);
+            if swarm.haves.should_flush(now) {
+                let peers = swarm
+                    .peers
+                    .iter()
+                    .filter_map(|(addr, conn)| Some((*addr, conn.has.as_ref()?)));
+                for (addr, pieces) in swarm.haves.flush(peers, now) {
+                    swarm.peers.get_mut(&addr).unwrap().haves.extend(pieces);
+                }
+            }
+            swarm
+                .peers
+                .values()
+                .map(|conn| conn.stats.clone())
+                .collect()
+        };
+        stats.sort_by_key(|peer| peer.addr);
+        let seeds = stats.iter().filter(|peer| peer.is_seed()).count();
+        let mut torrent = self.shared.torrent();
+        torrent.set_peer_counts(stats.len(), seeds);
+        torrent.set_peer_stats(stats);
+        let limits = torrent.limits();
+        let limits = RateLimits {
+            upload: limits.upload_rate,
+            download: limits.download_rate,
+        };
+        if self.shared.own_limiter.limits() != limits {
+            self.shared.own_limiter.set_limits(limits, now);
+        }
+        let attached = self.attached;
+        if !attached {
+            torrent.tick(now);
+            // There's no session here to publish them
+            torrent.take_events();
+        }
+
+        if let Some(failure) = self.shared.failure.lock().unwrap().clone() {
+            return DownloadState::Failed(failure);
+        }
+        // When it's done seeding is the session's call
+        if attached || torrent.status() != TorrentStatus::Seeding {
+            return DownloadState::Running;
+        }
+        match &self.config.seed_after {
+            Some(goals) if torrent.seed_goal_reached(goals, now).is_none() => {
+                DownloadState::Running
+            }
+            _ => DownloadState::Done,
+        }
+    }
+
+    // Back from a suspend: the trackers are announced to right away, the DHT node checks on its
+    // table and every peer's requests get a fresh timeout. `poll` calls it by itself unless
+    // attached, when it's the session's `SuspendDetector` that should
+    pub fn on_wake(&self, now: Instant) {
+        self.shared.wakes.fetch_add(1, Ordering::SeqCst);
+        if !self.attached {
+            self.shared.torrent().on_wake(now);
+        }
+    }
+
+    pub fn info_hash(&self) -> InfoHash {
+        self.shared.info_hash
+    }
+
+    // Bytes of finished pieces hashed so far: those the write cache still had, and those that
+    // were read back from disk
+    pub fn hashed_bytes(&self) -> (u64, u64) {
+        match self.shared.verifier.get() {
+            Some(verifier) => {
+                let verifier = verifier.lock().unwrap();
+                (
+                    verifier.stats().cached_bytes(),
+                    verifier.stats().disk_bytes(),
+                )
+            }
+            None => (0, 0),
+        }
+    }
+
+    // What the I/O cache in front of the disk has done, once there's a disk to be in front of
+    pub fn io_stats(&self) -> Option<IoCacheStats> {
+        Some(self.shared.io_cache.get()?.lock().unwrap().stats())
+    }
+
+    // A connection for this torrent that whoever listens for the session took, and its
+    // handshake, read already to tell which torrent it's for. See `PeerStream::accept`
+    pub fn accept(&mut self, stream: PeerStream, addr: SocketAddr, theirs: Handshake) {
+        self.on_incoming(addr, Dial::Routed(stream, theirs), Instant::now());
+    }
+
+    // Tells the trackers we're leaving, waiting for them at most a few seconds
+    pub fn stop(mut self) {
+        self.shared.stop.store(true, Ordering::SeqCst);
+        if let Some(announcer) = self.announcer.take() {
+            let _ = announcer.join();
+        }
+        self.forget_peers();
+    }
+
+    // Drops the peers without a word to the trackers, for when they're told some other way, e.g.
+    // by `shutdown::run`
+    pub fn close(self) {
+        self.shared.quiet.store(true, Ordering::SeqCst);
+        self.shared.stop.store(true, Ordering::SeqCst);
+        self.forget_peers();
+    }
+
+    // Their threads are on their way out, and a torrent that stays in a session shouldn't show
+    // them meanwhile
+    fn forget_peers(&self) {
+        let mut torrent = self.shared.torrent();
+        torrent.set_peer_counts(0, 0);
+        torrent.set_peer_stats(vec![]);
+    }
+
+    // Not behind a strict proxy though: with one of the torrent's own, others may still be
+    // listening for it
+    // Peers a relay introduced us to are connecting to us at the same time, so they're dialed
+    // right away rather than waiting their turn
+    fn dial_holepunched(&mut self, now: Instant) {
+        let dial = {
+            let mut holepunch = self.shared.holepunch.lock().unwrap();
+            holepunch.holepunches.expire(now);
+            mem::take(&mut holepunch.dial)
+        };
+        for addr in dial {
+            if self.shared.num_peers() >= self.config.max_peers
+                || !self.config.ip_filter.allows(addr.ip(), Attempt::Dial)
+            {
+                continue;
+            }
+            {
+                let mut torrent = self.shared.torrent();
+                let list = torrent.peer_list_mut();
+                let flags = PexFlags(PexFlags::HOLEPUNCH);
+                if !list.insert(addr, PeerSource::Pex, flags, now)
+                    || list.get(&addr).is_some_and(|candidate| candidate.in_use())
+                {
+                    continue;
+                }
+                list.on_connecting(&addr);
+            }
+            let stats = PeerStats::new(addr, PeerFlags::default(), now);
+            self.shared
+                .swarm()
+                .peers
+                .insert(addr, Connection::new(stats));
+            self.spawn_peer(addr, Dial::Connect);
+        }
+    }
+
+    fn on_incoming(&mut self, addr: SocketAddr, dial: Dial, now: Instant) {
+        if self.shared.num_peers() >= self.config.max_peers
+            || !self.config.ip_filter.allows(addr.ip(), Attempt::Accept)
+            || self
+                .shared
+                .proxy()
+                .is_some_and(|proxy| !proxy.allows_direct())
+        {
+            return;
+        }
+        // Banned, or not worth a place in the list
+        {
+            let mut torrent = self.shared.torrent();
+            let list = torrent.peer_list_mut();
+            if !list.insert(addr, PeerSource::Incoming, PexFlags(0), now) {
+                return;
+            }
+            list.on_connecting(&addr);
+        }
+        let flags = PeerFlags {
+            incoming: true,
+            ..PeerFlags::default()
+        };
+        let stats = PeerStats::new(addr, flags, now);
+        self.shared
+            .swarm()
+            .peers
+            .insert(addr, Connection::new(stats));
+        self.spawn_peer(addr, dial);
+    }
+
+    // The hash checks that came back. A good piece goes out in our haves, a bad one is downloaded
+    // again and whoever the verifier blames for it is banned
+    fn on_verified(&self, now: Instant) {
+        let Some(verifier) = self.shared.verifier.get() else {
+            return;
+        };
+        let mut finished = false;
+        {
+            let mut torrent = self.shared.torrent();
+            let mut verifier = verifier.lock().unwrap();
+            while let Some(verified) = torrent.picker_mut().and_then(|p| verifier.poll(p)) {
+                let piece = verified.piece;
+                self.shared.cache().remove(piece);
+                match verified.result {
+                    Ok(true) => {
+                        finished |= torrent.on_piece_verified(piece);
+                        self.shared.swarm().haves.push(piece);
+                    }
+                    Ok(false) => {
+                        torrent.on_piece_failed(piece);
+                        for addr in verified.banned {
+                            torrent.ban_peer(addr, BanReason::BadData, now);
+                        }
+                    }
+                    Err(kind) => self.shared.fail(&kind.into()),
+                }
+                if let Some(web) = self.shared.web_seeds.get() {
+                    let mut web = web.lock().unwrap();
+                    for seed in web.senders.remove(&piece).unwrap_or_default() {
+                        match verified.result {
+                            Ok(true) => web.seeds.on_piece_verified(seed, piece),
+                            // It may get another go at the piece, or run out of chances
+                            Ok(false) if web.seeds.on_hash_failure(seed, piece) => {
+                                web.refetch.push((seed, piece))
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+        if finished && let Err(err) = self.shared.storage.get().unwrap().flush() {
+            self.shared.fail(&err);
+        }
+    }
+
+    // Hands out the upload slots, on the choker's interval. A slot that's free while someone
+    // interested waits is handed out right away
+    fn rechoke(&self, swarm: &mut Swarm, seeding: bool, now: Instant) {
+        let candidates: Vec<ChokeCandidate<SocketAddr>> = swarm
+            .peers
+            .iter()
+            .map(|(addr, conn)| ChokeCandidate {
+                key: *addr,
+                interested: conn.interested,
+                download_rate: conn.stats.download_rate(),
+                upload_rate: conn.stats.upload_rate(),
+                snubbed: conn.stats.flags.snubbed,
+            })
+            .collect();
+        let choker = &mut swarm.choker;
+        let unchoked = candidates
+            .iter()
+            .filter(|c| choker.is_unchoked(&c.key))
+            .count();
+        let waiting = candidates
+            .iter()
+            .any(|c| c.interested && !choker.is_unchoked(&c.key));
+        if unchoked < self.config.choker.slots && waiting {
+            choker.rechoke(&candidates, seeding, now);
+        } else {
+            choker.tick(&candidates, seeding, now);
+        }
+        for (addr, conn) in &mut swarm.peers {
+            conn.unchoked = choker.is_unchoked(addr);
+        }
+    }
+
+    fn spawn_peer(&self, addr: SocketAddr, dial: Dial) {
+        let shared = self.shared.clone();
+        thread::spawn(move || {
+            let outgoing = matches!(dial, Dial::Connect);
+            let result = match dial {
+                Dial::Connect => shared
+                    .connect(addr)
+                    .and_then(|stream| run_peer(&shared, stream, addr, None)),
+                Dial::Accepted(stream) => {
+                    PeerStream::accept(stream, vec![shared.info_hash], shared.encryption)
+                        .and_then(|(stream, theirs)| run_peer(&shared, stream, addr, Some(theirs)))
+                }
+                Dial::Routed(stream, theirs) => run_peer(&shared, stream, addr, Some(theirs)),
+            };
+            shared.swarm().peers.remove(&addr);
+            let mut torrent = shared.torrent();
+            let now = Instant::now();
+            match result {
+                Err(err) if is_violation(&err) => {
+                    torrent.ban_peer(addr, BanReason::ProtocolViolation, now)
+                }
+                Err(_) if outgoing => {
+                    let list = torrent.peer_list_mut();
+                    list.on_connect_failed(&addr, now);
+                    // Behind a NAT, maybe, but able to connect out
+                    let holepunch = list
+                        .get(&addr)
+                        .is_some_and(|candidate| candidate.flags.has(PexFlags::HOLEPUNCH));
+                    if holepunch {
+                        drop(torrent);
+                        shared.rendezvous(addr, now);
+                    }
+                }
+                _ => torrent.peer_list_mut().on_disconnected(&addr, now),
+            }
+        });
+    }
+}
+
+// How a peer's thread gets its connection
+enum Dial {
+    Connect,
+    // From our own listener
+    Accepted(TcpStream),
+    // With their handshake, read by whoever accepted it to tell which torrent it's for
+    Routed(PeerStream, Handshake),
+}
+
+// A peer connection, encrypted underneath when MSE settled on RC4
+pub struct PeerStream {
+    tcp: TcpStream,
+    // Boxed, it's two RC4 states
+    cipher: Option<Box<Cipher>>,
+    // What came in with the end of the MSE handshake, decrypted already, to be read first
+    pending: Vec<u8>,
+}
+
+impl PeerStream {
+    // An incoming connection's MSE handshake, if it starts with one and `policy` allows it, then
+    // its BitTorrent handshake. `torrents` are the info-hashes it may ask for
+    pub fn accept(
+        tcp: TcpStream,
+        torrents: Vec<InfoHash>,
+        policy: EncryptionPolicy,
+    ) -> io::Result<(PeerStream, Handshake)> {
+        tcp.set_read_timeout(Some(CONNECT_TIMEOUT * 2))?;
+        let mse = MseHandshake::incoming(torrents, policy, &mut Rng::new());
+        let mut stream = PeerStream::negotiate(tcp, mse)?;
+        let mut buf = [0; HANDSHAKE_LEN];
+        stream.read_exact(&mut buf)?;
+        match Handshake::decode(&buf) {
+            Ok(Some(theirs)) => Ok((stream, theirs)),
+            _ => Err(error("bad handshake")),
+        }
+    }
+
+    // Our side of the MSE handshake, unless `policy` is `Disabled`. Ours for BitTorrent follows
+    fn connect(
+        tcp: TcpStream,
+        info_hash: InfoHash,
+        policy: EncryptionPolicy,
+    ) -> io::Result<PeerStream> {
+        tcp.set_read_timeout(Some(CONNECT_TIMEOUT * 2))?;
+        let mse = MseHandshake::outgoing(info_hash, vec![], policy, &mut Rng::new());
+        PeerStream::negotiate(tcp, mse)
+    }
+
+    fn negotiate(mut tcp: TcpStream, mut mse: MseHandshake) -> io::Result<PeerStream> {
+        let mut buf = [0; 4096];
+        let mut len = 0;
+        loop {
+            let established = mse
+                .handle_input(&buf[..len])
+                .map_err(|err| error(&format!("MSE: {:?}", err)))?;
+            if let Some(output) = mse.poll_transmit() {
+                tcp.write_all(&output)?;
+            }
+            if let Some(established) = established {
+                return Ok(PeerStream {
+                    tcp,
+                    cipher: established.cipher.map(Box::new),
+                    pending: established.payload,
+                });
+            }
+            len = tcp.read(&mut buf)?;
+            if len == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+}
+
+impl Read for PeerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.pending.is_empty() {
+            let n = buf.len().min(self.pending.len());
+            buf[..n].copy_from_slice(&self.pending[..n]);
+            self.pending.drain(..n);
+            return Ok(n);
+        }
+        let n = self.tcp.read(buf)?;
+        if let Some(cipher) = &mut self.cipher {
+            cipher.decrypt(&mut buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl Write for PeerStream {
+    // All of it or an error: RC4 has moved on, so there's no taking back part of it
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.cipher {
+            Some(cipher) => {
+                let mut encrypted = buf.to_vec();
+                cipher.encrypt(&mut encrypted);
+                self.tcp.write_all(&encrypted)?;
+            }
+            None => self.tcp.write_all(buf)?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.tcp.flush()
+    }
+}
+
+// However it's stopped, what's waiting in the I/O cache goes to disk. Peer threads writing after
+// this see `stop` and write theirs straight through
+impl Drop for Download {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::SeqCst);
+        self.shared.flush_all_writes();
+    }
+}
+
+impl Shared {
+    fn new(
+        torrent: Arc<Mutex<Torrent>>,
+        config: &DownloadConfig,
+        peer_id: [u8; 20],
+        port: u16,
+    ) -> Arc<Shared> {
+        let info_hash = torrent.lock().unwrap().info_hash();
+        Arc::new(Shared {
+            info_hash,
+            peer_id,
+            port,
+            torrent,
+            storage: OnceLock::new(),
+            verifier: OnceLock::new(),
+            cache: Mutex::new(BlockCache::new(WRITE_CACHE)),
+            io_cache: OnceLock::new(),
+            io_cache_config: config.io_cache.clone(),
+            web_seeds: OnceLock::new(),
+            metadata: Mutex::new(None),
+            swarm: Mutex::new(Swarm {
+                peers: HashMap::new(),
+                choker: Choker::new(config.choker.clone()),
+                haves: HaveBroadcaster::new(config.haves.clone(), Instant::now()),
+            }),
+            pipeline: config.pipeline.clone(),
+            encryption: config.encryption,
+            pex: config.pex,
+            proxy: config.proxy.clone(),
+            limiter: config.limiter.clone(),
+            own_limiter: RateLimiter::default(),
+            senders: Mutex::new(HashMap::new()),
+            holepunch: Mutex::new(Holepunching::default()),
+            failure: Mutex::new(None),
+            dht_port: AtomicU16::new(0),
+            wakes: AtomicU64::new(0),
+            stop: AtomicBool::new(false),
+            quiet: AtomicBool::new(false),
+        })
+    }
+
+    fn torrent(&self) -> MutexGuard<'_, Torrent> {
+        self.torrent.lock().unwrap()
+    }
+
+    fn swarm(&self) -> MutexGuard<'_, Swarm> {
+        self.swarm.lock().unwrap()
+    }
+
+    // Asks a peer that does ut_holepunch to introduce us to `target`, which we couldn't reach.
+    // It's likely connected to them if it told us about them, but any such peer may be
+    fn rendezvous(&self, target: SocketAddr, now: Instant) {
+        let mut swarm = self.swarm();
+        let Some((relay, conn)) = swarm
+            .peers
+            .iter_mut()
+            .find(|(addr, conn)| conn.holepunch && **addr != target)
+        else {
+            return;
+        };
+        let mut holepunch = self.holepunch.lock().unwrap();
+        if let Some(message) = holepunch.holepunches.rendezvous(target, *relay, now) {
+            conn.holepunch_messages.push(message);
+        }
+    }
+
+    fn num_peers(&self) -> usize {
+        self.swarm().peers.len()
+    }
+
+    fn senders(&self) -> MutexGuard<'_, HashMap<u32, Vec<SocketAddr>>> {
+        self.senders.lock().unwrap()
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+
+    // Whether `Download::on_wake` was called since `seen` was last brought up to date
+    fn woke(&self, seen: &mut u64) -> bool {
+        let wakes = self.wakes.load(Ordering::SeqCst);
+        mem::replace(seen, wakes) != wakes
+    }
+
+    // The torrent's own proxy, or else ours
+    fn proxy(&self) -> Option<ProxyConfig> {
+        self.torrent().effective_proxy(self.proxy.as_ref()).cloned()
+    }
+
+    // With encryption enabled but not forced, a peer that won't do MSE gets a second try in
+    // plaintext
+    fn connect(&self, addr: SocketAddr) -> io::Result<PeerStream> {
+        let proxy = self.proxy();
+        let dial = || proxy::connect(proxy.as_ref(), &Target::Addr(addr), CONNECT_TIMEOUT);
+        match PeerStream::connect(dial()?, self.info_hash, self.encryption) {
+            Err(_) if self.encryption == EncryptionPolicy::Enabled => {
+                PeerStream::connect(dial()?, self.info_hash, EncryptionPolicy::Disabled)
+            }
+            result => result,
+        }
+    }
+
+    // Bytes of blocks we may ask for now, as both download limits have it
+    fn download_allowance(&self, now: Instant) -> u64 {
+        let session = self.limiter.download().available(now);
+        session.min(self.own_limiter.download().available(now))
+    }
+
+    fn on_requested(&self, bytes: u64, now: Instant) {
+        self.limiter.download().try_take(bytes, now);
+        self.own_limiter.download().try_take(bytes, now);
+    }
+
+    // Waits until both upload limits let `bytes` of this kind of traffic go out. False if we're
+    // stopping meanwhile
+    fn wait_to_upload(&self, traffic: Traffic, bytes: u64) -> bool {
+        for limiter in [&self.limiter, &self.own_limiter] {
+            while !limiter.upload().try_send(traffic, bytes, Instant::now()) {
+                if self.stopped() {
+                    return false;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        true
+    }
+
+    fn fail(&self, err: &io::Error) {
+        self.failure
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| err.to_string());
+    }
+
+    // Once there's an info dict: finds what's on disk already, then lets the peers at it. The
+    // hashing happens outside the torrent's lock, so peer threads carry on meanwhile; none of them
+    // touch the data until `storage` is set
+    fn check_files(&self) -> io::Result<()> {
+        let Some(storage) = self.torrent().storage() else {
+            return Ok(());
+        };
+        let checked = recover(&storage)?;
+        self.torrent().apply_check(&checked);
+        self.set_storage(storage);
+        Ok(())
+    }
+
+    // The verifier and the I/O cache first: a peer thread that sees `storage` may use them right
+    // away
+    fn set_storage(&self, storage: Storage) {
+        let storage = Arc::new(storage);
+        let verifier = Verifier::new(Arc::clone(&storage), HASH_THREADS);
+        let _ = self.verifier.set(Mutex::new(verifier));
+        let total_length = match storage.num_pieces() {
+            0 => 0,
+            n => storage.offset_of(n - 1, 0) + storage.piece_size(n - 1) as u64,
+        };
+        let config = self.io_cache_config.clone();
+        let io_cache = IoCache::new(config, storage.piece_size(0), total_length);
+        let _ = self.io_cache.set(Mutex::new(io_cache));
+        let _ = self.storage.set(storage);
+    }
+
+    fn verifier(&self) -> MutexGuard<'_, Verifier<SocketAddr>> {
+        self.verifier.get().unwrap().lock().unwrap()
+    }
+
+    fn cache(&self) -> MutexGuard<'_, BlockCache> {
+        self.cache.lock().unwrap()
+    }
+
+    fn io_cache(&self) -> MutexGuard<'_, IoCache<SocketAddr>> {
+        self.io_cache.get().unwrap().lock().unwrap()
+    }
+
+    // A block we downloaded, which waits in the I/O cache to go out with its neighbours. Once
+    // we're stopping there's no later flush to wait for
+    fn write(&self, piece: u32, offset: u32, data: Arc<[u8]>) -> io::Result<()> {
+        let mut cache = self.io_cache();
+        cache.write(piece, offset, data, Instant::now());
+        let runs = match self.stopped() {
+            true => cache.flush_all(),
+            false => vec![],
+        };
+        drop(cache);
+        self.write_runs(runs)
+    }
+
+    // The writes that are due. Outside the cache's lock: meanwhile it answers reads of them as
+    // clean blocks
+    fn flush_writes(&self, now: Instant) {
+        if let Some(cache) = self.io_cache.get() {
+            let runs = cache.lock().unwrap().flush(now);
+            let _ = self.write_runs(runs);
+        }
+    }
+
+    fn flush_all_writes(&self) {
+        if let Some(cache) = self.io_cache.get() {
+            let runs = cache.lock().unwrap().flush_all();
+            let _ = self.write_runs(runs);
+        }
+    }
+
+    // A disk that won't take what we downloaded fails the download
+    fn write_runs(&self, runs: Vec<WriteRun>) -> io::Result<()> {
+        let storage = self.storage.get().unwrap();
+        for run in runs {
+            storage
+                .write(run.piece, run.offset, &run.data)
+                .inspect_err(|err| self.fail(err))?;
+        }
+        Ok(())
+    }
+
+    // From the I/O cache when it has the block, else from disk, leaving a copy in the cache
+    fn read(&self, block: &Block) -> io::Result<Vec<u8>> {
+        let len = block.length as usize;
+        let cached = self.io_cache().get(block.piece, block.offset);
+        if let Some(data) = cached.filter(|data| data.len() >= len) {
+            return Ok(data[..len].to_vec());
+        }
+        let storage = self.storage.get().unwrap();
+        let data = storage.read(block.piece, block.offset, block.length)?;
+        self.io_cache()
+            .insert(block.piece, block.offset, data.as_slice().into());
+        Ok(data)
+    }
+
+    // What a peer reading in order will ask for next. Errors are left for when it does
+    fn read_ahead(&self, blocks: Vec<Block>) {
+        let storage = self.storage.get().unwrap();
+        for block in blocks {
+            if let Ok(data) = storage.read(block.piece, block.offset, block.length) {
+                self.io_cache()
+                    .insert(block.piece, block.offset, data.into());
+            }
+        }
+    }
+
+    // Pieces the last engine got all of but stopped before their check came back. Nobody sends
+    // them again, so they're checked now
+    fn verify_received(&self) {
+        let torrent = self.torrent();
+        let Some(picker) = torrent.picker() else {
+            return;
+        };
+        let mut verifier = self.verifier();
+        for (piece, received) in picker.partial_progress() {
+            if received.all() {
+                let blocks = received.len();
+                verifier.submit(
+                    PieceBuffer {
+                        piece,
+                        blocks: vec![None; blocks],
+                    },
+                    vec![],
+                );
+            }
+        }
+    }
+
+    // A block from a peer or a web seed. Returns whether it completed its piece
+    fn on_block(&self, block: Block, data: Arc<[u8]>, source: Source) -> io::Result<bool> {
+        let len = data.len() as u64;
+        self.write(block.piece, block.offset, data.clone())?;
+        self.cache().insert(block.piece, block.offset, data);
+        match source {
+            Source::Peer(addr) => {
+                let mut senders = self.senders();
+                let piece_senders = senders.entry(block.piece).or_default();
+                if !piece_senders.contains(&addr) {
+                    piece_senders.push(addr);
+                }
+            }
+            Source::WebSeed(seed) => {
+                let mut web = self.web_seeds.get().unwrap().lock().unwrap();
+                let piece_senders = web.senders.entry(block.piece).or_default();
+                if !piece_senders.contains(&seed) {
+                    piece_senders.push(seed);
+                }
+            }
+        }
+        let complete = {
+            let mut torrent = self.torrent();
+            torrent.on_downloaded(len);
+            torrent.picker_mut().unwrap().on_block_received(&block)
+        };
+        if !complete {
+            return Ok(false);
+        }
+
+        // Hashed on the verifier's threads from what the cache still has, and from disk for the
+        // rest, so the piece is written out first. `Download::poll` takes it from there
+        let runs = self.io_cache().flush_piece(block.piece);
+        self.write_runs(runs)?;
+        let storage = self.storage.get().unwrap();
+        let senders = self.senders().remove(&block.piece).unwrap_or_default();
+        let blocks = storage.piece_size(block.piece).div_ceil(BLOCK_SIZE) as usize;
+        let buffer = self.cache().piece_buffer(block.piece, blocks);
+        self.verifier().submit(buffer, senders);
+        Ok(true)
+    }
+
+    // A batch for the first web seed that's free, if the download limit leaves room for it: a
+    // piece it's to fetch again, or else whatever the picker has for web seeds. The seed is busy
+    // until `on_web_batch`
+    fn next_web_batch(&self, now: Instant) -> Option<(usize, Vec<Block>, Vec<RangeRequest>)> {
+        let storage = self.storage.get()?;
+        if self.download_allowance(now) == 0 {
+            return None;
+        }
+        let mut torrent = self.torrent();
+        let picker = torrent.picker_mut()?;
+        let mut web = self.web_seeds.get()?.lock().unwrap();
+        let seed = web.seeds.next_ready(now)?;
+        let refetch = web.refetch.iter().position(|(from, _)| *from == seed);
+        let mut blocks = match refetch {
+            Some(i) => picker.pick_piece(web.refetch.swap_remove(i).1),
+            None => vec![],
+        };
+        if blocks.is_empty() {
+            blocks = picker.pick_web_seed(WEB_SEED_PIECES);
+        }
+        if blocks.is_empty() {
+            web.seeds.release(seed);
+            return None;
+        }
+        let requests = web.seeds.get(seed).requests(storage, &blocks);
+        drop(web);
+        drop(torrent);
+        let bytes = blocks.iter().map(|block| block.length as u64).sum();
+        self.on_requested(bytes, now);
+        Some((seed, blocks, requests))
+    }
+
+    // What came back for a batch: its blocks go the way blocks from peers do, or back to the
+    // picker. A seed that's slow still sent good data, it just gets a rest
+    fn on_web_batch(
+        &self,
+        seed: usize,
+        blocks: &[Block],
+        result: Result<Vec<u8>, WebSeedError>,
+        elapsed: Duration,
+    ) -> io::Result<()> {
+        let now = Instant::now();
+        let mut web = self.web_seeds.get().unwrap().lock().unwrap();
+        let data = match result {
+            Ok(data) => {
+                web.seeds.on_success(seed, data.len() as u64, elapsed, now);
+                data
+            }
+            Err(err) => {
+                web.seeds.on_failure(seed, &err, now);
+                drop(web);
+                self.abort_blocks(blocks);
+                return Ok(());
+            }
+        };
+        drop(web);
+        let mut at = 0;
+        for block in blocks {
+            let end = at + block.length as usize;
+            self.on_block(*block, data[at..end].into(), Source::WebSeed(seed))?;
+            at = end;
+        }
+        Ok(())
+    }
+
+    fn abort_blocks(&self, blocks: &[Block]) {
+        let mut torrent = self.torrent();
+        if let Some(picker) = torrent.picker_mut() {
+            for block in blocks {
+                picker.abort_request(block);
+            }
+        }
+    }
+
+    fn on_metadata(&self, info_bytes: &[u8]) -> io::Result<()> {
+        let metainfo = Metainfo::from_info_bytes(info_bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err)))?;
+        let mut torrent = self.torrent();
+        if torrent.metainfo().is_some() {
+            return Ok(());
+        }
+        torrent.set_metainfo(metainfo);
+        drop(torrent);
+        self.check_files()
+    }
+}
+
+// Where a connection is at. "They" are the peer, "we" are us
+struct Peer<'a> {
+    shared: &'a Shared,
+    stream: PeerStream,
+    addr: SocketAddr,
+    // Their pieces, once we know how many there are. What they sent before that waits in `early`
+    has: Option<Bitfield>,
+    early: Vec<Message>,
+    // Changed since `sync` last passed it on
+    has_changed: bool,
+    fast: bool,
+    // Their allowed fast set and ours, once we know how many pieces there are
+    fast_state: Option<FastState>,
+    // They're choking us, we're interested in them
+    choked: bool,
+    interested: bool,
+    // And the other way round
+    choking: bool,
+    peer_interested: bool,
+    pipeline: RequestPipeline,
+    // BEP 52 hash messages, for hybrid torrents. What we asked them for and haven't heard back
+    // about, and whether we've asked yet
+    v2: bool,
+    hash_requests: Vec<HashRequest>,
+    asked_hashes: bool,
+    // What they want their ut_metadata messages tagged with, and what we tag ours with
+    their_metadata_id: Option<u8>,
+    our_metadata_id: Option<u8>,
+    // The same for ut_pex, and what they've been told, when we both do it
+    their_pex_id: Option<u8>,
+    our_pex_id: Option<u8>,
+    // And for ut_holepunch
+    their_holepunch_id: Option<u8>,
+    our_holepunch_id: Option<u8>,
+    pex: Option<PexState>,
+    last_pex: Option<Instant>,
+    // Whether they've been told what we have, by bitfield or haves
+    announced: bool,
+    last_sent: Instant,
+    // The torrent's `peer_generation` when we connected. Once it moves on, or the peer list
+    // drops us, we're done
+    generation: u64,
+    // The last of `Shared::wakes` we saw
+    wakes: u64,
+    // Going by their peer ID
+    quirks: Quirks,
+    // For our entry in `Shared::swarm`: their client, as their extension handshake or else their
+    // peer ID has it, and block data moved since it was last updated
+    client: Option<String>,
+    received: u64,
+    sent: u64,
+    rng: Rng,
+}
+
+// `theirs` is the handshake of an incoming connection, None for one we made
+fn run_peer(
+    shared: &Shared,
+    mut stream: PeerStream,
+    addr: SocketAddr,
+    theirs: Option<Handshake>,
+) -> io::Result<()> {
+    let outgoing = theirs.is_none();
+    stream.tcp.set_read_timeout(Some(CONNECT_TIMEOUT * 2))?;
+    stream.tcp.set_write_timeout(Some(IDLE_TIMEOUT))?;
+    let mut reserved = Reserved::default()
+        .with(Feature::Extended)
+        .with(Feature::Fast);
+    let (hybrid, request_size) = {
+        let torrent = shared.torrent();
+        let hybrid = torrent.metainfo().is_some_and(|metainfo| {
+            metainfo
+                .info
+                .files
+                .iter()
+                .any(|file| file.pieces_root.is_some())
+        });
+        let request_size = torrent.picker().map_or(BLOCK_SIZE, |p| p.block_size());
+        (hybrid, request_size)
+    };
+    if hybrid {
+        reserved = reserved.with(Feature::V2);
+    }
+    let ours = Handshake::new(reserved, shared.info_hash, shared.peer_id);
+    if outgoing {
+        stream.write_all(&ours.encode())?;
+    }
+    let theirs = match theirs {
+        Some(theirs) => theirs,
+        None => {
+            let mut buf = [0; HANDSHAKE_LEN];
+            stream.read_exact(&mut buf)?;
+            match Handshake::decode(&buf) {
+                Ok(Some(theirs)) => theirs,
+                _ => return Err(error("bad handshake")),
+            }
+        }
+    };
+    if theirs.info_hash != shared.info_hash {
+        return Err(error("bad handshake"));
+    }
+    if theirs.peer_id == shared.peer_id {
+        return Err(error("connected to ourselves"));
+    }
+    if !outgoing {
+        stream.write_all(&ours.encode())?;
+    }
+    stream.tcp.set_read_timeout(Some(READ_TIMEOUT))?;
+    if let Some(conn) = shared.swarm().peers.get_mut(&addr) {
+        conn.stats.flags.encrypted = stream.is_encrypted();
+    }
+    let generation = {
+        let mut torrent = shared.torrent();
+        torrent.peer_list_mut().on_connected(&addr, Instant::now());
+        torrent.peer_generation()
+    };
+    let client = client_ident::identify(&theirs.peer_id);
+
+    let pipeline = PipelineConfig {
+        request_size,
+        ..shared.pipeline.clone()
+    };
+    let mut peer = Peer {
+        shared,
+        stream,
+        addr,
+        has: None,
+        early: vec![],
+        has_changed: false,
+        fast: theirs.negotiated(&reserved, Feature::Fast),
+        fast_state: None,
+        choked: true,
+        interested: false,
+        choking: true,
+        peer_interested: false,
+        pipeline: RequestPipeline::new(pipeline, Instant::now()),
+        v2: theirs.negotiated(&reserved, Feature::V2),
+        hash_requests: vec![],
+        asked_hashes: false,
+        their_metadata_id: None,
+        our_metadata_id: None,
+        their_pex_id: None,
+        our_pex_id: None,
+        their_holepunch_id: None,
+        our_holepunch_id: None,
+        pex: None,
+        last_pex: None,
+        announced: false,
+        last_sent: Instant::now(),
+        generation,
+        wakes: shared.wakes.load(Ordering::SeqCst),
+        quirks: client.as_ref().map(|c| c.quirks()).unwrap_or_default(),
+        client: client.map(|c| c.to_string()),
+        received: 0,
+        sent: 0,
+        rng: Rng::new(),
+    };
+    if theirs.negotiated(&reserved, Feature::Extended) {
+        let (private, metadata_size) = {
+            let torrent = shared.torrent();
+            let size = torrent.metainfo().map(|m| m.info_bytes.len() as u32);
+            (torrent.is_private(), size)
+        };
+        let mut ext = ExtensionHandshake::ours(Some(shared.port), CLIENT, private);
+        if !shared.pex {
+            ext.extensions.remove(UT_PEX);
+        }
+        // Peers only ever see the proxy's address, there's no hole to punch to us
+        if shared.proxy().is_some() {
+            ext.extensions.remove(UT_HOLEPUNCH);
+        }
+        ext.metadata_size = metadata_size;
+        peer.our_metadata_id = ext.id_for(UT_METADATA);
+        peer.our_pex_id = ext.id_for(UT_PEX);
+        peer.our_holepunch_id = ext.id_for(UT_HOLEPUNCH);
+        if peer.our_pex_id.is_some() {
+            peer.pex = Some(PexState::new(shared.info_hash));
+        }
+        peer.send(Message::Extended {
+            id: extension::HANDSHAKE_ID,
+            payload: ext.encode(),
+        })?;
+    }
+    peer.announce(true)?;
+    // With the fast extension something has to follow the handshake, even without the info dict
+    if !peer.announced && peer.fast {
+        peer.send(Message::HaveNone)?;
+    }
+
+    let result = peer.run();
+    peer.disconnect();
+    result
+}
+
+impl Peer<'_> {
+    fn run(&mut self) -> io::Result<()> {
+        let mut input = vec![];
+        let mut buf = vec![0; 64 * 1024];
+        let mut last_heard = Instant::now();
+        while !self.shared.stopped() {
+            {
+                let torrent = self.shared.torrent();
+                if torrent.peer_generation() != self.generation
+                    || !torrent.peer_list().is_connected(&self.addr)
+                {
+                    return Ok(());
+                }
+            }
+            // Asleep isn't quiet: they get as long as ever to answer
+            if self.shared.woke(&mut self.wakes) {
+                last_heard = Instant::now();
+                self.pipeline.on_wake(last_heard);
+            }
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => {
+                    input.extend_from_slice(&buf[..n]);
+                    last_heard = Instant::now();
+                }
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+            if last_heard.elapsed() > IDLE_TIMEOUT {
+                return Err(error("peer went quiet"));
+            }
+
+            loop {
+                let (message, used) = match Message::decode(&input) {
+                    Ok(Some(decoded)) => decoded,
+                    Ok(None) => break,
+                    // Messages from extensions we don't know are skipped
+                    Err(MessageError::UnknownId(_)) => {
+                        let len = u32::from_be_bytes([input[0], input[1], input[2], input[3]]);
+                        (Message::KeepAlive, 4 + len as usize)
+                    }
+                    Err(_) => return Err(violation("bad message")),
+                };
+                if used > input.len() {
+                    break;
+                }
+                input.drain(..used);
+                self.on_message(message)?;
+            }
+
+            self.catch_up()?;
+            self.request_hashes()?;
+            self.request()?;
+            self.exchange_peers()?;
+            self.sync()?;
+            if self.last_sent.elapsed() > KEEP_ALIVE {
+                self.send(Message::KeepAlive)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn on_message(&mut self, message: Message) -> io::Result<()> {
+        match message {
+            Message::Bitfield(_) | Message::Have(_) | Message::HaveAll | Message::HaveNone => {
+                match self.has.is_some() {
+                    true => self.on_has(message),
+                    false => self.early.push(message),
+                }
+            }
+            Message::Choke => {
+                self.choked = true;
+                // With the fast extension they'll reject what they drop, without it it's all gone
+                if !self.fast {
+                    self.abort_requests();
+                }
+            }
+            Message::Unchoke => self.choked = false,
+            // The choker has the final say, on its next round
+            Message::Interested => self.peer_interested = true,
+            Message::NotInterested => self.peer_interested = false,
+            Message::Request(block) => self.serve(block)?,
+            Message::Piece {
+                piece,
+                offset,
+                data,
+            } => self.on_piece(Block::new(piece, offset, data.len() as u32), data.into())?,
+            Message::RejectRequest(block) => {
+                if self.pipeline.cancel(&block)
+                    && let Some(picker) = self.shared.torrent().picker_mut()
+                {
+                    picker.abort_request(&block);
+                }
+            }
+            Message::AllowedFast(_) | Message::SuggestPiece(_) => {
+                if let Some(fast) = &mut self.fast_state {
+                    fast.on_message(&message);
+                }
+            }
+            Message::HashRequest(request) => {
+                let storage = self.shared.storage.get();
+                let reply = match storage.and_then(|storage| storage.answer_hashes(&request)) {
+                    Some(hashes) => Message::Hashes { request, hashes },
+                    None => Message::HashReject(request),
+                };
+                self.send(reply)?;
+            }
+            // Only answers to what we asked for are any use
+            Message::Hashes { request, hashes } => {
+                if let Some(i) = self.hash_requests.iter().position(|r| *r == request) {
+                    self.hash_requests.remove(i);
+                    let storage = self.shared.storage.get().unwrap();
+                    if !storage.on_hashes(&request, &hashes) {
+                        return Err(violation("bad hashes"));
+                    }
+                }
+            }
+            Message::HashReject(request) => self.hash_requests.retain(|r| *r != request),
+            Message::Extended {
+                id: extension::HANDSHAKE_ID,
+                payload,
+            } => {
+                let theirs = ExtensionHandshake::decode(&payload)
+                    .map_err(|_| violation("bad extension handshake"))?;
+                self.their_metadata_id = theirs.id_for(UT_METADATA);
+                self.their_pex_id = theirs.id_for(UT_PEX);
+                self.their_holepunch_id = theirs.id_for(UT_HOLEPUNCH);
+                if let Some(conn) = self.shared.swarm().peers.get_mut(&self.addr) {
+                    conn.holepunch =
+                        self.their_holepunch_id.is_some() && self.our_holepunch_id.is_some();
+                }
+                if theirs.client.is_some() {
+                    self.client = theirs.client;
+                }
+                // Our address as they see it, for BEP 40 priorities. A peer that lies only
+                // changes the order we dial in
+                let ip = match theirs.your_ip.as_deref() {
+                    Some(&[a, b, c, d]) => Some(IpAddr::from([a, b, c, d])),
+                    Some(ip) => <[u8; 16]>::try_from(ip).ok().map(IpAddr::from),
+                    None => None,
+                };
+                if let Some(ip) = ip.filter(|ip| !ip.is_unspecified()) {
+                    let addr = SocketAddr::new(ip, self.shared.port);
+                    self.shared.torrent().peer_list_mut().set_our_addr(addr);
+                }
+                if let Some(size) = theirs.metadata_size {
+                    self.fetch_metadata(size)?;
+                }
+            }
+            Message::Extended { id, payload } if Some(id) == self.our_metadata_id => {
+                self.on_metadata_message(&payload)?;
+            }
+            Message::Extended { id, payload } if Some(id) == self.our_pex_id => {
+                self.on_pex(&payload);
+            }
+            Message::Extended { id, payload } if Some(id) == self.our_holepunch_id => {
+                self.on_holepunch(&payload)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn on_has(&mut self, message: Message) {
+        let Some(has) = self.has.as_mut() else {
+            return;
+        };
+        let mut torrent = self.shared.torrent();
+        let Some(picker) = torrent.picker_mut() else {
+            return;
+        };
+        match message {
+            Message::Have(piece) if (piece as usize) < has.len() && !has.get(piece as usize) => {
+                has.set(piece as usize);
+                picker.peer_have(piece);
+                self.has_changed = true;
+            }
+            Message::Bitfield(_) | Message::HaveAll | Message::HaveNone => {
+                let new = match message {
+                    Message::Bitfield(bytes) => Bitfield::from_bytes(&bytes, has.len()),
+                    Message::HaveAll => Some(Bitfield::full(has.len())),
+                    _ => Some(Bitfield::new(has.len())),
+                };
+                if let Some(new) = new {
+                    picker.remove_peer(has);
+                    picker.add_peer(&new);
+                    *has = new;
+                    self.has_changed = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Once the info dict is in: their pieces go to the picker, and ours to them if they haven't
+    // heard yet
+    fn catch_up(&mut self) -> io::Result<()> {
+        if self.has.is_none()
+            && let Some(storage) = self.shared.storage.get()
+        {
+            let has = Bitfield::new(storage.num_pieces() as usize);
+            if let Some(picker) = self.shared.torrent().picker_mut() {
+                picker.add_peer(&has);
+            }
+            self.has = Some(has);
+            self.has_changed = true;
+            for message in std::mem::take(&mut self.early) {
+                self.on_has(message);
+            }
+        }
+        if !self.announced {
+            self.announce(false)?;
+        }
+        Ok(())
+    }
+
+    // Tells them what we have, once we know: right after the handshake that's a bitfield (lazy,
+    // if so configured) and our allowed fast set, later on a have for each piece. Pieces verified
+    // from then on reach them through the swarm's `HaveBroadcaster`
+    fn announce(&mut self, handshake: bool) -> io::Result<()> {
+        let Some(storage) = self.shared.storage.get() else {
+            return Ok(());
+        };
+        let num_pieces = storage.num_pieces();
+        let have = {
+            let torrent = self.shared.torrent();
+            let Some(picker) = torrent.picker() else {
+                return Ok(());
+            };
+            let have = picker.have().clone();
+            // Under the torrent's lock, so a piece is either in `have` or flushed to them later
+            if let Some(conn) = self.shared.swarm().peers.get_mut(&self.addr) {
+                conn.has = Some(Bitfield::new(num_pieces as usize));
+            }
+            have
+        };
+        self.announced = true;
+        if self.fast {
+            self.fast_state = Some(FastState::for_addr(
+                self.addr.ip(),
+                &self.shared.info_hash,
+                num_pieces,
+            ));
+        }
+        if !handshake {
+            for piece in have.iter_ones() {
+                self.send(Message::Have(piece as u32))?;
+            }
+            let granted = match &self.fast_state {
+                Some(fast) => fast.granted().to_vec(),
+                None => vec![],
+            };
+            for piece in granted.into_iter().filter(|p| have.get(*p as usize)) {
+                self.send(Message::AllowedFast(piece))?;
+            }
+            return Ok(());
+        }
+
+        let (bitfield, withheld) = self
+            .shared
+            .swarm()
+            .haves
+            .initial_announcement(&have, &mut self.rng);
+        let messages = match &self.fast_state {
+            Some(fast) => fast.handshake_messages(&bitfield),
+            None if bitfield.none() => vec![],
+            None => vec![Message::Bitfield(bitfield.as_bytes().to_vec())],
+        };
+        for message in messages {
+            self.send(message)?;
+        }
+        for piece in withheld {
+            self.send(Message::Have(piece))?;
+        }
+        Ok(())
+    }
+
+    // Piece layers the .torrent came without, asked for once per peer. Until they're in, the
+    // pieces they cover are checked with SHA-1 alone
+    fn request_hashes(&mut self) -> io::Result<()> {
+        if !self.v2 || self.asked_hashes {
+            return Ok(());
+        }
+        let Some(storage) = self.shared.storage.get() else {
+            return Ok(());
+        };
+        self.asked_hashes = true;
+        for request in storage.hash_requests() {
+            self.hash_requests.push(request);
+            self.send(Message::HashRequest(request))?;
+        }
+        Ok(())
+    }
+
+    fn request(&mut self) -> io::Result<()> {
+        let Some(has) = &self.has else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        // Requests that went unanswered too long, or all of them if they've stopped sending, go
+        // back to the picker for someone else
+        let mut dropped = self.pipeline.take_stale(now);
+        dropped.extend(self.pipeline.check_snub(now));
+        self.pipeline.tick(now);
+        let blocks = {
+            let mut torrent = self.shared.torrent();
+            let Some(picker) = torrent.picker_mut() else {
+                return Ok(());
+            };
+            for block in &dropped {
+                picker.abort_request(block);
+            }
+            let wanted = has.iter_ones().any(|piece| !picker.have().get(piece));
+            if wanted != self.interested {
+                self.interested = wanted;
+                drop(torrent);
+                let message = match wanted {
+                    true => Message::Interested,
+                    false => Message::NotInterested,
+                };
+                self.send(message)?;
+                return self.cancel(dropped);
+            }
+            // As many as the download limits let us have
+            let allowance = self.shared.download_allowance(now) / picker.block_size() as u64;
+            let slots = self.pipeline.slots().min(allowance as usize);
+            // Better to leave them be than ask for what gets us disconnected
+            let too_big = self
+                .quirks
+                .max_request
+                .is_some_and(|max| picker.block_size() > max);
+            if !wanted || slots == 0 || too_big {
+                vec![]
+            } else if !self.choked {
+                match self.pipeline.is_snubbed() {
+                    true => picker.pick_snubbed(has, slots),
+                    false => picker.pick(has, slots),
+                }
+            } else if let Some(fast) = &self.fast_state {
+                // While choked, what they let us have anyway
+                picker.pick(&fast.allowed_bitfield(has), slots)
+            } else {
+                vec![]
+            }
+        };
+        self.cancel(dropped)?;
+        for block in blocks {
+            self.shared.on_requested(block.length as u64, now);
+            self.pipeline.on_request_sent(block, now);
+            self.send(Message::Request(block))?;
+        }
+        Ok(())
+    }
+
+    fn cancel(&mut self, blocks: Vec<Block>) -> io::Result<()> {
+        for block in blocks {
+            self.send(Message::Cancel(block))?;
+        }
+        Ok(())
+    }
+
+    fn on_piece(&mut self, block: Block, data: Arc<[u8]>) -> io::Result<()> {
+        if !self.pipeline.on_block_received(&block, Instant::now()) {
+            return Ok(());
+        }
+        self.received += data.len() as u64;
+        if self.shared.on_block(block, data, Source::Peer(self.addr))?
+            && let Some(fast) = &mut self.fast_state
+        {
+            fast.clear_suggestion(block.piece);
+        }
+        Ok(())
+    }
+
+    // Requests are served if we aren't choking them, or it's in their allowed fast set. With the
+    // fast extension whatever we won't serve gets rejected, without it it's dropped
+    fn serve(&mut self, block: Block) -> io::Result<()> {
+        let storage = self.shared.storage.get();
+        let valid = storage.is_some_and(|storage| {
+            block.piece < storage.num_pieces()
+                && self
+                    .shared
+                    .torrent()
+                    .picker()
+                    .is_some_and(|picker| picker.have().get(block.piece as usize))
+        });
+        let piece_size = match valid {
+            true => storage.unwrap().piece_size(block.piece),
+            false => 0,
+        };
+        let action = match &self.fast_state {
+            Some(fast) if valid => fast.on_request(&block, piece_size, self.choking),
+            None if valid
+                && !self.choking
+                && block.is_servable(piece_size, DEFAULT_MAX_SERVED_REQUEST) =>
+            {
+                RequestAction::Serve
+            }
+            _ => RequestAction::Reject,
+        };
+        if action == RequestAction::Reject {
+            return match self.fast {
+                true => self.send(Message::RejectRequest(block)),
+                false => Ok(()),
+            };
+        }
+        if !self
+            .shared
+            .wait_to_upload(Traffic::Payload, block.length as u64)
+        {
+            return Ok(());
+        }
+        let ahead = self.shared.io_cache().on_request(self.addr, &block);
+        let data = self.shared.read(&block)?;
+        self.shared.torrent().on_uploaded(data.len() as u64);
+        self.sent += data.len() as u64;
+        self.send(Message::Piece {
+            piece: block.piece,
+            offset: block.offset,
+            data,
+        })?;
+        self.shared.read_ahead(ahead);
+        Ok(())
+    }
+
+    fn fetch_metadata(&mut self, size: u32) -> io::Result<()> {
+        let Some(id) = self.their_metadata_id else {
+            return Ok(());
+        };
+        if self.shared.torrent().metainfo().is_some() {
+            return Ok(());
+        }
+        let pieces = {
+            let mut metadata = self.shared.metadata.lock().unwrap();
+            if metadata.is_none() {
+                *metadata = MetadataDownload::new(self.shared.info_hash, size);
+            }
+            let Some(download) = metadata.as_mut() else {
+                return Ok(());
+            };
+            (0..download.num_pieces())
+                .map_while(|_| download.next_request())
+                .collect::<Vec<u32>>()
+        };
+        for piece in pieces {
+            self.send(Message::Extended {
+                id,
+                payload: MetadataMessage::Request(piece).encode(),
+            })?;
+        }
+        Ok(())
+    }
+
+    fn on_metadata_message(&mut self, payload: &[u8]) -> io::Result<()> {
+        match MetadataMessage::decode(payload) {
+            Ok(MetadataMessage::Request(piece)) => {
+                let Some(id) = self.their_metadata_id else {
+                    return Ok(());
+                };
+                let reply = match self.shared.torrent().metainfo() {
+                    Some(metainfo) => serve_piece(&metainfo.info_bytes, piece),
+                    None => MetadataMessage::Reject(piece),
+                };
+                self.send(Message::Extended {
+                    id,
+                    payload: reply.encode(),
+                })
+            }
+            Ok(MetadataMessage::Data { piece, data, .. }) => {
+                let done = match self.shared.metadata.lock().unwrap().as_mut() {
+                    Some(download) => download.on_data(piece, &data),
+                    None => return Ok(()),
+                };
+                match done {
+                    Ok(Some(info_bytes)) => {
+                        self.shared.on_metadata(&info_bytes).inspect_err(|err| {
+                            self.shared.fail(err);
+                        })
+                    }
+                    Ok(None) => Ok(()),
+                    Err(_) => Err(error("bad metadata")),
+                }
+            }
+            Ok(MetadataMessage::Reject(piece)) => {
+                if let Some(download) = self.shared.metadata.lock().unwrap().as_mut() {
+                    download.on_reject(piece);
+                }
+                Ok(())
+            }
+            Err(_) => Err(violation("bad metadata message")),
+        }
+    }
+
+    // Who else we're connected to, every `PEX_INTERVAL` for those who take ut_pex. Checked each
+    // time, since a magnet link's torrent only turns out to be private with its info dict
+    fn exchange_peers(&mut self) -> io::Result<()> {
+        let Some(id) = self.their_pex_id.filter(|_| self.pex.is_some()) else {
+            return Ok(());
+        };
+        if self
+            .last_pex
+            .is_some_and(|last| last.elapsed() < PEX_INTERVAL)
+        {
+            return Ok(());
+        }
+        let now = Instant::now();
+        self.last_pex = Some(now);
+        let connected: Vec<(SocketAddr, PexFlags)> = {
+            let torrent = self.shared.torrent();
+            if torrent.is_private() {
+                return Ok(());
+            }
+            let mut connected = torrent.peer_list().pex_sample(&mut self.rng);
+            connected.retain(|(addr, _)| *addr != self.addr);
+            connected
+        };
+        match self.pex.as_mut().unwrap().tick(&connected, now) {
+            Some(message) => self.send(Message::Extended {
+                id,
+                payload: message.encode(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    // Ones that come too often or don't parse are dropped, they're no reason to drop the peer
+    fn on_pex(&mut self, payload: &[u8]) {
+        let Some(pex) = &mut self.pex else {
+            return;
+        };
+        let now = Instant::now();
+        if let Ok(added) = pex.on_receive(payload, now) {
+            let mut torrent = self.shared.torrent();
+            if !torrent.is_private() {
+                torrent.peer_list_mut().add_from_pex(&added, now);
+            }
+        }
+    }
+
+    // A rendezvous makes us the relay: both sides get a connect, or they get an error. Anything
+    // else is about a rendezvous of ours, or someone else's that named us
+    fn on_holepunch(&mut self, payload: &[u8]) -> io::Result<()> {
+        let Ok(message) = HolepunchMessage::decode(payload) else {
+            return Ok(());
+        };
+        let HolepunchMessage::Rendezvous(target) = message else {
+            let mut holepunch = self.shared.holepunch.lock().unwrap();
+            if let Some(HolepunchEvent::Connect(addr)) =
+                holepunch.holepunches.handle(self.addr, message)
+            {
+                holepunch.dial.push(addr);
+            }
+            return Ok(());
+        };
+        let replies = {
+            let mut swarm = self.shared.swarm();
+            let replies =
+                holepunch::relay(self.addr, target, |target| match swarm.peers.get(&target) {
+                    Some(conn) if conn.holepunch => Ok(()),
+                    Some(_) => Err(ErrorCode::NoSupport),
+                    None => Err(ErrorCode::NotConnected),
+                });
+            // The target's goes out on its own connection
+            let mut ours = vec![];
+            for (to, message) in replies {
+                if to == self.addr {
+                    ours.push(message);
+                } else if let Some(conn) = swarm.peers.get_mut(&to) {
+                    conn.holepunch_messages.push(message);
+                }
+            }
+            ours
+        };
+        for message in replies {
+            self.send_holepunch(message)?;
+        }
+        Ok(())
+    }
+
+    fn send_holepunch(&mut self, message: HolepunchMessage) -> io::Result<()> {
+        match self.their_holepunch_id {
+            Some(id) => self.send(Message::Extended {
+                id,
+                payload: message.encode(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    // Brings our entry in the swarm up to date, and passes on what `poll` decided: whether to
+    // choke them, and the haves they're due
+    fn sync(&mut self) -> io::Result<()> {
+        let received = std::mem::take(&mut self.received);
+        if received > 0 {
+            self.shared
+                .torrent()
+                .peer_list_mut()
+                .on_downloaded(&self.addr, received);
+        }
+        let (unchoke, haves, holepunch_messages) = {
+            let mut swarm = self.shared.swarm();
+            let Some(conn) = swarm.peers.get_mut(&self.addr) else {
+                return Ok(());
+            };
+            let stats = &mut conn.stats;
+            if received > 0 {
+                stats.on_downloaded(received, Instant::now());
+            }
+            stats.on_uploaded(std::mem::take(&mut self.sent));
+            if stats.client != self.client {
+                stats.client = self.client.clone();
+            }
+            stats.flags.choked = self.choked;
+            stats.flags.interested = self.interested;
+            stats.queued_requests = self.pipeline.len();
+            if let Some(has) = &self.has {
+                stats.set_pieces(has.count_ones(), has.len());
+            }
+            conn.interested = self.peer_interested;
+            if self.has_changed && conn.has.is_some() {
+                conn.has = self.has.clone();
+                self.has_changed = false;
+            }
+            (
+                conn.unchoked,
+                std::mem::take(&mut conn.haves),
+                std::mem::take(&mut conn.holepunch_messages),
+            )
+        };
+        if unchoke == self.choking {
+            self.choking = !unchoke;
+            self.send(match unchoke {
+                true => Message::Unchoke,
+                false => Message::Choke,
+            })?;
+        }
+        for piece in haves {
+            self.send(Message::Have(piece))?;
+        }
+        for message in holepunch_messages {
+            self.send_holepunch(message)?;
+        }
+        Ok(())
+    }
+
+    fn abort_requests(&mut self) {
+        let blocks = self.pipeline.clear();
+        let mut torrent = self.shared.torrent();
+        if let Some(picker) = torrent.picker_mut() {
+            for block in &blocks {
+                picker.abort_request(block);
+            }
+        }
+    }
+
+    fn disconnect(&mut self) {
+        self.abort_requests();
+        if let Some(cache) = self.shared.io_cache.get() {
+            cache.lock().unwrap().remove_peer(&self.addr);
+        }
+        if let Some(has) = &self.has
+            && let Some(picker) = self.shared.torrent().picker_mut()
+        {
+            picker.remove_peer(has);
+        }
+    }
+
+    // Piece data waited for the upload limits in `serve`, the rest counts as control traffic
+    // here. It goes out even if we're stopping meanwhile
+    fn send(&mut self, message: Message) -> io::Result<()> {
+        let buf = message.encode();
+        if !matches!(message, Message::Piece { .. }) {
+            self.shared
+                .wait_to_upload(Traffic::Control, buf.len() as u64);
+        }
+        self.last_sent = Instant::now();
+        self.stream.write_all(&buf)
+    }
+}
+
+// Announces on each tracker's interval, `completed` as soon as we are, and `stopped` on the way
+// out
+fn announce_loop(shared: &Shared, key: u32) {
+    let mut event = AnnounceEvent::Started;
+    let mut next = Instant::now();
+    let mut was_complete = shared.torrent().status() == TorrentStatus::Seeding;
+    let mut wakes = shared.wakes.load(Ordering::SeqCst);
+    loop {
+        let stopping = shared.stopped();
+        // The trackers have likely dropped us while we were asleep
+        if shared.woke(&mut wakes) {
+            next = Instant::now();
+        }
+        let complete = shared.torrent().status() == TorrentStatus::Seeding;
+        if complete && !was_complete {
+            was_complete = true;
+            event = AnnounceEvent::Completed;
+            next = Instant::now();
+        }
+        if stopping && shared.quiet.load(Ordering::SeqCst) {
+            return;
+        }
+        if stopping {
+            event = AnnounceEvent::Stopped;
+        } else if Instant::now() < next {
+            thread::sleep(Duration::from_millis(250));
+            continue;
+        }
+
+        let (urls, request) = {
+            let torrent = shared.torrent();
+            let urls: Vec<String> = torrent.trackers().tiers().into_iter().flatten().collect();
+            let left = match torrent.metainfo() {
+                Some(_) => torrent.total_length() - torrent.bytes_done(),
+                // We don't know the size yet. Anything but 0 so the tracker doesn't take us for a
+                // seed
+                None => 1,
+            };
+            let request = AnnounceRequest {
+                info_hash: shared.info_hash,
+                peer_id: shared.peer_id,
+                port: shared.port,
+                uploaded: torrent.total_uploaded(),
+                downloaded: torrent.total_downloaded(),
+                left,
+                event,
+                num_want: Some(if stopping { 0 } else { 50 }),
+                key,
+                ipv4: None,
+                ipv6: None,
+            };
+            (urls, request)
+        };
+        let timeout = Duration::from_secs(if stopping { 3 } else { 10 });
+        let mut interval = Duration::from_secs(30 * 60);
+        let proxy = shared.proxy();
+        for url in &urls {
+            let response = match tracker::announce_via(url, &request, proxy.as_ref(), timeout) {
+                Ok(response) => response,
+                Err(err) => {
+                    shared.torrent().on_tracker_error(url, &err);
+                    continue;
+                }
+            };
+            interval = interval.min(response.interval);
+            let now = Instant::now();
+            let mut torrent = shared.torrent();
+            if let Some(warning) = &response.warning {
+                torrent.on_tracker_warning(url, warning);
+            }
+            for peer in response.peers {
+                torrent
+                    .peer_list_mut()
+                    .insert(peer, PeerSource::Tracker, PexFlags(0), now);
+            }
+        }
+        if stopping {
+            return;
+        }
+        event = AnnounceEvent::None;
+        next = Instant::now() + interval.max(Duration::from_secs(60));
+    }
+}
+
+fn dht_loop(shared: &Shared) {
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    let proxied = shared.proxy().map(|proxy| {
+        let socket = DhtSocket::start_via(&proxy, DhtConfig::default(), CONNECT_TIMEOUT);
+        (socket, proxy.allows_direct())
+    });
+    let dht = match proxied {
+        Some((Ok(socket), _)) => Ok(socket),
+        Some((Err(err), false)) => Err(err),
+        _ => DhtSocket::start(addr.into(), DhtConfig::default(), None).inspect(|socket| {
+            if let Ok(addr) = socket.local_addr() {
+                shared.dht_port.store(addr.port(), Ordering::SeqCst);
+            }
+        }),
+    };
+    let Ok(mut dht) = dht else {
+        return;
+    };
+    // Lookups wait for the bootstrap to find some nodes
+    let mut next_lookup = None;
+    let mut wakes = shared.wakes.load(Ordering::SeqCst);
+    while !shared.stopped() {
+        let Ok(events) = dht.poll(Duration::from_millis(200)) else {
+            return;
+        };
+        let now = Instant::now();
+        if shared.woke(&mut wakes) {
+            dht.dht().on_wake(now);
+        }
+        for event in events {
+            match event {
+                DhtEvent::Bootstrapped => {
+                    next_lookup.get_or_insert(now);
+                }
+                DhtEvent::Peers { peers, .. } => {
+                    let mut torrent = shared.torrent();
+                    for peer in peers {
+                        torrent
+                            .peer_list_mut()
+                            .insert(peer, PeerSource::Dht, PexFlags(0), now);
+                    }
+                }
+                DhtEvent::LookupDone { .. } => next_lookup = Some(now + DHT_LOOKUP_INTERVAL),
+                _ => {}
+            }
+        }
+        if next_lookup.is_some_and(|at| now >= at) {
+            // Which a magnet link's torrent only turns out to be once its info dict is in
+            if shared.torrent().is_private() {
+                return;
+            }
+            dht.dht().get_peers(shared.info_hash, now);
+            // Pushed back again when it's done
+            next_lookup = Some(now + DHT_LOOKUP_INTERVAL * 2);
+        }
+    }
+}
+
+// Announces the torrent on the LAN, and adds whoever else there has it
+fn lsd_loop(shared: &Shared) {
+    let Ok(mut lsd) = LsdSocket::bind(Lsd::new(shared.port, &mut Rng::new())) else {
+        return;
+    };
+    lsd.lsd().add_torrent(shared.info_hash, Instant::now());
+    while !shared.stopped() {
+        let Ok(peers) = lsd.poll(Duration::from_millis(200)) else {
+            return;
+        };
+        let now = Instant::now();
+        let mut torrent = shared.torrent();
+        // Like the DHT's, see `dht_loop`
+        if torrent.is_private() {
+            return;
+        }
+        for (info_hash, peer) in peers {
+            if info_hash == shared.info_hash {
+                torrent
+                    .peer_list_mut()
+                    .insert(peer, PeerSource::Lsd, PexFlags(0), now);
+            }
+        }
+    }
+}
+
+// Keeps each of the torrent's web seeds busy with a batch, fetched on a thread of its own.
+// Starts once there's an info dict and files to write to, and only if it lists any
+fn web_seed_loop(shared: &Shared) {
+    while shared.storage.get().is_none() {
+        if shared.stopped() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let seeds = match shared.torrent().metainfo() {
+        Some(metainfo) if !metainfo.url_list.is_empty() => {
+            WebSeeds::new(&metainfo.url_list, &metainfo.info)
+        }
+        _ => return,
+    };
+    let _ = shared.web_seeds.set(Mutex::new(WebSeeding {
+        seeds,
+        senders: HashMap::new(),
+        refetch: vec![],
+    }));
+    let (done, results) = mpsc::channel();
+    // What each busy seed was asked for, handed back to the picker if we stop first
+    let mut pending: HashMap<usize, Vec<Block>> = HashMap::new();
+    while !shared.stopped() {
+        while let Ok((seed, result, elapsed)) = results.try_recv() {
+            let blocks = pending.remove(&seed).unwrap_or_default();
+            // Only the disk fails, and that fails the download
+            if shared.on_web_batch(seed, &blocks, result, elapsed).is_err() {
+                return;
+            }
+        }
+        let Some((seed, blocks, requests)) = shared.next_web_batch(Instant::now()) else {
+            thread::sleep(Duration::from_millis(100));
+            continue;
+        };
+        pending.insert(seed, blocks);
+        let done = done.clone();
+        thread::spawn(move || {
+            let started = Instant::now();
+            let result = webseed::fetch_all(&requests, WEB_SEED_TIMEOUT);
+            let _ = done.send((seed, result, started.elapsed()));
+        });
+    }
+    for blocks in pending.values() {
+        shared.abort_blocks(blocks);
+    }
+}
+
+// For the bandwidth schedule, asked on every poll since daylight saving time moves it
+#[cfg(unix)]
+pub fn local_utc_offset() -> i64 {
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+        return 0;
+    }
+    tm.tm_gmtoff as i64
+}
+
+#[cfg(not(unix))]
+pub fn local_utc_offset() -> i64 {
+    0
+}
+
+fn error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// Something no well-behaved peer sends, which gets it banned
+#[derive(Debug)]
+struct Violation(&'static str);
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Violation {}
+
+fn violation(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, Violation(msg))
+}
+
+fn is_violation(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|err| err.is::<Violation>())
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use std::fs;
+
+    use sha1::{Digest, Sha1};
+
+    use super::*;
+    use crate::metainfo::MagnetLink;
+
+    // A .torrent's info dict for `data` in 16 KiB pieces
+    fn info_dict(data: &[u8]) -> Vec<u8> {
+        let pieces: Vec<u8> = data
+            .chunks(16384)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        let mut info = format!(
+            "d6:lengthi{}e4:name8:data.bin12:piece lengthi16384e6:pieces{}:",
+            data.len(),
+            pieces.len()
+        )
+        .into_bytes();
+        info.extend_from_slice(&pieces);
+        info.push(b'e');
+        info
+    }
+
+    #[test]
+    fn test_choking_and_allowed_fast() {
+        let dir = std::env::temp_dir().join(format!("hurricane-choking-{}", std::process::id()));
+        let data: Vec<u8> = (0..40 * 16384u32).map(|i| (i * 13 % 251) as u8).collect();
+        let metainfo = Metainfo::from_info_bytes(&info_dict(&data)).unwrap();
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("data.bin"), &data).unwrap();
+        let now = Instant::now();
+        let mut config = DownloadConfig::new(dir.clone());
+        (config.port, config.dht) = (0, false);
+        let mut seed = Download::start(Torrent::new(metainfo.clone(), now), config).unwrap();
+
+        let mut stream = TcpStream::connect(("127.0.0.1", seed.port())).unwrap();
+        let reserved = Reserved::default().with(Feature::Fast);
+        let ours = Handshake::new(reserved, metainfo.info_hash, [7; 20]);
+        stream.write_all(&ours.encode()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        // Their handshake, once the seed has taken the connection
+        let mut input = vec![];
+        while input.len() < HANDSHAKE_LEN {
+            seed.poll(Instant::now());
+            let mut buf = [0; HANDSHAKE_LEN];
+            if let Ok(n) = stream.read(&mut buf[input.len()..]) {
+                input.extend_from_slice(&buf[input.len()..input.len() + n]);
+            }
+        }
+        input.clear();
+        // The seed's next message, other than keep-alives, polling it meanwhile
+        let mut next = |stream: &mut TcpStream, seed: &mut Download| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while Instant::now() < deadline {
+                seed.poll(Instant::now());
+                if let Ok(Some((message, used))) = Message::decode(&input) {
+                    input.drain(..used);
+                    if message != Message::KeepAlive {
+                        return message;
+                    }
+                    continue;
+                }
+                let mut buf = [0; 65536];
+                if let Ok(n) = stream.read(&mut buf) {
+                    input.extend_from_slice(&buf[..n]);
+                }
+            }
+            panic!("nothing from the seed");
+        };
+
+        assert_eq!(next(&mut stream, &mut seed), Message::HaveAll);
+        let mut allowed = vec![];
+        while allowed.len() < 10 {
+            match next(&mut stream, &mut seed) {
+                Message::AllowedFast(piece) => allowed.push(piece),
+                message => panic!("{:?}", message),
+            }
+        }
+        let choked = (0..40).find(|p| !allowed.contains(p)).unwrap();
+        let requests = [
+            Block::new(choked, 0, 16384),
+            Block::new(allowed[0], 0, 16384),
+        ];
+        for block in requests {
+            stream.write_all(&Message::Request(block).encode()).unwrap();
+        }
+        // Choked, so only the allowed fast piece is served
+        assert_eq!(
+            next(&mut stream, &mut seed),
+            Message::RejectRequest(requests[0])
+        );
+        assert!(matches!(
+            next(&mut stream, &mut seed),
+            Message::Piece { piece, .. } if piece == allowed[0]
+        ));
+
+        // A free upload slot goes to whoever's interested right away
+        stream.write_all(&Message::Interested.encode()).unwrap();
+        assert_eq!(next(&mut stream, &mut seed), Message::Unchoke);
+        stream
+            .write_all(&Message::Request(requests[0]).encode())
+            .unwrap();
+        match next(&mut stream, &mut seed) {
+            Message::Piece {
+                piece,
+                offset,
+                data: block,
+            } => {
+                assert_eq!((piece, offset), (choked, 0));
+                let start = choked as usize * 16384;
+                assert_eq!(block, data[start..start + 16384]);
+            }
+            message => panic!("{:?}", message),
+        }
+        // Past the end of the piece is rejected whatever the choke state
+        let past = Block::new(choked, 16384, 1);
+        stream.write_all(&Message::Request(past).encode()).unwrap();
+        assert_eq!(next(&mut stream, &mut seed), Message::RejectRequest(past));
+        seed.stop();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_magnet_download_from_seed() {
+        let dir = std::env::temp_dir().join(format!("hurricane-download-{}", std::process::id()));
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let info = info_dict(&data);
+        let metainfo = Metainfo::from_info_bytes(&info).unwrap();
+        fs::create_dir_all(dir.join("seed")).unwrap();
+        fs::write(dir.join("seed/data.bin"), &data).unwrap();
+
+        let now = Instant::now();
+        let mut config = DownloadConfig::new(dir.join("seed"));
+        (config.port, config.dht) = (0, false);
+        let mut seed = Download::start(Torrent::new(metainfo.clone(), now), config).unwrap();
+        assert_eq!(seed.poll(now), DownloadState::Done);
+
+        // The leecher only has the info-hash and where to find the seed
+        let mut magnet = MagnetLink::new(metainfo.info_hash);
+        magnet.peers = vec![format!("127.0.0.1:{}", seed.port())];
+        let mut config = DownloadConfig::new(dir.join("leech"));
+        (config.port, config.dht) = (0, false);
+        let mut leech = Download::start(Torrent::from_magnet(&magnet, now), config).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(20);
+        let state = loop {
+            seed.poll(Instant::now());
+            match leech.poll(Instant::now()) {
+                DownloadState::Running if Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(20))
+                }
+                state => break state,
+            }
+        };
+        assert_eq!(state, DownloadState::Done);
+        assert_eq!(leech.torrent().name(), "data.bin");
+        // Every piece was hashed from the blocks as they came in
+        assert_eq!(leech.hashed_bytes(), (data.len() as u64, 0));
+        // And written out a piece at a time, as each was complete
+        let io = leech.io_stats().unwrap();
+        assert_eq!((io.blocks_written, io.write_runs), (7, 7));
+        assert!(seed.torrent().total_uploaded() >= data.len() as u64);
+        assert_eq!(fs::read(dir.join("leech/data.bin")).unwrap(), data);
+        leech.stop();
+        seed.stop();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_forced_encryption_and_upload_limit() {
+        let dir = std::env::temp_dir().join(format!("hurricane-mse-{}", std::process::id()));
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i * 11 % 251) as u8).collect();
+        let metainfo = Metainfo::from_info_bytes(&info_dict(&data)).unwrap();
+        fs::create_dir_all(dir.join("seed")).unwrap();
+        fs::write(dir.join("seed/data.bin"), &data).unwrap();
+
+        let now = Instant::now();
+        let mut config = DownloadConfig::new(dir.join("seed"));
+        (config.port, config.dht, config.lsd) = (0, false, false);
+        config.encryption = EncryptionPolicy::Forced;
+        let limits = RateLimits {
+            upload: Some(64 * 1024),
+            download: None,
+        };
+        config.limiter.set_limits(limits, now);
+        let mut seed = Download::start(Torrent::new(metainfo.clone(), now), config).unwrap();
+
+        // A plaintext handshake gets nowhere
+        let mut plain = TcpStream::connect(("127.0.0.1", seed.port())).unwrap();
+        let ours = Handshake::new(Reserved::default(), metainfo.info_hash, [7; 20]);
+        plain.write_all(&ours.encode()).unwrap();
+        plain
+            .set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            seed.poll(Instant::now());
+            match plain.read(&mut [0; HANDSHAKE_LEN]) {
+                Ok(0) | Err(_) if Instant::now() >= deadline => panic!("still connected"),
+                Ok(0) => break,
+                Err(err) if err.kind() == io::ErrorKind::ConnectionReset => break,
+                Ok(_) => panic!("handshake in the clear"),
+                Err(_) => {}
+            }
+        }
+
+        let mut magnet = MagnetLink::new(metainfo.info_hash);
+        magnet.peers = vec![format!("127.0.0.1:{}", seed.port())];
+        let mut config = DownloadConfig::new(dir.join("leech"));
+        (config.port, config.dht, config.lsd) = (0, false, false);
+        config.encryption = EncryptionPolicy::Forced;
+        let started = Instant::now();
+        let mut leech = Download::start(Torrent::from_magnet(&magnet, now), config).unwrap();
+        let mut encrypted = false;
+        let deadline = Instant::now() + Duration::from_secs(20);
+        let state = loop {
+            seed.poll(Instant::now());
+            encrypted |= seed
+                .torrent()
+                .peer_stats()
+                .iter()
+                .any(|peer| peer.flags.encrypted);
+            match leech.poll(Instant::now()) {
+                DownloadState::Running if Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(20))
+                }
+                state => break state,
+            }
+        };
+        assert_eq!(state, DownloadState::Done);
+        assert!(encrypted);
+        assert_eq!(fs::read(dir.join("leech/data.bin")).unwrap(), data);
+        // A second's worth up front, the rest at 64 KiB/s
+        assert!(started.elapsed() >= Duration::from_secs(2));
+        leech.stop();
+        seed.stop();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // An HTTP server that answers range requests for `data`, whatever the path
+    fn serve_ranges(data: Vec<u8>) -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = vec![];
+                let mut byte = [0];
+                while !request.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap_or(0) == 1 {
+                    request.push(byte[0]);
+                }
+                let request = String::from_utf8_lossy(&request);
+                let (start, end) = request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Range: bytes="))
+                    .and_then(|range| range.split_once('-'))
+                    .map(|(start, end)| (start.parse().unwrap(), end.parse::<usize>().unwrap()))
+                    .unwrap();
+                let body = &data[start..=end];
+                let _ = write!(
+                    stream,
+                    "HTTP/1.0 206 Partial Content\r\nContent-Length: {}\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(body);
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn test_web_seed_download() {
+        let dir = std::env::temp_dir().join(format!("hurricane-webseed-{}", std::process::id()));
+        let data: Vec<u8> = (0..150_000u32).map(|i| (i * 13 % 251) as u8).collect();
+        let mut metainfo = Metainfo::from_info_bytes(&info_dict(&data)).unwrap();
+        let port = serve_ranges(data.clone());
+        metainfo.url_list = vec![format!("http://127.0.0.1:{}/data.bin", port)];
+
+        // No peers at all, everything comes from the web seed
+        let now = Instant::now();
+        let mut config = DownloadConfig::new(dir.clone());
+        (config.port, config.dht, config.lsd) = (0, false, false);
+        let mut leech = Download::start(Torrent::new(metainfo, now), config).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(20);
+        let state = loop {
+            match leech.poll(Instant::now()) {
+                DownloadState::Running if Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(20))
+                }
+                state => break state,
+            }
+        };
+        assert_eq!(state, DownloadState::Done);
+        assert_eq!(leech.torrent().total_downloaded(), data.len() as u64);
+        assert_eq!(fs::read(dir.join("data.bin")).unwrap(), data);
+        leech.stop();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_web_seed_is_dropped() {
+        let dir = std::env::temp_dir().join(format!("hurricane-badseed-{}", std::process::id()));
+        let data: Vec<u8> = (0..150_000u32).map(|i| (i * 17 % 251) as u8).collect();
+        let mut metainfo = Metainfo::from_info_bytes(&info_dict(&data)).unwrap();
+        let port = serve_ranges(data.iter().map(|b| !b).collect());
+        metainfo.url_list = vec![format!("http://127.0.0.1:{}/data.bin", port)];
+
+        let now = Instant::now();
+        let mut config = DownloadConfig::new(dir.clone());
+        (config.port, config.dht, config.lsd) = (0, false, false);
+        let mut leech = Download::start(Torrent::new(metainfo, now), config).unwrap();
+        // Four pieces, then one more go at one of them, then it's out of budget
+        let shared = leech.shared.clone();
+        let disabled = || {
+            let web = shared.web_seeds.get()?.lock().unwrap();
+            Some(web.seeds.get(0).is_disabled())
+        };
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while disabled() != Some(true) && Instant::now() < deadline {
+            leech.poll(Instant::now());
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(disabled(), Some(true));
+        assert_eq!(leech.torrent().bytes_done(), 0);
+        leech.stop();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_relays_holepunch() {
+        let dir = std::env::temp_dir().join(format!("hurricane-relay-{}", std::process::id()));
+        let data: Vec<u8> = (0..4 * 16384u32).map(|i| (i % 251) as u8).collect();
+        let metainfo = Metainfo::from_info_bytes(&info_dict(&data)).unwrap();
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("data.bin"), &data).unwrap();
+        let mut config = DownloadConfig::new(dir.clone());
+        (config.port, config.dht) = (0, false);
+        let mut relay =
+            Download::start(Torrent::new(metainfo.clone(), Instant::now()), config).unwrap();
+
+        // Two peers that do ut_holepunch, under the IDs we use ourselves
+        let ext = ExtensionHandshake::ours(None, "test", false);
+        let id = ext.id_for(UT_HOLEPUNCH).unwrap();
+        let mut peers: Vec<TcpStream> = [1, 2]
+            .map(|n| {
+                let mut stream = TcpStream::connect(("127.0.0.1", relay.port())).unwrap();
+                let reserved = Reserved::default().with(Feature::Extended);
+                let ours = Handshake::new(reserved, metainfo.info_hash, [n; 20]);
+                stream.write_all(&ours.encode()).unwrap();
+                let handshake = Message::Extended {
+                    id: extension::HANDSHAKE_ID,
+                    payload: ext.encode(),
+                };
+                stream.write_all(&handshake.encode()).unwrap();
+                stream
+                    .set_read_timeout(Some(Duration::from_millis(20)))
+                    .unwrap();
+                stream
+            })
+            .into();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while relay
+            .shared
+            .swarm()
+            .peers
+            .values()
+            .filter(|c| c.holepunch)
+            .count()
+            < 2
+        {
+            assert!(Instant::now() < deadline);
+            relay.poll(Instant::now());
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let addrs: Vec<SocketAddr> = peers.iter().map(|p| p.local_addr().unwrap()).collect();
+        let rendezvous = Message::Extended {
+            id,
+            payload: HolepunchMessage::Rendezvous(addrs[1]).encode(),
+        };
+        peers[0].write_all(&rendezvous.encode()).unwrap();
+        // Each side is told to connect to the other
+        for (stream, other) in peers.iter_mut().zip([addrs[1], addrs[0]]) {
+            let mut input = vec![];
+            let message = loop {
+                assert!(Instant::now() < deadline);
+                relay.poll(Instant::now());
+                if let Ok(Some((message, used))) =
+                    Message::decode(&input[HANDSHAKE_LEN.min(input.len())..])
+                {
+                    input.drain(HANDSHAKE_LEN..HANDSHAKE_LEN + used);
+                    match message {
+                        Message::Extended { id: got, payload } if got == id => break payload,
+                        _ => continue,
+                    }
+                }
+                let mut buf = [0; 65536];
+                if let Ok(n) = stream.read(&mut buf) {
+                    input.extend_from_slice(&buf[..n]);
+                }
+            };
+            assert_eq!(
+                HolepunchMessage::decode(&message),
+                Ok(HolepunchMessage::Connect(other))
+            );
+        }
+        relay.stop();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
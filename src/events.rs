@@ -0,0 +1,149 @@
+// What happens to torrents, as it happens, for whoever wants to react to it instead of polling:
+// a script moving finished downloads away, a GUI's notifications, the daemon's `subscribe`.
+// Torrents queue their own events as the client drives them (`Torrent::on_piece_verified`,
+// `set_metainfo`, `set_error`, ...). The session adds what it sees (torrents added and removed,
+// status changes, seed goals met), tags each with the torrent's handle in
+// `Session::publish_events`, and sends them to every `Session::subscribe`r.
+// `Broadcast` is a broadcast channel on top of std's: every subscriber has a queue of its own,
+// `CAPACITY` long. One that falls that far behind misses events rather than holding up the
+// session, and its `missed` says how many. Dropping a `Subscription` ends it.
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::time::Duration;
+
+use crate::infohash::InfoHash;
+use crate::session::TorrentHandle;
+use crate::torrent::{SeedAction, SeedGoal, TorrentStatus};
+
+pub const CAPACITY: usize = 1024;
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Event {
+    pub handle: TorrentHandle,
+    pub info_hash: InfoHash,
+    pub kind: EventKind,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum EventKind {
+    Added { name: String },
+    // Whether by the user or a seed goal
+    Removed,
+    StatusChanged(TorrentStatus),
+    // The info dict of a torrent added by magnet link
+    MetadataReceived,
+    PieceVerified(u32),
+    // The last wanted piece passed its hash check
+    Completed,
+    Errored(String),
+    // A tracker's `warning message`, which comes with an otherwise good response
+    TrackerWarning { url: String, message: String },
+    // An announce that failed outright, e.g. a timeout or a `failure reason`
+    TrackerError { url: String, message: String },
+    // Sent data that failed hash checks one time too many
+    PeerBanned(SocketAddr),
+    SeedGoalReached { goal: SeedGoal, action: SeedAction },
+    // A followed BEP 46 link has a new version, which took the place of `previous` under the same
+    // handle
+    Updated { previous: InfoHash },
+}
+
+#[derive(Debug)]
+pub struct Subscription<T> {
+    receiver: Receiver<T>,
+    missed: Arc<AtomicU64>,
+}
+
+impl<T> Subscription<T> {
+    // None once there's nothing waiting
+    pub fn try_recv(&self) -> Option<T> {
+        self.receiver.try_recv().ok()
+    }
+
+    // None on timeout, or when the sender is gone for good
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<T> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
+
+    // Everything waiting, without blocking
+    pub fn try_iter(&self) -> impl Iterator<Item = T> + '_ {
+        self.receiver.try_iter()
+    }
+
+    // Events dropped because the queue was full
+    pub fn missed(&self) -> u64 {
+        self.missed.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+pub struct Broadcast<T> {
+    subscribers: Vec<(SyncSender<T>, Arc<AtomicU64>)>,
+}
+
+impl<T> Default for Broadcast<T> {
+    fn default() -> Self {
+        Broadcast {
+            subscribers: vec![],
+        }
+    }
+}
+
+impl<T: Clone> Broadcast<T> {
+    pub fn new() -> Self {
+        Broadcast::default()
+    }
+
+    // Gets what's sent from now on
+    pub fn subscribe(&mut self) -> Subscription<T> {
+        let (sender, receiver) = mpsc::sync_channel(CAPACITY);
+        let missed = Arc::new(AtomicU64::new(0));
+        self.subscribers.push((sender, missed.clone()));
+        Subscription { receiver, missed }
+    }
+
+    pub fn send(&mut self, value: &T) {
+        self.subscribers
+            .retain(|(sender, missed)| match sender.try_send(value.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    missed.fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+
+    pub fn num_subscribers(&self) -> usize {
+        self.subscribers.len()
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_broadcast() {
+        let mut broadcast = Broadcast::new();
+        broadcast.send(&0);
+        let first = broadcast.subscribe();
+        let second = broadcast.subscribe();
+        broadcast.send(&1);
+        drop(second);
+        broadcast.send(&2);
+        assert_eq!(broadcast.num_subscribers(), 1);
+        assert_eq!(first.try_iter().collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(first.try_recv(), None);
+
+        // A subscriber that doesn't keep up loses what doesn't fit
+        for i in 0..CAPACITY + 5 {
+            broadcast.send(&i);
+        }
+        assert_eq!(first.missed(), 5);
+        assert_eq!(first.try_iter().count(), CAPACITY);
+        assert_eq!(first.recv_timeout(Duration::from_millis(1)), None);
+    }
+}
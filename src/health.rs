@@ -0,0 +1,438 @@
+// Swarm health for many torrents at once, for curators checking whether a collection is still
+// alive. Every torrent's trackers are scraped, with the info-hashes that share a tracker batched
+// into as few requests as it allows, and optionally the DHT is asked for peers too.
+// Requests are grouped by tracker host, since the http:// and udp:// trackers of one site are
+// usually the same server: each host gets one worker, which spaces its requests out by
+// `tracker_interval`, and at most `concurrency` hosts are worked on at once. A host that fails a
+// request is taken to be down, and isn't asked about the rest.
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::dht::{DhtConfig, DhtEvent, DhtSocket};
+use crate::infohash::InfoHash;
+use crate::metainfo::{MagnetLink, Metainfo};
+use crate::proxy::ProxyConfig;
+use crate::tracker::{self, ScrapeStats, TrackerError, Url};
+
+// What a tracker said about one torrent. Ok(None) is a tracker that answered but doesn't know it
+pub type TrackerResult = Result<Option<ScrapeStats>, TrackerError>;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct SwarmTarget {
+    pub info_hash: InfoHash,
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+    // Private torrents are kept off the DHT
+    pub private: bool,
+}
+
+impl SwarmTarget {
+    pub fn new(info_hash: InfoHash) -> Self {
+        SwarmTarget {
+            info_hash,
+            name: None,
+            trackers: vec![],
+            private: false,
+        }
+    }
+
+    pub fn from_metainfo(metainfo: &Metainfo) -> Self {
+        let mut target = SwarmTarget::new(metainfo.info_hash);
+        target.name = Some(metainfo.info.name.clone());
+        target.private = metainfo.info.private;
+        target.add_trackers(metainfo.trackers().into_iter().flatten());
+        target
+    }
+
+    pub fn from_magnet(magnet: &MagnetLink) -> Self {
+        let mut target = SwarmTarget::new(magnet.info_hash);
+        target.name = magnet.display_name.clone();
+        target.add_trackers(magnet.trackers.iter().cloned());
+        target
+    }
+
+    // Tiers don't matter here, every tracker gets scraped. Ones already listed are skipped
+    pub fn add_trackers(&mut self, urls: impl IntoIterator<Item = String>) {
+        for url in urls {
+            if !self.trackers.contains(&url) {
+                self.trackers.push(url);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HealthConfig {
+    pub timeout: Duration,
+    // Tracker hosts being scraped at once
+    pub concurrency: usize,
+    // Least time between two requests to the same host
+    pub tracker_interval: Duration,
+    // Most info-hashes per scrape. UDP trackers take 74 a packet, and HTTP ones put them all
+    // in the URL, which some servers cut off long before that
+    pub batch: usize,
+    pub proxy: Option<ProxyConfig>,
+    // Looks for peers on the DHT as well, for at most `dht_timeout`
+    pub dht: Option<DhtConfig>,
+    pub dht_timeout: Duration,
+    // DHT lookups running at once
+    pub dht_lookups: usize,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            timeout: Duration::from_secs(15),
+            concurrency: 16,
+            tracker_interval: Duration::from_secs(1),
+            batch: 50,
+            proxy: None,
+            dht: None,
+            dht_timeout: Duration::from_secs(60),
+            dht_lookups: 8,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct SwarmHealth {
+    pub info_hash: InfoHash,
+    pub name: Option<String>,
+    // In the target's order
+    pub trackers: Vec<(String, TrackerResult)>,
+    // Distinct peers the DHT turned up. None if it wasn't asked
+    pub dht_peers: Option<usize>,
+}
+
+impl SwarmHealth {
+    // Trackers of one swarm mostly count the same peers, so the counts are the highest any
+    // tracker gave rather than a sum. None when no tracker had any
+    pub fn seeders(&self) -> Option<u32> {
+        self.best(|stats| stats.seeders)
+    }
+
+    pub fn leechers(&self) -> Option<u32> {
+        self.best(|stats| stats.leechers)
+    }
+
+    pub fn completed(&self) -> Option<u32> {
+        self.best(|stats| stats.completed)
+    }
+
+    // Trackers that answered, whether or not they knew the torrent
+    pub fn trackers_up(&self) -> usize {
+        self.trackers
+            .iter()
+            .filter(|(_, result)| result.is_ok())
+            .count()
+    }
+
+    // Anyone left to download it from, as far as we can tell
+    pub fn has_seeders(&self) -> bool {
+        self.seeders().is_some_and(|seeders| seeders > 0) || self.dht_peers.is_some_and(|n| n > 0)
+    }
+
+    fn best(&self, count: impl Fn(&ScrapeStats) -> u32) -> Option<u32> {
+        self.trackers
+            .iter()
+            .filter_map(|(_, result)| result.as_ref().ok()?.as_ref())
+            .map(count)
+            .max()
+    }
+}
+
+// Blocks until every tracker has answered or failed and the DHT lookups are done. The reports are
+// in the order of `targets`
+pub fn check(targets: &[SwarmTarget], config: &HealthConfig) -> Vec<SwarmHealth> {
+    let proxy = config.proxy.as_ref();
+    let scrape = |url: &str, info_hashes: &[InfoHash]| {
+        tracker::scrape_via(url, info_hashes, proxy, config.timeout)
+    };
+    thread::scope(|scope| {
+        let dht = config.dht.as_ref().map(|dht| {
+            let public: Vec<InfoHash> = targets
+                .iter()
+                .filter(|target| !target.private)
+                .map(|target| target.info_hash)
+                .collect();
+            scope.spawn(move || dht_peers(&public, dht, config))
+        });
+        let mut reports = scrape_all(targets, config, scrape);
+        if let Some(dht) = dht {
+            let found = dht.join().unwrap_or_default();
+            for (report, target) in reports.iter_mut().zip(targets) {
+                if !target.private {
+                    report.dht_peers = Some(found.get(&target.info_hash).map_or(0, HashSet::len));
+                }
+            }
+        }
+        reports
+    })
+}
+
+// The tracker half of `check`, with the scrape itself passed in
+fn scrape_all<F>(targets: &[SwarmTarget], config: &HealthConfig, scrape: F) -> Vec<SwarmHealth>
+where
+    F: Fn(&str, &[InfoHash]) -> Result<HashMap<InfoHash, ScrapeStats>, TrackerError> + Sync,
+{
+    // host -> url -> the info-hashes to ask it about
+    let mut hosts: BTreeMap<String, BTreeMap<&str, BTreeSet<InfoHash>>> = BTreeMap::new();
+    for target in targets {
+        for url in &target.trackers {
+            let host = Url::parse(url).map_or(url.as_str(), |url| url.host);
+            hosts
+                .entry(host.to_ascii_lowercase())
+                .or_default()
+                .entry(url)
+                .or_default()
+                .insert(target.info_hash);
+        }
+    }
+
+    let workers = config.concurrency.clamp(1, hosts.len().max(1));
+    let queue = Mutex::new(hosts.into_values().collect::<VecDeque<_>>());
+    let results = Mutex::new(HashMap::new());
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                loop {
+                    let Some(urls) = queue.lock().unwrap().pop_front() else {
+                        break;
+                    };
+                    scrape_host(&urls, config, &scrape, &results);
+                }
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    targets
+        .iter()
+        .map(|target| SwarmHealth {
+            info_hash: target.info_hash,
+            name: target.name.clone(),
+            trackers: target
+                .trackers
+                .iter()
+                .filter_map(|url| {
+                    let result = results.remove(&(url.as_str(), target.info_hash))?;
+                    Some((url.clone(), result))
+                })
+                .collect(),
+            dht_peers: None,
+        })
+        .collect()
+}
+
+// Every batch for one host's trackers in turn, spaced out. Once one fails the rest get the same
+// error without being sent
+fn scrape_host<'a, F>(
+    urls: &BTreeMap<&'a str, BTreeSet<InfoHash>>,
+    config: &HealthConfig,
+    scrape: &F,
+    results: &Mutex<HashMap<(&'a str, InfoHash), TrackerResult>>,
+) where
+    F: Fn(&str, &[InfoHash]) -> Result<HashMap<InfoHash, ScrapeStats>, TrackerError>,
+{
+    let mut last: Option<Instant> = None;
+    let mut down: Option<TrackerError> = None;
+    for (url, info_hashes) in urls {
+        let info_hashes: Vec<InfoHash> = info_hashes.iter().copied().collect();
+        for batch in info_hashes.chunks(config.batch.max(1)) {
+            let result = match &down {
+                Some(err) => Err(err.clone()),
+                None => {
+                    if let Some(last) = last {
+                        thread::sleep(config.tracker_interval.saturating_sub(last.elapsed()));
+                    }
+                    last = Some(Instant::now());
+                    scrape(url, batch)
+                }
+            };
+            let mut results = results.lock().unwrap();
+            match result {
+                Ok(mut stats) => {
+                    for info_hash in batch {
+                        results.insert((*url, *info_hash), Ok(stats.remove(info_hash)));
+                    }
+                }
+                Err(err) => {
+                    for info_hash in batch {
+                        results.insert((*url, *info_hash), Err(err.clone()));
+                    }
+                    down = Some(err);
+                }
+            }
+        }
+    }
+}
+
+// info-hash -> the peers the DHT knows of, from at most `dht_lookups` get_peers at a time
+fn dht_peers(
+    info_hashes: &[InfoHash],
+    dht: &DhtConfig,
+    config: &HealthConfig,
+) -> HashMap<InfoHash, HashSet<SocketAddr>> {
+    let mut found: HashMap<InfoHash, HashSet<SocketAddr>> = HashMap::new();
+    if info_hashes.is_empty() {
+        return found;
+    }
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    let Ok(mut socket) = DhtSocket::start(addr.into(), dht.clone(), None) else {
+        return found;
+    };
+
+    let deadline = Instant::now() + config.dht_timeout;
+    let mut waiting = info_hashes.iter().copied();
+    let mut running = 0;
+    let mut bootstrapped = false;
+    while Instant::now() < deadline {
+        let Ok(events) = socket.poll(Duration::from_millis(100)) else {
+            break;
+        };
+        let now = Instant::now();
+        for event in events {
+            match event {
+                DhtEvent::Bootstrapped => bootstrapped = true,
+                DhtEvent::Peers { info_hash, peers } => {
+                    found.entry(info_hash).or_default().extend(peers)
+                }
+                DhtEvent::LookupDone { .. } => running -= 1,
+                _ => {}
+            }
+        }
+        if !bootstrapped {
+            continue;
+        }
+        while running < config.dht_lookups.max(1) {
+            let Some(info_hash) = waiting.next() else {
+                break;
+            };
+            socket.dht().get_peers(info_hash, now);
+            running += 1;
+        }
+        if running == 0 {
+            break;
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn target(byte: u8, trackers: &[&str]) -> SwarmTarget {
+        let mut target = SwarmTarget::new(InfoHash([byte; 20]));
+        target.add_trackers(trackers.iter().map(|url| url.to_string()));
+        target
+    }
+
+    fn stats(seeders: u32) -> ScrapeStats {
+        ScrapeStats {
+            seeders,
+            completed: seeders * 10,
+            leechers: 1,
+        }
+    }
+
+    #[test]
+    fn test_scrape_all() {
+        let targets = [
+            target(1, &["udp://a.example:80", "http://b.example/announce"]),
+            target(2, &["udp://a.example:80", "http://a.example/announce"]),
+            target(3, &["udp://a.example:80", "http://down.example/announce"]),
+        ];
+        let config = HealthConfig {
+            batch: 2,
+            tracker_interval: Duration::from_millis(20),
+            ..HealthConfig::default()
+        };
+        let requests = Mutex::new(vec![]);
+        let start = Instant::now();
+        let reports = scrape_all(&targets, &config, |url, info_hashes| {
+            requests
+                .lock()
+                .unwrap()
+                .push((url.to_string(), info_hashes.len(), start.elapsed()));
+            match url {
+                "http://down.example/announce" => Err(TrackerError::InvalidUrl),
+                // Knows everything but the second torrent
+                "udp://a.example:80" => Ok(info_hashes
+                    .iter()
+                    .filter(|info_hash| info_hash.0[0] != 2)
+                    .map(|info_hash| (*info_hash, stats(info_hash.0[0] as u32)))
+                    .collect()),
+                _ => Ok(info_hashes
+                    .iter()
+                    .map(|info_hash| (*info_hash, stats(5)))
+                    .collect()),
+            }
+        });
+
+        // Batches of two, and a.example's three requests spaced out
+        let requests = requests.into_inner().unwrap();
+        assert_eq!(requests.len(), 5);
+        let mut a: Vec<_> = requests
+            .iter()
+            .filter(|(url, ..)| url.contains("a.example"))
+            .collect();
+        a.sort_by_key(|(.., at)| *at);
+        assert_eq!(a.len(), 3);
+        assert!(a[2].2 - a[1].2 >= config.tracker_interval);
+        assert!(a[1].2 - a[0].2 >= config.tracker_interval);
+
+        assert_eq!(
+            reports[0].trackers,
+            [
+                ("udp://a.example:80".to_string(), Ok(Some(stats(1)))),
+                ("http://b.example/announce".to_string(), Ok(Some(stats(5)))),
+            ]
+        );
+        assert_eq!(reports[0].seeders(), Some(5));
+        assert_eq!(reports[0].completed(), Some(50));
+        assert_eq!(reports[1].trackers[0].1, Ok(None));
+        assert_eq!(reports[1].seeders(), Some(5));
+        assert_eq!(reports[2].seeders(), Some(3));
+        assert_eq!(reports[2].trackers_up(), 1);
+        assert_eq!(reports[2].trackers[1].1, Err(TrackerError::InvalidUrl));
+    }
+
+    #[test]
+    fn test_nothing_answers() {
+        let targets = [
+            target(1, &["udp://a.example:80"]),
+            target(2, &["udp://a.example:80"]),
+            target(3, &[]),
+        ];
+        let config = HealthConfig {
+            batch: 1,
+            ..HealthConfig::default()
+        };
+        let requests = Mutex::new(0);
+        let reports = scrape_all(&targets, &config, |_, _| {
+            *requests.lock().unwrap() += 1;
+            Err(TrackerError::Io(std::io::ErrorKind::TimedOut))
+        });
+
+        // Down after the first batch, and not asked about the second
+        assert_eq!(requests.into_inner().unwrap(), 1);
+        assert_eq!(reports[1].trackers_up(), 0);
+        assert_eq!(
+            reports[1].trackers[0].1,
+            Err(TrackerError::Io(std::io::ErrorKind::TimedOut))
+        );
+        assert_eq!(reports[0].seeders(), None);
+        assert!(!reports[0].has_seeders());
+        assert!(reports[2].trackers.is_empty());
+
+        let dht_only = SwarmHealth {
+            dht_peers: Some(3),
+            ..reports[2].clone()
+        };
+        assert!(dht_only.has_seeders());
+    }
+}
@@ -0,0 +1,72 @@
+use std::fmt;
+
+// SHA-1 of a torrent's bencoded info dict. It's how the whole network refers to a torrent
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy)]
+pub struct InfoHash(pub [u8; 20]);
+
+impl InfoHash {
+    pub fn from_bytes(bytes: &[u8]) -> Option<InfoHash> {
+        Some(InfoHash(bytes.try_into().ok()?))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn from_hex(hex: &str) -> Option<InfoHash> {
+        if hex.len() != 40 || !hex.is_ascii() {
+            return None;
+        }
+
+        let mut bytes = [0u8; 20];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+        }
+        Some(InfoHash(bytes))
+    }
+}
+
+impl fmt::Display for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl fmt::Debug for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "InfoHash({})", self.to_hex())
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_roundtrip() {
+        let hex = "c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+        let hash = InfoHash::from_hex(hex).unwrap();
+
+        assert_eq!(hash.0[0], 0xc1);
+        assert_eq!(hash.to_hex(), hex);
+    }
+
+    #[test]
+    fn test_hex_invalid() {
+        assert_eq!(InfoHash::from_hex("c12f"), None);
+        assert_eq!(
+            InfoHash::from_hex("zz2fe1c06bba254a9dc9f519b335aa7c1367a88a"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_from_bytes_len() {
+        assert!(InfoHash::from_bytes(&[0; 20]).is_some());
+        assert!(InfoHash::from_bytes(&[0; 19]).is_none());
+    }
+}
@@ -0,0 +1,79 @@
+// Core types. These are always built and have no dependencies
+pub mod audit;
+pub mod bitfield;
+pub mod compact;
+pub mod dns;
+pub mod infohash;
+pub mod limiter;
+pub mod proxy;
+pub mod rate;
+pub mod rng;
+pub mod schedule;
+pub mod statefile;
+pub mod suspend;
+
+#[cfg(feature = "bencode")]
+pub use ::bencode;
+
+#[cfg(feature = "dht")]
+pub mod dht;
+
+#[cfg(feature = "metainfo")]
+pub mod metainfo;
+
+#[cfg(feature = "tracker-client")]
+pub mod tracker;
+
+#[cfg(feature = "full-client")]
+pub mod blocklist;
+#[cfg(feature = "full-client")]
+pub mod disk;
+#[cfg(feature = "full-client")]
+pub mod download;
+#[cfg(feature = "full-client")]
+pub mod events;
+#[cfg(feature = "full-client")]
+pub mod health;
+#[cfg(feature = "full-client")]
+pub mod lsd;
+#[cfg(feature = "full-client")]
+pub mod metrics;
+#[cfg(feature = "full-client")]
+pub mod peer;
+#[cfg(feature = "full-client")]
+pub mod persist;
+#[cfg(feature = "full-client")]
+pub mod picker;
+#[cfg(feature = "full-client")]
+pub mod portmap;
+#[cfg(feature = "full-client")]
+pub mod rpc;
+#[cfg(feature = "full-client")]
+pub mod session;
+#[cfg(feature = "full-client")]
+pub mod shutdown;
+#[cfg(feature = "full-client")]
+pub mod stream;
+#[cfg(feature = "full-client")]
+pub mod torrent;
+#[cfg(feature = "full-client")]
+pub mod watch;
+#[cfg(feature = "full-client")]
+pub mod webseed;
+
+#[cfg(feature = "webtorrent")]
+pub mod webtorrent;
+
+#[cfg(feature = "config")]
+pub mod config;
+
+#[cfg(feature = "daemon")]
+pub mod daemon;
+#[cfg(feature = "daemon")]
+pub mod tls;
+
+#[cfg(feature = "python")]
+mod python;
+
+#[cfg(feature = "node")]
+pub mod nodejs;
@@ -0,0 +1,272 @@
+// Upload rate limiting. Payload (piece data) goes through a token bucket filled at the user's
+// limit. Everything else (haves, requests, PEX, ut_metadata) has its own small bucket on top of
+// that, so a client capped to almost nothing still tells the swarm what it has and can hand out
+// metadata. Control traffic that overflows its bucket borrows from the payload one, never the
+// other way round.
+// `RateLimiter` is what the blocking engine in `download` shares between the peer threads of
+// every torrent: a bucket for what we request, and an `UploadLimiter` for everything we send.
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+use crate::schedule::RateLimits;
+
+// Guaranteed rate for control traffic, in bytes per second, whatever the payload limit
+pub const CONTROL_RATE: u64 = 16 * 1024;
+
+// Buckets hold at least this much, so the usual 16 KiB block or ut_metadata piece with its headers
+// fits. Anything bigger goes out on credit, see `TokenBucket::try_take`
+pub const MIN_BURST: u64 = 32 * 1024;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Traffic {
+    Payload,
+    Control,
+}
+
+// Bytes per second, refilled continuously and capped at one second's worth (or `MIN_BURST`). A
+// rate of 0 lets nothing through at all, e.g. a schedule's `up=0`
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    // None is unlimited
+    rate: Option<u64>,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Option<u64>, now: Instant) -> Self {
+        let mut bucket = TokenBucket {
+            rate,
+            tokens: 0.0,
+            last: now,
+        };
+        bucket.tokens = bucket.burst();
+        bucket
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.rate
+    }
+
+    pub fn set_rate(&mut self, rate: Option<u64>, now: Instant) {
+        self.refill(now);
+        self.rate = rate;
+        self.tokens = self.tokens.min(self.burst());
+    }
+
+    // Whole bytes that could go out right now
+    pub fn available(&mut self, now: Instant) -> u64 {
+        if self.rate.is_none() {
+            return u64::MAX;
+        }
+        self.refill(now);
+        self.tokens as u64
+    }
+
+    // More than the bucket can ever hold, e.g. a big bitfield or a 128 KiB block, is let through
+    // once it's full and leaves it in debt, to be paid off before anything else goes. Otherwise
+    // it would never get through at all, and the connection would stall behind it
+    pub fn try_take(&mut self, bytes: u64, now: Instant) -> bool {
+        if self.rate == Some(0) {
+            return false;
+        }
+        if self.available(now) < bytes && self.tokens < self.burst() {
+            return false;
+        }
+        if self.rate.is_some() {
+            self.tokens -= bytes as f64;
+        }
+        true
+    }
+
+    fn burst(&self) -> f64 {
+        match self.rate {
+            None | Some(0) => 0.0,
+            Some(rate) => rate.max(MIN_BURST) as f64,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        if let Some(rate) = self.rate {
+            self.tokens = (self.tokens + elapsed * rate as f64).min(self.burst());
+        }
+    }
+}
+
+// Session-wide upload limit, shared by every peer's send queue
+#[derive(Debug, Clone)]
+pub struct UploadLimiter {
+    payload: TokenBucket,
+    control: TokenBucket,
+}
+
+impl UploadLimiter {
+    // `limit` in bytes per second, None for unlimited
+    pub fn new(limit: Option<u64>, now: Instant) -> Self {
+        UploadLimiter {
+            payload: TokenBucket::new(limit, now),
+            control: TokenBucket::new(Some(CONTROL_RATE), now),
+        }
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        self.payload.rate()
+    }
+
+    pub fn set_limit(&mut self, limit: Option<u64>, now: Instant) {
+        self.payload.set_rate(limit, now);
+    }
+
+    // Whether `bytes` of this kind of traffic may go out now. If so they've been accounted for
+    pub fn try_send(&mut self, traffic: Traffic, bytes: u64, now: Instant) -> bool {
+        match traffic {
+            Traffic::Payload => self.payload.try_take(bytes, now),
+            Traffic::Control => {
+                self.control.try_take(bytes, now) || self.payload.try_take(bytes, now)
+            }
+        }
+    }
+}
+
+// Both directions' limits behind locks of their own. Clones share them, so new limits take
+// effect for every holder at once
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    shared: Arc<Buckets>,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    download: Mutex<TokenBucket>,
+    upload: Mutex<UploadLimiter>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits, now: Instant) -> Self {
+        RateLimiter {
+            shared: Arc::new(Buckets {
+                download: Mutex::new(TokenBucket::new(limits.download, now)),
+                upload: Mutex::new(UploadLimiter::new(limits.upload, now)),
+            }),
+        }
+    }
+
+    pub fn limits(&self) -> RateLimits {
+        RateLimits {
+            upload: self.upload().limit(),
+            download: self.download().rate(),
+        }
+    }
+
+    pub fn set_limits(&self, limits: RateLimits, now: Instant) {
+        self.download().set_rate(limits.download, now);
+        self.upload().set_limit(limits.upload, now);
+    }
+
+    pub fn download(&self) -> MutexGuard<'_, TokenBucket> {
+        self.shared.download.lock().unwrap()
+    }
+
+    pub fn upload(&self) -> MutexGuard<'_, UploadLimiter> {
+        self.shared.upload.lock().unwrap()
+    }
+}
+
+impl Default for RateLimiter {
+    // Unlimited both ways
+    fn default() -> Self {
+        RateLimiter::new(RateLimits::default(), Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_bucket_refills() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(Some(100_000), now);
+
+        assert!(bucket.try_take(100_000, now));
+        assert!(!bucket.try_take(1, now));
+        assert_eq!(bucket.available(now + Duration::from_millis(500)), 50_000);
+        // Never more than a second's worth
+        assert_eq!(bucket.available(now + Duration::from_secs(10)), 100_000);
+    }
+
+    #[test]
+    fn test_debt() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(Some(MIN_BURST), now);
+
+        // Too big for the bucket, so it waits for a full one and then overdraws it
+        assert!(bucket.try_take(1, now));
+        assert!(!bucket.try_take(4 * MIN_BURST, now));
+        assert!(bucket.try_take(4 * MIN_BURST, now + Duration::from_secs(1)));
+        assert!(!bucket.try_take(1, now + Duration::from_secs(3)));
+        assert!(bucket.try_take(1, now + Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_zero_rate() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(Some(0), now);
+
+        // Not even once, and not on credit
+        assert!(!bucket.try_take(1, now));
+        assert_eq!(bucket.available(now + Duration::from_secs(10)), 0);
+        assert!(!bucket.try_take(4 * MIN_BURST, now + Duration::from_secs(10)));
+        bucket.set_rate(Some(MIN_BURST), now + Duration::from_secs(10));
+        assert!(bucket.try_take(MIN_BURST, now + Duration::from_secs(11)));
+
+        // Control traffic still has its own bucket
+        let mut limiter = UploadLimiter::new(Some(0), now);
+        assert!(!limiter.try_send(Traffic::Payload, 1, now));
+        assert!(limiter.try_send(Traffic::Control, 100, now));
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let now = Instant::now();
+        let mut limiter = UploadLimiter::new(None, now);
+
+        assert!(limiter.try_send(Traffic::Payload, u64::MAX, now));
+        assert!(limiter.try_send(Traffic::Payload, u64::MAX, now));
+    }
+
+    #[test]
+    fn test_control_survives_payload_cap() {
+        let now = Instant::now();
+        let mut limiter = UploadLimiter::new(Some(1), now);
+
+        // Spend the payload bucket's burst. Payload is stuck, control isn't
+        assert!(limiter.try_send(Traffic::Payload, MIN_BURST, now));
+        assert!(!limiter.try_send(Traffic::Payload, 100, now));
+        assert!(limiter.try_send(Traffic::Control, 16 * 1024, now));
+        assert!(!limiter.try_send(Traffic::Control, MIN_BURST, now));
+        assert!(limiter.try_send(Traffic::Control, 4 * 1024, now + Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn test_clones_share_buckets() {
+        let now = Instant::now();
+        let limits = RateLimits {
+            upload: Some(MIN_BURST),
+            download: None,
+        };
+        let limiter = RateLimiter::new(limits, now);
+        let clone = limiter.clone();
+
+        assert!(clone.upload().try_send(Traffic::Payload, MIN_BURST, now));
+        assert!(!limiter.upload().try_send(Traffic::Payload, 1, now));
+        assert!(limiter.download().try_take(u64::MAX, now));
+        clone.set_limits(RateLimits::default(), now);
+        assert_eq!(limiter.limits(), RateLimits::default());
+        assert!(limiter.upload().try_send(Traffic::Payload, 1, now));
+    }
+}
@@ -0,0 +1,300 @@
+// Local Service Discovery (BEP 14).
+// Every few minutes we multicast an HTTP-ish BT-SEARCH message listing the torrents we're in, and
+// listen for everyone else's. Peers on the same LAN find each other within seconds and can talk
+// at wire speed without trackers or the DHT being involved. Sans-IO like the DHT; `LsdSocket`
+// does the multicast plumbing.
+// Private torrents (BEP 27) must not be announced. They're turned away by `add_torrent`, and
+// dropped on the next tick once one added by magnet link turns out to be private (`audit`
+// catches anything that slips through in debug builds).
+pub mod socket;
+
+pub use socket::LsdSocket;
+
+use std::collections::{HashMap, VecDeque};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::time::{Duration, Instant};
+
+use crate::audit::{self, Channel};
+use crate::infohash::InfoHash;
+use crate::rng::Rng;
+
+pub const LSD_PORT: u16 = 6771;
+pub const LSD_V4: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), LSD_PORT);
+pub const LSD_V6: SocketAddrV6 = SocketAddrV6::new(
+    Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f),
+    LSD_PORT,
+    0,
+    0,
+);
+
+// How often each torrent is announced again
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// Info-hashes per message. BEP 14 allows several, but a message has to fit in one datagram
+pub const MAX_INFO_HASHES: usize = 20;
+
+#[derive(PartialEq, Debug)]
+pub enum LsdError {
+    NotAnAnnounce,
+    MissingField(&'static str),
+    InvalidField(&'static str),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Announce {
+    // Where the announcer accepts peer connections
+    pub port: u16,
+    pub info_hashes: Vec<InfoHash>,
+    // Random per-client value so we can recognise our own messages when multicast loops them
+    // back to us
+    pub cookie: Option<String>,
+}
+
+impl Announce {
+    // `host` is the group the message is sent to, e.g. "239.192.152.143:6771"
+    pub fn encode(&self, host: &str) -> Vec<u8> {
+        let mut msg = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+            host, self.port
+        );
+        for info_hash in &self.info_hashes {
+            msg.push_str(&format!("Infohash: {}\r\n", info_hash.to_hex()));
+        }
+        if let Some(cookie) = &self.cookie {
+            msg.push_str(&format!("cookie: {}\r\n", cookie));
+        }
+        msg.push_str("\r\n\r\n");
+        msg.into_bytes()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Announce, LsdError> {
+        let text = std::str::from_utf8(buf).map_err(|_| LsdError::NotAnAnnounce)?;
+        let mut lines = text.lines();
+        if lines.next().map(str::trim) != Some("BT-SEARCH * HTTP/1.1") {
+            return Err(LsdError::NotAnAnnounce);
+        }
+
+        let mut port = None;
+        let mut info_hashes = vec![];
+        let mut cookie = None;
+        for line in lines {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            // Header names are case-insensitive like in HTTP, and clients disagree on the casing
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => {
+                    let parsed = value.parse().ok().filter(|p| *p != 0);
+                    port = Some(parsed.ok_or(LsdError::InvalidField("port"))?);
+                }
+                "infohash" => info_hashes.push(
+                    InfoHash::from_hex(&value.to_ascii_lowercase())
+                        .ok_or(LsdError::InvalidField("infohash"))?,
+                ),
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        if info_hashes.is_empty() {
+            return Err(LsdError::MissingField("infohash"));
+        }
+        Ok(Announce {
+            port: port.ok_or(LsdError::MissingField("port"))?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Lsd {
+    port: u16,
+    cookie: String,
+    // Torrent -> when it's next due to be announced
+    torrents: HashMap<InfoHash, Instant>,
+    outbox: VecDeque<Announce>,
+}
+
+impl Lsd {
+    // `port` is our peer listen port
+    pub fn new(port: u16, rng: &mut Rng) -> Self {
+        Lsd {
+            port,
+            cookie: format!("{:016x}", rng.next_u64()),
+            torrents: HashMap::new(),
+            outbox: VecDeque::new(),
+        }
+    }
+
+    pub fn cookie(&self) -> &str {
+        &self.cookie
+    }
+
+    // Announced on the next tick, then every ANNOUNCE_INTERVAL. Private torrents never are
+    pub fn add_torrent(&mut self, info_hash: InfoHash, now: Instant) {
+        if audit::is_private(info_hash) {
+            return;
+        }
+        self.torrents.entry(info_hash).or_insert(now);
+    }
+
+    pub fn remove_torrent(&mut self, info_hash: &InfoHash) {
+        self.torrents.remove(info_hash);
+    }
+
+    pub fn tick(&mut self, now: Instant) {
+        // A magnet link's torrent only turns out to be private once its info dict is in
+        self.torrents
+            .retain(|info_hash, _| !audit::is_private(*info_hash));
+        let mut due: Vec<InfoHash> = self
+            .torrents
+            .iter()
+            .filter(|(_, next)| **next <= now)
+            .map(|(info_hash, _)| *info_hash)
+            .collect();
+        due.sort();
+        for info_hash in &due {
+            audit::check_public(*info_hash, Channel::Lsd);
+            self.torrents.insert(*info_hash, now + ANNOUNCE_INTERVAL);
+        }
+
+        for chunk in due.chunks(MAX_INFO_HASHES) {
+            self.outbox.push_back(Announce {
+                port: self.port,
+                info_hashes: chunk.to_vec(),
+                cookie: Some(self.cookie.clone()),
+            });
+        }
+    }
+
+    // Messages to multicast to every group we're on
+    pub fn poll_transmit(&mut self) -> Option<Announce> {
+        self.outbox.pop_front()
+    }
+
+    // Peers announcing torrents we're in, as (torrent, address to connect to). Our own looped
+    // back messages and torrents we don't have are ignored
+    pub fn handle_packet(&mut self, buf: &[u8], from: SocketAddr) -> Vec<(InfoHash, SocketAddr)> {
+        let Ok(announce) = Announce::decode(buf) else {
+            return vec![];
+        };
+        if announce.cookie.as_deref() == Some(self.cookie.as_str()) {
+            return vec![];
+        }
+
+        let peer = SocketAddr::new(from.ip(), announce.port);
+        announce
+            .info_hashes
+            .into_iter()
+            .filter(|info_hash| self.torrents.contains_key(info_hash))
+            .map(|info_hash| (info_hash, peer))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    const HEX: &str = "c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+
+    #[test]
+    fn test_decode() {
+        let raw = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\nInfohash: {}\r\ncookie: abc\r\n\r\n\r\n",
+            HEX.to_uppercase()
+        );
+        let announce = Announce::decode(raw.as_bytes()).unwrap();
+
+        assert_eq!(announce.port, 6881);
+        assert_eq!(announce.info_hashes, vec![InfoHash::from_hex(HEX).unwrap()]);
+        assert_eq!(announce.cookie.as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let announce = Announce {
+            port: 51413,
+            info_hashes: vec![InfoHash([1; 20]), InfoHash([2; 20])],
+            cookie: None,
+        };
+
+        assert_eq!(
+            Announce::decode(&announce.encode("[ff15::efc0:988f]:6771")),
+            Ok(announce)
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(
+            Announce::decode(b"GET / HTTP/1.1\r\n\r\n"),
+            Err(LsdError::NotAnAnnounce)
+        );
+        assert_eq!(
+            Announce::decode(b"BT-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n"),
+            Err(LsdError::MissingField("infohash"))
+        );
+        assert_eq!(
+            Announce::decode(b"BT-SEARCH * HTTP/1.1\r\nPort: x\r\nInfohash: 00\r\n\r\n"),
+            Err(LsdError::InvalidField("port"))
+        );
+    }
+
+    #[test]
+    fn test_ignores_own_announces() {
+        let now = Instant::now();
+        let info_hash = InfoHash([1; 20]);
+        let mut ours = Lsd::new(6881, &mut Rng::with_seed(1));
+        let mut theirs = Lsd::new(7000, &mut Rng::with_seed(2));
+        ours.add_torrent(info_hash, now);
+        theirs.add_torrent(info_hash, now);
+        ours.tick(now);
+
+        let msg = ours.poll_transmit().unwrap().encode("239.192.152.143:6771");
+        let from: SocketAddr = "192.168.1.5:6771".parse().unwrap();
+        assert!(ours.handle_packet(&msg, from).is_empty());
+        assert_eq!(
+            theirs.handle_packet(&msg, from),
+            vec![(info_hash, "192.168.1.5:6881".parse().unwrap())]
+        );
+    }
+
+    #[test]
+    fn test_announce_schedule() {
+        let now = Instant::now();
+        let mut lsd = Lsd::new(6881, &mut Rng::with_seed(1));
+        for i in 0..25 {
+            lsd.add_torrent(InfoHash([i; 20]), now);
+        }
+
+        lsd.tick(now);
+        assert_eq!(
+            lsd.poll_transmit().unwrap().info_hashes.len(),
+            MAX_INFO_HASHES
+        );
+        assert_eq!(lsd.poll_transmit().unwrap().info_hashes.len(), 5);
+        lsd.tick(now + ANNOUNCE_INTERVAL / 2);
+        assert!(lsd.poll_transmit().is_none());
+        lsd.tick(now + ANNOUNCE_INTERVAL);
+        assert!(lsd.poll_transmit().is_some());
+    }
+
+    #[test]
+    fn test_private_never_announced() {
+        let now = Instant::now();
+        let mut lsd = Lsd::new(6881, &mut Rng::with_seed(1));
+        // Every test in the crate shares the registry, so these hashes are used nowhere else
+        let (private, later) = (InfoHash([0xa6; 20]), InfoHash([0xa7; 20]));
+        audit::register_private(private);
+        lsd.add_torrent(private, now);
+        lsd.add_torrent(later, now);
+        audit::register_private(later);
+        lsd.tick(now);
+        assert!(lsd.poll_transmit().is_none());
+        audit::unregister_private(private);
+        audit::unregister_private(later);
+    }
+}
@@ -0,0 +1,122 @@
+// Runs `Lsd` over the multicast groups. Other clients on the machine listen on the same port, so
+// the sockets are bound with address reuse. IPv6 is best effort: plenty of hosts have no route
+// for the group, and LSD over IPv4 alone is still useful
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use super::{LSD_PORT, LSD_V4, LSD_V6, Lsd};
+use crate::infohash::InfoHash;
+
+// Announces are a few hundred bytes
+const MAX_PACKET: usize = 1500;
+
+#[derive(Debug)]
+pub struct LsdSocket {
+    v4: UdpSocket,
+    v6: Option<UdpSocket>,
+    lsd: Lsd,
+    buf: Vec<u8>,
+}
+
+impl LsdSocket {
+    pub fn bind(lsd: Lsd) -> io::Result<Self> {
+        let v4 = reusable(
+            Domain::IPV4,
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, LSD_PORT).into(),
+        )?;
+        v4.join_multicast_v4(LSD_V4.ip(), &Ipv4Addr::UNSPECIFIED)?;
+        // Other clients on this machine count as local peers too. Our own messages coming back
+        // are filtered out by cookie
+        v4.set_multicast_loop_v4(true)?;
+
+        let v6 = reusable(
+            Domain::IPV6,
+            SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, LSD_PORT, 0, 0).into(),
+        )
+        .and_then(|socket| {
+            socket.join_multicast_v6(LSD_V6.ip(), 0)?;
+            socket.set_nonblocking(true)?;
+            Ok(socket)
+        })
+        .ok();
+
+        Ok(LsdSocket {
+            v4,
+            v6,
+            lsd,
+            buf: vec![0; MAX_PACKET],
+        })
+    }
+
+    pub fn lsd(&mut self) -> &mut Lsd {
+        &mut self.lsd
+    }
+
+    pub fn has_ipv6(&self) -> bool {
+        self.v6.is_some()
+    }
+
+    // Sends whatever announces are due, then listens for up to `timeout`. Returns local peers for
+    // our torrents
+    pub fn poll(&mut self, timeout: Duration) -> io::Result<Vec<(InfoHash, SocketAddr)>> {
+        self.lsd.tick(Instant::now());
+        self.flush()?;
+
+        let mut peers = vec![];
+        let deadline = Instant::now() + timeout;
+        loop {
+            // Short waits on the IPv4 socket so the (non-blocking) IPv6 one gets looked at too
+            let wait = deadline
+                .saturating_duration_since(Instant::now())
+                .clamp(Duration::from_millis(1), Duration::from_millis(50));
+            self.v4.set_read_timeout(Some(wait))?;
+            match self.v4.recv_from(&mut self.buf) {
+                Ok((len, from)) => peers.extend(self.lsd.handle_packet(&self.buf[..len], from)),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+
+            if let Some(v6) = &self.v6 {
+                while let Ok((len, from)) = v6.recv_from(&mut self.buf) {
+                    peers.extend(self.lsd.handle_packet(&self.buf[..len], from));
+                }
+            }
+
+            if Instant::now() >= deadline {
+                break;
+            }
+        }
+        Ok(peers)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while let Some(announce) = self.lsd.poll_transmit() {
+            self.v4
+                .send_to(&announce.encode(&LSD_V4.to_string()), LSD_V4)?;
+            if let Some(v6) = &self.v6 {
+                // Sending can fail even after joining worked, e.g. with no IPv6 default route
+                let _ = v6.send_to(&announce.encode(&LSD_V6.to_string()), LSD_V6);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn reusable(domain: Domain, addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    if domain == Domain::IPV6 {
+        socket.set_only_v6(true)?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
@@ -1,3 +1,879 @@
+// The hurricane command line client.
+//
+//     hurricane download <file.torrent | magnet link>
+//         [--output-dir <dir>] [--seed-after <goal>] [--port <port>]
+//     hurricane verify <file.torrent> <save dir>
+//     hurricane edit <file.torrent> [--output <file>] [--tracker <url>[,<url>...]]...
+//         [--no-trackers] [--web-seed <url>]... [--no-web-seeds] [--comment <text>]
+//         [--creation-date <unix time | now | none>] [--private | --public]
+//     hurricane scrape <file.torrent | magnet link | info-hash | dir>... [--tracker <url>]...
+//         [--dht] [--timeout <secs>] [--concurrency <n>]
+//     hurricane dht-router [--listen <addr>]... [--state-dir <dir>] [--metrics <addr>]
+//         [--router <host:port>]... [--max-torrents <n>] [--max-peers <n>]
+//         [--max-queries-per-ip <n>]
+//     hurricane daemon [--listen <addr>] [--token <token>] [--tls-cert <pem> --tls-key <pem>]
+//         [--state-dir <dir>] [--save-path <dir>] [--port <port>] [--watch <dir>]...
+//         [--metrics <addr>] [--stream <addr>]
+//     hurricane peers <torrent> [--listen <addr>] [--token <token>] [--tls-cert <pem>]
+//
+// `download`, `daemon` and `peers` also take `--config <file>` and any number of
+// `--set <key>=<value>`. Their settings come from ~/.config/hurricane/config.toml, then
+// HURRICANE_* variables, then flags, see the `config` module for the keys.
+// `download` fetches one torrent into the output directory (the current one by default), with a
+// live progress display, and exits with 0 once it's complete. `--seed-after` keeps it seeding
+// until a ratio (`2.0`) or a time (`90m`, `12h`, `2d`) is reached. It exits with 1 on errors
+// and 130 when interrupted.
+// `verify` rehashes a torrent's data on disk and lists the pieces that failed. It exits with 1
+// when anything is missing or corrupt, so scripts can tell whether the data is ready to seed.
+// `edit` changes a torrent file's trackers, web seeds, comment or creation date and writes it back
+// (to --output if given, otherwise over the original, which is kept as <file>.bak). Each
+// --tracker is a tier of comma separated URLs, and together they replace the torrent's trackers;
+// --web-seed likewise. An empty --comment removes it. The info dict is left byte for byte as it
+// was, so the info-hash stays the same, except with --private or --public, which make it a new
+// torrent with a new info-hash. It prints the info-hash, and exits with 1 on errors.
+// `scrape` reports how healthy each torrent's swarm is: the seeders, leechers and completed
+// downloads its trackers know of, and with --dht how many peers the DHT has. A directory stands for
+// the .torrent files in it, and every --tracker is scraped for every torrent on top of its own,
+// which is how bare info-hashes get any. Trackers that failed are listed on stderr.
+// `dht-router` runs a DHT node and nothing else, for operators of public bootstrap nodes. It
+// listens on 0.0.0.0:6881 unless given one or more --listen addresses (one per address family),
+// joins the network through the default routers or the --router ones, and answers queries until
+// SIGINT/SIGTERM. --state-dir keeps the node IDs and routing tables across restarts. --metrics
+// serves the routing table, storage and traffic counters on http://<addr>/metrics.
+// `daemon` runs headless, controlled over JSON-RPC (see the `daemon` module), until it's told to
+// shut down or gets SIGINT/SIGTERM. The session is kept in $XDG_STATE_HOME/hurricane unless
+// --state-dir says otherwise, and downloads go to the current directory unless --save-path does.
+// Torrent and magnet files dropped into a --watch directory are added, then renamed to *.added.
+// --metrics serves Prometheus metrics on http://<addr>/metrics. Listening anywhere but loopback
+// takes --token, --tls-cert and --tls-key, and the address in rpc.allowed_binds.
+// `peers` asks a running daemon for a torrent's connected peers and prints them as a table. The
+// torrent is its handle, info-hash or name. With --tls-cert it talks TLS to a daemon serving that
+// certificate.
+// Bad usage and bad settings exit with 2.
+use std::collections::BTreeMap;
+use std::io::{self, IsTerminal, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hurricane::config::{self, Config, Origin};
+use hurricane::dht::socket::resolve_routers;
+use hurricane::dht::{Dht, DhtConfig, DhtSocket};
+use hurricane::disk::recover;
+use hurricane::download::{Download, DownloadState};
+use hurricane::health::{self, HealthConfig, SwarmTarget};
+use hurricane::infohash::InfoHash;
+use hurricane::metainfo::edit::TorrentEditor;
+use hurricane::metainfo::{MagnetLink, Metainfo};
+use hurricane::metrics::{DhtMetrics, MetricsServer};
+use hurricane::rng::Rng;
+use hurricane::shutdown;
+use hurricane::torrent::{SeedGoals, Torrent, TorrentStatus};
+
+const USAGE: &str = "usage: hurricane download <file.torrent | magnet link> [--output-dir <dir>] [--seed-after <goal>] [--port <port>]
+       hurricane verify <file.torrent> <save dir>
+       hurricane edit <file.torrent> [--output <file>] [--tracker <url>[,<url>...]]... [--no-trackers] [--web-seed <url>]... [--no-web-seeds] [--comment <text>] [--creation-date <unix time | now | none>] [--private | --public]
+       hurricane scrape <file.torrent | magnet link | info-hash | dir>... [--tracker <url>]... [--dht] [--timeout <secs>] [--concurrency <n>]
+       hurricane dht-router [--listen <addr>]... [--state-dir <dir>] [--metrics <addr>] [--router <host:port>]... [--max-torrents <n>] [--max-peers <n>] [--max-queries-per-ip <n>]
+       hurricane daemon [--listen <addr>] [--token <token>] [--tls-cert <pem> --tls-key <pem>] [--state-dir <dir>] [--save-path <dir>] [--port <port>] [--watch <dir>]... [--metrics <addr>] [--stream <addr>]
+       hurricane peers <torrent> [--listen <addr>] [--token <token>] [--tls-cert <pem>]
+       download, daemon and peers also take --config <file> and --set <key>=<value>";
+
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("download") if args.len() >= 2 => download(&args[1], &args[2..]),
+        Some("verify") if args.len() == 3 => verify(&args[1], PathBuf::from(&args[2])),
+        Some("edit") if args.len() >= 2 => edit(&args[1], &args[2..]),
+        Some("scrape") => scrape(&args[1..]),
+        Some("dht-router") => dht_router(&args[1..]),
+        #[cfg(feature = "daemon")]
+        Some("daemon") => daemon(&args[1..]),
+        #[cfg(feature = "daemon")]
+        Some("peers") if args.len() >= 2 => peers(&args[1], &args[2..]),
+        _ => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(2);
+}
+
+// Settings from the config file, the environment, then `args`: `--config` picks the file,
+// `--set <key>=<value>` sets anything, and `flags` are (flag, key) pairs for the ones with
+// flags of their own. The other options are handed back in order
+fn load_config(args: &[String], flags: &[(&str, &str)]) -> (Config, Vec<(String, String)>) {
+    let mut options = vec![];
+    let mut args = args.iter();
+    while let Some(option) = args.next() {
+        let Some(value) = args.next() else { usage() };
+        options.push((option.clone(), value.clone()));
+    }
+    let path = options
+        .iter()
+        .rfind(|(option, _)| option == "--config")
+        .map(|(_, path)| PathBuf::from(path));
+    let vars = std::env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)));
+    let mut config = Config::load(path.as_deref(), vars).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        exit(2);
+    });
+
+    let mut rest = vec![];
+    for (option, value) in options {
+        let result = match flags.iter().find(|(flag, _)| *flag == option) {
+            Some((_, key)) => config.set(key, &value, Origin::Flag(option)),
+            None if option == "--config" => Ok(()),
+            None if option == "--set" => {
+                let Some((key, value)) = value.split_once('=') else {
+                    usage()
+                };
+                config.set(key, value, Origin::Flag(format!("--set {}", key)))
+            }
+            None => {
+                rest.push((option, value));
+                Ok(())
+            }
+        };
+        if let Err(err) = result {
+            eprintln!("{}", err);
+            exit(2);
+        }
+    }
+    (config, rest)
+}
+
+fn download(source: &str, args: &[String]) {
+    let flags = [("--output-dir", "paths.save"), ("--port", "network.port")];
+    let (settings, options) = load_config(args, &flags);
+    let mut config = settings.download_config();
+    for (option, value) in options {
+        match option.as_str() {
+            "--seed-after" => match parse_goal(&value) {
+                Some(goals) => config.seed_after = Some(goals),
+                None => {
+                    eprintln!(
+                        "--seed-after: a ratio like 2.0 or a time like 90m, not {}",
+                        value
+                    );
+                    exit(2);
+                }
+            },
+            _ => usage(),
+        }
+    }
+    if let Some(path) = &settings.blocklist
+        && let Err(err) = config.ip_filter.load(path)
+    {
+        eprintln!("loading the blocklist {}: {}", path.display(), err);
+        exit(1);
+    }
+
+    let now = Instant::now();
+    let torrent = if source.starts_with("magnet:") {
+        match MagnetLink::parse(source) {
+            Ok(magnet) => Torrent::from_magnet(&magnet, now),
+            Err(err) => {
+                eprintln!("bad magnet link: {}", err);
+                exit(2);
+            }
+        }
+    } else {
+        match std::fs::read(source).map(|buf| Metainfo::from_bytes(&buf)) {
+            Ok(Ok(metainfo)) => Torrent::new(metainfo, now),
+            Ok(Err(err)) => {
+                eprintln!("{}: {}", source, err);
+                exit(1);
+            }
+            Err(err) => {
+                eprintln!("{}: {}", source, err);
+                exit(1);
+            }
+        }
+    };
+
+    if let Err(err) = shutdown::catch_signals() {
+        eprintln!("catching signals: {}", err);
+        exit(1);
+    }
+    let mut download = match Download::start(torrent, config) {
+        Ok(download) => download,
+        Err(err) => {
+            eprintln!("starting the download: {}", err);
+            exit(1);
+        }
+    };
+
+    let tty = io::stdout().is_terminal();
+    let mut drawn = 0;
+    let mut last_line = Instant::now() - Duration::from_secs(60);
+    loop {
+        let now = Instant::now();
+        let state = download.poll(now);
+        let lines = render(&download.torrent(), terminal_width());
+        let mut out = io::stdout().lock();
+        if tty {
+            // Back up over the last frame and draw the new one in its place
+            if drawn > 0 {
+                let _ = write!(out, "\x1b[{}A\r\x1b[J", drawn);
+            }
+            let _ = writeln!(out, "{}", lines.join("\n"));
+            drawn = lines.len();
+        } else if now.duration_since(last_line) >= Duration::from_secs(5)
+            || state != DownloadState::Running
+        {
+            // Logs get the status line only
+            let _ = writeln!(out, "{}", lines[2]);
+            last_line = now;
+        }
+        let _ = out.flush();
+        drop(out);
+
+        match state {
+            DownloadState::Running if shutdown::requested() => {
+                download.stop();
+                exit(130);
+            }
+            DownloadState::Running => thread::sleep(Duration::from_millis(500)),
+            DownloadState::Done => {
+                download.stop();
+                return;
+            }
+            DownloadState::Failed(err) => {
+                eprintln!("download failed: {}", err);
+                download.stop();
+                exit(1);
+            }
+        }
+    }
+}
+
+// The name, a progress bar, rates and peers, and a map of the pieces we have, `width` wide
+fn render(torrent: &Torrent, width: usize) -> Vec<String> {
+    let progress = torrent.progress();
+    let bar_width = width.saturating_sub(40).clamp(10, 50);
+    let filled = (progress * bar_width as f64) as usize;
+    let status = match torrent.status() {
+        TorrentStatus::DownloadingMetadata => "fetching metadata",
+        TorrentStatus::CheckingFiles => "checking",
+        TorrentStatus::Seeding => "seeding",
+        TorrentStatus::Error => "error",
+        _ => "downloading",
+    };
+    let eta = match torrent.status() {
+        TorrentStatus::Downloading => torrent.eta().map_or("-".to_string(), duration),
+        _ => "-".to_string(),
+    };
+
+    let mut map = String::new();
+    if let Some(picker) = torrent.picker() {
+        let pieces = picker.num_pieces().max(1);
+        let cells = width.clamp(1, pieces);
+        for cell in 0..cells {
+            let (start, end) = (cell * pieces / cells, (cell + 1) * pieces / cells);
+            let have = (start..end).filter(|p| picker.have().get(*p)).count();
+            let shades = [' ', '░', '▒', '▓', '█'];
+            map.push(shades[(have * 4).div_ceil(end - start)]);
+        }
+    }
+
+    let name: String = torrent.name().chars().take(width).collect();
+    vec![
+        name,
+        format!(
+            "[{}{}] {:5.1}%  {} / {}",
+            "#".repeat(filled),
+            "-".repeat(bar_width - filled),
+            progress * 100.0,
+            bytes(torrent.bytes_done()),
+            bytes(torrent.total_length()),
+        ),
+        format!(
+            "{}  down {}/s  up {}/s  eta {}  peers {} ({} seeds)  ratio {:.2}",
+            status,
+            bytes(torrent.download_rate().get() as u64),
+            bytes(torrent.upload_rate().get() as u64),
+            eta,
+            torrent.num_peers(),
+            torrent.num_seeds(),
+            torrent.ratio(),
+        ),
+        map,
+    ]
+}
+
+fn bytes(n: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", n),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+fn duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+// `2.0` is a ratio, `90m` a seeding time
+fn parse_goal(text: &str) -> Option<SeedGoals> {
+    let goals = match text.ends_with(|c: char| c.is_ascii_alphabetic()) {
+        true => SeedGoals {
+            seed_time: Some(config::parse_duration(text)?),
+            ..SeedGoals::default()
+        },
+        false => SeedGoals {
+            ratio: Some(text.parse::<f64>().ok().filter(|r| *r >= 0.0)?),
+            ..SeedGoals::default()
+        },
+    };
+    Some(goals)
+}
+
+#[cfg(unix)]
+fn terminal_width() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+    match ok && size.ws_col > 0 {
+        true => size.ws_col as usize,
+        false => 80,
+    }
+}
+
+#[cfg(not(unix))]
+fn terminal_width() -> usize {
+    80
+}
+
+fn verify(torrent_file: &str, save_path: PathBuf) {
+    let metainfo = match std::fs::read(torrent_file).map(|buf| Metainfo::from_bytes(&buf)) {
+        Ok(Ok(metainfo)) => metainfo,
+        Ok(Err(err)) => {
+            eprintln!("{}: {}", torrent_file, err);
+            exit(1);
+        }
+        Err(err) => {
+            eprintln!("{}: {}", torrent_file, err);
+            exit(1);
+        }
+    };
+
+    let mut torrent = Torrent::new(metainfo, Instant::now());
+    torrent.set_save_path(save_path);
+    let storage = torrent.storage().unwrap();
+    let checked = match recover(&storage) {
+        Ok(checked) => checked,
+        Err(err) => {
+            eprintln!(
+                "reading {}: {}",
+                torrent.save_path().unwrap().display(),
+                err
+            );
+            exit(1);
+        }
+    };
+    let report = torrent.finish_recheck(&checked);
+
+    println!(
+        "{}: {}/{} pieces ok, {:.1}% done",
+        torrent.name(),
+        report.verified,
+        report.verified + report.missing,
+        torrent.progress() * 100.0
+    );
+    if !report.corrupt.is_empty() {
+        let pieces: Vec<String> = report.corrupt.iter().map(u32::to_string).collect();
+        println!("corrupt pieces: {}", pieces.join(", "));
+    }
+    if report.missing > 0 {
+        exit(1);
+    }
+}
+
+fn edit(torrent_file: &str, args: &[String]) {
+    let mut editor = match std::fs::read(torrent_file).map(|buf| TorrentEditor::from_bytes(&buf)) {
+        Ok(Ok(editor)) => editor,
+        Ok(Err(err)) => {
+            eprintln!("{}: {}", torrent_file, err);
+            exit(1);
+        }
+        Err(err) => {
+            eprintln!("{}: {}", torrent_file, err);
+            exit(1);
+        }
+    };
+    let original = editor.info_hash();
+
+    let mut output = PathBuf::from(torrent_file);
+    let mut tiers: Option<Vec<Vec<String>>> = None;
+    let mut web_seeds: Option<Vec<String>> = None;
+    let mut private = None;
+    let mut args = args.iter();
+    while let Some(option) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match option.as_str() {
+            "--output" => output = PathBuf::from(value()),
+            "--tracker" => {
+                let tier = value().split(',').map(|url| url.trim().to_string());
+                let tier: Vec<String> = tier.filter(|url| !url.is_empty()).collect();
+                tiers.get_or_insert_default().push(tier);
+            }
+            "--no-trackers" => tiers = Some(vec![]),
+            "--web-seed" => web_seeds.get_or_insert_default().push(value().clone()),
+            "--no-web-seeds" => web_seeds = Some(vec![]),
+            "--comment" => {
+                let comment = value();
+                editor.set_comment((!comment.is_empty()).then_some(comment.as_str()));
+            }
+            "--creation-date" => {
+                let date = match value().as_str() {
+                    "none" => None,
+                    "now" => Some(
+                        std::time::SystemTime::now()
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .map_or(0, |d| d.as_secs() as i64),
+                    ),
+                    date => Some(date.parse().unwrap_or_else(|_| usage())),
+                };
+                editor.set_creation_date(date);
+            }
+            "--private" => private = Some(true),
+            "--public" => private = Some(false),
+            _ => usage(),
+        }
+    }
+    if let Some(tiers) = tiers {
+        editor.set_trackers(&tiers);
+    }
+    if let Some(web_seeds) = web_seeds {
+        editor.set_web_seeds(&web_seeds);
+    }
+    let info_hash = match private {
+        Some(private) => editor.set_private(private),
+        None => editor.info_hash(),
+    };
+
+    if let Err(err) = hurricane::statefile::save(&output, &editor.to_bytes()) {
+        eprintln!("writing {}: {}", output.display(), err);
+        exit(1);
+    }
+    println!("{}: {}", output.display(), info_hash);
+    if info_hash != original {
+        println!(
+            "the info-hash changed from {}: this is a new torrent with a swarm of its own",
+            original
+        );
+    }
+}
+
+fn scrape(args: &[String]) {
+    let mut sources = vec![];
+    let mut extra_trackers = vec![];
+    let mut config = HealthConfig::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--tracker" => extra_trackers.push(value().clone()),
+            "--dht" => config.dht = Some(DhtConfig::default()),
+            "--timeout" => {
+                let secs = value().parse().unwrap_or_else(|_| usage());
+                config.timeout = Duration::from_secs(secs);
+                config.dht_timeout = config.timeout * 4;
+            }
+            "--concurrency" => config.concurrency = value().parse().unwrap_or_else(|_| usage()),
+            option if option.starts_with("--") => usage(),
+            source => sources.push(source),
+        }
+    }
+    if sources.is_empty() {
+        usage();
+    }
+
+    let mut targets = vec![];
+    for source in sources {
+        let path = Path::new(source);
+        if path.is_dir() {
+            let mut files: Vec<PathBuf> = match std::fs::read_dir(path) {
+                Ok(entries) => entries
+                    .filter_map(|entry| Some(entry.ok()?.path()))
+                    .filter(|path| path.extension().is_some_and(|ext| ext == "torrent"))
+                    .collect(),
+                Err(err) => {
+                    eprintln!("{}: {}", source, err);
+                    exit(1);
+                }
+            };
+            files.sort();
+            for file in files {
+                targets.push(scrape_target(&file.to_string_lossy()));
+            }
+        } else {
+            targets.push(scrape_target(source));
+        }
+    }
+    for target in &mut targets {
+        target.add_trackers(extra_trackers.iter().cloned());
+    }
+
+    let reports = health::check(&targets, &config);
+    let count = |n: Option<u32>| n.map_or("-".to_string(), |n| n.to_string());
+    let mut errors = BTreeMap::new();
+    for report in &reports {
+        let dht = match report.dht_peers {
+            Some(peers) => format!("  dht {:>5}", peers),
+            None => String::new(),
+        };
+        let line = format!(
+            "{}  seeders {:>6}  leechers {:>6}  completed {:>8}{}  trackers {}/{}  {}",
+            report.info_hash,
+            count(report.seeders()),
+            count(report.leechers()),
+            count(report.completed()),
+            dht,
+            report.trackers_up(),
+            report.trackers.len(),
+            report.name.as_deref().unwrap_or(""),
+        );
+        println!("{}", line.trim_end());
+        for (url, result) in &report.trackers {
+            if let Err(err) = result {
+                errors.entry(url.clone()).or_insert_with(|| err.to_string());
+            }
+        }
+    }
+    // Once per tracker rather than once per torrent
+    for (url, err) in errors {
+        eprintln!("{}: {}", url, err);
+    }
+    let seeded = reports.iter().filter(|report| report.has_seeders()).count();
+    println!(
+        "{} torrents, {} with seeders, {} without",
+        reports.len(),
+        seeded,
+        reports.len() - seeded
+    );
+}
+
+// A .torrent file, a magnet link or a bare info-hash
+fn scrape_target(source: &str) -> SwarmTarget {
+    if source.starts_with("magnet:") {
+        match MagnetLink::parse(source) {
+            Ok(magnet) => return SwarmTarget::from_magnet(&magnet),
+            Err(err) => {
+                eprintln!("bad magnet link: {}", err);
+                exit(2);
+            }
+        }
+    }
+    if let Some(info_hash) = InfoHash::from_hex(source) {
+        return SwarmTarget::new(info_hash);
+    }
+    match std::fs::read(source).map(|buf| Metainfo::from_bytes(&buf)) {
+        Ok(Ok(metainfo)) => SwarmTarget::from_metainfo(&metainfo),
+        Ok(Err(err)) => {
+            eprintln!("{}: {}", source, err);
+            exit(1);
+        }
+        Err(err) => {
+            eprintln!("{}: {}", source, err);
+            exit(1);
+        }
+    }
+}
+
+fn dht_router(args: &[String]) {
+    const SAVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+    let mut listen = vec![];
+    let mut state_dir = None;
+    let mut metrics_addr = None;
+    let mut routers = vec![];
+    // Sized for a busy public node rather than a client
+    let mut config = DhtConfig {
+        max_torrents: 100_000,
+        max_items: 10_000,
+        ..DhtConfig::default()
+    };
+    let mut args = args.iter();
+    while let Some(option) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        let number = || value.parse().unwrap_or_else(|_| usage());
+        match option.as_str() {
+            "--listen" => listen.push(value.parse().unwrap_or_else(|_| usage())),
+            "--state-dir" => state_dir = Some(PathBuf::from(value)),
+            "--metrics" => metrics_addr = Some(value.parse().unwrap_or_else(|_| usage())),
+            "--router" => routers.push(value.clone()),
+            "--max-torrents" => config.max_torrents = number(),
+            "--max-peers" => config.max_peers_per_torrent = number(),
+            "--max-queries-per-ip" => config.max_queries_per_ip = number(),
+            _ => usage(),
+        }
+    }
+    if listen.is_empty() {
+        listen.push(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 6881)));
+    }
+    if !routers.is_empty() {
+        config.routers = routers;
+    }
+
+    if let Err(err) = shutdown::catch_signals() {
+        eprintln!("catching signals: {}", err);
+        exit(1);
+    }
+    if let Some(dir) = &state_dir
+        && let Err(err) = std::fs::create_dir_all(dir)
+    {
+        eprintln!("{}: {}", dir.display(), err);
+        exit(1);
+    }
+    // (address family, socket, where its state is kept)
+    let mut nodes = vec![];
+    let mut id = None;
+    for addr in listen {
+        let family = if addr.is_ipv6() { "ipv6" } else { "ipv4" };
+        let state = state_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("dht-{}.dat", family)));
+        let started = match id {
+            // Both families under the one ID (BEP 32), unless a saved state says otherwise
+            Some(id) if !state.as_ref().is_some_and(|path| path.exists()) => {
+                let now = Instant::now();
+                let routers = resolve_routers(&config.routers, addr.is_ipv6());
+                let mut dht = Dht::with_id(id, config.clone(), Rng::new(), now);
+                dht.set_routers(&routers, now);
+                dht.bootstrap(now);
+                DhtSocket::bind(addr, dht)
+            }
+            _ => DhtSocket::start(addr, config.clone(), state.as_deref()),
+        };
+        let mut socket = match started {
+            Ok(socket) => socket,
+            Err(err) => {
+                eprintln!("listening on {}: {}", addr, err);
+                exit(1);
+            }
+        };
+        id.get_or_insert(socket.dht().id());
+        match socket.local_addr() {
+            Ok(addr) => println!("DHT node {:?} on {}", socket.dht().id(), addr),
+            Err(err) => eprintln!("{}", err),
+        }
+        nodes.push((family, socket, state));
+    }
+    let metrics = metrics_addr.map(|addr| match MetricsServer::bind(addr) {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("serving metrics on {}: {}", addr, err);
+            exit(1);
+        }
+    });
+
+    let save = |nodes: &[(&str, DhtSocket, Option<PathBuf>)]| {
+        for (_, socket, state) in nodes {
+            if let Some(path) = state
+                && let Err(err) = socket.save(path)
+            {
+                eprintln!("saving {}: {}", path.display(), err);
+            }
+        }
+    };
+    let mut saved = Instant::now();
+    // Split between the sockets, so each is waited on in turn
+    let wait = Duration::from_millis(100) / nodes.len() as u32;
+    while !shutdown::requested() {
+        for (_, socket, _) in &mut nodes {
+            // Only our own lookups have events, and nothing here is waiting on those
+            if let Err(err) = socket.poll(wait) {
+                eprintln!("{}", err);
+                exit(1);
+            }
+        }
+        if let Some(metrics) = &metrics {
+            metrics.poll(|| {
+                let collected: Vec<DhtMetrics> = nodes
+                    .iter_mut()
+                    .map(|(family, socket, _)| DhtMetrics::collect(socket.dht(), family))
+                    .collect();
+                DhtMetrics::to_prometheus(&collected)
+            });
+        }
+        if saved.elapsed() >= SAVE_INTERVAL {
+            save(&nodes);
+            saved = Instant::now();
+        }
+    }
+    save(&nodes);
+}
+
+#[cfg(feature = "daemon")]
+fn daemon(args: &[String]) {
+    use hurricane::daemon::Daemon;
+    use hurricane::watch::WatchDir;
+
+    let flags = [
+        ("--listen", "rpc.listen"),
+        ("--token", "rpc.token"),
+        ("--tls-cert", "rpc.tls_cert"),
+        ("--tls-key", "rpc.tls_key"),
+        ("--state-dir", "paths.state"),
+        ("--save-path", "paths.save"),
+        ("--port", "network.port"),
+        ("--metrics", "metrics.listen"),
+        ("--stream", "stream.listen"),
+    ];
+    let (mut settings, options) = load_config(args, &flags);
+    for (option, value) in options {
+        match option.as_str() {
+            // On top of the config file's
+            "--watch" => settings.watch.push(WatchDir::new(PathBuf::from(value))),
+            _ => usage(),
+        }
+    }
+
+    if let Err(err) = shutdown::catch_signals() {
+        eprintln!("catching signals: {}", err);
+        exit(1);
+    }
+    let config = settings.daemon_config();
+    let mut daemon = match Daemon::bind(config.clone()) {
+        Ok(daemon) => daemon,
+        Err(err) => {
+            eprintln!("starting the daemon on {}: {}", settings.rpc_listen, err);
+            exit(1);
+        }
+    };
+    settings.apply(daemon.session_mut());
+    let report = daemon.run();
+    if let Some(err) = report.save {
+        eprintln!("saving the session: {}", err);
+        exit(1);
+    }
+}
+
+#[cfg(feature = "daemon")]
+fn peers(torrent: &str, args: &[String]) {
+    use std::io::{BufRead, BufReader};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use hurricane::tls::RpcStream;
+    use serde_json::{Value, json};
+
+    let flags = [
+        ("--listen", "rpc.listen"),
+        ("--token", "rpc.token"),
+        ("--tls-cert", "rpc.tls_cert"),
+    ];
+    let (settings, options) = load_config(args, &flags);
+    if !options.is_empty() {
+        usage();
+    }
+    // A daemon listening everywhere is reachable on loopback
+    let mut addr = settings.rpc_listen;
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+        IpAddr::V6(ip) if ip.is_unspecified() => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+        _ => {}
+    }
+    let stream = match RpcStream::connect(addr, &settings.rpc, Duration::from_secs(5)) {
+        Ok(stream) => stream,
+        Err(err) => {
+            eprintln!("connecting to the daemon on {}: {}", addr, err);
+            exit(1);
+        }
+    };
+    let mut reader = BufReader::new(stream);
+    let mut call = |method: &str, params: Value| -> Value {
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+        let mut line = String::new();
+        let response = writeln!(reader.get_mut(), "{}", request).and_then(|_| {
+            loop {
+                line.clear();
+                if reader.read_line(&mut line)? == 0 {
+                    break Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                // Event notifications have no id
+                let response: Value = serde_json::from_str(&line)?;
+                if response["id"] == 1 {
+                    break Ok(response);
+                }
+            }
+        });
+        match response {
+            Ok(response) if response["error"].is_object() => {
+                eprintln!(
+                    "{}: {}",
+                    method,
+                    response["error"]["message"].as_str().unwrap_or("failed")
+                );
+                exit(1);
+            }
+            Ok(mut response) => response["result"].take(),
+            Err(err) => {
+                eprintln!("talking to the daemon on {}: {}", addr, err);
+                exit(1);
+            }
+        }
+    };
+
+    if let Some(token) = &settings.rpc.token {
+        call("auth", json!({"token": token}));
+    }
+    let handle = match torrent.parse::<u64>() {
+        Ok(handle) => handle,
+        Err(_) => {
+            let status = call("status", json!({}));
+            let found = status.as_array().into_iter().flatten().find(|t| {
+                t["name"] == torrent || t["info_hash"] == torrent.to_ascii_lowercase().as_str()
+            });
+            match found.and_then(|t| t["handle"].as_u64()) {
+                Some(handle) => handle,
+                None => {
+                    eprintln!("no torrent {}", torrent);
+                    exit(1);
+                }
+            }
+        }
+    };
+
+    let peers = call("peers", json!({"handle": handle}));
+    println!(
+        "{:<40} {:<20} {:<6} {:>6} {:>12} {:>12} {:>10} {:>10} {:>5}",
+        "ADDRESS", "CLIENT", "FLAGS", "HAS", "DOWN", "UP", "DOWNLOADED", "UPLOADED", "QUEUE"
+    );
+    for peer in peers.as_array().into_iter().flatten() {
+        let number = |key: &str| peer[key].as_u64().unwrap_or(0);
+        let client: String = peer["client"]
+            .as_str()
+            .unwrap_or("-")
+            .chars()
+            .take(20)
+            .collect();
+        println!(
+            "{:<40} {:<20} {:<6} {:>5.1}% {:>12} {:>12} {:>10} {:>10} {:>5}",
+            peer["addr"].as_str().unwrap_or(""),
+            client,
+            peer["flags"].as_str().unwrap_or(""),
+            peer["progress"].as_f64().unwrap_or(0.0) * 100.0,
+            format!("{}/s", bytes(number("download_rate"))),
+            format!("{}/s", bytes(number("upload_rate"))),
+            bytes(number("downloaded")),
+            bytes(number("uploaded")),
+            number("queued_requests"),
+        );
+    }
 }
@@ -0,0 +1,181 @@
+// Changing what a .torrent says around its info dict: trackers, web seeds, the comment and the
+// creation date. The info dict is kept as the very bytes it was read as, so the info-hash stays
+// what it was even for torrents whose info dict isn't canonical bencode, and anything else in
+// the file that we don't know about is written back untouched.
+// The one exception is the private flag (BEP 27), which lives in the info dict: changing it
+// makes a different torrent with a different info-hash, and a new swarm.
+use bencode::{BencodeValue, Document};
+use sha1::{Digest as _, Sha1};
+
+use super::{Metainfo, MetainfoError};
+use crate::infohash::InfoHash;
+
+#[derive(PartialEq, Debug)]
+pub struct TorrentEditor {
+    doc: Document,
+}
+
+impl TorrentEditor {
+    // Has to be a torrent `Metainfo::from_bytes` takes
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, MetainfoError> {
+        Metainfo::from_bytes(buf)?;
+        Ok(TorrentEditor {
+            doc: Document::decode(buf)?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.doc.encode()
+    }
+
+    // What the edited torrent parses to
+    pub fn metainfo(&self) -> Metainfo {
+        Metainfo::from_bytes(&self.to_bytes()).expect("edits keep the torrent valid")
+    }
+
+    pub fn info_hash(&self) -> InfoHash {
+        let info = self.doc.encode_entry(b"info").expect("checked when loaded");
+        InfoHash(Sha1::digest(info).into())
+    }
+
+    fn set(&mut self, key: &[u8], value: Option<BencodeValue>) {
+        let root = self.doc.root_mut();
+        match value {
+            Some(value) => root.insert(key, value),
+            None => root.remove(key),
+        };
+    }
+
+    pub fn set_announce(&mut self, url: Option<&str>) {
+        self.set(b"announce", url.map(BencodeValue::from));
+    }
+
+    // BEP 12 tiers. Empty tiers are dropped, and no tiers at all removes the list
+    pub fn set_announce_list(&mut self, tiers: &[Vec<String>]) {
+        let tiers: Vec<BencodeValue> = tiers
+            .iter()
+            .filter(|tier| !tier.is_empty())
+            .map(|tier| BencodeValue::List(tier.iter().map(|url| url.as_str().into()).collect()))
+            .collect();
+        self.set(
+            b"announce-list",
+            (!tiers.is_empty()).then_some(BencodeValue::List(tiers)),
+        );
+    }
+
+    // Both of the above: `announce` becomes the first tracker, for clients that don't know BEP
+    // 12, and the list is only written when there's more than one
+    pub fn set_trackers(&mut self, tiers: &[Vec<String>]) {
+        let first = tiers.iter().flatten().next().cloned();
+        self.set_announce(first.as_deref());
+        match tiers.iter().flatten().count() {
+            0 | 1 => self.set_announce_list(&[]),
+            _ => self.set_announce_list(tiers),
+        }
+    }
+
+    // BEP 19 `url-list`. Empty removes it
+    pub fn set_web_seeds(&mut self, urls: &[String]) {
+        let urls: Vec<BencodeValue> = urls.iter().map(|url| url.as_str().into()).collect();
+        self.set(
+            b"url-list",
+            (!urls.is_empty()).then_some(BencodeValue::List(urls)),
+        );
+    }
+
+    pub fn set_comment(&mut self, comment: Option<&str>) {
+        self.set(b"comment", comment.map(BencodeValue::from));
+    }
+
+    // Seconds since the Unix epoch
+    pub fn set_creation_date(&mut self, date: Option<i64>) {
+        self.set(b"creation date", date.map(BencodeValue::Int));
+    }
+
+    // Changes the info dict if the flag changes, which changes the info-hash. Returns the
+    // info-hash from then on
+    pub fn set_private(&mut self, private: bool) -> InfoHash {
+        let info = self
+            .doc
+            .root_mut()
+            .get_mut(b"info")
+            .expect("checked when loaded");
+        let was_private = info.get(b"private").and_then(|p| p.as_int()) == Some(1);
+        if was_private != private {
+            match private {
+                true => info.insert(b"private", 1),
+                false => info.remove(b"private"),
+            };
+        }
+        self.info_hash()
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    // Keys out of order inside the info dict: the hash is of these bytes, not a re-encoding
+    const INFO: &[u8] =
+        b"d12:piece lengthi16384e4:name1:x6:lengthi5e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+
+    fn torrent() -> Vec<u8> {
+        [
+            &b"d8:announce14:http://a/annce7:comment3:old4:info"[..],
+            INFO,
+            b"9:publisher4:somee",
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_edit() {
+        let original_hash = InfoHash(Sha1::digest(INFO).into());
+        let mut editor = TorrentEditor::from_bytes(&torrent()).unwrap();
+        assert_eq!(editor.info_hash(), original_hash);
+
+        let tiers = vec![
+            vec!["http://b/announce".to_string()],
+            vec!["udp://c:80".to_string(), "udp://d:80".to_string()],
+        ];
+        editor.set_trackers(&tiers);
+        editor.set_web_seeds(&["http://seed/files/".to_string()]);
+        editor.set_comment(None);
+        editor.set_creation_date(Some(1_700_000_000));
+
+        let edited = editor.to_bytes();
+        let metainfo = Metainfo::from_bytes(&edited).unwrap();
+        assert_eq!(metainfo.announce.as_deref(), Some("http://b/announce"));
+        assert_eq!(metainfo.announce_list, tiers);
+        assert_eq!(metainfo.url_list, ["http://seed/files/"]);
+        assert_eq!(metainfo.comment, None);
+        assert_eq!(metainfo.creation_date, Some(1_700_000_000));
+        // The info dict went through byte for byte, and so did what we don't know about
+        assert_eq!(editor.info_hash(), original_hash);
+        assert_eq!(metainfo.info_hash, original_hash);
+        assert!(edited.windows(INFO.len()).any(|w| w == INFO));
+        assert!(edited.windows(17).any(|w| w == b"9:publisher4:some"));
+
+        editor.set_trackers(&[vec!["http://only/announce".to_string()]]);
+        let metainfo = editor.metainfo();
+        assert_eq!(metainfo.announce_list, Vec::<Vec<String>>::new());
+        assert_eq!(metainfo.trackers(), [["http://only/announce"]]);
+    }
+
+    #[test]
+    fn test_private() {
+        let mut editor = TorrentEditor::from_bytes(&torrent()).unwrap();
+        let original = editor.info_hash();
+        assert_eq!(editor.set_private(false), original);
+        let private = editor.set_private(true);
+        assert_ne!(private, original);
+        let metainfo = editor.metainfo();
+        assert!(metainfo.info.private);
+        assert_eq!(metainfo.info_hash, private);
+
+        assert_eq!(
+            TorrentEditor::from_bytes(b"li1ee"),
+            Err(MetainfoError::NotADict)
+        );
+    }
+}
@@ -0,0 +1,364 @@
+// Magnet links (BEP 9): magnet:?xt=urn:btih:<info-hash>&dn=<name>&tr=<tracker>&x.pe=<peer>
+// The info-hash is either 40 hex characters or, in older links, 32 characters of base32.
+// BEP 46 links point at a publisher's key instead, magnet:?xs=urn:btpk:<public key>&s=<salt>, and
+// the current info-hash has to be looked up in the DHT.
+use std::fmt;
+
+use crate::infohash::InfoHash;
+
+#[derive(PartialEq, Debug)]
+pub enum MagnetError {
+    NotAMagnet,
+    MissingInfoHash,
+    InvalidInfoHash,
+    InvalidEncoding,
+    MissingPublicKey,
+    InvalidPublicKey,
+}
+
+impl fmt::Display for MagnetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MagnetError::NotAMagnet => write!(f, "not a magnet link"),
+            MagnetError::MissingInfoHash => write!(f, "no xt=urn:btih: info-hash"),
+            MagnetError::InvalidInfoHash => write!(f, "invalid info-hash"),
+            MagnetError::InvalidEncoding => write!(f, "invalid encoding"),
+            MagnetError::MissingPublicKey => write!(f, "no xs=urn:btpk: public key"),
+            MagnetError::InvalidPublicKey => write!(f, "invalid public key"),
+        }
+    }
+}
+
+impl std::error::Error for MagnetError {}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct MagnetLink {
+    pub info_hash: InfoHash,
+    pub display_name: Option<String>,
+    pub trackers: Vec<String>,
+    // `x.pe`: peers to try directly, as host:port
+    pub peers: Vec<String>,
+}
+
+impl MagnetLink {
+    pub fn new(info_hash: InfoHash) -> Self {
+        MagnetLink {
+            info_hash,
+            display_name: None,
+            trackers: vec![],
+            peers: vec![],
+        }
+    }
+
+    pub fn parse(uri: &str) -> Result<MagnetLink, MagnetError> {
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = vec![];
+        let mut peers = vec![];
+        for (key, value) in pairs(uri)? {
+            match key {
+                "xt" => {
+                    // Other xt kinds (btmh for v2, ed2k, ...) can sit alongside the one we want
+                    if let Some(hash) = percent_decode(value, false)?.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_btih(hash)?);
+                    }
+                }
+                "dn" => display_name = Some(percent_decode(value, true)?),
+                "tr" => trackers.push(percent_decode(value, false)?),
+                "x.pe" => peers.push(percent_decode(value, false)?),
+                _ => {}
+            }
+        }
+
+        Ok(MagnetLink {
+            info_hash: info_hash.ok_or(MagnetError::MissingInfoHash)?,
+            display_name,
+            trackers,
+            peers,
+        })
+    }
+
+    pub fn to_uri(&self) -> String {
+        let mut uri = format!("magnet:?xt=urn:btih:{}", self.info_hash.to_hex());
+        if let Some(name) = &self.display_name {
+            uri.push_str("&dn=");
+            uri.push_str(&percent_encode(name));
+        }
+        for tracker in &self.trackers {
+            uri.push_str("&tr=");
+            uri.push_str(&percent_encode(tracker));
+        }
+        for peer in &self.peers {
+            uri.push_str("&x.pe=");
+            uri.push_str(&percent_encode(peer));
+        }
+        uri
+    }
+}
+
+// A BEP 46 link. The value of the mutable item it points to is a dict whose `ih` is the current
+// info-hash
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct MutableMagnet {
+    pub public_key: [u8; 32],
+    // Lets one key publish several torrents. Empty if the link has none
+    pub salt: Vec<u8>,
+    pub display_name: Option<String>,
+    pub trackers: Vec<String>,
+    pub peers: Vec<String>,
+}
+
+impl MutableMagnet {
+    pub fn new(public_key: [u8; 32], salt: &[u8]) -> Self {
+        MutableMagnet {
+            public_key,
+            salt: salt.to_vec(),
+            display_name: None,
+            trackers: vec![],
+            peers: vec![],
+        }
+    }
+
+    pub fn parse(uri: &str) -> Result<MutableMagnet, MagnetError> {
+        let mut public_key = None;
+        let mut salt = vec![];
+        let mut display_name = None;
+        let mut trackers = vec![];
+        let mut peers = vec![];
+        for (key, value) in pairs(uri)? {
+            match key {
+                "xs" => {
+                    if let Some(key) = percent_decode(value, false)?.strip_prefix("urn:btpk:") {
+                        let key = hex_decode(key).and_then(|k| k.try_into().ok());
+                        public_key = Some(key.ok_or(MagnetError::InvalidPublicKey)?);
+                    }
+                }
+                "s" => {
+                    salt = hex_decode(&percent_decode(value, false)?)
+                        .ok_or(MagnetError::InvalidEncoding)?
+                }
+                "dn" => display_name = Some(percent_decode(value, true)?),
+                "tr" => trackers.push(percent_decode(value, false)?),
+                "x.pe" => peers.push(percent_decode(value, false)?),
+                _ => {}
+            }
+        }
+
+        Ok(MutableMagnet {
+            public_key: public_key.ok_or(MagnetError::MissingPublicKey)?,
+            salt,
+            display_name,
+            trackers,
+            peers,
+        })
+    }
+
+    pub fn to_uri(&self) -> String {
+        let mut uri = format!("magnet:?xs=urn:btpk:{}", hex_encode(&self.public_key));
+        if !self.salt.is_empty() {
+            uri.push_str("&s=");
+            uri.push_str(&hex_encode(&self.salt));
+        }
+        if let Some(name) = &self.display_name {
+            uri.push_str("&dn=");
+            uri.push_str(&percent_encode(name));
+        }
+        for tracker in &self.trackers {
+            uri.push_str("&tr=");
+            uri.push_str(&percent_encode(tracker));
+        }
+        for peer in &self.peers {
+            uri.push_str("&x.pe=");
+            uri.push_str(&percent_encode(peer));
+        }
+        uri
+    }
+
+    // A plain magnet link for one version of the torrent, keeping our name, trackers and peers
+    pub fn for_version(&self, info_hash: InfoHash) -> MagnetLink {
+        MagnetLink {
+            info_hash,
+            display_name: self.display_name.clone(),
+            trackers: self.trackers.clone(),
+            peers: self.peers.clone(),
+        }
+    }
+}
+
+fn pairs(uri: &str) -> Result<impl Iterator<Item = (&str, &str)>, MagnetError> {
+    let query = uri
+        .strip_prefix("magnet:?")
+        .ok_or(MagnetError::NotAMagnet)?;
+    Ok(query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|pair| pair.split_once('=').unwrap_or((pair, ""))))
+}
+
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_btih(hash: &str) -> Result<InfoHash, MagnetError> {
+    let parsed = match hash.len() {
+        40 => InfoHash::from_hex(hash),
+        32 => base32_decode(hash).and_then(|b| InfoHash::from_bytes(&b)),
+        _ => None,
+    };
+    parsed.ok_or(MagnetError::InvalidInfoHash)
+}
+
+// RFC 4648 base32 without padding, which is all a 20 byte info-hash ever needs
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn percent_decode(s: &str, plus_is_space: bool) -> Result<String, MagnetError> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes
+                    .get(i + 1..i + 3)
+                    .ok_or(MagnetError::InvalidEncoding)?;
+                let hex = std::str::from_utf8(hex).map_err(|_| MagnetError::InvalidEncoding)?;
+                out.push(u8::from_str_radix(hex, 16).map_err(|_| MagnetError::InvalidEncoding)?);
+                i += 3;
+            }
+            b'+' if plus_is_space => {
+                out.push(b' ');
+                i += 1;
+            }
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(out).map_err(|_| MagnetError::InvalidEncoding)
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    const HEX: &str = "c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+
+    #[test]
+    fn test_parse_hex() {
+        let uri = format!(
+            "magnet:?xt=urn:btih:{}&dn=Some+File%20Name&tr=udp%3A%2F%2Ftracker.example.com%3A80&tr=http://t2/announce&x.pe=10.0.0.1:6881",
+            HEX
+        );
+        let magnet = MagnetLink::parse(&uri).unwrap();
+
+        assert_eq!(magnet.info_hash.to_hex(), HEX);
+        assert_eq!(magnet.display_name.as_deref(), Some("Some File Name"));
+        assert_eq!(
+            magnet.trackers,
+            vec!["udp://tracker.example.com:80", "http://t2/announce"]
+        );
+        assert_eq!(magnet.peers, vec!["10.0.0.1:6881"]);
+    }
+
+    #[test]
+    fn test_parse_base32() {
+        // Same hash as HEX
+        let magnet =
+            MagnetLink::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+
+        assert_eq!(magnet.info_hash.to_hex(), HEX);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut magnet = MagnetLink::new(InfoHash::from_hex(HEX).unwrap());
+        magnet.display_name = Some("a b&c".to_string());
+        magnet.trackers = vec!["udp://t:1/announce?x=1".to_string()];
+
+        assert_eq!(MagnetLink::parse(&magnet.to_uri()), Ok(magnet));
+    }
+
+    #[test]
+    fn test_mutable_magnet() {
+        let key = "8543d3e6115f0f98c944077a4493dcd543e49c739fd998550a1f614ab36ed63e";
+        let uri = format!("magnet:?xs=urn:btpk:{}&s=6e0b&dn=feed", key);
+        let magnet = MutableMagnet::parse(&uri).unwrap();
+
+        assert_eq!(magnet.public_key[0], 0x85);
+        assert_eq!(magnet.salt, vec![0x6e, 0x0b]);
+        assert_eq!(magnet.display_name.as_deref(), Some("feed"));
+        assert_eq!(MutableMagnet::parse(&magnet.to_uri()), Ok(magnet.clone()));
+
+        let version = magnet.for_version(InfoHash([1; 20]));
+        assert_eq!(version.display_name.as_deref(), Some("feed"));
+        assert_eq!(
+            MutableMagnet::parse(&format!("magnet:?xt=urn:btih:{}", HEX)),
+            Err(MagnetError::MissingPublicKey)
+        );
+        assert_eq!(
+            MutableMagnet::parse("magnet:?xs=urn:btpk:abcd"),
+            Err(MagnetError::InvalidPublicKey)
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            MagnetLink::parse("http://example.com"),
+            Err(MagnetError::NotAMagnet)
+        );
+        assert_eq!(
+            MagnetLink::parse("magnet:?dn=x"),
+            Err(MagnetError::MissingInfoHash)
+        );
+        assert_eq!(
+            MagnetLink::parse("magnet:?xt=urn:btih:abcd"),
+            Err(MagnetError::InvalidInfoHash)
+        );
+        assert_eq!(
+            MagnetLink::parse("magnet:?xt=urn:btih:%zz"),
+            Err(MagnetError::InvalidEncoding)
+        );
+    }
+}
@@ -0,0 +1,529 @@
+// BEP 52 merkle trees. Each file of a v2 torrent is hashed on its own: the SHA-256 of every
+// 16 KiB block makes a leaf, leaves are padded with zero hashes out to a power of two, and pairs
+// are hashed together up to the file's `pieces root`. The layer where one node covers a whole
+// piece is what the .torrent's `piece layers` carries, so a finished piece can be checked
+// against it directly. Whatever layers we're missing come from peers through hash requests,
+// each answer carrying the uncle hashes that prove it against the root.
+use sha2::{Digest, Sha256};
+
+pub const MERKLE_BLOCK: u32 = 16 * 1024;
+
+// Peers won't send more hashes than this for one request
+pub const MAX_HASHES_PER_REQUEST: u32 = 512;
+
+pub type Hash256 = [u8; 32];
+
+// The header shared by the hash request, hashes and hash reject messages
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct HashRequest {
+    pub pieces_root: Hash256,
+    // 0 is the leaves
+    pub base_layer: u32,
+    // First hash wanted, counted in the base layer. A multiple of `length`
+    pub index: u32,
+    // A power of two, at least 2
+    pub length: u32,
+    // How many uncle hashes to send along, one per layer above the requested subtree
+    pub proof_layers: u32,
+}
+
+pub fn hash_block(data: &[u8]) -> Hash256 {
+    Sha256::digest(data).into()
+}
+
+pub fn hash_pair(left: &Hash256, right: &Hash256) -> Hash256 {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// A subtree of nothing but padding, `height` layers above the leaves
+pub fn pad_hash(height: u32) -> Hash256 {
+    (0..height).fold([0; 32], |hash, _| hash_pair(&hash, &hash))
+}
+
+// Root of a subtree `width` nodes wide (a power of two) over `hashes`, with `pad` standing in for
+// the nodes past the end
+pub fn root(hashes: &[Hash256], width: usize, pad: Hash256) -> Hash256 {
+    debug_assert!(width.is_power_of_two() && hashes.len() <= width);
+    let mut layer = hashes.to_vec();
+    let mut pad = pad;
+    let mut width = width;
+    while width > 1 {
+        layer = pair_up(&layer, pad);
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+    layer.first().copied().unwrap_or(pad)
+}
+
+// One file's tree, and as much of its piece layer as we've been able to check
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct MerkleFile {
+    root: Hash256,
+    length: u64,
+    piece_length: u32,
+    layer: Vec<Option<Hash256>>,
+}
+
+impl MerkleFile {
+    // `piece_length` is a power of two no smaller than a block, as BEP 52 requires
+    pub fn new(root: Hash256, length: u64, piece_length: u32) -> Self {
+        let mut file = MerkleFile {
+            root,
+            length,
+            piece_length,
+            layer: vec![],
+        };
+        file.layer = vec![None; file.num_pieces() as usize];
+        // A file of one piece or less has its root as its only piece hash
+        if file.is_small() {
+            file.layer = vec![Some(root)];
+        }
+        file
+    }
+
+    pub fn root(&self) -> Hash256 {
+        self.root
+    }
+
+    pub fn num_pieces(&self) -> u32 {
+        self.length.div_ceil(self.piece_length as u64) as u32
+    }
+
+    pub fn num_blocks(&self) -> u32 {
+        self.length.div_ceil(MERKLE_BLOCK as u64) as u32
+    }
+
+    fn blocks_per_piece(&self) -> u32 {
+        self.piece_length / MERKLE_BLOCK
+    }
+
+    fn is_small(&self) -> bool {
+        self.num_blocks() <= self.blocks_per_piece()
+    }
+
+    // Layers from the (padded) leaves up to the root
+    fn height(&self) -> u32 {
+        self.num_blocks()
+            .max(1)
+            .next_power_of_two()
+            .trailing_zeros()
+    }
+
+    fn piece_layer(&self) -> u32 {
+        self.blocks_per_piece().trailing_zeros()
+    }
+
+    pub fn has_piece_layer(&self) -> bool {
+        self.layer.iter().all(Option::is_some)
+    }
+
+    pub fn piece_hash(&self, piece: u32) -> Option<Hash256> {
+        self.layer.get(piece as usize).copied().flatten()
+    }
+
+    // The piece layer from the .torrent's `piece layers`. Rejected (and left alone) if it doesn't
+    // add up to the root
+    pub fn set_piece_layer(&mut self, layer: &[Hash256]) -> bool {
+        if self.is_small() {
+            return true;
+        }
+        if layer.len() != self.num_pieces() as usize {
+            return false;
+        }
+        let width = 1 << (self.height() - self.piece_layer());
+        if root(layer, width, pad_hash(self.piece_layer())) != self.root {
+            return false;
+        }
+        self.layer = layer.iter().copied().map(Some).collect();
+        true
+    }
+
+    // None while we don't know the piece's hash yet
+    pub fn verify_piece(&self, piece: u32, data: &[u8]) -> Option<bool> {
+        let expected = self.piece_hash(piece)?;
+        Some(self.hash_piece(data) == expected)
+    }
+
+    fn hash_piece(&self, data: &[u8]) -> Hash256 {
+        let leaves: Vec<Hash256> = data.chunks(MERKLE_BLOCK as usize).map(hash_block).collect();
+        // Short last pieces are padded to a whole piece, small files only to their own width
+        let width = if self.is_small() {
+            1 << self.height()
+        } else {
+            self.blocks_per_piece() as usize
+        };
+        root(&leaves, width, [0; 32])
+    }
+
+    // Which blocks of a piece that failed its check were the bad ones, going by the piece's leaf
+    // hashes (see `leaf_request`). None if the leaves don't match the piece hash themselves
+    pub fn bad_blocks(&self, piece: u32, data: &[u8], leaves: &[Hash256]) -> Option<Vec<u32>> {
+        let expected = self.piece_hash(piece)?;
+        let width = leaves.len().max(1).next_power_of_two();
+        if root(leaves, width, [0; 32]) != expected {
+            return None;
+        }
+        let bad = data
+            .chunks(MERKLE_BLOCK as usize)
+            .enumerate()
+            .filter(|(i, block)| leaves.get(*i) != Some(&hash_block(block)))
+            .map(|(i, _)| i as u32)
+            .collect();
+        Some(bad)
+    }
+
+    // What to ask peers for to fill in the piece layer, in chunks they're willing to send
+    pub fn layer_requests(&self) -> Vec<HashRequest> {
+        if self.is_small() {
+            return vec![];
+        }
+        let width = 1u32 << (self.height() - self.piece_layer());
+        let length = width.min(MAX_HASHES_PER_REQUEST);
+        (0..width)
+            .step_by(length as usize)
+            .filter(|index| {
+                let end = (index + length).min(self.num_pieces());
+                (*index..end).any(|piece| self.layer[piece as usize].is_none())
+            })
+            .map(|index| HashRequest {
+                pieces_root: self.root,
+                base_layer: self.piece_layer(),
+                index,
+                length,
+                proof_layers: self.height() - self.piece_layer() - length.trailing_zeros(),
+            })
+            .collect()
+    }
+
+    // The leaf hashes of one piece, checked against its piece hash, for `bad_blocks`
+    pub fn leaf_request(&self, piece: u32) -> HashRequest {
+        let length = if self.is_small() {
+            1 << self.height()
+        } else {
+            self.blocks_per_piece()
+        };
+        HashRequest {
+            pieces_root: self.root,
+            base_layer: 0,
+            index: piece * self.blocks_per_piece(),
+            length: length.max(2),
+            proof_layers: 0,
+        }
+    }
+
+    // Whether a request's numbers describe part of this tree at all. They come straight off the
+    // wire, and everything else here trusts them: a base layer way past the root alone would have
+    // `pad_hash` spin for billions of rounds
+    fn fits(&self, request: &HashRequest) -> bool {
+        let height = self.height();
+        let top = request
+            .base_layer
+            .checked_add(request.length.trailing_zeros())
+            .and_then(|top| top.checked_add(request.proof_layers));
+        request.pieces_root == self.root
+            && request.length.is_power_of_two()
+            && request.length <= MAX_HASHES_PER_REQUEST
+            && request.index.is_multiple_of(request.length)
+            && top.is_some_and(|top| top <= height)
+            && request.index as u64 + request.length as u64 <= 1 << (height - request.base_layer)
+    }
+
+    // Checks a `hashes` message against what we know and returns its base layer hashes if they
+    // hold up. Piece layer hashes are kept. Anything that doesn't verify should be treated like
+    // a bad block: dropped, and held against the peer
+    pub fn on_hashes(&mut self, request: &HashRequest, hashes: &[Hash256]) -> Option<Vec<Hash256>> {
+        let length = request.length as usize;
+        if !self.fits(request) || hashes.len() != length + request.proof_layers as usize {
+            return None;
+        }
+        let (base, uncles) = hashes.split_at(length);
+
+        let mut node = root(base, length, pad_hash(request.base_layer));
+        let mut position = request.index / request.length;
+        for uncle in uncles {
+            node = if position.is_multiple_of(2) {
+                hash_pair(&node, uncle)
+            } else {
+                hash_pair(uncle, &node)
+            };
+            position /= 2;
+        }
+
+        // Whatever we climbed to has to be something we already trust
+        let top = request.base_layer + request.length.trailing_zeros() + request.proof_layers;
+        let trusted = if top == self.height() {
+            Some(self.root)
+        } else if top == self.piece_layer() {
+            self.piece_hash(position)
+        } else {
+            None
+        };
+        if trusted != Some(node) {
+            return None;
+        }
+
+        if request.base_layer == self.piece_layer() && !self.is_small() {
+            for (i, hash) in base.iter().enumerate() {
+                if let Some(slot) = self.layer.get_mut(request.index as usize + i) {
+                    *slot = Some(*hash);
+                }
+            }
+        }
+        Some(base.to_vec())
+    }
+
+    // Our answer to a peer's hash request: the base layer hashes it asked for, then the uncles
+    // that prove them. From the piece layer up they come from what we've checked already. Below
+    // it they're hashed from `data`, the one piece the request falls in, which has to match its
+    // piece hash. None is a reject
+    pub fn answer(&self, request: &HashRequest, data: Option<&[u8]>) -> Option<Vec<Hash256>> {
+        if !self.fits(request) || !self.has_piece_layer() {
+            return None;
+        }
+        let height = self.height();
+        let pieces = self.piece_layer().min(height);
+        let base = request.base_layer;
+        let end = request.index + request.length - 1;
+
+        // Layers from the base up, each as the position of its first hash and the hashes. Nodes
+        // past the end of one are padding
+        let mut layers: Vec<(u32, Vec<Hash256>)> = vec![];
+        if base < pieces {
+            let piece = request.index >> (pieces - base);
+            if end >> (pieces - base) != piece {
+                return None;
+            }
+            let mut layer: Vec<Hash256> = data?
+                .chunks(MERKLE_BLOCK as usize)
+                .map(hash_block)
+                .collect();
+            for at in 0..pieces {
+                if at >= base {
+                    layers.push((piece << (pieces - at), layer.clone()));
+                }
+                layer = pair_up(&layer, pad_hash(at));
+            }
+            if layer.first() != self.piece_hash(piece).as_ref() {
+                return None;
+            }
+        }
+        let mut layer: Vec<Hash256> = self.layer.iter().flatten().copied().collect();
+        for at in pieces..=height {
+            if at >= base {
+                layers.push((0, layer.clone()));
+            }
+            layer = pair_up(&layer, pad_hash(at));
+        }
+
+        let node = |at: u32, i: u32| {
+            let (start, hashes) = &layers[(at - base) as usize];
+            i.checked_sub(*start)
+                .and_then(|i| hashes.get(i as usize))
+                .copied()
+                .unwrap_or_else(|| pad_hash(at))
+        };
+        let mut hashes: Vec<Hash256> = (request.index..=end).map(|i| node(base, i)).collect();
+        let top = base + request.length.trailing_zeros();
+        let mut position = request.index / request.length;
+        for at in top..top + request.proof_layers {
+            hashes.push(node(at, position ^ 1));
+            position /= 2;
+        }
+        Some(hashes)
+    }
+}
+
+// One layer up, with `pad` standing in for a missing right half
+fn pair_up(layer: &[Hash256], pad: Hash256) -> Vec<Hash256> {
+    layer
+        .chunks(2)
+        .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pad)))
+        .collect()
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    const PIECE: u32 = 2 * MERKLE_BLOCK;
+
+    // 5 blocks, so 3 pieces and a tree 8 leaves wide
+    fn data() -> Vec<u8> {
+        (0..MERKLE_BLOCK * 9 / 2)
+            .map(|i| (i / 1000) as u8)
+            .collect()
+    }
+
+    fn leaves(data: &[u8]) -> Vec<Hash256> {
+        data.chunks(MERKLE_BLOCK as usize).map(hash_block).collect()
+    }
+
+    fn piece_layer(data: &[u8]) -> Vec<Hash256> {
+        data.chunks(PIECE as usize)
+            .map(|piece| root(&leaves(piece), 2, [0; 32]))
+            .collect()
+    }
+
+    #[test]
+    fn test_piece_layer() {
+        let data = data();
+        let file_root = root(&leaves(&data), 8, [0; 32]);
+        let mut file = MerkleFile::new(file_root, data.len() as u64, PIECE);
+        assert_eq!(file.num_pieces(), 3);
+        assert_eq!(file.verify_piece(0, &data[..PIECE as usize]), None);
+
+        let mut bad = piece_layer(&data);
+        bad[1] = [1; 32];
+        assert!(!file.set_piece_layer(&bad));
+        assert!(file.set_piece_layer(&piece_layer(&data)));
+
+        assert_eq!(
+            file.verify_piece(2, &data[2 * PIECE as usize..]),
+            Some(true)
+        );
+        assert_eq!(file.verify_piece(1, &data[..PIECE as usize]), Some(false));
+    }
+
+    #[test]
+    fn test_small_file() {
+        let data = vec![7; 100];
+        let file = MerkleFile::new(hash_block(&data), 100, PIECE);
+
+        assert!(file.has_piece_layer());
+        assert!(file.layer_requests().is_empty());
+        assert_eq!(file.verify_piece(0, &data), Some(true));
+    }
+
+    #[test]
+    fn test_hashes_with_proof() {
+        let data = data();
+        let layer = piece_layer(&data);
+        let file_root = root(&leaves(&data), 8, [0; 32]);
+        let mut file = MerkleFile::new(file_root, data.len() as u64, PIECE);
+
+        let requests = file.layer_requests();
+        assert_eq!(requests.len(), 1);
+        let request = requests[0];
+        assert_eq!(
+            (request.base_layer, request.length, request.proof_layers),
+            (1, 4, 0)
+        );
+
+        // The padded fourth piece is part of the answer
+        let mut hashes = layer.clone();
+        hashes.push(pad_hash(1));
+        let mut forged = hashes.clone();
+        forged[0] = [9; 32];
+        assert_eq!(file.on_hashes(&request, &forged), None);
+        assert!(!file.has_piece_layer());
+
+        assert_eq!(file.on_hashes(&request, &hashes), Some(hashes.clone()));
+        assert!(file.has_piece_layer());
+        assert!(file.layer_requests().is_empty());
+
+        // A request for a subtree proves itself with uncles up to the root
+        let request = HashRequest {
+            index: 2,
+            length: 2,
+            proof_layers: 1,
+            ..request
+        };
+        let proof = root(&layer[..2], 2, [0; 32]);
+        assert!(
+            file.on_hashes(&request, &[layer[2], pad_hash(1), proof])
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_hostile_requests() {
+        let data = data();
+        let file_root = root(&leaves(&data), 8, [0; 32]);
+        let mut file = MerkleFile::new(file_root, data.len() as u64, PIECE);
+        let request = file.layer_requests()[0];
+
+        // Turned away before any hashing, rather than spinning or overflowing
+        for request in [
+            HashRequest {
+                base_layer: u32::MAX,
+                proof_layers: 0,
+                ..request
+            },
+            HashRequest {
+                proof_layers: u32::MAX,
+                ..request
+            },
+            HashRequest {
+                length: 1 << 31,
+                ..request
+            },
+            HashRequest {
+                index: 8,
+                ..request
+            },
+            HashRequest {
+                index: 1,
+                ..request
+            },
+        ] {
+            let hashes = vec![[0; 32]; 4];
+            assert_eq!(file.on_hashes(&request, &hashes), None);
+            assert_eq!(file.answer(&request, None), None);
+        }
+    }
+
+    #[test]
+    fn test_answer() {
+        let data = data();
+        let file_root = root(&leaves(&data), 8, [0; 32]);
+        let mut ours = MerkleFile::new(file_root, data.len() as u64, PIECE);
+        let mut theirs = ours.clone();
+        let request = theirs.layer_requests()[0];
+        // Nothing to answer with until we have the piece layer ourselves
+        assert_eq!(ours.answer(&request, None), None);
+        ours.set_piece_layer(&piece_layer(&data));
+
+        let hashes = ours.answer(&request, None).unwrap();
+        assert!(theirs.on_hashes(&request, &hashes).is_some());
+        assert!(theirs.has_piece_layer());
+
+        // Leaves take the piece's data, and only the right data
+        let request = ours.leaf_request(1);
+        let piece = &data[PIECE as usize..2 * PIECE as usize];
+        assert_eq!(ours.answer(&request, None), None);
+        assert_eq!(ours.answer(&request, Some(&data[..PIECE as usize])), None);
+        let hashes = ours.answer(&request, Some(piece)).unwrap();
+        assert_eq!(hashes, leaves(piece));
+        assert_eq!(theirs.on_hashes(&request, &hashes), Some(hashes));
+
+        // With proof up to the root
+        let request = HashRequest {
+            proof_layers: 2,
+            ..request
+        };
+        let hashes = ours.answer(&request, Some(piece)).unwrap();
+        assert_eq!(hashes.len(), 4);
+        assert!(theirs.on_hashes(&request, &hashes).is_some());
+    }
+
+    #[test]
+    fn test_bad_blocks() {
+        let data = data();
+        let file_root = root(&leaves(&data), 8, [0; 32]);
+        let mut file = MerkleFile::new(file_root, data.len() as u64, PIECE);
+        file.set_piece_layer(&piece_layer(&data));
+
+        let piece = &data[PIECE as usize..2 * PIECE as usize];
+        let mut corrupt = piece.to_vec();
+        corrupt[MERKLE_BLOCK as usize + 5] ^= 0xff;
+        let request = file.leaf_request(1);
+        assert_eq!((request.index, request.length), (2, 2));
+
+        let leaves = leaves(piece);
+        assert_eq!(file.bad_blocks(1, &corrupt, &leaves), Some(vec![1]));
+        assert_eq!(file.bad_blocks(1, &corrupt, &[[0; 32], [0; 32]]), None);
+    }
+}
@@ -0,0 +1,682 @@
+// .torrent files (BEP 3 metainfo) and magnet links (BEP 9). Hybrid v1/v2 torrents (BEP 52) also
+// get their per-file merkle roots and piece layers read, for `merkle`.
+pub mod edit;
+pub mod magnet;
+pub mod merkle;
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+pub use magnet::{MagnetLink, MutableMagnet};
+pub use merkle::{HashRequest, MerkleFile};
+
+use bencode::{BencodeValue, DecodeError, Document};
+use sha1::{Digest as _, Sha1};
+
+use crate::infohash::InfoHash;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum MetainfoError {
+    Decode(DecodeError),
+    NotADict,
+    // Fields are paths from the root dict, e.g. "info.files[3].length", or from the info dict for
+    // `from_info_bytes`
+    MissingField(String),
+    InvalidField(String),
+}
+
+impl From<DecodeError> for MetainfoError {
+    fn from(err: DecodeError) -> Self {
+        MetainfoError::Decode(err)
+    }
+}
+
+impl fmt::Display for MetainfoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MetainfoError::Decode(err) => write!(f, "bad bencode: {}", err),
+            MetainfoError::NotADict => write!(f, "not a bencoded dict"),
+            MetainfoError::MissingField(field) => write!(f, "missing {}", field),
+            MetainfoError::InvalidField(field) => write!(f, "invalid {}", field),
+        }
+    }
+}
+
+impl std::error::Error for MetainfoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MetainfoError::Decode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct FileEntry {
+    // Path components relative to the download directory, starting with the torrent's name for
+    // multi-file torrents. Already checked to be safe to join onto a directory
+    pub path: Vec<String>,
+    pub length: u64,
+    // BEP 52 root of the file's merkle tree, for hybrid torrents. Empty files have none
+    pub pieces_root: Option<[u8; 32]>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Info {
+    pub name: String,
+    pub piece_length: u32,
+    pub pieces: Vec<[u8; 20]>,
+    // Single-file torrents get one entry named after the torrent
+    pub files: Vec<FileEntry>,
+    // BEP 27
+    pub private: bool,
+}
+
+impl Info {
+    pub fn total_length(&self) -> u64 {
+        self.files.iter().map(|f| f.length).sum()
+    }
+
+    pub fn num_pieces(&self) -> u32 {
+        self.pieces.len() as u32
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Metainfo {
+    pub info_hash: InfoHash,
+    pub info: Info,
+    // The bencoded info dict, which is what ut_metadata (BEP 9) sends to magnet link downloaders
+    pub info_bytes: Vec<u8>,
+    pub announce: Option<String>,
+    // BEP 12 tiers. Empty if the torrent only has `announce`
+    pub announce_list: Vec<Vec<String>>,
+    // BEP 19 web seeds: HTTP servers with the torrent's files on them
+    pub url_list: Vec<String>,
+    // BEP 52 piece layers, keyed by the file's pieces root. Files of one piece or less aren't in
+    // here, their root is their piece hash
+    pub piece_layers: BTreeMap<[u8; 32], Vec<[u8; 32]>>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub creation_date: Option<i64>,
+}
+
+impl Metainfo {
+    pub fn from_bytes(buf: &[u8]) -> Result<Metainfo, MetainfoError> {
+        // Anything after the root dict is ignored, as it always has been
+        let (_, len) = bencode::decode_prefix(buf)?;
+        let doc = Document::decode(&buf[..len])?;
+        let root = doc.root();
+        if root.as_dict().is_none() {
+            return Err(MetainfoError::NotADict);
+        }
+
+        let info_value = root
+            .get(b"info")
+            .ok_or(MetainfoError::MissingField("info".to_string()))?;
+        let info = parse_info(info_value, "info")?;
+        // The info dict exactly as the file has it, which is what everyone else hashes too. Once
+        // re-encoded, one that wasn't canonical bencode would turn into a different torrent
+        let info_bytes = doc.encode_entry(b"info").expect("checked above");
+        let info_hash = InfoHash(Sha1::digest(&info_bytes).into());
+
+        let announce_list = match root.get(b"announce-list") {
+            Some(tiers) => tiers
+                .as_list()
+                .ok_or_else(|| MetainfoError::InvalidField("announce-list".to_string()))?
+                .iter()
+                .enumerate()
+                .map(|(i, tier)| {
+                    tier.as_list()
+                        .map(|urls| urls.iter().filter_map(string).collect::<Vec<_>>())
+                        .ok_or_else(|| MetainfoError::InvalidField(format!("announce-list[{}]", i)))
+                })
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .filter(|tier| !tier.is_empty())
+                .collect(),
+            None => vec![],
+        };
+
+        // A single URL or a list of them. Plenty of torrents carry an empty string here
+        let url_list = match root.get(b"url-list") {
+            Some(BencodeValue::List(urls)) => urls.iter().filter_map(string).collect(),
+            Some(url) => string(url).into_iter().collect(),
+            None => vec![],
+        }
+        .into_iter()
+        .filter(|url: &String| !url.is_empty())
+        .collect();
+
+        // Layers that aren't a whole number of hashes are dropped, the tree checks the rest
+        let piece_layers = root
+            .get(b"piece layers")
+            .and_then(|layers| layers.as_dict())
+            .into_iter()
+            .flatten()
+            .filter_map(|(root, layer)| {
+                let root = root.as_slice().try_into().ok()?;
+                let layer = layer.as_bytes().filter(|l| l.len().is_multiple_of(32))?;
+                Some((
+                    root,
+                    layer
+                        .chunks_exact(32)
+                        .map(|h| h.try_into().unwrap())
+                        .collect(),
+                ))
+            })
+            .collect();
+
+        Ok(Metainfo {
+            info_hash,
+            info,
+            info_bytes,
+            announce: root.get(b"announce").and_then(string),
+            announce_list,
+            url_list,
+            piece_layers,
+            comment: root.get(b"comment").and_then(string),
+            created_by: root.get(b"created by").and_then(string),
+            creation_date: root.get(b"creation date").and_then(|d| d.as_int()),
+        })
+    }
+
+    // A bare info dict, as fetched over ut_metadata for a magnet link. The caller checks
+    // `info_hash` against the one it asked for
+    pub fn from_info_bytes(buf: &[u8]) -> Result<Metainfo, MetainfoError> {
+        let values = bencode::decode(buf)?;
+        let info = parse_info(values.first().ok_or(MetainfoError::NotADict)?, "")?;
+        let info_hash = InfoHash(Sha1::digest(buf).into());
+
+        Ok(Metainfo {
+            info_hash,
+            info,
+            info_bytes: buf.to_vec(),
+            announce: None,
+            announce_list: vec![],
+            url_list: vec![],
+            piece_layers: BTreeMap::new(),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+        })
+    }
+
+    // Back to a .torrent file, e.g. to keep a copy of one added by magnet link. The info dict is
+    // `info_bytes` as they are, so the info-hash doesn't change
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let text = |s: &str| BencodeValue::ByteStr(s.as_bytes().to_vec());
+        let mut root = BTreeMap::new();
+        if let Some(announce) = &self.announce {
+            root.insert(b"announce".to_vec(), text(announce));
+        }
+        if !self.announce_list.is_empty() {
+            let tiers = self
+                .announce_list
+                .iter()
+                .map(|tier| BencodeValue::List(tier.iter().map(|url| text(url)).collect()))
+                .collect();
+            root.insert(b"announce-list".to_vec(), BencodeValue::List(tiers));
+        }
+        if !self.url_list.is_empty() {
+            let urls = self.url_list.iter().map(|url| text(url)).collect();
+            root.insert(b"url-list".to_vec(), BencodeValue::List(urls));
+        }
+        if !self.piece_layers.is_empty() {
+            let layers = self
+                .piece_layers
+                .iter()
+                .map(|(root, layer)| (root.to_vec(), BencodeValue::ByteStr(layer.concat())))
+                .collect();
+            root.insert(b"piece layers".to_vec(), BencodeValue::Dict(layers));
+        }
+        for (key, value) in [
+            (&b"comment"[..], &self.comment),
+            (b"created by", &self.created_by),
+        ] {
+            if let Some(value) = value {
+                root.insert(key.to_vec(), text(value));
+            }
+        }
+        if let Some(date) = self.creation_date {
+            root.insert(b"creation date".to_vec(), BencodeValue::Int(date));
+        }
+
+        // Written out by hand, to splice the info dict in where its key sorts
+        let mut entries: Vec<(&[u8], Vec<u8>)> = root
+            .iter()
+            .map(|(key, value)| (key.as_slice(), bencode::encode(value)))
+            .collect();
+        entries.push((b"info", self.info_bytes.clone()));
+        entries.sort_by_key(|(key, _)| *key);
+        let mut out = vec![b'd'];
+        for (key, value) in entries {
+            out.extend(bencode::encode(&BencodeValue::ByteStr(key.to_vec())));
+            out.extend(value);
+        }
+        out.push(b'e');
+        out
+    }
+
+    // Tracker tiers to try, in order. Per BEP 12 `announce` is ignored when there's a list
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        if !self.announce_list.is_empty() {
+            return self.announce_list.clone();
+        }
+        self.announce.iter().map(|url| vec![url.clone()]).collect()
+    }
+
+    // Merkle trees for each file of a hybrid torrent, in `info.files` order, None where a file
+    // has no root. Piece layers that don't add up to their root are left out, to be fetched from
+    // peers instead
+    pub fn merkle_files(&self) -> Vec<Option<MerkleFile>> {
+        self.info
+            .files
+            .iter()
+            .map(|file| {
+                let mut tree =
+                    MerkleFile::new(file.pieces_root?, file.length, self.info.piece_length);
+                if let Some(layer) = self.piece_layers.get(&tree.root()) {
+                    tree.set_piece_layer(layer);
+                }
+                Some(tree)
+            })
+            .collect()
+    }
+}
+
+// `at` is where the info dict is, for the paths in errors
+fn parse_info(info: &BencodeValue, at: &str) -> Result<Info, MetainfoError> {
+    let missing = |key| MetainfoError::MissingField(field(at, key));
+    let invalid = |key| MetainfoError::InvalidField(field(at, key));
+    if info.as_dict().is_none() {
+        return Err(MetainfoError::InvalidField(at.to_string()));
+    }
+
+    let name = info.get(b"name").ok_or_else(|| missing("name"))?;
+    let name = string(name)
+        .filter(|n| safe_component(n))
+        .ok_or_else(|| invalid("name"))?;
+
+    let piece_length = info
+        .get(b"piece length")
+        .ok_or_else(|| missing("piece length"))?
+        .as_int()
+        .and_then(|l| u32::try_from(l).ok())
+        .filter(|l| *l > 0)
+        .ok_or_else(|| invalid("piece length"))?;
+
+    let pieces = info
+        .get(b"pieces")
+        .ok_or_else(|| missing("pieces"))?
+        .as_bytes()
+        .filter(|p| p.len().is_multiple_of(20))
+        .ok_or_else(|| invalid("pieces"))?
+        .chunks_exact(20)
+        .map(|hash| hash.try_into().unwrap())
+        .collect();
+
+    let files = match (info.get(b"length"), info.get(b"files")) {
+        (Some(length), None) => vec![FileEntry {
+            path: vec![name.clone()],
+            length: length_of(length, &field(at, "length"))?,
+            pieces_root: pieces_root(info, &[name.as_str()]),
+        }],
+        (None, Some(files)) => {
+            let files = files
+                .as_list()
+                .filter(|f| !f.is_empty())
+                .ok_or_else(|| invalid("files"))?;
+            files
+                .iter()
+                .enumerate()
+                .map(|(i, file)| {
+                    parse_file(info, &name, file, &format!("{}[{}]", field(at, "files"), i))
+                })
+                .collect::<Result<_, _>>()?
+        }
+        (None, None) => return Err(missing("length")),
+        (Some(_), Some(_)) => return Err(invalid("files")),
+    };
+
+    let info = Info {
+        name,
+        piece_length,
+        pieces,
+        files,
+        private: info.get(b"private").and_then(|p| p.as_int()) == Some(1),
+    };
+
+    let expected = info.total_length().div_ceil(piece_length as u64);
+    if expected != info.pieces.len() as u64 {
+        return Err(invalid("pieces"));
+    }
+    Ok(info)
+}
+
+fn parse_file(
+    info: &BencodeValue,
+    name: &str,
+    file: &BencodeValue,
+    at: &str,
+) -> Result<FileEntry, MetainfoError> {
+    let length = length_of(
+        file.get(b"length")
+            .ok_or_else(|| MetainfoError::MissingField(field(at, "length")))?,
+        &field(at, "length"),
+    )?;
+
+    let mut path = vec![name.to_string()];
+    let components = file
+        .get(b"path")
+        .ok_or_else(|| MetainfoError::MissingField(field(at, "path")))?
+        .as_list()
+        .filter(|p| !p.is_empty())
+        .ok_or_else(|| MetainfoError::InvalidField(field(at, "path")))?;
+    for (i, component) in components.iter().enumerate() {
+        // Anything that could escape the download directory gets the whole torrent rejected
+        let component = string(component)
+            .filter(|c| safe_component(c))
+            .ok_or_else(|| MetainfoError::InvalidField(format!("{}[{}]", field(at, "path"), i)))?;
+        path.push(component);
+    }
+
+    let pieces_root = pieces_root(
+        info,
+        &path[1..].iter().map(String::as_str).collect::<Vec<_>>(),
+    );
+    Ok(FileEntry {
+        path,
+        length,
+        pieces_root,
+    })
+}
+
+// A file's root from the v2 `file tree`, where each path component is a nested dict and the file
+// itself sits under the empty key. Only meaningful when BEP 52's piece length rules hold
+fn pieces_root(info: &BencodeValue, path: &[&str]) -> Option<[u8; 32]> {
+    let piece_length = info.get(b"piece length")?.as_int()?;
+    if piece_length < merkle::MERKLE_BLOCK as i64 || !(piece_length as u64).is_power_of_two() {
+        return None;
+    }
+    let mut node = info.get(b"file tree")?;
+    for component in path {
+        node = node.get(component.as_bytes())?;
+    }
+    node.get(b"")?
+        .get(b"pieces root")?
+        .as_bytes()?
+        .try_into()
+        .ok()
+}
+
+fn length_of(value: &BencodeValue, at: &str) -> Result<u64, MetainfoError> {
+    value
+        .as_int()
+        .and_then(|l| u64::try_from(l).ok())
+        .ok_or_else(|| MetainfoError::InvalidField(at.to_string()))
+}
+
+// `key` under `at`, written like `bencode::Path`: info.files[3].length
+fn field(at: &str, key: &str) -> String {
+    match at {
+        "" => key.to_string(),
+        _ => format!("{}.{}", at, key),
+    }
+}
+
+fn string(value: &BencodeValue) -> Option<String> {
+    value
+        .as_bytes()
+        .map(|b| String::from_utf8_lossy(b).into_owned())
+}
+
+fn safe_component(component: &str) -> bool {
+    !component.is_empty()
+        && component != "."
+        && component != ".."
+        && !component.contains(['/', '\\', '\0'])
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use std::collections::BTreeMap;
+
+    use sha1::{Digest as _, Sha1};
+
+    use super::*;
+
+    fn bytes(b: &[u8]) -> BencodeValue {
+        BencodeValue::ByteStr(b.to_vec())
+    }
+
+    fn dict(entries: Vec<(&[u8], BencodeValue)>) -> BencodeValue {
+        let map: BTreeMap<Vec<u8>, BencodeValue> =
+            entries.into_iter().map(|(k, v)| (k.to_vec(), v)).collect();
+        BencodeValue::Dict(map)
+    }
+
+    fn multi_file(path: Vec<&[u8]>) -> Vec<u8> {
+        let file = dict(vec![
+            (b"length", BencodeValue::Int(5)),
+            (
+                b"path",
+                BencodeValue::List(path.into_iter().map(bytes).collect()),
+            ),
+        ]);
+        let info = dict(vec![
+            (b"files", BencodeValue::List(vec![file])),
+            (b"name", bytes(b"dir")),
+            (b"piece length", BencodeValue::Int(16384)),
+            (b"pieces", bytes(&[0; 20])),
+        ]);
+        bencode::encode(&dict(vec![(b"info", info)]))
+    }
+
+    #[test]
+    fn test_single_file() {
+        let buf = include_bytes!("../../bencode/tests/fixtures/sample.torrent");
+        let metainfo = Metainfo::from_bytes(buf).unwrap();
+
+        assert_eq!(metainfo.info.name, "sample.txt");
+        assert_eq!(metainfo.info.total_length(), 25000);
+        assert_eq!(metainfo.info.num_pieces(), 2);
+        assert_eq!(metainfo.info.files[0].path, vec!["sample.txt"]);
+        assert_eq!(
+            metainfo.trackers(),
+            vec![vec!["http://tracker.example.com:6969/announce".to_string()]]
+        );
+
+        let info = &bencode::decode(buf).unwrap()[0];
+        let expected: [u8; 20] = Sha1::digest(bencode::encode(info.get(b"info").unwrap())).into();
+        assert_eq!(metainfo.info_hash, InfoHash(expected));
+    }
+
+    #[test]
+    fn test_from_info_bytes() {
+        let buf = include_bytes!("../../bencode/tests/fixtures/sample.torrent");
+        let metainfo = Metainfo::from_bytes(buf).unwrap();
+        let fetched = Metainfo::from_info_bytes(&metainfo.info_bytes).unwrap();
+
+        assert_eq!(fetched.info_hash, metainfo.info_hash);
+        assert_eq!(fetched.info, metainfo.info);
+        assert!(fetched.trackers().is_empty());
+    }
+
+    #[test]
+    fn test_to_bytes() {
+        let buf = include_bytes!("../../bencode/tests/fixtures/sample.torrent");
+        let mut metainfo = Metainfo::from_bytes(buf).unwrap();
+        metainfo.url_list = vec!["http://seed/".to_string()];
+        metainfo
+            .piece_layers
+            .insert([1; 32], vec![[2; 32], [3; 32]]);
+        assert_eq!(Metainfo::from_bytes(&metainfo.to_bytes()), Ok(metainfo));
+    }
+
+    #[test]
+    fn test_non_canonical_info() {
+        // Keys out of order, which a re-encoding would sort
+        let info = b"d4:name1:x12:piece lengthi16384e6:lengthi5e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let buf = [&b"d7:comment1:c4:info"[..], info, b"e"].concat();
+        let metainfo = Metainfo::from_bytes(&buf).unwrap();
+
+        assert_eq!(metainfo.info_hash, InfoHash(Sha1::digest(info).into()));
+        assert_eq!(metainfo.info_bytes, info);
+        let written = metainfo.to_bytes();
+        assert_eq!(written, buf);
+        assert_eq!(Metainfo::from_bytes(&written), Ok(metainfo));
+    }
+
+    #[test]
+    fn test_multi_file() {
+        let metainfo = Metainfo::from_bytes(&multi_file(vec![b"sub", b"a.txt"])).unwrap();
+
+        assert_eq!(metainfo.info.files.len(), 1);
+        assert_eq!(metainfo.info.files[0].path, vec!["dir", "sub", "a.txt"]);
+        assert!(metainfo.trackers().is_empty());
+    }
+
+    #[test]
+    fn test_path_traversal_rejected() {
+        assert_eq!(
+            Metainfo::from_bytes(&multi_file(vec![b"..", b"etc"])),
+            Err(MetainfoError::InvalidField("info.files[0].path[0]".into()))
+        );
+        assert_eq!(
+            Metainfo::from_bytes(&multi_file(vec![b"ok", b"a/b"])),
+            Err(MetainfoError::InvalidField("info.files[0].path[1]".into()))
+        );
+    }
+
+    #[test]
+    fn test_piece_count_mismatch() {
+        let info = dict(vec![
+            (b"length", BencodeValue::Int(40000)),
+            (b"name", bytes(b"x")),
+            (b"piece length", BencodeValue::Int(16384)),
+            (b"pieces", bytes(&[0; 40])),
+        ]);
+        let info_bytes = bencode::encode(&info);
+        let buf = bencode::encode(&dict(vec![(b"info", info)]));
+
+        assert_eq!(
+            Metainfo::from_bytes(&buf),
+            Err(MetainfoError::InvalidField("info.pieces".into()))
+        );
+        assert_eq!(
+            Metainfo::from_info_bytes(&info_bytes)
+                .unwrap_err()
+                .to_string(),
+            "invalid pieces"
+        );
+    }
+
+    #[test]
+    fn test_url_list() {
+        let with = |url_list: BencodeValue| {
+            let mut root = bencode::decode(&multi_file(vec![b"a"])).unwrap().remove(0);
+            if let BencodeValue::Dict(map) = &mut root {
+                map.insert(b"url-list".to_vec(), url_list);
+            }
+            Metainfo::from_bytes(&bencode::encode(&root))
+                .unwrap()
+                .url_list
+        };
+
+        assert_eq!(with(bytes(b"http://a/")), vec!["http://a/"]);
+        assert_eq!(
+            with(BencodeValue::List(vec![
+                bytes(b"http://a/"),
+                bytes(b""),
+                bytes(b"http://b/")
+            ])),
+            vec!["http://a/", "http://b/"]
+        );
+        assert!(with(bytes(b"")).is_empty());
+    }
+
+    #[test]
+    fn test_hybrid_merkle() {
+        let data = vec![3; 40000];
+        let layer: Vec<[u8; 32]> = data.chunks(16384).map(merkle::hash_block).collect();
+        let pieces_root = merkle::root(&layer, 4, [0; 32]);
+
+        let file = dict(vec![
+            (b"length", BencodeValue::Int(40000)),
+            (b"path", BencodeValue::List(vec![bytes(b"a")])),
+        ]);
+        let tree = dict(vec![(
+            b"a",
+            dict(vec![(
+                b"",
+                dict(vec![
+                    (b"length", BencodeValue::Int(40000)),
+                    (b"pieces root", bytes(&pieces_root)),
+                ]),
+            )]),
+        )]);
+        let info = dict(vec![
+            (b"file tree", tree),
+            (b"files", BencodeValue::List(vec![file])),
+            (b"name", bytes(b"dir")),
+            (b"piece length", BencodeValue::Int(16384)),
+            (b"pieces", bytes(&[0; 60])),
+        ]);
+        let layers = dict(vec![(&pieces_root[..], bytes(&layer.concat()))]);
+        let buf = bencode::encode(&dict(vec![(b"info", info), (b"piece layers", layers)]));
+
+        let metainfo = Metainfo::from_bytes(&buf).unwrap();
+        assert_eq!(metainfo.info.files[0].pieces_root, Some(pieces_root));
+        let files = metainfo.merkle_files();
+        let tree = files[0].as_ref().unwrap();
+        assert!(tree.has_piece_layer());
+        assert_eq!(tree.verify_piece(2, &data[32768..]), Some(true));
+
+        // Plain v1 torrents have no trees
+        let metainfo = Metainfo::from_bytes(&multi_file(vec![b"a"])).unwrap();
+        assert_eq!(metainfo.merkle_files(), vec![None]);
+    }
+
+    #[test]
+    fn test_fuzz_corpus() {
+        let hybrid = include_bytes!("../../fuzz/corpus/metainfo/hybrid.torrent");
+        let metainfo = Metainfo::from_bytes(hybrid).unwrap();
+        // The padding files have no trees, the real ones all have their piece layers
+        let trees = metainfo.merkle_files();
+        assert_eq!(trees.iter().flatten().count(), 3);
+        assert!(trees.iter().flatten().all(MerkleFile::has_piece_layer));
+
+        let multi = include_bytes!("../../fuzz/corpus/metainfo/multi-file.torrent");
+        let metainfo = Metainfo::from_bytes(multi).unwrap();
+        assert!(metainfo.info.files.len() > 1);
+        assert_eq!(metainfo.url_list, ["http://mirror.example.org/pub/"]);
+
+        let non_canonical = include_bytes!("../../fuzz/corpus/metainfo/non-canonical.torrent");
+        let metainfo = Metainfo::from_bytes(non_canonical).unwrap();
+        assert!(metainfo.info.private);
+        assert_eq!(
+            metainfo.info_hash,
+            InfoHash(Sha1::digest(&metainfo.info_bytes).into())
+        );
+        assert_ne!(
+            bencode::encode(&bencode::decode(&metainfo.info_bytes).unwrap()[0]),
+            metainfo.info_bytes
+        );
+    }
+
+    #[test]
+    fn test_missing_info() {
+        assert_eq!(
+            Metainfo::from_bytes(b"d8:announce3:urle"),
+            Err(MetainfoError::MissingField("info".into()))
+        );
+        let err = Metainfo::from_bytes(b"d4:infod4:namei1eee").unwrap_err();
+        assert_eq!(err.to_string(), "invalid info.name");
+        let err = Metainfo::from_bytes(b"d4:infod4:name").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "bad bencode: no end token in a dict while parsing info at byte 14"
+        );
+    }
+}
@@ -0,0 +1,663 @@
+// Numbers about the session and each torrent, for dashboards and alerts: totals, rates, peers,
+// hash failures, addresses the blocklist turned away, and the DHT, disk caches and port mappings
+// when the caller has them. `SessionMetrics::collect`
+// takes a snapshot, which renders in the Prometheus text format (`to_prometheus`) for a scraper,
+// or goes out as JSON from the daemon's `stats` call.
+// Rates are the torrents' own moving averages (see `Rate`), summed for the session's. Byte
+// totals count across restarts, like the ones in the status call, so they're counters that only
+// go back to zero when a torrent is removed.
+// `DhtMetrics` is the same for a DHT node on its own, such as `hurricane dht-router`: its routing
+// table, what it stores and its traffic.
+// `MetricsServer` answers `GET /metrics` on a port of its own. It has no auth, like most
+// exporters, so it belongs on loopback or behind a firewall.
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::blocklist::BlockCounts;
+use crate::dht::{Dht, DhtStats};
+use crate::disk::IoCacheStats;
+use crate::infohash::InfoHash;
+use crate::portmap::{MappingState, MappingStatus, Protocol};
+use crate::session::{Session, TorrentHandle};
+
+// Request heads bigger than this are not from a scraper
+const MAX_HEAD: usize = 8 * 1024;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct TorrentMetrics {
+    pub handle: TorrentHandle,
+    pub info_hash: InfoHash,
+    pub name: String,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    // Bytes per second
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    pub connected_peers: usize,
+    // Addresses we could connect to
+    pub known_peers: usize,
+    pub hash_failures: u64,
+    // 0.0 to 1.0
+    pub progress: f64,
+}
+
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct SessionMetrics {
+    pub torrents: Vec<TorrentMetrics>,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    pub connected_peers: usize,
+    pub known_peers: usize,
+    pub hash_failures: u64,
+    pub blocked: BlockCounts,
+    // Left out of the output while None. The session doesn't own either, so whoever runs them
+    // fills these in
+    pub dht_nodes: Option<usize>,
+    // Share of the finished pieces' bytes hashed from the write cache rather than read back from
+    // disk, 0.0 to 1.0
+    pub cache_hit_ratio: Option<f64>,
+    // What the I/O cache in front of the disk did: reads it answered, writes it coalesced
+    pub io_cache: Option<IoCacheStats>,
+    // Ports we asked the router to forward, and how that went. Empty without port mapping
+    pub port_mappings: Vec<MappingStatus>,
+}
+
+impl SessionMetrics {
+    pub fn collect(session: &Session) -> Self {
+        let mut metrics = SessionMetrics {
+            blocked: session.ip_filter().counts(),
+            ..SessionMetrics::default()
+        };
+        for handle in session.queue() {
+            let torrent = session.get(*handle).unwrap();
+            let entry = TorrentMetrics {
+                handle: *handle,
+                info_hash: torrent.info_hash(),
+                name: torrent.name().to_string(),
+                downloaded: torrent.total_downloaded(),
+                uploaded: torrent.total_uploaded(),
+                download_rate: torrent.download_rate().get(),
+                upload_rate: torrent.upload_rate().get(),
+                connected_peers: torrent.num_peers(),
+                known_peers: torrent.peer_list().len(),
+                hash_failures: torrent.hash_failures(),
+                progress: torrent.progress(),
+            };
+            metrics.downloaded += entry.downloaded;
+            metrics.uploaded += entry.uploaded;
+            metrics.download_rate += entry.download_rate;
+            metrics.upload_rate += entry.upload_rate;
+            metrics.connected_peers += entry.connected_peers;
+            metrics.known_peers += entry.known_peers;
+            metrics.hash_failures += entry.hash_failures;
+            metrics.torrents.push(entry);
+        }
+        metrics
+    }
+
+    // The text exposition format, version 0.0.4. Per-torrent series are labelled with the
+    // torrent's handle, info-hash and name
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let session = |out: &mut String, name, kind, help, value: f64| {
+            family(out, name, kind, help, [(String::new(), value)]);
+        };
+        session(
+            &mut out,
+            "hurricane_torrents",
+            "gauge",
+            "Torrents in the session",
+            self.torrents.len() as f64,
+        );
+        session(
+            &mut out,
+            "hurricane_downloaded_bytes_total",
+            "counter",
+            "Payload bytes downloaded",
+            self.downloaded as f64,
+        );
+        session(
+            &mut out,
+            "hurricane_uploaded_bytes_total",
+            "counter",
+            "Payload bytes uploaded",
+            self.uploaded as f64,
+        );
+        session(
+            &mut out,
+            "hurricane_download_rate_bytes",
+            "gauge",
+            "Download rate in bytes per second, smoothed",
+            self.download_rate,
+        );
+        session(
+            &mut out,
+            "hurricane_upload_rate_bytes",
+            "gauge",
+            "Upload rate in bytes per second, smoothed",
+            self.upload_rate,
+        );
+        session(
+            &mut out,
+            "hurricane_peers_connected",
+            "gauge",
+            "Connected peers",
+            self.connected_peers as f64,
+        );
+        session(
+            &mut out,
+            "hurricane_peers_known",
+            "gauge",
+            "Peer addresses known",
+            self.known_peers as f64,
+        );
+        session(
+            &mut out,
+            "hurricane_hash_failures_total",
+            "counter",
+            "Pieces that failed their hash check",
+            self.hash_failures as f64,
+        );
+        let blocked = [
+            ("dial", self.blocked.dials),
+            ("accept", self.blocked.accepts),
+            ("discovered", self.blocked.discovered),
+        ];
+        family(
+            &mut out,
+            "hurricane_blocked_total",
+            "counter",
+            "Addresses turned away by the IP blocklist",
+            blocked.map(|(attempt, n)| (format!("{{attempt=\"{}\"}}", attempt), n as f64)),
+        );
+        if let Some(nodes) = self.dht_nodes {
+            session(
+                &mut out,
+                "hurricane_dht_nodes",
+                "gauge",
+                "Nodes in the DHT routing table",
+                nodes as f64,
+            );
+        }
+        if let Some(ratio) = self.cache_hit_ratio {
+            session(
+                &mut out,
+                "hurricane_cache_hit_ratio",
+                "gauge",
+                "Share of hashed piece data the write cache answered",
+                ratio,
+            );
+        }
+        if let Some(io) = &self.io_cache {
+            let reads = [("hit", io.read_hits), ("miss", io.read_misses)];
+            family(
+                &mut out,
+                "hurricane_io_cache_reads_total",
+                "counter",
+                "Block reads, by whether the I/O cache had them",
+                reads.map(|(result, n)| (format!("{{result=\"{}\"}}", result), n as f64)),
+            );
+            session(
+                &mut out,
+                "hurricane_io_cache_blocks_written_total",
+                "counter",
+                "Downloaded blocks written to disk",
+                io.blocks_written as f64,
+            );
+            session(
+                &mut out,
+                "hurricane_io_cache_writes_total",
+                "counter",
+                "Disk writes issued for them, after coalescing",
+                io.write_runs as f64,
+            );
+            session(
+                &mut out,
+                "hurricane_io_cache_read_ahead_blocks_total",
+                "counter",
+                "Blocks read ahead for peers reading in order",
+                io.read_ahead_blocks as f64,
+            );
+            session(
+                &mut out,
+                "hurricane_io_cache_evicted_blocks_total",
+                "counter",
+                "Blocks evicted from the I/O cache",
+                io.evicted_blocks as f64,
+            );
+        }
+        if !self.port_mappings.is_empty() {
+            family(
+                &mut out,
+                "hurricane_port_mapped",
+                "gauge",
+                "Whether the router forwards the port to us",
+                self.port_mappings.iter().map(|mapping| {
+                    let protocol = match mapping.protocol {
+                        Protocol::Tcp => "tcp",
+                        Protocol::Udp => "udp",
+                    };
+                    let mapped = matches!(mapping.state, MappingState::Mapped { .. });
+                    let labels = format!("{{protocol=\"{}\",port=\"{}\"}}", protocol, mapping.port);
+                    (labels, mapped as u8 as f64)
+                }),
+            );
+        }
+
+        type Field = fn(&TorrentMetrics) -> f64;
+        let torrent_families: [(&str, &str, &str, Field); 8] = [
+            (
+                "downloaded_bytes_total",
+                "counter",
+                "Payload bytes downloaded",
+                |t| t.downloaded as f64,
+            ),
+            (
+                "uploaded_bytes_total",
+                "counter",
+                "Payload bytes uploaded",
+                |t| t.uploaded as f64,
+            ),
+            (
+                "download_rate_bytes",
+                "gauge",
+                "Download rate in bytes per second, smoothed",
+                |t| t.download_rate,
+            ),
+            (
+                "upload_rate_bytes",
+                "gauge",
+                "Upload rate in bytes per second, smoothed",
+                |t| t.upload_rate,
+            ),
+            ("peers_connected", "gauge", "Connected peers", |t| {
+                t.connected_peers as f64
+            }),
+            ("peers_known", "gauge", "Peer addresses known", |t| {
+                t.known_peers as f64
+            }),
+            (
+                "hash_failures_total",
+                "counter",
+                "Pieces that failed their hash check",
+                |t| t.hash_failures as f64,
+            ),
+            (
+                "progress_ratio",
+                "gauge",
+                "Share of the wanted data we have",
+                |t| t.progress,
+            ),
+        ];
+        for (name, kind, help, field) in torrent_families {
+            let samples = self.torrents.iter().map(|t| {
+                let labels = format!(
+                    "{{handle=\"{}\",info_hash=\"{}\",name=\"{}\"}}",
+                    t.handle.0,
+                    t.info_hash.to_hex(),
+                    escape(&t.name)
+                );
+                (labels, field(t))
+            });
+            family(
+                &mut out,
+                &format!("hurricane_torrent_{}", name),
+                kind,
+                help,
+                samples,
+            );
+        }
+        out
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct DhtMetrics {
+    // "ipv4" or "ipv6", the label that tells a dual-stack node's two halves apart
+    pub family: &'static str,
+    pub nodes: usize,
+    pub buckets: usize,
+    pub torrents: usize,
+    pub peers: usize,
+    pub items: usize,
+    pub stats: DhtStats,
+}
+
+impl DhtMetrics {
+    pub fn collect(dht: &Dht, family: &'static str) -> Self {
+        DhtMetrics {
+            family,
+            nodes: dht.table().len(),
+            buckets: dht.table().num_buckets(),
+            torrents: dht.stored().len(),
+            peers: dht.stored_peers(),
+            items: dht.items().len(),
+            stats: dht.stats().clone(),
+        }
+    }
+
+    // Every node's series in one family each, labelled with the node's address family
+    pub fn to_prometheus(nodes: &[DhtMetrics]) -> String {
+        let mut out = String::new();
+        let label =
+            |node: &DhtMetrics, extra: &str| format!("{{family=\"{}\"{}}}", node.family, extra);
+
+        type Field = fn(&DhtMetrics) -> f64;
+        let gauges: [(&str, &str, Field); 5] = [
+            ("nodes", "Nodes in the routing table", |n| n.nodes as f64),
+            ("buckets", "Buckets in the routing table", |n| {
+                n.buckets as f64
+            }),
+            ("torrents", "Info-hashes with stored peers", |n| {
+                n.torrents as f64
+            }),
+            ("peers", "Peers stored across all info-hashes", |n| {
+                n.peers as f64
+            }),
+            ("items", "BEP 44 items stored", |n| n.items as f64),
+        ];
+        for (name, help, field) in gauges {
+            let samples = nodes.iter().map(|n| (label(n, ""), field(n)));
+            family(
+                &mut out,
+                &format!("hurricane_dht_{}", name),
+                "gauge",
+                help,
+                samples,
+            );
+        }
+
+        let directions = nodes.iter().flat_map(|n| {
+            [
+                ("in", n, n.stats.packets_in, n.stats.bytes_in),
+                ("out", n, n.stats.packets_out, n.stats.bytes_out),
+            ]
+        });
+        let samples = directions.clone().map(|(dir, n, packets, _)| {
+            (label(n, &format!(",direction=\"{}\"", dir)), packets as f64)
+        });
+        family(
+            &mut out,
+            "hurricane_dht_packets_total",
+            "counter",
+            "UDP packets",
+            samples,
+        );
+        let samples = directions
+            .map(|(dir, n, _, bytes)| (label(n, &format!(",direction=\"{}\"", dir)), bytes as f64));
+        family(
+            &mut out,
+            "hurricane_dht_bytes_total",
+            "counter",
+            "UDP payload bytes",
+            samples,
+        );
+
+        let samples = nodes.iter().flat_map(|n| {
+            n.stats.queries.iter().map(move |(method, count)| {
+                (label(n, &format!(",method=\"{}\"", method)), *count as f64)
+            })
+        });
+        family(
+            &mut out,
+            "hurricane_dht_queries_total",
+            "counter",
+            "Queries answered",
+            samples,
+        );
+
+        let samples = nodes.iter().flat_map(|n| {
+            [
+                (
+                    label(n, ",reason=\"rate_limited\""),
+                    n.stats.rate_limited as f64,
+                ),
+                (label(n, ",reason=\"invalid\""), n.stats.invalid as f64),
+            ]
+        });
+        family(
+            &mut out,
+            "hurricane_dht_dropped_total",
+            "counter",
+            "Incoming packets dropped unanswered",
+            samples,
+        );
+
+        let samples = nodes
+            .iter()
+            .map(|n| (label(n, ""), n.stats.timeouts as f64));
+        family(
+            &mut out,
+            "hurricane_dht_timeouts_total",
+            "counter",
+            "Queries of ours that went unanswered",
+            samples,
+        );
+
+        let samples = nodes.iter().flat_map(|n| {
+            [
+                (label(n, ",what=\"peer\""), n.stats.evicted_peers as f64),
+                (
+                    label(n, ",what=\"torrent\""),
+                    n.stats.evicted_torrents as f64,
+                ),
+            ]
+        });
+        family(
+            &mut out,
+            "hurricane_dht_evicted_total",
+            "counter",
+            "Stored peers and info-hashes dropped to make room",
+            samples,
+        );
+        out
+    }
+}
+
+fn family(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (String, f64)>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{} {}", name, labels, value);
+    }
+}
+
+// Label values are quoted, so backslashes, quotes and newlines are escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub struct MetricsServer {
+    listener: TcpListener,
+}
+
+impl MetricsServer {
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(MetricsServer { listener })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Answers the scrapes that came in since the last call, each on a thread of its own so a
+    // slow one holds nothing up. `render` is only called when there's a scrape to answer
+    pub fn poll(&self, render: impl FnOnce() -> String) {
+        let mut body = None;
+        let mut render = Some(render);
+        while let Ok((stream, _)) = self.listener.accept() {
+            let body: &Arc<String> = body.get_or_insert_with(|| Arc::new(render.take().unwrap()()));
+            let body = body.clone();
+            thread::spawn(move || {
+                let _ = answer(stream, &body);
+            });
+        }
+    }
+}
+
+fn answer(mut stream: TcpStream, body: &str) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut head = vec![];
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf)?;
+        if n == 0 || head.len() + n > MAX_HEAD {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut words = head.split_whitespace();
+    let (method, path) = (words.next(), words.next());
+    let (status, content_type, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => {
+            ("200 OK", "text/plain; version=0.0.4; charset=utf-8", body)
+        }
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "not found\n"),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n",
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::metainfo::MagnetLink;
+    use crate::torrent::Torrent;
+
+    fn session() -> Session {
+        let now = Instant::now();
+        let mut session = Session::new();
+        for i in 1..=2 {
+            let mut magnet = MagnetLink::new(InfoHash([i; 20]));
+            magnet.display_name = Some(format!("torrent \"{}\"", i));
+            let handle = session
+                .add(Torrent::from_magnet(&magnet, now), now)
+                .handle();
+            let mut torrent = session.get_mut(handle).unwrap();
+            torrent.on_downloaded(1000 * i as u64);
+            torrent.set_peer_counts(i as usize, 0);
+        }
+        session
+    }
+
+    #[test]
+    fn test_prometheus() {
+        let mut metrics = SessionMetrics::collect(&session());
+        assert_eq!(metrics.downloaded, 3000);
+        assert_eq!(metrics.connected_peers, 3);
+        metrics.dht_nodes = Some(120);
+        metrics.io_cache = Some(IoCacheStats {
+            read_hits: 5,
+            write_runs: 2,
+            ..IoCacheStats::default()
+        });
+        metrics.port_mappings = vec![MappingStatus {
+            protocol: Protocol::Tcp,
+            port: 6881,
+            state: MappingState::Pending,
+        }];
+
+        let text = metrics.to_prometheus();
+        assert!(text.contains(
+            "# TYPE hurricane_downloaded_bytes_total counter\nhurricane_downloaded_bytes_total 3000\n"
+        ));
+        assert!(text.contains("hurricane_dht_nodes 120\n"));
+        assert!(text.contains("hurricane_blocked_total{attempt=\"accept\"} 0\n"));
+        assert!(!text.contains("hurricane_cache_hit_ratio"));
+        assert!(text.contains("hurricane_io_cache_reads_total{result=\"hit\"} 5\n"));
+        assert!(text.contains("hurricane_io_cache_writes_total 2\n"));
+        assert!(text.contains("hurricane_port_mapped{protocol=\"tcp\",port=\"6881\"} 0\n"));
+        let hex = InfoHash([2; 20]).to_hex();
+        assert!(text.contains(&format!(
+            "hurricane_torrent_peers_connected{{handle=\"1\",info_hash=\"{}\",name=\"torrent \\\"2\\\"\"}} 2\n",
+            hex
+        )));
+        // One HELP and one TYPE per family, samples after them
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            let (series, value) = line.rsplit_once(' ').unwrap();
+            assert!(series.starts_with("hurricane_"));
+            assert!(value.parse::<f64>().is_ok(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_dht_prometheus() {
+        let now = Instant::now();
+        let mut dht = Dht::new(crate::dht::DhtConfig::default(), now);
+        dht.handle_packet(b"junk", "10.0.0.1:6881".parse().unwrap(), now);
+        let mut v6 = DhtMetrics::collect(&dht, "ipv6");
+        v6.stats.queries.insert("ping", 3);
+
+        let text = DhtMetrics::to_prometheus(&[DhtMetrics::collect(&dht, "ipv4"), v6]);
+        assert!(text.contains("hurricane_dht_nodes{family=\"ipv4\"} 0\n"));
+        assert!(text.contains("hurricane_dht_packets_total{family=\"ipv6\",direction=\"in\"} 1\n"));
+        assert!(
+            text.contains("hurricane_dht_dropped_total{family=\"ipv4\",reason=\"invalid\"} 1\n")
+        );
+        assert!(text.contains("hurricane_dht_queries_total{family=\"ipv6\",method=\"ping\"} 3\n"));
+        assert_eq!(text.matches("# TYPE hurricane_dht_bytes_total").count(), 1);
+    }
+
+    #[test]
+    fn test_server() {
+        let server = MetricsServer::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let scrape = |path: &str| {
+            let mut stream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            // Polled until the connection is picked up and answered
+            stream
+                .set_read_timeout(Some(Duration::from_millis(10)))
+                .unwrap();
+            let mut response = vec![];
+            for _ in 0..500 {
+                server.poll(|| "hurricane_torrents 2\n".to_string());
+                match stream.read_to_end(&mut response) {
+                    Ok(_) => break,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(err) if err.kind() == io::ErrorKind::TimedOut => continue,
+                    Err(err) => panic!("{}", err),
+                }
+            }
+            String::from_utf8(response).unwrap()
+        };
+        let response = scrape("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nhurricane_torrents 2\n"));
+        assert!(scrape("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,179 @@
+// Node.js bindings (napi-rs) for the parsing layers, so Electron front-ends can read .torrent
+// files and magnet links without embedding a whole client. Build the addon with `napi build`.
+use std::collections::BTreeMap;
+
+use napi::bindgen_prelude::*;
+use napi::{JsBuffer, JsObject, JsUnknown, ValueType};
+use napi_derive::napi;
+
+use crate::bencode::{self, BencodeValue};
+use crate::infohash::InfoHash;
+use crate::metainfo::{MagnetLink, Metainfo};
+
+#[napi(object)]
+pub struct TorrentFile {
+    pub path: Vec<String>,
+    pub length: i64,
+}
+
+#[napi(object)]
+pub struct Torrent {
+    pub info_hash: String,
+    pub name: String,
+    pub piece_length: u32,
+    pub num_pieces: u32,
+    pub total_length: i64,
+    pub files: Vec<TorrentFile>,
+    pub trackers: Vec<Vec<String>>,
+    pub private: bool,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub creation_date: Option<i64>,
+}
+
+#[napi(object)]
+pub struct Magnet {
+    pub info_hash: String,
+    pub display_name: Option<String>,
+    pub trackers: Vec<String>,
+    pub peers: Vec<String>,
+}
+
+fn error<E: std::fmt::Display>(err: E) -> Error {
+    Error::from_reason(err.to_string())
+}
+
+// Byte strings come back as Buffers and dict keys as (lossy UTF-8) strings
+#[napi]
+pub fn bdecode(env: Env, buf: Buffer) -> Result<JsUnknown> {
+    let values = bencode::decode(&buf).map_err(error)?;
+    match values.as_slice() {
+        [value] => to_js(&env, value),
+        _ => Err(Error::from_reason("expected exactly one bencoded value")),
+    }
+}
+
+// Numbers must be integers (so anything past 2^53 loses precision). Strings are encoded as UTF-8
+#[napi]
+pub fn bencode(value: JsUnknown) -> Result<Buffer> {
+    Ok(bencode::encode(&from_js(value)?).into())
+}
+
+#[napi]
+pub fn parse_torrent(buf: Buffer) -> Result<Torrent> {
+    let metainfo = Metainfo::from_bytes(&buf).map_err(error)?;
+    let trackers = metainfo.trackers();
+    let info = metainfo.info;
+
+    Ok(Torrent {
+        info_hash: metainfo.info_hash.to_hex(),
+        num_pieces: info.num_pieces(),
+        total_length: info.total_length() as i64,
+        name: info.name,
+        piece_length: info.piece_length,
+        files: info
+            .files
+            .into_iter()
+            .map(|f| TorrentFile {
+                path: f.path,
+                length: f.length as i64,
+            })
+            .collect(),
+        trackers,
+        private: info.private,
+        comment: metainfo.comment,
+        created_by: metainfo.created_by,
+        creation_date: metainfo.creation_date,
+    })
+}
+
+#[napi]
+pub fn parse_magnet(uri: String) -> Result<Magnet> {
+    let magnet = MagnetLink::parse(&uri).map_err(error)?;
+    Ok(Magnet {
+        info_hash: magnet.info_hash.to_hex(),
+        display_name: magnet.display_name,
+        trackers: magnet.trackers,
+        peers: magnet.peers,
+    })
+}
+
+#[napi]
+pub fn magnet_to_uri(magnet: Magnet) -> Result<String> {
+    let info_hash = InfoHash::from_hex(&magnet.info_hash)
+        .ok_or_else(|| Error::from_reason("info-hash must be 40 hex characters"))?;
+    Ok(MagnetLink {
+        info_hash,
+        display_name: magnet.display_name,
+        trackers: magnet.trackers,
+        peers: magnet.peers,
+    }
+    .to_uri())
+}
+
+fn to_js(env: &Env, value: &BencodeValue) -> Result<JsUnknown> {
+    Ok(match value {
+        BencodeValue::Int(i) => env.create_int64(*i)?.into_unknown(),
+        BencodeValue::ByteStr(bytes) => env
+            .create_buffer_with_data(bytes.clone())?
+            .into_raw()
+            .into_unknown(),
+        BencodeValue::List(list) => {
+            let mut array = env.create_array_with_length(list.len())?;
+            for (i, item) in list.iter().enumerate() {
+                array.set_element(i as u32, to_js(env, item)?)?;
+            }
+            array.into_unknown()
+        }
+        BencodeValue::Dict(dict) => {
+            let mut object = env.create_object()?;
+            for (key, item) in dict {
+                object.set_named_property(&String::from_utf8_lossy(key), to_js(env, item)?)?;
+            }
+            object.into_unknown()
+        }
+    })
+}
+
+fn from_js(value: JsUnknown) -> Result<BencodeValue> {
+    match value.get_type()? {
+        ValueType::Number => {
+            let n = value.coerce_to_number()?.get_double()?;
+            if n.fract() != 0.0 || !n.is_finite() {
+                return Err(Error::from_reason("can't bencode a non-integer number"));
+            }
+            Ok(BencodeValue::Int(n as i64))
+        }
+        ValueType::String => {
+            let s = value.coerce_to_string()?.into_utf8()?;
+            Ok(BencodeValue::ByteStr(s.as_slice().to_vec()))
+        }
+        ValueType::Object if value.is_buffer()? => {
+            let buf = unsafe { value.cast::<JsBuffer>() }.into_value()?;
+            Ok(BencodeValue::ByteStr(buf.to_vec()))
+        }
+        ValueType::Object if value.is_array()? => {
+            let array: JsObject = unsafe { value.cast() };
+            let len = array.get_array_length()?;
+            let items = (0..len)
+                .map(|i| from_js(array.get_element::<JsUnknown>(i)?))
+                .collect::<Result<_>>()?;
+            Ok(BencodeValue::List(items))
+        }
+        ValueType::Object => {
+            let object: JsObject = unsafe { value.cast() };
+            let keys = object.get_property_names()?;
+            let mut dict = BTreeMap::new();
+            for i in 0..keys.get_array_length()? {
+                let key = keys
+                    .get_element::<JsUnknown>(i)?
+                    .coerce_to_string()?
+                    .into_utf8()?;
+                let item = object.get_named_property::<JsUnknown>(key.as_str()?)?;
+                dict.insert(key.as_slice().to_vec(), from_js(item)?);
+            }
+            Ok(BencodeValue::Dict(dict))
+        }
+        other => Err(Error::from_reason(format!("can't bencode {}", other))),
+    }
+}
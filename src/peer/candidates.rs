@@ -0,0 +1,814 @@
+// Addresses we could connect to for one torrent, from trackers, the DHT, PEX and so on.
+// In a swarm of tens of thousands every source happily hands us more peers than we'll ever try,
+// so the list is capped (scaled to the swarm size), the least promising entry is evicted when
+// something better arrives, and addresses nobody has mentioned in a while are forgotten.
+// Addresses of a family we don't use are turned away, and the preferred one gets a small edge.
+// So are blocked ones, see `blocklist`.
+// A private torrent's (BEP 27) peers come from its trackers only, so its list turns away what the
+// DHT, PEX and LSD find. Peers that connected to us, or that the user added, are still fine.
+// It's also where connection slots are handed out. Only `MAX_HALF_OPEN` dials are in flight at
+// a time, an address that failed or just disconnected waits a while before it's tried again,
+// and peers that sent bad data or broke the protocol are banned by IP for a while. Once every
+// slot is taken, `replacement` names a connected peer that's had its chance and is worth less
+// than the best waiting candidate, for the caller to drop.
+// Once we know our own address, candidates that score the same are told apart by their BEP 40
+// priority (see `priority`): the highest are dialed first, and the lowest are the first to go.
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use super::listen::IpFamilies;
+use super::pex::{MAX_PEX_PEERS, PexFlags};
+use super::priority::peer_priority;
+use crate::blocklist::{Attempt, IpFilter};
+use crate::rng::Rng;
+
+// Bounds on how many candidates we keep. Small swarms get the floor, huge ones the ceiling
+pub const MIN_CANDIDATES: usize = 200;
+pub const MAX_CANDIDATES: usize = 3000;
+
+// Candidates nobody has told us about again in this long are dropped
+pub const CANDIDATE_TTL: Duration = Duration::from_secs(30 * 60);
+
+// Failed connection attempts before we give up on an address
+pub const MAX_CONNECT_FAILURES: u32 = 3;
+
+// Dials in flight at once. Each ties up a socket until it connects or times out, and plenty of
+// home routers struggle with more
+pub const MAX_HALF_OPEN: usize = 8;
+
+// Before dialing an address again after a disconnect. Doubled for each failed attempt in a row
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+// Connected peers get this long to prove themselves before they can lose their slot
+pub const REPLACE_GRACE: Duration = Duration::from_secs(2 * 60);
+
+// How much better a candidate has to score to take a connected peer's slot: a whole source's
+// worth, so slots don't churn over small differences
+const REPLACE_MARGIN: i64 = 100;
+
+// Score bonus for the preferred address family. Enough to break ties, not to beat a better source
+const PREFERRED_FAMILY_BONUS: i64 = 10;
+
+// Ordered from least to most trusted
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub enum PeerSource {
+    Pex,
+    Dht,
+    // Local service discovery
+    Lsd,
+    Tracker,
+    // They connected to us
+    Incoming,
+    // Added by the user or from a magnet link's x.pe
+    Manual,
+}
+
+// Why a peer was banned
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum BanReason {
+    // Sent data that failed hash checks
+    BadData,
+    // Sent something no well-behaved client would
+    ProtocolViolation,
+}
+
+impl BanReason {
+    pub fn duration(self) -> Duration {
+        match self {
+            BanReason::BadData => Duration::from_secs(60 * 60),
+            BanReason::ProtocolViolation => Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub source: PeerSource,
+    pub flags: PexFlags,
+    pub last_seen: Instant,
+    pub failures: u32,
+    // Dialed, or they connected to us, and no handshake yet
+    pub half_open: bool,
+    pub connected: bool,
+    pub connected_at: Option<Instant>,
+    // Block data they sent us, over every connection so far
+    pub downloaded: u64,
+    // Not to be dialed before then
+    pub retry_at: Option<Instant>,
+    // BEP 40, with our address of its family. 0 until we know that
+    pub priority: u32,
+}
+
+impl Candidate {
+    fn new(source: PeerSource, flags: PexFlags, now: Instant) -> Self {
+        Candidate {
+            source,
+            flags,
+            last_seen: now,
+            failures: 0,
+            half_open: false,
+            connected: false,
+            connected_at: None,
+            downloaded: 0,
+            retry_at: None,
+            priority: 0,
+        }
+    }
+
+    // Connected or on the way there, so not a candidate for another connection
+    pub fn in_use(&self) -> bool {
+        self.half_open || self.connected
+    }
+
+    // Higher is better. Sources and reachability count the most, then what they've sent us, a
+    // point per MiB up to two sources' worth. Failures and staleness pull an address down
+    fn score(&self, seeding: bool, now: Instant) -> i64 {
+        let mut score = self.source as i64 * 100;
+        if self.flags.has(PexFlags::REACHABLE) {
+            score += 50;
+        }
+        // Seeds are no use to us once we're seeding too
+        if self.flags.has(PexFlags::SEED) && !seeding {
+            score += 25;
+        }
+        score += (self.downloaded >> 20).min(200) as i64;
+        let age = now.saturating_duration_since(self.last_seen).as_secs() / 60;
+        score - self.failures as i64 * 100 - age as i64 * 2
+    }
+
+    fn dialable(&self, now: Instant) -> bool {
+        !self.in_use() && self.retry_at.is_none_or(|at| now >= at)
+    }
+}
+
+fn allowed_when_private(source: PeerSource) -> bool {
+    matches!(
+        source,
+        PeerSource::Tracker | PeerSource::Incoming | PeerSource::Manual
+    )
+}
+
+pub fn candidate_cap(swarm_size: usize) -> usize {
+    swarm_size.clamp(MIN_CANDIDATES, MAX_CANDIDATES)
+}
+
+#[derive(Debug)]
+pub struct PeerList {
+    peers: HashMap<SocketAddr, Candidate>,
+    cap: usize,
+    seeding: bool,
+    families: IpFamilies,
+    filter: IpFilter,
+    private: bool,
+    // Until when
+    bans: HashMap<IpAddr, Instant>,
+    max_half_open: usize,
+    // As other peers see us, at most one per family
+    our_addrs: Vec<SocketAddr>,
+}
+
+impl Default for PeerList {
+    fn default() -> Self {
+        PeerList::new()
+    }
+}
+
+impl PeerList {
+    pub fn new() -> Self {
+        PeerList {
+            peers: HashMap::new(),
+            cap: MIN_CANDIDATES,
+            seeding: false,
+            families: IpFamilies::default(),
+            filter: IpFilter::new(),
+            private: false,
+            bans: HashMap::new(),
+            max_half_open: MAX_HALF_OPEN,
+            our_addrs: vec![],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn cap(&self) -> usize {
+        self.cap
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&Candidate> {
+        self.peers.get(addr)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&SocketAddr, &Candidate)> {
+        self.peers.iter()
+    }
+
+    // Swarm size as best we know it, e.g. seeders + leechers from the last scrape
+    pub fn set_swarm_size(&mut self, swarm_size: usize, now: Instant) {
+        self.cap = candidate_cap(swarm_size);
+        while self.peers.len() > self.cap {
+            if !self.evict_worst(None, now) {
+                break;
+            }
+        }
+    }
+
+    pub fn set_seeding(&mut self, seeding: bool) {
+        self.seeding = seeding;
+    }
+
+    pub fn families(&self) -> IpFamilies {
+        self.families
+    }
+
+    // Candidates of a family that's now turned off are dropped, unless we're connected to them
+    pub fn set_families(&mut self, families: IpFamilies) {
+        self.families = families;
+        self.peers
+            .retain(|addr, c| c.in_use() || families.allows(addr));
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private
+    }
+
+    // Once a torrent is known to be private, what came from the DHT, PEX or LSD is dropped
+    pub fn set_private(&mut self, private: bool) {
+        self.private = private;
+        if private {
+            self.peers
+                .retain(|_, c| c.in_use() || allowed_when_private(c.source));
+        }
+    }
+
+    // Forgets everyone, connected or not, e.g. when a private torrent's trackers change. Bans stay
+    pub fn clear(&mut self) {
+        self.peers.clear();
+    }
+
+    pub fn our_addrs(&self) -> &[SocketAddr] {
+        &self.our_addrs
+    }
+
+    // Our external address and listen port, e.g. from a peer's `yourip`. It replaces the one we
+    // had for its family, and every candidate of that family gets its priority worked out again
+    pub fn set_our_addr(&mut self, addr: SocketAddr) {
+        if self.our_addrs.contains(&addr) {
+            return;
+        }
+        self.our_addrs.retain(|a| a.is_ipv6() != addr.is_ipv6());
+        self.our_addrs.push(addr);
+        for (peer, candidate) in &mut self.peers {
+            if peer.is_ipv6() == addr.is_ipv6() {
+                candidate.priority = peer_priority(addr, *peer);
+            }
+        }
+    }
+
+    pub fn filter(&self) -> &IpFilter {
+        &self.filter
+    }
+
+    // Blocked candidates are dropped, and counted, unless we're connected to them
+    pub fn set_filter(&mut self, filter: IpFilter) {
+        self.peers
+            .retain(|addr, c| c.in_use() || filter.allows(addr.ip(), Attempt::Discovered));
+        self.filter = filter;
+    }
+
+    // Returns whether the address is (still) in the list. Hearing about a known address again
+    // refreshes it and keeps its best source
+    pub fn insert(
+        &mut self,
+        addr: SocketAddr,
+        source: PeerSource,
+        flags: PexFlags,
+        now: Instant,
+    ) -> bool {
+        if !self.families.allows(&addr)
+            || (self.private && !allowed_when_private(source))
+            || self.is_banned(addr.ip(), now)
+            || !self.filter.allows(addr.ip(), Attempt::Discovered)
+        {
+            return false;
+        }
+        if let Some(candidate) = self.peers.get_mut(&addr) {
+            candidate.last_seen = now;
+            candidate.source = candidate.source.max(source);
+            candidate.flags = PexFlags(candidate.flags.0 | flags.0);
+            return true;
+        }
+
+        let mut candidate = Candidate::new(source, flags, now);
+        candidate.priority = self.priority(&addr);
+        if self.peers.len() >= self.cap {
+            let rank = (self.score(&addr, &candidate, now), candidate.priority);
+            if !self.evict_worst(Some(rank), now) {
+                return false;
+            }
+        }
+        self.peers.insert(addr, candidate);
+        true
+    }
+
+    // Takes peers from an incoming PEX message. While there's plenty of room everything goes in,
+    // but once the list fills up only the most promising few per message are considered, so a
+    // busy swarm's gossip can't keep churning the whole list. Returns how many were added
+    pub fn add_from_pex(&mut self, added: &[(SocketAddr, PexFlags)], now: Instant) -> usize {
+        let free = self.cap.saturating_sub(self.peers.len());
+        let budget = if free >= self.cap / 2 {
+            MAX_PEX_PEERS
+        } else {
+            free.max(MAX_PEX_PEERS / 5)
+        };
+
+        let mut added = added.to_vec();
+        let seeding = self.seeding;
+        added.sort_by_key(|(_, flags)| {
+            let seed = flags.has(PexFlags::SEED) && !seeding;
+            std::cmp::Reverse((flags.has(PexFlags::REACHABLE), seed))
+        });
+        added.retain(|(addr, _)| !self.peers.contains_key(addr));
+        added.truncate(budget);
+        added
+            .into_iter()
+            .filter(|(addr, flags)| self.insert(*addr, PeerSource::Pex, *flags, now))
+            .count()
+    }
+
+    pub fn max_half_open(&self) -> usize {
+        self.max_half_open
+    }
+
+    pub fn set_max_half_open(&mut self, max: usize) {
+        self.max_half_open = max.max(1);
+    }
+
+    pub fn half_open(&self) -> usize {
+        self.peers.values().filter(|c| c.half_open).count()
+    }
+
+    // We're dialing it, or it connected to us. Either way it's half-open until the handshake
+    pub fn on_connecting(&mut self, addr: &SocketAddr) {
+        if let Some(candidate) = self.peers.get_mut(addr) {
+            candidate.half_open = true;
+        }
+    }
+
+    // Handshake done
+    pub fn on_connected(&mut self, addr: &SocketAddr, now: Instant) {
+        if let Some(candidate) = self.peers.get_mut(addr) {
+            candidate.half_open = false;
+            candidate.connected = true;
+            candidate.connected_at = Some(now);
+            candidate.failures = 0;
+        }
+    }
+
+    pub fn is_connected(&self, addr: &SocketAddr) -> bool {
+        self.peers.get(addr).is_some_and(|c| c.connected)
+    }
+
+    // Block data from a connected peer, which counts for them from now on
+    pub fn on_downloaded(&mut self, addr: &SocketAddr, bytes: u64) {
+        if let Some(candidate) = self.peers.get_mut(addr) {
+            candidate.downloaded += bytes;
+        }
+    }
+
+    // Also what ends an attempt that never got as far as the handshake, which counts as a failed
+    // one. Whoever is running the connection should notice it's no longer `is_connected`
+    pub fn on_disconnected(&mut self, addr: &SocketAddr, now: Instant) {
+        let Some(candidate) = self.peers.get_mut(addr) else {
+            return;
+        };
+        if candidate.half_open {
+            self.on_connect_failed(addr, now);
+            return;
+        }
+        candidate.connected = false;
+        candidate.connected_at = None;
+        candidate.last_seen = now;
+        candidate.retry_at = Some(now + RECONNECT_DELAY);
+    }
+
+    // E.g. one that's been blocked since it was added
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        self.peers.remove(addr);
+    }
+
+    pub fn on_connect_failed(&mut self, addr: &SocketAddr, now: Instant) {
+        let Some(candidate) = self.peers.get_mut(addr) else {
+            return;
+        };
+        candidate.half_open = false;
+        candidate.connected = false;
+        candidate.failures += 1;
+        if candidate.failures >= MAX_CONNECT_FAILURES {
+            self.peers.remove(addr);
+            return;
+        }
+        candidate.retry_at = Some(now + RECONNECT_DELAY * 2u32.pow(candidate.failures - 1));
+    }
+
+    // Drops every address at `addr`'s IP, connected or not, and turns the IP away until the ban
+    // runs out. The caller still has to close the connection
+    pub fn ban(&mut self, addr: SocketAddr, reason: BanReason, now: Instant) {
+        self.bans.insert(addr.ip(), now + reason.duration());
+        self.peers.retain(|a, _| a.ip() != addr.ip());
+    }
+
+    pub fn is_banned(&self, ip: IpAddr, now: Instant) -> bool {
+        self.bans.get(&ip).is_some_and(|until| now < *until)
+    }
+
+    pub fn num_banned(&self, now: Instant) -> usize {
+        self.bans.values().filter(|until| now < **until).count()
+    }
+
+    // Forgets addresses that have gone quiet, and bans that ran out. Peers in use are kept
+    // regardless
+    pub fn decay(&mut self, now: Instant) {
+        self.peers.retain(|_, c| {
+            c.in_use() || now.saturating_duration_since(c.last_seen) < CANDIDATE_TTL
+        });
+        self.bans.retain(|_, until| now < *until);
+    }
+
+    // The best `count` addresses to dial, as far as the half-open limit allows. Ones waiting out
+    // a reconnect delay are left for later
+    pub fn next_candidates(&self, count: usize, now: Instant) -> Vec<SocketAddr> {
+        let count = count.min(self.max_half_open.saturating_sub(self.half_open()));
+        self.ranked(now).into_iter().take(count).collect()
+    }
+
+    // For when every slot is taken: (connected peer to drop, candidate to dial in its place), if
+    // the candidate scores enough better. Peers connected for less than REPLACE_GRACE are safe
+    pub fn replacement(&self, now: Instant) -> Option<(SocketAddr, SocketAddr)> {
+        let best = *self.ranked(now).first()?;
+        let (worst_score, worst) = self
+            .peers
+            .iter()
+            .filter(|(_, c)| {
+                c.connected_at
+                    .is_some_and(|at| now.saturating_duration_since(at) >= REPLACE_GRACE)
+            })
+            .map(|(addr, c)| (self.score(addr, c, now), c.priority, *addr))
+            .min()
+            .map(|(score, _, addr)| (score, addr))?;
+        let best_score = self.score(&best, &self.peers[&best], now);
+        (best_score - worst_score >= REPLACE_MARGIN).then_some((worst, best))
+    }
+
+    // Dialable addresses, best first
+    fn ranked(&self, now: Instant) -> Vec<SocketAddr> {
+        let mut candidates: Vec<(i64, u32, SocketAddr)> = self
+            .peers
+            .iter()
+            .filter(|(_, c)| c.dialable(now))
+            .map(|(addr, c)| (self.score(addr, c, now), c.priority, *addr))
+            .collect();
+        candidates.sort_by(|a, b| b.cmp(a));
+        candidates.into_iter().map(|(.., addr)| addr).collect()
+    }
+
+    // Connected peers in random order, for `PexState::tick`. With hundreds of connections only
+    // the first MAX_PEX_PEERS new ones fit in a message, and shuffling means different neighbours
+    // hear about different parts of the swarm instead of all getting the same slice
+    pub fn pex_sample(&self, rng: &mut Rng) -> Vec<(SocketAddr, PexFlags)> {
+        let mut connected: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, c)| c.connected)
+            .map(|(addr, c)| (*addr, c.flags))
+            .collect();
+        rng.shuffle(&mut connected);
+        connected
+    }
+
+    fn priority(&self, addr: &SocketAddr) -> u32 {
+        self.our_addrs
+            .iter()
+            .find(|ours| ours.is_ipv6() == addr.is_ipv6())
+            .map_or(0, |ours| peer_priority(*ours, *addr))
+    }
+
+    fn score(&self, addr: &SocketAddr, candidate: &Candidate, now: Instant) -> i64 {
+        let score = candidate.score(self.seeding, now);
+        if self.families.prefers(addr) {
+            score + PREFERRED_FAMILY_BONUS
+        } else {
+            score
+        }
+    }
+
+    // Drops the lowest ranked unconnected candidate, as long as it ranks below `than`: a
+    // (score, priority) pair
+    fn evict_worst(&mut self, than: Option<(i64, u32)>, now: Instant) -> bool {
+        let worst = self
+            .peers
+            .iter()
+            .filter(|(_, c)| !c.in_use())
+            .map(|(addr, c)| ((self.score(addr, c, now), c.priority), *addr))
+            .min();
+        match worst {
+            Some((rank, addr)) if than.is_none_or(|than| rank < than) => {
+                self.peers.remove(&addr);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::blocklist::Blocklist;
+
+    fn peer(i: u16) -> SocketAddr {
+        SocketAddr::new([10, 0, (i >> 8) as u8, i as u8].into(), 6881)
+    }
+
+    fn full_list(now: Instant) -> PeerList {
+        let mut list = PeerList::new();
+        for i in 0..MIN_CANDIDATES as u16 {
+            assert!(list.insert(peer(i), PeerSource::Pex, PexFlags(0), now));
+        }
+        list
+    }
+
+    #[test]
+    fn test_cap_scales_with_swarm() {
+        assert_eq!(candidate_cap(10), MIN_CANDIDATES);
+        assert_eq!(candidate_cap(1000), 1000);
+        assert_eq!(candidate_cap(50_000), MAX_CANDIDATES);
+    }
+
+    #[test]
+    fn test_eviction_prefers_better_sources() {
+        let now = Instant::now();
+        let mut list = full_list(now);
+
+        // Another PEX peer doesn't beat what we have, a tracker peer does
+        assert!(!list.insert(peer(1000), PeerSource::Pex, PexFlags(0), now));
+        assert!(list.insert(peer(1001), PeerSource::Tracker, PexFlags(0), now));
+        assert_eq!(list.len(), MIN_CANDIDATES);
+        assert_eq!(list.next_candidates(1, now), vec![peer(1001)]);
+    }
+
+    #[test]
+    fn test_connected_never_evicted() {
+        let now = Instant::now();
+        let mut list = PeerList::new();
+        list.insert(peer(1), PeerSource::Pex, PexFlags(0), now);
+        list.on_connected(&peer(1), now);
+        list.set_swarm_size(0, now);
+        list.decay(now + CANDIDATE_TTL);
+
+        assert!(list.get(&peer(1)).is_some());
+    }
+
+    #[test]
+    fn test_decay_and_failures() {
+        let now = Instant::now();
+        let mut list = PeerList::new();
+        list.insert(peer(1), PeerSource::Dht, PexFlags(0), now);
+        list.insert(peer(2), PeerSource::Dht, PexFlags(0), now);
+        // Hearing about it again keeps it fresh
+        list.insert(
+            peer(2),
+            PeerSource::Dht,
+            PexFlags(0),
+            now + CANDIDATE_TTL / 2,
+        );
+
+        list.decay(now + CANDIDATE_TTL);
+        assert!(list.get(&peer(1)).is_none());
+        assert!(list.get(&peer(2)).is_some());
+
+        for _ in 0..MAX_CONNECT_FAILURES {
+            list.on_connect_failed(&peer(2), now);
+        }
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn test_pex_budget_when_full() {
+        let now = Instant::now();
+        let mut list = full_list(now);
+        let added: Vec<_> = (1000..1050)
+            .map(|i| (peer(i), PexFlags(PexFlags::REACHABLE)))
+            .collect();
+
+        // Reachable peers outscore the old ones, but only a handful get in per message
+        assert_eq!(list.add_from_pex(&added, now), MAX_PEX_PEERS / 5);
+        assert_eq!(list.len(), MIN_CANDIDATES);
+    }
+
+    #[test]
+    fn test_families() {
+        let now = Instant::now();
+        let v6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let mut list = PeerList::new();
+        list.insert(peer(1), PeerSource::Dht, PexFlags(0), now);
+        list.insert(v6, PeerSource::Dht, PexFlags(0), now);
+        assert_eq!(list.next_candidates(2, now), vec![peer(1), v6]);
+
+        list.set_families(IpFamilies {
+            prefer_ipv6: true,
+            ..IpFamilies::default()
+        });
+        assert_eq!(list.next_candidates(2, now), vec![v6, peer(1)]);
+
+        list.set_families(IpFamilies {
+            ipv4: false,
+            ..IpFamilies::default()
+        });
+        assert!(list.get(&peer(1)).is_none());
+        assert!(!list.insert(peer(2), PeerSource::Manual, PexFlags(0), now));
+        assert_eq!(list.len(), 1);
+    }
+
+    #[test]
+    fn test_filter() {
+        let now = Instant::now();
+        let mut list = PeerList::new();
+        for i in 0..3 {
+            list.insert(peer(i), PeerSource::Tracker, PexFlags(0), now);
+        }
+        list.on_connected(&peer(0), now);
+
+        let mut blocklist = Blocklist::new();
+        blocklist.insert(peer(0).ip(), peer(1).ip());
+        let filter = IpFilter::new();
+        filter.set_list(blocklist);
+        list.set_filter(filter.clone());
+        // The connection's left to whoever made it
+        assert!(list.get(&peer(0)).is_some());
+        assert!(list.get(&peer(1)).is_none());
+        assert_eq!(list.add_from_pex(&[(peer(1), PexFlags(0))], now), 0);
+        assert_eq!(filter.counts().discovered, 2);
+        assert!(list.insert(peer(2), PeerSource::Dht, PexFlags(0), now));
+    }
+
+    #[test]
+    fn test_private() {
+        let now = Instant::now();
+        let mut list = PeerList::new();
+        let sources = [
+            PeerSource::Pex,
+            PeerSource::Dht,
+            PeerSource::Lsd,
+            PeerSource::Tracker,
+            PeerSource::Manual,
+        ];
+        for (i, source) in sources.into_iter().enumerate() {
+            list.insert(peer(i as u16), source, PexFlags(0), now);
+        }
+        list.on_connected(&peer(0), now);
+
+        list.set_private(true);
+        let mut kept: Vec<_> = list.iter().map(|(addr, _)| *addr).collect();
+        kept.sort();
+        assert_eq!(kept, [peer(0), peer(3), peer(4)]);
+        assert!(!list.insert(peer(9), PeerSource::Dht, PexFlags(0), now));
+        assert_eq!(list.add_from_pex(&[(peer(10), PexFlags(0))], now), 0);
+        assert!(list.insert(peer(11), PeerSource::Incoming, PexFlags(0), now));
+
+        list.clear();
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn test_slots() {
+        let now = Instant::now();
+        let mut list = PeerList::new();
+        for i in 0..MAX_HALF_OPEN as u16 + 2 {
+            list.insert(peer(i), PeerSource::Tracker, PexFlags(0), now);
+        }
+        let dialing = list.next_candidates(usize::MAX, now);
+        assert_eq!(dialing.len(), MAX_HALF_OPEN);
+        for addr in &dialing {
+            list.on_connecting(addr);
+        }
+        assert!(list.next_candidates(usize::MAX, now).is_empty());
+
+        // One gets through, one never does, one goes away again
+        list.on_connected(&dialing[0], now);
+        list.on_disconnected(&dialing[1], now);
+        list.on_connected(&dialing[2], now);
+        list.on_disconnected(&dialing[2], now);
+        assert!(list.is_connected(&dialing[0]));
+        assert_eq!(list.get(&dialing[1]).unwrap().failures, 1);
+        assert_eq!(list.half_open(), MAX_HALF_OPEN - 3);
+        let next = list.next_candidates(usize::MAX, now);
+        assert_eq!(next.len(), 2);
+        assert!(!next.contains(&dialing[1]) && !next.contains(&dialing[2]));
+
+        // Each failure in a row doubles the wait
+        let retry_at = |list: &PeerList| list.get(&dialing[1]).unwrap().retry_at;
+        assert_eq!(retry_at(&list), Some(now + RECONNECT_DELAY));
+        list.on_connect_failed(&dialing[1], now);
+        assert_eq!(retry_at(&list), Some(now + RECONNECT_DELAY * 2));
+        assert_eq!(
+            list.get(&dialing[2]).unwrap().retry_at,
+            Some(now + RECONNECT_DELAY)
+        );
+    }
+
+    #[test]
+    fn test_bans() {
+        let now = Instant::now();
+        let mut list = PeerList::new();
+        let other_port = SocketAddr::new(peer(1).ip(), 51413);
+        list.insert(peer(1), PeerSource::Tracker, PexFlags(0), now);
+        list.insert(other_port, PeerSource::Tracker, PexFlags(0), now);
+        list.on_connected(&peer(1), now);
+
+        list.ban(peer(1), BanReason::ProtocolViolation, now);
+        assert!(list.is_empty());
+        assert!(!list.is_connected(&peer(1)));
+        assert!(!list.insert(other_port, PeerSource::Manual, PexFlags(0), now));
+        assert_eq!(list.num_banned(now), 1);
+
+        let over = now + BanReason::ProtocolViolation.duration();
+        list.decay(over);
+        assert_eq!(list.num_banned(over), 0);
+        assert!(list.insert(other_port, PeerSource::Manual, PexFlags(0), over));
+    }
+
+    #[test]
+    fn test_replacement() {
+        let now = Instant::now();
+        let mut list = PeerList::new();
+        list.insert(peer(1), PeerSource::Pex, PexFlags(0), now);
+        list.insert(peer(2), PeerSource::Pex, PexFlags(0), now);
+        list.on_connected(&peer(1), now);
+        list.on_connected(&peer(2), now);
+        list.on_downloaded(&peer(2), 50 << 20);
+        list.insert(peer(3), PeerSource::Dht, PexFlags(0), now);
+
+        // Not before they've had their chance, and not for a candidate that's no better
+        assert_eq!(list.replacement(now), None);
+        let later = now + REPLACE_GRACE;
+        assert_eq!(list.replacement(later), Some((peer(1), peer(3))));
+        list.on_disconnected(&peer(1), later);
+        assert_eq!(list.replacement(later), None);
+    }
+
+    #[test]
+    fn test_priority_breaks_ties() {
+        let now = Instant::now();
+        let mut list = PeerList::new();
+        for i in 0..20 {
+            list.insert(peer(i), PeerSource::Tracker, PexFlags(0), now);
+        }
+        assert!(list.iter().all(|(_, c)| c.priority == 0));
+
+        // Known afterwards, and only for its own family
+        let ours: SocketAddr = "203.0.113.7:6881".parse().unwrap();
+        list.set_our_addr(ours);
+        list.set_our_addr("[2001:db8::7]:6881".parse().unwrap());
+        assert_eq!(list.our_addrs().len(), 2);
+        list.set_max_half_open(20);
+        let priorities: Vec<u32> = list
+            .next_candidates(20, now)
+            .iter()
+            .map(|addr| list.get(addr).unwrap().priority)
+            .collect();
+        assert_eq!(priorities.len(), 20);
+        assert!(priorities.windows(2).all(|w| w[0] >= w[1]));
+        assert_eq!(
+            priorities[0],
+            peer_priority(ours, list.next_candidates(1, now)[0])
+        );
+
+        // A better source still comes first
+        list.insert(peer(20), PeerSource::Manual, PexFlags(0), now);
+        assert_eq!(list.next_candidates(1, now), [peer(20)]);
+
+        // Full up, the lowest priority of the lot makes room for a newcomer that outranks it
+        list.set_swarm_size(0, now);
+        for i in 21..MIN_CANDIDATES as u16 {
+            list.insert(peer(i), PeerSource::Tracker, PexFlags(0), now);
+        }
+        let lowest = (0..MIN_CANDIDATES as u16)
+            .map(peer)
+            .filter(|addr| *addr != peer(20))
+            .min_by_key(|addr| peer_priority(ours, *addr))
+            .unwrap();
+        let newcomer = (MIN_CANDIDATES as u16..)
+            .map(peer)
+            .find(|addr| peer_priority(ours, *addr) > peer_priority(ours, lowest))
+            .unwrap();
+        assert!(list.insert(newcomer, PeerSource::Tracker, PexFlags(0), now));
+        assert!(list.get(&lowest).is_none());
+    }
+}
@@ -0,0 +1,330 @@
+// The unchoke scheduler (tit-for-tat).
+// Every 10 seconds the interested peers are ranked and the best ones get our upload slots. While
+// leeching "best" means whoever is sending us the most, which rewards reciprocation. While seeding
+// there's nothing to reciprocate, so we favour peers that can take data from us the fastest.
+// One slot is reserved for an optimistic unchoke, rotated every 30 seconds, so new peers get a
+// chance to prove themselves and we can discover better partners.
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use crate::rng::Rng;
+
+#[derive(Debug, Clone)]
+pub struct ChokerConfig {
+    // Total upload slots, including the optimistic one
+    pub slots: usize,
+    pub interval: Duration,
+    pub optimistic_interval: Duration,
+}
+
+impl Default for ChokerConfig {
+    fn default() -> Self {
+        ChokerConfig {
+            slots: 4,
+            interval: Duration::from_secs(10),
+            optimistic_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+// What the choker needs to know about each connected peer
+#[derive(Debug, Clone)]
+pub struct ChokeCandidate<K> {
+    pub key: K,
+    pub interested: bool,
+    // Bytes per second the peer is sending us
+    pub download_rate: f64,
+    // Bytes per second we're sending the peer
+    pub upload_rate: f64,
+    // Snubbing peers only ever get the optimistic slot
+    pub snubbed: bool,
+}
+
+#[derive(PartialEq, Debug, Default)]
+pub struct ChokeDecision<K> {
+    pub unchoke: Vec<K>,
+    pub choke: Vec<K>,
+}
+
+#[derive(Debug)]
+pub struct Choker<K> {
+    config: ChokerConfig,
+    unchoked: HashSet<K>,
+    optimistic: Option<K>,
+    last_run: Option<Instant>,
+    last_optimistic: Option<Instant>,
+    rng: Rng,
+}
+
+impl<K: Eq + Hash + Clone> Choker<K> {
+    pub fn new(config: ChokerConfig) -> Self {
+        Choker::with_rng(config, Rng::new())
+    }
+
+    pub fn with_rng(config: ChokerConfig, rng: Rng) -> Self {
+        Choker {
+            config,
+            unchoked: HashSet::new(),
+            optimistic: None,
+            last_run: None,
+            last_optimistic: None,
+            rng,
+        }
+    }
+
+    pub fn is_unchoked(&self, key: &K) -> bool {
+        self.unchoked.contains(key)
+    }
+
+    pub fn optimistic(&self) -> Option<&K> {
+        self.optimistic.as_ref()
+    }
+
+    // Reruns the algorithm if the interval has passed
+    pub fn tick(
+        &mut self,
+        peers: &[ChokeCandidate<K>],
+        seeding: bool,
+        now: Instant,
+    ) -> Option<ChokeDecision<K>> {
+        let due = self
+            .last_run
+            .is_none_or(|last| now.saturating_duration_since(last) >= self.config.interval);
+
+        due.then(|| self.rechoke(peers, seeding, now))
+    }
+
+    // Runs the algorithm right away. Useful when an unchoked peer disconnects or loses interest
+    // and we don't want the slot sitting idle until the next tick
+    pub fn rechoke(
+        &mut self,
+        peers: &[ChokeCandidate<K>],
+        seeding: bool,
+        now: Instant,
+    ) -> ChokeDecision<K> {
+        self.last_run = Some(now);
+
+        let mut ranked: Vec<&ChokeCandidate<K>> = peers.iter().filter(|p| p.interested).collect();
+        ranked.sort_by(|a, b| {
+            let (a, b) = if seeding {
+                (a.upload_rate, b.upload_rate)
+            } else {
+                (a.download_rate, b.download_rate)
+            };
+            b.total_cmp(&a)
+        });
+
+        let regular_slots = self.config.slots.saturating_sub(1);
+        let mut unchoke: HashSet<K> = ranked
+            .iter()
+            .filter(|p| !p.snubbed)
+            .take(regular_slots)
+            .map(|p| p.key.clone())
+            .collect();
+
+        // Hang on to the current optimistic unchoke until it's time to rotate, as long as it's
+        // still around, still interested and hasn't earned a regular slot in the meantime
+        let rotate = self.last_optimistic.is_none_or(|last| {
+            now.saturating_duration_since(last) >= self.config.optimistic_interval
+        });
+        let keep = self.optimistic.as_ref().is_some_and(|opt| {
+            !rotate && !unchoke.contains(opt) && ranked.iter().any(|p| p.key == *opt)
+        });
+
+        if !keep {
+            let choked: Vec<&K> = ranked
+                .iter()
+                .map(|p| &p.key)
+                .filter(|k| !unchoke.contains(*k))
+                .collect();
+
+            self.optimistic = if choked.is_empty() {
+                None
+            } else {
+                Some(choked[self.rng.below(choked.len())].clone())
+            };
+            self.last_optimistic = Some(now);
+        }
+
+        if let Some(opt) = &self.optimistic {
+            unchoke.insert(opt.clone());
+        }
+
+        let decision = ChokeDecision {
+            unchoke: peers
+                .iter()
+                .map(|p| p.key.clone())
+                .filter(|k| unchoke.contains(k) && !self.unchoked.contains(k))
+                .collect(),
+            choke: peers
+                .iter()
+                .map(|p| p.key.clone())
+                .filter(|k| !unchoke.contains(k) && self.unchoked.contains(k))
+                .collect(),
+        };
+
+        self.unchoked = unchoke;
+        decision
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn peer(key: u32, interested: bool, down: f64, up: f64) -> ChokeCandidate<u32> {
+        ChokeCandidate {
+            key,
+            interested,
+            download_rate: down,
+            upload_rate: up,
+            snubbed: false,
+        }
+    }
+
+    fn choker() -> Choker<u32> {
+        Choker::with_rng(ChokerConfig::default(), Rng::with_seed(5))
+    }
+
+    #[test]
+    fn test_leeching_ranks_by_download() {
+        let mut choker = choker();
+        let peers: Vec<_> = (0..6).map(|i| peer(i, true, i as f64, 0.0)).collect();
+
+        choker.rechoke(&peers, false, Instant::now());
+
+        // Top three by download rate always make it, plus one optimistic
+        assert!(choker.is_unchoked(&5));
+        assert!(choker.is_unchoked(&4));
+        assert!(choker.is_unchoked(&3));
+        assert!(choker.optimistic().is_some_and(|o| *o < 3));
+        assert_eq!((0..6).filter(|i| choker.is_unchoked(i)).count(), 4);
+    }
+
+    #[test]
+    fn test_seeding_ranks_by_upload() {
+        let mut choker = choker();
+        let peers: Vec<_> = (0..6)
+            .map(|i| peer(i, true, i as f64, -(i as f64)))
+            .collect();
+
+        choker.rechoke(&peers, true, Instant::now());
+
+        assert!(choker.is_unchoked(&0));
+        assert!(choker.is_unchoked(&1));
+        assert!(choker.is_unchoked(&2));
+    }
+
+    #[test]
+    fn test_uninterested_stay_choked() {
+        let mut choker = choker();
+        let peers = vec![peer(0, false, 100.0, 0.0), peer(1, true, 1.0, 0.0)];
+
+        let decision = choker.rechoke(&peers, false, Instant::now());
+
+        assert_eq!(decision.unchoke, vec![1]);
+        assert!(!choker.is_unchoked(&0));
+    }
+
+    #[test]
+    fn test_snubbed_only_optimistic() {
+        let config = ChokerConfig {
+            slots: 2,
+            ..ChokerConfig::default()
+        };
+        let mut choker = Choker::with_rng(config, Rng::with_seed(5));
+        let mut snubber = peer(0, true, 100.0, 0.0);
+        snubber.snubbed = true;
+        let peers = vec![snubber, peer(1, true, 1.0, 0.0)];
+
+        choker.rechoke(&peers, false, Instant::now());
+
+        // The snubbing peer can't win the regular slot despite its rate, only the optimistic one
+        assert!(choker.is_unchoked(&1));
+        assert_eq!(choker.optimistic(), Some(&0));
+    }
+
+    #[test]
+    fn test_decision_is_a_diff() {
+        let now = Instant::now();
+        let mut choker = choker();
+        let mut peers = vec![peer(0, true, 10.0, 0.0), peer(1, true, 5.0, 0.0)];
+        choker.rechoke(&peers, false, now);
+
+        peers[0].interested = false;
+        let decision = choker.rechoke(&peers, false, now + Duration::from_secs(10));
+
+        assert_eq!(
+            decision,
+            ChokeDecision {
+                unchoke: vec![],
+                choke: vec![0]
+            }
+        );
+    }
+
+    #[test]
+    fn test_tick_interval() {
+        let now = Instant::now();
+        let mut choker = choker();
+        let peers = vec![peer(0, true, 10.0, 0.0)];
+
+        assert!(choker.tick(&peers, false, now).is_some());
+        assert!(
+            choker
+                .tick(&peers, false, now + Duration::from_secs(9))
+                .is_none()
+        );
+        assert!(
+            choker
+                .tick(&peers, false, now + Duration::from_secs(10))
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_optimistic_rotation() {
+        let now = Instant::now();
+        let config = ChokerConfig {
+            slots: 1,
+            ..ChokerConfig::default()
+        };
+        let mut choker = Choker::with_rng(config, Rng::with_seed(9));
+        let peers: Vec<_> = (0..50).map(|i| peer(i, true, 0.0, 0.0)).collect();
+
+        choker.rechoke(&peers, false, now);
+        let first = *choker.optimistic().unwrap();
+
+        // Held until the optimistic interval passes
+        choker.rechoke(&peers, false, now + Duration::from_secs(10));
+        choker.rechoke(&peers, false, now + Duration::from_secs(20));
+        assert_eq!(choker.optimistic(), Some(&first));
+
+        let mut rotated = false;
+        for i in 1..=10 {
+            choker.rechoke(&peers, false, now + Duration::from_secs(30 * i));
+            rotated |= choker.optimistic() != Some(&first);
+        }
+        assert!(rotated);
+    }
+
+    #[test]
+    fn test_optimistic_dropped_when_gone() {
+        let now = Instant::now();
+        let config = ChokerConfig {
+            slots: 1,
+            ..ChokerConfig::default()
+        };
+        let mut choker = Choker::with_rng(config, Rng::with_seed(9));
+        choker.rechoke(&[peer(0, true, 0.0, 0.0)], false, now);
+
+        choker.rechoke(
+            &[peer(1, true, 0.0, 0.0)],
+            false,
+            now + Duration::from_secs(10),
+        );
+
+        assert_eq!(choker.optimistic(), Some(&1));
+    }
+}
@@ -0,0 +1,323 @@
+// Who's on the other end, going by their peer ID. Nothing standardizes it, there are only the
+// conventions clients copied from each other:
+//   Azureus style: "-", a two letter client code, four version characters, "-": -qB4630-
+//   Shadow style: a one letter client code, up to five version characters padded with "-" and
+//     three more "-": S58B-----
+//   Mainline style: "M" and the version's numbers, each followed by "-": M4-3-6--
+// and a few clients that did something of their own. Ours are Azureus style, "-HU" and the crate
+// version (see `PEER_ID_PREFIX`).
+// The name shows in peer lists when the extension handshake doesn't give one. `Quirks` is what we
+// know about clients that need handling of their own, looked up from the name and version.
+use std::fmt;
+
+use crate::peer::BLOCK_SIZE;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Client {
+    pub name: String,
+    // As the client numbers its releases, e.g. "4.6.3" or "0.3.18"
+    pub version: Option<String>,
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{} {}", self.name, version),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+// What a client needs from us that others don't
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct Quirks {
+    // It closes connections over requests bigger than this
+    pub max_request: Option<u32>,
+}
+
+impl Client {
+    fn new(name: &str, version: Option<String>) -> Self {
+        Client {
+            name: name.to_string(),
+            version,
+        }
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        match self.name.as_str() {
+            // The original Python client drops requests over 16 KiB along with the connection
+            "Mainline" => Quirks {
+                max_request: Some(BLOCK_SIZE),
+            },
+            _ => Quirks::default(),
+        }
+    }
+}
+
+// Azureus style client codes, the ones seen in swarms today and a good part of the history
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("7T", "aTorrent"),
+    ("AG", "Ares"),
+    ("AZ", "Azureus"),
+    ("BC", "BitComet"),
+    ("BF", "Bitflu"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("BW", "BitWombat"),
+    ("CD", "Enhanced CTorrent"),
+    ("CT", "CTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("FW", "FrostWire"),
+    ("FX", "Freebox BitTorrent"),
+    ("HL", "Halite"),
+    ("HU", "Hurricane"),
+    ("KG", "KGet"),
+    ("KT", "KTorrent"),
+    ("LH", "LH-ABC"),
+    ("LP", "Lphant"),
+    ("LT", "libtorrent"),
+    ("lt", "rTorrent"),
+    ("LW", "LimeWire"),
+    ("MO", "MonoTorrent"),
+    ("PI", "PicoTorrent"),
+    ("qB", "qBittorrent"),
+    ("QD", "QQDownload"),
+    ("SD", "Thunder"),
+    ("SP", "BitSpirit"),
+    ("ST", "SymTorrent"),
+    ("SZ", "Shareaza"),
+    ("TL", "Tribler"),
+    ("TR", "Transmission"),
+    ("TT", "TuoTu"),
+    ("UM", "µTorrent Mac"),
+    ("UT", "µTorrent"),
+    ("UW", "µTorrent Web"),
+    ("VG", "Vagaa"),
+    ("WD", "WebTorrent Desktop"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+    ("ZT", "ZipTorrent"),
+];
+
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT BitTorrent"),
+];
+
+// One character per version number, past 9 in letters
+const VERSION_CHARS: &[u8; 64] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz.-";
+
+// Our prefix: "-HU" and the crate's major, minor and patch versions, then a 0 as most clients
+// leave their fourth character. None of them may go past 35
+pub const PEER_ID_PREFIX: &[u8; 8] = &[
+    b'-',
+    b'H',
+    b'U',
+    version_char(env!("CARGO_PKG_VERSION_MAJOR")),
+    version_char(env!("CARGO_PKG_VERSION_MINOR")),
+    version_char(env!("CARGO_PKG_VERSION_PATCH")),
+    b'0',
+    b'-',
+];
+
+const fn version_char(number: &str) -> u8 {
+    let digits = number.as_bytes();
+    let mut n = 0;
+    let mut i = 0;
+    while i < digits.len() {
+        n = n * 10 + (digits[i] - b'0') as usize;
+        i += 1;
+    }
+    assert!(n < 36, "version number too big for a peer ID");
+    VERSION_CHARS[n]
+}
+
+fn version_number(c: u8) -> Option<usize> {
+    VERSION_CHARS[..62].iter().position(|v| *v == c)
+}
+
+// None for IDs that follow no convention we know, which plenty of clients send
+pub fn identify(peer_id: &[u8; 20]) -> Option<Client> {
+    azureus(peer_id)
+        .or_else(|| shadow(peer_id))
+        .or_else(|| mainline(peer_id))
+        .or_else(|| oddball(peer_id))
+}
+
+fn azureus(id: &[u8; 20]) -> Option<Client> {
+    if id[0] != b'-' || id[7] != b'-' || !id[1..3].iter().all(u8::is_ascii_alphanumeric) {
+        return None;
+    }
+    let code = std::str::from_utf8(&id[1..3]).unwrap();
+    let numbers = id[3..7]
+        .iter()
+        .map(|c| version_number(*c))
+        .collect::<Option<Vec<_>>>()?;
+    let name = AZUREUS_CLIENTS
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map_or(code, |(_, name)| *name);
+    let version = match code {
+        // The fourth character is the release type, B for beta and S for stable
+        "UT" | "UM" | "UW" | "BT" => join(&numbers[..3]),
+        // 2.94 is -TR2940-
+        "TR" if (1..4).contains(&numbers[0]) => {
+            format!("{}.{}{}", numbers[0], numbers[1], numbers[2])
+        }
+        _ => match numbers[3] {
+            0 => join(&numbers[..3]),
+            _ => join(&numbers),
+        },
+    };
+    Some(Client::new(name, Some(version)))
+}
+
+fn shadow(id: &[u8; 20]) -> Option<Client> {
+    let name = SHADOW_CLIENTS.iter().find(|(c, _)| *c == id[0])?.1;
+    if &id[6..9] != b"---" {
+        return None;
+    }
+    let numbers: Vec<usize> = id[1..6]
+        .iter()
+        .take_while(|c| **c != b'-')
+        .map(|c| version_number(*c))
+        .collect::<Option<_>>()?;
+    if numbers.is_empty() || id[1 + numbers.len()..6].iter().any(|c| *c != b'-') {
+        return None;
+    }
+    Some(Client::new(name, Some(join(&numbers))))
+}
+
+fn mainline(id: &[u8; 20]) -> Option<Client> {
+    if id[0] != b'M' {
+        return None;
+    }
+    // Numbers each followed by a dash, up to eight bytes in all
+    let mut numbers = vec![];
+    let mut rest = &id[1..8];
+    while numbers.len() < 3 {
+        let len = rest.iter().take_while(|c| c.is_ascii_digit()).count();
+        if len == 0 || rest.get(len) != Some(&b'-') {
+            return None;
+        }
+        numbers.push(std::str::from_utf8(&rest[..len]).unwrap().parse().ok()?);
+        rest = &rest[len + 1..];
+    }
+    if !rest.iter().all(|c| *c == b'-') {
+        return None;
+    }
+    Some(Client::new("Mainline", Some(join(&numbers))))
+}
+
+fn oddball(id: &[u8; 20]) -> Option<Client> {
+    let digits = |range: std::ops::Range<usize>| id[range].iter().all(u8::is_ascii_digit);
+    if id.starts_with(b"exbc") {
+        // Two bytes of version, binary
+        let name = match &id[6..10] {
+            b"LORD" => "BitLord",
+            _ => "BitComet",
+        };
+        return Some(Client::new(name, Some(format!("{}.{:02}", id[4], id[5]))));
+    }
+    if id.starts_with(b"AZ2500BT") {
+        return Some(Client::new("BitTyrant", None));
+    }
+    if id.starts_with(b"XBT") && digits(3..6) {
+        let numbers: Vec<usize> = id[3..6].iter().map(|c| (c - b'0') as usize).collect();
+        return Some(Client::new("XBT Client", Some(join(&numbers))));
+    }
+    if id.starts_with(b"OP") && digits(2..6) {
+        let build = String::from_utf8_lossy(&id[2..6]).into_owned();
+        return Some(Client::new("Opera", Some(build)));
+    }
+    if id.starts_with(b"-ML") {
+        let end = id[3..].iter().position(|c| *c == b'-')? + 3;
+        let version = std::str::from_utf8(&id[3..end]).ok()?;
+        return Some(Client::new("MLDonkey", Some(version.to_string())));
+    }
+    if id.starts_with(b"\x00\x03BS") {
+        return Some(Client::new("BitSpirit", Some("3".to_string())));
+    }
+    None
+}
+
+fn join(numbers: &[usize]) -> String {
+    numbers
+        .iter()
+        .map(|n| n.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn id(prefix: &[u8]) -> [u8; 20] {
+        let mut id = *b"abcdefghijklmnopqrst";
+        id[..prefix.len()].copy_from_slice(prefix);
+        id
+    }
+
+    fn name(prefix: &[u8]) -> Option<String> {
+        identify(&id(prefix)).map(|client| client.to_string())
+    }
+
+    #[test]
+    fn test_identify() {
+        let cases: &[(&[u8], &str)] = &[
+            (b"-qB4630-", "qBittorrent 4.6.3"),
+            (b"-UT355S-", "µTorrent 3.5.5"),
+            (b"-TR2940-", "Transmission 2.94"),
+            (b"-TR4050-", "Transmission 4.0.5"),
+            (b"-LT1234-", "libtorrent 1.2.3.4"),
+            (b"-DE13F0-", "Deluge 1.3.15"),
+            (b"-XX1000-", "XX 1.0.0"),
+            (b"S58B-----", "Shadow 5.8.11"),
+            (b"T03I-----", "BitTornado 0.3.18"),
+            (b"M4-3-6--", "Mainline 4.3.6"),
+            (b"M4-20-8-", "Mainline 4.20.8"),
+            (b"exbc\x00\x3fLORD", "BitLord 0.63"),
+            (b"exbc\x01\x02", "BitComet 1.02"),
+            (b"XBT054d-", "XBT Client 0.5.4"),
+            (b"OP7100", "Opera 7100"),
+            (b"-ML2.7.2-", "MLDonkey 2.7.2"),
+        ];
+        for (prefix, expected) in cases {
+            assert_eq!(name(prefix).as_deref(), Some(*expected));
+        }
+
+        for prefix in [
+            &b"abcdefgh"[..],
+            b"-qB4630x",
+            b"-q!4630-",
+            b"S58B-x---",
+            b"M4-3-6-x",
+            b"M4--6---",
+        ] {
+            assert_eq!(name(prefix), None, "{:?}", String::from_utf8_lossy(prefix));
+        }
+        assert_eq!(identify(&[0; 20]), None);
+    }
+
+    #[test]
+    fn test_ours() {
+        let ours = identify(&id(PEER_ID_PREFIX)).unwrap();
+        assert_eq!(ours.name, "Hurricane");
+        assert_eq!(ours.version.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+        assert_eq!(ours.quirks(), Quirks::default());
+    }
+
+    #[test]
+    fn test_quirks() {
+        let mainline = identify(&id(b"M4-4-0--")).unwrap();
+        assert_eq!(mainline.quirks().max_request, Some(BLOCK_SIZE));
+    }
+}
@@ -0,0 +1,281 @@
+// tokio-util integration for the wire protocol (feature `tokio`). `PeerCodec` turns a socket into
+// a stream of handshake + messages, encrypting and decrypting underneath when MSE negotiated RC4.
+// `PeerWriter` drains a `SendQueue` into the socket no faster than it accepts data, so control
+// messages pushed while pieces are waiting still go out first.
+// For split sockets, give the read and write halves each a clone of the codec: one only ever
+// decrypts and the other only encrypts, so the two RC4 streams stay in step.
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use bytes::{Buf, BytesMut};
+use futures_sink::Sink;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedWrite};
+
+use super::handshake::{HANDSHAKE_LEN, Handshake, HandshakeError};
+use super::message::{Message, MessageError};
+use super::mse::{Cipher, Established};
+use super::send_queue::SendQueue;
+
+// Bytes the writer buffers before waiting for the socket. Small, so a control message pushed
+// now is at most this far behind on the wire
+pub const WRITE_BUFFER: usize = 32 * 1024;
+
+#[derive(Debug)]
+pub enum CodecError {
+    Io(io::Error),
+    Handshake(HandshakeError),
+    Message(MessageError),
+}
+
+impl From<io::Error> for CodecError {
+    fn from(e: io::Error) -> Self {
+        CodecError::Io(e)
+    }
+}
+
+impl From<HandshakeError> for CodecError {
+    fn from(e: HandshakeError) -> Self {
+        CodecError::Handshake(e)
+    }
+}
+
+impl From<MessageError> for CodecError {
+    fn from(e: MessageError) -> Self {
+        CodecError::Message(e)
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Frame {
+    Handshake(Handshake),
+    Message(Message),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PeerCodec {
+    cipher: Option<Cipher>,
+    // Length of the read buffer prefix that has already been decrypted
+    decrypted: usize,
+    got_handshake: bool,
+}
+
+impl PeerCodec {
+    // A plaintext connection. The first frame decoded is the other side's handshake
+    pub fn new() -> Self {
+        PeerCodec::default()
+    }
+
+    // A connection that went through MSE. `established.payload` is already decrypted and has to
+    // be at the front of the read buffer, which `framed` takes care of
+    pub fn from_mse(established: &Established) -> Self {
+        PeerCodec {
+            cipher: established.cipher.clone(),
+            decrypted: established.payload.len(),
+            got_handshake: false,
+        }
+    }
+
+    pub fn framed<T: AsyncRead + AsyncWrite>(io: T, established: Established) -> Framed<T, Self> {
+        let mut framed = Framed::new(io, PeerCodec::from_mse(&established));
+        framed
+            .read_buffer_mut()
+            .extend_from_slice(&established.payload);
+        framed
+    }
+
+    fn write(&mut self, buf: &mut [u8], dst: &mut BytesMut) {
+        if let Some(cipher) = &mut self.cipher {
+            cipher.encrypt(buf);
+        }
+        dst.extend_from_slice(buf);
+    }
+}
+
+impl Decoder for PeerCodec {
+    type Item = Frame;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, CodecError> {
+        if let Some(cipher) = &mut self.cipher {
+            cipher.decrypt(&mut src[self.decrypted..]);
+        }
+        self.decrypted = src.len();
+
+        let (frame, len) = if self.got_handshake {
+            match Message::decode(src)? {
+                Some((msg, len)) => (Frame::Message(msg), len),
+                None => return Ok(None),
+            }
+        } else {
+            match Handshake::decode(src)? {
+                Some(handshake) => {
+                    self.got_handshake = true;
+                    (Frame::Handshake(handshake), HANDSHAKE_LEN)
+                }
+                None => return Ok(None),
+            }
+        };
+        src.advance(len);
+        self.decrypted -= len;
+        Ok(Some(frame))
+    }
+}
+
+impl Encoder<Handshake> for PeerCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, handshake: Handshake, dst: &mut BytesMut) -> Result<(), CodecError> {
+        self.write(&mut handshake.encode(), dst);
+        Ok(())
+    }
+}
+
+impl Encoder<Message> for PeerCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> Result<(), CodecError> {
+        self.write(&mut msg.encode(), dst);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct PeerWriter<W> {
+    sink: FramedWrite<W, PeerCodec>,
+    queue: SendQueue,
+}
+
+impl<W: AsyncWrite + Unpin> PeerWriter<W> {
+    pub fn new(io: W, codec: PeerCodec) -> Self {
+        let mut sink = FramedWrite::new(io, codec);
+        sink.set_backpressure_boundary(WRITE_BUFFER);
+        PeerWriter {
+            sink,
+            queue: SendQueue::new(),
+        }
+    }
+
+    pub fn queue(&mut self) -> &mut SendQueue {
+        &mut self.queue
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.sink.get_ref()
+    }
+
+    // Writes queued messages until the queue is empty and everything is flushed. A message only
+    // leaves the queue once the sink has room for it, so messages pushed in between polls are
+    // still sent in priority order, and unsent pieces can still be cancelled
+    pub fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), CodecError>> {
+        let mut sink = Pin::new(&mut self.sink);
+        loop {
+            if self.queue.is_empty() {
+                return Sink::<Message>::poll_flush(sink.as_mut(), cx);
+            }
+            ready!(Sink::<Message>::poll_ready(sink.as_mut(), cx))?;
+            let msg = self.queue.pop().unwrap();
+            sink.as_mut().start_send(msg)?;
+        }
+    }
+
+    pub async fn send(&mut self) -> Result<(), CodecError> {
+        std::future::poll_fn(|cx| self.poll_send(cx)).await
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::infohash::InfoHash;
+    use crate::peer::handshake::Reserved;
+    use crate::peer::mse::{EncryptionPolicy, MseHandshake};
+    use crate::peer::{BLOCK_SIZE, Block};
+    use crate::rng::Rng;
+    use std::task::Waker;
+
+    fn handshake() -> Handshake {
+        Handshake::new(Reserved::default(), InfoHash([1; 20]), [2; 20])
+    }
+
+    #[test]
+    fn test_decode_fragmented() {
+        let mut wire = handshake().encode().to_vec();
+        wire.extend(Message::Have(5).encode());
+        wire.extend(Message::Unchoke.encode());
+        let mut codec = PeerCodec::new();
+        let mut src = BytesMut::new();
+        let mut frames = vec![];
+        for chunk in wire.chunks(7) {
+            src.extend_from_slice(chunk);
+            while let Some(frame) = codec.decode(&mut src).unwrap() {
+                frames.push(frame);
+            }
+        }
+
+        assert_eq!(
+            frames,
+            vec![
+                Frame::Handshake(handshake()),
+                Frame::Message(Message::Have(5)),
+                Frame::Message(Message::Unchoke),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encrypted() {
+        let info_hash = InfoHash([1; 20]);
+        let mut rng = Rng::with_seed(1);
+        let mut a = MseHandshake::outgoing(info_hash, vec![], EncryptionPolicy::Forced, &mut rng);
+        let mut b = MseHandshake::incoming(vec![info_hash], EncryptionPolicy::Forced, &mut rng);
+        let b_done = b.handle_input(&a.poll_transmit().unwrap()).unwrap();
+        assert!(b_done.is_none());
+        a.handle_input(&b.poll_transmit().unwrap()).unwrap();
+        let b_done = b
+            .handle_input(&a.poll_transmit().unwrap())
+            .unwrap()
+            .unwrap();
+        let a_done = a
+            .handle_input(&b.poll_transmit().unwrap())
+            .unwrap()
+            .unwrap();
+
+        let mut sender = PeerCodec::from_mse(&a_done);
+        let mut receiver = PeerCodec::from_mse(&b_done);
+        let mut wire = BytesMut::new();
+        sender.encode(handshake(), &mut wire).unwrap();
+        sender.encode(Message::Interested, &mut wire).unwrap();
+
+        assert_ne!(wire[..20], handshake().encode()[..20]);
+        assert_eq!(
+            receiver.decode(&mut wire).unwrap(),
+            Some(Frame::Handshake(handshake()))
+        );
+        assert_eq!(
+            receiver.decode(&mut wire).unwrap(),
+            Some(Frame::Message(Message::Interested))
+        );
+    }
+
+    #[test]
+    fn test_writer_priority() {
+        let mut writer = PeerWriter::new(vec![], PeerCodec::new());
+        let piece = Message::Piece {
+            piece: 0,
+            offset: 0,
+            data: vec![0; BLOCK_SIZE as usize],
+        };
+        writer.queue().push(piece.clone());
+        writer.queue().push(piece);
+        writer.queue().push(Message::Choke);
+        writer.queue().cancel_piece(Block::new(0, 0, BLOCK_SIZE));
+
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(matches!(writer.poll_send(&mut cx), Poll::Ready(Ok(()))));
+        let written = writer.get_ref();
+        assert_eq!(written[..5], Message::Choke.encode());
+        assert_eq!(written.len(), 5 + 13 + BLOCK_SIZE as usize);
+    }
+}
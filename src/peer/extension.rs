@@ -0,0 +1,197 @@
+// BEP 10 extension protocol handshake.
+// Right after the BitTorrent handshake, peers that set the extension bit exchange a bencoded dict
+// mapping extension names to the message IDs they want to *receive* them on. So when we send a
+// ut_pex message we use the peer's number for it, and they use ours when sending to us.
+use std::collections::BTreeMap;
+
+use bencode::{BencodeValue, DecodeError};
+
+// Extended message ID reserved for the handshake itself
+pub const HANDSHAKE_ID: u8 = 0;
+
+// Extensions we support and the IDs we want them sent to us on
+pub const UT_PEX: &str = "ut_pex";
+pub const UT_METADATA: &str = "ut_metadata";
+pub const UT_HOLEPUNCH: &str = "ut_holepunch";
+pub const EXTENSIONS: &[(&str, u8)] = &[(UT_PEX, 1), (UT_METADATA, 2), (UT_HOLEPUNCH, 3)];
+
+#[derive(PartialEq, Debug)]
+pub enum ExtensionError {
+    Decode(DecodeError),
+    NotADict,
+    InvalidField(&'static str),
+}
+
+impl From<DecodeError> for ExtensionError {
+    fn from(err: DecodeError) -> Self {
+        ExtensionError::Decode(err)
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct ExtensionHandshake {
+    // "m": extension name -> message ID. ID 0 means the extension is disabled
+    pub extensions: BTreeMap<String, u8>,
+    // "p": the port the peer listens on, handy for incoming connections
+    pub listen_port: Option<u16>,
+    // "v": client name and version
+    pub client: Option<String>,
+    // "yourip": our address as the peer sees it, 4 or 16 raw bytes
+    pub your_ip: Option<Vec<u8>>,
+    // "reqq": how many outstanding requests the peer will queue before dropping them
+    pub reqq: Option<u32>,
+    // "metadata_size": length of the info dict, for peers that can send it over ut_metadata
+    pub metadata_size: Option<u32>,
+}
+
+impl ExtensionHandshake {
+    // The handshake we send, advertising everything in `EXTENSIONS`. Private torrents (BEP 27)
+    // leave out ut_pex so peers know not to send it
+    pub fn ours(listen_port: Option<u16>, client: &str, private: bool) -> Self {
+        ExtensionHandshake {
+            extensions: EXTENSIONS
+                .iter()
+                .filter(|(name, _)| !(private && *name == UT_PEX))
+                .map(|(name, id)| (name.to_string(), *id))
+                .collect(),
+            listen_port,
+            client: Some(client.to_string()),
+            your_ip: None,
+            reqq: None,
+            metadata_size: None,
+        }
+    }
+
+    // The ID to use when sending the named extension to whoever sent this handshake
+    pub fn id_for(&self, name: &str) -> Option<u8> {
+        self.extensions.get(name).copied().filter(|id| *id != 0)
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<ExtensionHandshake, ExtensionError> {
+        let values = bencode::decode(payload)?;
+        let root = values.first().ok_or(ExtensionError::NotADict)?;
+        if root.as_dict().is_none() {
+            return Err(ExtensionError::NotADict);
+        }
+
+        let mut handshake = ExtensionHandshake::default();
+        if let Some(m) = root.get(b"m") {
+            let m = m.as_dict().ok_or(ExtensionError::InvalidField("m"))?;
+            for (name, id) in m {
+                // Unknown junk in here is common, so skip entries we can't make sense of
+                let Some(id) = id.as_int().and_then(|i| u8::try_from(i).ok()) else {
+                    continue;
+                };
+                handshake
+                    .extensions
+                    .insert(String::from_utf8_lossy(name).into_owned(), id);
+            }
+        }
+
+        handshake.listen_port = root
+            .get(b"p")
+            .and_then(|p| p.as_int())
+            .and_then(|p| u16::try_from(p).ok())
+            .filter(|p| *p != 0);
+        handshake.client = root
+            .get(b"v")
+            .and_then(|v| v.as_bytes())
+            .map(|v| String::from_utf8_lossy(v).into_owned());
+        handshake.your_ip = root
+            .get(b"yourip")
+            .and_then(|ip| ip.as_bytes())
+            .filter(|ip| ip.len() == 4 || ip.len() == 16)
+            .map(|ip| ip.to_vec());
+        handshake.reqq = root
+            .get(b"reqq")
+            .and_then(|r| r.as_int())
+            .and_then(|r| u32::try_from(r).ok());
+        handshake.metadata_size = root
+            .get(b"metadata_size")
+            .and_then(|size| size.as_int())
+            .and_then(|size| u32::try_from(size).ok())
+            .filter(|size| *size != 0);
+
+        Ok(handshake)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let m = self
+            .extensions
+            .iter()
+            .map(|(name, id)| (name.as_bytes().to_vec(), BencodeValue::Int(*id as i64)))
+            .collect();
+
+        let mut root = BTreeMap::new();
+        root.insert(b"m".to_vec(), BencodeValue::Dict(m));
+        if let Some(port) = self.listen_port {
+            root.insert(b"p".to_vec(), BencodeValue::Int(port as i64));
+        }
+        if let Some(client) = &self.client {
+            root.insert(
+                b"v".to_vec(),
+                BencodeValue::ByteStr(client.as_bytes().to_vec()),
+            );
+        }
+        if let Some(ip) = &self.your_ip {
+            root.insert(b"yourip".to_vec(), BencodeValue::ByteStr(ip.clone()));
+        }
+        if let Some(reqq) = self.reqq {
+            root.insert(b"reqq".to_vec(), BencodeValue::Int(reqq as i64));
+        }
+        if let Some(size) = self.metadata_size {
+            root.insert(b"metadata_size".to_vec(), BencodeValue::Int(size as i64));
+        }
+
+        bencode::encode(&BencodeValue::Dict(root))
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_handshake() {
+        let raw = b"d1:md11:LT_metadatai1e6:ut_pexi2ee1:pi6881e1:v13:\xc2\xb5Torrent 1.2e";
+        let handshake = ExtensionHandshake::decode(raw).unwrap();
+
+        assert_eq!(handshake.id_for("ut_pex"), Some(2));
+        assert_eq!(handshake.id_for("ut_metadata"), None);
+        assert_eq!(handshake.listen_port, Some(6881));
+        assert_eq!(handshake.client.as_deref(), Some("µTorrent 1.2"));
+    }
+
+    #[test]
+    fn test_disabled_extension() {
+        let handshake = ExtensionHandshake::decode(b"d1:md6:ut_pexi0eee").unwrap();
+
+        assert_eq!(handshake.id_for("ut_pex"), None);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut ours = ExtensionHandshake::ours(Some(51413), "Hurricane 0.1.0", false);
+        ours.your_ip = Some(vec![1, 2, 3, 4]);
+        ours.reqq = Some(250);
+        ours.metadata_size = Some(31337);
+
+        assert_eq!(ExtensionHandshake::decode(&ours.encode()), Ok(ours));
+    }
+
+    #[test]
+    fn test_private_leaves_out_pex() {
+        let ours = ExtensionHandshake::ours(None, "Hurricane 0.1.0", true);
+
+        assert_eq!(ours.id_for(UT_PEX), None);
+        assert_eq!(ours.id_for(UT_METADATA), Some(2));
+    }
+
+    #[test]
+    fn test_not_a_dict() {
+        assert_eq!(
+            ExtensionHandshake::decode(b"i1e"),
+            Err(ExtensionError::NotADict)
+        );
+    }
+}
@@ -0,0 +1,372 @@
+// BEP 6 fast extension.
+// Mostly about cutting round trips at startup: a seed can say "have all" instead of sending a
+// whole bitfield, brand new peers get a small set of pieces they may request even while choked,
+// and requests get an explicit reject instead of silently vanishing when we choke someone.
+use std::net::{IpAddr, Ipv4Addr};
+
+use sha1::{Digest, Sha1};
+
+use super::message::Message;
+use super::{Block, DEFAULT_MAX_SERVED_REQUEST};
+use crate::bitfield::Bitfield;
+use crate::infohash::InfoHash;
+
+// How many pieces we let a peer download from us before we ever unchoke it
+pub const ALLOWED_FAST_COUNT: usize = 10;
+
+// The canonical allowed fast set from BEP 6. Both sides can compute it, so it's the same no
+// matter who asks, which stops a peer from reconnecting over and over to get different pieces
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: &InfoHash, num_pieces: u32, k: usize) -> Vec<u32> {
+    let k = k.min(num_pieces as usize);
+    let mut set = Vec::with_capacity(k);
+
+    // Only the /24 counts, so one user can't farm pieces from a handful of adjacent addresses
+    let masked = u32::from(ip) & 0xffff_ff00;
+    let mut x = masked.to_be_bytes().to_vec();
+    x.extend_from_slice(&info_hash.0);
+
+    while set.len() < k {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if set.len() >= k {
+                break;
+            }
+
+            let y = u32::from_be_bytes(chunk.try_into().unwrap());
+            let index = y % num_pieces;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+
+    set
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum RequestAction {
+    Serve,
+    Reject,
+}
+
+// Fast extension bookkeeping for one connection where both sides negotiated it
+#[derive(Debug, Clone)]
+pub struct FastState {
+    // Pieces we allow this peer to request while we're choking it
+    granted: Vec<u32>,
+    // Pieces the peer allows us to request while it's choking us
+    allowed: Vec<u32>,
+    // Pieces the peer suggested we download, most recent last
+    suggested: Vec<u32>,
+    // Longest request we serve, anything bigger is rejected whether we're choking or not
+    max_request: u32,
+}
+
+impl FastState {
+    pub fn new(peer_ip: Ipv4Addr, info_hash: &InfoHash, num_pieces: u32) -> Self {
+        FastState {
+            granted: allowed_fast_set(peer_ip, info_hash, num_pieces, ALLOWED_FAST_COUNT),
+            allowed: vec![],
+            suggested: vec![],
+            max_request: DEFAULT_MAX_SERVED_REQUEST,
+        }
+    }
+
+    // BEP 6 only has an allowed fast set for IPv4 peers. IPv6 ones get none
+    pub fn for_addr(peer_ip: IpAddr, info_hash: &InfoHash, num_pieces: u32) -> Self {
+        match peer_ip {
+            IpAddr::V4(ip) => FastState::new(ip, info_hash, num_pieces),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => FastState::new(ip, info_hash, num_pieces),
+                None => FastState {
+                    granted: vec![],
+                    allowed: vec![],
+                    suggested: vec![],
+                    max_request: DEFAULT_MAX_SERVED_REQUEST,
+                },
+            },
+        }
+    }
+
+    pub fn set_max_request(&mut self, max_request: u32) {
+        self.max_request = max_request;
+    }
+
+    pub fn granted(&self) -> &[u32] {
+        &self.granted
+    }
+
+    pub fn suggested(&self) -> &[u32] {
+        &self.suggested
+    }
+
+    // What to send right after the handshake. Empty and complete bitfields get the one byte
+    // messages, and the peer learns which pieces it can get from us before being unchoked
+    pub fn handshake_messages(&self, have: &Bitfield) -> Vec<Message> {
+        let mut msgs = vec![if have.all() {
+            Message::HaveAll
+        } else if have.none() {
+            Message::HaveNone
+        } else {
+            Message::Bitfield(have.as_bytes().to_vec())
+        }];
+
+        // Allowed fast only matters to peers that can't already get everything from us. We don't
+        // know what the peer has yet, so grant pieces we have and let them ignore the rest
+        msgs.extend(
+            self.granted
+                .iter()
+                .filter(|p| have.get(**p as usize))
+                .map(|p| Message::AllowedFast(*p)),
+        );
+        msgs
+    }
+
+    // Feed incoming fast extension messages through here
+    pub fn on_message(&mut self, msg: &Message) {
+        match msg {
+            Message::AllowedFast(piece) if !self.allowed.contains(piece) => {
+                self.allowed.push(*piece);
+            }
+            Message::SuggestPiece(piece) => {
+                self.suggested.retain(|p| p != piece);
+                self.suggested.push(*piece);
+            }
+            _ => {}
+        }
+    }
+
+    // Stop listing a suggestion once we've started or finished the piece
+    pub fn clear_suggestion(&mut self, piece: u32) {
+        self.suggested.retain(|p| *p != piece);
+    }
+
+    pub fn can_request_while_choked(&self, piece: u32) -> bool {
+        self.allowed.contains(&piece)
+    }
+
+    // What to do with a request from the peer, for a block of a piece `piece_size` bytes long.
+    // With the fast extension we must never silently ignore one, so anything we won't serve gets
+    // an explicit reject. Blocks of any size up to the maximum are fine, 16 KiB is only what most
+    // clients happen to ask for
+    pub fn on_request(&self, block: &Block, piece_size: u32, choking: bool) -> RequestAction {
+        if !block.is_servable(piece_size, self.max_request) {
+            RequestAction::Reject
+        } else if !choking || self.granted.contains(&block.piece) {
+            RequestAction::Serve
+        } else {
+            RequestAction::Reject
+        }
+    }
+
+    // Choking a fast peer doesn't implicitly cancel its requests, so reject everything queued
+    // that isn't in its allowed fast set
+    pub fn on_choke<'a, I>(&self, queued: I) -> Vec<Message>
+    where
+        I: IntoIterator<Item = &'a Block>,
+    {
+        queued
+            .into_iter()
+            .filter(|b| !self.granted.contains(&b.piece))
+            .map(|b| Message::RejectRequest(*b))
+            .collect()
+    }
+
+    // Narrows `peer_has` down to what we may request while choked, for feeding into the picker
+    pub fn allowed_bitfield(&self, peer_has: &Bitfield) -> Bitfield {
+        restrict(peer_has, &self.allowed)
+    }
+
+    // Narrows `peer_has` down to the suggested pieces
+    pub fn suggested_bitfield(&self, peer_has: &Bitfield) -> Bitfield {
+        restrict(peer_has, &self.suggested)
+    }
+}
+
+// Turns a have-all/have-none into the bitfield it stands for
+pub fn bitfield_from(msg: &Message, num_pieces: usize) -> Option<Bitfield> {
+    match msg {
+        Message::HaveAll => Some(Bitfield::full(num_pieces)),
+        Message::HaveNone => Some(Bitfield::new(num_pieces)),
+        Message::Bitfield(bits) => Bitfield::from_bytes(bits, num_pieces),
+        _ => None,
+    }
+}
+
+fn restrict(peer_has: &Bitfield, pieces: &[u32]) -> Bitfield {
+    let mut out = Bitfield::new(peer_has.len());
+    for piece in pieces {
+        if peer_has.get(*piece as usize) {
+            out.set(*piece as usize);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    const PIECE: u32 = 256 * 1024;
+
+    #[test]
+    fn test_allowed_fast_set_bep6_vectors() {
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        let info_hash = InfoHash([0xaa; 20]);
+
+        assert_eq!(
+            allowed_fast_set(ip, &info_hash, 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(ip, &info_hash, 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+    }
+
+    #[test]
+    fn test_allowed_fast_set_small_torrent() {
+        let set = allowed_fast_set(Ipv4Addr::new(1, 2, 3, 4), &InfoHash([1; 20]), 3, 10);
+
+        let mut sorted = set.clone();
+        sorted.sort();
+        assert_eq!(sorted, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_allowed_fast_set_same_subnet() {
+        let info_hash = InfoHash([7; 20]);
+        let a = allowed_fast_set(Ipv4Addr::new(10, 0, 0, 1), &info_hash, 500, 10);
+        let b = allowed_fast_set(Ipv4Addr::new(10, 0, 0, 254), &info_hash, 500, 10);
+
+        assert_eq!(a, b);
+    }
+
+    fn state() -> FastState {
+        FastState::new(Ipv4Addr::new(80, 4, 4, 200), &InfoHash([0xaa; 20]), 1313)
+    }
+
+    #[test]
+    fn test_handshake_messages() {
+        let state = state();
+
+        let seed = state.handshake_messages(&Bitfield::full(1313));
+        assert_eq!(seed[0], Message::HaveAll);
+        assert_eq!(seed.len(), 1 + ALLOWED_FAST_COUNT);
+
+        let empty = state.handshake_messages(&Bitfield::new(1313));
+        assert_eq!(empty, vec![Message::HaveNone]);
+
+        let mut partial = Bitfield::new(1313);
+        partial.set(1059);
+        let msgs = state.handshake_messages(&partial);
+        assert_eq!(
+            msgs,
+            vec![
+                Message::Bitfield(partial.as_bytes().to_vec()),
+                Message::AllowedFast(1059)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_requests_while_choking() {
+        let state = state();
+
+        assert_eq!(
+            state.on_request(&Block::new(1059, 0, 16384), PIECE, true),
+            RequestAction::Serve
+        );
+        assert_eq!(
+            state.on_request(&Block::new(1, 0, 16384), PIECE, true),
+            RequestAction::Reject
+        );
+        assert_eq!(
+            state.on_request(&Block::new(1, 0, 16384), PIECE, false),
+            RequestAction::Serve
+        );
+    }
+
+    #[test]
+    fn test_request_sizes() {
+        let mut state = state();
+
+        assert_eq!(
+            state.on_request(&Block::new(1, 100, 5000), PIECE, false),
+            RequestAction::Serve
+        );
+        assert_eq!(
+            state.on_request(&Block::new(1, 0, 64 * 1024), PIECE, false),
+            RequestAction::Serve
+        );
+        assert_eq!(
+            state.on_request(&Block::new(1, 0, 0), PIECE, false),
+            RequestAction::Reject
+        );
+
+        // Past the end of the piece, or far enough to wrap around
+        assert_eq!(
+            state.on_request(&Block::new(1, PIECE - 100, 16384), PIECE, false),
+            RequestAction::Reject
+        );
+        assert_eq!(
+            state.on_request(&Block::new(1, u32::MAX - 10, 16384), PIECE, false),
+            RequestAction::Reject
+        );
+
+        state.set_max_request(32 * 1024);
+        assert_eq!(
+            state.on_request(&Block::new(1059, 0, 64 * 1024), PIECE, true),
+            RequestAction::Reject
+        );
+    }
+
+    #[test]
+    fn test_choke_rejects_queue() {
+        let state = state();
+        let queued = [Block::new(1, 0, 16384), Block::new(1059, 0, 16384)];
+
+        assert_eq!(
+            state.on_choke(&queued),
+            vec![Message::RejectRequest(Block::new(1, 0, 16384))]
+        );
+    }
+
+    #[test]
+    fn test_incoming_allowed_and_suggest() {
+        let mut state = state();
+        state.on_message(&Message::AllowedFast(4));
+        state.on_message(&Message::SuggestPiece(2));
+        state.on_message(&Message::SuggestPiece(3));
+        state.on_message(&Message::SuggestPiece(2));
+
+        assert!(state.can_request_while_choked(4));
+        assert!(!state.can_request_while_choked(5));
+        assert_eq!(state.suggested(), &[3, 2]);
+
+        let peer_has = Bitfield::full(10);
+        assert_eq!(
+            state
+                .allowed_bitfield(&peer_has)
+                .iter_ones()
+                .collect::<Vec<_>>(),
+            vec![4]
+        );
+
+        state.clear_suggestion(3);
+        assert_eq!(
+            state
+                .suggested_bitfield(&peer_has)
+                .iter_ones()
+                .collect::<Vec<_>>(),
+            vec![2]
+        );
+    }
+
+    #[test]
+    fn test_bitfield_from_have_all_none() {
+        assert!(bitfield_from(&Message::HaveAll, 10).unwrap().all());
+        assert!(bitfield_from(&Message::HaveNone, 10).unwrap().none());
+        assert_eq!(bitfield_from(&Message::Choke, 10), None);
+    }
+}
@@ -0,0 +1,174 @@
+// The 68 byte handshake that opens every peer connection:
+// <19>"BitTorrent protocol"<8 reserved bytes><20 byte info-hash><20 byte peer ID>
+use crate::infohash::InfoHash;
+use crate::peer::client_ident::PEER_ID_PREFIX;
+use crate::rng::Rng;
+
+pub const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+pub const HANDSHAKE_LEN: usize = 68;
+
+#[derive(PartialEq, Debug)]
+pub enum HandshakeError {
+    BadProtocol,
+}
+
+// Protocol extensions advertised through the reserved bytes
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Feature {
+    // BEP 5
+    Dht,
+    // BEP 6
+    Fast,
+    // BEP 10
+    Extended,
+    // BEP 52, which brings the hash request, hashes and hash reject messages
+    V2,
+}
+
+impl Feature {
+    // (byte, mask) of the bit for this feature
+    fn position(self) -> (usize, u8) {
+        match self {
+            Feature::Dht => (7, 0x01),
+            Feature::Fast => (7, 0x04),
+            Feature::Extended => (5, 0x10),
+            Feature::V2 => (7, 0x10),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct Reserved(pub [u8; 8]);
+
+impl Reserved {
+    pub fn with(mut self, feature: Feature) -> Self {
+        let (byte, mask) = feature.position();
+        self.0[byte] |= mask;
+        self
+    }
+
+    pub fn supports(&self, feature: Feature) -> bool {
+        let (byte, mask) = feature.position();
+        self.0[byte] & mask != 0
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Handshake {
+    pub reserved: Reserved,
+    pub info_hash: InfoHash,
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    pub fn new(reserved: Reserved, info_hash: InfoHash, peer_id: [u8; 20]) -> Self {
+        Handshake {
+            reserved,
+            info_hash,
+            peer_id,
+        }
+    }
+
+    // An extension is only in use if both sides advertised it
+    pub fn negotiated(&self, ours: &Reserved, feature: Feature) -> bool {
+        self.reserved.supports(feature) && ours.supports(feature)
+    }
+
+    pub fn encode(&self) -> [u8; HANDSHAKE_LEN] {
+        let mut buf = [0u8; HANDSHAKE_LEN];
+        buf[0] = PROTOCOL.len() as u8;
+        buf[1..20].copy_from_slice(PROTOCOL);
+        buf[20..28].copy_from_slice(&self.reserved.0);
+        buf[28..48].copy_from_slice(&self.info_hash.0);
+        buf[48..68].copy_from_slice(&self.peer_id);
+        buf
+    }
+
+    // None if the buffer doesn't hold the whole handshake yet
+    pub fn decode(buf: &[u8]) -> Result<Option<Handshake>, HandshakeError> {
+        if !buf.is_empty() && buf[0] as usize != PROTOCOL.len() {
+            return Err(HandshakeError::BadProtocol);
+        }
+        if buf.len() < HANDSHAKE_LEN {
+            return Ok(None);
+        }
+        if &buf[1..20] != PROTOCOL {
+            return Err(HandshakeError::BadProtocol);
+        }
+
+        Ok(Some(Handshake {
+            reserved: Reserved(buf[20..28].try_into().unwrap()),
+            info_hash: InfoHash::from_bytes(&buf[28..48]).unwrap(),
+            peer_id: buf[48..68].try_into().unwrap(),
+        }))
+    }
+}
+
+// A fresh peer ID: our client prefix and version, then random printable characters so it
+// survives trackers that log or echo it back as text
+pub fn generate_peer_id(rng: &mut Rng) -> [u8; 20] {
+    const CHARS: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    let mut peer_id = [0; 20];
+    peer_id[..8].copy_from_slice(PEER_ID_PREFIX);
+    for b in &mut peer_id[8..] {
+        *b = CHARS[rng.below(CHARS.len())];
+    }
+    peer_id
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake_roundtrip() {
+        let reserved = Reserved::default()
+            .with(Feature::Fast)
+            .with(Feature::Extended);
+        let handshake = Handshake::new(reserved, InfoHash([0xaa; 20]), *b"-HU0001-abcdefghijkl");
+        let buf = handshake.encode();
+
+        assert_eq!(&buf[..20], b"\x13BitTorrent protocol");
+        assert_eq!(&buf[20..28], &[0, 0, 0, 0, 0, 0x10, 0, 0x04]);
+        assert_eq!(Handshake::decode(&buf), Ok(Some(handshake)));
+    }
+
+    #[test]
+    fn test_handshake_partial() {
+        let handshake = Handshake::new(Reserved::default(), InfoHash([0; 20]), [0; 20]);
+
+        assert_eq!(Handshake::decode(&handshake.encode()[..40]), Ok(None));
+    }
+
+    #[test]
+    fn test_handshake_bad_protocol() {
+        let mut buf = Handshake::new(Reserved::default(), InfoHash([0; 20]), [0; 20]).encode();
+        buf[5] = b'X';
+
+        assert_eq!(Handshake::decode(&buf), Err(HandshakeError::BadProtocol));
+        assert_eq!(Handshake::decode(&[4]), Err(HandshakeError::BadProtocol));
+    }
+
+    #[test]
+    fn test_negotiated() {
+        let ours = Reserved::default().with(Feature::Fast);
+        let theirs = Handshake::new(
+            Reserved::default().with(Feature::Fast).with(Feature::Dht),
+            InfoHash([0; 20]),
+            [0; 20],
+        );
+
+        assert!(theirs.negotiated(&ours, Feature::Fast));
+        assert!(!theirs.negotiated(&ours, Feature::Dht));
+    }
+
+    #[test]
+    fn test_generate_peer_id() {
+        let mut rng = Rng::with_seed(1);
+        let peer_id = generate_peer_id(&mut rng);
+
+        assert_eq!(&peer_id[..8], PEER_ID_PREFIX);
+        assert!(peer_id.iter().all(|b| b.is_ascii_graphic()));
+        assert_ne!(generate_peer_id(&mut rng), peer_id);
+    }
+}
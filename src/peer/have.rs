@@ -0,0 +1,237 @@
+// Batches outgoing `have` announcements.
+// Sending a `have` to every peer the instant each piece verifies is a lot of tiny messages in a
+// big swarm. Instead they're collected and flushed about once a second (or sooner if a lot pile
+// up). With "smart have" enabled, peers that already have a piece don't get told about it; they
+// can't want it from us, so the message is pure overhead.
+use std::time::{Duration, Instant};
+
+use crate::bitfield::Bitfield;
+use crate::rng::Rng;
+
+#[derive(Debug, Clone)]
+pub struct HaveConfig {
+    pub interval: Duration,
+    // Flush early once this many pieces are waiting
+    pub max_batch: usize,
+    pub smart: bool,
+    // Lazy bitfield: when we're (nearly) complete, leave a few random pieces out of the initial
+    // bitfield and announce them with `have`s instead. Some ISPs throttle connections that open
+    // with a full seed bitfield, and a few old clients misbehave on them
+    pub lazy_bitfield: bool,
+    // Fraction of pieces we need before lazy mode kicks in
+    pub lazy_threshold: f64,
+    // How many pieces to leave out
+    pub lazy_withheld: usize,
+}
+
+impl Default for HaveConfig {
+    fn default() -> Self {
+        HaveConfig {
+            interval: Duration::from_secs(1),
+            max_batch: 32,
+            smart: true,
+            lazy_bitfield: false,
+            lazy_threshold: 0.9,
+            lazy_withheld: 8,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct HaveBroadcaster {
+    config: HaveConfig,
+    pending: Vec<u32>,
+    last_flush: Instant,
+}
+
+impl HaveBroadcaster {
+    pub fn new(config: HaveConfig, now: Instant) -> Self {
+        HaveBroadcaster {
+            config,
+            pending: Vec::new(),
+            last_flush: now,
+        }
+    }
+
+    pub fn push(&mut self, piece: u32) {
+        if !self.pending.contains(&piece) {
+            self.pending.push(piece);
+        }
+    }
+
+    // What to send a newly connected peer: the bitfield, then `have`s for each returned piece
+    pub fn initial_announcement(&self, have: &Bitfield, rng: &mut Rng) -> (Bitfield, Vec<u32>) {
+        let owned = have.count_ones();
+        let threshold = (have.len() as f64 * self.config.lazy_threshold).ceil() as usize;
+        if !self.config.lazy_bitfield || owned == 0 || owned < threshold {
+            return (have.clone(), vec![]);
+        }
+
+        let mut pieces: Vec<usize> = have.iter_ones().collect();
+        rng.shuffle(&mut pieces);
+        pieces.truncate(self.config.lazy_withheld);
+        pieces.sort_unstable();
+
+        let mut bitfield = have.clone();
+        for piece in &pieces {
+            bitfield.clear(*piece);
+        }
+        (bitfield, pieces.into_iter().map(|p| p as u32).collect())
+    }
+
+    pub fn pending(&self) -> &[u32] {
+        &self.pending
+    }
+
+    pub fn should_flush(&self, now: Instant) -> bool {
+        if self.pending.is_empty() {
+            return false;
+        }
+
+        self.pending.len() >= self.config.max_batch
+            || now.saturating_duration_since(self.last_flush) >= self.config.interval
+    }
+
+    // Drains the batch and works out which pieces each peer should be told about. `peers` pairs
+    // whatever the caller uses to identify a peer with that peer's current bitfield. Peers that
+    // end up with nothing to send are left out
+    pub fn flush<'a, K, I>(&mut self, peers: I, now: Instant) -> Vec<(K, Vec<u32>)>
+    where
+        I: IntoIterator<Item = (K, &'a Bitfield)>,
+    {
+        self.last_flush = now;
+        let pending = std::mem::take(&mut self.pending);
+        if pending.is_empty() {
+            return vec![];
+        }
+
+        peers
+            .into_iter()
+            .filter_map(|(key, has)| {
+                let haves: Vec<u32> = pending
+                    .iter()
+                    .copied()
+                    .filter(|p| !self.config.smart || !has.get(*p as usize))
+                    .collect();
+                (!haves.is_empty()).then_some((key, haves))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_flush_after_interval() {
+        let now = Instant::now();
+        let mut haves = HaveBroadcaster::new(HaveConfig::default(), now);
+        haves.push(1);
+
+        assert!(!haves.should_flush(now + Duration::from_millis(500)));
+        assert!(haves.should_flush(now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_flush_when_batch_full() {
+        let now = Instant::now();
+        let config = HaveConfig {
+            max_batch: 2,
+            ..HaveConfig::default()
+        };
+        let mut haves = HaveBroadcaster::new(config, now);
+        haves.push(1);
+        haves.push(1);
+        assert!(!haves.should_flush(now));
+
+        haves.push(2);
+        assert!(haves.should_flush(now));
+    }
+
+    #[test]
+    fn test_nothing_to_flush() {
+        let now = Instant::now();
+        let mut haves = HaveBroadcaster::new(HaveConfig::default(), now);
+
+        assert!(!haves.should_flush(now + Duration::from_secs(5)));
+        assert!(haves.flush([(0, &Bitfield::new(4))], now).is_empty());
+    }
+
+    #[test]
+    fn test_smart_have_suppression() {
+        let now = Instant::now();
+        let mut haves = HaveBroadcaster::new(HaveConfig::default(), now);
+        haves.push(0);
+        haves.push(2);
+
+        let mut partial = Bitfield::new(4);
+        partial.set(0);
+        let seed = Bitfield::full(4);
+        let empty = Bitfield::new(4);
+
+        let out = haves.flush([(1, &partial), (2, &seed), (3, &empty)], now);
+
+        assert_eq!(out, vec![(1, vec![2]), (3, vec![0, 2])]);
+        assert!(haves.pending().is_empty());
+    }
+
+    fn lazy() -> HaveBroadcaster {
+        let config = HaveConfig {
+            lazy_bitfield: true,
+            lazy_withheld: 3,
+            ..HaveConfig::default()
+        };
+        HaveBroadcaster::new(config, Instant::now())
+    }
+
+    #[test]
+    fn test_lazy_bitfield_withholds_pieces() {
+        let have = Bitfield::full(20);
+
+        let (bitfield, haves) = lazy().initial_announcement(&have, &mut Rng::with_seed(4));
+
+        assert_eq!(haves.len(), 3);
+        assert_eq!(bitfield.count_ones(), 17);
+        for piece in haves {
+            assert!(!bitfield.get(piece as usize));
+        }
+    }
+
+    #[test]
+    fn test_lazy_bitfield_below_threshold() {
+        let mut have = Bitfield::new(20);
+        have.set(0);
+
+        let (bitfield, haves) = lazy().initial_announcement(&have, &mut Rng::with_seed(4));
+
+        assert_eq!(bitfield, have);
+        assert!(haves.is_empty());
+    }
+
+    #[test]
+    fn test_lazy_bitfield_disabled() {
+        let haves = HaveBroadcaster::new(HaveConfig::default(), Instant::now());
+        let have = Bitfield::full(20);
+
+        let (bitfield, extra) = haves.initial_announcement(&have, &mut Rng::with_seed(4));
+
+        assert_eq!(bitfield, have);
+        assert!(extra.is_empty());
+    }
+
+    #[test]
+    fn test_redundant_haves_when_not_smart() {
+        let now = Instant::now();
+        let config = HaveConfig {
+            smart: false,
+            ..HaveConfig::default()
+        };
+        let mut haves = HaveBroadcaster::new(config, now);
+        haves.push(0);
+
+        let out = haves.flush([("seed", &Bitfield::full(4))], now);
+
+        assert_eq!(out, vec![("seed", vec![0])]);
+    }
+}
@@ -0,0 +1,310 @@
+// NAT holepunching (ut_holepunch, BEP 55). Two peers that can't accept connections both talk to
+// a relay peer connected to each. The initiator sends the relay a rendezvous naming the target;
+// the relay sends each side a connect with the other's address, and both connect at once so
+// their NATs each see an outgoing connection and let the other's packets through.
+// Relays are best picked among peers that told us about the target over PEX with the holepunch
+// flag set, since they're known to be connected to it.
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+// How long we wait for the relay to come back with a connect
+pub const RENDEZVOUS_TIMEOUT: Duration = Duration::from_secs(30);
+
+const MSG_RENDEZVOUS: u8 = 0;
+const MSG_CONNECT: u8 = 1;
+const MSG_ERROR: u8 = 2;
+
+#[derive(PartialEq, Debug)]
+pub enum HolepunchError {
+    TooShort,
+    UnknownType(u8),
+    UnknownAddrType(u8),
+    UnknownErrorCode(u32),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ErrorCode {
+    // The target address is invalid
+    NoSuchPeer,
+    // The relay isn't connected to the target
+    NotConnected,
+    // The target doesn't do holepunching
+    NoSupport,
+    // The target is the relay itself
+    NoSelf,
+}
+
+impl ErrorCode {
+    fn to_u32(self) -> u32 {
+        match self {
+            ErrorCode::NoSuchPeer => 1,
+            ErrorCode::NotConnected => 2,
+            ErrorCode::NoSupport => 3,
+            ErrorCode::NoSelf => 4,
+        }
+    }
+
+    fn from_u32(code: u32) -> Option<Self> {
+        match code {
+            1 => Some(ErrorCode::NoSuchPeer),
+            2 => Some(ErrorCode::NotConnected),
+            3 => Some(ErrorCode::NoSupport),
+            4 => Some(ErrorCode::NoSelf),
+            _ => None,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum HolepunchMessage {
+    // To a relay: please introduce us to this peer
+    Rendezvous(SocketAddr),
+    // From a relay: connect to this peer now, it's connecting to us
+    Connect(SocketAddr),
+    // From a relay: the rendezvous for this peer didn't work out
+    Error(SocketAddr, ErrorCode),
+}
+
+impl HolepunchMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let (msg_type, addr, code) = match self {
+            HolepunchMessage::Rendezvous(addr) => (MSG_RENDEZVOUS, addr, 0),
+            HolepunchMessage::Connect(addr) => (MSG_CONNECT, addr, 0),
+            HolepunchMessage::Error(addr, code) => (MSG_ERROR, addr, code.to_u32()),
+        };
+        let mut buf = vec![msg_type];
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                buf.push(0);
+                buf.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                buf.push(1);
+                buf.extend_from_slice(&ip.octets());
+            }
+        }
+        buf.extend_from_slice(&addr.port().to_be_bytes());
+        buf.extend_from_slice(&code.to_be_bytes());
+        buf
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<HolepunchMessage, HolepunchError> {
+        if payload.len() < 2 {
+            return Err(HolepunchError::TooShort);
+        }
+        let ip_len = match payload[1] {
+            0 => 4,
+            1 => 16,
+            other => return Err(HolepunchError::UnknownAddrType(other)),
+        };
+        if payload.len() < 2 + ip_len + 6 {
+            return Err(HolepunchError::TooShort);
+        }
+        let ip_bytes = &payload[2..2 + ip_len];
+        let ip = match ip_len {
+            4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(ip_bytes).unwrap())),
+            _ => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip_bytes).unwrap())),
+        };
+        let rest = &payload[2 + ip_len..];
+        let addr = SocketAddr::new(ip, u16::from_be_bytes([rest[0], rest[1]]));
+        let code = u32::from_be_bytes(rest[2..6].try_into().unwrap());
+
+        match payload[0] {
+            MSG_RENDEZVOUS => Ok(HolepunchMessage::Rendezvous(addr)),
+            MSG_CONNECT => Ok(HolepunchMessage::Connect(addr)),
+            MSG_ERROR => ErrorCode::from_u32(code)
+                .map(|code| HolepunchMessage::Error(addr, code))
+                .ok_or(HolepunchError::UnknownErrorCode(code)),
+            other => Err(HolepunchError::UnknownType(other)),
+        }
+    }
+}
+
+// The relay's side: what to send, and to whom, for a rendezvous from `from` naming `target`.
+// `check` says whether we can introduce the target: Ok if we're connected to it and it
+// advertised ut_holepunch
+pub fn relay(
+    from: SocketAddr,
+    target: SocketAddr,
+    check: impl Fn(SocketAddr) -> Result<(), ErrorCode>,
+) -> Vec<(SocketAddr, HolepunchMessage)> {
+    let checked = if target == from {
+        Err(ErrorCode::NoSuchPeer)
+    } else {
+        check(target)
+    };
+    match checked {
+        Ok(()) => vec![
+            (from, HolepunchMessage::Connect(target)),
+            (target, HolepunchMessage::Connect(from)),
+        ],
+        Err(code) => vec![(from, HolepunchMessage::Error(target, code))],
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum HolepunchEvent {
+    // Connect to this peer right away. Either our rendezvous worked or someone else's named us
+    Connect(SocketAddr),
+    // The relay couldn't introduce us. Try another relay, or give up on the peer
+    Failed(SocketAddr, ErrorCode),
+}
+
+// Our rendezvous requests in flight, one per target
+#[derive(Debug, Default)]
+pub struct Holepunches {
+    // Target -> (relay, when we asked)
+    pending: HashMap<SocketAddr, (SocketAddr, Instant)>,
+}
+
+impl Holepunches {
+    pub fn new() -> Self {
+        Holepunches::default()
+    }
+
+    pub fn is_pending(&self, target: &SocketAddr) -> bool {
+        self.pending.contains_key(target)
+    }
+
+    // The rendezvous to send to `relay`, or None if we're already trying to reach the target
+    pub fn rendezvous(
+        &mut self,
+        target: SocketAddr,
+        relay: SocketAddr,
+        now: Instant,
+    ) -> Option<HolepunchMessage> {
+        if self.pending.contains_key(&target) {
+            return None;
+        }
+        self.pending.insert(target, (relay, now));
+        Some(HolepunchMessage::Rendezvous(target))
+    }
+
+    // A holepunch message from the peer at `from`. Rendezvous requests are for `relay` instead
+    pub fn handle(
+        &mut self,
+        from: SocketAddr,
+        message: HolepunchMessage,
+    ) -> Option<HolepunchEvent> {
+        match message {
+            HolepunchMessage::Rendezvous(_) => None,
+            HolepunchMessage::Connect(addr) => {
+                self.pending.remove(&addr);
+                Some(HolepunchEvent::Connect(addr))
+            }
+            HolepunchMessage::Error(addr, code) => {
+                // Only the relay we asked gets to fail our attempt
+                if self
+                    .pending
+                    .get(&addr)
+                    .is_some_and(|(relay, _)| *relay == from)
+                {
+                    self.pending.remove(&addr);
+                    Some(HolepunchEvent::Failed(addr, code))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    // Targets whose relay never answered
+    pub fn expire(&mut self, now: Instant) -> Vec<SocketAddr> {
+        let expired: Vec<SocketAddr> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, asked))| now.saturating_duration_since(*asked) >= RENDEZVOUS_TIMEOUT)
+            .map(|(target, _)| *target)
+            .collect();
+        for target in &expired {
+            self.pending.remove(target);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_message_roundtrip() {
+        let rendezvous = HolepunchMessage::Rendezvous(addr("1.2.3.4:6881"));
+        let error = HolepunchMessage::Error(addr("[2001:db8::1]:80"), ErrorCode::NoSupport);
+
+        assert_eq!(
+            rendezvous.encode(),
+            [0, 0, 1, 2, 3, 4, 0x1a, 0xe1, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            HolepunchMessage::decode(&rendezvous.encode()),
+            Ok(rendezvous)
+        );
+        assert_eq!(error.encode().len(), 24);
+        assert_eq!(HolepunchMessage::decode(&error.encode()), Ok(error));
+        assert_eq!(
+            HolepunchMessage::decode(&[1, 2, 0]),
+            Err(HolepunchError::UnknownAddrType(2))
+        );
+    }
+
+    #[test]
+    fn test_relay() {
+        let a = addr("1.1.1.1:1");
+        let b = addr("2.2.2.2:2");
+        let c = addr("3.3.3.3:3");
+        let check = |target| {
+            if target == b {
+                Ok(())
+            } else {
+                Err(ErrorCode::NotConnected)
+            }
+        };
+
+        assert_eq!(
+            relay(a, b, check),
+            vec![
+                (a, HolepunchMessage::Connect(b)),
+                (b, HolepunchMessage::Connect(a))
+            ]
+        );
+        assert_eq!(
+            relay(a, c, check),
+            vec![(a, HolepunchMessage::Error(c, ErrorCode::NotConnected))]
+        );
+        assert_eq!(
+            relay(a, a, check),
+            vec![(a, HolepunchMessage::Error(a, ErrorCode::NoSuchPeer))]
+        );
+    }
+
+    #[test]
+    fn test_initiator() {
+        let now = Instant::now();
+        let relay = addr("1.1.1.1:1");
+        let target = addr("2.2.2.2:2");
+        let mut holepunches = Holepunches::new();
+
+        assert!(holepunches.rendezvous(target, relay, now).is_some());
+        assert!(holepunches.rendezvous(target, relay, now).is_none());
+        let error = HolepunchMessage::Error(target, ErrorCode::NoSupport);
+        assert_eq!(holepunches.handle(addr("9.9.9.9:9"), error), None);
+        assert_eq!(
+            holepunches.handle(relay, error),
+            Some(HolepunchEvent::Failed(target, ErrorCode::NoSupport))
+        );
+
+        holepunches.rendezvous(target, relay, now);
+        assert_eq!(
+            holepunches.handle(relay, HolepunchMessage::Connect(target)),
+            Some(HolepunchEvent::Connect(target))
+        );
+        holepunches.rendezvous(target, relay, now);
+        assert_eq!(holepunches.expire(now + RENDEZVOUS_TIMEOUT), vec![target]);
+    }
+}
@@ -0,0 +1,199 @@
+// Where incoming peer connections arrive. We listen on the same port over IPv4 and IPv6, each on
+// its own socket (the IPv6 one v6-only, so the two don't fight over the port), and carry on with
+// whichever binds: plenty of hosts have no IPv6 at all and a few have no IPv4
+use std::io;
+use std::net::{
+    Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpListener, TcpStream,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+// Pending connections the kernel queues for us before `accept`
+const BACKLOG: i32 = 128;
+
+// Which address families to use for peers, and which one to try first when a peer is reachable
+// over both
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct IpFamilies {
+    pub ipv4: bool,
+    pub ipv6: bool,
+    pub prefer_ipv6: bool,
+}
+
+impl Default for IpFamilies {
+    fn default() -> Self {
+        IpFamilies {
+            ipv4: true,
+            ipv6: true,
+            prefer_ipv6: false,
+        }
+    }
+}
+
+impl IpFamilies {
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        match addr {
+            SocketAddr::V4(_) => self.ipv4,
+            SocketAddr::V6(_) => self.ipv6,
+        }
+    }
+
+    pub fn prefers(&self, addr: &SocketAddr) -> bool {
+        addr.is_ipv6() == self.prefer_ipv6
+    }
+}
+
+#[derive(Debug)]
+pub struct Listeners {
+    v4: Option<TcpListener>,
+    v6: Option<TcpListener>,
+}
+
+impl Listeners {
+    // Port 0 picks a free port over IPv4 and then uses the same one for IPv6. Only fails if
+    // neither family could be bound
+    pub fn bind(port: u16, families: IpFamilies) -> io::Result<Self> {
+        let mut error = io::Error::new(io::ErrorKind::InvalidInput, "no address family enabled");
+        let mut port = port;
+
+        let v4 = if families.ipv4 {
+            match listen(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into()) {
+                Ok(listener) => {
+                    port = listener.local_addr()?.port();
+                    Some(listener)
+                }
+                Err(e) => {
+                    error = e;
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let v6 = if families.ipv6 {
+            match listen(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0).into()) {
+                Ok(listener) => Some(listener),
+                Err(e) => {
+                    error = e;
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        if v4.is_none() && v6.is_none() {
+            return Err(error);
+        }
+        Ok(Listeners { v4, v6 })
+    }
+
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.v4
+            .iter()
+            .chain(&self.v6)
+            .filter_map(|listener| listener.local_addr().ok())
+            .collect()
+    }
+
+    pub fn has_ipv4(&self) -> bool {
+        self.v4.is_some()
+    }
+
+    pub fn has_ipv6(&self) -> bool {
+        self.v6.is_some()
+    }
+
+    // Never blocks: the next waiting connection from either family, if any
+    pub fn accept(&self) -> io::Result<Option<(TcpStream, SocketAddr)>> {
+        for listener in self.v4.iter().chain(&self.v6) {
+            match listener.accept() {
+                Ok((stream, addr)) => {
+                    stream.set_nonblocking(false)?;
+                    return Ok(Some((stream, addr)));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn listen(addr: SocketAddr) -> io::Result<TcpListener> {
+    let domain = Domain::for_address(addr);
+    let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if domain == Domain::IPV6 {
+        socket.set_only_v6(true)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn accept(listeners: &Listeners) -> SocketAddr {
+        for _ in 0..100 {
+            if let Some((_, addr)) = listeners.accept().unwrap() {
+                return addr;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        panic!("no connection");
+    }
+
+    #[test]
+    fn test_families() {
+        let families = IpFamilies {
+            ipv4: false,
+            ..IpFamilies::default()
+        };
+        let v4: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+
+        assert!(!families.allows(&v4));
+        assert!(families.allows(&v6));
+        assert!(IpFamilies::default().prefers(&v4));
+        assert!(!IpFamilies::default().prefers(&v6));
+    }
+
+    #[test]
+    fn test_accepts_both_families() {
+        let listeners = Listeners::bind(0, IpFamilies::default()).unwrap();
+        let addrs = listeners.local_addrs();
+        assert_eq!(addrs.len(), 2);
+        assert_eq!(addrs[0].port(), addrs[1].port());
+        let port = addrs[0].port();
+
+        let _v4 = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        assert!(accept(&listeners).is_ipv4());
+        let _v6 = TcpStream::connect((Ipv6Addr::LOCALHOST, port)).unwrap();
+        assert!(accept(&listeners).is_ipv6());
+        assert!(listeners.accept().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_single_family() {
+        let families = IpFamilies {
+            ipv6: false,
+            ..IpFamilies::default()
+        };
+        let listeners = Listeners::bind(0, families).unwrap();
+
+        assert!(listeners.has_ipv4());
+        assert!(!listeners.has_ipv6());
+
+        let none = IpFamilies {
+            ipv4: false,
+            ipv6: false,
+            prefer_ipv6: false,
+        };
+        assert!(Listeners::bind(0, none).is_err());
+    }
+}
@@ -0,0 +1,398 @@
+// Peer wire protocol messages (BEP 3, plus the BEP 6 fast extension, BEP 10 framing and the
+// BEP 52 hash messages). Every message after the handshake is a 4 byte big-endian length, a 1
+// byte ID and a payload. A length of zero is a keep-alive.
+use super::Block;
+use crate::metainfo::HashRequest;
+
+// Anything bigger than this is either broken or hostile. A piece message with a 16 KiB block is
+// only 16397 bytes, but bitfields for huge torrents can get fairly large
+pub const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+#[derive(PartialEq, Debug)]
+pub enum MessageError {
+    UnknownId(u8),
+    InvalidLength(u8, usize),
+    TooLarge(usize),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request(Block),
+    Piece {
+        piece: u32,
+        offset: u32,
+        data: Vec<u8>,
+    },
+    Cancel(Block),
+    // DHT port, BEP 5
+    Port(u16),
+    // BEP 6
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest(Block),
+    AllowedFast(u32),
+    // BEP 10. `id` 0 is the extension handshake, anything else is whatever the receiver mapped
+    // that number to in its handshake
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    // BEP 52. `hashes` is the requested base layer hashes followed by the proof
+    HashRequest(HashRequest),
+    Hashes {
+        request: HashRequest,
+        hashes: Vec<[u8; 32]>,
+    },
+    HashReject(HashRequest),
+}
+
+impl Message {
+    pub fn id(&self) -> Option<u8> {
+        let id = match self {
+            Message::KeepAlive => return None,
+            Message::Choke => 0,
+            Message::Unchoke => 1,
+            Message::Interested => 2,
+            Message::NotInterested => 3,
+            Message::Have(_) => 4,
+            Message::Bitfield(_) => 5,
+            Message::Request(_) => 6,
+            Message::Piece { .. } => 7,
+            Message::Cancel(_) => 8,
+            Message::Port(_) => 9,
+            Message::SuggestPiece(_) => 0x0d,
+            Message::HaveAll => 0x0e,
+            Message::HaveNone => 0x0f,
+            Message::RejectRequest(_) => 0x10,
+            Message::AllowedFast(_) => 0x11,
+            Message::Extended { .. } => 20,
+            Message::HashRequest(_) => 21,
+            Message::Hashes { .. } => 22,
+            Message::HashReject(_) => 23,
+        };
+        Some(id)
+    }
+
+    // Whether this message only makes sense if both sides negotiated the fast extension
+    pub fn is_fast(&self) -> bool {
+        matches!(
+            self,
+            Message::SuggestPiece(_)
+                | Message::HaveAll
+                | Message::HaveNone
+                | Message::RejectRequest(_)
+                | Message::AllowedFast(_)
+        )
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        let Some(id) = self.id() else {
+            buf.extend_from_slice(&0u32.to_be_bytes());
+            return;
+        };
+
+        let mut payload = Vec::new();
+        match self {
+            Message::Have(piece) | Message::SuggestPiece(piece) | Message::AllowedFast(piece) => {
+                payload.extend_from_slice(&piece.to_be_bytes());
+            }
+            Message::Bitfield(bits) => payload.extend_from_slice(bits),
+            Message::Request(block) | Message::Cancel(block) | Message::RejectRequest(block) => {
+                payload.extend_from_slice(&block.piece.to_be_bytes());
+                payload.extend_from_slice(&block.offset.to_be_bytes());
+                payload.extend_from_slice(&block.length.to_be_bytes());
+            }
+            Message::Piece {
+                piece,
+                offset,
+                data,
+            } => {
+                payload.extend_from_slice(&piece.to_be_bytes());
+                payload.extend_from_slice(&offset.to_be_bytes());
+                payload.extend_from_slice(data);
+            }
+            Message::Port(port) => payload.extend_from_slice(&port.to_be_bytes()),
+            Message::Extended { id, payload: data } => {
+                payload.push(*id);
+                payload.extend_from_slice(data);
+            }
+            Message::HashRequest(request) | Message::HashReject(request) => {
+                write_hash_request(&mut payload, request);
+            }
+            Message::Hashes { request, hashes } => {
+                write_hash_request(&mut payload, request);
+                for hash in hashes {
+                    payload.extend_from_slice(hash);
+                }
+            }
+            _ => {}
+        }
+
+        buf.extend_from_slice(&(payload.len() as u32 + 1).to_be_bytes());
+        buf.push(id);
+        buf.extend_from_slice(&payload);
+    }
+
+    // Decodes one message from the front of `buf`. Returns the message and how many bytes it
+    // used, or None if the buffer doesn't hold a whole message yet
+    pub fn decode(buf: &[u8]) -> Result<Option<(Message, usize)>, MessageError> {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+
+        let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        if len > MAX_MESSAGE_LEN {
+            return Err(MessageError::TooLarge(len));
+        }
+        if buf.len() < 4 + len {
+            return Ok(None);
+        }
+        if len == 0 {
+            return Ok(Some((Message::KeepAlive, 4)));
+        }
+
+        let id = buf[4];
+        let payload = &buf[5..4 + len];
+        let msg = Message::decode_payload(id, payload)?;
+        Ok(Some((msg, 4 + len)))
+    }
+
+    fn decode_payload(id: u8, payload: &[u8]) -> Result<Message, MessageError> {
+        let expect_len = |n: usize| {
+            if payload.len() == n {
+                Ok(())
+            } else {
+                Err(MessageError::InvalidLength(id, payload.len()))
+            }
+        };
+
+        let msg = match id {
+            0 => expect_len(0).map(|_| Message::Choke)?,
+            1 => expect_len(0).map(|_| Message::Unchoke)?,
+            2 => expect_len(0).map(|_| Message::Interested)?,
+            3 => expect_len(0).map(|_| Message::NotInterested)?,
+            4 => expect_len(4).map(|_| Message::Have(read_u32(payload, 0)))?,
+            5 => Message::Bitfield(payload.to_vec()),
+            6 => expect_len(12).map(|_| Message::Request(read_block(payload)))?,
+            7 => {
+                if payload.len() < 8 {
+                    return Err(MessageError::InvalidLength(id, payload.len()));
+                }
+                Message::Piece {
+                    piece: read_u32(payload, 0),
+                    offset: read_u32(payload, 4),
+                    data: payload[8..].to_vec(),
+                }
+            }
+            8 => expect_len(12).map(|_| Message::Cancel(read_block(payload)))?,
+            9 => expect_len(2)
+                .map(|_| Message::Port(u16::from_be_bytes([payload[0], payload[1]])))?,
+            0x0d => expect_len(4).map(|_| Message::SuggestPiece(read_u32(payload, 0)))?,
+            0x0e => expect_len(0).map(|_| Message::HaveAll)?,
+            0x0f => expect_len(0).map(|_| Message::HaveNone)?,
+            0x10 => expect_len(12).map(|_| Message::RejectRequest(read_block(payload)))?,
+            0x11 => expect_len(4).map(|_| Message::AllowedFast(read_u32(payload, 0)))?,
+            20 => {
+                if payload.is_empty() {
+                    return Err(MessageError::InvalidLength(id, 0));
+                }
+                Message::Extended {
+                    id: payload[0],
+                    payload: payload[1..].to_vec(),
+                }
+            }
+            21 => expect_len(HASH_REQUEST_LEN)
+                .map(|_| Message::HashRequest(read_hash_request(payload)))?,
+            22 => {
+                let hashes = &payload[HASH_REQUEST_LEN.min(payload.len())..];
+                if payload.len() < HASH_REQUEST_LEN || !hashes.len().is_multiple_of(32) {
+                    return Err(MessageError::InvalidLength(id, payload.len()));
+                }
+                Message::Hashes {
+                    request: read_hash_request(payload),
+                    hashes: hashes
+                        .chunks_exact(32)
+                        .map(|h| h.try_into().unwrap())
+                        .collect(),
+                }
+            }
+            23 => expect_len(HASH_REQUEST_LEN)
+                .map(|_| Message::HashReject(read_hash_request(payload)))?,
+            _ => return Err(MessageError::UnknownId(id)),
+        };
+
+        Ok(msg)
+    }
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+fn read_block(buf: &[u8]) -> Block {
+    Block::new(read_u32(buf, 0), read_u32(buf, 4), read_u32(buf, 8))
+}
+
+// Pieces root, then base layer, index, length and proof layers
+const HASH_REQUEST_LEN: usize = 48;
+
+fn read_hash_request(buf: &[u8]) -> HashRequest {
+    HashRequest {
+        pieces_root: buf[..32].try_into().unwrap(),
+        base_layer: read_u32(buf, 32),
+        index: read_u32(buf, 36),
+        length: read_u32(buf, 40),
+        proof_layers: read_u32(buf, 44),
+    }
+}
+
+fn write_hash_request(buf: &mut Vec<u8>, request: &HashRequest) {
+    buf.extend_from_slice(&request.pieces_root);
+    for n in [
+        request.base_layer,
+        request.index,
+        request.length,
+        request.proof_layers,
+    ] {
+        buf.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn roundtrip(msg: Message) {
+        let buf = msg.encode();
+        assert_eq!(Message::decode(&buf), Ok(Some((msg, buf.len()))));
+    }
+
+    #[test]
+    fn test_keepalive() {
+        assert_eq!(Message::KeepAlive.encode(), vec![0, 0, 0, 0]);
+        roundtrip(Message::KeepAlive);
+    }
+
+    #[test]
+    fn test_have() {
+        assert_eq!(Message::Have(258).encode(), vec![0, 0, 0, 5, 4, 0, 0, 1, 2]);
+        roundtrip(Message::Have(258));
+    }
+
+    #[test]
+    fn test_roundtrips() {
+        roundtrip(Message::Choke);
+        roundtrip(Message::Unchoke);
+        roundtrip(Message::Interested);
+        roundtrip(Message::NotInterested);
+        roundtrip(Message::Bitfield(vec![0xff, 0x80]));
+        roundtrip(Message::Request(Block::new(1, 16384, 16384)));
+        roundtrip(Message::Cancel(Block::new(1, 16384, 16384)));
+        roundtrip(Message::Piece {
+            piece: 3,
+            offset: 0,
+            data: b"hello".to_vec(),
+        });
+        roundtrip(Message::Port(6881));
+        roundtrip(Message::SuggestPiece(7));
+        roundtrip(Message::HaveAll);
+        roundtrip(Message::HaveNone);
+        roundtrip(Message::RejectRequest(Block::new(1, 0, 16384)));
+        roundtrip(Message::AllowedFast(9));
+        roundtrip(Message::Extended {
+            id: 0,
+            payload: b"de".to_vec(),
+        });
+    }
+
+    #[test]
+    fn test_hash_messages() {
+        let request = HashRequest {
+            pieces_root: [7; 32],
+            base_layer: 1,
+            index: 4,
+            length: 2,
+            proof_layers: 3,
+        };
+        assert_eq!(Message::HashRequest(request).encode().len(), 4 + 1 + 48);
+        roundtrip(Message::HashRequest(request));
+        roundtrip(Message::HashReject(request));
+        roundtrip(Message::Hashes {
+            request,
+            hashes: vec![[1; 32]; 5],
+        });
+
+        let mut buf = Message::Hashes {
+            request,
+            hashes: vec![[1; 32]],
+        }
+        .encode();
+        buf[3] -= 1;
+        buf.pop();
+        assert_eq!(
+            Message::decode(&buf),
+            Err(MessageError::InvalidLength(22, 48 + 31))
+        );
+    }
+
+    #[test]
+    fn test_partial_buffer() {
+        let buf = Message::Have(1).encode();
+
+        assert_eq!(Message::decode(&buf[..2]), Ok(None));
+        assert_eq!(Message::decode(&buf[..8]), Ok(None));
+    }
+
+    #[test]
+    fn test_back_to_back() {
+        let mut buf = Message::Unchoke.encode();
+        Message::Have(5).encode_into(&mut buf);
+
+        let (first, used) = Message::decode(&buf).unwrap().unwrap();
+        let (second, _) = Message::decode(&buf[used..]).unwrap().unwrap();
+
+        assert_eq!(first, Message::Unchoke);
+        assert_eq!(second, Message::Have(5));
+    }
+
+    #[test]
+    fn test_bad_length() {
+        let buf = [0, 0, 0, 3, 4, 0, 0];
+
+        assert_eq!(
+            Message::decode(&buf),
+            Err(MessageError::InvalidLength(4, 2))
+        );
+    }
+
+    #[test]
+    fn test_unknown_id() {
+        let buf = [0, 0, 0, 1, 0x42];
+
+        assert_eq!(Message::decode(&buf), Err(MessageError::UnknownId(0x42)));
+    }
+
+    #[test]
+    fn test_too_large() {
+        let buf = [0xff, 0xff, 0xff, 0xff];
+
+        assert_eq!(
+            Message::decode(&buf),
+            Err(MessageError::TooLarge(0xffff_ffff))
+        );
+    }
+}
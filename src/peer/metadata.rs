@@ -0,0 +1,256 @@
+// ut_metadata (BEP 9): fetching the info dict from peers, which is how a magnet link turns into
+// a torrent. The dict is split into 16 KiB pieces, each requested with a bencoded header. Data
+// messages carry the piece as raw bytes after their header. Nothing is trusted until the whole
+// thing hashes to the info-hash.
+use std::collections::BTreeMap;
+
+use bencode::{BencodeValue, DecodeError};
+use sha1::{Digest, Sha1};
+
+use crate::infohash::InfoHash;
+
+pub const METADATA_PIECE_LEN: usize = 16 * 1024;
+
+// Real info dicts are at most a few MiB even for torrents with huge piece counts
+pub const MAX_METADATA_SIZE: usize = 16 << 20;
+
+#[derive(PartialEq, Debug)]
+pub enum MetadataError {
+    Decode(DecodeError),
+    NotADict,
+    InvalidField(&'static str),
+    // The assembled metadata doesn't hash to the info-hash. Everything has been thrown away
+    HashMismatch,
+}
+
+impl From<DecodeError> for MetadataError {
+    fn from(err: DecodeError) -> Self {
+        MetadataError::Decode(err)
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum MetadataMessage {
+    Request(u32),
+    Data {
+        piece: u32,
+        total_size: u32,
+        data: Vec<u8>,
+    },
+    Reject(u32),
+}
+
+impl MetadataMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let (msg_type, piece) = match self {
+            MetadataMessage::Request(piece) => (0, piece),
+            MetadataMessage::Data { piece, .. } => (1, piece),
+            MetadataMessage::Reject(piece) => (2, piece),
+        };
+        let mut header = BTreeMap::new();
+        header.insert(b"msg_type".to_vec(), BencodeValue::Int(msg_type));
+        header.insert(b"piece".to_vec(), BencodeValue::Int(*piece as i64));
+        if let MetadataMessage::Data { total_size, .. } = self {
+            header.insert(
+                b"total_size".to_vec(),
+                BencodeValue::Int(*total_size as i64),
+            );
+        }
+
+        let mut buf = bencode::encode(&BencodeValue::Dict(header));
+        if let MetadataMessage::Data { data, .. } = self {
+            buf.extend_from_slice(data);
+        }
+        buf
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<MetadataMessage, MetadataError> {
+        let (header, len) = bencode::decode_prefix(payload)?;
+        if header.as_dict().is_none() {
+            return Err(MetadataError::NotADict);
+        }
+        let int = |key: &'static str| {
+            header
+                .get(key.as_bytes())
+                .and_then(|v| v.as_int())
+                .and_then(|v| u32::try_from(v).ok())
+                .ok_or(MetadataError::InvalidField(key))
+        };
+
+        let piece = int("piece")?;
+        match int("msg_type")? {
+            0 => Ok(MetadataMessage::Request(piece)),
+            1 => Ok(MetadataMessage::Data {
+                piece,
+                total_size: int("total_size")?,
+                data: payload[len..].to_vec(),
+            }),
+            2 => Ok(MetadataMessage::Reject(piece)),
+            _ => Err(MetadataError::InvalidField("msg_type")),
+        }
+    }
+}
+
+// Our answer to a request, when we have the metadata
+pub fn serve_piece(info_bytes: &[u8], piece: u32) -> MetadataMessage {
+    let start = piece as usize * METADATA_PIECE_LEN;
+    if start >= info_bytes.len() {
+        return MetadataMessage::Reject(piece);
+    }
+    let end = (start + METADATA_PIECE_LEN).min(info_bytes.len());
+    MetadataMessage::Data {
+        piece,
+        total_size: info_bytes.len() as u32,
+        data: info_bytes[start..end].to_vec(),
+    }
+}
+
+// Assembles the metadata for one torrent from whichever peers have it. One of these is shared
+// by all connections: each asks `next_request` for a piece to request from its peer
+#[derive(Debug)]
+pub struct MetadataDownload {
+    info_hash: InfoHash,
+    buf: Vec<u8>,
+    received: Vec<bool>,
+    requested: Vec<bool>,
+}
+
+impl MetadataDownload {
+    // `size` is the metadata_size from a peer's extension handshake. None if it's nonsense
+    pub fn new(info_hash: InfoHash, size: u32) -> Option<Self> {
+        let size = size as usize;
+        if size == 0 || size > MAX_METADATA_SIZE {
+            return None;
+        }
+        let pieces = size.div_ceil(METADATA_PIECE_LEN);
+        Some(MetadataDownload {
+            info_hash,
+            buf: vec![0; size],
+            received: vec![false; pieces],
+            requested: vec![false; pieces],
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn num_pieces(&self) -> u32 {
+        self.received.len() as u32
+    }
+
+    // A piece nobody has been asked for yet. Once everything has been asked for, pieces still
+    // missing are handed out again so one slow peer can't hold the download up
+    pub fn next_request(&mut self) -> Option<u32> {
+        let missing = |requested: bool| {
+            (0..self.received.len()).find(|i| !self.received[*i] && self.requested[*i] == requested)
+        };
+        let piece = missing(false).or_else(|| missing(true))?;
+        self.requested[piece] = true;
+        Some(piece as u32)
+    }
+
+    // The peer doesn't have it after all, or disconnected before answering
+    pub fn on_reject(&mut self, piece: u32) {
+        if let Some(requested) = self.requested.get_mut(piece as usize) {
+            *requested = false;
+        }
+    }
+
+    // Returns the verified metadata once the last piece is in
+    pub fn on_data(&mut self, piece: u32, data: &[u8]) -> Result<Option<Vec<u8>>, MetadataError> {
+        let start = piece as usize * METADATA_PIECE_LEN;
+        let expected = METADATA_PIECE_LEN.min(self.buf.len().saturating_sub(start));
+        if start >= self.buf.len() || data.len() != expected {
+            return Err(MetadataError::InvalidField("piece"));
+        }
+        self.buf[start..start + expected].copy_from_slice(data);
+        self.received[piece as usize] = true;
+
+        if !self.received.iter().all(|r| *r) {
+            return Ok(None);
+        }
+        if Sha1::digest(&self.buf)[..] != self.info_hash.0 {
+            self.received.fill(false);
+            self.requested.fill(false);
+            return Err(MetadataError::HashMismatch);
+        }
+        Ok(Some(self.buf.clone()))
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn metadata(len: usize) -> (InfoHash, Vec<u8>) {
+        let buf: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        (InfoHash(Sha1::digest(&buf).into()), buf)
+    }
+
+    #[test]
+    fn test_message_roundtrip() {
+        let data = MetadataMessage::Data {
+            piece: 1,
+            total_size: 20000,
+            data: b"ee raw data".to_vec(),
+        };
+
+        assert_eq!(
+            MetadataMessage::encode(&MetadataMessage::Request(3)),
+            b"d8:msg_typei0e5:piecei3ee"
+        );
+        assert_eq!(MetadataMessage::decode(&data.encode()), Ok(data));
+        assert_eq!(
+            MetadataMessage::decode(b"d8:msg_typei7e5:piecei0ee"),
+            Err(MetadataError::InvalidField("msg_type"))
+        );
+    }
+
+    #[test]
+    fn test_download() {
+        let (info_hash, info) = metadata(40000);
+        let mut download = MetadataDownload::new(info_hash, info.len() as u32).unwrap();
+
+        assert_eq!(download.num_pieces(), 3);
+        let mut result = None;
+        while let Some(piece) = download.next_request() {
+            let MetadataMessage::Data { data, .. } = serve_piece(&info, piece) else {
+                panic!("expected data");
+            };
+            result = download.on_data(piece, &data).unwrap();
+            if result.is_some() {
+                break;
+            }
+        }
+        assert_eq!(result, Some(info));
+    }
+
+    #[test]
+    fn test_hash_mismatch_restarts() {
+        let (info_hash, info) = metadata(100);
+        let mut download = MetadataDownload::new(info_hash, 100).unwrap();
+        download.next_request();
+
+        assert_eq!(
+            download.on_data(0, &[0; 100]),
+            Err(MetadataError::HashMismatch)
+        );
+        assert_eq!(download.next_request(), Some(0));
+        assert_eq!(download.on_data(0, &info), Ok(Some(info)));
+    }
+
+    #[test]
+    fn test_rerequests_missing_pieces() {
+        let (info_hash, _) = metadata(20000);
+        let mut download = MetadataDownload::new(info_hash, 20000).unwrap();
+
+        assert_eq!(download.next_request(), Some(0));
+        assert_eq!(download.next_request(), Some(1));
+        download.on_reject(1);
+        assert_eq!(download.next_request(), Some(1));
+        assert_eq!(download.next_request(), Some(0));
+        assert!(MetadataDownload::new(info_hash, 0).is_none());
+        assert_eq!(serve_piece(&[0; 10], 1), MetadataMessage::Reject(1));
+    }
+}
@@ -0,0 +1,60 @@
+pub mod candidates;
+pub mod choker;
+pub mod client_ident;
+#[cfg(feature = "tokio")]
+pub mod codec;
+pub mod extension;
+pub mod fast;
+pub mod handshake;
+pub mod have;
+pub mod holepunch;
+pub mod listen;
+pub mod message;
+pub mod metadata;
+pub mod mse;
+pub mod pex;
+pub mod pipeline;
+pub mod priority;
+pub mod send_queue;
+pub mod stats;
+
+// Blocks are the unit of transfer on the wire. 16 KiB is what every client requests in practice
+// and what we request by default, but nothing in the protocol fixes it
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+
+// Bounds on the request size we can be configured to use ourselves
+pub const MIN_REQUEST_SIZE: u32 = 1024;
+pub const MAX_REQUEST_SIZE: u32 = 128 * 1024;
+
+// Biggest request from a peer we serve unless told otherwise. Some clients ask for 32 KiB or more,
+// anything past this gets rejected (or dropped, without the fast extension)
+pub const DEFAULT_MAX_SERVED_REQUEST: u32 = MAX_REQUEST_SIZE;
+
+// A `request`/`piece`/`cancel` triple: which piece, where in it, and how many bytes
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub struct Block {
+    pub piece: u32,
+    pub offset: u32,
+    pub length: u32,
+}
+
+impl Block {
+    pub fn new(piece: u32, offset: u32, length: u32) -> Self {
+        Block {
+            piece,
+            offset,
+            length,
+        }
+    }
+
+    // Whether we'd serve this as a peer's request: not empty, no bigger than `max_len` and inside
+    // a piece of `piece_size` bytes. Any offset goes, not just multiples of 16 KiB
+    pub fn is_servable(&self, piece_size: u32, max_len: u32) -> bool {
+        self.length > 0
+            && self.length <= max_len
+            && self
+                .offset
+                .checked_add(self.length)
+                .is_some_and(|end| end <= piece_size)
+    }
+}
@@ -0,0 +1,668 @@
+// Message Stream Encryption, the obfuscated handshake ISPs that throttle BitTorrent force clients
+// to use:
+//   1 A->B: Ya, PadA
+//   2 B->A: Yb, PadB
+//   3 A->B: HASH('req1', S), HASH('req2', SKEY) xor HASH('req3', S),
+//           ENCRYPT(VC, crypto_provide, len(PadC), PadC, len(IA)), ENCRYPT(IA)
+//   4 B->A: ENCRYPT(VC, crypto_select, len(PadD), PadD), ENCRYPT2(payload)
+// Y is a 768-bit Diffie-Hellman public key, S the shared secret, SKEY the info-hash and VC eight
+// zero bytes. ENCRYPT is RC4, ENCRYPT2 whatever crypto_select picked. The pads are random so
+// nothing in the stream has a fixed length or position.
+// This is obfuscation rather than security (there's no authentication), which is also why the
+// DH keys coming from our non-cryptographic Rng is acceptable.
+// Sans-IO like the rest of the peer code. Once the handshake is done, incoming bytes go through
+// `Cipher::decrypt` before the wire codec sees them, and encoded messages through
+// `Cipher::encrypt` on the way out.
+use num_bigint::BigUint;
+use sha1::{Digest, Sha1};
+
+use crate::infohash::InfoHash;
+use crate::rng::Rng;
+
+// The 768-bit safe prime from the spec. The generator is 2
+const PRIME: [u8; 96] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xc9, 0x0f, 0xda, 0xa2, 0x21, 0x68, 0xc2, 0x34,
+    0xc4, 0xc6, 0x62, 0x8b, 0x80, 0xdc, 0x1c, 0xd1, 0x29, 0x02, 0x4e, 0x08, 0x8a, 0x67, 0xcc, 0x74,
+    0x02, 0x0b, 0xbe, 0xa6, 0x3b, 0x13, 0x9b, 0x22, 0x51, 0x4a, 0x08, 0x79, 0x8e, 0x34, 0x04, 0xdd,
+    0xef, 0x95, 0x19, 0xb3, 0xcd, 0x3a, 0x43, 0x1b, 0x30, 0x2b, 0x0a, 0x6d, 0xf2, 0x5f, 0x14, 0x37,
+    0x4f, 0xe1, 0x35, 0x6d, 0x6d, 0x51, 0xc2, 0x45, 0xe4, 0x85, 0xb5, 0x76, 0x62, 0x5e, 0x7e, 0xc6,
+    0xf4, 0x4c, 0x42, 0xe9, 0xa6, 0x3a, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+
+pub const KEY_LEN: usize = 96;
+pub const MAX_PAD_LEN: usize = 512;
+const VC: [u8; 8] = [0; 8];
+
+// crypto_provide/crypto_select bits
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum EncryptionPolicy {
+    // Plain BitTorrent only. Incoming MSE handshakes fail when they don't parse as one
+    Disabled,
+    // Outgoing connections try MSE (and should be retried in plaintext if it fails), incoming
+    // ones may use either. RC4 is picked whenever the other side offers it
+    #[default]
+    Enabled,
+    // MSE with RC4 only, in both directions
+    Forced,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct EncryptionConfig {
+    pub outgoing: EncryptionPolicy,
+    pub incoming: EncryptionPolicy,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum CryptoMethod {
+    // Only the handshake was obfuscated
+    Plaintext,
+    Rc4,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum MseError {
+    // No HASH('req1', S) or ENCRYPT(VC) where it should have been
+    NoSync,
+    // The other side's SKEY isn't a torrent we have
+    UnknownInfoHash,
+    BadVerification,
+    BadPadding,
+    // Nothing in crypto_provide that our policy allows
+    NoCommonMethod,
+    // crypto_select wasn't exactly one of the methods we offered
+    InvalidSelect,
+    // A plain BitTorrent handshake while encryption is forced
+    PlaintextRejected,
+}
+
+// RC4 as MSE uses it: the first 1024 bytes of keystream are thrown away
+#[derive(Debug, Clone)]
+struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut s = [0u8; 256];
+        for (i, x) in s.iter_mut().enumerate() {
+            *x = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        Rc4 { s, i: 0, j: 0 }
+    }
+
+    fn mse(key: &[u8]) -> Self {
+        let mut rc4 = Rc4::new(key);
+        rc4.apply(&mut [0; 1024]);
+        rc4
+    }
+
+    fn apply(&mut self, buf: &mut [u8]) {
+        for byte in buf {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.s[self.i as usize].wrapping_add(self.s[self.j as usize]) as usize];
+            *byte ^= k;
+        }
+    }
+}
+
+// The two RC4 streams of an established connection, one per direction
+#[derive(Debug, Clone)]
+pub struct Cipher {
+    encrypt: Rc4,
+    decrypt: Rc4,
+}
+
+impl Cipher {
+    pub fn encrypt(&mut self, buf: &mut [u8]) {
+        self.encrypt.apply(buf);
+    }
+
+    pub fn decrypt(&mut self, buf: &mut [u8]) {
+        self.decrypt.apply(buf);
+    }
+}
+
+#[derive(Debug)]
+pub struct Established {
+    // The torrent the connection is for. None for incoming plaintext connections, where the
+    // BitTorrent handshake that follows says
+    pub info_hash: Option<InfoHash>,
+    pub method: CryptoMethod,
+    // Some for RC4
+    pub cipher: Option<Cipher>,
+    // Already decrypted bytes that came in after the handshake, usually the other side's
+    // BitTorrent handshake. Hand them to the wire codec first
+    pub payload: Vec<u8>,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum Step {
+    // No MSE at all
+    Passthrough,
+    // Incoming: plain BitTorrent handshake or Ya?
+    Detect,
+    // Waiting for the other side's public key
+    PublicKey,
+    // Outgoing: looking for ENCRYPT(VC) after PadB
+    SyncVc,
+    // Outgoing: crypto_select, PadD
+    Select,
+    // Incoming: looking for HASH('req1', S) after PadA
+    SyncReq1,
+    // Incoming: SKEY, VC, crypto_provide, PadC, IA
+    Provide,
+    Done,
+}
+
+#[derive(Debug)]
+pub struct MseHandshake {
+    outgoing: bool,
+    policy: EncryptionPolicy,
+    step: Step,
+    private_key: BigUint,
+    // Outgoing: the torrent we want. Incoming: every torrent we'd accept
+    torrents: Vec<InfoHash>,
+    info_hash: Option<InfoHash>,
+    // Outgoing: IA, sent along with message 3
+    initial_payload: Vec<u8>,
+    secret: Vec<u8>,
+    encrypt: Option<Rc4>,
+    decrypt: Option<Rc4>,
+    // Received bytes not consumed yet, the first `decrypted` of which have been decrypted
+    buf: Vec<u8>,
+    decrypted: usize,
+    outbox: Vec<u8>,
+    pad: Vec<u8>,
+}
+
+impl MseHandshake {
+    // `initial_payload` is our BitTorrent handshake (and anything else worth sending early), so
+    // it goes out with message 3 instead of costing another round trip
+    pub fn outgoing(
+        info_hash: InfoHash,
+        initial_payload: Vec<u8>,
+        policy: EncryptionPolicy,
+        rng: &mut Rng,
+    ) -> Self {
+        let mut handshake = MseHandshake::new(true, vec![info_hash], policy, rng);
+        handshake.info_hash = Some(info_hash);
+        if policy == EncryptionPolicy::Disabled {
+            handshake.step = Step::Passthrough;
+            handshake.outbox = initial_payload;
+        } else {
+            handshake.initial_payload = initial_payload;
+            handshake.send_public_key();
+        }
+        handshake
+    }
+
+    // `torrents` are the info-hashes an incoming connection may ask for
+    pub fn incoming(torrents: Vec<InfoHash>, policy: EncryptionPolicy, rng: &mut Rng) -> Self {
+        let mut handshake = MseHandshake::new(false, torrents, policy, rng);
+        if policy == EncryptionPolicy::Disabled {
+            handshake.step = Step::Passthrough;
+        }
+        handshake
+    }
+
+    fn new(
+        outgoing: bool,
+        torrents: Vec<InfoHash>,
+        policy: EncryptionPolicy,
+        rng: &mut Rng,
+    ) -> Self {
+        // 160 bits of private key is what the spec recommends
+        let mut private_key = [0u8; 20];
+        rng.fill(&mut private_key);
+        let mut pad = vec![0u8; rng.below(MAX_PAD_LEN + 1)];
+        rng.fill(&mut pad);
+
+        MseHandshake {
+            outgoing,
+            policy,
+            // Incoming connections might not be using MSE at all
+            step: if outgoing {
+                Step::PublicKey
+            } else {
+                Step::Detect
+            },
+            private_key: BigUint::from_bytes_be(&private_key),
+            torrents,
+            info_hash: None,
+            initial_payload: vec![],
+            secret: vec![],
+            encrypt: None,
+            decrypt: None,
+            buf: vec![],
+            decrypted: 0,
+            outbox: vec![],
+            pad,
+        }
+    }
+
+    // Bytes to send to the other side
+    pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+        if self.outbox.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.outbox))
+        }
+    }
+
+    // Feeds in received bytes. Returns the connection once the handshake is complete; anything
+    // after that is the caller's to decrypt
+    pub fn handle_input(&mut self, data: &[u8]) -> Result<Option<Established>, MseError> {
+        self.buf.extend_from_slice(data);
+        loop {
+            let before = self.step;
+            match self.step {
+                Step::Passthrough => return Ok(Some(self.finish(CryptoMethod::Plaintext))),
+                Step::Detect => self.detect()?,
+                Step::PublicKey => self.read_public_key(),
+                Step::SyncVc => self.sync_vc()?,
+                Step::Select => {
+                    if let Some(method) = self.read_select()? {
+                        return Ok(Some(self.finish(method)));
+                    }
+                }
+                Step::SyncReq1 => self.sync_req1()?,
+                Step::Provide => {
+                    if let Some(method) = self.read_provide()? {
+                        return Ok(Some(self.finish(method)));
+                    }
+                }
+                Step::Done => return Ok(None),
+            }
+            if self.step == before {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn detect(&mut self) -> Result<(), MseError> {
+        let plain = b"\x13BitTorrent protocol";
+        let len = self.buf.len().min(plain.len());
+        if self.buf[..len] != plain[..len] {
+            self.step = Step::PublicKey;
+        } else if len == plain.len() {
+            if self.policy == EncryptionPolicy::Forced {
+                return Err(MseError::PlaintextRejected);
+            }
+            self.step = Step::Passthrough;
+        }
+        Ok(())
+    }
+
+    fn send_public_key(&mut self) {
+        let public_key = BigUint::from(2u32).modpow(&self.private_key, &prime());
+        self.outbox.extend_from_slice(&to_key(&public_key));
+        self.outbox.append(&mut self.pad);
+    }
+
+    fn read_public_key(&mut self) {
+        if self.buf.len() < KEY_LEN {
+            return;
+        }
+        let theirs = BigUint::from_bytes_be(&self.buf[..KEY_LEN]);
+        self.secret = to_key(&theirs.modpow(&self.private_key, &prime())).to_vec();
+        self.buf.drain(..KEY_LEN);
+
+        if self.outgoing {
+            self.send_request();
+            self.step = Step::SyncVc;
+        } else {
+            self.send_public_key();
+            self.step = Step::SyncReq1;
+        }
+    }
+
+    // Message 3
+    fn send_request(&mut self) {
+        let skey = self.torrents[0];
+        let mut encrypt = Rc4::mse(&hash(&[b"keyA", &self.secret, &skey.0]));
+
+        self.outbox
+            .extend_from_slice(&hash(&[b"req1", &self.secret]));
+        let req2 = hash(&[b"req2", &skey.0]);
+        let req3 = hash(&[b"req3", &self.secret]);
+        self.outbox
+            .extend(req2.iter().zip(req3).map(|(a, b)| a ^ b));
+
+        let mut msg = VC.to_vec();
+        msg.extend_from_slice(&self.provide().to_be_bytes());
+        // PadC is left empty, Ya and PadA have done the obfuscating by now
+        msg.extend_from_slice(&0u16.to_be_bytes());
+        msg.extend_from_slice(&(self.initial_payload.len() as u16).to_be_bytes());
+        msg.append(&mut self.initial_payload);
+        encrypt.apply(&mut msg);
+        self.outbox.extend_from_slice(&msg);
+        self.encrypt = Some(encrypt);
+    }
+
+    fn provide(&self) -> u32 {
+        match self.policy {
+            EncryptionPolicy::Forced => CRYPTO_RC4,
+            _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        }
+    }
+
+    fn sync_vc(&mut self) -> Result<(), MseError> {
+        let skey = self.torrents[0];
+        let mut decrypt = Rc4::mse(&hash(&[b"keyB", &self.secret, &skey.0]));
+        let mut vc = VC;
+        decrypt.apply(&mut vc);
+
+        match find(&self.buf, &vc, MAX_PAD_LEN) {
+            Some(at) => {
+                self.buf.drain(..at + vc.len());
+                self.decrypt = Some(decrypt);
+                self.step = Step::Select;
+                Ok(())
+            }
+            None if self.buf.len() >= MAX_PAD_LEN + vc.len() => Err(MseError::NoSync),
+            None => Ok(()),
+        }
+    }
+
+    fn read_select(&mut self) -> Result<Option<CryptoMethod>, MseError> {
+        if !self.decrypt_to(6) {
+            return Ok(None);
+        }
+        let select = u32::from_be_bytes(self.buf[..4].try_into().unwrap());
+        let pad_len = u16::from_be_bytes([self.buf[4], self.buf[5]]) as usize;
+        if pad_len > MAX_PAD_LEN {
+            return Err(MseError::BadPadding);
+        }
+        if !self.decrypt_to(6 + pad_len) {
+            return Ok(None);
+        }
+
+        let method = match select {
+            CRYPTO_RC4 => CryptoMethod::Rc4,
+            CRYPTO_PLAINTEXT => CryptoMethod::Plaintext,
+            _ => return Err(MseError::InvalidSelect),
+        };
+        if select & self.provide() == 0 {
+            return Err(MseError::InvalidSelect);
+        }
+        self.consume(6 + pad_len);
+        Ok(Some(method))
+    }
+
+    fn sync_req1(&mut self) -> Result<(), MseError> {
+        let req1 = hash(&[b"req1", &self.secret]);
+        match find(&self.buf, &req1, MAX_PAD_LEN) {
+            Some(at) => {
+                self.buf.drain(..at + req1.len());
+                self.step = Step::Provide;
+                Ok(())
+            }
+            None if self.buf.len() >= MAX_PAD_LEN + req1.len() => Err(MseError::NoSync),
+            None => Ok(()),
+        }
+    }
+
+    fn read_provide(&mut self) -> Result<Option<CryptoMethod>, MseError> {
+        if self.info_hash.is_none() {
+            if self.buf.len() < 20 {
+                return Ok(None);
+            }
+            let req3 = hash(&[b"req3", &self.secret]);
+            let req2: Vec<u8> = self.buf[..20]
+                .iter()
+                .zip(req3)
+                .map(|(a, b)| a ^ b)
+                .collect();
+            let skey = *self
+                .torrents
+                .iter()
+                .find(|info_hash| hash(&[b"req2", &info_hash.0]) == req2[..])
+                .ok_or(MseError::UnknownInfoHash)?;
+            self.buf.drain(..20);
+            self.info_hash = Some(skey);
+            self.decrypt = Some(Rc4::mse(&hash(&[b"keyA", &self.secret, &skey.0])));
+            self.encrypt = Some(Rc4::mse(&hash(&[b"keyB", &self.secret, &skey.0])));
+        }
+
+        // VC, crypto_provide, len(PadC)
+        if !self.decrypt_to(14) {
+            return Ok(None);
+        }
+        if self.buf[..8] != VC {
+            return Err(MseError::BadVerification);
+        }
+        let provide = u32::from_be_bytes(self.buf[8..12].try_into().unwrap());
+        let pad_len = u16::from_be_bytes([self.buf[12], self.buf[13]]) as usize;
+        if pad_len > MAX_PAD_LEN {
+            return Err(MseError::BadPadding);
+        }
+        // PadC, len(IA)
+        let ia_at = 14 + pad_len + 2;
+        if !self.decrypt_to(ia_at) {
+            return Ok(None);
+        }
+        let ia_len = u16::from_be_bytes([self.buf[ia_at - 2], self.buf[ia_at - 1]]) as usize;
+        if !self.decrypt_to(ia_at + ia_len) {
+            return Ok(None);
+        }
+
+        let (select, method) = if provide & CRYPTO_RC4 != 0 {
+            (CRYPTO_RC4, CryptoMethod::Rc4)
+        } else if provide & CRYPTO_PLAINTEXT != 0 && self.policy != EncryptionPolicy::Forced {
+            (CRYPTO_PLAINTEXT, CryptoMethod::Plaintext)
+        } else {
+            return Err(MseError::NoCommonMethod);
+        };
+
+        // Message 4, with an empty PadD
+        let mut msg = VC.to_vec();
+        msg.extend_from_slice(&select.to_be_bytes());
+        msg.extend_from_slice(&0u16.to_be_bytes());
+        self.encrypt.as_mut().unwrap().apply(&mut msg);
+        self.outbox.extend_from_slice(&msg);
+
+        // IA stays in the buffer as the start of the payload
+        self.consume(ia_at);
+        Ok(Some(method))
+    }
+
+    // Decrypts the buffer up to `end` if that much has arrived
+    fn decrypt_to(&mut self, end: usize) -> bool {
+        if self.buf.len() < end {
+            return false;
+        }
+        if end > self.decrypted {
+            let decrypt = self.decrypt.as_mut().unwrap();
+            decrypt.apply(&mut self.buf[self.decrypted..end]);
+            self.decrypted = end;
+        }
+        true
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.buf.drain(..len);
+        self.decrypted = self.decrypted.saturating_sub(len);
+    }
+
+    fn finish(&mut self, method: CryptoMethod) -> Established {
+        self.step = Step::Done;
+        let mut payload = std::mem::take(&mut self.buf);
+        let cipher = match method {
+            CryptoMethod::Rc4 => {
+                let mut cipher = Cipher {
+                    encrypt: self.encrypt.take().unwrap(),
+                    decrypt: self.decrypt.take().unwrap(),
+                };
+                cipher.decrypt(&mut payload[self.decrypted..]);
+                Some(cipher)
+            }
+            CryptoMethod::Plaintext => None,
+        };
+        Established {
+            info_hash: self.info_hash,
+            method,
+            cipher,
+            payload,
+        }
+    }
+}
+
+fn prime() -> BigUint {
+    BigUint::from_bytes_be(&PRIME)
+}
+
+// Keys and S are always sent as 96 bytes, zero padded on the left
+fn to_key(n: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = n.to_bytes_be();
+    let mut key = [0u8; KEY_LEN];
+    key[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+// Position of `needle` in `buf`, if it starts within the first `max_skip` bytes
+fn find(buf: &[u8], needle: &[u8], max_skip: usize) -> Option<usize> {
+    buf.windows(needle.len())
+        .take(max_skip + 1)
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn exchange(
+        a: &mut MseHandshake,
+        b: &mut MseHandshake,
+    ) -> Result<(Established, Established), MseError> {
+        let (mut a_done, mut b_done) = (None, None);
+        while a_done.is_none() || b_done.is_none() {
+            let mut progress = false;
+            if let Some(data) = a.poll_transmit() {
+                progress = true;
+                b_done = b_done.or(b.handle_input(&data)?);
+            }
+            if let Some(data) = b.poll_transmit() {
+                progress = true;
+                a_done = a_done.or(a.handle_input(&data)?);
+            }
+            assert!(progress, "handshake stalled");
+        }
+        Ok((a_done.unwrap(), b_done.unwrap()))
+    }
+
+    #[test]
+    fn test_rc4_vector() {
+        let mut buf = *b"Plaintext";
+        Rc4::new(b"Key").apply(&mut buf);
+
+        assert_eq!(buf, [0xbb, 0xf3, 0x16, 0xe8, 0xd9, 0x40, 0xaf, 0x0a, 0xd3]);
+    }
+
+    #[test]
+    fn test_encrypted_exchange() {
+        let info_hash = InfoHash([7; 20]);
+        let mut rng = Rng::with_seed(1);
+        let mut a = MseHandshake::outgoing(
+            info_hash,
+            b"hello".to_vec(),
+            EncryptionPolicy::Enabled,
+            &mut rng,
+        );
+        let mut b = MseHandshake::incoming(
+            vec![InfoHash([1; 20]), info_hash],
+            EncryptionPolicy::Forced,
+            &mut rng,
+        );
+        let (a_done, b_done) = exchange(&mut a, &mut b).unwrap();
+
+        assert_eq!(a_done.method, CryptoMethod::Rc4);
+        assert_eq!(b_done.method, CryptoMethod::Rc4);
+        assert_eq!(b_done.info_hash, Some(info_hash));
+        assert_eq!(b_done.payload, b"hello");
+
+        let (mut a_cipher, mut b_cipher) = (a_done.cipher.unwrap(), b_done.cipher.unwrap());
+        let mut msg = *b"\0\0\0\x01\x02";
+        a_cipher.encrypt(&mut msg);
+        assert_ne!(&msg, b"\0\0\0\x01\x02");
+        b_cipher.decrypt(&mut msg);
+        assert_eq!(&msg, b"\0\0\0\x01\x02");
+    }
+
+    #[test]
+    fn test_byte_at_a_time() {
+        let info_hash = InfoHash([7; 20]);
+        let mut rng = Rng::with_seed(2);
+        let mut a = MseHandshake::outgoing(info_hash, vec![], EncryptionPolicy::Forced, &mut rng);
+        let mut b = MseHandshake::incoming(vec![info_hash], EncryptionPolicy::Enabled, &mut rng);
+
+        let mut a_done = None;
+        let mut b_done = None;
+        while a_done.is_none() {
+            for byte in a.poll_transmit().unwrap_or_default() {
+                b_done = b_done.or(b.handle_input(&[byte]).unwrap());
+            }
+            for byte in b.poll_transmit().unwrap_or_default() {
+                a_done = a_done.or(a.handle_input(&[byte]).unwrap());
+            }
+        }
+
+        assert_eq!(a_done.unwrap().method, CryptoMethod::Rc4);
+        assert_eq!(b_done.unwrap().payload, b"");
+    }
+
+    #[test]
+    fn test_incoming_plaintext() {
+        let handshake = b"\x13BitTorrent protocol\0\0\0\0\0\0\0\0";
+        let mut rng = Rng::with_seed(3);
+        let mut enabled = MseHandshake::incoming(vec![], EncryptionPolicy::Enabled, &mut rng);
+        let mut forced = MseHandshake::incoming(vec![], EncryptionPolicy::Forced, &mut rng);
+
+        assert!(enabled.handle_input(&handshake[..10]).unwrap().is_none());
+        let done = enabled.handle_input(&handshake[10..]).unwrap().unwrap();
+        assert_eq!(done.method, CryptoMethod::Plaintext);
+        assert_eq!(done.payload, handshake);
+        assert!(done.cipher.is_none());
+        assert_eq!(
+            forced.handle_input(handshake).unwrap_err(),
+            MseError::PlaintextRejected
+        );
+    }
+
+    #[test]
+    fn test_unknown_info_hash() {
+        let mut rng = Rng::with_seed(4);
+        let mut a = MseHandshake::outgoing(
+            InfoHash([7; 20]),
+            vec![],
+            EncryptionPolicy::Enabled,
+            &mut rng,
+        );
+        let mut b =
+            MseHandshake::incoming(vec![InfoHash([8; 20])], EncryptionPolicy::Enabled, &mut rng);
+
+        assert_eq!(
+            exchange(&mut a, &mut b).unwrap_err(),
+            MseError::UnknownInfoHash
+        );
+    }
+}
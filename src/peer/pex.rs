@@ -0,0 +1,363 @@
+// Peer exchange (ut_pex, BEP 11).
+// Connected peers periodically tell each other which peers they've connected to or dropped since
+// the last message. In swarms where the trackers are dead or stingy this is where most of our
+// peers come from. IPv4 and IPv6 peers travel in separate keys (`added`, `added6`, ...) but are
+// one list to us.
+use std::collections::{BTreeMap, HashSet};
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::time::{Duration, Instant};
+
+use bencode::{BencodeValue, DecodeError};
+
+use crate::audit::{self, Channel};
+use crate::compact::{CompactAddr, CompactPeers};
+use crate::infohash::InfoHash;
+
+// The spec says at most one message per minute
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+// Be a little lenient with incoming messages since timers on both ends jitter
+pub const MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(45);
+
+// Max added and dropped entries in a single message
+pub const MAX_PEX_PEERS: usize = 50;
+
+#[derive(PartialEq, Debug)]
+pub enum PexError {
+    Decode(DecodeError),
+    NotADict,
+    InvalidField(&'static str),
+    TooFrequent,
+}
+
+impl From<DecodeError> for PexError {
+    fn from(err: DecodeError) -> Self {
+        PexError::Decode(err)
+    }
+}
+
+// Per-peer flags from `added.f`
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy, Default)]
+pub struct PexFlags(pub u8);
+
+impl PexFlags {
+    pub const PREFERS_ENCRYPTION: u8 = 0x01;
+    pub const SEED: u8 = 0x02;
+    pub const UTP: u8 = 0x04;
+    pub const HOLEPUNCH: u8 = 0x08;
+    // We connected out to this peer, so it's known to accept incoming connections
+    pub const REACHABLE: u8 = 0x10;
+
+    pub fn has(&self, flag: u8) -> bool {
+        self.0 & flag != 0
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct PexMessage {
+    pub added: Vec<(SocketAddr, PexFlags)>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<PexMessage, PexError> {
+        let values = bencode::decode(payload)?;
+        let root = values.first().ok_or(PexError::NotADict)?;
+        if root.as_dict().is_none() {
+            return Err(PexError::NotADict);
+        }
+
+        let mut added = with_flags(root, parse_peers::<SocketAddrV4>(root, "added")?, "added.f");
+        added.extend(with_flags(
+            root,
+            parse_peers::<SocketAddrV6>(root, "added6")?,
+            "added6.f",
+        ));
+        let mut dropped = parse_peers::<SocketAddrV4>(root, "dropped")?;
+        dropped.extend(parse_peers::<SocketAddrV6>(root, "dropped6")?);
+        Ok(PexMessage { added, dropped })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut root = BTreeMap::new();
+        let (v4, v6): (Vec<_>, Vec<_>) = self.added.iter().partition(|(addr, _)| addr.is_ipv4());
+        for (added, key) in [(v4, "added"), (v6, "added6")] {
+            // IPv6 keys only when there's something to put in them, for clients that predate them
+            if added.is_empty() && key == "added6" {
+                continue;
+            }
+            let flags: Vec<u8> = added.iter().map(|(_, f)| f.0).collect();
+            let addrs: Vec<SocketAddr> = added.iter().map(|(addr, _)| *addr).collect();
+            root.insert(
+                key.as_bytes().to_vec(),
+                BencodeValue::ByteStr(write_peers(&addrs)),
+            );
+            root.insert(
+                format!("{}.f", key).into_bytes(),
+                BencodeValue::ByteStr(flags),
+            );
+        }
+        let (v4, v6): (Vec<SocketAddr>, Vec<SocketAddr>) =
+            self.dropped.iter().partition(|addr| addr.is_ipv4());
+        root.insert(b"dropped".to_vec(), BencodeValue::ByteStr(write_peers(&v4)));
+        if !v6.is_empty() {
+            root.insert(
+                b"dropped6".to_vec(),
+                BencodeValue::ByteStr(write_peers(&v6)),
+            );
+        }
+        bencode::encode(&BencodeValue::Dict(root))
+    }
+}
+
+fn parse_peers<A>(root: &BencodeValue, key: &'static str) -> Result<Vec<SocketAddr>, PexError>
+where
+    A: CompactAddr + Into<SocketAddr>,
+{
+    let Some(value) = root.get(key.as_bytes()) else {
+        return Ok(vec![]);
+    };
+
+    value
+        .as_bytes()
+        .and_then(|b| CompactPeers::parse::<A>(b).ok())
+        .map(|peers| peers.into_iter().map(Into::into).collect())
+        .ok_or(PexError::InvalidField(key))
+}
+
+// Flags are optional, and some clients send fewer than they should
+fn with_flags(
+    root: &BencodeValue,
+    peers: Vec<SocketAddr>,
+    key: &str,
+) -> Vec<(SocketAddr, PexFlags)> {
+    let flags = root
+        .get(key.as_bytes())
+        .and_then(|f| f.as_bytes())
+        .unwrap_or(&[]);
+    peers
+        .into_iter()
+        .enumerate()
+        .map(|(i, addr)| (addr, PexFlags(flags.get(i).copied().unwrap_or(0))))
+        .collect()
+}
+
+// All of one family
+fn write_peers(peers: &[SocketAddr]) -> Vec<u8> {
+    peers.iter().flat_map(CompactPeers::write_one).collect()
+}
+
+// PEX state for one connection
+#[derive(Debug)]
+pub struct PexState {
+    // The torrent the connection is for. Private ones mustn't get here at all
+    info_hash: InfoHash,
+    // What this peer currently believes our connections are
+    sent: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl PexState {
+    pub fn new(info_hash: InfoHash) -> Self {
+        PexState {
+            info_hash,
+            sent: HashSet::new(),
+            last_sent: None,
+            last_received: None,
+        }
+    }
+
+    // Builds the next message if one is due. `connected` should be everyone we're connected to
+    // in this torrent except the peer we're sending to
+    pub fn tick(
+        &mut self,
+        connected: &[(SocketAddr, PexFlags)],
+        now: Instant,
+    ) -> Option<PexMessage> {
+        if self
+            .last_sent
+            .is_some_and(|last| now.saturating_duration_since(last) < PEX_INTERVAL)
+        {
+            return None;
+        }
+        audit::check_public(self.info_hash, Channel::Pex);
+
+        let current: HashSet<SocketAddr> = connected.iter().map(|(a, _)| *a).collect();
+        let msg = PexMessage {
+            added: connected
+                .iter()
+                .filter(|(addr, _)| !self.sent.contains(addr))
+                .take(MAX_PEX_PEERS)
+                .copied()
+                .collect(),
+            dropped: self
+                .sent
+                .iter()
+                .filter(|addr| !current.contains(addr))
+                .take(MAX_PEX_PEERS)
+                .copied()
+                .collect(),
+        };
+        if msg.is_empty() {
+            return None;
+        }
+
+        // Anything over the cap stays pending and goes out in a later message
+        for (addr, _) in &msg.added {
+            self.sent.insert(*addr);
+        }
+        for addr in &msg.dropped {
+            self.sent.remove(addr);
+        }
+        self.last_sent = Some(now);
+        Some(msg)
+    }
+
+    // Parses an incoming message and returns the peers worth trying. Peers that flood us with
+    // PEX get their messages refused, and oversized lists get truncated to the spec's limit
+    pub fn on_receive(
+        &mut self,
+        payload: &[u8],
+        now: Instant,
+    ) -> Result<Vec<(SocketAddr, PexFlags)>, PexError> {
+        if self
+            .last_received
+            .is_some_and(|last| now.saturating_duration_since(last) < MIN_RECEIVE_INTERVAL)
+        {
+            return Err(PexError::TooFrequent);
+        }
+        self.last_received = Some(now);
+
+        let mut msg = PexMessage::decode(payload)?;
+        msg.added.truncate(MAX_PEX_PEERS);
+        Ok(msg.added)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn peer(i: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, i], 6881))
+    }
+
+    #[test]
+    fn test_message_roundtrip() {
+        let msg = PexMessage {
+            added: vec![
+                (peer(1), PexFlags(PexFlags::SEED)),
+                (peer(2), PexFlags(PexFlags::REACHABLE | PexFlags::UTP)),
+                (
+                    "[2001:db8::1]:6881".parse().unwrap(),
+                    PexFlags(PexFlags::SEED),
+                ),
+            ],
+            dropped: vec![peer(3), "[2001:db8::2]:6881".parse().unwrap()],
+        };
+        let encoded = msg.encode();
+
+        assert_eq!(PexMessage::decode(&encoded), Ok(msg));
+        let root = &bencode::decode(&encoded).unwrap()[0];
+        assert_eq!(root.get(b"added6").unwrap().as_bytes().unwrap().len(), 18);
+        assert_eq!(root.get(b"added6.f").unwrap().as_bytes(), Some(&[2u8][..]));
+    }
+
+    #[test]
+    fn test_missing_flags() {
+        let raw = b"d5:added6:\x0a\x00\x00\x01\x1a\xe1e";
+        let msg = PexMessage::decode(raw).unwrap();
+
+        assert_eq!(msg.added, vec![(peer(1), PexFlags(0))]);
+        assert!(msg.dropped.is_empty());
+    }
+
+    #[test]
+    fn test_bad_added_len() {
+        assert_eq!(
+            PexMessage::decode(b"d5:added5:abcdee"),
+            Err(PexError::InvalidField("added"))
+        );
+    }
+
+    #[test]
+    fn test_tick_deltas() {
+        let now = Instant::now();
+        let mut state = PexState::new(InfoHash([0; 20]));
+        let flags = PexFlags::default();
+
+        let first = state
+            .tick(&[(peer(1), flags), (peer(2), flags)], now)
+            .unwrap();
+        assert_eq!(first.added.len(), 2);
+
+        // Too soon
+        assert!(state.tick(&[(peer(1), flags)], now).is_none());
+
+        let second = state
+            .tick(&[(peer(1), flags), (peer(3), flags)], now + PEX_INTERVAL)
+            .unwrap();
+        assert_eq!(second.added, vec![(peer(3), flags)]);
+        assert_eq!(second.dropped, vec![peer(2)]);
+    }
+
+    #[test]
+    fn test_tick_nothing_changed() {
+        let now = Instant::now();
+        let mut state = PexState::new(InfoHash([0; 20]));
+        let connected = [(peer(1), PexFlags::default())];
+        state.tick(&connected, now);
+
+        assert!(state.tick(&connected, now + PEX_INTERVAL).is_none());
+    }
+
+    #[test]
+    fn test_tick_caps_added() {
+        let now = Instant::now();
+        let mut state = PexState::new(InfoHash([0; 20]));
+        let connected: Vec<_> = (0..80).map(|i| (peer(i), PexFlags::default())).collect();
+
+        let first = state.tick(&connected, now).unwrap();
+        let second = state.tick(&connected, now + PEX_INTERVAL).unwrap();
+
+        assert_eq!(first.added.len(), MAX_PEX_PEERS);
+        assert_eq!(second.added.len(), 30);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "leaked to Pex")]
+    fn test_private_never_sent() {
+        let info_hash = InfoHash([0xa3; 20]);
+        audit::register_private(info_hash);
+        let mut state = PexState::new(info_hash);
+        state.tick(&[(peer(1), PexFlags::default())], Instant::now());
+    }
+
+    #[test]
+    fn test_receive_rate_limit() {
+        let now = Instant::now();
+        let mut state = PexState::new(InfoHash([0; 20]));
+        let payload = PexMessage {
+            added: vec![(peer(1), PexFlags::default())],
+            dropped: vec![],
+        }
+        .encode();
+
+        assert_eq!(state.on_receive(&payload, now).unwrap().len(), 1);
+        assert_eq!(
+            state.on_receive(&payload, now + Duration::from_secs(10)),
+            Err(PexError::TooFrequent)
+        );
+        assert!(
+            state
+                .on_receive(&payload, now + MIN_RECEIVE_INTERVAL)
+                .is_ok()
+        );
+    }
+}
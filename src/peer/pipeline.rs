@@ -0,0 +1,450 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use super::{BLOCK_SIZE, Block};
+use crate::rate::Rate;
+
+#[derive(Debug, Clone)]
+pub struct PipelineConfig {
+    // Never keep fewer than this many requests in flight, even on a peer that looks dead slow
+    pub min_depth: usize,
+    // Hard cap so a fast peer can't make us queue the whole torrent with it
+    pub max_depth: usize,
+    // How many seconds worth of data (at the measured rate) we want queued up with the peer
+    pub queue_time: Duration,
+    // Floor on how long a request may go unanswered before we consider it stale
+    pub min_timeout: Duration,
+    // A peer that sends nothing for this long while we have requests out is snubbing us
+    pub snub_timeout: Duration,
+    // Bytes per request, to turn the byte window into a number of requests. Should match the
+    // picker's block size
+    pub request_size: u32,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        PipelineConfig {
+            min_depth: 2,
+            max_depth: 250,
+            queue_time: Duration::from_secs(3),
+            min_timeout: Duration::from_secs(20),
+            snub_timeout: Duration::from_secs(60),
+            request_size: BLOCK_SIZE,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Pending {
+    block: Block,
+    sent_at: Instant,
+}
+
+// Tracks the requests we have outstanding with a single peer. The queue depth follows the
+// peer's measured download rate: we keep `rate * queue_time` bytes requested, so there is always
+// something on the wire. The round trip time only sets timeouts. Timed from request to block it
+// includes the wait behind everything queued ahead, so adding it to the window would make the
+// depth feed on itself
+#[derive(Debug)]
+pub struct RequestPipeline {
+    config: PipelineConfig,
+    pending: VecDeque<Pending>,
+    rate: Rate,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    // Last time the peer delivered a block, or when we started waiting on it if it was idle
+    last_progress: Instant,
+    snubbed: bool,
+}
+
+impl RequestPipeline {
+    pub fn new(config: PipelineConfig, now: Instant) -> Self {
+        RequestPipeline {
+            config,
+            pending: VecDeque::new(),
+            rate: Rate::new(now),
+            srtt: None,
+            rttvar: Duration::ZERO,
+            last_progress: now,
+            snubbed: false,
+        }
+    }
+
+    // How many requests we'd like to have outstanding right now
+    pub fn desired_depth(&self) -> usize {
+        // Until it proves otherwise a snubbing peer only gets one request at a time
+        if self.snubbed {
+            return 1;
+        }
+
+        let bytes = self.rate.get() * self.config.queue_time.as_secs_f64();
+        let depth = (bytes / self.config.request_size as f64).ceil() as usize;
+
+        depth.clamp(self.config.min_depth, self.config.max_depth)
+    }
+
+    // How many more requests the caller should send
+    pub fn slots(&self) -> usize {
+        self.desired_depth().saturating_sub(self.pending.len())
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn contains(&self, block: &Block) -> bool {
+        self.pending.iter().any(|p| p.block == *block)
+    }
+
+    pub fn outstanding(&self) -> impl Iterator<Item = &Block> {
+        self.pending.iter().map(|p| &p.block)
+    }
+
+    // Smoothed round trip time, if we've had at least one block come back
+    pub fn rtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn download_rate(&self) -> f64 {
+        self.rate.get()
+    }
+
+    pub fn on_request_sent(&mut self, block: Block, now: Instant) {
+        if self.pending.is_empty() {
+            self.last_progress = now;
+        }
+        self.pending.push_back(Pending {
+            block,
+            sent_at: now,
+        });
+    }
+
+    // Returns false if we never asked for this block (or already cancelled it)
+    pub fn on_block_received(&mut self, block: &Block, now: Instant) -> bool {
+        let Some(idx) = self.pending.iter().position(|p| p.block == *block) else {
+            return false;
+        };
+
+        let pending = self.pending.remove(idx).unwrap();
+        self.sample_rtt(now.saturating_duration_since(pending.sent_at));
+        self.rate.add(block.length as u64);
+        self.last_progress = now;
+        self.snubbed = false;
+        true
+    }
+
+    // Forget about a request, e.g. because another peer delivered the block first
+    pub fn cancel(&mut self, block: &Block) -> bool {
+        let Some(idx) = self.pending.iter().position(|p| p.block == *block) else {
+            return false;
+        };
+
+        self.pending.remove(idx);
+        true
+    }
+
+    // When we get choked the peer drops everything we asked for, so we should too
+    pub fn clear(&mut self) -> Vec<Block> {
+        self.pending.drain(..).map(|p| p.block).collect()
+    }
+
+    // Requests older than this are considered lost
+    pub fn timeout(&self) -> Duration {
+        let estimate = match self.srtt {
+            Some(srtt) => srtt + self.rttvar * 4,
+            None => Duration::ZERO,
+        };
+
+        estimate.max(self.config.min_timeout)
+    }
+
+    // Removes and returns every request that has been outstanding for longer than `timeout`.
+    // The caller should send `cancel` for each and hand the blocks back to the picker
+    pub fn take_stale(&mut self, now: Instant) -> Vec<Block> {
+        let timeout = self.timeout();
+        let mut stale = Vec::new();
+
+        self.pending.retain(|p| {
+            if now.saturating_duration_since(p.sent_at) > timeout {
+                stale.push(p.block);
+                false
+            } else {
+                true
+            }
+        });
+
+        stale
+    }
+
+    pub fn is_snubbed(&self) -> bool {
+        self.snubbed
+    }
+
+    // Checks whether the peer has gone quiet on us. The first time it trips, every outstanding
+    // request is handed back so the caller can cancel them and let other peers pick them up.
+    // Receiving any block clears the snubbed state
+    pub fn check_snub(&mut self, now: Instant) -> Vec<Block> {
+        if self.snubbed || self.pending.is_empty() {
+            return vec![];
+        }
+        if now.saturating_duration_since(self.last_progress) <= self.config.snub_timeout {
+            return vec![];
+        }
+
+        self.snubbed = true;
+        self.clear()
+    }
+
+    // After the machine slept, every request looks ancient and the peer looks like it's been
+    // snubbing us for hours. Start the clocks over instead of cancelling everything
+    pub fn on_wake(&mut self, now: Instant) {
+        for pending in &mut self.pending {
+            pending.sent_at = now;
+        }
+        self.last_progress = now;
+    }
+
+    pub fn tick(&mut self, now: Instant) {
+        self.rate.tick(now);
+    }
+
+    // RFC 6298 style smoothing, which is also what libtorrent uses for its request timeouts
+    fn sample_rtt(&mut self, sample: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(sample);
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + sample) / 8);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn block(i: u32) -> Block {
+        Block::new(0, i * BLOCK_SIZE, BLOCK_SIZE)
+    }
+
+    #[test]
+    fn test_pipeline_min_depth() {
+        let pipeline = RequestPipeline::new(PipelineConfig::default(), Instant::now());
+
+        assert_eq!(pipeline.desired_depth(), 2);
+        assert_eq!(pipeline.slots(), 2);
+    }
+
+    #[test]
+    fn test_pipeline_depth_grows_with_rate() {
+        let start = Instant::now();
+        let mut pipeline = RequestPipeline::new(PipelineConfig::default(), start);
+
+        // 64 blocks in one second = 1 MiB/s
+        for i in 0..64 {
+            pipeline.on_request_sent(block(i), start);
+            assert!(pipeline.on_block_received(&block(i), start + Duration::from_millis(100)));
+        }
+        pipeline.tick(start + Duration::from_secs(1));
+
+        // 1 MiB/s * 3s queue time = 192 blocks
+        assert_eq!(pipeline.desired_depth(), 192);
+        assert_eq!(pipeline.rtt(), Some(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn test_pipeline_depth_settles_on_steady_peer() {
+        let start = Instant::now();
+        let mut pipeline = RequestPipeline::new(PipelineConfig::default(), start);
+
+        // A peer serving one block every 1/16s in order, 256 KiB/s, for two minutes, with the
+        // pipe kept full. Every block waits behind the whole queue
+        let mut next = 0;
+        for served in 0..16 * 120 {
+            let now = start + Duration::from_millis((served as u64 + 1) * 1000 / 16);
+            for _ in 0..pipeline.slots() {
+                pipeline.on_request_sent(block(next), now);
+                next += 1;
+            }
+            assert!(pipeline.on_block_received(&block(served), now));
+            pipeline.tick(now);
+        }
+
+        // 256 KiB/s * 3s = 48 blocks, however long the queue makes the round trip look
+        let depth = pipeline.desired_depth();
+        assert!((44..=52).contains(&depth), "depth {}", depth);
+        assert!(pipeline.rtt().unwrap() > Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_pipeline_depth_capped() {
+        let start = Instant::now();
+        let config = PipelineConfig {
+            max_depth: 10,
+            ..PipelineConfig::default()
+        };
+        let mut pipeline = RequestPipeline::new(config, start);
+
+        for i in 0..64 {
+            pipeline.on_request_sent(block(i), start);
+            pipeline.on_block_received(&block(i), start);
+        }
+        pipeline.tick(start + Duration::from_secs(1));
+
+        assert_eq!(pipeline.desired_depth(), 10);
+    }
+
+    #[test]
+    fn test_pipeline_slots() {
+        let start = Instant::now();
+        let mut pipeline = RequestPipeline::new(PipelineConfig::default(), start);
+        pipeline.on_request_sent(block(0), start);
+
+        assert_eq!(pipeline.slots(), 1);
+
+        pipeline.on_request_sent(block(1), start);
+        assert_eq!(pipeline.slots(), 0);
+        assert_eq!(pipeline.len(), 2);
+    }
+
+    #[test]
+    fn test_pipeline_unrequested_block() {
+        let start = Instant::now();
+        let mut pipeline = RequestPipeline::new(PipelineConfig::default(), start);
+
+        assert!(!pipeline.on_block_received(&block(0), start));
+        assert_eq!(pipeline.rtt(), None);
+    }
+
+    #[test]
+    fn test_pipeline_cancel() {
+        let start = Instant::now();
+        let mut pipeline = RequestPipeline::new(PipelineConfig::default(), start);
+        pipeline.on_request_sent(block(0), start);
+
+        assert!(pipeline.cancel(&block(0)));
+        assert!(!pipeline.cancel(&block(0)));
+        assert!(pipeline.is_empty());
+    }
+
+    #[test]
+    fn test_pipeline_stale() {
+        let start = Instant::now();
+        let mut pipeline = RequestPipeline::new(PipelineConfig::default(), start);
+        pipeline.on_request_sent(block(0), start);
+        pipeline.on_request_sent(block(1), start + Duration::from_secs(15));
+
+        let stale = pipeline.take_stale(start + Duration::from_secs(21));
+
+        assert_eq!(stale, vec![block(0)]);
+        assert_eq!(pipeline.outstanding().collect::<Vec<_>>(), vec![&block(1)]);
+    }
+
+    #[test]
+    fn test_pipeline_timeout_follows_rtt() {
+        let start = Instant::now();
+        let mut pipeline = RequestPipeline::new(PipelineConfig::default(), start);
+        pipeline.on_request_sent(block(0), start);
+        pipeline.on_block_received(&block(0), start + Duration::from_secs(10));
+
+        // srtt + 4 * rttvar = 10s + 4 * 5s
+        assert_eq!(pipeline.timeout(), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_pipeline_snubbed() {
+        let start = Instant::now();
+        let mut pipeline = RequestPipeline::new(PipelineConfig::default(), start);
+        pipeline.on_request_sent(block(0), start);
+        pipeline.on_request_sent(block(1), start);
+
+        assert!(
+            pipeline
+                .check_snub(start + Duration::from_secs(60))
+                .is_empty()
+        );
+
+        let redistribute = pipeline.check_snub(start + Duration::from_secs(61));
+        assert_eq!(redistribute, vec![block(0), block(1)]);
+        assert!(pipeline.is_snubbed());
+        assert_eq!(pipeline.desired_depth(), 1);
+
+        // Only reported once
+        pipeline.on_request_sent(block(2), start + Duration::from_secs(62));
+        assert!(
+            pipeline
+                .check_snub(start + Duration::from_secs(200))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_pipeline_wake() {
+        let start = Instant::now();
+        let mut pipeline = RequestPipeline::new(PipelineConfig::default(), start);
+        pipeline.on_request_sent(block(0), start);
+
+        // An hour asleep, as far as the clock can tell
+        let woke = start + Duration::from_secs(3600);
+        pipeline.on_wake(woke);
+        assert!(
+            pipeline
+                .take_stale(woke + Duration::from_secs(1))
+                .is_empty()
+        );
+        assert!(
+            pipeline
+                .check_snub(woke + Duration::from_secs(1))
+                .is_empty()
+        );
+        assert_eq!(pipeline.len(), 1);
+    }
+
+    #[test]
+    fn test_pipeline_unsnubbed_by_block() {
+        let start = Instant::now();
+        let mut pipeline = RequestPipeline::new(PipelineConfig::default(), start);
+        pipeline.on_request_sent(block(0), start);
+        pipeline.check_snub(start + Duration::from_secs(61));
+
+        pipeline.on_request_sent(block(1), start + Duration::from_secs(62));
+        pipeline.on_block_received(&block(1), start + Duration::from_secs(63));
+
+        assert!(!pipeline.is_snubbed());
+    }
+
+    #[test]
+    fn test_pipeline_idle_peer_not_snubbed() {
+        let start = Instant::now();
+        let mut pipeline = RequestPipeline::new(PipelineConfig::default(), start);
+
+        // Nothing requested for ages, then one request: the clock starts from the request
+        pipeline.on_request_sent(block(0), start + Duration::from_secs(100));
+
+        assert!(
+            pipeline
+                .check_snub(start + Duration::from_secs(120))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_pipeline_clear() {
+        let start = Instant::now();
+        let mut pipeline = RequestPipeline::new(PipelineConfig::default(), start);
+        pipeline.on_request_sent(block(0), start);
+        pipeline.on_request_sent(block(1), start);
+
+        assert_eq!(pipeline.clear(), vec![block(0), block(1)]);
+        assert!(pipeline.is_empty());
+    }
+}
@@ -0,0 +1,112 @@
+// Canonical peer priority (BEP 40). Both ends of a possible connection compute the same number
+// from the two addresses, so when every client dials high priority peers first and drops low
+// priority ones first, the swarm settles on the same connections from both sides instead of
+// everyone trying everyone. It also spreads a client's connections over the address space: an
+// attacker holding a block of addresses gets roughly the priorities anyone else would, rather
+// than being able to crowd out the rest by being first to announce.
+// The addresses are masked so that only the bits that tell apart their networks count in full,
+// and hashed with CRC32-C. The BEP only spells out IPv4; for IPv6 the same three steps are
+// taken at /48, /56 and the whole address.
+use std::net::{IpAddr, SocketAddr};
+
+pub fn peer_priority(a: SocketAddr, b: SocketAddr) -> u32 {
+    if a.ip() == b.ip() {
+        let (low, high) = (a.port().min(b.port()), a.port().max(b.port()));
+        let mut buf = [0; 4];
+        buf[..2].copy_from_slice(&low.to_be_bytes());
+        buf[2..].copy_from_slice(&high.to_be_bytes());
+        return crc32c(&buf);
+    }
+    match (a.ip(), b.ip()) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => masked(&a.octets(), &b.octets(), [2, 3, 4]),
+        (IpAddr::V6(a), IpAddr::V6(b)) => masked(&a.octets(), &b.octets(), [6, 7, 16]),
+        // Never the case for two ends of one connection
+        _ => 0,
+    }
+}
+
+// `keep` is three prefix lengths in bytes, shortest first. Addresses that differ within the
+// first are kept in full up to it, ones that differ within the second up to that, and closer
+// ones up to the third. Past that, only every other bit counts
+fn masked(a: &[u8], b: &[u8], keep: [usize; 3]) -> u32 {
+    let common = a.iter().zip(b).take_while(|(a, b)| a == b).count();
+    let keep = if common < keep[0] {
+        keep[0]
+    } else if common < keep[1] {
+        keep[1]
+    } else {
+        keep[2]
+    };
+    let mask = |ip: &[u8]| -> Vec<u8> {
+        ip.iter()
+            .enumerate()
+            .map(|(i, byte)| if i < keep { *byte } else { byte & 0x55 })
+            .collect()
+    };
+    let (a, b) = (mask(a), mask(b));
+    let (low, high) = if a <= b { (a, b) } else { (b, a) };
+    crc32c(&[low, high].concat())
+}
+
+// CRC32-C (Castagnoli), bit by bit. Only ever run over a couple of addresses
+fn crc32c(buf: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in buf {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    // The examples from BEP 40
+    #[test]
+    fn test_bep40_vectors() {
+        assert_eq!(
+            peer_priority(addr("123.213.32.10:0"), addr("98.76.54.32:0")),
+            0xec2d_7224
+        );
+        assert_eq!(
+            peer_priority(addr("123.213.32.10:0"), addr("123.213.32.234:0")),
+            0x9956_8189
+        );
+    }
+
+    #[test]
+    fn test_symmetric() {
+        let pairs = [
+            ("1.2.3.4:6881", "5.6.7.8:51413"),
+            ("10.0.0.1:6881", "10.0.0.1:6882"),
+            ("[2001:db8::1]:6881", "[2001:db8:1::2]:6881"),
+        ];
+        for (a, b) in pairs {
+            assert_eq!(
+                peer_priority(addr(a), addr(b)),
+                peer_priority(addr(b), addr(a))
+            );
+        }
+        // Same address: the ports decide
+        assert_ne!(
+            peer_priority(addr("10.0.0.1:6881"), addr("10.0.0.1:6882")),
+            peer_priority(addr("10.0.0.1:6881"), addr("10.0.0.1:6883"))
+        );
+    }
+}
@@ -0,0 +1,231 @@
+// Outgoing messages for one peer. Piece messages are big and there can be a lot of them queued
+// while the socket is slow, so they wait behind everything else: a request, cancel or choke
+// never sits behind a megabyte of block data. Messages only leave the queue when the socket can
+// take them, so anything still queued can be taken back.
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use super::Block;
+use super::message::Message;
+use crate::limiter::{Traffic, UploadLimiter};
+
+// Piece data we're willing to have queued before the caller should stop reading blocks from
+// disk for this peer
+pub const MAX_QUEUED_PIECE_BYTES: usize = 1 << 20;
+
+#[derive(Debug, Default)]
+pub struct SendQueue {
+    control: VecDeque<Message>,
+    pieces: VecDeque<Message>,
+    piece_bytes: usize,
+}
+
+impl SendQueue {
+    pub fn new() -> Self {
+        SendQueue::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.control.len() + self.pieces.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn piece_bytes(&self) -> usize {
+        self.piece_bytes
+    }
+
+    // Backpressure: don't queue more pieces until this goes false again
+    pub fn is_full(&self) -> bool {
+        self.piece_bytes >= MAX_QUEUED_PIECE_BYTES
+    }
+
+    pub fn push(&mut self, msg: Message) {
+        match msg {
+            Message::Piece { ref data, .. } => {
+                self.piece_bytes += data.len();
+                self.pieces.push_back(msg);
+            }
+            // Cancelling a request that never went out: neither needs sending
+            Message::Cancel(block) if self.remove_request(block) => {}
+            msg => self.control.push_back(msg),
+        }
+    }
+
+    // The next message to write
+    pub fn pop(&mut self) -> Option<Message> {
+        if let Some(msg) = self.control.pop_front() {
+            return Some(msg);
+        }
+        let msg = self.pieces.pop_front()?;
+        self.piece_bytes -= piece_block(&msg).length as usize;
+        Some(msg)
+    }
+
+    // Like `pop`, but only what the upload limiter lets through right now. Everything but piece
+    // data counts as control traffic, so haves, PEX and metadata keep flowing under a tight cap
+    pub fn pop_limited(&mut self, limiter: &mut UploadLimiter, now: Instant) -> Option<Message> {
+        if let Some(msg) = self.control.front() {
+            let len = msg.encode().len() as u64;
+            if !limiter.try_send(Traffic::Control, len, now) {
+                return None;
+            }
+            return self.control.pop_front();
+        }
+        let block = piece_block(self.pieces.front()?);
+        // Length prefix, ID, index and offset
+        let len = 13 + block.length as u64;
+        if !limiter.try_send(Traffic::Payload, len, now) {
+            return None;
+        }
+        self.pop()
+    }
+
+    // The peer cancelled a request. Returns whether the piece was still unsent, in which case
+    // it's been dropped
+    pub fn cancel_piece(&mut self, block: Block) -> bool {
+        let Some(at) = self.pieces.iter().position(|msg| piece_block(msg) == block) else {
+            return false;
+        };
+        self.pieces.remove(at);
+        self.piece_bytes -= block.length as usize;
+        true
+    }
+
+    // Drops every unsent piece, e.g. after choking the peer. Returns their blocks so peers with
+    // the fast extension can be sent rejects for them
+    pub fn clear_pieces(&mut self) -> Vec<Block> {
+        self.piece_bytes = 0;
+        self.pieces.drain(..).map(|msg| piece_block(&msg)).collect()
+    }
+
+    fn remove_request(&mut self, block: Block) -> bool {
+        let Some(at) = self
+            .control
+            .iter()
+            .position(|msg| *msg == Message::Request(block))
+        else {
+            return false;
+        };
+        self.control.remove(at);
+        true
+    }
+}
+
+fn piece_block(msg: &Message) -> Block {
+    match msg {
+        Message::Piece {
+            piece,
+            offset,
+            data,
+        } => Block::new(*piece, *offset, data.len() as u32),
+        _ => unreachable!("only piece messages are queued as pieces"),
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::peer::BLOCK_SIZE;
+
+    fn piece(index: u32) -> Message {
+        Message::Piece {
+            piece: index,
+            offset: 0,
+            data: vec![0; BLOCK_SIZE as usize],
+        }
+    }
+
+    #[test]
+    fn test_control_preempts_pieces() {
+        let mut queue = SendQueue::new();
+        queue.push(piece(0));
+        queue.push(piece(1));
+        queue.push(Message::Have(3));
+        queue.push(Message::Interested);
+
+        assert_eq!(queue.pop(), Some(Message::Have(3)));
+        assert_eq!(queue.pop(), Some(Message::Interested));
+        assert_eq!(queue.pop(), Some(piece(0)));
+        assert_eq!(queue.piece_bytes(), BLOCK_SIZE as usize);
+        assert_eq!(queue.pop(), Some(piece(1)));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn test_cancel_piece() {
+        let mut queue = SendQueue::new();
+        queue.push(piece(0));
+        queue.push(piece(1));
+
+        assert!(queue.cancel_piece(Block::new(1, 0, BLOCK_SIZE)));
+        assert!(!queue.cancel_piece(Block::new(1, 0, BLOCK_SIZE)));
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.piece_bytes(), BLOCK_SIZE as usize);
+    }
+
+    #[test]
+    fn test_cancel_unsent_request() {
+        let block = Block::new(0, 0, BLOCK_SIZE);
+        let mut queue = SendQueue::new();
+        queue.push(Message::Request(block));
+        queue.push(Message::Cancel(block));
+
+        assert!(queue.is_empty());
+        // Once the request is out, the cancel has to be sent
+        queue.push(Message::Request(block));
+        queue.pop();
+        queue.push(Message::Cancel(block));
+        assert_eq!(queue.pop(), Some(Message::Cancel(block)));
+    }
+
+    #[test]
+    fn test_backpressure() {
+        let mut queue = SendQueue::new();
+        while !queue.is_full() {
+            queue.push(piece(0));
+        }
+
+        assert_eq!(queue.len(), MAX_QUEUED_PIECE_BYTES / BLOCK_SIZE as usize);
+        assert_eq!(queue.clear_pieces().len(), 64);
+        assert!(queue.is_empty() && !queue.is_full());
+    }
+
+    #[test]
+    fn test_limited_control_gets_through() {
+        let now = Instant::now();
+        let mut limiter = UploadLimiter::new(Some(1), now);
+        let mut queue = SendQueue::new();
+        for i in 0..3 {
+            queue.push(piece(i));
+        }
+
+        // One block fits the payload bucket's initial burst, then only control moves
+        assert!(queue.pop_limited(&mut limiter, now).is_some());
+        assert!(queue.pop_limited(&mut limiter, now).is_none());
+        queue.push(Message::Have(9));
+        assert_eq!(queue.pop_limited(&mut limiter, now), Some(Message::Have(9)));
+        assert!(queue.pop_limited(&mut limiter, now).is_none());
+        assert_eq!(queue.piece_bytes(), 2 * BLOCK_SIZE as usize);
+    }
+
+    #[test]
+    fn test_limited_oversized_message() {
+        let now = Instant::now();
+        let mut limiter = UploadLimiter::new(Some(1), now);
+        let mut queue = SendQueue::new();
+        let big = Message::Piece {
+            piece: 0,
+            offset: 0,
+            data: vec![0; 128 * 1024],
+        };
+        queue.push(big.clone());
+        queue.push(big.clone());
+
+        // Bigger than the bucket ever gets, but it doesn't hold the queue up for good
+        assert_eq!(queue.pop_limited(&mut limiter, now), Some(big));
+        assert!(queue.pop_limited(&mut limiter, now).is_none());
+    }
+}
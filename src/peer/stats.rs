@@ -0,0 +1,183 @@
+// What each connection is doing, for debugging a swarm: who's on the other end, how we're talking
+// to them, how fast data moves each way, how much they have, and whether they're holding out on
+// us. Whatever drives the connections keeps one `PeerStats` per peer, calls `tick` every second
+// and hands copies to the torrent (`Torrent::set_peer_stats`), where the RPC API and
+// `hurricane peers` read them.
+// A peer is snubbing us when it has our requests and hasn't sent a byte of them for
+// `SNUB_TIMEOUT`, the same test the choker's `snubbed` is meant for.
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::rate::Rate;
+
+pub const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct PeerFlags {
+    // They connected to us
+    pub incoming: bool,
+    // MSE/PE, see `mse`
+    pub encrypted: bool,
+    pub utp: bool,
+    // They're choking us, so our requests wait
+    pub choked: bool,
+    // We want something they have
+    pub interested: bool,
+    pub snubbed: bool,
+}
+
+// One letter each, in the order above, as the peer tables show them: `IEPcis`. Nothing set is
+// an empty string
+impl fmt::Display for PeerFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let letters = [
+            (self.incoming, 'I'),
+            (self.encrypted, 'E'),
+            (self.utp, 'P'),
+            (self.choked, 'c'),
+            (self.interested, 'i'),
+            (self.snubbed, 's'),
+        ];
+        for (set, letter) in letters {
+            if set {
+                write!(f, "{}", letter)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerStats {
+    pub addr: SocketAddr,
+    // From their extension handshake
+    pub client: Option<String>,
+    pub flags: PeerFlags,
+    // Our requests they haven't answered yet
+    pub queued_requests: usize,
+    download: Rate,
+    upload: Rate,
+    // Theirs, and the torrent's. 0 of 0 until we have the info dict
+    pieces: usize,
+    num_pieces: usize,
+    // Since then they've sent nothing we asked for
+    last_received: Instant,
+}
+
+impl PeerStats {
+    pub fn new(addr: SocketAddr, flags: PeerFlags, now: Instant) -> Self {
+        PeerStats {
+            addr,
+            client: None,
+            // Everyone starts out choked
+            flags: PeerFlags {
+                choked: true,
+                ..flags
+            },
+            queued_requests: 0,
+            download: Rate::new(now),
+            upload: Rate::new(now),
+            pieces: 0,
+            num_pieces: 0,
+            last_received: now,
+        }
+    }
+
+    // Block data they sent us
+    pub fn on_downloaded(&mut self, bytes: u64, now: Instant) {
+        self.download.add(bytes);
+        self.last_received = now;
+        self.flags.snubbed = false;
+    }
+
+    // Block data we sent them
+    pub fn on_uploaded(&mut self, bytes: u64) {
+        self.upload.add(bytes);
+    }
+
+    pub fn set_pieces(&mut self, pieces: usize, num_pieces: usize) {
+        self.pieces = pieces;
+        self.num_pieces = num_pieces;
+    }
+
+    // Call every second
+    pub fn tick(&mut self, now: Instant) {
+        self.download.tick(now);
+        self.upload.tick(now);
+        if self.queued_requests == 0 || self.flags.choked {
+            self.last_received = now;
+        }
+        self.flags.snubbed = now.saturating_duration_since(self.last_received) >= SNUB_TIMEOUT;
+    }
+
+    pub fn download_rate(&self) -> f64 {
+        self.download.get()
+    }
+
+    pub fn upload_rate(&self) -> f64 {
+        self.upload.get()
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.download.total()
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.upload.total()
+    }
+
+    // Share of the torrent they have, 0.0 to 1.0
+    pub fn progress(&self) -> f64 {
+        match self.num_pieces {
+            0 => 0.0,
+            n => self.pieces as f64 / n as f64,
+        }
+    }
+
+    pub fn is_seed(&self) -> bool {
+        self.num_pieces > 0 && self.pieces == self.num_pieces
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let now = Instant::now();
+        let flags = PeerFlags {
+            incoming: true,
+            encrypted: true,
+            ..PeerFlags::default()
+        };
+        let mut stats = PeerStats::new("10.0.0.1:6881".parse().unwrap(), flags, now);
+        assert_eq!(stats.flags.to_string(), "IEc");
+        assert_eq!(stats.progress(), 0.0);
+
+        stats.flags.choked = false;
+        stats.flags.interested = true;
+        stats.queued_requests = 4;
+        stats.on_downloaded(32 * 1024, now);
+        stats.on_uploaded(1000);
+        stats.set_pieces(3, 4);
+        stats.tick(now + Duration::from_secs(2));
+        assert_eq!(stats.download_rate(), 16.0 * 1024.0);
+        assert_eq!(stats.uploaded(), 1000);
+        assert_eq!(stats.progress(), 0.75);
+        assert!(!stats.is_seed());
+        assert!(!stats.flags.snubbed);
+
+        // Requests out and nothing back for a minute
+        stats.tick(now + SNUB_TIMEOUT);
+        assert_eq!(stats.flags.to_string(), "IEis");
+        stats.on_downloaded(16 * 1024, now + SNUB_TIMEOUT);
+        assert!(!stats.flags.snubbed);
+
+        // Nothing asked for, nothing owed
+        stats.queued_requests = 0;
+        stats.tick(now + SNUB_TIMEOUT * 3);
+        assert!(!stats.flags.snubbed);
+    }
+}
@@ -0,0 +1,500 @@
+// Saving a whole session to a state directory and loading it back on startup, so a restart picks
+// up every torrent with its options, stats and place in the queue without anything being added
+// again. The directory holds:
+// - `session.dat`: the session's settings and one entry per torrent, in queue order
+// - `torrents/<info-hash>.torrent`: each torrent's metainfo, once it has one
+// - `resume/<info-hash>.resume`: each torrent's `ResumeData`, which has its pieces and totals
+// All three go through `statefile`, so a crash mid-save loses at most that save. A torrent whose
+// .torrent file went missing comes back as a magnet link and fetches its metadata again.
+// Per-torrent proxies aren't saved, since they may hold passwords. A torrent that follows a BEP 46
+// link keeps following it; one still looking for its first version isn't saved.
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use bencode::BencodeValue;
+
+use crate::disk::{Allocation, Backend, ResumeData, resume};
+use crate::infohash::InfoHash;
+use crate::metainfo::{MagnetLink, Metainfo, MutableMagnet};
+use crate::picker::Priority;
+use crate::session::{QueueLimits, Session, TorrentHandle};
+use crate::statefile;
+use crate::torrent::{SeedAction, SeedGoals, Torrent, TorrentLimits, TorrentStatus};
+
+pub const SESSION_FILE: &str = "session.dat";
+
+// A loaded session. Torrents with an info dict start out in `CheckingFiles`: validate each
+// one's resume data against its storage (`ResumeData::validate`) and `Torrent::apply_resume` it,
+// or do a full check for those without, then `Session::update_queue`
+pub struct Restored {
+    pub session: Session,
+    pub resume: Vec<(TorrentHandle, ResumeData)>,
+}
+
+// Writes out everything `load` needs. Metainfo files that didn't change aren't written again, and
+// those of torrents no longer in the session are removed
+pub fn save(session: &Session, dir: &Path) -> io::Result<()> {
+    let torrents_dir = dir.join("torrents");
+    let resume_dir = dir.join("resume");
+    fs::create_dir_all(&torrents_dir)?;
+    fs::create_dir_all(&resume_dir)?;
+
+    let mut entries = vec![];
+    let mut kept = HashSet::new();
+    for handle in session.queue() {
+        let torrent = session.get(*handle).unwrap();
+        let hex = torrent.info_hash().to_hex();
+        if let Some(metainfo) = torrent.metainfo() {
+            let path = torrents_dir.join(format!("{}.torrent", hex));
+            let bytes = metainfo.to_bytes();
+            if fs::read(&path).ok().as_deref() != Some(&bytes[..]) {
+                statefile::save(&path, &bytes)?;
+            }
+            let stamps = match torrent.storage() {
+                Some(storage) => resume::stamp_files(&storage)?,
+                None => vec![],
+            };
+            if let Some(resume) = torrent.resume_data(stamps) {
+                resume.save(&resume_dir.join(format!("{}.resume", hex)))?;
+            }
+        }
+        entries.push(encode_torrent(&torrent, session.feed(*handle)));
+        kept.insert(hex);
+    }
+
+    let mut root = BTreeMap::new();
+    let limits = session.queue_limits();
+    root.insert(
+        b"max downloading".to_vec(),
+        limit(limits.downloading.map(|n| n as u64)),
+    );
+    root.insert(
+        b"max seeding".to_vec(),
+        limit(limits.seeding.map(|n| n as u64)),
+    );
+    root.insert(
+        b"seed goals".to_vec(),
+        encode_goals(&session.default_seed_goals()),
+    );
+    root.insert(
+        b"bandwidth".to_vec(),
+        text(&session.bandwidth_schedule().to_string()),
+    );
+    root.insert(b"torrents".to_vec(), BencodeValue::List(entries));
+    statefile::save(
+        &dir.join(SESSION_FILE),
+        &bencode::encode(&BencodeValue::Dict(root)),
+    )?;
+
+    for (dir, extension) in [(&torrents_dir, "torrent"), (&resume_dir, "resume")] {
+        remove_stale(dir, extension, &kept)?;
+    }
+    Ok(())
+}
+
+// Ok(None) if there's no saved session
+pub fn load(dir: &Path, now: Instant) -> io::Result<Option<Restored>> {
+    let Some(root) = statefile::load(&dir.join(SESSION_FILE), |buf| {
+        bencode::decode(buf).ok()?.into_iter().next()
+    })?
+    else {
+        return Ok(None);
+    };
+
+    let mut session = Session::new();
+    session.set_queue_limits(QueueLimits {
+        downloading: root
+            .get(b"max downloading")
+            .and_then(decode_limit)
+            .map(|n| n as usize),
+        seeding: root
+            .get(b"max seeding")
+            .and_then(decode_limit)
+            .map(|n| n as usize),
+    });
+    if let Some(goals) = root.get(b"seed goals") {
+        session.set_default_seed_goals(decode_goals(goals));
+    }
+    if let Some(schedule) = root
+        .get(b"bandwidth")
+        .and_then(string)
+        .and_then(|text| text.parse().ok())
+    {
+        session.set_bandwidth_schedule(schedule);
+    }
+
+    let mut resume = vec![];
+    let entries = root
+        .get(b"torrents")
+        .and_then(|t| t.as_list())
+        .unwrap_or(&[]);
+    for entry in entries {
+        let Some(torrent) = decode_torrent(entry, dir, now)? else {
+            continue;
+        };
+        let path = dir
+            .join("resume")
+            .join(format!("{}.resume", torrent.info_hash().to_hex()));
+        let has_metainfo = torrent.metainfo().is_some();
+        let handle = session.add(torrent, now).handle();
+        if let Some(feed) = entry
+            .get(b"feed")
+            .and_then(string)
+            .and_then(|uri| MutableMagnet::parse(&uri).ok())
+        {
+            session.set_feed(handle, feed, now);
+        }
+        if has_metainfo && let Some(data) = ResumeData::load(&path)? {
+            resume.push((handle, data));
+        }
+    }
+    Ok(Some(Restored { session, resume }))
+}
+
+fn encode_torrent(torrent: &Torrent, feed: Option<&MutableMagnet>) -> BencodeValue {
+    let mut entry = BTreeMap::new();
+    if let Some(feed) = feed {
+        entry.insert(b"feed".to_vec(), text(&feed.to_uri()));
+    }
+    entry.insert(
+        b"info-hash".to_vec(),
+        BencodeValue::ByteStr(torrent.info_hash().0.to_vec()),
+    );
+    entry.insert(b"name".to_vec(), text(torrent.name()));
+    if let Some(path) = torrent.save_path() {
+        entry.insert(
+            b"save path".to_vec(),
+            BencodeValue::ByteStr(path_to_bytes(path)),
+        );
+    }
+    if let Some(label) = torrent.label() {
+        entry.insert(b"label".to_vec(), text(label));
+    }
+    let tiers = torrent
+        .trackers()
+        .tiers()
+        .iter()
+        .map(|tier| BencodeValue::List(tier.iter().map(|url| text(url)).collect()))
+        .collect();
+    entry.insert(b"trackers".to_vec(), BencodeValue::List(tiers));
+    let paused = torrent.status() == TorrentStatus::Paused;
+    entry.insert(b"paused".to_vec(), flag(paused));
+    entry.insert(b"force start".to_vec(), flag(torrent.is_force_started()));
+    entry.insert(b"sequential".to_vec(), flag(torrent.is_sequential()));
+    entry.insert(b"skip download".to_vec(), flag(torrent.skips_download()));
+    let backend = match torrent.backend() {
+        Backend::Positioned => "positioned",
+        Backend::Mmap => "mmap",
+    };
+    entry.insert(b"backend".to_vec(), text(backend));
+    let allocation = match torrent.allocation() {
+        Allocation::None => "none",
+        Allocation::Sparse => "sparse",
+        Allocation::Full => "full",
+    };
+    entry.insert(b"allocation".to_vec(), text(allocation));
+    let priorities = torrent
+        .file_priorities()
+        .iter()
+        .map(|p| BencodeValue::Int(*p as i64))
+        .collect();
+    entry.insert(b"priorities".to_vec(), BencodeValue::List(priorities));
+    let limits = torrent.limits();
+    entry.insert(b"download rate".to_vec(), limit(limits.download_rate));
+    entry.insert(b"upload rate".to_vec(), limit(limits.upload_rate));
+    entry.insert(
+        b"max peers".to_vec(),
+        limit(limits.max_peers.map(|n| n as u64)),
+    );
+    if let Some(goals) = torrent.seed_goals() {
+        entry.insert(b"seed goals".to_vec(), encode_goals(&goals));
+    }
+    BencodeValue::Dict(entry)
+}
+
+// None for entries too broken to make anything of
+fn decode_torrent(entry: &BencodeValue, dir: &Path, now: Instant) -> io::Result<Option<Torrent>> {
+    let Some(info_hash) = entry
+        .get(b"info-hash")
+        .and_then(|h| h.as_bytes())
+        .and_then(|h| h.try_into().ok())
+        .map(InfoHash)
+    else {
+        return Ok(None);
+    };
+    let path = dir
+        .join("torrents")
+        .join(format!("{}.torrent", info_hash.to_hex()));
+    let metainfo = statefile::load(&path, |buf| Metainfo::from_bytes(buf).ok())?
+        .filter(|metainfo| metainfo.info_hash == info_hash);
+    let flag = |key: &[u8]| entry.get(key).and_then(|v| v.as_int()) == Some(1);
+
+    let mut torrent = match metainfo {
+        Some(metainfo) => Torrent::new(metainfo, now),
+        None => {
+            let mut magnet = MagnetLink::new(info_hash);
+            magnet.display_name = entry.get(b"name").and_then(string);
+            Torrent::from_magnet(&magnet, now)
+        }
+    };
+    let tiers: Vec<Vec<String>> = entry
+        .get(b"trackers")
+        .and_then(|t| t.as_list())
+        .unwrap_or(&[])
+        .iter()
+        .map(|tier| {
+            tier.as_list()
+                .unwrap_or(&[])
+                .iter()
+                .filter_map(string)
+                .collect()
+        })
+        .collect();
+    torrent.replace_trackers(&tiers, now);
+    if let Some(path) = entry.get(b"save path").and_then(|p| p.as_bytes()) {
+        torrent.set_save_path(path_from_bytes(path));
+    }
+    torrent.set_label(entry.get(b"label").and_then(string));
+    torrent.set_backend(match entry.get(b"backend").and_then(string).as_deref() {
+        Some("mmap") => Backend::Mmap,
+        _ => Backend::Positioned,
+    });
+    torrent.set_allocation(match entry.get(b"allocation").and_then(string).as_deref() {
+        Some("sparse") => Allocation::Sparse,
+        Some("full") => Allocation::Full,
+        _ => Allocation::None,
+    });
+    let priorities = entry
+        .get(b"priorities")
+        .and_then(|p| p.as_list())
+        .unwrap_or(&[]);
+    for (file, priority) in priorities.iter().enumerate() {
+        let priority = match priority.as_int() {
+            Some(0) => Priority::Skip,
+            Some(1) => Priority::Low,
+            Some(3) => Priority::High,
+            _ => Priority::Normal,
+        };
+        torrent.set_file_priority(file, priority);
+    }
+    torrent.set_sequential(flag(b"sequential"));
+    torrent.set_skip_download(flag(b"skip download"));
+    torrent.set_limits(TorrentLimits {
+        download_rate: entry.get(b"download rate").and_then(decode_limit),
+        upload_rate: entry.get(b"upload rate").and_then(decode_limit),
+        max_peers: entry
+            .get(b"max peers")
+            .and_then(decode_limit)
+            .map(|n| n as usize),
+    });
+    torrent.set_seed_goals(entry.get(b"seed goals").map(decode_goals));
+    if flag(b"paused") {
+        torrent.set_status(TorrentStatus::Paused);
+    }
+    torrent.set_force_start(flag(b"force start"));
+    Ok(Some(torrent))
+}
+
+fn encode_goals(goals: &SeedGoals) -> BencodeValue {
+    let mut dict = BTreeMap::new();
+    // Thousandths, bencode has no floats
+    if let Some(ratio) = goals.ratio {
+        dict.insert(
+            b"ratio".to_vec(),
+            BencodeValue::Int((ratio * 1000.0).round() as i64),
+        );
+    }
+    for (key, time) in [
+        (&b"seed time"[..], goals.seed_time),
+        (b"idle time", goals.idle_time),
+    ] {
+        if let Some(time) = time {
+            dict.insert(key.to_vec(), BencodeValue::Int(time.as_secs() as i64));
+        }
+    }
+    let action = match goals.action {
+        SeedAction::Pause => "pause",
+        SeedAction::Remove => "remove",
+    };
+    dict.insert(b"action".to_vec(), text(action));
+    BencodeValue::Dict(dict)
+}
+
+fn decode_goals(value: &BencodeValue) -> SeedGoals {
+    let secs = |key: &[u8]| {
+        value
+            .get(key)
+            .and_then(decode_limit)
+            .map(Duration::from_secs)
+    };
+    SeedGoals {
+        ratio: value
+            .get(b"ratio")
+            .and_then(decode_limit)
+            .map(|n| n as f64 / 1000.0),
+        seed_time: secs(b"seed time"),
+        idle_time: secs(b"idle time"),
+        action: match value.get(b"action").and_then(string).as_deref() {
+            Some("remove") => SeedAction::Remove,
+            _ => SeedAction::Pause,
+        },
+    }
+}
+
+fn remove_stale(dir: &Path, extension: &str, kept: &HashSet<String>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let stale = path.extension().is_some_and(|ext| ext == extension)
+            && path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .is_some_and(|stem| !kept.contains(stem));
+        if stale {
+            fs::remove_file(&path)?;
+            let _ = fs::remove_file(statefile::backup_path(&path));
+        }
+    }
+    Ok(())
+}
+
+// -1 for no limit
+fn limit(value: Option<u64>) -> BencodeValue {
+    BencodeValue::Int(value.map_or(-1, |n| n as i64))
+}
+
+fn decode_limit(value: &BencodeValue) -> Option<u64> {
+    value.as_int().and_then(|n| u64::try_from(n).ok())
+}
+
+fn flag(value: bool) -> BencodeValue {
+    BencodeValue::Int(value as i64)
+}
+
+fn text(value: &str) -> BencodeValue {
+    BencodeValue::ByteStr(value.as_bytes().to_vec())
+}
+
+fn string(value: &BencodeValue) -> Option<String> {
+    value
+        .as_bytes()
+        .map(|b| String::from_utf8_lossy(b).into_owned())
+}
+
+// Paths are saved as the OS has them on unix, where they needn't be UTF-8
+#[cfg(unix)]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::OsStr::from_bytes(bytes).into()
+}
+
+#[cfg(not(unix))]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    String::from_utf8_lossy(bytes).into_owned().into()
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::schedule::BandwidthSchedule;
+    use crate::session::RemoveOptions;
+
+    fn dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("hurricane-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = dir("persist");
+        let now = Instant::now();
+        let mut session = Session::new();
+
+        let buf = include_bytes!("../bencode/tests/fixtures/sample.torrent");
+        let mut torrent = Torrent::new(Metainfo::from_bytes(buf).unwrap(), now);
+        torrent.set_save_path(dir.join("data"));
+        torrent.set_label(Some("linux".to_string()));
+        torrent.set_file_priority(0, Priority::High);
+        torrent.set_sequential(true);
+        torrent.set_allocation(Allocation::Sparse);
+        torrent.set_limits(TorrentLimits {
+            upload_rate: Some(1000),
+            ..TorrentLimits::default()
+        });
+        torrent.set_seed_goals(Some(SeedGoals {
+            ratio: Some(1.5),
+            action: SeedAction::Remove,
+            ..SeedGoals::default()
+        }));
+        torrent.picker_mut().unwrap().piece_verified(0);
+        torrent.on_uploaded(700);
+        session.add(torrent, now);
+
+        let mut magnet = MagnetLink::new(InfoHash([7; 20]));
+        magnet.display_name = Some("pending".to_string());
+        magnet.trackers = vec!["udp://tracker:80".to_string()];
+        let pending = session
+            .add(Torrent::from_magnet(&magnet, now), now)
+            .handle();
+        session.pause(&[pending]);
+        session.set_queue_position(pending, 0).unwrap();
+        let feed = MutableMagnet::new([5; 32], b"salt");
+        session.set_feed(pending, feed.clone(), now);
+        session.set_queue_limits(QueueLimits {
+            downloading: None,
+            seeding: Some(2),
+        });
+        let schedule: BandwidthSchedule = "mon 09:00-17:00 up=1K".parse().unwrap();
+        session.set_bandwidth_schedule(schedule.clone());
+
+        save(&session, &dir).unwrap();
+        let Restored { session, resume } = load(&dir, now).unwrap().unwrap();
+
+        assert_eq!(session.queue_limits().downloading, None);
+        assert_eq!(session.bandwidth_schedule(), &schedule);
+        let &[first, second] = session.queue() else {
+            panic!()
+        };
+        let magnet = session.get(first).unwrap();
+        assert_eq!(magnet.name(), "pending");
+        assert_eq!(magnet.status(), TorrentStatus::Paused);
+        assert_eq!(magnet.trackers().tiers(), [["udp://tracker:80"]]);
+        assert_eq!(session.feed(first), Some(&feed));
+        assert_eq!(session.feed(second), None);
+
+        let torrent = session.get(second).unwrap();
+        assert_eq!(torrent.label(), Some("linux"));
+        assert_eq!(torrent.save_path(), Some(dir.join("data").as_path()));
+        assert_eq!(torrent.file_priorities()[0], Priority::High);
+        assert!(torrent.is_sequential());
+        assert_eq!(torrent.allocation(), Allocation::Sparse);
+        assert_eq!(torrent.limits().upload_rate, Some(1000));
+        assert_eq!(torrent.seed_goals().unwrap().ratio, Some(1.5));
+        assert_eq!(torrent.status(), TorrentStatus::CheckingFiles);
+        assert_eq!(resume.len(), 1);
+        assert_eq!(resume[0].0, second);
+        assert!(resume[0].1.have.get(0));
+        assert_eq!(resume[0].1.uploaded, 700);
+
+        // Gone from the session, gone from the state directory
+        drop((magnet, torrent));
+        let mut session = session;
+        session.remove(&[second], RemoveOptions::default());
+        save(&session, &dir).unwrap();
+        assert_eq!(fs::read_dir(dir.join("torrents")).unwrap().count(), 0);
+        assert_eq!(fs::read_dir(dir.join("resume")).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,919 @@
+// Decides which blocks to request from which peer.
+// The default strategy is rarest-first: pieces that the fewest connected peers have get
+// downloaded first, so they spread through the swarm before those peers leave. Pieces we've
+// already started are always finished before new ones are started, which keeps the number of
+// half-done pieces (and the memory they pin) small.
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+
+use crate::bitfield::Bitfield;
+use crate::peer::{BLOCK_SIZE, Block, MAX_REQUEST_SIZE, MIN_REQUEST_SIZE};
+use crate::rng::Rng;
+
+// How many of the next pieces in line sequential mode picks among, rarest first. Enough that
+// peers downloading the same file in order don't all want the exact same piece from the swarm,
+// few enough that playback can start soon
+pub const SEQUENTIAL_WINDOW: usize = 5;
+
+// `PiecePicker::slot` of a piece that isn't in any bucket
+const UNBUCKETED: usize = usize::MAX;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub enum Priority {
+    // Never download
+    Skip,
+    Low,
+    Normal,
+    High,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum BlockState {
+    Open,
+    Requested,
+    Received,
+}
+
+#[derive(Debug)]
+struct PartialPiece {
+    // The request size when the piece was started. Changing it only affects pieces started later
+    block_size: u32,
+    blocks: Vec<BlockState>,
+}
+
+impl PartialPiece {
+    fn received(&self) -> usize {
+        self.blocks
+            .iter()
+            .filter(|b| **b == BlockState::Received)
+            .count()
+    }
+}
+
+#[derive(Debug)]
+pub struct PiecePicker {
+    piece_length: u32,
+    total_length: u64,
+    have: Bitfield,
+    // How many connected peers have each piece
+    availability: Vec<u32>,
+    priority: Vec<Priority>,
+    partial: HashMap<u32, PartialPiece>,
+    // The pieces we want and haven't started, grouped by priority and then availability, so
+    // picking can walk them rarest first without sorting them all on every call
+    buckets: BTreeMap<(Reverse<Priority>, u32), Vec<u32>>,
+    // Where each piece sits in its bucket, `UNBUCKETED` if it isn't in one
+    slot: Vec<usize>,
+    // How much we ask for per request
+    block_size: u32,
+    // Pieces in order instead of rarest first, for playing media while it downloads
+    sequential: bool,
+    rng: Rng,
+}
+
+impl PiecePicker {
+    pub fn new(num_pieces: usize, piece_length: u32, total_length: u64) -> Self {
+        PiecePicker::with_rng(num_pieces, piece_length, total_length, Rng::new())
+    }
+
+    pub fn with_rng(num_pieces: usize, piece_length: u32, total_length: u64, rng: Rng) -> Self {
+        let mut picker = PiecePicker {
+            piece_length,
+            total_length,
+            have: Bitfield::new(num_pieces),
+            availability: vec![0; num_pieces],
+            priority: vec![Priority::Normal; num_pieces],
+            partial: HashMap::new(),
+            buckets: BTreeMap::new(),
+            slot: vec![UNBUCKETED; num_pieces],
+            block_size: BLOCK_SIZE,
+            sequential: false,
+            rng,
+        };
+        picker.rebucket_all();
+        picker
+    }
+
+    pub fn is_sequential(&self) -> bool {
+        self.sequential
+    }
+
+    // Can be switched either way at any time, pieces already started are finished regardless
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    // For peers that choke on 16 KiB requests, or to cut the request count on fast links. Clamped
+    // to what peers can be expected to serve
+    pub fn set_block_size(&mut self, size: u32) {
+        self.block_size = size.clamp(MIN_REQUEST_SIZE, MAX_REQUEST_SIZE);
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.availability.len()
+    }
+
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub fn availability(&self, piece: u32) -> u32 {
+        self.availability[piece as usize]
+    }
+
+    // The last piece is usually shorter than the rest
+    pub fn piece_size(&self, piece: u32) -> u32 {
+        let start = piece as u64 * self.piece_length as u64;
+        (self.total_length - start).min(self.piece_length as u64) as u32
+    }
+
+    // At the current block size
+    pub fn blocks_in_piece(&self, piece: u32) -> usize {
+        self.piece_size(piece).div_ceil(self.block_size) as usize
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have.all()
+    }
+
+    // Priority hooks. Anything at `Skip` is never picked
+    pub fn priority(&self, piece: u32) -> Priority {
+        self.priority[piece as usize]
+    }
+
+    pub fn set_priority(&mut self, piece: u32, priority: Priority) {
+        self.unbucket(piece);
+        self.priority[piece as usize] = priority;
+        self.bucket(piece);
+    }
+
+    // Sets every piece's priority from the files it overlaps, `files` being their lengths in
+    // torrent order. A piece gets the highest priority of its files: one shared by a file we want
+    // and a skipped one still has to be downloaded in full to be verified
+    pub fn set_file_priorities(&mut self, files: &[u64], priorities: &[Priority]) {
+        self.priority.fill(Priority::Skip);
+        let piece_length = self.piece_length as u64;
+        let mut start = 0;
+        for (length, priority) in files.iter().zip(priorities) {
+            let end = start + length;
+            if *length > 0 {
+                let pieces = (start / piece_length) as usize..end.div_ceil(piece_length) as usize;
+                for piece in &mut self.priority[pieces] {
+                    *piece = (*piece).max(*priority);
+                }
+            }
+            start = end;
+        }
+        self.rebucket_all();
+    }
+
+    // A peer connected and told us what it has
+    pub fn add_peer(&mut self, bitfield: &Bitfield) {
+        for piece in bitfield.iter_ones() {
+            self.set_availability(piece as u32, self.availability[piece] + 1);
+        }
+    }
+
+    // A peer disconnected, so whatever it had is no longer available from it
+    pub fn remove_peer(&mut self, bitfield: &Bitfield) {
+        for piece in bitfield.iter_ones() {
+            self.set_availability(piece as u32, self.availability[piece].saturating_sub(1));
+        }
+    }
+
+    pub fn peer_have(&mut self, piece: u32) {
+        self.set_availability(piece, self.availability[piece as usize] + 1);
+    }
+
+    // We already have this piece (e.g. from resume data)
+    pub fn mark_have(&mut self, piece: u32) {
+        self.unbucket(piece);
+        self.have.set(piece as usize);
+        self.partial.remove(&piece);
+    }
+
+    // Forgets every piece we have or started, ahead of a recheck. Availability, priorities and
+    // the block size stay
+    pub fn reset(&mut self) {
+        self.have = Bitfield::new(self.num_pieces());
+        self.partial.clear();
+        self.rebucket_all();
+    }
+
+    // Blocks of unfinished pieces that have arrived, for resume data. Bit i is the i-th 16 KiB of
+    // the piece whatever our block size, so resume data means the same after changing it (and
+    // matches what `Storage::recover_piece` finds). A 16 KiB stretch counts once all of it is in
+    pub fn partial_progress(&self) -> Vec<(u32, Bitfield)> {
+        let mut progress: Vec<(u32, Bitfield)> = self
+            .partial
+            .iter()
+            .filter_map(|(piece, partial)| {
+                let size = self.piece_size(*piece);
+                let mut received = Bitfield::new(size.div_ceil(BLOCK_SIZE) as usize);
+                for (i, start) in (0..size).step_by(BLOCK_SIZE as usize).enumerate() {
+                    let end = (start + BLOCK_SIZE).min(size);
+                    if partial.blocks[spanned(start, end, partial.block_size)]
+                        .iter()
+                        .all(|b| *b == BlockState::Received)
+                    {
+                        received.set(i);
+                    }
+                }
+                (!received.none()).then_some((*piece, received))
+            })
+            .collect();
+        progress.sort_by_key(|(piece, _)| *piece);
+        progress
+    }
+
+    // Picks a piece back up where resume data left it. The blocks must already be on disk
+    pub fn restore_partial(&mut self, piece: u32, received: &Bitfield) {
+        if piece as usize >= self.num_pieces() || self.have.get(piece as usize) {
+            return;
+        }
+        self.start_piece(piece);
+        let size = self.piece_size(piece);
+        let partial = self.partial.get_mut(&piece).unwrap();
+        let block_size = partial.block_size;
+        for (i, state) in partial.blocks.iter_mut().enumerate() {
+            let start = i as u32 * block_size;
+            let end = (start + block_size).min(size);
+            if spanned(start, end, BLOCK_SIZE).all(|u| u < received.len() && received.get(u)) {
+                *state = BlockState::Received;
+            }
+        }
+    }
+
+    // Pick up to `count` blocks to request from a peer that has the pieces in `peer_has`
+    pub fn pick(&mut self, peer_has: &Bitfield, count: usize) -> Vec<Block> {
+        self.pick_impl(peer_has, count, false)
+    }
+
+    // Picking for a peer that is snubbing us. It stays out of pieces other peers are working on
+    // so it can't hold them up, and goes for the most common pieces instead of the rarest: if it
+    // never delivers, we've lost nothing that's hard to get elsewhere
+    pub fn pick_snubbed(&mut self, peer_has: &Bitfield, count: usize) -> Vec<Block> {
+        self.pick_impl(peer_has, count, true)
+    }
+
+    // Picking for a web seed (BEP 19). It has every piece and each request costs an HTTP round
+    // trip, so it gets a run of whole consecutive pieces that can go out as one range request.
+    // The run starts at the rarest piece nobody has started, which is where peers need the most
+    // help. Once every piece is started it helps finish the one with the most blocks left
+    pub fn pick_web_seed(&mut self, max_pieces: usize) -> Vec<Block> {
+        let mut picked = Vec::new();
+        let sequential = self.sequential;
+        let first = (0..self.num_pieces() as u32)
+            .filter(|p| self.is_wanted(*p) && !self.partial.contains_key(p))
+            .min_by_key(|p| {
+                let availability = if sequential { 0 } else { self.availability(*p) };
+                (Reverse(self.priority(*p)), availability, *p)
+            });
+
+        let Some(first) = first else {
+            let open = |partial: &PartialPiece| {
+                partial
+                    .blocks
+                    .iter()
+                    .filter(|b| **b == BlockState::Open)
+                    .count()
+            };
+            let piece = self
+                .partial
+                .iter()
+                .filter(|(p, partial)| self.priority(**p) != Priority::Skip && open(partial) > 0)
+                .max_by_key(|(p, partial)| (open(partial), Reverse(**p)))
+                .map(|(p, _)| *p);
+            if let Some(piece) = piece {
+                self.pick_from_piece(piece, usize::MAX, &mut picked);
+            }
+            return picked;
+        };
+
+        let mut piece = first;
+        while (piece - first) < max_pieces as u32
+            && (piece as usize) < self.num_pieces()
+            && self.is_wanted(piece)
+            && !self.partial.contains_key(&piece)
+        {
+            self.start_piece(piece);
+            self.pick_from_piece(piece, usize::MAX, &mut picked);
+            piece += 1;
+        }
+        picked
+    }
+
+    // All of one piece, for a web seed to fetch again after what it sent failed its hash check.
+    // Nothing if someone else has started it meanwhile
+    pub fn pick_piece(&mut self, piece: u32) -> Vec<Block> {
+        let mut picked = Vec::new();
+        if (piece as usize) < self.num_pieces()
+            && self.is_wanted(piece)
+            && !self.partial.contains_key(&piece)
+        {
+            self.start_piece(piece);
+            self.pick_from_piece(piece, usize::MAX, &mut picked);
+        }
+        picked
+    }
+
+    fn pick_impl(&mut self, peer_has: &Bitfield, count: usize, snubbed: bool) -> Vec<Block> {
+        let mut picked = Vec::new();
+        if count == 0 {
+            return picked;
+        }
+
+        // Finish what we started first, most complete pieces first (or earliest, in sequential
+        // mode)
+        let sequential = self.sequential && !snubbed;
+        let mut started: Vec<u32> = self
+            .partial
+            .keys()
+            .copied()
+            .filter(|p| !snubbed && peer_has.get(*p as usize))
+            .filter(|p| self.priority(*p) != Priority::Skip)
+            .collect();
+        started.sort_by_key(|p| {
+            let received = if sequential {
+                0
+            } else {
+                self.partial[p].received()
+            };
+            (Reverse(self.priority(*p)), Reverse(received), *p)
+        });
+
+        for piece in started {
+            self.pick_from_piece(piece, count, &mut picked);
+            if picked.len() >= count {
+                return picked;
+            }
+        }
+
+        // Then new pieces: highest priority, then rarest, starting each bucket at a random piece
+        // so peers started at the same time don't all go after the exact same pieces. Sequential
+        // mode only goes rarest first within the window of next pieces, and in order past it.
+        // Pieces are only started once all are chosen, as starting one takes it out of its bucket
+        let mut chosen = Vec::new();
+        let mut blocks = picked.len();
+        let mut choose = |picker: &Self, piece: u32| {
+            if !peer_has.get(piece as usize) {
+                return false;
+            }
+            chosen.push(piece);
+            blocks += picker.blocks_in_piece(piece);
+            blocks >= count
+        };
+        if sequential {
+            let window_end = self.sequential_window_end();
+            let mut priorities: Vec<Reverse<Priority>> =
+                self.buckets.keys().map(|(priority, _)| *priority).collect();
+            priorities.dedup();
+            'walk: for Reverse(priority) in priorities {
+                let pieces: Vec<u32> = (0..window_end)
+                    .filter(|p| self.is_bucketed(*p) && self.priority(*p) == priority)
+                    .collect();
+                let mut window: Vec<(u32, u64, u32)> = pieces
+                    .into_iter()
+                    .map(|p| (self.availability(p), self.rng.next_u64(), p))
+                    .collect();
+                window.sort_unstable();
+                for (_, _, piece) in window {
+                    if choose(self, piece) {
+                        break 'walk;
+                    }
+                }
+                for piece in window_end..self.num_pieces() as u32 {
+                    if self.is_bucketed(piece)
+                        && self.priority(piece) == priority
+                        && choose(self, piece)
+                    {
+                        break 'walk;
+                    }
+                }
+            }
+        } else {
+            let mut keys: Vec<(Reverse<Priority>, u32)> = self.buckets.keys().copied().collect();
+            if snubbed {
+                keys.sort_unstable_by_key(|(priority, availability)| {
+                    (*priority, Reverse(*availability))
+                });
+            }
+            'walk: for key in keys {
+                let len = self.buckets[&key].len();
+                let first = self.rng.below(len);
+                for i in 0..len {
+                    let piece = self.buckets[&key][(first + i) % len];
+                    if choose(self, piece) {
+                        break 'walk;
+                    }
+                }
+            }
+        }
+
+        for piece in chosen {
+            self.start_piece(piece);
+            self.pick_from_piece(piece, count, &mut picked);
+        }
+        picked
+    }
+
+    // Returns true when this block completed its piece, which then needs to be hash checked
+    pub fn on_block_received(&mut self, block: &Block) -> bool {
+        let Some(partial) = self.partial.get_mut(&block.piece) else {
+            return false;
+        };
+        let idx = (block.offset / partial.block_size) as usize;
+        let Some(state) = partial.blocks.get_mut(idx) else {
+            return false;
+        };
+
+        *state = BlockState::Received;
+        partial.blocks.iter().all(|b| *b == BlockState::Received)
+    }
+
+    // The request was cancelled, rejected or timed out, so someone else may have the block
+    pub fn abort_request(&mut self, block: &Block) {
+        if let Some(state) = self
+            .partial
+            .get_mut(&block.piece)
+            .and_then(|p| p.blocks.get_mut((block.offset / p.block_size) as usize))
+            && *state == BlockState::Requested
+        {
+            *state = BlockState::Open;
+        }
+    }
+
+    pub fn piece_verified(&mut self, piece: u32) {
+        self.mark_have(piece);
+    }
+
+    // Hash check failed, start the whole piece over
+    pub fn piece_failed(&mut self, piece: u32) {
+        self.partial.remove(&piece);
+        self.bucket(piece);
+    }
+
+    // One past the last piece of the sequential window: the first `SEQUENTIAL_WINDOW` pieces we
+    // still want, counting from the start
+    fn sequential_window_end(&self) -> u32 {
+        (0..self.num_pieces() as u32)
+            .filter(|p| self.is_wanted(*p))
+            .nth(SEQUENTIAL_WINDOW)
+            .unwrap_or(self.num_pieces() as u32)
+    }
+
+    fn is_wanted(&self, piece: u32) -> bool {
+        !self.have.get(piece as usize) && self.priority(piece) != Priority::Skip
+    }
+
+    // Wanted and not started yet
+    fn is_bucketed(&self, piece: u32) -> bool {
+        self.slot[piece as usize] != UNBUCKETED
+    }
+
+    fn bucket_key(&self, piece: u32) -> (Reverse<Priority>, u32) {
+        (Reverse(self.priority(piece)), self.availability(piece))
+    }
+
+    // Puts a piece in its bucket if it belongs in one. Anything that changes a piece's priority or
+    // availability takes it out with `unbucket` first and puts it back with this after
+    fn bucket(&mut self, piece: u32) {
+        if self.is_bucketed(piece) || !self.is_wanted(piece) || self.partial.contains_key(&piece) {
+            return;
+        }
+        let bucket = self.buckets.entry(self.bucket_key(piece)).or_default();
+        self.slot[piece as usize] = bucket.len();
+        bucket.push(piece);
+    }
+
+    fn unbucket(&mut self, piece: u32) {
+        let slot = std::mem::replace(&mut self.slot[piece as usize], UNBUCKETED);
+        if slot == UNBUCKETED {
+            return;
+        }
+        let key = self.bucket_key(piece);
+        let bucket = self.buckets.get_mut(&key).unwrap();
+        bucket.swap_remove(slot);
+        match bucket.get(slot) {
+            Some(moved) => self.slot[*moved as usize] = slot,
+            None if bucket.is_empty() => {
+                self.buckets.remove(&key);
+            }
+            None => {}
+        }
+    }
+
+    fn rebucket_all(&mut self) {
+        self.buckets.clear();
+        self.slot.fill(UNBUCKETED);
+        for piece in 0..self.num_pieces() as u32 {
+            self.bucket(piece);
+        }
+    }
+
+    fn set_availability(&mut self, piece: u32, availability: u32) {
+        self.unbucket(piece);
+        self.availability[piece as usize] = availability;
+        self.bucket(piece);
+    }
+
+    fn start_piece(&mut self, piece: u32) {
+        self.unbucket(piece);
+        let blocks = self.blocks_in_piece(piece);
+        self.partial.insert(
+            piece,
+            PartialPiece {
+                block_size: self.block_size,
+                blocks: vec![BlockState::Open; blocks],
+            },
+        );
+    }
+
+    fn pick_from_piece(&mut self, piece: u32, count: usize, picked: &mut Vec<Block>) {
+        let size = self.piece_size(piece);
+        let partial = self.partial.get_mut(&piece).unwrap();
+
+        for (i, state) in partial.blocks.iter_mut().enumerate() {
+            if picked.len() >= count {
+                return;
+            }
+            if *state != BlockState::Open {
+                continue;
+            }
+
+            let offset = i as u32 * partial.block_size;
+            let length = partial.block_size.min(size - offset);
+            *state = BlockState::Requested;
+            picked.push(Block::new(piece, offset, length));
+        }
+    }
+}
+
+// Indexes of the `unit` sized chunks that bytes `start..end` of a piece touch
+fn spanned(start: u32, end: u32, unit: u32) -> Range<usize> {
+    (start / unit) as usize..end.div_ceil(unit) as usize
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    const PIECE: u32 = 2 * BLOCK_SIZE;
+
+    fn picker(num_pieces: usize) -> PiecePicker {
+        PiecePicker::with_rng(
+            num_pieces,
+            PIECE,
+            num_pieces as u64 * PIECE as u64,
+            Rng::with_seed(1),
+        )
+    }
+
+    fn bitfield(len: usize, pieces: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(len);
+        for piece in pieces {
+            bitfield.set(*piece);
+        }
+        bitfield
+    }
+
+    #[test]
+    fn test_picks_rarest() {
+        let mut picker = picker(4);
+        picker.add_peer(&Bitfield::full(4));
+        picker.add_peer(&bitfield(4, &[0, 1, 3]));
+        picker.add_peer(&bitfield(4, &[0, 3]));
+
+        // Piece 2 is only on one peer, piece 1 on two
+        let blocks = picker.pick(&Bitfield::full(4), 3);
+
+        assert_eq!(
+            blocks,
+            vec![
+                Block::new(2, 0, BLOCK_SIZE),
+                Block::new(2, BLOCK_SIZE, BLOCK_SIZE),
+                Block::new(1, 0, BLOCK_SIZE),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_only_picks_what_peer_has() {
+        let mut picker = picker(4);
+        picker.add_peer(&bitfield(4, &[3]));
+
+        let blocks = picker.pick(&bitfield(4, &[3]), 10);
+
+        assert!(blocks.iter().all(|b| b.piece == 3));
+        assert_eq!(blocks.len(), 2);
+    }
+
+    #[test]
+    fn test_finishes_partial_first() {
+        let mut picker = picker(4);
+        picker.add_peer(&Bitfield::full(4));
+        picker.add_peer(&bitfield(4, &[0, 1, 2]));
+
+        // Start piece 3 (the rarest) with one block, then ask again from a peer that has all
+        let first = picker.pick(&Bitfield::full(4), 1);
+        assert_eq!(first, vec![Block::new(3, 0, BLOCK_SIZE)]);
+
+        let second = picker.pick(&Bitfield::full(4), 1);
+        assert_eq!(second, vec![Block::new(3, BLOCK_SIZE, BLOCK_SIZE)]);
+    }
+
+    #[test]
+    fn test_no_duplicate_requests() {
+        let mut picker = picker(2);
+        picker.add_peer(&Bitfield::full(2));
+
+        let a = picker.pick(&Bitfield::full(2), 2);
+        let b = picker.pick(&Bitfield::full(2), 2);
+
+        assert!(a.iter().all(|block| !b.contains(block)));
+        assert!(picker.pick(&Bitfield::full(2), 2).is_empty());
+    }
+
+    #[test]
+    fn test_abort_makes_block_pickable() {
+        let mut picker = picker(1);
+        picker.add_peer(&Bitfield::full(1));
+
+        let blocks = picker.pick(&Bitfield::full(1), 1);
+        picker.abort_request(&blocks[0]);
+
+        assert_eq!(picker.pick(&Bitfield::full(1), 1), blocks);
+    }
+
+    #[test]
+    fn test_partial_progress_roundtrip() {
+        let mut original = picker(2);
+        original.add_peer(&Bitfield::full(2));
+        let blocks = original.pick(&bitfield(2, &[1]), 2);
+        original.on_block_received(&blocks[1]);
+
+        let progress = original.partial_progress();
+        assert_eq!(progress, vec![(1, bitfield(2, &[1]))]);
+
+        let mut restored = picker(2);
+        restored.restore_partial(1, &progress[0].1);
+        assert_eq!(restored.partial_progress(), progress);
+        // Only the missing block is left to fetch
+        assert_eq!(
+            restored.pick(&Bitfield::full(2), 4)[0],
+            Block::new(1, 0, BLOCK_SIZE)
+        );
+        assert!(restored.on_block_received(&Block::new(1, 0, BLOCK_SIZE)));
+    }
+
+    #[test]
+    fn test_custom_block_size() {
+        let mut original = picker(2);
+        original.add_peer(&Bitfield::full(2));
+        original.set_block_size(BLOCK_SIZE / 2);
+        let blocks = original.pick(&bitfield(2, &[1]), 4);
+        assert_eq!(blocks.len(), 4);
+        assert_eq!(blocks[3], Block::new(1, 3 * BLOCK_SIZE / 2, BLOCK_SIZE / 2));
+
+        // Half of the first 16 KiB isn't enough to show up in resume data
+        original.on_block_received(&blocks[0]);
+        assert!(original.partial_progress().is_empty());
+        original.on_block_received(&blocks[1]);
+        original.on_block_received(&blocks[3]);
+        let progress = original.partial_progress();
+        assert_eq!(progress, vec![(1, bitfield(2, &[0]))]);
+
+        // Resumed with bigger requests, each covering a whole piece here
+        let mut restored = picker(2);
+        restored.set_block_size(4 * BLOCK_SIZE);
+        restored.restore_partial(1, &bitfield(2, &[0, 1]));
+        restored.restore_partial(0, &progress[0].1);
+        assert_eq!(
+            restored.pick(&Bitfield::full(2), 4),
+            vec![Block::new(0, 0, PIECE)]
+        );
+        assert_eq!(restored.partial_progress(), vec![(1, bitfield(2, &[0, 1]))]);
+
+        restored.set_block_size(1);
+        assert_eq!(restored.block_size(), MIN_REQUEST_SIZE);
+    }
+
+    #[test]
+    fn test_file_priorities() {
+        let mut picker = picker(4);
+        picker.add_peer(&Bitfield::full(4));
+        // The second file starts halfway through piece 1 and ends halfway through piece 2
+        let half = PIECE as u64 / 2;
+        let files = [3 * half, 0, 2 * half, 3 * half];
+        picker.set_file_priorities(
+            &files,
+            &[
+                Priority::Skip,
+                Priority::High,
+                Priority::High,
+                Priority::Low,
+            ],
+        );
+
+        assert_eq!(picker.priority(0), Priority::Skip);
+        assert_eq!(picker.priority(1), Priority::High);
+        assert_eq!(picker.priority(2), Priority::High);
+        assert_eq!(picker.priority(3), Priority::Low);
+        let mut pieces: Vec<u32> = picker
+            .pick(&Bitfield::full(4), 6)
+            .iter()
+            .map(|b| b.piece)
+            .collect();
+        // 1 and 2 are tied, whichever comes first
+        pieces[..4].sort();
+        assert_eq!(pieces, vec![1, 1, 2, 2, 3, 3]);
+    }
+
+    #[test]
+    fn test_sequential() {
+        let mut picker = picker(10);
+        picker.add_peer(&Bitfield::full(10));
+        // Pieces 3 and 8 are the rarest, but 8 is past the window
+        picker.add_peer(&bitfield(10, &[0, 1, 2, 4, 5, 6, 7, 9]));
+        picker.mark_have(0);
+        picker.set_sequential(true);
+
+        let pieces = |blocks: Vec<Block>| blocks.iter().map(|b| b.piece).collect::<Vec<_>>();
+        let first = pieces(picker.pick(&Bitfield::full(10), 2));
+        assert_eq!(first, vec![3, 3]);
+        let rest = pieces(picker.pick(&Bitfield::full(10), 12));
+        let mut window = rest[..8].to_vec();
+        window.sort();
+        assert_eq!(window, vec![1, 1, 2, 2, 4, 4, 5, 5]);
+        assert_eq!(rest[8..], [6, 6, 7, 7]);
+
+        // Switched back, the rarest piece goes first again
+        picker.set_sequential(false);
+        assert_eq!(pieces(picker.pick(&Bitfield::full(10), 1)), vec![8]);
+    }
+
+    #[test]
+    fn test_piece_completion() {
+        let mut picker = picker(1);
+        picker.add_peer(&Bitfield::full(1));
+        let blocks = picker.pick(&Bitfield::full(1), 2);
+
+        assert!(!picker.on_block_received(&blocks[0]));
+        assert!(picker.on_block_received(&blocks[1]));
+
+        picker.piece_verified(0);
+        assert!(picker.is_complete());
+        assert!(picker.pick(&Bitfield::full(1), 2).is_empty());
+    }
+
+    #[test]
+    fn test_failed_piece_is_repicked() {
+        let mut picker = picker(1);
+        picker.add_peer(&Bitfield::full(1));
+        let blocks = picker.pick(&Bitfield::full(1), 2);
+        picker.on_block_received(&blocks[0]);
+        picker.on_block_received(&blocks[1]);
+
+        picker.piece_failed(0);
+
+        assert_eq!(picker.pick(&Bitfield::full(1), 2), blocks);
+    }
+
+    #[test]
+    fn test_priority_override() {
+        let mut picker = picker(3);
+        picker.add_peer(&Bitfield::full(3));
+        picker.add_peer(&bitfield(3, &[0, 1]));
+        picker.set_priority(2, Priority::Skip);
+        picker.set_priority(0, Priority::High);
+
+        let blocks = picker.pick(&Bitfield::full(3), 10);
+
+        assert_eq!(blocks[0].piece, 0);
+        assert!(blocks.iter().all(|b| b.piece != 2));
+    }
+
+    #[test]
+    fn test_short_last_piece() {
+        let mut picker = PiecePicker::with_rng(2, PIECE, PIECE as u64 + 100, Rng::with_seed(1));
+        picker.add_peer(&bitfield(2, &[1]));
+
+        assert_eq!(picker.piece_size(1), 100);
+        assert_eq!(
+            picker.pick(&bitfield(2, &[1]), 5),
+            vec![Block::new(1, 0, 100)]
+        );
+    }
+
+    #[test]
+    fn test_snubbed_picks_common_pieces() {
+        let mut picker = picker(3);
+        picker.add_peer(&Bitfield::full(3));
+        picker.add_peer(&bitfield(3, &[0, 1]));
+        picker.add_peer(&bitfield(3, &[1]));
+
+        let blocks = picker.pick_snubbed(&Bitfield::full(3), 1);
+
+        assert_eq!(blocks, vec![Block::new(1, 0, BLOCK_SIZE)]);
+    }
+
+    #[test]
+    fn test_snubbed_skips_partial_pieces() {
+        let mut picker = picker(2);
+        picker.add_peer(&Bitfield::full(2));
+        picker.add_peer(&bitfield(2, &[0]));
+        picker.pick(&Bitfield::full(2), 1);
+
+        let blocks = picker.pick_snubbed(&Bitfield::full(2), 1);
+
+        // Piece 1 is already started by a healthy peer, so the snubbed one gets a fresh piece
+        assert_eq!(blocks, vec![Block::new(0, 0, BLOCK_SIZE)]);
+    }
+
+    #[test]
+    fn test_remove_peer() {
+        let mut picker = picker(2);
+        let peer = bitfield(2, &[1]);
+        picker.add_peer(&peer);
+        picker.peer_have(0);
+        picker.remove_peer(&peer);
+
+        assert_eq!(picker.availability(0), 1);
+        assert_eq!(picker.availability(1), 0);
+    }
+
+    #[test]
+    fn test_web_seed_picks_consecutive_rare_pieces() {
+        let mut picker = picker(5);
+        picker.add_peer(&bitfield(5, &[0, 1, 4]));
+        picker.mark_have(3);
+
+        let blocks = picker.pick_web_seed(4);
+
+        // Pieces 2 and 3 nobody has, but we already have 3, so the run stops there
+        assert_eq!(
+            blocks,
+            vec![
+                Block::new(2, 0, BLOCK_SIZE),
+                Block::new(2, BLOCK_SIZE, BLOCK_SIZE)
+            ]
+        );
+        assert_eq!(picker.pick_web_seed(2).len(), 4);
+    }
+
+    #[test]
+    fn test_web_seed_helps_partial_pieces() {
+        let mut picker = picker(1);
+        picker.add_peer(&Bitfield::full(1));
+        picker.pick(&Bitfield::full(1), 1);
+
+        let blocks = picker.pick_web_seed(4);
+
+        assert_eq!(blocks, vec![Block::new(0, BLOCK_SIZE, BLOCK_SIZE)]);
+        assert!(picker.pick_web_seed(4).is_empty());
+    }
+
+    #[test]
+    fn test_pick_whole_piece() {
+        let mut picker = picker(3);
+        picker.mark_have(2);
+
+        assert_eq!(picker.pick_piece(0).len(), 2);
+        // Started already, had already, or not there at all
+        assert!(picker.pick_piece(0).is_empty());
+        assert!(picker.pick_piece(2).is_empty());
+        assert!(picker.pick_piece(3).is_empty());
+    }
+
+    #[test]
+    fn test_empty_pick_starts_nothing() {
+        let mut picker = picker(2);
+        picker.add_peer(&Bitfield::full(2));
+
+        assert!(picker.pick(&Bitfield::full(2), 0).is_empty());
+        assert!(picker.partial.is_empty());
+        assert_eq!(picker.pick_web_seed(2).len(), 4);
+    }
+
+    #[test]
+    fn test_rarest_follows_availability() {
+        let mut picker = picker(3);
+        let everything = Bitfield::full(3);
+        let some = bitfield(3, &[0, 1]);
+        picker.add_peer(&everything);
+        picker.add_peer(&some);
+        picker.peer_have(1);
+        assert_eq!(picker.pick(&everything, 1)[0].piece, 2);
+
+        // Piece 0 is the rarest left once that peer goes, then piece 2 comes back after failing
+        picker.remove_peer(&some);
+        assert_eq!(picker.pick(&everything, 3)[1].piece, 0);
+        picker.piece_failed(2);
+        assert_eq!(picker.pick(&everything, 1)[0].piece, 2);
+    }
+}
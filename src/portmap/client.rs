@@ -0,0 +1,341 @@
+// Runs `PortMapper` against the gateway, blocking. Each port due for mapping is tried over
+// NAT-PMP first, then over UPnP; the gateway is only searched for over SSDP the first time
+// NAT-PMP doesn't work out, and whichever method mapped a port is used again to remove it.
+// `spawn` runs one on a thread of its own, for an event loop that can't wait on the gateway.
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::natpmp::{NATPMP_PORT, Request, Response};
+use super::upnp::{self, Gateway};
+use super::{LEASE, MappingState, MappingStatus, Method, PortMapError, PortMapper, Protocol};
+
+// NAT-PMP retransmits start at this and double, RFC 6886 style but with fewer tries
+const NATPMP_FIRST_WAIT: Duration = Duration::from_millis(250);
+const NATPMP_TRIES: u32 = 4;
+
+// How often a spawned client looks for mappings that are due
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub struct PortMapClient {
+    mapper: PortMapper,
+    // NAT-PMP gateway, None once it's stopped answering
+    natpmp: Option<Ipv4Addr>,
+    // None until searched for; Some(None) when the search came up empty
+    upnp: Option<Option<Gateway>>,
+    // For HTTP requests to the UPnP gateway and the SSDP search
+    timeout: Duration,
+}
+
+impl PortMapClient {
+    // `gateway` is where NAT-PMP requests go, usually `natpmp::default_gateway()`
+    pub fn new(mapper: PortMapper, gateway: Option<Ipv4Addr>) -> Self {
+        PortMapClient {
+            mapper,
+            natpmp: gateway,
+            upnp: None,
+            timeout: Duration::from_secs(3),
+        }
+    }
+
+    pub fn mapper(&self) -> &PortMapper {
+        &self.mapper
+    }
+
+    pub fn mapper_mut(&mut self) -> &mut PortMapper {
+        &mut self.mapper
+    }
+
+    // Maps or renews whatever is due. Failures are recorded in the mapper's status and retried
+    // with backoff, only local socket errors come back from here
+    pub fn poll(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        for (protocol, port) in self.mapper.due(now) {
+            match self.map(protocol, port)? {
+                Ok((method, external_port, lifetime)) => self.mapper.on_mapped(
+                    protocol,
+                    port,
+                    method,
+                    external_port,
+                    lifetime,
+                    Instant::now(),
+                ),
+                Err(err) => self.mapper.on_failed(protocol, port, err, Instant::now()),
+            }
+        }
+        Ok(())
+    }
+
+    // Removes the mapping for one port from the router and stops renewing it
+    pub fn remove(&mut self, protocol: Protocol, port: u16) -> Result<(), PortMapError> {
+        match self.mapper.remove(protocol, port) {
+            Some((method, external_port)) => self.unmap(protocol, port, method, external_port),
+            None => Ok(()),
+        }
+    }
+
+    // Removes every mapping we made. Call on shutdown: leases run out eventually, but until they
+    // do the router forwards to a port nobody listens on
+    pub fn shutdown(&mut self) -> Vec<PortMapError> {
+        self.mapper
+            .mapped()
+            .into_iter()
+            .filter_map(|(protocol, port, _, _)| self.remove(protocol, port).err())
+            .collect()
+    }
+
+    // Moves the client to a thread of its own. Dropping the handle removes the mappings there
+    // without waiting for it
+    pub fn spawn(self) -> PortMapHandle {
+        let (commands, received) = mpsc::channel();
+        let (done, finished) = mpsc::channel();
+        let status = Arc::new(Mutex::new((vec![], None)));
+        let shared = Arc::clone(&status);
+        thread::spawn(move || {
+            let mut client = self;
+            loop {
+                match received.recv_timeout(POLL_INTERVAL) {
+                    Ok(Command::Add(protocol, port)) => {
+                        client.mapper.add(protocol, port, Instant::now())
+                    }
+                    Ok(Command::Remove(protocol, port)) => {
+                        let _ = client.remove(protocol, port);
+                    }
+                    Ok(Command::Wake) => client.mapper.on_wake(Instant::now()),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+                if client.poll().is_err() {
+                    break;
+                }
+                *shared.lock().unwrap() = (client.mapper.status(), client.mapper.external_ip());
+            }
+            let _ = done.send(client.shutdown());
+        });
+        PortMapHandle {
+            commands: Some(commands),
+            status,
+            finished,
+        }
+    }
+
+    fn map(
+        &mut self,
+        protocol: Protocol,
+        port: u16,
+    ) -> io::Result<Result<(Method, u16, Duration), PortMapError>> {
+        if let Some(gateway) = self.natpmp {
+            let request = Request::Map {
+                protocol,
+                internal_port: port,
+                external_port: port,
+                lifetime: LEASE,
+            };
+            match natpmp_request(gateway, &request)? {
+                Ok(Response::Map {
+                    external_port,
+                    lifetime,
+                    ..
+                }) => {
+                    if self.mapper.external_ip().is_none()
+                        && let Ok(Ok(Response::ExternalAddress(ip))) =
+                            natpmp_request(gateway, &Request::ExternalAddress)
+                    {
+                        self.mapper.set_external_ip(IpAddr::V4(ip));
+                    }
+                    return Ok(Ok((Method::NatPmp, external_port, lifetime)));
+                }
+                // The gateway speaks NAT-PMP but said no. UPnP on the same box won't say yes
+                Err(err @ PortMapError::Refused(_)) => return Ok(Err(err)),
+                _ => self.natpmp = None,
+            }
+        }
+
+        let timeout = self.timeout;
+        let gateway = self
+            .upnp
+            .get_or_insert_with(|| upnp::discover(timeout).ok())
+            .clone();
+        let Some(gateway) = gateway else {
+            return Ok(Err(PortMapError::NoGateway));
+        };
+        let result = upnp::add_port_mapping(&gateway, protocol, port, LEASE, timeout);
+        if result.is_ok()
+            && self.mapper.external_ip().is_none()
+            && let Ok(ip) = upnp::external_ip(&gateway, timeout)
+        {
+            self.mapper.set_external_ip(ip);
+        }
+        Ok(result.map(|lifetime| (Method::Upnp, port, lifetime)))
+    }
+
+    fn unmap(
+        &mut self,
+        protocol: Protocol,
+        port: u16,
+        method: Method,
+        external_port: u16,
+    ) -> Result<(), PortMapError> {
+        match method {
+            Method::NatPmp => {
+                let gateway = self.natpmp.ok_or(PortMapError::NoGateway)?;
+                let request = Request::Map {
+                    protocol,
+                    internal_port: port,
+                    external_port: 0,
+                    lifetime: Duration::ZERO,
+                };
+                natpmp_request(gateway, &request)?.map(|_| ())
+            }
+            Method::Upnp => {
+                let Some(Some(gateway)) = &self.upnp else {
+                    return Err(PortMapError::NoGateway);
+                };
+                upnp::delete_port_mapping(gateway, protocol, external_port, self.timeout)
+            }
+        }
+    }
+}
+
+enum Command {
+    Add(Protocol, u16),
+    Remove(Protocol, u16),
+    Wake,
+}
+
+// A `PortMapClient` running on its own thread, see `PortMapClient::spawn`. What it did shows up
+// here after its next poll
+#[derive(Debug)]
+pub struct PortMapHandle {
+    // None once it's shutting down
+    commands: Option<Sender<Command>>,
+    status: Arc<Mutex<(Vec<MappingStatus>, Option<IpAddr>)>>,
+    finished: mpsc::Receiver<Vec<PortMapError>>,
+}
+
+impl PortMapHandle {
+    pub fn add(&self, protocol: Protocol, port: u16) {
+        self.send(Command::Add(protocol, port));
+    }
+
+    pub fn remove(&self, protocol: Protocol, port: u16) {
+        self.send(Command::Remove(protocol, port));
+    }
+
+    // See `PortMapper::on_wake`
+    pub fn on_wake(&self) {
+        self.send(Command::Wake);
+    }
+
+    pub fn status(&self) -> Vec<MappingStatus> {
+        self.status.lock().unwrap().0.clone()
+    }
+
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.status.lock().unwrap().1
+    }
+
+    // Removes every mapping, waiting until `deadline` at most. Returns how many are left on the
+    // router: those that failed to go, or all that were there if time ran out
+    pub fn shutdown(&mut self, deadline: Instant) -> usize {
+        self.commands = None;
+        let left = deadline.saturating_duration_since(Instant::now());
+        match self.finished.recv_timeout(left) {
+            Ok(errors) => errors.len(),
+            Err(_) => self
+                .status()
+                .iter()
+                .filter(|status| matches!(status.state, MappingState::Mapped { .. }))
+                .count(),
+        }
+    }
+
+    fn send(&self, command: Command) {
+        if let Some(commands) = &self.commands {
+            let _ = commands.send(command);
+        }
+    }
+}
+
+// One request with retransmits. The outer error is our socket failing; a gateway that never
+// answers is `NoGateway`
+fn natpmp_request(
+    gateway: Ipv4Addr,
+    request: &Request,
+) -> io::Result<Result<Response, PortMapError>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(SocketAddrV4::new(gateway, NATPMP_PORT))?;
+    let mut buf = [0; 16];
+    let mut wait = NATPMP_FIRST_WAIT;
+    for _ in 0..NATPMP_TRIES {
+        socket.send(&request.encode())?;
+        socket.set_read_timeout(Some(wait))?;
+        match socket.recv(&mut buf) {
+            Ok(len) => return Ok(Response::decode(&buf[..len])),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            // ICMP port unreachable: nothing listening for NAT-PMP
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => break,
+            Err(e) => return Err(e),
+        }
+        wait *= 2;
+    }
+    Ok(Err(PortMapError::NoGateway))
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_spawned_client_maps_and_unmaps() {
+        // A NAT-PMP gateway on loopback that grants whatever it's asked for
+        let gateway = UdpSocket::bind((Ipv4Addr::LOCALHOST, NATPMP_PORT)).unwrap();
+        let (requests, received) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 16];
+            while let Ok((len, from)) = gateway.recv_from(&mut buf) {
+                let request = buf[..len].to_vec();
+                let op = request[1];
+                let mut response = vec![0, 128 + op, 0, 0, 0, 0, 0, 1];
+                match op {
+                    0 => response.extend([192, 0, 2, 1]),
+                    _ => response.extend(&request[4..12]),
+                }
+                let _ = requests.send(request);
+                let _ = gateway.send_to(&response, from);
+            }
+        });
+
+        let client = PortMapClient::new(PortMapper::new(), Some(Ipv4Addr::LOCALHOST));
+        let mut handle = client.spawn();
+        handle.add(Protocol::Tcp, 6881);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while handle.status().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(matches!(
+            handle.status()[0].state,
+            MappingState::Mapped {
+                method: Method::NatPmp,
+                external_port: 6881,
+                ..
+            }
+        ));
+        assert_eq!(handle.external_ip(), Some(IpAddr::from([192, 0, 2, 1])));
+
+        assert_eq!(handle.shutdown(deadline), 0);
+        // Mapped, asked for the address, then deleted with a lifetime of zero
+        let requests: Vec<Vec<u8>> = received.try_iter().collect();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2][1], 2);
+        assert_eq!(requests[2][8..12], [0; 4]);
+    }
+}
@@ -0,0 +1,296 @@
+// Port forwarding on the home router, so peers behind the same kind of NAT as us can still
+// connect in. Two protocols do this: NAT-PMP (and PCP, its successor, whose routers still answer
+// NAT-PMP requests) is a few UDP datagrams to the default gateway; UPnP IGD means finding the
+// router over SSDP multicast and then SOAP over HTTP. We try NAT-PMP first and fall back to UPnP.
+// Mappings are leased, so `PortMapper` keeps track of when each one is due for renewal and backs
+// off ones that fail. Sans-IO like the DHT; `PortMapClient` does the talking.
+pub mod client;
+pub mod natpmp;
+pub mod upnp;
+
+pub use client::{PortMapClient, PortMapHandle};
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// Lease we ask for. Routers are free to hand out less
+pub const LEASE: Duration = Duration::from_secs(2 * 60 * 60);
+
+// Mappings without an expiry still get refreshed this often, in case the router rebooted
+pub const PERMANENT_REFRESH: Duration = Duration::from_secs(60 * 60);
+
+// Backoff after the first failure. Doubles with each one after that
+const RETRY_BASE: Duration = Duration::from_secs(60);
+
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Debug, Clone, Copy)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Method {
+    NatPmp,
+    Upnp,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum PortMapError {
+    Io(io::ErrorKind),
+    // Nothing answered as a NAT-PMP or UPnP gateway
+    NoGateway,
+    InvalidResponse(&'static str),
+    // The gateway's own error: a NAT-PMP result code or a UPnP errorCode
+    Refused(u16),
+}
+
+impl From<io::Error> for PortMapError {
+    fn from(err: io::Error) -> Self {
+        PortMapError::Io(err.kind())
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum MappingState {
+    // Not mapped yet, or waiting to be retried after a failure
+    Pending,
+    Mapped {
+        method: Method,
+        external_port: u16,
+        // None for permanent mappings
+        expires: Option<Instant>,
+    },
+    Failed(PortMapError),
+}
+
+// What's reported for each port we want forwarded
+#[derive(PartialEq, Debug, Clone)]
+pub struct MappingStatus {
+    pub protocol: Protocol,
+    pub port: u16,
+    pub state: MappingState,
+}
+
+#[derive(Debug)]
+struct Entry {
+    state: MappingState,
+    // When to (re)map or retry
+    next: Instant,
+    failures: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct PortMapper {
+    // By protocol and internal port
+    mappings: BTreeMap<(Protocol, u16), Entry>,
+    external_ip: Option<IpAddr>,
+}
+
+impl PortMapper {
+    pub fn new() -> Self {
+        PortMapper::default()
+    }
+
+    // Mapped to the same external port if the router allows, on the next `due`
+    pub fn add(&mut self, protocol: Protocol, port: u16, now: Instant) {
+        self.mappings.entry((protocol, port)).or_insert(Entry {
+            state: MappingState::Pending,
+            next: now,
+            failures: 0,
+        });
+    }
+
+    // Forgets the port. Returns the method it was mapped with, if it was, so the caller can
+    // remove the mapping from the router
+    pub fn remove(&mut self, protocol: Protocol, port: u16) -> Option<(Method, u16)> {
+        match self.mappings.remove(&(protocol, port))?.state {
+            MappingState::Mapped {
+                method,
+                external_port,
+                ..
+            } => Some((method, external_port)),
+            _ => None,
+        }
+    }
+
+    // Ports to map now, either for the first time, again after a failure or to renew the lease
+    pub fn due(&self, now: Instant) -> Vec<(Protocol, u16)> {
+        self.mappings
+            .iter()
+            .filter(|(_, entry)| entry.next <= now)
+            .map(|(key, _)| *key)
+            .collect()
+    }
+
+    // `lifetime` is what the router granted, zero for a permanent mapping. Renewed halfway
+    // through, the way NAT-PMP recommends
+    pub fn on_mapped(
+        &mut self,
+        protocol: Protocol,
+        port: u16,
+        method: Method,
+        external_port: u16,
+        lifetime: Duration,
+        now: Instant,
+    ) {
+        let Some(entry) = self.mappings.get_mut(&(protocol, port)) else {
+            return;
+        };
+        let (expires, next) = match lifetime {
+            Duration::ZERO => (None, now + PERMANENT_REFRESH),
+            lifetime => (Some(now + lifetime), now + lifetime / 2),
+        };
+        entry.state = MappingState::Mapped {
+            method,
+            external_port,
+            expires,
+        };
+        entry.next = next;
+        entry.failures = 0;
+    }
+
+    // After a suspend the router may have rebooted or let our leases run out, so everything is
+    // mapped again right away, failures forgotten
+    pub fn on_wake(&mut self, now: Instant) {
+        for entry in self.mappings.values_mut() {
+            entry.next = now;
+            entry.failures = 0;
+        }
+    }
+
+    // A renewal that fails leaves the mapping in place until it expires, it may well still work
+    pub fn on_failed(&mut self, protocol: Protocol, port: u16, err: PortMapError, now: Instant) {
+        let Some(entry) = self.mappings.get_mut(&(protocol, port)) else {
+            return;
+        };
+        let still_mapped = match entry.state {
+            MappingState::Mapped { expires, .. } => expires.is_none_or(|expires| expires > now),
+            _ => false,
+        };
+        if !still_mapped {
+            entry.state = MappingState::Failed(err);
+        }
+        entry.next = now + RETRY_BASE * (1 << entry.failures.min(5));
+        entry.failures += 1;
+    }
+
+    // Mappings on the router right now, as (protocol, internal port, method, external port)
+    pub fn mapped(&self) -> Vec<(Protocol, u16, Method, u16)> {
+        self.mappings
+            .iter()
+            .filter_map(|((protocol, port), entry)| match entry.state {
+                MappingState::Mapped {
+                    method,
+                    external_port,
+                    ..
+                } => Some((*protocol, *port, method, external_port)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.external_ip
+    }
+
+    pub fn set_external_ip(&mut self, ip: IpAddr) {
+        self.external_ip = Some(ip);
+    }
+
+    pub fn status(&self) -> Vec<MappingStatus> {
+        self.mappings
+            .iter()
+            .map(|((protocol, port), entry)| MappingStatus {
+                protocol: *protocol,
+                port: *port,
+                state: entry.state.clone(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_renews_halfway() {
+        let now = Instant::now();
+        let mut mapper = PortMapper::new();
+        mapper.add(Protocol::Tcp, 6881, now);
+        mapper.add(Protocol::Udp, 6881, now);
+        assert_eq!(mapper.due(now).len(), 2);
+
+        for protocol in [Protocol::Tcp, Protocol::Udp] {
+            mapper.on_mapped(protocol, 6881, Method::NatPmp, 7000, LEASE, now);
+        }
+        assert!(mapper.due(now + LEASE / 3).is_empty());
+        assert_eq!(mapper.due(now + LEASE / 2).len(), 2);
+        assert_eq!(
+            mapper.mapped()[0],
+            (Protocol::Tcp, 6881, Method::NatPmp, 7000)
+        );
+        assert_eq!(
+            mapper.remove(Protocol::Tcp, 6881),
+            Some((Method::NatPmp, 7000))
+        );
+        assert_eq!(mapper.status().len(), 1);
+    }
+
+    #[test]
+    fn test_failures_back_off() {
+        let now = Instant::now();
+        let mut mapper = PortMapper::new();
+        mapper.add(Protocol::Tcp, 6881, now);
+
+        mapper.on_failed(Protocol::Tcp, 6881, PortMapError::NoGateway, now);
+        assert_eq!(
+            mapper.status()[0].state,
+            MappingState::Failed(PortMapError::NoGateway)
+        );
+        assert!(mapper.due(now + RETRY_BASE / 2).is_empty());
+        assert_eq!(mapper.due(now + RETRY_BASE).len(), 1);
+
+        mapper.on_failed(Protocol::Tcp, 6881, PortMapError::NoGateway, now);
+        assert!(mapper.due(now + RETRY_BASE).is_empty());
+        assert_eq!(mapper.due(now + RETRY_BASE * 2).len(), 1);
+    }
+
+    #[test]
+    fn test_wake_remaps() {
+        let now = Instant::now();
+        let mut mapper = PortMapper::new();
+        mapper.add(Protocol::Tcp, 6881, now);
+        mapper.add(Protocol::Udp, 6881, now);
+        mapper.on_mapped(Protocol::Tcp, 6881, Method::NatPmp, 7000, LEASE, now);
+        mapper.on_failed(Protocol::Udp, 6881, PortMapError::NoGateway, now);
+        let later = now + Duration::from_secs(1);
+        assert!(mapper.due(later).is_empty());
+
+        mapper.on_wake(later);
+        assert_eq!(mapper.due(later).len(), 2);
+    }
+
+    #[test]
+    fn test_failed_renewal_keeps_mapping() {
+        let now = Instant::now();
+        let mut mapper = PortMapper::new();
+        mapper.add(Protocol::Udp, 6881, now);
+        mapper.on_mapped(Protocol::Udp, 6881, Method::Upnp, 6881, LEASE, now);
+
+        mapper.on_failed(
+            Protocol::Udp,
+            6881,
+            PortMapError::Refused(501),
+            now + LEASE / 2,
+        );
+        assert!(matches!(
+            mapper.status()[0].state,
+            MappingState::Mapped { .. }
+        ));
+        mapper.on_failed(Protocol::Udp, 6881, PortMapError::Refused(501), now + LEASE);
+        assert!(mapper.mapped().is_empty());
+    }
+}
@@ -0,0 +1,182 @@
+// NAT-PMP (RFC 6886). Requests go to port 5351 on the default gateway over UDP; every response
+// echoes the opcode plus 128 and carries a result code and the gateway's seconds-since-boot. A
+// mapping is deleted by requesting it again with a lifetime of zero.
+use std::fs;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use super::{PortMapError, Protocol};
+
+pub const NATPMP_PORT: u16 = 5351;
+
+const VERSION: u8 = 0;
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+const OP_MAP_UDP: u8 = 1;
+const OP_MAP_TCP: u8 = 2;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Request {
+    ExternalAddress,
+    Map {
+        protocol: Protocol,
+        internal_port: u16,
+        // Only a suggestion. Zero lets the gateway pick
+        external_port: u16,
+        lifetime: Duration,
+    },
+}
+
+impl Request {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Request::ExternalAddress => vec![VERSION, OP_EXTERNAL_ADDRESS],
+            Request::Map {
+                protocol,
+                internal_port,
+                external_port,
+                lifetime,
+            } => {
+                let mut buf = vec![VERSION, map_op(*protocol), 0, 0];
+                buf.extend(internal_port.to_be_bytes());
+                buf.extend(external_port.to_be_bytes());
+                let lifetime = u32::try_from(lifetime.as_secs()).unwrap_or(u32::MAX);
+                buf.extend(lifetime.to_be_bytes());
+                buf
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Response {
+    ExternalAddress(Ipv4Addr),
+    Map {
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        // What the gateway granted, zero when a mapping was deleted
+        lifetime: Duration,
+    },
+}
+
+impl Response {
+    pub fn decode(buf: &[u8]) -> Result<Response, PortMapError> {
+        if buf.len() < 8 {
+            return Err(PortMapError::InvalidResponse("short response"));
+        }
+        // A gateway that only speaks PCP answers with its own version and an error
+        if buf[0] != VERSION {
+            return Err(PortMapError::InvalidResponse("unsupported version"));
+        }
+        let result = u16::from_be_bytes([buf[2], buf[3]]);
+        if result != 0 {
+            return Err(PortMapError::Refused(result));
+        }
+
+        match buf[1] {
+            op if op == 128 + OP_EXTERNAL_ADDRESS && buf.len() >= 12 => Ok(
+                Response::ExternalAddress(Ipv4Addr::new(buf[8], buf[9], buf[10], buf[11])),
+            ),
+            op if (op == 128 + OP_MAP_UDP || op == 128 + OP_MAP_TCP) && buf.len() >= 16 => {
+                Ok(Response::Map {
+                    protocol: if op == 128 + OP_MAP_UDP {
+                        Protocol::Udp
+                    } else {
+                        Protocol::Tcp
+                    },
+                    internal_port: u16::from_be_bytes([buf[8], buf[9]]),
+                    external_port: u16::from_be_bytes([buf[10], buf[11]]),
+                    lifetime: Duration::from_secs(u32::from_be_bytes([
+                        buf[12], buf[13], buf[14], buf[15],
+                    ]) as u64),
+                })
+            }
+            _ => Err(PortMapError::InvalidResponse("unexpected opcode")),
+        }
+    }
+}
+
+fn map_op(protocol: Protocol) -> u8 {
+    match protocol {
+        Protocol::Udp => OP_MAP_UDP,
+        Protocol::Tcp => OP_MAP_TCP,
+    }
+}
+
+// The IPv4 default gateway, where NAT-PMP requests go. Only Linux exposes the routing table
+// without a syscall dance; elsewhere the embedder has to tell `PortMapClient`
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    parse_route_table(&fs::read_to_string("/proc/net/route").ok()?)
+}
+
+// Columns are interface, destination, gateway, ...; addresses are the raw network order bytes
+// printed as a native u32
+fn parse_route_table(table: &str) -> Option<Ipv4Addr> {
+    table.lines().skip(1).find_map(|line| {
+        let mut columns = line.split_whitespace().skip(1);
+        let destination = u32::from_str_radix(columns.next()?, 16).ok()?;
+        let gateway = u32::from_str_radix(columns.next()?, 16).ok()?;
+        (destination == 0 && gateway != 0).then(|| Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_map_roundtrip() {
+        let request = Request::Map {
+            protocol: Protocol::Tcp,
+            internal_port: 6881,
+            external_port: 6881,
+            lifetime: Duration::from_secs(7200),
+        };
+        assert_eq!(
+            request.encode(),
+            [0, 2, 0, 0, 0x1a, 0xe1, 0x1a, 0xe1, 0, 0, 0x1c, 0x20]
+        );
+
+        let response = [
+            0, 130, 0, 0, 0, 0, 1, 0, 0x1a, 0xe1, 0x1b, 0x58, 0, 0, 0x0e, 0x10,
+        ];
+        assert_eq!(
+            Response::decode(&response),
+            Ok(Response::Map {
+                protocol: Protocol::Tcp,
+                internal_port: 6881,
+                external_port: 7000,
+                lifetime: Duration::from_secs(3600),
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let external = [0, 128, 0, 0, 0, 0, 0, 9, 203, 0, 113, 7];
+        assert_eq!(
+            Response::decode(&external),
+            Ok(Response::ExternalAddress(Ipv4Addr::new(203, 0, 113, 7)))
+        );
+        // Result code 3: network failure
+        assert_eq!(
+            Response::decode(&[0, 128, 0, 3, 0, 0, 0, 9, 0, 0, 0, 0]),
+            Err(PortMapError::Refused(3))
+        );
+        assert_eq!(
+            Response::decode(&[2, 129, 1, 0, 0, 0, 0, 0]),
+            Err(PortMapError::InvalidResponse("unsupported version"))
+        );
+    }
+
+    #[test]
+    fn test_parse_route_table() {
+        let table = "Iface\tDestination\tGateway \tFlags\n\
+                     eth0\t0000A8C0\t00000000\t0001\n\
+                     eth0\t00000000\t0101A8C0\t0003\n";
+        assert_eq!(
+            parse_route_table(table),
+            Some(Ipv4Addr::new(192, 168, 1, 1))
+        );
+    }
+}
@@ -0,0 +1,350 @@
+// UPnP Internet Gateway Device. The router is found by multicasting an SSDP M-SEARCH and reading
+// the LOCATION header of whoever answers; that's an XML device description listing its services,
+// and the WANIPConnection (or WANPPPConnection on DSL) service's control URL takes SOAP calls to
+// add and delete port mappings. The XML is tiny and regular, so we pick it apart with string
+// searches rather than pull in a parser.
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream, UdpSocket};
+use std::time::{Duration, Instant};
+
+use super::{PortMapError, Protocol};
+use crate::tracker::Url;
+
+pub const SSDP_V4: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+
+// Services that can forward ports, in order of preference
+const SERVICE_TYPES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+// Some routers refuse leases other than permanent ones with this error
+const ONLY_PERMANENT_LEASES: u16 = 725;
+
+// Descriptions and SOAP responses are a few KiB
+const MAX_RESPONSE: u64 = 256 * 1024;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Gateway {
+    pub control_url: String,
+    pub service_type: String,
+}
+
+pub fn search_message() -> Vec<u8> {
+    format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\r\n",
+        SSDP_V4, SEARCH_TARGET
+    )
+    .into_bytes()
+}
+
+// The description URL from an M-SEARCH response
+pub fn parse_search_response(buf: &[u8]) -> Option<String> {
+    let text = std::str::from_utf8(buf).ok()?;
+    let mut lines = text.lines();
+    if !lines.next()?.contains(" 200") {
+        return None;
+    }
+    lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("location")
+            .then(|| value.trim().to_string())
+    })
+}
+
+// The port mapping service in a device description fetched from `location`
+pub fn parse_description(xml: &str, location: &str) -> Option<Gateway> {
+    let services: Vec<&str> = xml.split("<service>").skip(1).collect();
+    let (service_type, service) = SERVICE_TYPES.iter().find_map(|wanted| {
+        services
+            .iter()
+            .find(|service| tag(service, "serviceType") == Some(*wanted))
+            .map(|service| (*wanted, *service))
+    })?;
+    let control = tag(service, "controlURL")?;
+
+    let control_url = if control.contains("://") {
+        control.to_string()
+    } else {
+        // Relative to URLBase if the device gives one, else to where the description came from
+        let base = tag(xml, "URLBase").unwrap_or(location);
+        let url = Url::parse(base).ok()?;
+        let origin = &base[..base.len() - url.path.len()];
+        format!(
+            "{}/{}",
+            origin.trim_end_matches('/'),
+            control.trim_start_matches('/')
+        )
+    };
+    Some(Gateway {
+        control_url,
+        service_type: service_type.to_string(),
+    })
+}
+
+fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let len = xml[start..].find(&format!("</{}>", name))?;
+    Some(xml[start..start + len].trim())
+}
+
+fn soap_body(gateway: &Gateway, action: &str, args: &[(&str, String)]) -> String {
+    let args: String = args
+        .iter()
+        .map(|(name, value)| format!("<{0}>{1}</{0}>", name, value))
+        .collect();
+    format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>",
+        action, gateway.service_type, args
+    )
+}
+
+fn protocol_name(protocol: Protocol) -> &'static str {
+    match protocol {
+        Protocol::Tcp => "TCP",
+        Protocol::Udp => "UDP",
+    }
+}
+
+// Finds the gateway: M-SEARCH, then fetch the first description that has a port mapping service
+pub fn discover(timeout: Duration) -> Result<Gateway, PortMapError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.send_to(&search_message(), SSDP_V4)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0; 2048];
+    loop {
+        let wait = deadline.saturating_duration_since(Instant::now());
+        if wait.is_zero() {
+            return Err(PortMapError::NoGateway);
+        }
+        socket.set_read_timeout(Some(wait))?;
+        let Ok((len, _)) = socket.recv_from(&mut buf) else {
+            return Err(PortMapError::NoGateway);
+        };
+        let Some(location) = parse_search_response(&buf[..len]) else {
+            continue;
+        };
+        let gateway = http(&location, None, timeout)
+            .ok()
+            .and_then(|(_, xml)| parse_description(&xml, &location));
+        if let Some(gateway) = gateway {
+            return Ok(gateway);
+        }
+    }
+}
+
+// Returns the lease granted, zero for permanent
+pub fn add_port_mapping(
+    gateway: &Gateway,
+    protocol: Protocol,
+    port: u16,
+    lease: Duration,
+    timeout: Duration,
+) -> Result<Duration, PortMapError> {
+    let internal_client = local_address(gateway)?;
+    let request = |lease: Duration| {
+        let args = [
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", port.to_string()),
+            ("NewProtocol", protocol_name(protocol).to_string()),
+            ("NewInternalPort", port.to_string()),
+            ("NewInternalClient", internal_client.to_string()),
+            ("NewEnabled", "1".to_string()),
+            (
+                "NewPortMappingDescription",
+                format!("Hurricane {}", protocol_name(protocol)),
+            ),
+            ("NewLeaseDuration", lease.as_secs().to_string()),
+        ];
+        soap(gateway, "AddPortMapping", &args, timeout)
+    };
+    match request(lease) {
+        Err(PortMapError::Refused(ONLY_PERMANENT_LEASES)) => {
+            request(Duration::ZERO).map(|_| Duration::ZERO)
+        }
+        result => result.map(|_| lease),
+    }
+}
+
+pub fn delete_port_mapping(
+    gateway: &Gateway,
+    protocol: Protocol,
+    external_port: u16,
+    timeout: Duration,
+) -> Result<(), PortMapError> {
+    let args = [
+        ("NewRemoteHost", String::new()),
+        ("NewExternalPort", external_port.to_string()),
+        ("NewProtocol", protocol_name(protocol).to_string()),
+    ];
+    soap(gateway, "DeletePortMapping", &args, timeout).map(|_| ())
+}
+
+pub fn external_ip(gateway: &Gateway, timeout: Duration) -> Result<IpAddr, PortMapError> {
+    let response = soap(gateway, "GetExternalIPAddress", &[], timeout)?;
+    tag(&response, "NewExternalIPAddress")
+        .and_then(|ip| ip.parse().ok())
+        .ok_or(PortMapError::InvalidResponse("external address"))
+}
+
+// Our address on the gateway's network, which is what the mapping has to point at. Connecting a
+// UDP socket picks the route without sending anything
+fn local_address(gateway: &Gateway) -> Result<IpAddr, PortMapError> {
+    let url = Url::parse(&gateway.control_url).map_err(|_| PortMapError::NoGateway)?;
+    let addr = url
+        .resolve(80)
+        .ok()
+        .and_then(|addrs| addrs.first().copied())
+        .ok_or(PortMapError::NoGateway)?;
+    let socket = UdpSocket::bind(match addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    })?;
+    socket.connect(addr)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+fn soap(
+    gateway: &Gateway,
+    action: &str,
+    args: &[(&str, String)],
+    timeout: Duration,
+) -> Result<String, PortMapError> {
+    let body = soap_body(gateway, action, args);
+    let soap_action = format!("{}#{}", gateway.service_type, action);
+    let (status, response) = http(&gateway.control_url, Some((&soap_action, &body)), timeout)?;
+    match status {
+        200 => Ok(response),
+        _ => Err(fault(&response).unwrap_or(PortMapError::InvalidResponse("status"))),
+    }
+}
+
+fn fault(response: &str) -> Option<PortMapError> {
+    tag(response, "errorCode")?
+        .parse()
+        .ok()
+        .map(PortMapError::Refused)
+}
+
+// HTTP/1.0 like the tracker client. A GET, or a SOAP POST given the action and body
+fn http(
+    url: &str,
+    soap: Option<(&str, &str)>,
+    timeout: Duration,
+) -> Result<(u16, String), PortMapError> {
+    let parsed = Url::parse(url).map_err(|_| PortMapError::InvalidResponse("url"))?;
+    if parsed.scheme != "http" {
+        return Err(PortMapError::InvalidResponse("url"));
+    }
+    // Gateways go by address, so there's nothing to race
+    let addr = parsed
+        .resolve(80)
+        .ok()
+        .and_then(|addrs| addrs.first().copied())
+        .ok_or(PortMapError::InvalidResponse("url"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let path = if parsed.path.is_empty() {
+        "/"
+    } else {
+        parsed.path
+    };
+    let host = match parsed.port {
+        Some(port) => format!("{}:{}", parsed.host, port),
+        None => parsed.host.to_string(),
+    };
+    match soap {
+        None => write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, host)?,
+        Some((action, body)) => write!(
+            stream,
+            "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: text/xml; charset=\"utf-8\"\r\n\
+             SOAPAction: \"{}\"\r\nContent-Length: {}\r\n\r\n{}",
+            path,
+            host,
+            action,
+            body.len(),
+            body
+        )?,
+    }
+
+    let mut response = vec![];
+    stream.take(MAX_RESPONSE).read_to_end(&mut response)?;
+    parse_response(&response)
+}
+
+fn parse_response(response: &[u8]) -> Result<(u16, String), PortMapError> {
+    let text = String::from_utf8_lossy(response);
+    let (head, body) = text
+        .split_once("\r\n\r\n")
+        .ok_or(PortMapError::InvalidResponse("no headers"))?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or(PortMapError::InvalidResponse("status line"))?;
+    Ok((status, body.to_string()))
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    const DESCRIPTION: &str = "<root><device><serviceList>\
+        <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+        <controlURL>/l3f</controlURL></service>\
+        <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+        <controlURL>/upnp/control/WANIPConn1</controlURL></service>\
+        </serviceList></device></root>";
+
+    #[test]
+    fn test_parse_search_response() {
+        let response = b"HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\n\
+            Location: http://192.168.1.1:49152/rootDesc.xml\r\nST: upnp:rootdevice\r\n\r\n";
+
+        assert_eq!(
+            parse_search_response(response).as_deref(),
+            Some("http://192.168.1.1:49152/rootDesc.xml")
+        );
+        assert_eq!(parse_search_response(&search_message()), None);
+    }
+
+    #[test]
+    fn test_parse_description() {
+        let gateway =
+            parse_description(DESCRIPTION, "http://192.168.1.1:49152/rootDesc.xml").unwrap();
+
+        assert_eq!(
+            gateway.control_url,
+            "http://192.168.1.1:49152/upnp/control/WANIPConn1"
+        );
+        assert_eq!(gateway.service_type, SERVICE_TYPES[1]);
+        assert_eq!(parse_description("<root></root>", "http://h/"), None);
+    }
+
+    #[test]
+    fn test_fault() {
+        let response = "<s:Envelope><s:Body><s:Fault><detail><UPnPError>\
+            <errorCode>725</errorCode><errorDescription>OnlyPermanentLeasesSupported\
+            </errorDescription></UPnPError></detail></s:Fault></s:Body></s:Envelope>";
+        let (status, body) = parse_response(
+            format!("HTTP/1.1 500 Internal Server Error\r\n\r\n{}", response).as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(status, 500);
+        assert_eq!(
+            fault(&body),
+            Some(PortMapError::Refused(ONLY_PERMANENT_LEASES))
+        );
+    }
+}
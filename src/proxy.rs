@@ -0,0 +1,409 @@
+// SOCKS5 (RFC 1928) with optional username/password auth (RFC 1929). TCP goes through CONNECT;
+// UDP (the DHT, UDP trackers) through UDP ASSOCIATE, where every datagram to and from the proxy's
+// relay carries a small header naming the real peer. Hostnames are handed to the proxy to resolve
+// so DNS doesn't leak around it.
+// In strict mode nothing falls back to a direct connection when the proxy fails. Whatever can't
+// go through a SOCKS proxy at all (incoming connections, LSD, port mapping) should stay off too;
+// `allows_direct` is what to check.
+use std::io::{self, Read, Write};
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream, UdpSocket,
+};
+use std::time::Duration;
+
+use crate::dns;
+
+const VERSION: u8 = 5;
+const AUTH_NONE: u8 = 0;
+const AUTH_PASSWORD: u8 = 2;
+const AUTH_UNACCEPTABLE: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+const CMD_UDP_ASSOCIATE: u8 = 3;
+const ATYP_V4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_V6: u8 = 4;
+
+#[derive(PartialEq, Debug)]
+pub enum ProxyError {
+    Io(io::ErrorKind),
+    InvalidReply,
+    // The proxy wants auth we didn't configure, or won't take ours
+    NoAcceptableAuth,
+    AuthFailed,
+    // The proxy's REP code, e.g. 5 for connection refused
+    Refused(u8),
+    // Domain names are length prefixed with a single byte
+    NameTooLong,
+}
+
+impl From<io::Error> for ProxyError {
+    fn from(err: io::Error) -> Self {
+        ProxyError::Io(err.kind())
+    }
+}
+
+impl From<ProxyError> for io::Error {
+    fn from(err: ProxyError) -> Self {
+        match err {
+            ProxyError::Io(kind) => kind.into(),
+            ProxyError::Refused(5) => io::ErrorKind::ConnectionRefused.into(),
+            err => io::Error::other(format!("SOCKS5 proxy: {:?}", err)),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct ProxyConfig {
+    pub addr: SocketAddr,
+    // Username and password
+    pub auth: Option<(String, String)>,
+    // Never connect directly, even when the proxy is down
+    pub strict: bool,
+}
+
+impl ProxyConfig {
+    pub fn new(addr: SocketAddr) -> Self {
+        ProxyConfig {
+            addr,
+            auth: None,
+            strict: false,
+        }
+    }
+
+    // A torrent's own proxy wins over the global one
+    pub fn choose<'a>(
+        global: Option<&'a ProxyConfig>,
+        torrent: Option<&'a ProxyConfig>,
+    ) -> Option<&'a ProxyConfig> {
+        torrent.or(global)
+    }
+
+    pub fn allows_direct(&self) -> bool {
+        !self.strict
+    }
+
+    // A TCP connection to `target` through the proxy
+    pub fn connect(&self, target: &Target, timeout: Duration) -> io::Result<TcpStream> {
+        let mut stream = self.open(timeout)?;
+        request(&mut stream, CMD_CONNECT, target)?;
+        Ok(stream)
+    }
+
+    // Sets up UDP relaying for `socket`, which should be bound to the address family of the
+    // proxy. The relay lasts as long as the returned `UdpRelay` lives
+    pub fn associate(&self, socket: &UdpSocket, timeout: Duration) -> io::Result<UdpRelay> {
+        let mut control = self.open(timeout)?;
+        // Where our datagrams will come from. Proxies behind NAT can't use it, so zeros are fine
+        let from = Target::Addr(socket.local_addr()?);
+        let relay = match request(&mut control, CMD_UDP_ASSOCIATE, &from)? {
+            // Proxies that bind the relay on all interfaces answer with the unspecified address
+            Target::Addr(relay) if relay.ip().is_unspecified() => {
+                SocketAddr::new(self.addr.ip(), relay.port())
+            }
+            Target::Addr(relay) => relay,
+            Target::Domain(..) => return Err(ProxyError::InvalidReply.into()),
+        };
+        Ok(UdpRelay { control, relay })
+    }
+
+    // Connected and authenticated, ready for a request
+    fn open(&self, timeout: Duration) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect_timeout(&self.addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let method = if self.auth.is_some() {
+            AUTH_PASSWORD
+        } else {
+            AUTH_NONE
+        };
+        stream.write_all(&[VERSION, 1, method])?;
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != VERSION {
+            return Err(ProxyError::InvalidReply.into());
+        }
+        if reply[1] == AUTH_UNACCEPTABLE || reply[1] != method {
+            return Err(ProxyError::NoAcceptableAuth.into());
+        }
+
+        if let Some((username, password)) = &self.auth {
+            stream.write_all(&password_request(username, password)?)?;
+            stream.read_exact(&mut reply)?;
+            if reply[1] != 0 {
+                return Err(ProxyError::AuthFailed.into());
+            }
+        }
+        Ok(stream)
+    }
+}
+
+// Where to connect. Hostnames stay unresolved so the proxy can look them up
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Target {
+    Addr(SocketAddr),
+    Domain(String, u16),
+}
+
+impl Target {
+    pub fn encode(&self, buf: &mut Vec<u8>) -> Result<(), ProxyError> {
+        match self {
+            Target::Addr(SocketAddr::V4(addr)) => {
+                buf.push(ATYP_V4);
+                buf.extend(addr.ip().octets());
+            }
+            Target::Addr(SocketAddr::V6(addr)) => {
+                buf.push(ATYP_V6);
+                buf.extend(addr.ip().octets());
+            }
+            Target::Domain(host, _) => {
+                let len = u8::try_from(host.len()).map_err(|_| ProxyError::NameTooLong)?;
+                buf.push(ATYP_DOMAIN);
+                buf.push(len);
+                buf.extend(host.as_bytes());
+            }
+        }
+        buf.extend(self.port().to_be_bytes());
+        Ok(())
+    }
+
+    // The target and how many bytes it took
+    pub fn decode(buf: &[u8]) -> Option<(Target, usize)> {
+        let (target, len) = match *buf.first()? {
+            ATYP_V4 => {
+                let ip: [u8; 4] = buf.get(1..5)?.try_into().ok()?;
+                (IpAddr::from(ip), 5)
+            }
+            ATYP_V6 => {
+                let ip: [u8; 16] = buf.get(1..17)?.try_into().ok()?;
+                (IpAddr::from(ip), 17)
+            }
+            ATYP_DOMAIN => {
+                let len = *buf.get(1)? as usize;
+                let host = std::str::from_utf8(buf.get(2..2 + len)?).ok()?;
+                let port = u16::from_be_bytes(buf.get(2 + len..4 + len)?.try_into().ok()?);
+                return Some((Target::Domain(host.to_string(), port), 4 + len));
+            }
+            _ => return None,
+        };
+        let port = u16::from_be_bytes(buf.get(len..len + 2)?.try_into().ok()?);
+        Some((Target::Addr(SocketAddr::new(target, port)), len + 2))
+    }
+
+    fn port(&self) -> u16 {
+        match self {
+            Target::Addr(addr) => addr.port(),
+            Target::Domain(_, port) => *port,
+        }
+    }
+}
+
+// A TCP connection to `target`, through the proxy if there is one. If the proxy fails and isn't
+// strict we go direct instead
+pub fn connect(
+    proxy: Option<&ProxyConfig>,
+    target: &Target,
+    timeout: Duration,
+) -> io::Result<TcpStream> {
+    if let Some(proxy) = proxy {
+        match proxy.connect(target, timeout) {
+            Err(_) if proxy.allows_direct() => {}
+            result => return result,
+        }
+    }
+    match target {
+        Target::Addr(addr) => TcpStream::connect_timeout(addr, timeout),
+        Target::Domain(host, port) => dns::connect(host, *port, timeout),
+    }
+}
+
+// A UDP ASSOCIATE in effect. Datagrams for the real destination go to `relay_addr` wrapped by
+// `send_to`, and what comes back from there is unwrapped by `unwrap`. Dropping this closes the
+// control connection, which ends the association
+#[derive(Debug)]
+pub struct UdpRelay {
+    control: TcpStream,
+    relay: SocketAddr,
+}
+
+impl UdpRelay {
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.relay
+    }
+
+    pub fn send_to(&self, socket: &UdpSocket, buf: &[u8], target: &Target) -> io::Result<usize> {
+        let mut packet = vec![0, 0, 0];
+        target.encode(&mut packet)?;
+        let header = packet.len();
+        packet.extend_from_slice(buf);
+        Ok(socket.send_to(&packet, self.relay)? - header)
+    }
+
+    // The real sender and payload of a datagram from the relay. Anything else (strays, fragments
+    // we don't reassemble, senders given by name) is None
+    pub fn unwrap<'a>(&self, from: SocketAddr, buf: &'a [u8]) -> Option<(SocketAddr, &'a [u8])> {
+        if from != self.relay || buf.len() < 3 || buf[2] != 0 {
+            return None;
+        }
+        match Target::decode(&buf[3..])? {
+            (Target::Addr(addr), len) => Some((addr, &buf[3 + len..])),
+            (Target::Domain(..), _) => None,
+        }
+    }
+
+    // Whether the proxy is still holding the association open
+    pub fn is_alive(&self) -> bool {
+        let _ = self.control.set_nonblocking(true);
+        let alive = !matches!(self.control.peek(&mut [0]), Ok(0));
+        let _ = self.control.set_nonblocking(false);
+        alive
+    }
+}
+
+// An unbound UDP socket of the proxy's address family, for `associate`
+pub fn udp_socket_for(proxy: &ProxyConfig) -> io::Result<UdpSocket> {
+    UdpSocket::bind(match proxy.addr {
+        SocketAddr::V4(_) => SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)),
+    })
+}
+
+fn password_request(username: &str, password: &str) -> Result<Vec<u8>, ProxyError> {
+    let username_len = u8::try_from(username.len()).map_err(|_| ProxyError::AuthFailed)?;
+    let password_len = u8::try_from(password.len()).map_err(|_| ProxyError::AuthFailed)?;
+    let mut buf = vec![1, username_len];
+    buf.extend(username.as_bytes());
+    buf.push(password_len);
+    buf.extend(password.as_bytes());
+    Ok(buf)
+}
+
+// Sends a request and reads the reply, returning the address the proxy bound
+fn request(stream: &mut TcpStream, command: u8, target: &Target) -> Result<Target, ProxyError> {
+    let mut buf = vec![VERSION, command, 0];
+    target.encode(&mut buf)?;
+    stream.write_all(&buf)?;
+
+    // Version, reply, reserved and the address type, then the address itself
+    let mut head = [0; 5];
+    stream.read_exact(&mut head)?;
+    if head[0] != VERSION {
+        return Err(ProxyError::InvalidReply);
+    }
+    if head[1] != 0 {
+        return Err(ProxyError::Refused(head[1]));
+    }
+    let rest = match head[3] {
+        ATYP_V4 => 4 + 2 - 1,
+        ATYP_V6 => 16 + 2 - 1,
+        ATYP_DOMAIN => head[4] as usize + 2,
+        _ => return Err(ProxyError::InvalidReply),
+    };
+    let mut reply = head[3..].to_vec();
+    reply.resize(2 + rest, 0);
+    stream.read_exact(&mut reply[2..])?;
+    Target::decode(&reply)
+        .map(|(target, _)| target)
+        .ok_or(ProxyError::InvalidReply)
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_target_roundtrip() {
+        for target in [
+            Target::Addr("10.0.0.1:6881".parse().unwrap()),
+            Target::Addr("[2001:db8::1]:51413".parse().unwrap()),
+            Target::Domain("tracker.example".to_string(), 80),
+        ] {
+            let mut buf = vec![];
+            target.encode(&mut buf).unwrap();
+            buf.push(0xaa);
+            assert_eq!(Target::decode(&buf), Some((target, buf.len() - 1)));
+        }
+
+        let long = Target::Domain("a".repeat(256), 80);
+        assert_eq!(long.encode(&mut vec![]), Err(ProxyError::NameTooLong));
+    }
+
+    #[test]
+    fn test_connect_with_auth() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut proxy = ProxyConfig::new(listener.local_addr().unwrap());
+        proxy.auth = Some(("user".to_string(), "pass".to_string()));
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [5, 1, AUTH_PASSWORD]);
+            stream.write_all(&[5, AUTH_PASSWORD]).unwrap();
+
+            let mut auth = [0; 11];
+            stream.read_exact(&mut auth).unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x04pass");
+            stream.write_all(&[1, 0]).unwrap();
+
+            let mut request = [0; 22];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(&request[..5], [5, CMD_CONNECT, 0, ATYP_DOMAIN, 15]);
+            assert_eq!(&request[5..20], b"tracker.example");
+            stream
+                .write_all(&[5, 0, 0, ATYP_V4, 10, 0, 0, 2, 0x1a, 0xe1])
+                .unwrap();
+            stream.write_all(b"hi").unwrap();
+        });
+
+        let target = Target::Domain("tracker.example".to_string(), 80);
+        let mut stream = proxy.connect(&target, Duration::from_secs(5)).unwrap();
+        let mut hi = [0; 2];
+        stream.read_exact(&mut hi).unwrap();
+        assert_eq!(&hi, b"hi");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_strict_never_goes_direct() {
+        // Nothing listens on the proxy's port, but something does on the target
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dead = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut proxy = ProxyConfig::new(dead);
+        let target = Target::Addr(target.local_addr().unwrap());
+        let timeout = Duration::from_secs(1);
+
+        assert!(connect(Some(&proxy), &target, timeout).is_ok());
+        proxy.strict = true;
+        assert!(connect(Some(&proxy), &target, timeout).is_err());
+    }
+
+    #[test]
+    fn test_udp_relay_unwrap() {
+        let relay = UdpRelay {
+            control: {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                TcpStream::connect(listener.local_addr().unwrap()).unwrap()
+            },
+            relay: "127.0.0.1:1080".parse().unwrap(),
+        };
+        let peer: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+        let mut datagram = vec![0, 0, 0];
+        Target::Addr(peer).encode(&mut datagram).unwrap();
+        datagram.extend(b"d1:ad2:id20:");
+
+        assert_eq!(
+            relay.unwrap(relay.relay_addr(), &datagram),
+            Some((peer, &b"d1:ad2:id20:"[..]))
+        );
+        assert_eq!(relay.unwrap(peer, &datagram), None);
+        datagram[2] = 1;
+        assert_eq!(relay.unwrap(relay.relay_addr(), &datagram), None);
+    }
+}
@@ -0,0 +1,577 @@
+// Python bindings, built with `maturin build` (see pyproject.toml).
+// A `Session` is a daemon without its control socket: a thread polls it, and the methods make the
+// same calls a JSON-RPC client would, so statuses and events come out as the same dicts. Events
+// can be iterated blocking, or with `async for` on a running asyncio loop. Bencode and
+// info-hashes are bound on their own for scripts that only need those.
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use pyo3::exceptions::{
+    PyKeyError, PyRuntimeError, PyStopAsyncIteration, PyTypeError, PyValueError,
+};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict, PyInt, PyList, PyString, PyTuple};
+use serde_json::{Value, json};
+
+use crate::bencode::{self, BencodeValue};
+use crate::daemon::{Daemon, DaemonConfig, RpcFault, event_json};
+use crate::events::{Event, Subscription};
+use crate::infohash::InfoHash;
+use crate::session::TorrentHandle;
+
+// How often the session thread polls the daemon, and how long a wait for events goes between
+// checks for Ctrl-C or a closed session
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const WAIT_INTERVAL: Duration = Duration::from_millis(100);
+
+// What the session, its handles and its event streams share. The daemon is None once closed
+struct Shared {
+    daemon: Mutex<Option<Daemon>>,
+    closed: AtomicBool,
+}
+
+impl Shared {
+    fn call(&self, method: &str, params: Value) -> PyResult<Value> {
+        let mut daemon = self.daemon()?;
+        let daemon = daemon.as_mut().expect("checked by daemon()");
+        daemon.call(method, &params, Instant::now()).map_err(fault)
+    }
+
+    fn daemon(&self) -> PyResult<MutexGuard<'_, Option<Daemon>>> {
+        let daemon = self.daemon.lock().unwrap();
+        match daemon.is_some() {
+            true => Ok(daemon),
+            false => Err(PyRuntimeError::new_err("session is closed")),
+        }
+    }
+}
+
+fn fault(fault: RpcFault) -> PyErr {
+    PyRuntimeError::new_err(fault.message)
+}
+
+#[pyclass(name = "Session", frozen)]
+struct PySession {
+    shared: Arc<Shared>,
+    runner: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[pymethods]
+impl PySession {
+    // Restores what was saved in `state_dir`, if given, and starts right away
+    #[new]
+    #[pyo3(signature = (save_path = PathBuf::from("."), *, port = 6881, state_dir = None, dht = true))]
+    fn new(
+        py: Python<'_>,
+        save_path: PathBuf,
+        port: u16,
+        state_dir: Option<PathBuf>,
+        dht: bool,
+    ) -> PyResult<Self> {
+        let mut config = DaemonConfig::new(save_path);
+        config.listen = None;
+        config.state_dir = state_dir;
+        config.peers.port = port;
+        config.peers.dht = dht;
+        let daemon = py
+            .allow_threads(|| Daemon::bind(config))
+            .map_err(|err| PyRuntimeError::new_err(err.to_string()))?;
+
+        let shared = Arc::new(Shared {
+            daemon: Mutex::new(Some(daemon)),
+            closed: AtomicBool::new(false),
+        });
+        // Only as long as someone still holds the session, a handle or an event stream
+        let weak = Arc::downgrade(&shared);
+        let runner = thread::spawn(move || run(weak));
+        Ok(PySession {
+            shared,
+            runner: Mutex::new(Some(runner)),
+        })
+    }
+
+    // One of `torrent`, a path to a .torrent file, or `magnet`
+    #[pyo3(signature = (torrent = None, magnet = None, *, save_path = None, label = None, paused = false))]
+    fn add(
+        &self,
+        torrent: Option<PathBuf>,
+        magnet: Option<String>,
+        save_path: Option<PathBuf>,
+        label: Option<String>,
+        paused: bool,
+    ) -> PyResult<PyTorrentHandle> {
+        let added = self.shared.call(
+            "add",
+            json!({
+                "torrent": torrent,
+                "magnet": magnet,
+                "save_path": save_path,
+                "label": label,
+                "paused": paused,
+            }),
+        )?;
+        Ok(self.handle(TorrentHandle(added["handle"].as_u64().unwrap_or_default())))
+    }
+
+    // In queue order
+    fn torrents(&self) -> PyResult<Vec<PyTorrentHandle>> {
+        let daemon = self.shared.daemon()?;
+        let handles: Vec<_> = daemon.as_ref().unwrap().session().handles().collect();
+        Ok(handles
+            .into_iter()
+            .map(|handle| self.handle(handle))
+            .collect())
+    }
+
+    fn find(&self, info_hash: &PyInfoHash) -> PyResult<Option<PyTorrentHandle>> {
+        let daemon = self.shared.daemon()?;
+        let found = daemon.as_ref().unwrap().session().find(info_hash.0);
+        Ok(found.map(|handle| self.handle(handle)))
+    }
+
+    // The `session` call: rates, totals, torrent counts and so on
+    fn status<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        json_to_py(py, &self.shared.call("session", Value::Null)?)
+    }
+
+    // Only what happens from here on. `types` takes the event names the JSON-RPC `subscribe`
+    // does, None is all of them
+    #[pyo3(signature = (types = None))]
+    fn subscribe(&self, types: Option<Vec<String>>) -> PyResult<PyEvents> {
+        let mut daemon = self.shared.daemon()?;
+        let subscription = daemon.as_mut().unwrap().session_mut().subscribe();
+        Ok(PyEvents {
+            shared: self.shared.clone(),
+            subscription: Arc::new(Mutex::new(subscription)),
+            types: types.unwrap_or_default(),
+        })
+    }
+
+    // Stops the torrents, tells the trackers and saves the session, like the daemon does on its
+    // way out. Event streams end. Closing twice is fine
+    fn close(&self, py: Python<'_>) -> PyResult<()> {
+        self.shared.closed.store(true, Ordering::Relaxed);
+        let runner = self.runner.lock().unwrap().take();
+        let daemon = self.shared.daemon.lock().unwrap().take();
+        let report = py.allow_threads(|| {
+            if let Some(runner) = runner {
+                let _ = runner.join();
+            }
+            daemon.map(Daemon::shutdown)
+        });
+        match report.and_then(|report| report.save) {
+            Some(kind) => Err(PyRuntimeError::new_err(format!(
+                "saving the session failed: {}",
+                std::io::Error::from(kind)
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    #[pyo3(signature = (*_args))]
+    fn __exit__(&self, py: Python<'_>, _args: &Bound<'_, PyTuple>) -> PyResult<bool> {
+        self.close(py)?;
+        Ok(false)
+    }
+}
+
+impl PySession {
+    fn handle(&self, handle: TorrentHandle) -> PyTorrentHandle {
+        PyTorrentHandle {
+            shared: self.shared.clone(),
+            handle,
+        }
+    }
+}
+
+fn run(shared: Weak<Shared>) {
+    while let Some(shared) = shared.upgrade() {
+        if shared.closed.load(Ordering::Relaxed) {
+            break;
+        }
+        if let Some(daemon) = shared.daemon.lock().unwrap().as_mut() {
+            daemon.poll(Instant::now());
+        }
+        drop(shared);
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+// Stays valid after its torrent is removed; calls then raise KeyError
+#[pyclass(name = "TorrentHandle", frozen)]
+struct PyTorrentHandle {
+    shared: Arc<Shared>,
+    handle: TorrentHandle,
+}
+
+#[pymethods]
+impl PyTorrentHandle {
+    #[getter]
+    fn id(&self) -> u64 {
+        self.handle.0
+    }
+
+    // The dict the JSON-RPC `status` call has for it
+    fn status<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let statuses = self
+            .shared
+            .call("status", json!({"handles": [self.handle.0]}))?;
+        match &statuses[0] {
+            Value::Null => Err(PyKeyError::new_err("unknown torrent")),
+            status => json_to_py(py, status),
+        }
+    }
+
+    fn peers<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let peers = self
+            .shared
+            .call("peers", json!({"handle": self.handle.0}))
+            .map_err(|_| PyKeyError::new_err("unknown torrent"))?;
+        json_to_py(py, &peers)
+    }
+
+    fn pause(&self) -> PyResult<()> {
+        self.batch("pause", json!({}))
+    }
+
+    fn resume(&self) -> PyResult<()> {
+        self.batch("resume", json!({}))
+    }
+
+    #[pyo3(signature = (*, delete_files = false))]
+    fn remove(&self, delete_files: bool) -> PyResult<()> {
+        self.batch("remove", json!({"delete_files": delete_files}))
+    }
+
+    // Rates in bytes a second. None is unlimited, or the session's own limit for max_peers
+    #[pyo3(signature = (*, download_rate = None, upload_rate = None, max_peers = None))]
+    fn set_limits(
+        &self,
+        download_rate: Option<u64>,
+        upload_rate: Option<u64>,
+        max_peers: Option<u64>,
+    ) -> PyResult<()> {
+        self.batch(
+            "set_limits",
+            json!({
+                "download_rate": download_rate,
+                "upload_rate": upload_rate,
+                "max_peers": max_peers,
+            }),
+        )
+    }
+
+    fn __eq__(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared) && self.handle == other.handle
+    }
+
+    fn __hash__(&self) -> u64 {
+        self.handle.0
+    }
+
+    fn __repr__(&self) -> String {
+        format!("TorrentHandle({})", self.handle.0)
+    }
+}
+
+impl PyTorrentHandle {
+    // A call on `handles`, which answers with null or an error for each
+    fn batch(&self, method: &str, mut params: Value) -> PyResult<()> {
+        params["handles"] = json!([self.handle.0]);
+        match &self.shared.call(method, params)?[0] {
+            Value::String(err) if err == "unknown torrent" => Err(PyKeyError::new_err(err.clone())),
+            Value::String(err) => Err(PyRuntimeError::new_err(err.clone())),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Dicts the way `subscribe` sends them over JSON-RPC, with a TorrentHandle for `handle`. Events
+// dropped because nobody read them in time are counted in `missed`
+#[pyclass(name = "Events", frozen)]
+struct PyEvents {
+    shared: Arc<Shared>,
+    subscription: Arc<Mutex<Subscription<Event>>>,
+    types: Vec<String>,
+}
+
+#[pymethods]
+impl PyEvents {
+    #[getter]
+    fn missed(&self) -> u64 {
+        self.subscription.lock().unwrap().missed()
+    }
+
+    fn __iter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    // Blocks until the next one, or until the session is closed
+    fn __next__(&self, py: Python<'_>) -> PyResult<Option<PyObject>> {
+        loop {
+            match py.allow_threads(|| self.wait(WAIT_INTERVAL)) {
+                Wait::Event(event) => return Ok(Some(self.to_py(py, &event)?.unbind())),
+                Wait::Closed => return Ok(None),
+                Wait::Nothing => py.check_signals()?,
+            }
+        }
+    }
+
+    fn __aiter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    // Waits on the running loop's default executor, whose threads the interpreter knows to wait
+    // for on its way out. A closed session ends the stream with StopAsyncIteration
+    fn __anext__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let event_loop = py.import("asyncio")?.call_method0("get_running_loop")?;
+        let next = Next {
+            events: PyEvents {
+                shared: self.shared.clone(),
+                subscription: self.subscription.clone(),
+                types: self.types.clone(),
+            },
+            gone: Arc::new(AtomicBool::new(false)),
+        };
+        let gone = Done(next.gone.clone());
+        let future = event_loop.call_method1("run_in_executor", (py.None(), next))?;
+        future.call_method1("add_done_callback", (gone,))?;
+        Ok(future)
+    }
+}
+
+// What `__anext__` runs on the executor
+#[pyclass(frozen)]
+struct Next {
+    events: PyEvents,
+    gone: Arc<AtomicBool>,
+}
+
+#[pymethods]
+impl Next {
+    fn __call__(&self, py: Python<'_>) -> PyResult<PyObject> {
+        loop {
+            match py.allow_threads(|| self.events.wait(WAIT_INTERVAL)) {
+                Wait::Event(event) => return Ok(self.events.to_py(py, &event)?.unbind()),
+                Wait::Closed => return Err(PyStopAsyncIteration::new_err(())),
+                // Nobody's waiting anymore, e.g. the task was cancelled
+                Wait::Nothing if self.gone.load(Ordering::Relaxed) => return Ok(py.None()),
+                Wait::Nothing => {}
+            }
+        }
+    }
+}
+
+// Tells `Next` its future was cancelled
+#[pyclass(frozen)]
+struct Done(Arc<AtomicBool>);
+
+#[pymethods]
+impl Done {
+    fn __call__(&self, _future: &Bound<'_, PyAny>) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+enum Wait {
+    Event(Event),
+    Nothing,
+    Closed,
+}
+
+impl PyEvents {
+    fn wait(&self, timeout: Duration) -> Wait {
+        let subscription = self.subscription.lock().unwrap();
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.shared.closed.load(Ordering::Relaxed) {
+                // Whatever was sent before the close still comes out
+                return match subscription.try_recv() {
+                    Some(event) => Wait::Event(event),
+                    None => Wait::Closed,
+                };
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Wait::Nothing;
+            }
+            match subscription.recv_timeout(deadline - now) {
+                Some(event) if self.wanted(&event) => return Wait::Event(event),
+                Some(_) => {}
+                None => return Wait::Nothing,
+            }
+        }
+    }
+
+    fn wanted(&self, event: &Event) -> bool {
+        let json = event_json(event);
+        self.types.is_empty() || self.types.iter().any(|t| json["type"] == t.as_str())
+    }
+
+    fn to_py<'py>(&self, py: Python<'py>, event: &Event) -> PyResult<Bound<'py, PyAny>> {
+        let json = event_json(event);
+        let dict = json_to_py(py, &json)?;
+        if let Some(handle) = json["handle"].as_u64() {
+            let handle = PyTorrentHandle {
+                shared: self.shared.clone(),
+                handle: TorrentHandle(handle),
+            };
+            dict.set_item("handle", handle)?;
+        }
+        Ok(dict)
+    }
+}
+
+fn json_to_py<'py>(py: Python<'py>, value: &Value) -> PyResult<Bound<'py, PyAny>> {
+    Ok(match value {
+        Value::Null => py.None().into_bound(py),
+        Value::Bool(b) => b.into_pyobject(py)?.to_owned().into_any(),
+        Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+            (Some(n), _) => n.into_pyobject(py)?.into_any(),
+            (None, Some(n)) => n.into_pyobject(py)?.into_any(),
+            _ => n.as_f64().unwrap_or_default().into_pyobject(py)?.into_any(),
+        },
+        Value::String(s) => PyString::new(py, s).into_any(),
+        Value::Array(items) => {
+            let out = PyList::empty(py);
+            for item in items {
+                out.append(json_to_py(py, item)?)?;
+            }
+            out.into_any()
+        }
+        Value::Object(fields) => {
+            let out = PyDict::new(py);
+            for (key, item) in fields {
+                out.set_item(key, json_to_py(py, item)?)?;
+            }
+            out.into_any()
+        }
+    })
+}
+
+#[pyclass(name = "InfoHash", frozen, eq, hash)]
+#[derive(PartialEq, Eq, Hash)]
+struct PyInfoHash(InfoHash);
+
+#[pymethods]
+impl PyInfoHash {
+    #[new]
+    fn new(bytes: &[u8]) -> PyResult<Self> {
+        InfoHash::from_bytes(bytes)
+            .map(PyInfoHash)
+            .ok_or_else(|| PyValueError::new_err("info-hash must be 20 bytes"))
+    }
+
+    #[staticmethod]
+    fn from_hex(hex: &str) -> PyResult<Self> {
+        InfoHash::from_hex(hex)
+            .map(PyInfoHash)
+            .ok_or_else(|| PyValueError::new_err("info-hash must be 40 hex characters"))
+    }
+
+    fn hex(&self) -> String {
+        self.0.to_hex()
+    }
+
+    fn __bytes__<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, self.0.as_bytes())
+    }
+
+    fn __str__(&self) -> String {
+        self.0.to_hex()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("InfoHash('{}')", self.0.to_hex())
+    }
+}
+
+// Decodes a single bencoded value into ints, bytes, lists and dicts with bytes keys
+#[pyfunction]
+fn bdecode<'py>(py: Python<'py>, buf: &[u8]) -> PyResult<Bound<'py, PyAny>> {
+    let values = bencode::decode(buf).map_err(|e| PyValueError::new_err(e.to_string()))?;
+    match values.as_slice() {
+        [value] => to_py(py, value),
+        _ => Err(PyValueError::new_err("expected exactly one bencoded value")),
+    }
+}
+
+// Strings are accepted anywhere bytes are and get encoded as UTF-8
+#[pyfunction]
+#[pyo3(name = "bencode")]
+fn bencode_value<'py>(py: Python<'py>, value: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyBytes>> {
+    Ok(PyBytes::new(py, &bencode::encode(&from_py(value)?)))
+}
+
+fn to_py<'py>(py: Python<'py>, value: &BencodeValue) -> PyResult<Bound<'py, PyAny>> {
+    Ok(match value {
+        BencodeValue::Int(i) => i.into_pyobject(py)?.into_any(),
+        BencodeValue::ByteStr(bytes) => PyBytes::new(py, bytes).into_any(),
+        BencodeValue::List(list) => {
+            let out = PyList::empty(py);
+            for item in list {
+                out.append(to_py(py, item)?)?;
+            }
+            out.into_any()
+        }
+        BencodeValue::Dict(dict) => {
+            let out = PyDict::new(py);
+            for (key, item) in dict {
+                out.set_item(PyBytes::new(py, key), to_py(py, item)?)?;
+            }
+            out.into_any()
+        }
+    })
+}
+
+fn from_py(value: &Bound<'_, PyAny>) -> PyResult<BencodeValue> {
+    if value.is_instance_of::<PyInt>() {
+        Ok(BencodeValue::Int(value.extract()?))
+    } else if value.is_instance_of::<PyBytes>() || value.is_instance_of::<PyString>() {
+        Ok(BencodeValue::ByteStr(bytes_from_py(value)?))
+    } else if value.is_instance_of::<PyList>() || value.is_instance_of::<PyTuple>() {
+        let items = value
+            .try_iter()?
+            .map(|item| from_py(&item?))
+            .collect::<PyResult<_>>()?;
+        Ok(BencodeValue::List(items))
+    } else if let Ok(dict) = value.downcast::<PyDict>() {
+        let mut out = BTreeMap::new();
+        for (key, item) in dict {
+            out.insert(bytes_from_py(&key)?, from_py(&item)?);
+        }
+        Ok(BencodeValue::Dict(out))
+    } else {
+        Err(PyTypeError::new_err(format!(
+            "can't bencode {}",
+            value.get_type().name()?
+        )))
+    }
+}
+
+fn bytes_from_py(value: &Bound<'_, PyAny>) -> PyResult<Vec<u8>> {
+    if let Ok(s) = value.downcast::<PyString>() {
+        Ok(s.to_str()?.as_bytes().to_vec())
+    } else {
+        value.extract()
+    }
+}
+
+#[pymodule]
+fn hurricane(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyInfoHash>()?;
+    m.add_class::<PySession>()?;
+    m.add_class::<PyTorrentHandle>()?;
+    m.add_class::<PyEvents>()?;
+    m.add_function(wrap_pyfunction!(bdecode, m)?)?;
+    m.add_function(wrap_pyfunction!(bencode_value, m)?)?;
+    Ok(())
+}
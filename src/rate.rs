@@ -0,0 +1,104 @@
+use std::time::{Duration, Instant};
+
+// How often accumulated bytes get folded into the average
+const TICK: Duration = Duration::from_secs(1);
+
+// Weight given to the newest sample. Higher reacts faster but is noisier
+const ALPHA: f64 = 0.3;
+
+// Exponentially weighted moving average of a transfer rate, in bytes per second.
+// Callers add bytes as they move and call `tick` periodically; time is always passed in so the
+// estimator can be driven deterministically in tests
+#[derive(Debug, Clone)]
+pub struct Rate {
+    avg: f64,
+    primed: bool,
+    pending: u64,
+    total: u64,
+    last_tick: Instant,
+}
+
+impl Rate {
+    pub fn new(now: Instant) -> Self {
+        Rate {
+            avg: 0.0,
+            primed: false,
+            pending: 0,
+            total: 0,
+            last_tick: now,
+        }
+    }
+
+    pub fn add(&mut self, bytes: u64) {
+        self.pending += bytes;
+        self.total += bytes;
+    }
+
+    pub fn tick(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_tick);
+        if elapsed < TICK {
+            return;
+        }
+
+        let sample = self.pending as f64 / elapsed.as_secs_f64();
+        if self.primed {
+            self.avg += ALPHA * (sample - self.avg);
+        } else {
+            // The first sample would otherwise take several ticks to climb up from zero
+            self.avg = sample;
+            self.primed = true;
+        }
+
+        self.pending = 0;
+        self.last_tick = now;
+    }
+
+    // Current smoothed rate in bytes per second
+    pub fn get(&self) -> f64 {
+        self.avg
+    }
+
+    // Every byte ever added
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_first_sample() {
+        let start = Instant::now();
+        let mut rate = Rate::new(start);
+        rate.add(2000);
+        rate.tick(start + Duration::from_secs(2));
+
+        assert_eq!(rate.get(), 1000.0);
+        assert_eq!(rate.total(), 2000);
+    }
+
+    #[test]
+    fn test_rate_waits_for_tick() {
+        let start = Instant::now();
+        let mut rate = Rate::new(start);
+        rate.add(2000);
+        rate.tick(start + Duration::from_millis(500));
+
+        assert_eq!(rate.get(), 0.0);
+    }
+
+    #[test]
+    fn test_rate_smoothing() {
+        let start = Instant::now();
+        let mut rate = Rate::new(start);
+        rate.add(1000);
+        rate.tick(start + Duration::from_secs(1));
+        rate.tick(start + Duration::from_secs(2));
+
+        // One idle second pulls the average down, but not all the way
+        assert!(rate.get() < 1000.0);
+        assert!(rate.get() > 0.0);
+    }
+}
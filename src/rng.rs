@@ -0,0 +1,87 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+// Small xorshift64* generator for things like tie-breaking and picking random samples.
+// NOT suitable for anything security related
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new() -> Self {
+        // RandomState is seeded by the OS, which saves us pulling in a dependency for this
+        let seed = RandomState::new().build_hasher().finish();
+        Rng::with_seed(seed)
+    }
+
+    pub fn with_seed(seed: u64) -> Self {
+        // xorshift gets stuck at zero forever
+        Rng(if seed == 0 { 0x9e3779b97f4a7c15 } else { seed })
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    // Uniform-ish number in [0, n). The modulo bias is irrelevant for our use cases
+    pub fn below(&mut self, n: usize) -> usize {
+        assert!(n > 0);
+        (self.next_u64() % n as u64) as usize
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = self.below(i + 1);
+            items.swap(i, j);
+        }
+    }
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Rng::new()
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_rng_seeded_deterministic() {
+        let mut a = Rng::with_seed(42);
+        let mut b = Rng::with_seed(42);
+
+        assert_eq!(a.next_u64(), b.next_u64());
+        assert_eq!(a.next_u64(), b.next_u64());
+    }
+
+    #[test]
+    fn test_rng_below() {
+        let mut rng = Rng::with_seed(1);
+        for _ in 0..1000 {
+            assert!(rng.below(7) < 7);
+        }
+    }
+
+    #[test]
+    fn test_rng_shuffle_keeps_items() {
+        let mut rng = Rng::with_seed(7);
+        let mut items: Vec<u32> = (0..50).collect();
+        rng.shuffle(&mut items);
+        items.sort();
+
+        assert_eq!(items, (0..50).collect::<Vec<u32>>());
+    }
+}
@@ -0,0 +1,247 @@
+// Access control for a remote control endpoint, the daemon's JSON-RPC socket (see `daemon`). It's
+// the part every request goes through, kept sans-IO so it works with whatever ends up serving it:
+// - a bind check that refuses wildcard and public addresses unless they're on the allowlist, and
+//   refuses anything but loopback without a token and TLS, since an open control port hands out
+//   the whole machine's disk, and a token sent in the clear is as good as none
+// - bearer token auth, compared in constant time
+// - a per-client request budget, charged before the token is checked so guessing it is slow too
+// The TLS itself is the server's business, this only knows where its certificate and key are;
+// the daemon's is in `tls`.
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+#[derive(PartialEq, Debug)]
+pub enum RpcError {
+    BindNotAllowed(IpAddr),
+    // Binding off loopback with no token configured
+    TokenRequired,
+    // Or with no certificate and key to serve TLS with
+    TlsRequired,
+    Unauthorized,
+    RateLimited,
+}
+
+// Where the daemon listens unless told otherwise
+pub const DEFAULT_PORT: u16 = 9091;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct RpcConfig {
+    // Sent by clients as `Authorization: Bearer <token>`. None only works on loopback
+    pub token: Option<String>,
+    // Addresses the server may bind to besides loopback, which is always allowed
+    pub allowed_binds: Vec<IpAddr>,
+    // Requests per client per window
+    pub max_requests: u32,
+    pub window: Duration,
+    // PEM files: the certificate chain to serve, and its private key. Needed off loopback
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+}
+
+impl Default for RpcConfig {
+    fn default() -> Self {
+        RpcConfig {
+            token: None,
+            allowed_binds: vec![],
+            max_requests: 50,
+            window: Duration::from_secs(1),
+            tls_cert: None,
+            tls_key: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Budget {
+    used: u32,
+    window_start: Instant,
+}
+
+#[derive(Debug)]
+pub struct RpcGuard {
+    config: RpcConfig,
+    clients: HashMap<IpAddr, Budget>,
+}
+
+impl RpcGuard {
+    pub fn new(config: RpcConfig) -> Self {
+        RpcGuard {
+            config,
+            clients: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &RpcConfig {
+        &self.config
+    }
+
+    // Call before binding the listener
+    pub fn check_bind(&self, addr: &SocketAddr) -> Result<(), RpcError> {
+        let ip = addr.ip();
+        if ip.is_loopback() {
+            return Ok(());
+        }
+        if !self.config.allowed_binds.contains(&ip) {
+            return Err(RpcError::BindNotAllowed(ip));
+        }
+        if self.config.token.is_none() {
+            return Err(RpcError::TokenRequired);
+        }
+        if self.config.tls_cert.is_none() || self.config.tls_key.is_none() {
+            return Err(RpcError::TlsRequired);
+        }
+        Ok(())
+    }
+
+    // `authorization` is the request's Authorization header, if it had one
+    pub fn authorize(
+        &mut self,
+        client: IpAddr,
+        authorization: Option<&str>,
+        now: Instant,
+    ) -> Result<(), RpcError> {
+        let window = self.config.window;
+        let budget = self.clients.entry(client).or_insert(Budget {
+            used: 0,
+            window_start: now,
+        });
+        if now.saturating_duration_since(budget.window_start) >= window {
+            *budget = Budget {
+                used: 0,
+                window_start: now,
+            };
+        }
+        if budget.used >= self.config.max_requests {
+            return Err(RpcError::RateLimited);
+        }
+        budget.used += 1;
+
+        let Some(token) = &self.config.token else {
+            // Only reachable over loopback, see `check_bind`
+            return Ok(());
+        };
+        let given = authorization
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .unwrap_or("");
+        if constant_time_eq(given.as_bytes(), token.as_bytes()) {
+            Ok(())
+        } else {
+            Err(RpcError::Unauthorized)
+        }
+    }
+
+    // Forgets clients whose window has run out. Call every so often
+    pub fn prune(&mut self, now: Instant) {
+        let window = self.config.window;
+        self.clients
+            .retain(|_, budget| now.saturating_duration_since(budget.window_start) < window);
+    }
+}
+
+// Loopback for each family, the default place for the server to listen
+pub fn default_binds(port: u16) -> [SocketAddr; 2] {
+    [
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+        SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port),
+    ]
+}
+
+// Time depends on the length only, not on where the first difference is
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn client() -> IpAddr {
+        "192.168.1.20".parse().unwrap()
+    }
+
+    #[test]
+    fn test_bind_allowlist() {
+        let mut config = RpcConfig::default();
+        let guard = RpcGuard::new(config.clone());
+        for addr in default_binds(9091) {
+            assert_eq!(guard.check_bind(&addr), Ok(()));
+        }
+        let lan: SocketAddr = "192.168.1.10:9091".parse().unwrap();
+        assert_eq!(
+            guard.check_bind(&"0.0.0.0:9091".parse().unwrap()),
+            Err(RpcError::BindNotAllowed(Ipv4Addr::UNSPECIFIED.into()))
+        );
+
+        config.allowed_binds = vec![lan.ip()];
+        let guard = RpcGuard::new(config.clone());
+        assert_eq!(guard.check_bind(&lan), Err(RpcError::TokenRequired));
+        config.token = Some("secret".to_string());
+        assert_eq!(
+            RpcGuard::new(config.clone()).check_bind(&lan),
+            Err(RpcError::TlsRequired)
+        );
+        config.tls_cert = Some(PathBuf::from("cert.pem"));
+        assert_eq!(
+            RpcGuard::new(config.clone()).check_bind(&lan),
+            Err(RpcError::TlsRequired)
+        );
+        config.tls_key = Some(PathBuf::from("key.pem"));
+        assert_eq!(RpcGuard::new(config).check_bind(&lan), Ok(()));
+    }
+
+    #[test]
+    fn test_token() {
+        let now = Instant::now();
+        let mut guard = RpcGuard::new(RpcConfig {
+            token: Some("secret".to_string()),
+            ..RpcConfig::default()
+        });
+
+        assert_eq!(
+            guard.authorize(client(), Some("Bearer secret"), now),
+            Ok(())
+        );
+        assert_eq!(
+            guard.authorize(client(), Some("Bearer secreT"), now),
+            Err(RpcError::Unauthorized)
+        );
+        assert_eq!(
+            guard.authorize(client(), Some("secret"), now),
+            Err(RpcError::Unauthorized)
+        );
+        assert_eq!(
+            guard.authorize(client(), None, now),
+            Err(RpcError::Unauthorized)
+        );
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let now = Instant::now();
+        let mut guard = RpcGuard::new(RpcConfig {
+            max_requests: 3,
+            ..RpcConfig::default()
+        });
+
+        for _ in 0..3 {
+            assert_eq!(guard.authorize(client(), None, now), Ok(()));
+        }
+        assert_eq!(
+            guard.authorize(client(), None, now),
+            Err(RpcError::RateLimited)
+        );
+        // Other clients have budgets of their own
+        let other = "192.168.1.21".parse().unwrap();
+        assert_eq!(guard.authorize(other, None, now), Ok(()));
+
+        let later = now + Duration::from_secs(1);
+        assert_eq!(guard.authorize(client(), None, later), Ok(()));
+        guard.prune(later + Duration::from_secs(1));
+        assert!(guard.clients.is_empty());
+    }
+}
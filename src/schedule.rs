@@ -0,0 +1,389 @@
+// Rate limits that change with the time of day and the day of the week, e.g. unlimited at night
+// and 1 MiB/s during work hours. A schedule is a list of rules, the first one covering the current
+// time wins, and outside all of them the default limits apply. Its text form, one rule a line, is
+// what goes in the config file:
+//
+//     default up=2M
+//     mon-fri 09:00-17:00 up=1M down=1M
+//     * 01:00-07:00 up=unlimited down=unlimited
+//
+// Rates are bytes per second with an optional K/M/G (binary) suffix; a direction left out of a
+// rule is unlimited. A rule that ends before it starts runs past midnight into the next day.
+// Nothing here reads the clock or the time zone, which std doesn't know about: the caller works
+// out the local time and hands it to `BandwidthScheduler::poll`, whose changes go to
+// `UploadLimiter::set_limit` and `TokenBucket::set_rate`. Those keep their buckets, so a change
+// only speeds up or slows down the refill and no peer is choked or dropped over it.
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+// Bytes per second, None for unlimited
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct RateLimits {
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+}
+
+// A minute of some day of the week, in local time
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct LocalTime {
+    // 0 is Monday
+    pub weekday: u8,
+    pub minute: u16,
+}
+
+impl LocalTime {
+    // `utc_offset` in seconds, east of UTC positive
+    pub fn from_system(time: SystemTime, utc_offset: i64) -> Self {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64)
+            + utc_offset;
+        let minutes = secs.div_euclid(60);
+        let days = minutes.div_euclid(MINUTES_PER_DAY as i64);
+        LocalTime {
+            // The epoch was a Thursday
+            weekday: (days + 3).rem_euclid(7) as u8,
+            minute: minutes.rem_euclid(MINUTES_PER_DAY as i64) as u16,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct ScheduleRule {
+    // Bit 0 is Monday. For rules past midnight, the days they start on
+    pub days: u8,
+    // Minutes since midnight. `end` is exclusive, and equal to `start` for the whole day
+    pub start: u16,
+    pub end: u16,
+    pub limits: RateLimits,
+}
+
+impl ScheduleRule {
+    pub fn covers(&self, at: LocalTime) -> bool {
+        let on = |day: u8| self.days & (1 << day) != 0;
+        let yesterday = (at.weekday + 6) % 7;
+        match self.start.cmp(&self.end) {
+            Ordering::Less => on(at.weekday) && (self.start..self.end).contains(&at.minute),
+            Ordering::Greater => {
+                (on(at.weekday) && at.minute >= self.start)
+                    || (on(yesterday) && at.minute < self.end)
+            }
+            Ordering::Equal => on(at.weekday),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct BandwidthSchedule {
+    pub default: RateLimits,
+    pub rules: Vec<ScheduleRule>,
+}
+
+impl BandwidthSchedule {
+    pub fn limits_at(&self, at: LocalTime) -> RateLimits {
+        self.rules
+            .iter()
+            .find(|rule| rule.covers(at))
+            .map_or(self.default, |rule| rule.limits)
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct ScheduleError {
+    // Counting from 1
+    pub line: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+impl FromStr for BandwidthSchedule {
+    type Err = ScheduleError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut schedule = BandwidthSchedule::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |reason| ScheduleError {
+                line: i + 1,
+                reason,
+            };
+            let mut words = line.split_whitespace();
+            let first = words.next().unwrap_or("");
+            if first == "default" {
+                schedule.default = parse_limits(words).map_err(error)?;
+                continue;
+            }
+            let days = parse_days(first).ok_or(error("bad days"))?;
+            let (start, end) = words
+                .next()
+                .and_then(parse_hours)
+                .ok_or(error("bad hours"))?;
+            let limits = parse_limits(words).map_err(error)?;
+            schedule.rules.push(ScheduleRule {
+                days,
+                start,
+                end,
+                limits,
+            });
+        }
+        Ok(schedule)
+    }
+}
+
+// Parses back to the same schedule, though not necessarily the same text
+impl fmt::Display for BandwidthSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "default{}", DisplayLimits(self.default))?;
+        for rule in &self.rules {
+            let days: Vec<&str> = (0..7)
+                .filter(|day| rule.days & (1 << day) != 0)
+                .map(|day| DAY_NAMES[day])
+                .collect();
+            let days = if days.len() == 7 {
+                "*".to_string()
+            } else {
+                days.join(",")
+            };
+            let end = if rule.start == rule.end && rule.start == 0 {
+                MINUTES_PER_DAY
+            } else {
+                rule.end
+            };
+            writeln!(
+                f,
+                "{} {:02}:{:02}-{:02}:{:02}{}",
+                days,
+                rule.start / 60,
+                rule.start % 60,
+                end / 60,
+                end % 60,
+                DisplayLimits(rule.limits)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+struct DisplayLimits(RateLimits);
+
+impl fmt::Display for DisplayLimits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, rate) in [("up", self.0.upload), ("down", self.0.download)] {
+            let Some(rate) = rate else {
+                continue;
+            };
+            let (value, suffix) = [(1 << 30, "G"), (1 << 20, "M"), (1 << 10, "K")]
+                .into_iter()
+                .find(|(unit, _)| rate >= *unit && rate.is_multiple_of(*unit))
+                .map_or((rate, ""), |(unit, suffix)| (rate / unit, suffix));
+            write!(f, " {}={}{}", name, value, suffix)?;
+        }
+        Ok(())
+    }
+}
+
+// `mon`, `mon-fri` (wrapping, so `fri-mon` is the long weekend), `sat,sun` or `*` for every day
+fn parse_days(text: &str) -> Option<u8> {
+    if text == "*" {
+        return Some(0x7f);
+    }
+    let day = |name: &str| {
+        DAY_NAMES
+            .iter()
+            .position(|day| day.eq_ignore_ascii_case(name))
+    };
+    let mut days = 0u8;
+    for part in text.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (day(first)?, day(last)?);
+                let len = (last + 7 - first) % 7 + 1;
+                for i in 0..len {
+                    days |= 1 << ((first + i) % 7);
+                }
+            }
+            None => days |= 1 << day(part)?,
+        }
+    }
+    Some(days)
+}
+
+// `09:00-17:30`. `24:00` is allowed as an end, and `00:00-24:00` is the whole day
+fn parse_hours(text: &str) -> Option<(u16, u16)> {
+    let time = |text: &str| -> Option<u16> {
+        let (hours, minutes) = text.split_once(':')?;
+        let (hours, minutes): (u16, u16) = (hours.parse().ok()?, minutes.parse().ok()?);
+        let minute = hours * 60 + minutes;
+        (minutes < 60 && minute <= MINUTES_PER_DAY).then_some(minute)
+    };
+    let (start, end) = text.split_once('-')?;
+    let (start, end) = (time(start)?, time(end)?);
+    if start == MINUTES_PER_DAY {
+        return None;
+    }
+    Some((start, end % MINUTES_PER_DAY))
+}
+
+fn parse_limits<'a>(words: impl Iterator<Item = &'a str>) -> Result<RateLimits, &'static str> {
+    let mut limits = RateLimits::default();
+    for word in words {
+        let (name, value) = word.split_once('=').ok_or("expected up= or down=")?;
+        let rate = parse_rate(value).ok_or("bad rate")?;
+        match name {
+            "up" => limits.upload = rate,
+            "down" => limits.download = rate,
+            _ => return Err("expected up= or down="),
+        }
+    }
+    Ok(limits)
+}
+
+pub(crate) fn parse_rate(text: &str) -> Option<Option<u64>> {
+    if text == "unlimited" {
+        return Some(None);
+    }
+    let (digits, unit) = match text.char_indices().last()? {
+        (i, 'K' | 'k') => (&text[..i], 1 << 10),
+        (i, 'M' | 'm') => (&text[..i], 1 << 20),
+        (i, 'G' | 'g') => (&text[..i], 1 << 30),
+        _ => (text, 1),
+    };
+    let rate: u64 = digits.parse().ok()?;
+    Some(Some(rate.checked_mul(unit)?))
+}
+
+// Tracks which limits are in force, so the caller only touches its limiters on a change
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthScheduler {
+    schedule: BandwidthSchedule,
+    applied: Option<RateLimits>,
+}
+
+impl BandwidthScheduler {
+    pub fn new(schedule: BandwidthSchedule) -> Self {
+        BandwidthScheduler {
+            schedule,
+            applied: None,
+        }
+    }
+
+    pub fn schedule(&self) -> &BandwidthSchedule {
+        &self.schedule
+    }
+
+    // Takes effect on the next `poll`, whether or not the limits actually changed
+    pub fn set_schedule(&mut self, schedule: BandwidthSchedule) {
+        self.schedule = schedule;
+        self.applied = None;
+    }
+
+    // Call every so often, a minute apart at most. Returns the limits to switch to, if the
+    // schedule moved on since the last call
+    pub fn poll(&mut self, at: LocalTime) -> Option<RateLimits> {
+        let limits = self.schedule.limits_at(at);
+        if self.applied == Some(limits) {
+            return None;
+        }
+        self.applied = Some(limits);
+        Some(limits)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn at(weekday: u8, hours: u16, minutes: u16) -> LocalTime {
+        LocalTime {
+            weekday,
+            minute: hours * 60 + minutes,
+        }
+    }
+
+    const TEXT: &str = "
+        # work hours are for work
+        default up=2M
+        mon-fri 09:00-17:00 up=1M down=1M
+        fri-sun 22:00-06:00 up=unlimited
+    ";
+
+    #[test]
+    fn test_limits_by_time() {
+        let schedule: BandwidthSchedule = TEXT.parse().unwrap();
+        let work = RateLimits {
+            upload: Some(1 << 20),
+            download: Some(1 << 20),
+        };
+        let night = RateLimits::default();
+        let default = RateLimits {
+            upload: Some(2 << 20),
+            download: None,
+        };
+
+        assert_eq!(schedule.limits_at(at(0, 9, 0)), work);
+        assert_eq!(schedule.limits_at(at(0, 17, 0)), default);
+        assert_eq!(schedule.limits_at(at(5, 12, 0)), default);
+        // Friday night runs into Saturday morning, Sunday night into Monday's
+        assert_eq!(schedule.limits_at(at(4, 23, 0)), night);
+        assert_eq!(schedule.limits_at(at(5, 5, 59)), night);
+        assert_eq!(schedule.limits_at(at(0, 1, 0)), night);
+        assert_eq!(schedule.limits_at(at(3, 23, 0)), default);
+        assert_eq!(schedule.limits_at(at(4, 1, 0)), default);
+    }
+
+    #[test]
+    fn test_parse_round_trip() {
+        let schedule: BandwidthSchedule = TEXT.parse().unwrap();
+        assert_eq!(
+            schedule.to_string().parse::<BandwidthSchedule>(),
+            Ok(schedule)
+        );
+
+        let whole_day: BandwidthSchedule = "sat,sun 00:00-24:00 up=500K".parse().unwrap();
+        assert!(whole_day.rules[0].covers(at(6, 23, 59)));
+        assert!(!whole_day.rules[0].covers(at(0, 0, 0)));
+        assert_eq!(
+            whole_day.to_string(),
+            "default\nsat,sun 00:00-24:00 up=500K\n"
+        );
+
+        let err = |text: &str| text.parse::<BandwidthSchedule>().unwrap_err();
+        assert_eq!(err("\nweekdays 09:00-17:00").line, 2);
+        assert_eq!(err("mon 09:00-25:00").reason, "bad hours");
+        assert_eq!(err("mon 09:00-17:00 up=fast").reason, "bad rate");
+        assert_eq!(err("default sideways=1K").reason, "expected up= or down=");
+    }
+
+    #[test]
+    fn test_scheduler_reports_changes() {
+        let mut scheduler = BandwidthScheduler::new(TEXT.parse().unwrap());
+        assert!(scheduler.poll(at(0, 8, 59)).is_some());
+        assert_eq!(scheduler.poll(at(0, 8, 59)), None);
+        assert_eq!(scheduler.poll(at(0, 9, 0)).unwrap().upload, Some(1 << 20));
+        assert_eq!(scheduler.poll(at(0, 10, 0)), None);
+
+        scheduler.set_schedule(BandwidthSchedule::default());
+        assert_eq!(scheduler.poll(at(0, 10, 0)), Some(RateLimits::default()));
+
+        // 2024-01-01 was a Monday
+        let monday = UNIX_EPOCH + Duration::from_secs(1_704_067_200);
+        assert_eq!(LocalTime::from_system(monday, 0), at(0, 0, 0));
+        assert_eq!(LocalTime::from_system(monday, -90 * 60), at(6, 22, 30));
+    }
+}
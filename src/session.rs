@@ -0,0 +1,1353 @@
+// The torrents a client runs, by handle. Handles stay valid (and unique) for the life of the
+// session, unlike info-hashes which change when a torrent moves to a new version. The batch
+// methods take many handles at once so a UI acting on a multi-selection makes one call, not
+// hundreds; each torrent is done completely or not at all, and gets its own result.
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::blocklist::IpFilter;
+use crate::dht::{Dht, DhtEvent, Subscription as FeedSubscription};
+use crate::disk::{Allocation, Backend, MoveProgress, Relocation};
+use crate::events::{Broadcast, Event, EventKind, Subscription};
+use crate::infohash::InfoHash;
+use crate::metainfo::magnet::MagnetError;
+use crate::metainfo::{MagnetLink, Metainfo, MetainfoError, MutableMagnet};
+use crate::schedule::{BandwidthSchedule, BandwidthScheduler, LocalTime, RateLimits};
+use crate::torrent::{SeedAction, SeedGoal, SeedGoals, Torrent, TorrentLimits, TorrentStatus};
+
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Debug, Clone, Copy)]
+pub struct TorrentHandle(pub u64);
+
+#[derive(PartialEq, Debug)]
+pub enum SessionError {
+    // No torrent with that handle, e.g. it was removed already
+    UnknownTorrent,
+    // A .torrent file that doesn't parse
+    InvalidTorrent(MetainfoError),
+    InvalidMagnet(MagnetError),
+    // The torrent is still waiting for its info dict from peers
+    MissingMetadata,
+    Io(io::ErrorKind),
+}
+
+impl From<io::Error> for SessionError {
+    fn from(err: io::Error) -> Self {
+        SessionError::Io(err.kind())
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Added {
+    New(TorrentHandle),
+    // The info-hash was already in the session, and the new copy's trackers, web seeds and peers
+    // were merged into the torrent we had
+    Merged(TorrentHandle),
+}
+
+impl Added {
+    pub fn handle(self) -> TorrentHandle {
+        match self {
+            Added::New(handle) | Added::Merged(handle) => handle,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct RemoveOptions {
+    // Also delete the torrent's files from disk
+    pub delete_files: bool,
+}
+
+// Settings for every torrent `add_torrents_from_dir` or `add_file` adds
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct AddDefaults {
+    // Where the data goes, and where to look for data that's already there
+    pub save_path: Option<PathBuf>,
+    pub label: Option<String>,
+    pub paused: bool,
+    pub backend: Backend,
+    pub allocation: Allocation,
+    // Time between the rechecks of torrents whose data was found, so an import of hundreds
+    // doesn't have them all reading the disk at once
+    pub recheck_interval: Duration,
+}
+
+impl Default for AddDefaults {
+    fn default() -> Self {
+        AddDefaults {
+            save_path: None,
+            label: None,
+            paused: false,
+            backend: Backend::default(),
+            allocation: Allocation::default(),
+            recheck_interval: Duration::from_secs(2),
+        }
+    }
+}
+
+// How many torrents may run at once. Force-started torrents don't count, and never wait
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct QueueLimits {
+    // None for no limit. Torrents fetching metadata count as downloading
+    pub downloading: Option<usize>,
+    pub seeding: Option<usize>,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        QueueLimits {
+            downloading: Some(3),
+            seeding: Some(5),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum QueueMove {
+    Up,
+    Down,
+    Top,
+    Bottom,
+}
+
+// A torrent met one of its seed goals and `action` was taken on it
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct SeedGoalEvent {
+    pub handle: TorrentHandle,
+    pub goal: SeedGoal,
+    pub action: SeedAction,
+}
+
+// What became of one .torrent file in an import
+#[derive(PartialEq, Debug)]
+pub struct Imported {
+    pub path: PathBuf,
+    pub result: Result<Added, SessionError>,
+    // Some of its files were already there, so it's waiting for a recheck
+    pub found_data: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Session {
+    // Each behind a lock of its own, so a torrent's peers can work on it between calls, see
+    // `shared`
+    torrents: BTreeMap<TorrentHandle, Arc<Mutex<Torrent>>>,
+    next_handle: u64,
+    // Rechecks to start, in order of when
+    checks: VecDeque<(Instant, TorrentHandle)>,
+    bandwidth: BandwidthScheduler,
+    // For torrents without goals of their own
+    seed_goals: SeedGoals,
+    // Every torrent, first in line first
+    queue: Vec<TorrentHandle>,
+    queue_limits: QueueLimits,
+    events: Broadcast<Event>,
+    // The session's own events, waiting for `publish_events`
+    pending: Vec<Event>,
+    // As of the last `publish_events`, to tell what changed
+    statuses: BTreeMap<TorrentHandle, TorrentStatus>,
+    // Every torrent's peer list shares it
+    ip_filter: IpFilter,
+    // The torrents that follow a publisher's key, see `follow`
+    feeds: BTreeMap<TorrentHandle, Feed>,
+}
+
+// A BEP 46 link being followed, and how to set up the torrent of its first version
+#[derive(Debug)]
+struct Feed {
+    magnet: MutableMagnet,
+    subscription: FeedSubscription,
+    defaults: AddDefaults,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session::default()
+    }
+
+    // Adding a torrent we already have isn't an error: users re-add a magnet link to pick up
+    // more trackers, or the .torrent to skip the metadata download
+    pub fn add(&mut self, mut torrent: Torrent, now: Instant) -> Added {
+        if let Some(handle) = self.find(torrent.info_hash()) {
+            self.torrents[&handle].lock().unwrap().merge(torrent, now);
+            return Added::Merged(handle);
+        }
+        torrent.peer_list_mut().set_filter(self.ip_filter.clone());
+        let handle = TorrentHandle(self.next_handle);
+        self.next_handle += 1;
+        self.pending.push(Event {
+            handle,
+            info_hash: torrent.info_hash(),
+            kind: EventKind::Added {
+                name: torrent.name().to_string(),
+            },
+        });
+        self.torrents.insert(handle, Arc::new(Mutex::new(torrent)));
+        self.queue.push(handle);
+        Added::New(handle)
+    }
+
+    // Follows a BEP 46 link: the handle has no torrent until the publisher's current version is
+    // found in the DHT, and its torrent is replaced with each newer one after that. Drive it with
+    // `poll_feeds` and `on_dht_event`
+    pub fn follow(
+        &mut self,
+        magnet: MutableMagnet,
+        defaults: AddDefaults,
+        now: Instant,
+    ) -> TorrentHandle {
+        let handle = TorrentHandle(self.next_handle);
+        self.next_handle += 1;
+        let subscription = FeedSubscription::new(magnet.public_key, &magnet.salt, now);
+        self.feeds.insert(
+            handle,
+            Feed {
+                magnet,
+                subscription,
+                defaults,
+            },
+        );
+        handle
+    }
+
+    // Has a torrent that's in the session already follow `magnet`, e.g. when it's loaded back
+    pub fn set_feed(&mut self, handle: TorrentHandle, magnet: MutableMagnet, now: Instant) {
+        let subscription = FeedSubscription::new(magnet.public_key, &magnet.salt, now);
+        self.feeds.insert(
+            handle,
+            Feed {
+                magnet,
+                subscription,
+                defaults: AddDefaults::default(),
+            },
+        );
+    }
+
+    // The link a torrent follows, if it was added by one
+    pub fn feed(&self, handle: TorrentHandle) -> Option<&MutableMagnet> {
+        self.feeds.get(&handle).map(|feed| &feed.magnet)
+    }
+
+    pub fn has_feeds(&self) -> bool {
+        !self.feeds.is_empty()
+    }
+
+    // Looks up the feeds that are due for it in `dht`
+    pub fn poll_feeds(&mut self, dht: &mut Dht, now: Instant) {
+        for feed in self.feeds.values_mut() {
+            feed.subscription.poll(dht, now);
+        }
+    }
+
+    // Feed every event of the DHT that `poll_feeds` uses through here. Returns the torrents that
+    // moved to a new version: whatever runs their peers has to start over with the new info-hash
+    pub fn on_dht_event(&mut self, event: &DhtEvent, now: Instant) -> Vec<TorrentHandle> {
+        let updates: Vec<(TorrentHandle, InfoHash)> = self
+            .feeds
+            .iter_mut()
+            .filter_map(|(handle, feed)| Some((*handle, feed.subscription.on_event(event)?)))
+            .collect();
+        updates
+            .into_iter()
+            .filter(|(handle, info_hash)| self.update(*handle, *info_hash, now))
+            .map(|(handle, _)| handle)
+            .collect()
+    }
+
+    // Puts version `info_hash` of a feed under its handle. It's fetched by magnet link, into the
+    // same place as the version before, with the same settings
+    fn update(&mut self, handle: TorrentHandle, info_hash: InfoHash, now: Instant) -> bool {
+        let feed = &self.feeds[&handle];
+        let magnet = MagnetLink {
+            info_hash,
+            display_name: feed.magnet.display_name.clone(),
+            trackers: feed.magnet.trackers.clone(),
+            peers: feed.magnet.peers.clone(),
+        };
+        let mut torrent = Torrent::from_magnet(&magnet, now);
+        torrent.peer_list_mut().set_filter(self.ip_filter.clone());
+        let Some(previous) = self.torrents.get(&handle) else {
+            apply_defaults(&mut torrent, &feed.defaults);
+            self.pending.push(Event {
+                handle,
+                info_hash,
+                kind: EventKind::Added {
+                    name: torrent.name().to_string(),
+                },
+            });
+            self.torrents.insert(handle, Arc::new(Mutex::new(torrent)));
+            self.queue.push(handle);
+            return true;
+        };
+
+        let mut previous = previous.lock().unwrap();
+        if previous.info_hash() == info_hash {
+            return false;
+        }
+        if let Some(save_path) = previous.save_path() {
+            torrent.set_save_path(save_path.to_path_buf());
+        }
+        torrent.set_label(previous.label().map(str::to_string));
+        torrent.set_backend(previous.backend());
+        torrent.set_allocation(previous.allocation());
+        torrent.set_limits(previous.limits());
+        torrent.set_seed_goals(previous.seed_goals());
+        if previous.status() == TorrentStatus::Paused {
+            torrent.set_status(TorrentStatus::Paused);
+        }
+        // What the old version had queued still goes out under its own info-hash
+        let old = previous.info_hash();
+        let kinds = previous
+            .take_events()
+            .into_iter()
+            .chain([EventKind::Updated { previous: old }]);
+        let events: Vec<Event> = kinds
+            .map(|kind| Event {
+                handle,
+                info_hash: match kind {
+                    EventKind::Updated { .. } => info_hash,
+                    _ => old,
+                },
+                kind,
+            })
+            .collect();
+        drop(previous);
+        self.pending.extend(events);
+        self.torrents.insert(handle, Arc::new(Mutex::new(torrent)));
+        true
+    }
+
+    // Adds every .torrent file in `dir` (not its subdirectories), in name order. Torrents whose
+    // data is already under the save path get a recheck scheduled, one every `recheck_interval`;
+    // see `due_checks`. The rest have nothing to check and start downloading
+    pub fn add_torrents_from_dir(
+        &mut self,
+        dir: &Path,
+        defaults: &AddDefaults,
+        now: Instant,
+    ) -> io::Result<Vec<Imported>> {
+        let mut paths = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let is_torrent = path
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("torrent"));
+            if is_torrent && path.is_file() {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        Ok(paths
+            .into_iter()
+            .map(|path| self.add_file(&path, defaults, now))
+            .collect())
+    }
+
+    // Adds a .torrent file, or a .magnet file: a text file holding a magnet link. A .torrent
+    // whose data is already under the save path gets a recheck scheduled, like in
+    // `add_torrents_from_dir`
+    pub fn add_file(&mut self, path: &Path, defaults: &AddDefaults, now: Instant) -> Imported {
+        match self.import(path, defaults, now) {
+            Ok((added, found_data)) => Imported {
+                path: path.to_path_buf(),
+                result: Ok(added),
+                found_data,
+            },
+            Err(err) => Imported {
+                path: path.to_path_buf(),
+                result: Err(err),
+                found_data: false,
+            },
+        }
+    }
+
+    // Rehashes everything on disk: each torrent forgets what it has and goes to `CheckingFiles`,
+    // and is first in line in `due_checks`. Run `resume::recover` on its storage then and hand the
+    // result to `Torrent::finish_recheck`, which reports the corrupt pieces. A running torrent
+    // should have its `DiskIo` paused meanwhile
+    pub fn force_recheck(
+        &mut self,
+        handles: &[TorrentHandle],
+        now: Instant,
+    ) -> Vec<Result<(), SessionError>> {
+        handles
+            .iter()
+            .map(|handle| {
+                let torrent = self
+                    .torrents
+                    .get(handle)
+                    .ok_or(SessionError::UnknownTorrent)?;
+                if !torrent.lock().unwrap().begin_recheck() {
+                    return Err(SessionError::MissingMetadata);
+                }
+                self.checks.retain(|(_, h)| h != handle);
+                let at = self.checks.partition_point(|(at, _)| *at <= now);
+                self.checks.insert(at, (now, *handle));
+                Ok(())
+            })
+            .collect()
+    }
+
+    // Torrents whose scheduled recheck is due, in order. Handles that were removed meanwhile are
+    // skipped
+    pub fn due_checks(&mut self, now: Instant) -> Vec<TorrentHandle> {
+        let mut due = vec![];
+        while let Some((at, handle)) = self.checks.front().copied() {
+            if at > now {
+                break;
+            }
+            self.checks.pop_front();
+            if self.torrents.contains_key(&handle) {
+                due.push(handle);
+            }
+        }
+        due
+    }
+
+    fn import(
+        &mut self,
+        path: &Path,
+        defaults: &AddDefaults,
+        now: Instant,
+    ) -> Result<(Added, bool), SessionError> {
+        let buf = fs::read(path)?;
+        let is_magnet = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("magnet"));
+        if is_magnet {
+            let text = String::from_utf8_lossy(&buf);
+            let uri = text.lines().map(str::trim).find(|line| !line.is_empty());
+            let magnet =
+                MagnetLink::parse(uri.unwrap_or_default()).map_err(SessionError::InvalidMagnet)?;
+            let mut torrent = Torrent::from_magnet(&magnet, now);
+            apply_defaults(&mut torrent, defaults);
+            return Ok((self.add(torrent, now), false));
+        }
+
+        let metainfo = Metainfo::from_bytes(&buf).map_err(SessionError::InvalidTorrent)?;
+        let found_data = defaults.save_path.as_ref().is_some_and(|save_path| {
+            metainfo.info.files.iter().any(|file| {
+                let relative: PathBuf = file.path.iter().collect();
+                save_path.join(relative).exists()
+            })
+        });
+
+        let mut torrent = Torrent::new(metainfo, now);
+        if !found_data {
+            torrent.set_status(TorrentStatus::Downloading);
+        }
+        apply_defaults(&mut torrent, defaults);
+
+        let added = self.add(torrent, now);
+        if let (Added::New(handle), true) = (added, found_data) {
+            let at = match self.checks.back() {
+                Some((last, _)) => (*last + defaults.recheck_interval).max(now),
+                None => now,
+            };
+            self.checks.push_back((at, handle));
+        }
+        Ok((added, found_data))
+    }
+
+    pub fn find(&self, info_hash: InfoHash) -> Option<TorrentHandle> {
+        self.torrents
+            .iter()
+            .find(|(_, torrent)| torrent.lock().unwrap().info_hash() == info_hash)
+            .map(|(handle, _)| *handle)
+    }
+
+    // Hold it briefly: the torrent's peers need it too, when something runs them
+    pub fn get(&self, handle: TorrentHandle) -> Option<MutexGuard<'_, Torrent>> {
+        Some(self.torrents.get(&handle)?.lock().unwrap())
+    }
+
+    pub fn get_mut(&mut self, handle: TorrentHandle) -> Option<MutexGuard<'_, Torrent>> {
+        self.get(handle)
+    }
+
+    // The torrent itself, for whatever runs its peers (`Download::attach`). It stays in the
+    // session: status, queueing and events still go through here
+    pub fn shared(&self, handle: TorrentHandle) -> Option<Arc<Mutex<Torrent>>> {
+        self.torrents.get(&handle).cloned()
+    }
+
+    pub fn contains(&self, handle: TorrentHandle) -> bool {
+        self.torrents.contains_key(&handle)
+    }
+
+    pub fn handles(&self) -> impl Iterator<Item = TorrentHandle> + '_ {
+        self.torrents.keys().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.torrents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.torrents.is_empty()
+    }
+
+    // Pausing a force-started torrent ends the force start too
+    pub fn pause(&mut self, handles: &[TorrentHandle]) -> Vec<Result<(), SessionError>> {
+        self.each(handles, |torrent| {
+            torrent.set_force_start(false);
+            torrent.set_status(TorrentStatus::Paused);
+            Ok(())
+        })
+    }
+
+    // Paused torrents go back in line, see `update_queue`. Force-started ones go straight back
+    // to work
+    pub fn resume(&mut self, handles: &[TorrentHandle]) -> Vec<Result<(), SessionError>> {
+        self.each(handles, |torrent| {
+            if torrent.status() == TorrentStatus::Paused {
+                torrent.set_status(TorrentStatus::Queued);
+                if torrent.is_force_started() {
+                    torrent.set_status(torrent.active_status());
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // Reloading its list (`IpFilter::load`) applies to every torrent at once
+    pub fn ip_filter(&self) -> &IpFilter {
+        &self.ip_filter
+    }
+
+    pub fn queue_limits(&self) -> QueueLimits {
+        self.queue_limits
+    }
+
+    // Takes effect on the next `update_queue`
+    pub fn set_queue_limits(&mut self, limits: QueueLimits) {
+        self.queue_limits = limits;
+    }
+
+    // Every torrent, first in line first
+    pub fn queue(&self) -> &[TorrentHandle] {
+        &self.queue
+    }
+
+    // 0 is first in line
+    pub fn queue_position(&self, handle: TorrentHandle) -> Option<usize> {
+        self.queue.iter().position(|h| *h == handle)
+    }
+
+    // Positions past the end put it last
+    pub fn set_queue_position(
+        &mut self,
+        handle: TorrentHandle,
+        position: usize,
+    ) -> Result<(), SessionError> {
+        let from = self
+            .queue_position(handle)
+            .ok_or(SessionError::UnknownTorrent)?;
+        self.queue.remove(from);
+        self.queue.insert(position.min(self.queue.len()), handle);
+        Ok(())
+    }
+
+    // Moves a selection together, keeping its order: `Top` puts the whole selection first in the
+    // order it was in the queue, `Up` moves each one past its neighbour unless that one's selected
+    // too, and so on
+    pub fn move_in_queue(
+        &mut self,
+        handles: &[TorrentHandle],
+        direction: QueueMove,
+    ) -> Vec<Result<(), SessionError>> {
+        let results = handles
+            .iter()
+            .map(|handle| match self.queue.contains(handle) {
+                true => Ok(()),
+                false => Err(SessionError::UnknownTorrent),
+            })
+            .collect();
+        let selected = |handle: &TorrentHandle| handles.contains(handle);
+        match direction {
+            QueueMove::Top | QueueMove::Bottom => {
+                let (moved, rest): (Vec<_>, Vec<_>) = self.queue.iter().partition(|h| selected(h));
+                self.queue = match direction {
+                    QueueMove::Top => moved.into_iter().chain(rest).collect(),
+                    _ => rest.into_iter().chain(moved).collect(),
+                };
+            }
+            QueueMove::Up => {
+                for i in 1..self.queue.len() {
+                    if selected(&self.queue[i]) && !selected(&self.queue[i - 1]) {
+                        self.queue.swap(i, i - 1);
+                    }
+                }
+            }
+            QueueMove::Down => {
+                for i in (0..self.queue.len().saturating_sub(1)).rev() {
+                    if selected(&self.queue[i]) && !selected(&self.queue[i + 1]) {
+                        self.queue.swap(i, i + 1);
+                    }
+                }
+            }
+        }
+        results
+    }
+
+    // Hands out the download and seed slots in queue order: torrents that fit start (or keep
+    // going), the rest are `Queued`. One that finished downloading moves over to the seed slots
+    // and lets the next download in. Paused torrents and ones being checked sit this out.
+    // Call after anything that changes a torrent's status; returns the torrents whose status
+    // changed, so their peers can be started or stopped
+    pub fn update_queue(&mut self) -> Vec<(TorrentHandle, TorrentStatus)> {
+        let mut changed = vec![];
+        let (mut downloading, mut seeding) = (0, 0);
+        for handle in &self.queue {
+            let mut torrent = self.torrents[handle].lock().unwrap();
+            let queueable = matches!(
+                torrent.status(),
+                TorrentStatus::DownloadingMetadata
+                    | TorrentStatus::Downloading
+                    | TorrentStatus::Seeding
+                    | TorrentStatus::Queued
+            );
+            if !queueable || torrent.is_force_started() {
+                continue;
+            }
+            let active = torrent.active_status();
+            let (count, limit) = match active {
+                TorrentStatus::Seeding => (&mut seeding, self.queue_limits.seeding),
+                _ => (&mut downloading, self.queue_limits.downloading),
+            };
+            let status = if limit.is_none_or(|limit| *count < limit) {
+                *count += 1;
+                active
+            } else {
+                TorrentStatus::Queued
+            };
+            if torrent.status() != status {
+                torrent.set_status(status);
+                changed.push((*handle, status));
+            }
+        }
+        changed
+    }
+
+    pub fn set_label(
+        &mut self,
+        handles: &[TorrentHandle],
+        label: Option<&str>,
+    ) -> Vec<Result<(), SessionError>> {
+        self.each(handles, |torrent| {
+            torrent.set_label(label.map(str::to_string));
+            Ok(())
+        })
+    }
+
+    pub fn set_limits(
+        &mut self,
+        handles: &[TorrentHandle],
+        limits: TorrentLimits,
+    ) -> Vec<Result<(), SessionError>> {
+        self.each(handles, |torrent| {
+            torrent.set_limits(limits);
+            Ok(())
+        })
+    }
+
+    // Moves a torrent's files to `to` and makes that its save path. The torrent keeps running:
+    // pause its `DiskIo` and wait out the jobs in flight first, then point it at the new
+    // `Torrent::storage` and resume. If the move fails the files are back where they were, the
+    // save path is unchanged and the old storage is still good
+    pub fn move_storage(
+        &mut self,
+        handle: TorrentHandle,
+        to: &Path,
+        progress: impl FnMut(MoveProgress),
+    ) -> Result<(), SessionError> {
+        let mut torrent = self.get_mut(handle).ok_or(SessionError::UnknownTorrent)?;
+        if let (Some(metainfo), Some(from)) = (torrent.metainfo(), torrent.save_path()) {
+            let relocation = Relocation {
+                from: from.to_path_buf(),
+                to: to.to_path_buf(),
+            };
+            if relocation.from != relocation.to {
+                relocation.apply_with_progress(&metainfo.info.files, progress)?;
+            }
+        }
+        torrent.set_save_path(to.to_path_buf());
+        Ok(())
+    }
+
+    pub fn default_seed_goals(&self) -> SeedGoals {
+        self.seed_goals
+    }
+
+    pub fn set_default_seed_goals(&mut self, goals: SeedGoals) {
+        self.seed_goals = goals;
+    }
+
+    // Call after ticking the torrents. Pauses or removes each seeding torrent that met a goal
+    pub fn check_seed_goals(&mut self, now: Instant) -> Vec<SeedGoalEvent> {
+        let mut events = vec![];
+        for (handle, torrent) in &self.torrents {
+            let mut torrent = torrent.lock().unwrap();
+            let Some(goal) = torrent.seed_goal_reached(&self.seed_goals, now) else {
+                continue;
+            };
+            let action = torrent.seed_goals().unwrap_or(self.seed_goals).action;
+            if action == SeedAction::Pause {
+                torrent.set_status(TorrentStatus::Paused);
+            }
+            events.push(SeedGoalEvent {
+                handle: *handle,
+                goal,
+                action,
+            });
+            self.pending.push(Event {
+                handle: *handle,
+                info_hash: torrent.info_hash(),
+                kind: EventKind::SeedGoalReached { goal, action },
+            });
+        }
+        for event in &events {
+            if event.action == SeedAction::Remove {
+                self.forget(event.handle);
+            }
+        }
+        events
+    }
+
+    pub fn bandwidth_schedule(&self) -> &BandwidthSchedule {
+        self.bandwidth.schedule()
+    }
+
+    // Replaces the schedule of session-wide rate limits, e.g. from the config file or over RPC.
+    // The next `poll_rate_limits` hands out whatever it says for the current time
+    pub fn set_bandwidth_schedule(&mut self, schedule: BandwidthSchedule) {
+        self.bandwidth.set_schedule(schedule);
+    }
+
+    // The session-wide limits to switch to, when the schedule says they change. Apply them with
+    // `UploadLimiter::set_limit` and the download bucket's `set_rate`, which leave peers be
+    pub fn poll_rate_limits(&mut self, at: LocalTime) -> Option<RateLimits> {
+        self.bandwidth.poll(at)
+    }
+
+    // Call when `SuspendDetector` says we just woke up. The DHT, the port mapper and each peer's
+    // request pipeline have `on_wake`s of their own
+    pub fn on_wake(&mut self, now: Instant) {
+        for torrent in self.torrents.values() {
+            torrent.lock().unwrap().on_wake(now);
+        }
+    }
+
+    // A torrent whose files can't be deleted stays in the session, so the user can try again
+    pub fn remove(
+        &mut self,
+        handles: &[TorrentHandle],
+        options: RemoveOptions,
+    ) -> Vec<Result<(), SessionError>> {
+        handles
+            .iter()
+            .map(|handle| {
+                // Still looking for its first version
+                if !self.torrents.contains_key(handle) && self.feeds.remove(handle).is_some() {
+                    return Ok(());
+                }
+                let torrent = self.get(*handle).ok_or(SessionError::UnknownTorrent)?;
+                if options.delete_files {
+                    delete_files(&torrent)?;
+                }
+                drop(torrent);
+                self.forget(*handle);
+                Ok(())
+            })
+            .collect()
+    }
+
+    fn forget(&mut self, handle: TorrentHandle) {
+        if let Some(torrent) = self.torrents.remove(&handle) {
+            let mut torrent = torrent.lock().unwrap();
+            // What it had queued still goes out, before it's gone
+            let info_hash = torrent.info_hash();
+            let kinds = torrent
+                .take_events()
+                .into_iter()
+                .chain([EventKind::Removed]);
+            self.pending.extend(kinds.map(|kind| Event {
+                handle,
+                info_hash,
+                kind,
+            }));
+        }
+        self.queue.retain(|h| *h != handle);
+        self.statuses.remove(&handle);
+        self.feeds.remove(&handle);
+    }
+
+    // Events from `publish_events` on, see `events`
+    pub fn subscribe(&mut self) -> Subscription<Event> {
+        self.events.subscribe()
+    }
+
+    // Call once per tick, after everything else. Sends what happened since the last call to the
+    // subscribers: the session's own events, then each torrent's in queue order, each followed
+    // by its change of status if it had one. Torrents added since come with no status change
+    pub fn publish_events(&mut self) {
+        let mut events = std::mem::take(&mut self.pending);
+        for handle in &self.queue {
+            let mut torrent = self.torrents[handle].lock().unwrap();
+            let info_hash = torrent.info_hash();
+            let event = |kind| Event {
+                handle: *handle,
+                info_hash,
+                kind,
+            };
+            events.extend(torrent.take_events().into_iter().map(event));
+            let status = torrent.status();
+            match self.statuses.insert(*handle, status) {
+                Some(before) if before != status => {
+                    events.push(event(EventKind::StatusChanged(status)))
+                }
+                _ => {}
+            }
+        }
+        for event in &events {
+            self.events.send(event);
+        }
+    }
+
+    fn each<F>(&mut self, handles: &[TorrentHandle], mut f: F) -> Vec<Result<(), SessionError>>
+    where
+        F: FnMut(&mut Torrent) -> Result<(), SessionError>,
+    {
+        handles
+            .iter()
+            .map(|handle| match self.torrents.get(handle) {
+                Some(torrent) => f(&mut torrent.lock().unwrap()),
+                None => Err(SessionError::UnknownTorrent),
+            })
+            .collect()
+    }
+}
+
+fn apply_defaults(torrent: &mut Torrent, defaults: &AddDefaults) {
+    if let Some(save_path) = &defaults.save_path {
+        torrent.set_save_path(save_path.clone());
+    }
+    torrent.set_label(defaults.label.clone());
+    torrent.set_backend(defaults.backend);
+    torrent.set_allocation(defaults.allocation);
+    if defaults.paused {
+        torrent.set_status(TorrentStatus::Paused);
+    }
+}
+
+// Files that were never created are fine. The torrent's directory goes too if that leaves it
+// empty
+fn delete_files(torrent: &Torrent) -> io::Result<()> {
+    let (Some(metainfo), Some(save_path), Some(storage)) =
+        (torrent.metainfo(), torrent.save_path(), torrent.storage())
+    else {
+        return Ok(());
+    };
+    for file in 0..metainfo.info.files.len() {
+        match fs::remove_file(storage.path(file)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+    if metainfo.info.files.len() > 1 {
+        let _ = fs::remove_dir(save_path.join(&metainfo.info.name));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::metainfo::MagnetLink;
+    use crate::tracker::TrackerError;
+
+    fn session() -> (Session, Vec<TorrentHandle>) {
+        let mut session = Session::new();
+        let handles = (0..3)
+            .map(|i| {
+                let magnet = MagnetLink::new(InfoHash([i; 20]));
+                session
+                    .add(
+                        Torrent::from_magnet(&magnet, Instant::now()),
+                        Instant::now(),
+                    )
+                    .handle()
+            })
+            .collect();
+        (session, handles)
+    }
+
+    #[test]
+    fn test_batch_results_per_handle() {
+        let (mut session, handles) = session();
+        let mut targets = handles[..2].to_vec();
+        targets.push(TorrentHandle(99));
+
+        assert_eq!(
+            session.set_label(&targets, Some("tv")),
+            vec![Ok(()), Ok(()), Err(SessionError::UnknownTorrent)]
+        );
+        assert_eq!(session.get(handles[1]).unwrap().label(), Some("tv"));
+        assert_eq!(session.get(handles[2]).unwrap().label(), None);
+
+        let limits = TorrentLimits {
+            upload_rate: Some(1000),
+            ..TorrentLimits::default()
+        };
+        session.set_limits(&handles, limits);
+        assert!(
+            handles
+                .iter()
+                .all(|h| session.get(*h).unwrap().limits() == limits)
+        );
+    }
+
+    #[test]
+    fn test_pause_ends_force_start() {
+        let (mut session, handles) = session();
+        session.get_mut(handles[0]).unwrap().set_force_start(true);
+
+        assert!(session.pause(&handles).iter().all(Result::is_ok));
+        let torrent = session.get(handles[0]).unwrap();
+        assert_eq!(torrent.status(), TorrentStatus::Paused);
+        assert!(!torrent.is_force_started());
+    }
+
+    #[test]
+    fn test_add_duplicate_merges() {
+        let now = Instant::now();
+        let (mut session, handles) = session();
+        let mut magnet = MagnetLink::new(InfoHash([1; 20]));
+        magnet.trackers = vec!["http://t.example/announce".to_string()];
+        session.set_label(&handles[1..2], Some("tv"));
+
+        let added = session.add(Torrent::from_magnet(&magnet, now), now);
+        assert_eq!(added, Added::Merged(handles[1]));
+        assert_eq!(session.len(), 3);
+        let torrent = session.get(handles[1]).unwrap();
+        assert_eq!(torrent.trackers().len(), 1);
+        assert_eq!(torrent.label(), Some("tv"));
+        drop(torrent);
+
+        let magnet = MagnetLink::new(InfoHash([7; 20]));
+        assert!(matches!(
+            session.add(Torrent::from_magnet(&magnet, now), now),
+            Added::New(_)
+        ));
+        assert_eq!(session.find(InfoHash([7; 20])), Some(TorrentHandle(3)));
+    }
+
+    #[test]
+    fn test_move_storage() {
+        let dir = std::env::temp_dir().join(format!("hurricane-move-{}", std::process::id()));
+        let buf = include_bytes!("../bencode/tests/fixtures/sample.torrent");
+        let metainfo = Metainfo::from_bytes(buf).unwrap();
+        let name = metainfo.info.name.clone();
+        let mut torrent = Torrent::new(metainfo, Instant::now());
+        torrent.set_save_path(dir.join("a"));
+        fs::create_dir_all(dir.join("a")).unwrap();
+        fs::write(dir.join("a").join(&name), b"data").unwrap();
+        let (mut session, _) = session();
+        let handle = session.add(torrent, Instant::now()).handle();
+
+        let mut last = MoveProgress::default();
+        session
+            .move_storage(handle, &dir.join("b"), |p| last = p)
+            .unwrap();
+        assert_eq!(last.bytes_done, 4);
+        assert_eq!(fs::read(dir.join("b").join(&name)).unwrap(), b"data");
+        let torrent = session.get(handle).unwrap();
+        assert_eq!(torrent.save_path(), Some(dir.join("b").as_path()));
+        drop(torrent);
+
+        // Moving onto a file that's already there fails and leaves the torrent where it was
+        fs::create_dir_all(dir.join("c")).unwrap();
+        fs::write(dir.join("c").join(&name), b"other").unwrap();
+        assert_eq!(
+            session.move_storage(handle, &dir.join("c"), |_| {}),
+            Err(SessionError::Io(io::ErrorKind::AlreadyExists))
+        );
+        let torrent = session.get(handle).unwrap();
+        assert_eq!(torrent.save_path(), Some(dir.join("b").as_path()));
+        assert!(dir.join("b").join(&name).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_add_torrents_from_dir() {
+        let now = Instant::now();
+        let dir = std::env::temp_dir().join(format!("hurricane-import-{}", std::process::id()));
+        let buf = include_bytes!("../bencode/tests/fixtures/sample.torrent");
+        let metainfo = Metainfo::from_bytes(buf).unwrap();
+        fs::create_dir_all(dir.join("torrents")).unwrap();
+        fs::create_dir_all(dir.join("data")).unwrap();
+        fs::write(dir.join("torrents/a.torrent"), buf).unwrap();
+        fs::write(dir.join("torrents/b.TORRENT"), buf).unwrap();
+        fs::write(dir.join("torrents/c.torrent"), b"not bencode").unwrap();
+        fs::write(dir.join("torrents/notes.txt"), b"").unwrap();
+        fs::write(dir.join("data").join(&metainfo.info.name), b"data").unwrap();
+
+        let (mut session, _) = session();
+        let defaults = AddDefaults {
+            save_path: Some(dir.join("data")),
+            label: Some("imported".to_string()),
+            ..AddDefaults::default()
+        };
+        let imported = session
+            .add_torrents_from_dir(&dir.join("torrents"), &defaults, now)
+            .unwrap();
+        let invalid =
+            SessionError::InvalidTorrent(Metainfo::from_bytes(b"not bencode").unwrap_err());
+        let results: Vec<_> = imported
+            .iter()
+            .map(|i| (i.path.file_name().unwrap().to_str().unwrap(), &i.result))
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                ("a.torrent", &Ok(Added::New(TorrentHandle(3)))),
+                ("b.TORRENT", &Ok(Added::Merged(TorrentHandle(3)))),
+                ("c.torrent", &Err(invalid)),
+            ]
+        );
+        assert!(imported[0].found_data);
+
+        let torrent = session.get(TorrentHandle(3)).unwrap();
+        assert_eq!(torrent.label(), Some("imported"));
+        assert_eq!(torrent.status(), TorrentStatus::CheckingFiles);
+        drop(torrent);
+        assert_eq!(session.due_checks(now), vec![TorrentHandle(3)]);
+        assert!(session.due_checks(now + Duration::from_secs(60)).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_checks_are_staggered() {
+        let now = Instant::now();
+        let (mut session, handles) = session();
+        for handle in &handles {
+            session.checks.push_back((now, *handle));
+        }
+        let interval = AddDefaults::default().recheck_interval;
+        let dir = std::env::temp_dir().join(format!("hurricane-stagger-{}", std::process::id()));
+        let buf = include_bytes!("../bencode/tests/fixtures/sample.torrent");
+        let metainfo = Metainfo::from_bytes(buf).unwrap();
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("s.torrent"), buf).unwrap();
+        fs::write(dir.join(&metainfo.info.name), b"data").unwrap();
+        let defaults = AddDefaults {
+            save_path: Some(dir.clone()),
+            ..AddDefaults::default()
+        };
+        session.add_torrents_from_dir(&dir, &defaults, now).unwrap();
+
+        session.remove(&handles[1..2], RemoveOptions::default());
+        assert_eq!(session.due_checks(now), vec![handles[0], handles[2]]);
+        assert!(session.due_checks(now + interval / 2).is_empty());
+        assert_eq!(session.due_checks(now + interval), vec![TorrentHandle(3)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_force_recheck() {
+        let now = Instant::now();
+        let (mut session, handles) = session();
+        let buf = include_bytes!("../bencode/tests/fixtures/sample.torrent");
+        let torrent = Torrent::new(Metainfo::from_bytes(buf).unwrap(), now);
+        let handle = session.add(torrent, now).handle();
+        session
+            .checks
+            .push_back((now + Duration::from_secs(5), handles[0]));
+
+        assert_eq!(
+            session.force_recheck(&[handle, handles[1]], now),
+            vec![Ok(()), Err(SessionError::MissingMetadata)]
+        );
+        let torrent = session.get(handle).unwrap();
+        assert_eq!(torrent.status(), TorrentStatus::CheckingFiles);
+        drop(torrent);
+        // Ahead of the check already waiting
+        assert_eq!(session.due_checks(now), vec![handle]);
+    }
+
+    #[test]
+    fn test_queue() {
+        let (mut session, handles) = session();
+        let [a, b, c] = handles[..] else { panic!() };
+        session.set_queue_limits(QueueLimits {
+            downloading: Some(2),
+            seeding: Some(1),
+        });
+        assert_eq!(session.update_queue(), vec![(c, TorrentStatus::Queued)]);
+        assert_eq!(session.update_queue(), vec![]);
+
+        session.set_queue_position(c, 0).unwrap();
+        assert_eq!(
+            session.update_queue(),
+            vec![
+                (c, TorrentStatus::DownloadingMetadata),
+                (b, TorrentStatus::Queued)
+            ]
+        );
+
+        // `a` finishes and takes the seed slot, letting `b` in
+        let buf = include_bytes!("../bencode/tests/fixtures/sample.torrent");
+        let mut torrent = session.get_mut(a).unwrap();
+        torrent.set_metainfo(Metainfo::from_bytes(buf).unwrap());
+        let picker = torrent.picker_mut().unwrap();
+        for piece in 0..picker.num_pieces() as u32 {
+            picker.piece_verified(piece);
+        }
+        torrent.set_status(TorrentStatus::Seeding);
+        drop(torrent);
+        assert_eq!(
+            session.update_queue(),
+            vec![(b, TorrentStatus::DownloadingMetadata)]
+        );
+
+        // Force-started and paused torrents don't take a slot
+        session.get_mut(c).unwrap().set_force_start(true);
+        session.pause(&[b]);
+        session.move_in_queue(&[b], QueueMove::Top);
+        assert_eq!(session.update_queue(), vec![]);
+        session.resume(&[b]);
+        assert_eq!(
+            session.update_queue(),
+            vec![(b, TorrentStatus::DownloadingMetadata)]
+        );
+    }
+
+    #[test]
+    fn test_move_in_queue() {
+        let (mut session, handles) = session();
+        let [a, b, c] = handles[..] else { panic!() };
+        let order = |session: &Session| -> Vec<TorrentHandle> {
+            let mut order: Vec<_> = session.handles().collect();
+            order.sort_by_key(|h| session.queue_position(*h));
+            order
+        };
+
+        session.move_in_queue(&[b, c], QueueMove::Up);
+        assert_eq!(order(&session), [b, c, a]);
+        session.move_in_queue(&[b, c], QueueMove::Up);
+        assert_eq!(order(&session), [b, c, a]);
+        assert_eq!(
+            session.move_in_queue(&[b, TorrentHandle(99)], QueueMove::Bottom),
+            vec![Ok(()), Err(SessionError::UnknownTorrent)]
+        );
+        assert_eq!(order(&session), [c, a, b]);
+        session.move_in_queue(&[c], QueueMove::Down);
+        assert_eq!(order(&session), [a, c, b]);
+        session.move_in_queue(&[b], QueueMove::Top);
+        assert_eq!(order(&session), [b, a, c]);
+
+        session.remove(&[a], RemoveOptions::default());
+        assert_eq!(session.queue_position(c), Some(1));
+    }
+
+    #[test]
+    fn test_seed_goals() {
+        let (mut session, handles) = session();
+        let later = Instant::now() + Duration::from_secs(10);
+        let goals = SeedGoals {
+            seed_time: Some(Duration::from_secs(10)),
+            ..SeedGoals::default()
+        };
+        session.set_default_seed_goals(goals);
+        for handle in &handles[..2] {
+            session
+                .get_mut(*handle)
+                .unwrap()
+                .set_status(TorrentStatus::Seeding);
+        }
+        session
+            .get_mut(handles[1])
+            .unwrap()
+            .set_seed_goals(Some(SeedGoals {
+                action: SeedAction::Remove,
+                ..goals
+            }));
+        assert!(session.check_seed_goals(later).is_empty());
+
+        for handle in handles.clone() {
+            session.get_mut(handle).unwrap().tick(later);
+        }
+        let events = session.check_seed_goals(later);
+        assert_eq!(
+            events,
+            vec![
+                SeedGoalEvent {
+                    handle: handles[0],
+                    goal: SeedGoal::SeedTime,
+                    action: SeedAction::Pause,
+                },
+                SeedGoalEvent {
+                    handle: handles[1],
+                    goal: SeedGoal::SeedTime,
+                    action: SeedAction::Remove,
+                },
+            ]
+        );
+        assert_eq!(
+            session.get(handles[0]).unwrap().status(),
+            TorrentStatus::Paused
+        );
+        assert!(session.get(handles[1]).is_none());
+        assert_eq!(session.len(), 2);
+    }
+
+    #[test]
+    fn test_remove_deletes_files() {
+        let dir = std::env::temp_dir().join(format!("hurricane-session-{}", std::process::id()));
+        let buf = include_bytes!("../bencode/tests/fixtures/sample.torrent");
+        let metainfo = Metainfo::from_bytes(buf).unwrap();
+        let name = metainfo.info.name.clone();
+        let mut torrent = Torrent::new(metainfo, Instant::now());
+        torrent.set_save_path(dir.clone());
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(&name), b"data").unwrap();
+
+        let (mut session, handles) = session();
+        let handle = session.add(torrent, Instant::now()).handle();
+        let options = RemoveOptions { delete_files: true };
+        assert_eq!(
+            session.remove(&[handle, handles[0], handle], options),
+            vec![Ok(()), Ok(()), Err(SessionError::UnknownTorrent)]
+        );
+
+        assert_eq!(session.len(), 2);
+        assert!(!dir.join(&name).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_events() {
+        let buf = include_bytes!("../bencode/tests/fixtures/sample.torrent");
+        let metainfo = Metainfo::from_bytes(buf).unwrap();
+        let info_hash = metainfo.info_hash;
+        let now = Instant::now();
+        let mut session = Session::new();
+        let events = session.subscribe();
+        let kinds = |session: &mut Session| {
+            session.publish_events();
+            events.try_iter().map(|e| e.kind).collect::<Vec<_>>()
+        };
+
+        let magnet = MagnetLink::new(info_hash);
+        let handle = session
+            .add(Torrent::from_magnet(&magnet, now), now)
+            .handle();
+        let name = session.get(handle).unwrap().name().to_string();
+        assert_eq!(kinds(&mut session), [EventKind::Added { name }]);
+
+        // Its .torrent brings the info dict
+        session.add(Torrent::new(metainfo, now), now);
+        assert_eq!(
+            kinds(&mut session),
+            [
+                EventKind::MetadataReceived,
+                EventKind::StatusChanged(TorrentStatus::CheckingFiles),
+            ]
+        );
+
+        let mut torrent = session.get_mut(handle).unwrap();
+        torrent.set_status(TorrentStatus::Downloading);
+        let num_pieces = torrent.picker().unwrap().num_pieces() as u32;
+        let finished: Vec<bool> = (0..num_pieces)
+            .map(|piece| torrent.on_piece_verified(piece))
+            .collect();
+        assert_eq!(finished.iter().filter(|f| **f).count(), 1);
+        assert!(finished[num_pieces as usize - 1]);
+        drop(torrent);
+        let mut expected: Vec<_> = (0..num_pieces).map(EventKind::PieceVerified).collect();
+        expected.push(EventKind::Completed);
+        expected.push(EventKind::StatusChanged(TorrentStatus::Seeding));
+        assert_eq!(kinds(&mut session), expected);
+
+        let mut torrent = session.get_mut(handle).unwrap();
+        torrent.on_tracker_warning("http://tracker/announce", "slow down");
+        torrent.on_tracker_error(
+            "udp://tracker:80",
+            &TrackerError::Failure("unregistered torrent".to_string()),
+        );
+        torrent.set_error("disk full".to_string());
+        drop(torrent);
+        session.remove(&[handle], RemoveOptions::default());
+        assert_eq!(
+            kinds(&mut session),
+            [
+                EventKind::TrackerWarning {
+                    url: "http://tracker/announce".to_string(),
+                    message: "slow down".to_string(),
+                },
+                EventKind::TrackerError {
+                    url: "udp://tracker:80".to_string(),
+                    message: "tracker failure: unregistered torrent".to_string(),
+                },
+                EventKind::Errored("disk full".to_string()),
+                EventKind::Removed,
+            ]
+        );
+        assert_eq!(events.missed(), 0);
+    }
+
+    #[test]
+    fn test_follow_feed() {
+        use crate::dht::item::Item;
+        use crate::dht::subscription::torrent_item;
+
+        let now = Instant::now();
+        let mut session = Session::new();
+        let events = session.subscribe();
+        let version = |seq, byte| {
+            let item = torrent_item(&[3; 32], b"", seq, InfoHash([byte; 20]));
+            DhtEvent::Item {
+                target: Item::Mutable(item.clone()).target(),
+                item: Item::Mutable(item),
+            }
+        };
+        let key = torrent_item(&[3; 32], b"", 1, InfoHash([1; 20])).key;
+        let defaults = AddDefaults {
+            label: Some("feed".to_string()),
+            ..AddDefaults::default()
+        };
+        let handle = session.follow(MutableMagnet::new(key, b""), defaults, now);
+        assert!(session.get(handle).is_none());
+
+        assert_eq!(session.on_dht_event(&version(1, 1), now), [handle]);
+        assert_eq!(session.get(handle).unwrap().label(), Some("feed"));
+        session.pause(&[handle]);
+        // The same version again, or an older one
+        assert!(session.on_dht_event(&version(1, 1), now).is_empty());
+
+        assert_eq!(session.on_dht_event(&version(2, 2), now), [handle]);
+        assert!(session.on_dht_event(&version(1, 1), now).is_empty());
+        let torrent = session.get(handle).unwrap();
+        assert_eq!(torrent.info_hash(), InfoHash([2; 20]));
+        assert_eq!(torrent.label(), Some("feed"));
+        assert_eq!(torrent.status(), TorrentStatus::Paused);
+        drop(torrent);
+        assert_eq!(session.queue(), [handle]);
+        session.publish_events();
+        let kinds: Vec<EventKind> = events.try_iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds.last(),
+            Some(&EventKind::Updated {
+                previous: InfoHash([1; 20])
+            })
+        );
+
+        session.remove(&[handle], RemoveOptions::default());
+        assert_eq!(session.feed(handle), None);
+    }
+}
@@ -0,0 +1,308 @@
+// Shutting down in order, so the swarm and the next start both find things as we left them:
+// 1. `stopped` announces to the trackers of every running torrent, so they drop us from their
+//    peer lists instead of handing out a dead address until we time out
+// 2. peer connections closed
+// 3. the port mappings removed from the router, so it stops forwarding to a port nobody listens on
+// 4. the disk cache flushed
+// 5. the session and every torrent's resume data saved, see `persist::save`
+// The whole thing is bounded by `ShutdownConfig::timeout`. Announces only get part of it, since
+// a dead tracker shouldn't cost the flush, and so do the port mappings. Past the deadline the flushes left are skipped: all
+// that costs is partial pieces, fetched again next time. The state is saved whatever the time,
+// it's what lets the next start skip a full check.
+// A shutdown is asked for with `request`, by the daemon's `shutdown` call or by SIGINT/SIGTERM
+// once `catch_signals` is set up. A second signal exits right away.
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::persist;
+use crate::proxy::ProxyConfig;
+use crate::session::{Session, TorrentHandle};
+use crate::torrent::TorrentStatus;
+use crate::tracker::{self, AnnounceEvent, AnnounceRequest};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+// Check on every tick of the event loop
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+// SIGINT and SIGTERM call `request`, and exit straight away if one came in already
+#[cfg(unix)]
+pub fn catch_signals() -> io::Result<()> {
+    extern "C" fn on_signal(_: libc::c_int) {
+        if REQUESTED.swap(true, Ordering::SeqCst) {
+            // Only async-signal-safe calls in here
+            unsafe { libc::_exit(130) };
+        }
+    }
+
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// Nothing to catch: ctrl-c ends the process as before
+#[cfg(not(unix))]
+pub fn catch_signals() -> io::Result<()> {
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct ShutdownConfig {
+    pub timeout: Duration,
+    // Announces get this much of `timeout` at most, and so does removing the port mappings. The
+    // rest is for flushing and saving
+    pub announce_timeout: Duration,
+    // Who we announced as
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub key: u32,
+    // The session-wide proxy, for torrents without their own
+    pub proxy: Option<ProxyConfig>,
+    // Where `persist::save` writes the session. None saves nothing
+    pub state_dir: Option<PathBuf>,
+}
+
+impl ShutdownConfig {
+    pub fn new(peer_id: [u8; 20], port: u16, key: u32) -> Self {
+        ShutdownConfig {
+            timeout: Duration::from_secs(10),
+            announce_timeout: Duration::from_secs(4),
+            peer_id,
+            port,
+            key,
+            proxy: None,
+            state_dir: None,
+        }
+    }
+}
+
+// The parts of shutting down that live with whoever runs the peers and the disk cache
+pub trait ShutdownHooks {
+    fn close_peers(&mut self) {}
+
+    // Removes our port mappings, giving up at `deadline`. Returns how many are left on the router
+    fn remove_port_mappings(&mut self, _deadline: Instant) -> usize {
+        0
+    }
+
+    // Writes out what the disk cache holds for the torrent
+    fn flush(&mut self, _handle: TorrentHandle) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ShutdownHooks for () {}
+
+#[derive(PartialEq, Eq, Debug, Default)]
+pub struct ShutdownReport {
+    pub announced: usize,
+    // Failed or still going when their time ran out
+    pub announce_failures: usize,
+    pub port_mappings_left: usize,
+    pub flushed: usize,
+    pub flush_errors: Vec<(TorrentHandle, io::ErrorKind)>,
+    // Torrents not flushed for lack of time
+    pub flush_skipped: usize,
+    pub save: Option<io::ErrorKind>,
+    pub timed_out: bool,
+}
+
+pub fn run(
+    session: &Session,
+    config: &ShutdownConfig,
+    hooks: &mut impl ShutdownHooks,
+) -> ShutdownReport {
+    let start = Instant::now();
+    let deadline = start + config.timeout;
+    let mut report = ShutdownReport::default();
+
+    let (announced, failures) = announce_stopped(session, config, start);
+    report.announced = announced;
+    report.announce_failures = failures;
+
+    hooks.close_peers();
+    let unmap_deadline = (Instant::now() + config.announce_timeout).min(deadline);
+    report.port_mappings_left = hooks.remove_port_mappings(unmap_deadline);
+
+    let handles: Vec<TorrentHandle> = session.handles().collect();
+    for (i, handle) in handles.iter().enumerate() {
+        if Instant::now() >= deadline {
+            report.flush_skipped = handles.len() - i;
+            break;
+        }
+        match hooks.flush(*handle) {
+            Ok(()) => report.flushed += 1,
+            Err(err) => report.flush_errors.push((*handle, err.kind())),
+        }
+    }
+
+    if let Some(dir) = &config.state_dir {
+        report.save = persist::save(session, dir).err().map(|err| err.kind());
+    }
+    report.timed_out = Instant::now() > deadline;
+    report
+}
+
+// All at once, each on a thread of its own. Those still going when time's up are left to it,
+// the process is about to exit anyway. Returns how many went out and how many of those failed
+fn announce_stopped(session: &Session, config: &ShutdownConfig, start: Instant) -> (usize, usize) {
+    let timeout = config.announce_timeout.min(config.timeout);
+    let deadline = start + timeout;
+    let (sender, results) = mpsc::channel();
+    let mut sent = 0;
+    for handle in session.handles() {
+        let torrent = session.get(handle).unwrap();
+        let running = matches!(
+            torrent.status(),
+            TorrentStatus::DownloadingMetadata
+                | TorrentStatus::Downloading
+                | TorrentStatus::Seeding
+        );
+        if !running {
+            continue;
+        }
+        let request = AnnounceRequest {
+            info_hash: torrent.info_hash(),
+            peer_id: config.peer_id,
+            port: config.port,
+            uploaded: torrent.total_uploaded(),
+            downloaded: torrent.total_downloaded(),
+            left: torrent.total_length() - torrent.bytes_done(),
+            event: AnnounceEvent::Stopped,
+            num_want: Some(0),
+            key: config.key,
+            ipv4: None,
+            ipv6: None,
+        };
+        let proxy = torrent.effective_proxy(config.proxy.as_ref()).cloned();
+        // Trackers that have been failing have likely forgotten us already
+        for entry in torrent.trackers().iter().filter(|e| e.failures() == 0) {
+            let (url, request, proxy, sender) = (
+                entry.url.clone(),
+                request.clone(),
+                proxy.clone(),
+                sender.clone(),
+            );
+            thread::spawn(move || {
+                let result = tracker::announce_via(&url, &request, proxy.as_ref(), timeout);
+                let _ = sender.send(result.is_ok());
+            });
+            sent += 1;
+        }
+    }
+
+    let mut failures = sent;
+    for _ in 0..sent {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match results.recv_timeout(left) {
+            Ok(true) => failures -= 1,
+            Ok(false) => {}
+            Err(_) => break,
+        }
+    }
+    (sent, failures)
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use std::fs;
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::infohash::InfoHash;
+    use crate::metainfo::MagnetLink;
+    use crate::torrent::Torrent;
+
+    #[derive(Default)]
+    struct Hooks {
+        calls: Vec<String>,
+    }
+
+    impl ShutdownHooks for Hooks {
+        fn close_peers(&mut self) {
+            self.calls.push("close".to_string());
+        }
+
+        fn remove_port_mappings(&mut self, _deadline: Instant) -> usize {
+            self.calls.push("unmap".to_string());
+            1
+        }
+
+        fn flush(&mut self, handle: TorrentHandle) -> io::Result<()> {
+            self.calls.push(format!("flush {}", handle.0));
+            match handle.0 {
+                1 => Err(io::ErrorKind::StorageFull.into()),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_shutdown_sequence() {
+        let dir = std::env::temp_dir().join(format!("hurricane-shutdown-{}", std::process::id()));
+        // A tracker that takes the connection and never answers
+        let tracker = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/announce", tracker.local_addr().unwrap());
+
+        let now = Instant::now();
+        let mut session = Session::new();
+        for i in 0..3 {
+            let mut magnet = MagnetLink::new(InfoHash([i; 20]));
+            magnet.trackers = vec![url.clone()];
+            session.add(Torrent::from_magnet(&magnet, now), now);
+        }
+        session.pause(&[TorrentHandle(2)]);
+
+        let mut config = ShutdownConfig::new([0; 20], 6881, 0);
+        config.announce_timeout = Duration::from_millis(200);
+        config.state_dir = Some(dir.clone());
+        let mut hooks = Hooks::default();
+        let report = run(&session, &config, &mut hooks);
+
+        // The paused torrent isn't in the swarm, so has nothing to announce
+        assert_eq!((report.announced, report.announce_failures), (2, 2));
+        assert_eq!(
+            hooks.calls,
+            ["close", "unmap", "flush 0", "flush 1", "flush 2"]
+        );
+        assert_eq!(report.port_mappings_left, 1);
+        assert_eq!(report.flushed, 2);
+        assert_eq!(
+            report.flush_errors,
+            [(TorrentHandle(1), io::ErrorKind::StorageFull)]
+        );
+        assert_eq!(report.save, None);
+        assert!(!report.timed_out);
+        assert_eq!(persist::load(&dir, now).unwrap().unwrap().session.len(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_out_of_time_skips_flushes() {
+        let now = Instant::now();
+        let mut session = Session::new();
+        session.add(
+            Torrent::from_magnet(&MagnetLink::new(InfoHash([1; 20])), now),
+            now,
+        );
+        let mut config = ShutdownConfig::new([0; 20], 6881, 0);
+        config.timeout = Duration::ZERO;
+        let report = run(&session, &config, &mut ());
+        assert_eq!((report.flushed, report.flush_skipped), (0, 1));
+        assert!(report.timed_out);
+    }
+}
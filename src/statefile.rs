@@ -0,0 +1,113 @@
+// Small state files that must survive a crash or power loss mid-write: resume data, the DHT
+// routing table and so on. A save goes to a temporary file that is synced before being renamed
+// over the real one, and the directory is synced after so the rename itself is on disk. The
+// previous version is kept as `<name>.bak`, and loading falls back to it if the main file is
+// missing or doesn't decode, so the worst a badly timed crash costs is the last save.
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub fn save(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = with_suffix(path, ".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+
+    match fs::rename(path, backup_path(path)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    fs::rename(&tmp, path)?;
+    sync_dir(path)
+}
+
+// Ok(None) when there's nothing usable, neither the file nor its backup. Only real I/O errors
+// (permissions, a failing disk) are errors
+pub fn load<T>(path: &Path, decode: impl Fn(&[u8]) -> Option<T>) -> io::Result<Option<T>> {
+    for path in [path.to_path_buf(), backup_path(path)] {
+        match fs::read(&path) {
+            Ok(buf) => {
+                if let Some(value) = decode(&buf) {
+                    return Ok(Some(value));
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
+
+pub fn backup_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".bak")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    name.into()
+}
+
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+// Directories can't be opened for syncing on Windows, and NTFS journals the rename anyway
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hurricane-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn decode(buf: &[u8]) -> Option<Vec<u8>> {
+        buf.starts_with(b"ok").then(|| buf.to_vec())
+    }
+
+    #[test]
+    fn test_save_keeps_backup() {
+        let dir = dir("statefile");
+        let path = dir.join("resume.dat");
+
+        assert_eq!(load(&path, decode).unwrap(), None);
+        save(&path, b"ok 1").unwrap();
+        save(&path, b"ok 2").unwrap();
+
+        assert_eq!(load(&path, decode).unwrap(), Some(b"ok 2".to_vec()));
+        assert_eq!(fs::read(backup_path(&path)).unwrap(), b"ok 1");
+        assert!(!with_suffix(&path, ".tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_falls_back_to_backup() {
+        let dir = dir("statefile-torn");
+        let path = dir.join("resume.dat");
+        save(&path, b"ok 1").unwrap();
+        save(&path, b"ok 2").unwrap();
+
+        // A torn write that somehow made it to the real name
+        fs::write(&path, b"o").unwrap();
+        assert_eq!(load(&path, decode).unwrap(), Some(b"ok 1".to_vec()));
+
+        // Crash between the two renames: only the backup is there
+        fs::remove_file(&path).unwrap();
+        assert_eq!(load(&path, decode).unwrap(), Some(b"ok 1".to_vec()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
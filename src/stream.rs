@@ -0,0 +1,656 @@
+// Streaming a torrent's files over local HTTP while they download, so a media player can be
+// pointed at `http://127.0.0.1:<port>/torrent/<handle>` (the torrent's biggest file) or
+// `/torrent/<handle>/<file index>` and start playing right away. Players seek with Range
+// requests; each response waits for the pieces behind it as it goes, and asks through
+// `poll_boost` for the pieces just ahead of it to be fetched first, which the session applies
+// with `Torrent::set_streaming`. Reads go through the disk scheduler as `IoClass::Streaming` so
+// they jump ahead of seeding.
+// One request per connection and one thread per connection. Players open a new connection to
+// seek anyway, and there's rarely more than one of them watching.
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::bitfield::Bitfield;
+use crate::disk::{DiskScheduler, IoClass, Storage};
+use crate::metainfo::Info;
+use crate::session::TorrentHandle;
+
+// Request heads bigger than this are not from a player
+const MAX_HEAD: usize = 8 * 1024;
+
+// A client that connects and says nothing is dropped after this long
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct StreamConfig {
+    // Loopback by default: anyone who can reach the server can read the torrents' files
+    pub addr: SocketAddr,
+    // Pieces from the read position on that get boosted
+    pub read_ahead: u32,
+    // A response waiting this long for a piece gives up and closes the connection
+    pub piece_timeout: Duration,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        StreamConfig {
+            addr: (Ipv4Addr::LOCALHOST, 0).into(),
+            read_ahead: 8,
+            piece_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+// Pieces a stream is about to read. Goes to `Torrent::set_streaming`
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Boost {
+    pub handle: TorrentHandle,
+    pub pieces: Range<u32>,
+}
+
+// The `Range` header, bounds inclusive as they're written
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ByteRange {
+    // bytes=a-b
+    Between(u64, u64),
+    // bytes=a-
+    From(u64),
+    // bytes=-n, the last n bytes
+    Last(u64),
+}
+
+impl ByteRange {
+    // First and last byte of a file of `len` bytes, None if the range is past its end
+    pub fn resolve(self, len: u64) -> Option<(u64, u64)> {
+        let (start, end) = match self {
+            ByteRange::Between(start, end) => (start, end.min(len.checked_sub(1)?)),
+            ByteRange::From(start) => (start, len.checked_sub(1)?),
+            ByteRange::Last(0) => return None,
+            ByteRange::Last(n) => (len.saturating_sub(n), len.checked_sub(1)?),
+        };
+        (start <= end).then_some((start, end))
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct StreamRequest {
+    // HEAD: headers only, players use it to learn the length
+    pub head_only: bool,
+    pub handle: TorrentHandle,
+    // None for the torrent's biggest file
+    pub file: Option<usize>,
+    pub range: Option<ByteRange>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum StreamError {
+    BadRequest,
+    MethodNotAllowed,
+    NotFound,
+    // The file's length, for the Content-Range of the 416
+    RangeNotSatisfiable(u64),
+}
+
+impl StreamError {
+    fn status(&self) -> &'static str {
+        match self {
+            StreamError::BadRequest => "400 Bad Request",
+            StreamError::MethodNotAllowed => "405 Method Not Allowed",
+            StreamError::NotFound => "404 Not Found",
+            StreamError::RangeNotSatisfiable(_) => "416 Range Not Satisfiable",
+        }
+    }
+}
+
+// Parses a request head, everything up to the blank line
+pub fn parse_request(head: &str) -> Result<StreamRequest, StreamError> {
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+        return Err(StreamError::BadRequest);
+    };
+    let head_only = match method {
+        "GET" => false,
+        "HEAD" => true,
+        _ => return Err(StreamError::MethodNotAllowed),
+    };
+
+    // Players tack on query strings of their own now and then
+    let path = target.split('?').next().unwrap_or("");
+    let mut segments = path.trim_end_matches('/').split('/').skip(1);
+    if segments.next() != Some("torrent") {
+        return Err(StreamError::NotFound);
+    }
+    let handle = segments
+        .next()
+        .and_then(|handle| handle.parse().ok())
+        .map(TorrentHandle)
+        .ok_or(StreamError::NotFound)?;
+    let file = match segments.next() {
+        Some(file) => Some(file.parse().map_err(|_| StreamError::NotFound)?),
+        None => None,
+    };
+    if segments.next().is_some() {
+        return Err(StreamError::NotFound);
+    }
+
+    let range = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("range"))
+        .and_then(|(_, value)| parse_range(value.trim()));
+    Ok(StreamRequest {
+        head_only,
+        handle,
+        file,
+        range,
+    })
+}
+
+// Only single ranges. A header we can't make sense of is ignored and the whole file sent, which
+// is what RFC 9110 asks of servers anyway
+fn parse_range(value: &str) -> Option<ByteRange> {
+    let spec = value.strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    match (start.trim(), end.trim()) {
+        ("", last) => Some(ByteRange::Last(last.parse().ok()?)),
+        (start, "") => Some(ByteRange::From(start.parse().ok()?)),
+        (start, end) => {
+            let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+            (start <= end).then_some(ByteRange::Between(start, end))
+        }
+    }
+}
+
+// Players sniff the data anyway, but some won't try without a plausible type
+pub fn content_type(name: &str) -> &'static str {
+    let extension = name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("mp4" | "m4v") => "video/mp4",
+        Some("mkv") => "video/x-matroska",
+        Some("webm") => "video/webm",
+        Some("avi") => "video/x-msvideo",
+        Some("mov") => "video/quicktime",
+        Some("ts") => "video/mp2t",
+        Some("mp3") => "audio/mpeg",
+        Some("flac") => "audio/flac",
+        Some("ogg" | "oga") => "audio/ogg",
+        Some("m4a") => "audio/mp4",
+        Some("wav") => "audio/wav",
+        Some("srt") => "application/x-subrip",
+        Some("vtt") => "text/vtt",
+        _ => "application/octet-stream",
+    }
+}
+
+// What the server knows of a torrent it streams
+struct Source {
+    storage: Arc<Storage>,
+    piece_length: u64,
+    num_pieces: u32,
+    // Name, offset in the torrent and length of each file
+    files: Vec<(String, u64, u64)>,
+    have: Bitfield,
+}
+
+struct Shared {
+    sources: Mutex<HashMap<TorrentHandle, Source>>,
+    // Signalled when a piece comes in or a source goes away
+    changed: Condvar,
+    scheduler: Arc<DiskScheduler>,
+    boosts: Mutex<Sender<Boost>>,
+    config: StreamConfig,
+    stop: AtomicBool,
+}
+
+pub struct StreamServer {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    boosts: Receiver<Boost>,
+    acceptor: Option<JoinHandle<()>>,
+}
+
+impl StreamServer {
+    pub fn bind(config: StreamConfig, scheduler: Arc<DiskScheduler>) -> io::Result<Self> {
+        let listener = TcpListener::bind(config.addr)?;
+        let addr = listener.local_addr()?;
+        let (sender, boosts) = mpsc::channel();
+        let shared = Arc::new(Shared {
+            sources: Mutex::new(HashMap::new()),
+            changed: Condvar::new(),
+            scheduler,
+            boosts: Mutex::new(sender),
+            config,
+            stop: AtomicBool::new(false),
+        });
+
+        let acceptor = {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || accept(&listener, &shared))
+        };
+        Ok(StreamServer {
+            addr,
+            shared,
+            boosts,
+            acceptor: Some(acceptor),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // Where a player finds a torrent's file, its biggest one if `file` is None
+    pub fn url(&self, handle: TorrentHandle, file: Option<usize>) -> String {
+        match file {
+            Some(file) => format!("http://{}/torrent/{}/{}", self.addr, handle.0, file),
+            None => format!("http://{}/torrent/{}", self.addr, handle.0),
+        }
+    }
+
+    // Makes a torrent streamable. `have` is the pieces already verified, `piece_done` keeps it
+    // current from there
+    pub fn add(&self, handle: TorrentHandle, info: &Info, storage: Arc<Storage>, have: &Bitfield) {
+        let mut offset = 0;
+        let files = info
+            .files
+            .iter()
+            .map(|file| {
+                let start = offset;
+                offset += file.length;
+                let name = file.path.last().cloned().unwrap_or_default();
+                (name, start, file.length)
+            })
+            .collect();
+        let source = Source {
+            storage,
+            piece_length: info.piece_length as u64,
+            num_pieces: info.num_pieces(),
+            files,
+            have: have.clone(),
+        };
+        self.shared.sources.lock().unwrap().insert(handle, source);
+    }
+
+    // Streams of the torrent that are waiting on a piece stop, the rest finish what they have
+    pub fn remove(&self, handle: TorrentHandle) {
+        self.shared.sources.lock().unwrap().remove(&handle);
+        self.shared.changed.notify_all();
+    }
+
+    pub fn piece_done(&self, handle: TorrentHandle, piece: u32) {
+        if let Some(source) = self.shared.sources.lock().unwrap().get_mut(&handle) {
+            source.have.set(piece as usize);
+            self.shared.changed.notify_all();
+        }
+    }
+
+    // Boosts in the order streams asked for them. Only the latest one per torrent matters
+    pub fn poll_boost(&self) -> Option<Boost> {
+        self.boosts.try_recv().ok()
+    }
+
+    fn stop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        self.shared.changed.notify_all();
+        if let Some(acceptor) = self.acceptor.take() {
+            // accept() only notices the flag once it returns
+            let _ = TcpStream::connect_timeout(&self.addr, Duration::from_secs(1));
+            let _ = acceptor.join();
+        }
+    }
+}
+
+impl Drop for StreamServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn accept(listener: &TcpListener, shared: &Arc<Shared>) {
+    for stream in listener.incoming() {
+        if shared.stop.load(Ordering::Relaxed) {
+            break;
+        }
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let shared = Arc::clone(shared);
+        thread::spawn(move || {
+            // Errors here are players hanging up mid-response, which is how they seek
+            let _ = serve(stream, &shared);
+        });
+    }
+}
+
+fn serve(mut stream: TcpStream, shared: &Shared) -> io::Result<()> {
+    stream.set_read_timeout(Some(HEAD_TIMEOUT))?;
+    let head = read_head(&mut stream)?;
+    let request = match parse_request(&head) {
+        Ok(request) => request,
+        Err(err) => return respond_error(&mut stream, err),
+    };
+
+    // Everything the response needs from the source, so it isn't held locked while sending
+    let located = {
+        let sources = shared.sources.lock().unwrap();
+        sources.get(&request.handle).and_then(|source| {
+            let file = match request.file {
+                Some(file) => file,
+                None => (0..source.files.len()).max_by_key(|&f| source.files[f].2)?,
+            };
+            let (name, start, len) = source.files.get(file)?.clone();
+            Some((name, start, len, source.piece_length))
+        })
+    };
+    let Some((name, file_start, file_len, piece_length)) = located else {
+        return respond_error(&mut stream, StreamError::NotFound);
+    };
+
+    let (first, last) = match request.range {
+        Some(range) => match range.resolve(file_len) {
+            Some(bounds) => bounds,
+            None => {
+                return respond_error(&mut stream, StreamError::RangeNotSatisfiable(file_len));
+            }
+        },
+        // An empty file has no bytes to send, `last` only matters if `first` is below it
+        None => (0, file_len.saturating_sub(1)),
+    };
+    let body_len = if file_len == 0 { 0 } else { last - first + 1 };
+
+    let mut head = match request.range {
+        Some(_) => format!(
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n",
+            first, last, file_len
+        ),
+        None => "HTTP/1.1 200 OK\r\n".to_string(),
+    };
+    head.push_str(&format!(
+        "Content-Length: {}\r\nContent-Type: {}\r\nAccept-Ranges: bytes\r\nConnection: close\r\n\r\n",
+        body_len,
+        content_type(&name)
+    ));
+    stream.write_all(head.as_bytes())?;
+    if request.head_only || body_len == 0 {
+        return Ok(());
+    }
+
+    // A piece at a time, or what's left of it in the file
+    let mut position = file_start + first;
+    let end = file_start + last + 1;
+    while position < end {
+        let piece = (position / piece_length) as u32;
+        let piece_start = piece as u64 * piece_length;
+        let len = (piece_start + piece_length).min(end) - position;
+        let storage = wait_for(shared, request.handle, piece)?;
+        let data = read(
+            shared,
+            storage,
+            piece,
+            (position - piece_start) as u32,
+            len as u32,
+        )?;
+        stream.write_all(&data)?;
+        position += len;
+    }
+    Ok(())
+}
+
+fn read_head(stream: &mut TcpStream) -> io::Result<String> {
+    let mut head = vec![];
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_HEAD {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+fn respond_error(stream: &mut TcpStream, err: StreamError) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\n", err.status());
+    if let StreamError::RangeNotSatisfiable(len) = err {
+        head.push_str(&format!("Content-Range: bytes */{}\r\n", len));
+    }
+    head.push_str("Content-Length: 0\r\nConnection: close\r\n\r\n");
+    stream.write_all(head.as_bytes())
+}
+
+// Boosts the pieces from `piece` on and blocks until we have it. Fails if it takes too long, the
+// torrent stops being streamed or the server shuts down
+fn wait_for(shared: &Shared, handle: TorrentHandle, piece: u32) -> io::Result<Arc<Storage>> {
+    let deadline = Instant::now() + shared.config.piece_timeout;
+    let mut boosted = false;
+    let mut sources = shared.sources.lock().unwrap();
+    loop {
+        if shared.stop.load(Ordering::Relaxed) {
+            return Err(io::ErrorKind::Interrupted.into());
+        }
+        let Some(source) = sources.get(&handle) else {
+            return Err(io::ErrorKind::NotFound.into());
+        };
+        // Boosted before checking, so the read-ahead keeps moving while we have what we need
+        if !boosted {
+            let end = piece
+                .saturating_add(shared.config.read_ahead.max(1))
+                .min(source.num_pieces);
+            let boost = Boost {
+                handle,
+                pieces: piece..end,
+            };
+            let _ = shared.boosts.lock().unwrap().send(boost);
+            boosted = true;
+        }
+        if source.have.get(piece as usize) {
+            return Ok(Arc::clone(&source.storage));
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        sources = shared
+            .changed
+            .wait_timeout(sources, deadline - now)
+            .unwrap()
+            .0;
+    }
+}
+
+fn read(
+    shared: &Shared,
+    storage: Arc<Storage>,
+    piece: u32,
+    offset: u32,
+    len: u32,
+) -> io::Result<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    shared.scheduler.submit(IoClass::Streaming, move || {
+        let _ = sender.send(storage.read(piece, offset, len));
+    });
+    receiver
+        .recv()
+        .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::metainfo::FileEntry;
+
+    fn head(lines: &[&str]) -> String {
+        lines.join("\r\n") + "\r\n\r\n"
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let request = parse_request(&head(&["GET /torrent/3 HTTP/1.1", "Host: x"])).unwrap();
+        assert_eq!(
+            request,
+            StreamRequest {
+                head_only: false,
+                handle: TorrentHandle(3),
+                file: None,
+                range: None,
+            }
+        );
+
+        let request = parse_request(&head(&[
+            "HEAD /torrent/3/1?t=0 HTTP/1.1",
+            "range: bytes=10-",
+        ]))
+        .unwrap();
+        assert!(request.head_only);
+        assert_eq!(request.file, Some(1));
+        assert_eq!(request.range, Some(ByteRange::From(10)));
+
+        let range = |value: &str| {
+            parse_request(&head(&["GET /torrent/0 HTTP/1.1", value]))
+                .unwrap()
+                .range
+        };
+        assert_eq!(range("Range: bytes=0-99"), Some(ByteRange::Between(0, 99)));
+        assert_eq!(range("Range: bytes=-500"), Some(ByteRange::Last(500)));
+        assert_eq!(range("Range: bytes=0-1,5-6"), None);
+        assert_eq!(range("Range: bytes=9-3"), None);
+
+        let err = |line: &str| parse_request(&head(&[line])).unwrap_err();
+        assert_eq!(
+            err("POST /torrent/0 HTTP/1.1"),
+            StreamError::MethodNotAllowed
+        );
+        assert_eq!(err("GET /files/0 HTTP/1.1"), StreamError::NotFound);
+        assert_eq!(err("GET /torrent/x HTTP/1.1"), StreamError::NotFound);
+        assert_eq!(err("GET /torrent/0/1/2 HTTP/1.1"), StreamError::NotFound);
+        assert_eq!(err("GET"), StreamError::BadRequest);
+    }
+
+    #[test]
+    fn test_resolve_range() {
+        assert_eq!(ByteRange::Between(10, 19).resolve(100), Some((10, 19)));
+        assert_eq!(ByteRange::Between(90, 200).resolve(100), Some((90, 99)));
+        assert_eq!(ByteRange::From(100).resolve(100), None);
+        assert_eq!(ByteRange::Last(30).resolve(100), Some((70, 99)));
+        assert_eq!(ByteRange::Last(300).resolve(100), Some((0, 99)));
+        assert_eq!(ByteRange::Last(0).resolve(100), None);
+        assert_eq!(ByteRange::From(0).resolve(0), None);
+        assert_eq!(content_type("Movie.MKV"), "video/x-matroska");
+        assert_eq!(content_type("README"), "application/octet-stream");
+    }
+
+    fn get(addr: SocketAddr, path: &str, range: &str) -> (String, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: x\r\n{}\r\n", path, range);
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&response[..split]).into_owned();
+        (head, response[split + 4..].to_vec())
+    }
+
+    #[test]
+    fn test_stream_over_http() {
+        let dir: PathBuf =
+            std::env::temp_dir().join(format!("hurricane-stream-{}", std::process::id()));
+        let data: Vec<u8> = (0..100u8).collect();
+        let info = Info {
+            name: "t".to_string(),
+            piece_length: 16,
+            pieces: vec![[0; 20]; 7],
+            files: [("a.srt", 10), ("b.mkv", 90)]
+                .iter()
+                .map(|(name, length)| FileEntry {
+                    path: vec!["t".to_string(), name.to_string()],
+                    length: *length,
+                    pieces_root: None,
+                })
+                .collect(),
+            private: false,
+        };
+        let storage = Storage::new(&info, &dir);
+        for piece in 0..7 {
+            let start = piece as usize * 16;
+            storage
+                .write(piece, 0, &data[start..(start + 16).min(100)])
+                .unwrap();
+        }
+
+        let config = StreamConfig {
+            read_ahead: 2,
+            ..StreamConfig::default()
+        };
+        let server = StreamServer::bind(config, Arc::new(DiskScheduler::new(1))).unwrap();
+        let handle = TorrentHandle(4);
+        let mut have = Bitfield::new(7);
+        for piece in 0..3 {
+            have.set(piece);
+        }
+        server.add(handle, &info, Arc::new(storage), &have);
+
+        // File 1 starts 10 bytes in. Bytes 5..=20 of it are in pieces 0 and 1
+        let (head, body) = get(server.local_addr(), "/torrent/4", "Range: bytes=5-20\r\n");
+        assert!(head.starts_with("HTTP/1.1 206"), "{}", head);
+        assert!(head.contains("Content-Range: bytes 5-20/90"));
+        assert!(head.contains("video/x-matroska"));
+        assert_eq!(body, &data[15..31]);
+        assert_eq!(server.poll_boost().unwrap().pieces, 0..2);
+        assert_eq!(server.poll_boost().unwrap().pieces, 1..3);
+
+        let (head, body) = get(server.local_addr(), "/torrent/4/0", "");
+        assert!(head.starts_with("HTTP/1.1 200"));
+        assert_eq!(body, &data[..10]);
+        let (head, _) = get(server.local_addr(), "/torrent/4/1", "Range: bytes=90-\r\n");
+        assert!(head.starts_with("HTTP/1.1 416"));
+        assert!(head.contains("bytes */90"));
+        assert!(
+            get(server.local_addr(), "/torrent/5", "")
+                .0
+                .starts_with("HTTP/1.1 404")
+        );
+        while server.poll_boost().is_some() {}
+
+        // The tail of the file isn't there yet: the response waits for it, boosting it
+        let url = server.url(handle, Some(1));
+        assert!(url.ends_with("/torrent/4/1"));
+        let addr = server.local_addr();
+        let reader = thread::spawn(move || get(addr, "/torrent/4/1", "Range: bytes=-10\r\n"));
+        let boost = loop {
+            if let Some(boost) = server.poll_boost() {
+                break boost;
+            }
+            thread::sleep(Duration::from_millis(5));
+        };
+        assert_eq!(
+            boost,
+            Boost {
+                handle,
+                pieces: 5..7
+            }
+        );
+        server.piece_done(handle, 5);
+        server.piece_done(handle, 6);
+        let (_, body) = reader.join().unwrap();
+        assert_eq!(body, &data[90..]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+// Noticing that the machine was asleep. No platform tells a library about suspend in a portable
+// way, but the clocks give it away: on Linux and macOS `Instant` stops while suspended and the
+// wall clock doesn't, and on Windows `Instant` keeps going, so a loop that ticks every second
+// suddenly sees a gap of minutes. Either one means whatever we were waiting on (trackers, DHT
+// queries, peers, the router's port mappings) may be long gone, and the caller should check on it
+// all right away instead of letting every timeout fire at once.
+// A big jump of the wall clock (NTP stepping it, the user changing it) looks just like a suspend.
+// That only costs an early re-announce or two, which is harmless.
+use std::time::{Duration, Instant, SystemTime};
+
+// Gaps shorter than this are just a slow tick
+pub const SUSPEND_THRESHOLD: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct SuspendDetector {
+    threshold: Duration,
+    last: Option<(Instant, SystemTime)>,
+}
+
+impl Default for SuspendDetector {
+    fn default() -> Self {
+        SuspendDetector::new(SUSPEND_THRESHOLD)
+    }
+}
+
+impl SuspendDetector {
+    pub fn new(threshold: Duration) -> Self {
+        SuspendDetector {
+            threshold,
+            last: None,
+        }
+    }
+
+    // Call on every tick of the event loop. Returns roughly how long we were asleep if we just
+    // woke up
+    pub fn check(&mut self, now: Instant, wall: SystemTime) -> Option<Duration> {
+        let (last, last_wall) = self.last.replace((now, wall))?;
+        let elapsed = now.saturating_duration_since(last);
+        // A wall clock that went backwards tells us nothing
+        let wall_elapsed = wall.duration_since(last_wall).unwrap_or(Duration::ZERO);
+
+        if wall_elapsed > elapsed + self.threshold {
+            Some(wall_elapsed - elapsed)
+        } else if elapsed > self.threshold {
+            Some(elapsed)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_steady_ticks() {
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        let mut detector = SuspendDetector::default();
+
+        assert_eq!(detector.check(now, wall), None);
+        for secs in 1..100 {
+            let tick = Duration::from_secs(secs);
+            assert_eq!(detector.check(now + tick, wall + tick), None);
+        }
+        // The wall clock being set back isn't a wake-up
+        let tick = Duration::from_secs(101);
+        assert_eq!(detector.check(now + tick, wall), None);
+    }
+
+    #[test]
+    fn test_monotonic_clock_paused() {
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        let mut detector = SuspendDetector::default();
+        detector.check(now, wall);
+
+        let woke = detector.check(
+            now + Duration::from_secs(1),
+            wall + Duration::from_secs(3601),
+        );
+        assert_eq!(woke, Some(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn test_monotonic_clock_jumped() {
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        let mut detector = SuspendDetector::default();
+        detector.check(now, wall);
+
+        let gap = Duration::from_secs(600);
+        assert_eq!(detector.check(now + gap, wall + gap), Some(gap));
+        assert_eq!(
+            detector.check(now + gap + Duration::from_secs(1), wall + gap),
+            None
+        );
+    }
+}
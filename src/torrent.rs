@@ -0,0 +1,1338 @@
+// Per-torrent state: what we're downloading, how far along we are and who we're talking to.
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::audit;
+use crate::bitfield::Bitfield;
+use crate::disk::{Allocation, Backend, FileStamp, ResumeData, Storage, Validated};
+use crate::events::EventKind;
+use crate::infohash::InfoHash;
+use crate::metainfo::{MagnetLink, Metainfo};
+use crate::peer::candidates::{BanReason, PeerList, PeerSource};
+use crate::peer::pex::PexFlags;
+use crate::peer::stats::PeerStats;
+use crate::picker::{PiecePicker, Priority};
+use crate::proxy::ProxyConfig;
+use crate::rate::Rate;
+use crate::tracker::TrackerError;
+use crate::tracker::list::TrackerList;
+
+// Progress is reported to pollers in steps this fine. Anything finer would make the fingerprint
+// change on nearly every block
+pub const PROGRESS_BUCKETS: u64 = 1000;
+
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum TorrentStatus {
+    // Started from a magnet link and still fetching the info dict from peers
+    DownloadingMetadata,
+    CheckingFiles,
+    Downloading,
+    Seeding,
+    // Waiting for a slot under the active torrent limits
+    Queued,
+    Paused,
+    Error,
+}
+
+// Per-torrent caps on top of the session's. None means no cap of its own
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct TorrentLimits {
+    // Bytes per second
+    pub download_rate: Option<u64>,
+    pub upload_rate: Option<u64>,
+    pub max_peers: Option<usize>,
+}
+
+// When a finished torrent has seeded enough. Each goal is optional and the first one met counts
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct SeedGoals {
+    // Uploaded over downloaded, see `Torrent::ratio`
+    pub ratio: Option<f64>,
+    // Time spent seeding, across restarts
+    pub seed_time: Option<Duration>,
+    // Time spent seeding without uploading anything
+    pub idle_time: Option<Duration>,
+    pub action: SeedAction,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum SeedAction {
+    // Resuming it by hand pauses it again unless its goals are raised
+    #[default]
+    Pause,
+    // Taken out of the session, files left in place
+    Remove,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SeedGoal {
+    Ratio,
+    SeedTime,
+    IdleTime,
+}
+
+// What a forced recheck found
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct RecheckReport {
+    // Pieces that checked out
+    pub verified: usize,
+    // Pieces we thought we had, or that were all there on disk, that failed the hash check
+    pub corrupt: Vec<u32>,
+    // Pieces left to download, the corrupt ones included
+    pub missing: usize,
+}
+
+#[derive(Debug)]
+pub struct Torrent {
+    info_hash: InfoHash,
+    name: String,
+    label: Option<String>,
+    // Where the data lives. None until the torrent is placed somewhere
+    save_path: Option<PathBuf>,
+    // How its `Storage` gets at the files, and how it creates them
+    backend: Backend,
+    allocation: Allocation,
+    // None until we have the info dict
+    metainfo: Option<Metainfo>,
+    picker: Option<PiecePicker>,
+    // One per file in `info.files`, which the picker's piece priorities follow
+    file_priorities: Vec<Priority>,
+    // Kept here too so it carries over to the picker of a torrent still fetching its metadata
+    sequential: bool,
+    // Pieces a stream is about to read, raised to high priority whatever their files' priority
+    streaming: Option<Range<u32>>,
+    status: TorrentStatus,
+    // Set by the user to get this one done now: never queued, and exempt from seed limits and
+    // the upload limiter
+    force_started: bool,
+    // Overrides the session's proxy for this torrent's trackers and peers
+    proxy: Option<ProxyConfig>,
+    // Starts out as the metainfo's or the magnet link's, the user can swap them out later
+    trackers: TrackerList,
+    limits: TorrentLimits,
+    num_peers: usize,
+    num_seeds: usize,
+    // Each connected peer, as of the connections' last tick
+    peer_stats: Vec<PeerStats>,
+    // Bumped whenever every peer has to go, see `peer_generation`
+    peer_generation: u64,
+    // Addresses we could connect to
+    peer_list: PeerList,
+    download_rate: Rate,
+    upload_rate: Rate,
+    // Totals from earlier runs, out of resume data
+    prior_downloaded: u64,
+    prior_uploaded: u64,
+    // Pieces that failed their hash check since the torrent was added
+    hash_failures: u64,
+    // None follows the session's goals
+    seed_goals: Option<SeedGoals>,
+    // Counted on `tick`, this run's and earlier ones'
+    seeding_time: Duration,
+    last_tick: Instant,
+    // When we last uploaded anything while seeding, or started seeding
+    idle_since: Instant,
+    uploaded_at_tick: u64,
+    // Seed what's on disk and never download the rest, for data that came from elsewhere
+    skip_download: bool,
+    // Set between `begin_recheck` and `finish_recheck`: the status to go back to and the pieces
+    // we had before
+    recheck: Option<(TorrentStatus, Bitfield)>,
+    // Waiting for `Session::publish_events` or `take_events`
+    events: Vec<EventKind>,
+}
+
+impl Torrent {
+    pub fn new(metainfo: Metainfo, now: Instant) -> Self {
+        let mut torrent = Torrent::empty(metainfo.info_hash, metainfo.info.name.clone(), now);
+        torrent.trackers = TrackerList::new(&metainfo.trackers(), now);
+        torrent.set_metainfo(metainfo);
+        torrent
+    }
+
+    pub fn from_magnet(magnet: &MagnetLink, now: Instant) -> Self {
+        let name = magnet
+            .display_name
+            .clone()
+            .unwrap_or_else(|| magnet.info_hash.to_hex());
+        let mut torrent = Torrent::empty(magnet.info_hash, name, now);
+        // A magnet link's trackers are all in a tier of their own
+        let tiers: Vec<Vec<String>> = magnet.trackers.iter().map(|t| vec![t.clone()]).collect();
+        torrent.trackers = TrackerList::new(&tiers, now);
+        // x.pe peers given by name would need a lookup first, only literal addresses go in here
+        for peer in magnet.peers.iter().filter_map(|p| p.parse().ok()) {
+            torrent
+                .peer_list
+                .insert(peer, PeerSource::Manual, PexFlags(0), now);
+        }
+        torrent
+    }
+
+    fn empty(info_hash: InfoHash, name: String, now: Instant) -> Self {
+        Torrent {
+            info_hash,
+            name,
+            label: None,
+            save_path: None,
+            backend: Backend::default(),
+            allocation: Allocation::default(),
+            metainfo: None,
+            picker: None,
+            file_priorities: vec![],
+            sequential: false,
+            streaming: None,
+            status: TorrentStatus::DownloadingMetadata,
+            force_started: false,
+            proxy: None,
+            trackers: TrackerList::default(),
+            limits: TorrentLimits::default(),
+            num_peers: 0,
+            num_seeds: 0,
+            peer_stats: vec![],
+            peer_generation: 0,
+            peer_list: PeerList::new(),
+            download_rate: Rate::new(now),
+            upload_rate: Rate::new(now),
+            prior_downloaded: 0,
+            prior_uploaded: 0,
+            hash_failures: 0,
+            seed_goals: None,
+            seeding_time: Duration::ZERO,
+            last_tick: now,
+            idle_since: now,
+            uploaded_at_tick: 0,
+            skip_download: false,
+            recheck: None,
+            events: vec![],
+        }
+    }
+
+    // Called once the info dict arrives for a torrent added by magnet link
+    pub fn set_metainfo(&mut self, metainfo: Metainfo) {
+        self.unregister_private();
+        let info = &metainfo.info;
+        if info.private {
+            audit::register_private(self.info_hash);
+        }
+        self.peer_list.set_private(info.private);
+        self.name = info.name.clone();
+        let mut picker =
+            PiecePicker::new(info.pieces.len(), info.piece_length, info.total_length());
+        picker.set_sequential(self.sequential);
+        self.picker = Some(picker);
+        self.file_priorities = vec![Priority::Normal; info.files.len()];
+        self.metainfo = Some(metainfo);
+        self.status = TorrentStatus::CheckingFiles;
+        self.events.push(EventKind::MetadataReceived);
+    }
+
+    // Moves on to a new version of an updatable torrent (BEP 46). Everything tied to the old
+    // info-hash is dropped and the metadata fetched again, while the name, label and save path
+    // carry over so files the versions share don't have to be downloaded twice
+    pub fn switch_version(&mut self, info_hash: InfoHash) {
+        self.unregister_private();
+        self.peer_list.set_private(false);
+        self.info_hash = info_hash;
+        self.metainfo = None;
+        self.picker = None;
+        self.status = TorrentStatus::DownloadingMetadata;
+    }
+
+    // Folds a second copy of this torrent (same info-hash, added again from a magnet link or a
+    // .torrent) into this one: its trackers, web seeds and peers are added to ours, and its info
+    // dict is taken if we're still waiting on metadata. Everything else about us stays as it is
+    pub fn merge(&mut self, mut other: Torrent, now: Instant) {
+        let trackers = self.trackers.tiers();
+        self.trackers.merge(&other.trackers.tiers(), now);
+        self.on_trackers_changed(&trackers);
+        for (addr, candidate) in other.peer_list.iter() {
+            self.peer_list
+                .insert(*addr, candidate.source, candidate.flags, now);
+        }
+        // Its registration goes with its info dict, which is about to be taken
+        other.unregister_private();
+        match (&mut self.metainfo, other.metainfo.take()) {
+            (Some(ours), Some(theirs)) => {
+                for url in theirs.url_list {
+                    if !ours.url_list.contains(&url) {
+                        ours.url_list.push(url);
+                    }
+                }
+            }
+            (None, Some(theirs)) if self.status == TorrentStatus::DownloadingMetadata => {
+                self.set_metainfo(theirs);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn info_hash(&self) -> InfoHash {
+        self.info_hash
+    }
+
+    // Private torrents (BEP 27) get their peers from their trackers and nowhere else, so check
+    // this before handing the torrent to the DHT, PEX or LSD. Torrents still fetching metadata
+    // don't know yet, and count as public until they do
+    pub fn is_private(&self) -> bool {
+        self.metainfo.as_ref().is_some_and(|m| m.info.private)
+    }
+
+    fn unregister_private(&self) {
+        if self.is_private() {
+            audit::unregister_private(self.info_hash);
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    // Doesn't move anything by itself. See `SavePathTemplate::relocate` for that
+    pub fn set_label(&mut self, label: Option<String>) {
+        self.label = label;
+    }
+
+    pub fn save_path(&self) -> Option<&Path> {
+        self.save_path.as_deref()
+    }
+
+    pub fn set_save_path(&mut self, path: PathBuf) {
+        self.save_path = Some(path);
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    // Takes effect the next time its storage is opened
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+
+    pub fn allocation(&self) -> Allocation {
+        self.allocation
+    }
+
+    // Only affects files created from now on
+    pub fn set_allocation(&mut self, allocation: Allocation) {
+        self.allocation = allocation;
+    }
+
+    // None until there's both an info dict and somewhere to put the files
+    pub fn storage(&self) -> Option<Storage> {
+        let (metainfo, save_path) = (self.metainfo.as_ref()?, self.save_path.as_ref()?);
+        let mut storage = Storage::with_backend(&metainfo.info, save_path, self.backend);
+        storage.set_allocation(self.allocation);
+        storage.set_merkle(metainfo.merkle_files());
+        for (file, priority) in self.file_priorities.iter().enumerate() {
+            storage.set_skipped(file, *priority == Priority::Skip);
+        }
+        Some(storage)
+    }
+
+    pub fn is_sequential(&self) -> bool {
+        self.sequential
+    }
+
+    // Downloads pieces roughly in order, see `PiecePicker::set_sequential`
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+        if let Some(picker) = self.picker.as_mut() {
+            picker.set_sequential(sequential);
+        }
+    }
+
+    pub fn streaming(&self) -> Option<Range<u32>> {
+        self.streaming.clone()
+    }
+
+    // Pieces someone is streaming from right now, see `stream::StreamServer::poll_boost`. They
+    // go ahead of everything else until the stream moves on; None drops the boost
+    pub fn set_streaming(&mut self, pieces: Option<Range<u32>>) {
+        self.streaming = pieces;
+        self.update_piece_priorities();
+    }
+
+    // Empty until we have the info dict
+    pub fn file_priorities(&self) -> &[Priority] {
+        &self.file_priorities
+    }
+
+    // `Priority::Skip` leaves the file out of the download, except for whatever it shares a piece
+    // with a file we do want. Storage opened afterwards doesn't create or allocate it either.
+    // False if there's no such file
+    pub fn set_file_priority(&mut self, file: usize, priority: Priority) -> bool {
+        let Some(slot) = self.file_priorities.get_mut(file) else {
+            return false;
+        };
+        *slot = priority;
+        self.update_piece_priorities();
+        true
+    }
+
+    pub fn metainfo(&self) -> Option<&Metainfo> {
+        self.metainfo.as_ref()
+    }
+
+    pub fn picker(&self) -> Option<&PiecePicker> {
+        self.picker.as_ref()
+    }
+
+    pub fn picker_mut(&mut self) -> Option<&mut PiecePicker> {
+        self.picker.as_mut()
+    }
+
+    pub fn status(&self) -> TorrentStatus {
+        self.status
+    }
+
+    // Queueing a force-started torrent does nothing, it has no slot to wait for
+    pub fn set_status(&mut self, status: TorrentStatus) {
+        if status == TorrentStatus::Queued && self.force_started {
+            return;
+        }
+        self.status = status;
+    }
+
+    // Something went wrong that needs the user, e.g. the disk is full
+    pub fn set_error(&mut self, message: String) {
+        self.status = TorrentStatus::Error;
+        self.events.push(EventKind::Errored(message));
+    }
+
+    // A piece passed its hash check. Moves on to seeding once that was the last one we wanted,
+    // and returns whether it was
+    pub fn on_piece_verified(&mut self, piece: u32) -> bool {
+        let Some(picker) = &mut self.picker else {
+            return false;
+        };
+        picker.piece_verified(piece);
+        self.events.push(EventKind::PieceVerified(piece));
+        let finished = picker.is_complete() && self.status == TorrentStatus::Downloading;
+        if finished {
+            self.status = TorrentStatus::Seeding;
+            self.events.push(EventKind::Completed);
+        }
+        finished
+    }
+
+    // A piece failed its hash check and goes back to being requested
+    pub fn on_piece_failed(&mut self, piece: u32) {
+        if let Some(picker) = &mut self.picker {
+            picker.piece_failed(piece);
+            self.hash_failures += 1;
+        }
+    }
+
+    pub fn hash_failures(&self) -> u64 {
+        self.hash_failures
+    }
+
+    pub fn on_tracker_warning(&mut self, url: &str, message: &str) {
+        self.events.push(EventKind::TrackerWarning {
+            url: url.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    pub fn on_tracker_error(&mut self, url: &str, err: &TrackerError) {
+        self.events.push(EventKind::TrackerError {
+            url: url.to_string(),
+            message: err.to_string(),
+        });
+    }
+
+    // Keeps its IP out of the peer list for a while, see `PeerList::ban`. The caller still has to
+    // close the connection
+    pub fn ban_peer(&mut self, addr: SocketAddr, reason: BanReason, now: Instant) {
+        self.peer_list.ban(addr, reason, now);
+        self.events.push(EventKind::PeerBanned(addr));
+    }
+
+    // What happened since the last call. Sessions take care of this, other callers should call
+    // it every so often so the queue doesn't grow
+    pub fn take_events(&mut self) -> Vec<EventKind> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn is_force_started(&self) -> bool {
+        self.force_started
+    }
+
+    // Force starting also lifts a pause or a place in the queue. Turning it off leaves the status
+    // alone, the queue gets to decide again on its next pass
+    pub fn set_force_start(&mut self, force: bool) {
+        self.force_started = force;
+        if force && matches!(self.status, TorrentStatus::Queued | TorrentStatus::Paused) {
+            self.status = self.active_status();
+        }
+    }
+
+    // Whether queue limits, seed limits and the upload limiter apply. Peers of a torrent that
+    // doesn't respect them send with `SendQueue::pop` rather than `pop_limited`
+    pub fn respects_limits(&self) -> bool {
+        !self.force_started
+    }
+
+    // Ignored while force started, see `respects_limits`
+    pub fn limits(&self) -> TorrentLimits {
+        self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: TorrentLimits) {
+        self.limits = limits;
+    }
+
+    pub fn proxy(&self) -> Option<&ProxyConfig> {
+        self.proxy.as_ref()
+    }
+
+    pub fn set_proxy(&mut self, proxy: Option<ProxyConfig>) {
+        self.proxy = proxy;
+    }
+
+    // The proxy this torrent's connections go through, given the session-wide one
+    pub fn effective_proxy<'a>(
+        &'a self,
+        global: Option<&'a ProxyConfig>,
+    ) -> Option<&'a ProxyConfig> {
+        ProxyConfig::choose(global, self.proxy.as_ref())
+    }
+
+    pub fn trackers(&self) -> &TrackerList {
+        &self.trackers
+    }
+
+    pub fn trackers_mut(&mut self) -> &mut TrackerList {
+        &mut self.trackers
+    }
+
+    // Announces to one tracker (by index into `trackers`), or to all of them for None, on the
+    // next pass instead of waiting out its interval or backoff. False if there's no such tracker
+    pub fn force_reannounce(&mut self, tracker: Option<usize>, now: Instant) -> bool {
+        self.trackers.force_reannounce(tracker, now)
+    }
+
+    // Swaps out the trackers without re-adding the torrent, e.g. when one has gone dead. The new
+    // ones are announced to straight away
+    pub fn replace_trackers(&mut self, tiers: &[Vec<String>], now: Instant) {
+        let trackers = self.trackers.tiers();
+        self.trackers.replace(tiers, now);
+        self.on_trackers_changed(&trackers);
+    }
+
+    // A private torrent's peers are only good for the trackers they came from (BEP 27), so new
+    // trackers mean starting over with whoever those hand us
+    fn on_trackers_changed(&mut self, before: &[Vec<String>]) {
+        if self.is_private() && self.trackers.tiers() != before {
+            self.peer_list.clear();
+            self.peer_generation += 1;
+        }
+    }
+
+    // Changes when every connection should be dropped, e.g. a private torrent's trackers changed.
+    // Whatever runs the connections compares it to the one they were made under
+    pub fn peer_generation(&self) -> u64 {
+        self.peer_generation
+    }
+
+    // Back from a suspend, when the trackers have likely dropped us from their swarms
+    pub fn on_wake(&mut self, now: Instant) {
+        self.trackers.force_reannounce(None, now);
+    }
+
+    // What the torrent would be doing if it were running
+    pub fn active_status(&self) -> TorrentStatus {
+        match &self.picker {
+            None => TorrentStatus::DownloadingMetadata,
+            Some(picker) if picker.is_complete() || self.skip_download => TorrentStatus::Seeding,
+            Some(_) => TorrentStatus::Downloading,
+        }
+    }
+
+    pub fn num_peers(&self) -> usize {
+        self.num_peers
+    }
+
+    pub fn num_seeds(&self) -> usize {
+        self.num_seeds
+    }
+
+    pub fn set_peer_counts(&mut self, peers: usize, seeds: usize) {
+        self.num_peers = peers;
+        self.num_seeds = seeds;
+    }
+
+    pub fn peer_stats(&self) -> &[PeerStats] {
+        &self.peer_stats
+    }
+
+    pub fn set_peer_stats(&mut self, stats: Vec<PeerStats>) {
+        self.peer_stats = stats;
+    }
+
+    pub fn peer_list(&self) -> &PeerList {
+        &self.peer_list
+    }
+
+    pub fn peer_list_mut(&mut self) -> &mut PeerList {
+        &mut self.peer_list
+    }
+
+    pub fn download_rate(&self) -> &Rate {
+        &self.download_rate
+    }
+
+    pub fn upload_rate(&self) -> &Rate {
+        &self.upload_rate
+    }
+
+    // Across restarts, unlike the rates' own totals
+    pub fn total_downloaded(&self) -> u64 {
+        self.prior_downloaded + self.download_rate.total()
+    }
+
+    pub fn total_uploaded(&self) -> u64 {
+        self.prior_uploaded + self.upload_rate.total()
+    }
+
+    // Uploaded over downloaded. Data that was already on disk counts as downloaded, so seeding
+    // a torrent we never downloaded a byte of doesn't start out at an infinite ratio
+    pub fn ratio(&self) -> f64 {
+        let downloaded = self.total_downloaded().max(self.bytes_done());
+        match downloaded {
+            0 => 0.0,
+            downloaded => self.total_uploaded() as f64 / downloaded as f64,
+        }
+    }
+
+    pub fn seeding_time(&self) -> Duration {
+        self.seeding_time
+    }
+
+    // Zero unless seeding
+    pub fn idle_time(&self, now: Instant) -> Duration {
+        match self.status {
+            TorrentStatus::Seeding => now.saturating_duration_since(self.idle_since),
+            _ => Duration::ZERO,
+        }
+    }
+
+    // None for the session's
+    pub fn seed_goals(&self) -> Option<SeedGoals> {
+        self.seed_goals
+    }
+
+    pub fn set_seed_goals(&mut self, goals: Option<SeedGoals>) {
+        self.seed_goals = goals;
+    }
+
+    // The first of the goals in force that this torrent has met. Only torrents that are seeding
+    // and respect limits have goals to meet
+    pub fn seed_goal_reached(&self, defaults: &SeedGoals, now: Instant) -> Option<SeedGoal> {
+        if self.status != TorrentStatus::Seeding || !self.respects_limits() {
+            return None;
+        }
+        let goals = self.seed_goals.as_ref().unwrap_or(defaults);
+        if goals.ratio.is_some_and(|ratio| self.ratio() >= ratio) {
+            Some(SeedGoal::Ratio)
+        } else if goals
+            .seed_time
+            .is_some_and(|time| self.seeding_time >= time)
+        {
+            Some(SeedGoal::SeedTime)
+        } else if goals
+            .idle_time
+            .is_some_and(|time| self.idle_time(now) >= time)
+        {
+            Some(SeedGoal::IdleTime)
+        } else {
+            None
+        }
+    }
+
+    pub fn on_downloaded(&mut self, bytes: u64) {
+        self.download_rate.add(bytes);
+    }
+
+    pub fn on_uploaded(&mut self, bytes: u64) {
+        self.upload_rate.add(bytes);
+    }
+
+    // A snapshot for `ResumeData::save`. None until we have the info dict, there's nothing worth
+    // resuming before that. `files` comes from `resume::stamp_files` on this torrent's storage
+    pub fn resume_data(&self, files: Vec<Option<FileStamp>>) -> Option<ResumeData> {
+        let picker = self.picker.as_ref()?;
+        Some(ResumeData {
+            info_hash: self.info_hash,
+            have: picker.have().clone(),
+            partial: picker.partial_progress(),
+            files,
+            trackers: self.trackers.tiers(),
+            peers: self
+                .peer_list
+                .iter()
+                .filter(|(_, candidate)| candidate.failures == 0)
+                .map(|(addr, _)| *addr)
+                .collect(),
+            uploaded: self.total_uploaded(),
+            downloaded: self.total_downloaded(),
+            seeding_time: self.seeding_time.as_secs(),
+        })
+    }
+
+    // Picks up where the last run left off, in place of a full check. `validated` is `resume`
+    // after `ResumeData::validate` against the files on disk
+    pub fn apply_resume(&mut self, resume: &ResumeData, validated: &Validated, now: Instant) {
+        if self.picker.is_none() {
+            return;
+        }
+        self.apply_check(validated);
+        if !resume.trackers.is_empty() {
+            self.trackers.replace(&resume.trackers, now);
+        }
+        // Whoever we knew last time may well be gone, so they rank below fresh tracker peers. A
+        // private torrent's can only have come from its trackers
+        let source = match self.is_private() {
+            true => PeerSource::Tracker,
+            false => PeerSource::Pex,
+        };
+        for peer in &resume.peers {
+            self.peer_list.insert(*peer, source, PexFlags(0), now);
+        }
+        self.prior_downloaded = resume.downloaded;
+        self.prior_uploaded = resume.uploaded;
+        self.seeding_time = Duration::from_secs(resume.seeding_time);
+    }
+
+    // The result of checking the files, from `resume::recover` when there's no resume data to go
+    // on. Finishes the `CheckingFiles` stage
+    pub fn apply_check(&mut self, checked: &Validated) {
+        let Some(picker) = self.picker.as_mut() else {
+            return;
+        };
+        for piece in checked.have.iter_ones() {
+            picker.mark_have(piece as u32);
+        }
+        for (piece, blocks) in &checked.partial {
+            picker.restore_partial(*piece, blocks);
+        }
+        self.update_piece_priorities();
+        if self.status == TorrentStatus::CheckingFiles {
+            self.status = self.active_status();
+        }
+    }
+
+    // Starts a forced recheck of the data on disk. Everything we had is forgotten until the check
+    // is done, so nothing is served or requested on the strength of stale data, and the torrent
+    // sits in `CheckingFiles` meanwhile. The check itself is `resume::recover` on its storage,
+    // handed to `finish_recheck`. False without an info dict, there's nothing to check
+    pub fn begin_recheck(&mut self) -> bool {
+        let Some(picker) = self.picker.as_mut() else {
+            return false;
+        };
+        if self.recheck.is_none() {
+            self.recheck = Some((self.status, picker.have().clone()));
+        }
+        picker.reset();
+        self.status = TorrentStatus::CheckingFiles;
+        true
+    }
+
+    // Like `apply_check`, but reporting what changed. Works for a torrent's first check as well
+    // as after `begin_recheck`. A torrent that was paused before the recheck stays paused
+    pub fn finish_recheck(&mut self, checked: &Validated) -> RecheckReport {
+        let (before, had) = self.recheck.take().unzip();
+        self.apply_check(checked);
+        if before == Some(TorrentStatus::Paused) {
+            self.status = TorrentStatus::Paused;
+        }
+
+        let mut corrupt = checked.corrupt.clone();
+        if let Some(had) = had {
+            corrupt.extend(
+                had.iter_ones()
+                    .filter(|piece| !checked.have.get(*piece))
+                    .map(|piece| piece as u32),
+            );
+        }
+        corrupt.sort_unstable();
+        corrupt.dedup();
+        let verified = checked.have.count_ones();
+        RecheckReport {
+            verified,
+            corrupt,
+            missing: checked.have.len() - verified,
+        }
+    }
+
+    pub fn skips_download(&self) -> bool {
+        self.skip_download
+    }
+
+    // Pieces we don't have are never picked while this is on, and the torrent counts as seeding.
+    // Turning it off puts them back at their files' priorities
+    pub fn set_skip_download(&mut self, skip: bool) {
+        self.skip_download = skip;
+        self.update_piece_priorities();
+        if matches!(
+            self.status,
+            TorrentStatus::Downloading | TorrentStatus::Seeding
+        ) {
+            self.status = self.active_status();
+        }
+    }
+
+    fn update_piece_priorities(&mut self) {
+        let (Some(metainfo), Some(picker)) = (&self.metainfo, self.picker.as_mut()) else {
+            return;
+        };
+        let lengths: Vec<u64> = metainfo.info.files.iter().map(|f| f.length).collect();
+        picker.set_file_priorities(&lengths, &self.file_priorities);
+        if let Some(pieces) = &self.streaming {
+            for piece in pieces.start..pieces.end.min(picker.num_pieces() as u32) {
+                picker.set_priority(piece, Priority::High);
+            }
+        }
+        if self.skip_download {
+            for piece in 0..picker.num_pieces() as u32 {
+                if !picker.have().get(piece as usize) {
+                    picker.set_priority(piece, Priority::Skip);
+                }
+            }
+        }
+    }
+
+    pub fn tick(&mut self, now: Instant) {
+        self.download_rate.tick(now);
+        self.upload_rate.tick(now);
+        self.peer_list.decay(now);
+
+        let uploaded = self.total_uploaded();
+        if self.status == TorrentStatus::Seeding {
+            self.seeding_time += now.saturating_duration_since(self.last_tick);
+            if uploaded != self.uploaded_at_tick {
+                self.idle_since = now;
+            }
+        } else {
+            self.idle_since = now;
+        }
+        self.uploaded_at_tick = uploaded;
+        self.last_tick = now;
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.metainfo
+            .as_ref()
+            .map(|m| m.info.total_length())
+            .unwrap_or(0)
+    }
+
+    // Verified bytes
+    pub fn bytes_done(&self) -> u64 {
+        let Some(picker) = &self.picker else {
+            return 0;
+        };
+        picker
+            .have()
+            .iter_ones()
+            .map(|piece| picker.piece_size(piece as u32) as u64)
+            .sum()
+    }
+
+    // What's left of the pieces we want, partial pieces counted in full
+    pub fn bytes_wanted_remaining(&self) -> u64 {
+        let Some(picker) = &self.picker else {
+            return 0;
+        };
+        (0..picker.num_pieces() as u32)
+            .filter(|piece| self.is_missing(picker, *piece))
+            .map(|piece| picker.piece_size(piece) as u64)
+            .sum()
+    }
+
+    // Time to finish the pieces we want at the smoothed download rate. None is never: nothing's
+    // coming in, or some piece we need isn't on any peer we know (web seeds have everything)
+    pub fn eta(&self) -> Option<Duration> {
+        let picker = self.picker.as_ref()?;
+        let pieces: Vec<u32> = (0..picker.num_pieces() as u32)
+            .filter(|piece| self.is_missing(picker, *piece))
+            .collect();
+        let remaining = pieces.iter().map(|p| picker.piece_size(*p) as u64).sum();
+        self.eta_for(remaining, &pieces)
+    }
+
+    // Same for each file, in `info.files` order, as if it had the whole download rate to itself.
+    // Skipped files have nothing remaining, even if pieces they share are still to come
+    pub fn file_etas(&self) -> Vec<Option<Duration>> {
+        let (Some(metainfo), Some(picker)) = (&self.metainfo, &self.picker) else {
+            return vec![];
+        };
+        let piece_length = metainfo.info.piece_length as u64;
+        let mut start = 0;
+        metainfo
+            .info
+            .files
+            .iter()
+            .zip(&self.file_priorities)
+            .map(|(file, priority)| {
+                let end = start + file.length;
+                if *priority == Priority::Skip {
+                    start = end;
+                    return Some(Duration::ZERO);
+                }
+                let first = (start / piece_length) as u32;
+                let last = end.div_ceil(piece_length) as u32;
+                let pieces: Vec<u32> = (first..last)
+                    .filter(|piece| self.is_missing(picker, *piece))
+                    .collect();
+                let remaining = pieces
+                    .iter()
+                    .map(|piece| {
+                        let piece_start = *piece as u64 * piece_length;
+                        let piece_end = piece_start + picker.piece_size(*piece) as u64;
+                        piece_end.min(end) - piece_start.max(start)
+                    })
+                    .sum();
+                start = end;
+                self.eta_for(remaining, &pieces)
+            })
+            .collect()
+    }
+
+    fn is_missing(&self, picker: &PiecePicker, piece: u32) -> bool {
+        !picker.have().get(piece as usize) && picker.priority(piece) != Priority::Skip
+    }
+
+    fn eta_for(&self, remaining: u64, pieces: &[u32]) -> Option<Duration> {
+        if remaining == 0 {
+            return Some(Duration::ZERO);
+        }
+        let picker = self.picker.as_ref()?;
+        let web_seeded = self
+            .metainfo
+            .as_ref()
+            .is_some_and(|m| !m.url_list.is_empty());
+        if !web_seeded && pieces.iter().any(|p| picker.availability(*p) == 0) {
+            return None;
+        }
+        let rate = self.download_rate.get();
+        if rate < 1.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(remaining as f64 / rate))
+    }
+
+    // 0.0 to 1.0
+    pub fn progress(&self) -> f64 {
+        match self.total_length() {
+            0 => 0.0,
+            total => self.bytes_done() as f64 / total as f64,
+        }
+    }
+
+    // A cheap summary of what a status poller would show: status, progress in 0.1% steps and peer
+    // counts. RPC clients can hold on to it and skip torrents whose fingerprint hasn't changed
+    // since the last poll. Rates are left out on purpose since they'd change on every poll
+    pub fn state_fingerprint(&self) -> u64 {
+        let progress_bucket = match self.total_length() {
+            0 => 0,
+            total => self.bytes_done() * PROGRESS_BUCKETS / total,
+        };
+
+        let mut hasher = DefaultHasher::new();
+        self.status.hash(&mut hasher);
+        self.force_started.hash(&mut hasher);
+        progress_bucket.hash(&mut hasher);
+        self.num_peers.hash(&mut hasher);
+        self.num_seeds.hash(&mut hasher);
+        self.metainfo.is_some().hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl Drop for Torrent {
+    fn drop(&mut self) {
+        self.unregister_private();
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::bitfield::Bitfield;
+
+    fn torrent(now: Instant) -> Torrent {
+        let buf = include_bytes!("../bencode/tests/fixtures/sample.torrent");
+        Torrent::new(Metainfo::from_bytes(buf).unwrap(), now)
+    }
+
+    #[test]
+    fn test_progress() {
+        let mut torrent = torrent(Instant::now());
+        assert_eq!(torrent.progress(), 0.0);
+
+        torrent.picker_mut().unwrap().piece_verified(1);
+        assert_eq!(torrent.bytes_done(), 25000 - 16384);
+
+        torrent.picker_mut().unwrap().piece_verified(0);
+        assert_eq!(torrent.progress(), 1.0);
+    }
+
+    #[test]
+    fn test_eta() {
+        let now = Instant::now();
+        let mut torrent = torrent(now);
+        torrent.on_downloaded(1000);
+        torrent.tick(now + Duration::from_secs(1));
+
+        // Coming in at 1000 B/s, but nobody has the pieces
+        assert_eq!(torrent.eta(), None);
+        torrent.picker_mut().unwrap().add_peer(&Bitfield::full(2));
+        assert_eq!(torrent.eta(), Some(Duration::from_secs(25)));
+
+        torrent.picker_mut().unwrap().piece_verified(0);
+        assert_eq!(torrent.bytes_wanted_remaining(), 25000 - 16384);
+        assert_eq!(torrent.eta(), Some(Duration::from_millis(8616)));
+        assert_eq!(torrent.file_etas(), vec![torrent.eta()]);
+
+        torrent
+            .picker_mut()
+            .unwrap()
+            .set_priority(1, Priority::Skip);
+        assert_eq!(torrent.eta(), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_file_priorities() {
+        let mut torrent = torrent(Instant::now());
+        assert_eq!(torrent.file_priorities(), [Priority::Normal]);
+        assert!(!torrent.set_file_priority(1, Priority::High));
+
+        assert!(torrent.set_file_priority(0, Priority::Skip));
+        let picker = torrent.picker().unwrap();
+        assert_eq!(picker.priority(0), Priority::Skip);
+        assert_eq!(picker.priority(1), Priority::Skip);
+        assert_eq!(torrent.bytes_wanted_remaining(), 0);
+        assert_eq!(torrent.file_etas(), vec![Some(Duration::ZERO)]);
+
+        // Turning skip-download on and off again doesn't lose them
+        torrent.set_skip_download(true);
+        torrent.set_skip_download(false);
+        assert_eq!(torrent.picker().unwrap().priority(0), Priority::Skip);
+        torrent.set_file_priority(0, Priority::High);
+        assert_eq!(torrent.picker().unwrap().priority(1), Priority::High);
+    }
+
+    #[test]
+    fn test_streaming_boost() {
+        let mut torrent = torrent(Instant::now());
+        torrent.set_file_priority(0, Priority::Skip);
+        torrent.set_streaming(Some(1..5));
+        let picker = torrent.picker().unwrap();
+        assert_eq!(picker.priority(0), Priority::Skip);
+        assert_eq!(picker.priority(1), Priority::High);
+
+        torrent.set_streaming(None);
+        assert_eq!(torrent.picker().unwrap().priority(1), Priority::Skip);
+    }
+
+    #[test]
+    fn test_fingerprint_changes_with_state() {
+        let mut torrent = torrent(Instant::now());
+        let start = torrent.state_fingerprint();
+        assert_eq!(torrent.state_fingerprint(), start);
+
+        torrent.set_peer_counts(3, 1);
+        let with_peers = torrent.state_fingerprint();
+        assert_ne!(with_peers, start);
+
+        torrent.set_status(TorrentStatus::Downloading);
+        let downloading = torrent.state_fingerprint();
+        assert_ne!(downloading, with_peers);
+
+        torrent.picker_mut().unwrap().piece_verified(0);
+        assert_ne!(torrent.state_fingerprint(), downloading);
+    }
+
+    #[test]
+    fn test_resume() {
+        let now = Instant::now();
+        let mut before = torrent(now);
+        before.picker_mut().unwrap().piece_verified(0);
+        before.peer_list_mut().insert(
+            "10.0.0.1:6881".parse().unwrap(),
+            PeerSource::Tracker,
+            PexFlags(0),
+            now,
+        );
+        before.on_uploaded(500);
+        let resume = before.resume_data(vec![None]).unwrap();
+        assert_eq!(resume.uploaded, 500);
+
+        let mut restarted = torrent(now);
+        let validated = Validated {
+            have: resume.have.clone(),
+            partial: vec![],
+            corrupt: vec![],
+            rechecked: 0,
+        };
+        restarted.apply_resume(&resume, &validated, now);
+        assert!(restarted.picker().unwrap().have().get(0));
+        assert_eq!(restarted.status(), TorrentStatus::Downloading);
+        assert_eq!(restarted.peer_list().len(), 1);
+        assert_eq!(restarted.total_uploaded(), 500);
+    }
+
+    #[test]
+    fn test_seed_goals() {
+        let now = Instant::now();
+        let secs = |n| now + Duration::from_secs(n);
+        let mut torrent = torrent(now);
+        torrent.set_status(TorrentStatus::Seeding);
+        let total = torrent.total_length();
+        torrent.on_downloaded(total);
+        torrent.on_uploaded(total);
+
+        let defaults = SeedGoals {
+            ratio: Some(2.0),
+            ..SeedGoals::default()
+        };
+        assert_eq!(torrent.seed_goal_reached(&defaults, now), None);
+        torrent.on_uploaded(total);
+        assert_eq!(
+            torrent.seed_goal_reached(&defaults, now),
+            Some(SeedGoal::Ratio)
+        );
+        torrent.set_force_start(true);
+        assert_eq!(torrent.seed_goal_reached(&defaults, now), None);
+        torrent.set_force_start(false);
+
+        // The torrent's own goals replace the session's
+        torrent.set_seed_goals(Some(SeedGoals {
+            seed_time: Some(Duration::from_secs(60)),
+            idle_time: Some(Duration::from_secs(30)),
+            ..SeedGoals::default()
+        }));
+        torrent.tick(secs(20));
+        assert_eq!(torrent.seed_goal_reached(&defaults, secs(20)), None);
+        torrent.tick(secs(50));
+        assert_eq!(
+            torrent.seed_goal_reached(&defaults, secs(50)),
+            Some(SeedGoal::IdleTime)
+        );
+        torrent.on_uploaded(1);
+        torrent.tick(secs(60));
+        assert_eq!(torrent.seeding_time(), Duration::from_secs(60));
+        assert_eq!(
+            torrent.seed_goal_reached(&defaults, secs(60)),
+            Some(SeedGoal::SeedTime)
+        );
+        assert_eq!(torrent.resume_data(vec![None]).unwrap().seeding_time, 60);
+    }
+
+    #[test]
+    fn test_forced_recheck() {
+        let mut torrent = torrent(Instant::now());
+        torrent.picker_mut().unwrap().piece_verified(0);
+        torrent.picker_mut().unwrap().piece_verified(1);
+        torrent.set_status(TorrentStatus::Paused);
+
+        assert!(torrent.begin_recheck());
+        assert_eq!(torrent.status(), TorrentStatus::CheckingFiles);
+        assert!(torrent.picker().unwrap().have().none());
+
+        // Piece 1 went bad on disk behind our back
+        let mut have = Bitfield::new(2);
+        have.set(0);
+        let checked = Validated {
+            have,
+            partial: vec![],
+            corrupt: vec![1],
+            rechecked: 2,
+        };
+        let report = torrent.finish_recheck(&checked);
+        assert_eq!(
+            report,
+            RecheckReport {
+                verified: 1,
+                corrupt: vec![1],
+                missing: 1,
+            }
+        );
+        assert_eq!(torrent.status(), TorrentStatus::Paused);
+        assert!(torrent.picker().unwrap().have().get(0));
+    }
+
+    #[test]
+    fn test_skip_download() {
+        let mut torrent = torrent(Instant::now());
+        torrent.set_skip_download(true);
+        torrent.begin_recheck();
+        let mut have = Bitfield::new(2);
+        have.set(1);
+        let checked = Validated {
+            have,
+            partial: vec![],
+            corrupt: vec![],
+            rechecked: 2,
+        };
+        torrent.finish_recheck(&checked);
+
+        // Half the data and seeding it, with nothing left to pick
+        assert_eq!(torrent.status(), TorrentStatus::Seeding);
+        let picker = torrent.picker_mut().unwrap();
+        assert!(picker.pick(&Bitfield::full(2), 10).is_empty());
+
+        torrent.set_skip_download(false);
+        assert_eq!(torrent.status(), TorrentStatus::Downloading);
+        let picker = torrent.picker_mut().unwrap();
+        assert_eq!(picker.pick(&Bitfield::full(2), 10)[0].piece, 0);
+    }
+
+    #[test]
+    fn test_fingerprint_ignores_rates() {
+        let now = Instant::now();
+        let mut torrent = torrent(now);
+        let before = torrent.state_fingerprint();
+        torrent.on_downloaded(1000);
+        torrent.tick(now + std::time::Duration::from_secs(1));
+
+        assert_eq!(torrent.state_fingerprint(), before);
+    }
+
+    #[test]
+    fn test_private() {
+        let buf = include_bytes!("../bencode/tests/fixtures/sample.torrent");
+        let mut metainfo = Metainfo::from_bytes(buf).unwrap();
+        assert!(!torrent(Instant::now()).is_private());
+
+        // The registry is shared by every test, so keep away from the sample's info-hash
+        metainfo.info_hash = InfoHash([0xa4; 20]);
+        metainfo.info.private = true;
+        let mut private = Torrent::new(metainfo.clone(), Instant::now());
+        assert!(private.is_private());
+        assert!(audit::is_private(metainfo.info_hash));
+
+        // A duplicate being merged in and dropped doesn't make it public
+        private.merge(
+            Torrent::new(metainfo.clone(), Instant::now()),
+            Instant::now(),
+        );
+        assert!(audit::is_private(metainfo.info_hash));
+
+        // Peers from its trackers only, and only for as long as they're its trackers
+        let now = Instant::now();
+        let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let list = private.peer_list_mut();
+        assert!(!list.insert(peer, PeerSource::Dht, PexFlags(0), now));
+        assert!(list.insert(peer, PeerSource::Tracker, PexFlags(0), now));
+        let tiers = private.trackers().tiers();
+        private.replace_trackers(&tiers, now);
+        assert_eq!(private.peer_generation(), 0);
+        private.replace_trackers(&[vec!["http://tracker.example/announce".to_string()]], now);
+        assert!(private.peer_list().is_empty());
+        assert_eq!(private.peer_generation(), 1);
+
+        private.switch_version(InfoHash([0xa5; 20]));
+        assert!(!private.is_private());
+        assert!(!audit::is_private(metainfo.info_hash));
+
+        drop(Torrent::new(metainfo.clone(), Instant::now()));
+        assert!(!audit::is_private(metainfo.info_hash));
+    }
+
+    #[test]
+    fn test_switch_version() {
+        let mut torrent = torrent(Instant::now());
+        torrent.set_label(Some("feed".to_string()));
+        torrent.switch_version(InfoHash([9; 20]));
+
+        assert_eq!(torrent.info_hash(), InfoHash([9; 20]));
+        assert_eq!(torrent.status(), TorrentStatus::DownloadingMetadata);
+        assert!(torrent.metainfo().is_none());
+        assert_eq!(torrent.label(), Some("feed"));
+    }
+
+    #[test]
+    fn test_force_start() {
+        let mut torrent = torrent(Instant::now());
+        torrent.set_status(TorrentStatus::Queued);
+        let queued = torrent.state_fingerprint();
+
+        torrent.set_force_start(true);
+        assert_eq!(torrent.status(), TorrentStatus::Downloading);
+        assert!(!torrent.respects_limits());
+        assert_ne!(torrent.state_fingerprint(), queued);
+        torrent.set_status(TorrentStatus::Queued);
+        assert_eq!(torrent.status(), TorrentStatus::Downloading);
+
+        torrent.set_force_start(false);
+        torrent.set_status(TorrentStatus::Queued);
+        assert_eq!(torrent.status(), TorrentStatus::Queued);
+    }
+
+    #[test]
+    fn test_replace_trackers() {
+        let now = Instant::now();
+        let mut torrent = torrent(now);
+        let original = torrent.trackers().tiers();
+        assert!(!original.is_empty());
+        torrent.trackers_mut().on_failed(0, now);
+        assert!(torrent.trackers().due(now).is_empty());
+
+        assert!(torrent.force_reannounce(Some(0), now));
+        assert_eq!(torrent.trackers().due(now), vec![0]);
+        assert!(!torrent.force_reannounce(Some(9), now));
+
+        let tiers = vec![vec!["udp://tracker.example:6969".to_string()]];
+        torrent.replace_trackers(&tiers, now);
+        assert_eq!(torrent.trackers().tiers(), tiers);
+        assert_eq!(torrent.trackers().due(now), vec![0]);
+    }
+
+    #[test]
+    fn test_merge() {
+        let now = Instant::now();
+        let metainfo = torrent(now).metainfo().unwrap().clone();
+        let mut magnet = MagnetLink::new(metainfo.info_hash);
+        magnet.trackers = vec!["http://extra/announce".to_string()];
+        magnet.peers = vec!["10.0.0.1:6881".to_string(), "peer.example:6881".to_string()];
+        let mut ours = Torrent::from_magnet(&magnet, now);
+        assert_eq!(ours.peer_list().len(), 1);
+
+        let mut theirs = Torrent::new(metainfo, now);
+        theirs.set_label(Some("ignored".to_string()));
+        let their_trackers = theirs.trackers().len();
+        ours.merge(theirs, now);
+
+        assert_eq!(ours.status(), TorrentStatus::CheckingFiles);
+        assert!(ours.metainfo().is_some());
+        assert_eq!(ours.trackers().len(), their_trackers + 1);
+        assert_eq!(ours.peer_list().len(), 1);
+        assert_eq!(ours.label(), None);
+    }
+
+    #[test]
+    fn test_from_magnet() {
+        let mut magnet = MagnetLink::new(InfoHash([1; 20]));
+        magnet.trackers = vec![
+            "http://a/announce".to_string(),
+            "http://b/announce".to_string(),
+        ];
+        let torrent = Torrent::from_magnet(&magnet, Instant::now());
+
+        assert_eq!(torrent.status(), TorrentStatus::DownloadingMetadata);
+        assert_eq!(torrent.name(), InfoHash([1; 20]).to_hex());
+        assert_eq!(torrent.progress(), 0.0);
+        assert_eq!(torrent.trackers().tiers().len(), 2);
+    }
+}
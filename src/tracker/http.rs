@@ -0,0 +1,335 @@
+// HTTP trackers. Requests are GETs with the parameters in the query string; responses are a
+// bencoded dict. We always ask for compact peer lists (BEP 23) but accept the old list of dicts
+// too, since some trackers ignore `compact`.
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::time::Duration;
+
+use bencode::BencodeValue;
+
+use super::{AnnounceEvent, AnnounceRequest, AnnounceResponse, ScrapeStats, TrackerError, Url};
+use crate::compact::CompactPeers;
+use crate::infohash::InfoHash;
+use crate::proxy::{self, ProxyConfig, Target};
+
+// Responses bigger than this are not from a tracker
+const MAX_RESPONSE: u64 = 1 << 20;
+
+pub fn announce(
+    url: &str,
+    request: &AnnounceRequest,
+    proxy: Option<&ProxyConfig>,
+    timeout: Duration,
+) -> Result<AnnounceResponse, TrackerError> {
+    parse_announce(&get(&announce_url(url, request), proxy, timeout)?)
+}
+
+pub fn scrape(
+    url: &str,
+    info_hashes: &[InfoHash],
+    proxy: Option<&ProxyConfig>,
+    timeout: Duration,
+) -> Result<HashMap<InfoHash, ScrapeStats>, TrackerError> {
+    let mut url = scrape_url(url).ok_or(TrackerError::ScrapeUnsupported)?;
+    for info_hash in info_hashes {
+        url.push(if url.contains('?') { '&' } else { '?' });
+        url.push_str("info_hash=");
+        url.push_str(&url_encode(&info_hash.0));
+    }
+    parse_scrape(&get(&url, proxy, timeout)?)
+}
+
+pub fn announce_url(url: &str, request: &AnnounceRequest) -> String {
+    let mut query = format!(
+        "info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1&key={:08x}",
+        url_encode(&request.info_hash.0),
+        url_encode(&request.peer_id),
+        request.port,
+        request.uploaded,
+        request.downloaded,
+        request.left,
+        request.key,
+    );
+    let event = match request.event {
+        AnnounceEvent::None => None,
+        AnnounceEvent::Started => Some("started"),
+        AnnounceEvent::Completed => Some("completed"),
+        AnnounceEvent::Stopped => Some("stopped"),
+    };
+    if let Some(event) = event {
+        query.push_str("&event=");
+        query.push_str(event);
+    }
+    if let Some(num_want) = request.num_want {
+        query.push_str(&format!("&numwant={}", num_want));
+    }
+    if let Some(ipv4) = request.ipv4 {
+        query.push_str(&format!("&ipv4={}", ipv4));
+    }
+    if let Some(ipv6) = request.ipv6 {
+        query.push_str(&format!(
+            "&ipv6={}",
+            url_encode(ipv6.to_string().as_bytes())
+        ));
+    }
+
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", url, separator, query)
+}
+
+// The scrape convention: if the last path segment of the announce URL starts with "announce",
+// the same URL with "scrape" in its place is the scrape URL
+pub fn scrape_url(announce: &str) -> Option<String> {
+    let (path, query) = match announce.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (announce, None),
+    };
+    let slash = path.rfind('/')?;
+    let rest = path[slash + 1..].strip_prefix("announce")?;
+    let mut url = format!("{}/scrape{}", &path[..slash], rest);
+    if let Some(query) = query {
+        url.push('?');
+        url.push_str(query);
+    }
+    Some(url)
+}
+
+pub fn parse_announce(body: &[u8]) -> Result<AnnounceResponse, TrackerError> {
+    let root = root_dict(body)?;
+    let int = |key: &[u8]| root.get(key).and_then(|v| v.as_int());
+    let count = |key: &[u8]| int(key).and_then(|v| u32::try_from(v).ok());
+    let secs = |key: &[u8]| {
+        int(key)
+            .and_then(|v| u64::try_from(v).ok())
+            .map(Duration::from_secs)
+    };
+
+    let mut peers = vec![];
+    match root.get(b"peers") {
+        Some(BencodeValue::ByteStr(buf)) => peers.extend(
+            CompactPeers::parse::<SocketAddrV4>(buf)
+                .map_err(|_| TrackerError::InvalidResponse("peers"))?
+                .into_iter()
+                .map(SocketAddr::V4),
+        ),
+        Some(BencodeValue::List(list)) => peers.extend(list.iter().filter_map(|peer| {
+            let ip = std::str::from_utf8(peer.get(b"ip")?.as_bytes()?).ok()?;
+            let port = u16::try_from(peer.get(b"port")?.as_int()?).ok()?;
+            Some(SocketAddr::new(ip.parse().ok()?, port))
+        })),
+        _ => {}
+    }
+    if let Some(buf) = root.get(b"peers6").and_then(|v| v.as_bytes()) {
+        peers.extend(
+            CompactPeers::parse::<SocketAddrV6>(buf)
+                .map_err(|_| TrackerError::InvalidResponse("peers6"))?
+                .into_iter()
+                .map(SocketAddr::V6),
+        );
+    }
+
+    Ok(AnnounceResponse {
+        interval: secs(b"interval").ok_or(TrackerError::InvalidResponse("interval"))?,
+        min_interval: secs(b"min interval"),
+        seeders: count(b"complete"),
+        leechers: count(b"incomplete"),
+        peers,
+        tracker_id: root
+            .get(b"tracker id")
+            .and_then(|v| v.as_bytes())
+            .map(|v| String::from_utf8_lossy(v).into_owned()),
+        warning: root
+            .get(b"warning message")
+            .and_then(|v| v.as_bytes())
+            .map(|v| String::from_utf8_lossy(v).into_owned()),
+    })
+}
+
+pub fn parse_scrape(body: &[u8]) -> Result<HashMap<InfoHash, ScrapeStats>, TrackerError> {
+    let root = root_dict(body)?;
+    let files = root
+        .get(b"files")
+        .and_then(|f| f.as_dict())
+        .ok_or(TrackerError::InvalidResponse("files"))?;
+
+    Ok(files
+        .iter()
+        .filter_map(|(info_hash, stats)| {
+            let count = |key: &[u8]| {
+                stats
+                    .get(key)
+                    .and_then(|v| v.as_int())
+                    .and_then(|v| u32::try_from(v).ok())
+                    .unwrap_or(0)
+            };
+            let stats = ScrapeStats {
+                seeders: count(b"complete"),
+                completed: count(b"downloaded"),
+                leechers: count(b"incomplete"),
+            };
+            Some((InfoHash::from_bytes(info_hash)?, stats))
+        })
+        .collect())
+}
+
+fn root_dict(body: &[u8]) -> Result<BencodeValue, TrackerError> {
+    let root = bencode::decode(body)?
+        .into_iter()
+        .next()
+        .filter(|root| root.as_dict().is_some())
+        .ok_or(TrackerError::InvalidResponse("not a dict"))?;
+    if let Some(reason) = root.get(b"failure reason").and_then(|r| r.as_bytes()) {
+        return Err(TrackerError::Failure(
+            String::from_utf8_lossy(reason).into_owned(),
+        ));
+    }
+    Ok(root)
+}
+
+// Plain HTTP/1.0 so the response is never chunked and ends when the connection closes. The
+// tracker goes by name, for the proxy to resolve or for `proxy::connect` to race its addresses
+fn get(url: &str, proxy: Option<&ProxyConfig>, timeout: Duration) -> Result<Vec<u8>, TrackerError> {
+    let parsed = Url::parse(url)?;
+    let target = Target::Domain(parsed.host.to_string(), parsed.port.unwrap_or(80));
+    let mut stream = proxy::connect(proxy, &target, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let path = if parsed.path.is_empty() {
+        "/"
+    } else {
+        parsed.path
+    };
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: Hurricane/{}\r\nConnection: close\r\n\r\n",
+        path,
+        parsed.host,
+        env!("CARGO_PKG_VERSION")
+    )?;
+
+    let mut response = vec![];
+    stream.take(MAX_RESPONSE).read_to_end(&mut response)?;
+    let header_end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or(TrackerError::InvalidResponse("no headers"))?;
+    let status = response
+        .split(|b| *b == b' ')
+        .nth(1)
+        .ok_or(TrackerError::InvalidResponse("no status"))?;
+    if status != b"200" {
+        return Err(TrackerError::InvalidResponse("HTTP status"));
+    }
+    Ok(response.split_off(header_end + 4))
+}
+
+pub(crate) fn url_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 3);
+    for b in bytes {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') {
+            out.push(*b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_announce_url() {
+        let request = AnnounceRequest {
+            info_hash: InfoHash([0xab; 20]),
+            peer_id: *b"-HU0001-abcdefghijkl",
+            port: 6881,
+            uploaded: 0,
+            downloaded: 10,
+            left: 100,
+            event: AnnounceEvent::Started,
+            num_want: Some(50),
+            key: 0xdead,
+            ipv4: None,
+            ipv6: None,
+        };
+        let url = announce_url("http://t.example/announce?passkey=x", &request);
+
+        assert!(url.starts_with("http://t.example/announce?passkey=x&info_hash=%AB%AB"));
+        assert!(url.contains("&peer_id=-HU0001-abcdefghijkl&port=6881&"));
+        assert!(url.ends_with("&key=0000dead&event=started&numwant=50"));
+
+        let request = AnnounceRequest {
+            ipv4: Some([203, 0, 113, 7].into()),
+            ipv6: Some("2001:db8::7".parse().unwrap()),
+            ..request
+        };
+        let url = announce_url("http://t.example/announce", &request);
+        assert!(url.ends_with("&numwant=50&ipv4=203.0.113.7&ipv6=2001%3Adb8%3A%3A7"));
+    }
+
+    #[test]
+    fn test_scrape_url() {
+        assert_eq!(
+            scrape_url("http://t.example/announce").as_deref(),
+            Some("http://t.example/scrape")
+        );
+        assert_eq!(
+            scrape_url("http://t.example/x/announce.php?k=1").as_deref(),
+            Some("http://t.example/x/scrape.php?k=1")
+        );
+        assert_eq!(scrape_url("http://t.example/a"), None);
+    }
+
+    #[test]
+    fn test_parse_announce() {
+        let body = b"d8:completei5e10:incompletei3e8:intervali1800e5:peers12:\x01\x02\x03\x04\x1a\xe1\x05\x06\x07\x08\x00\x50e";
+        let response = parse_announce(body).unwrap();
+
+        assert_eq!(response.interval, Duration::from_secs(1800));
+        assert_eq!(response.seeders, Some(5));
+        assert_eq!(
+            response.peers,
+            vec![
+                "1.2.3.4:6881".parse::<SocketAddr>().unwrap(),
+                "5.6.7.8:80".parse().unwrap()
+            ]
+        );
+
+        assert_eq!(response.warning, None);
+
+        let body = b"d8:intervali60e5:peersld2:ip3:::14:porti6881eee15:warning message4:slowe";
+        let response = parse_announce(body).unwrap();
+        assert_eq!(response.peers, vec!["[::1]:6881".parse().unwrap()]);
+        assert_eq!(response.warning.as_deref(), Some("slow"));
+        assert_eq!(
+            parse_announce(b"d14:failure reason4:nopee"),
+            Err(TrackerError::Failure("nope".to_string()))
+        );
+        let err = parse_announce(b"d8:intervali60e5:peersld2:ip3:::14:porti68x1eeee").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "bad bencode in the response: unexpected 'x' in an int while parsing peers[0].port at byte 42"
+        );
+    }
+
+    #[test]
+    fn test_parse_scrape() {
+        let mut body = b"d5:filesd20:".to_vec();
+        body.extend_from_slice(&[7; 20]);
+        body.extend_from_slice(b"d8:completei10e10:downloadedi50e10:incompletei2eeee");
+        let stats = parse_scrape(&body).unwrap();
+
+        assert_eq!(
+            stats[&InfoHash([7; 20])],
+            ScrapeStats {
+                seeders: 10,
+                completed: 50,
+                leechers: 2
+            }
+        );
+    }
+}
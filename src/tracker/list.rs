@@ -0,0 +1,266 @@
+// The trackers of one torrent and when each is next due. Tiers are kept as BEP 12 lays them out,
+// but every tracker gets announced to on its own schedule: a dead tracker in the first tier
+// shouldn't hold up the others. Failures back off exponentially, successes follow the tracker's
+// interval.
+use std::time::{Duration, Instant};
+
+// First retry after a failed announce, doubling from there
+pub const RETRY_BASE: Duration = Duration::from_secs(60);
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct TrackerEntry {
+    pub url: String,
+    pub tier: usize,
+    next_announce: Instant,
+    failures: u32,
+}
+
+impl TrackerEntry {
+    pub fn next_announce(&self) -> Instant {
+        self.next_announce
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct TrackerList {
+    entries: Vec<TrackerEntry>,
+}
+
+impl TrackerList {
+    // Everything is due right away. Empty tiers and duplicate URLs are dropped
+    pub fn new(tiers: &[Vec<String>], now: Instant) -> Self {
+        let mut list = TrackerList::default();
+        list.replace(tiers, now);
+        list
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&TrackerEntry> {
+        self.entries.get(index)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &TrackerEntry> {
+        self.entries.iter()
+    }
+
+    // The same layout `new` takes, for saving alongside the rest of the torrent
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        let mut tiers: Vec<Vec<String>> = vec![];
+        for entry in &self.entries {
+            match tiers.get_mut(entry.tier) {
+                Some(tier) => tier.push(entry.url.clone()),
+                None => tiers.push(vec![entry.url.clone()]),
+            }
+        }
+        tiers
+    }
+
+    // Swaps in a new set of trackers, all due right away with a clean slate
+    pub fn replace(&mut self, tiers: &[Vec<String>], now: Instant) {
+        self.entries.clear();
+        for urls in tiers.iter().filter(|urls| !urls.is_empty()) {
+            let tier = self.entries.last().map_or(0, |e| e.tier + 1);
+            for url in urls {
+                if self.entries.iter().any(|e| &e.url == url) {
+                    continue;
+                }
+                self.entries.push(TrackerEntry {
+                    url: url.clone(),
+                    tier,
+                    next_announce: now,
+                    failures: 0,
+                });
+            }
+        }
+    }
+
+    // Adds trackers we don't have yet, e.g. from a second copy of the same torrent. They join the
+    // tier of the same number, or a new one past the end. Existing indices stay put
+    pub fn merge(&mut self, tiers: &[Vec<String>], now: Instant) {
+        let num_tiers = self.entries.iter().map(|e| e.tier + 1).max().unwrap_or(0);
+        let mut next_tier = num_tiers;
+        for (i, urls) in tiers.iter().filter(|urls| !urls.is_empty()).enumerate() {
+            let tier = if i < num_tiers { i } else { next_tier };
+            let mut added = false;
+            for url in urls {
+                if self.entries.iter().any(|e| &e.url == url) {
+                    continue;
+                }
+                self.entries.push(TrackerEntry {
+                    url: url.clone(),
+                    tier,
+                    next_announce: now,
+                    failures: 0,
+                });
+                added = true;
+            }
+            if added && tier == next_tier {
+                next_tier += 1;
+            }
+        }
+    }
+
+    // Indices of the trackers to announce to now
+    pub fn due(&self, now: Instant) -> Vec<usize> {
+        (0..self.entries.len())
+            .filter(|&i| self.entries[i].next_announce <= now)
+            .collect()
+    }
+
+    // When the next tracker is due, if there are any
+    pub fn next_due(&self) -> Option<Instant> {
+        self.entries.iter().map(|e| e.next_announce).min()
+    }
+
+    pub fn on_announced(&mut self, index: usize, interval: Duration, now: Instant) {
+        if let Some(entry) = self.entries.get_mut(index) {
+            entry.failures = 0;
+            entry.next_announce = now + interval;
+        }
+    }
+
+    pub fn on_failed(&mut self, index: usize, now: Instant) {
+        if let Some(entry) = self.entries.get_mut(index) {
+            entry.next_announce = now + RETRY_BASE * (1 << entry.failures.min(5));
+            entry.failures += 1;
+        }
+    }
+
+    // Makes one tracker (or all of them, for None) due now and forgets their failures. Returns
+    // false for an index that doesn't exist
+    pub fn force_reannounce(&mut self, index: Option<usize>, now: Instant) -> bool {
+        let entries = match index {
+            Some(index) => match self.entries.get_mut(index) {
+                Some(entry) => std::slice::from_mut(entry),
+                None => return false,
+            },
+            None => &mut self.entries[..],
+        };
+        for entry in entries {
+            entry.failures = 0;
+            entry.next_announce = now;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn tiers() -> Vec<Vec<String>> {
+        vec![
+            vec![
+                "http://a/announce".to_string(),
+                "http://b/announce".to_string(),
+            ],
+            vec![],
+            vec!["udp://c:80".to_string(), "http://a/announce".to_string()],
+        ]
+    }
+
+    #[test]
+    fn test_tiers() {
+        let list = TrackerList::new(&tiers(), Instant::now());
+
+        assert_eq!(list.len(), 3);
+        assert_eq!(list.get(2).unwrap().tier, 1);
+        assert_eq!(
+            list.tiers(),
+            vec![
+                vec![
+                    "http://a/announce".to_string(),
+                    "http://b/announce".to_string()
+                ],
+                vec!["udp://c:80".to_string()],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_backoff_and_force() {
+        let now = Instant::now();
+        let mut list = TrackerList::new(&tiers(), now);
+        assert_eq!(list.due(now), vec![0, 1, 2]);
+
+        list.on_announced(0, Duration::from_secs(1800), now);
+        list.on_failed(1, now);
+        list.on_failed(1, now);
+        assert_eq!(list.get(1).unwrap().failures(), 2);
+        assert_eq!(list.get(1).unwrap().next_announce(), now + RETRY_BASE * 2);
+        assert_eq!(list.due(now), vec![2]);
+
+        let later = now + Duration::from_secs(10);
+        assert!(list.force_reannounce(Some(1), later));
+        assert_eq!(list.due(later), vec![1, 2]);
+        assert_eq!(list.get(1).unwrap().failures(), 0);
+        assert!(list.force_reannounce(None, later));
+        assert_eq!(list.due(later), vec![0, 1, 2]);
+        assert!(!list.force_reannounce(Some(3), later));
+    }
+
+    #[test]
+    fn test_replace() {
+        let now = Instant::now();
+        let mut list = TrackerList::new(&tiers(), now);
+        list.on_announced(0, Duration::from_secs(1800), now);
+        list.on_failed(1, now);
+
+        let later = now + Duration::from_secs(10);
+        list.replace(
+            &[
+                vec!["http://d/announce".to_string()],
+                vec!["http://a/announce".to_string()],
+            ],
+            later,
+        );
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.due(later), vec![0, 1]);
+        assert_eq!(list.get(1).unwrap().tier, 1);
+        assert_eq!(list.next_due(), Some(later));
+    }
+
+    #[test]
+    fn test_merge() {
+        let now = Instant::now();
+        let mut list = TrackerList::new(&tiers(), now);
+        list.on_announced(0, Duration::from_secs(1800), now);
+
+        list.merge(
+            &[
+                vec![
+                    "http://a/announce".to_string(),
+                    "http://e/announce".to_string(),
+                ],
+                vec!["udp://c:80".to_string()],
+                vec!["http://f/announce".to_string()],
+            ],
+            now,
+        );
+        assert_eq!(
+            list.tiers(),
+            vec![
+                vec![
+                    "http://a/announce".to_string(),
+                    "http://b/announce".to_string(),
+                    "http://e/announce".to_string(),
+                ],
+                vec!["udp://c:80".to_string()],
+                vec!["http://f/announce".to_string()],
+            ]
+        );
+        // The one we'd already announced to keeps its schedule
+        assert_eq!(list.due(now), vec![1, 2, 3, 4]);
+    }
+}
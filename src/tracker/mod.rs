@@ -0,0 +1,240 @@
+// Tracker announces and scrapes, over HTTP (BEP 3, BEP 23, BEP 48) and UDP (BEP 15).
+// The request/response types are shared; `announce` and `scrape` pick the protocol from the URL
+// and block until the tracker answers or `timeout` runs out. Only plain http:// is supported,
+// https trackers need a TLS stack we don't pull in.
+pub mod http;
+pub mod list;
+pub mod udp;
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use std::{fmt, io};
+
+use bencode::DecodeError;
+
+use crate::dns;
+use crate::infohash::InfoHash;
+use crate::proxy::ProxyConfig;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum TrackerError {
+    Io(io::ErrorKind),
+    Decode(DecodeError),
+    InvalidUrl,
+    UnsupportedScheme(String),
+    // Not the response we were expecting, e.g. a bad HTTP status or a mismatched transaction ID
+    InvalidResponse(&'static str),
+    // The tracker's own failure reason
+    Failure(String),
+    // The tracker doesn't do scrapes (its announce URL has no `announce` to replace)
+    ScrapeUnsupported,
+}
+
+impl From<io::Error> for TrackerError {
+    fn from(err: io::Error) -> Self {
+        TrackerError::Io(err.kind())
+    }
+}
+
+impl From<DecodeError> for TrackerError {
+    fn from(err: DecodeError) -> Self {
+        TrackerError::Decode(err)
+    }
+}
+
+impl fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrackerError::Io(kind) => write!(f, "{}", kind),
+            TrackerError::Decode(err) => write!(f, "bad bencode in the response: {}", err),
+            TrackerError::InvalidUrl => write!(f, "invalid URL"),
+            TrackerError::UnsupportedScheme(scheme) => {
+                write!(f, "unsupported scheme {}://", scheme)
+            }
+            TrackerError::InvalidResponse(what) => write!(f, "bad response: {}", what),
+            TrackerError::Failure(reason) => write!(f, "tracker failure: {}", reason),
+            TrackerError::ScrapeUnsupported => write!(f, "the tracker doesn't do scrapes"),
+        }
+    }
+}
+
+impl std::error::Error for TrackerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TrackerError::Decode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum AnnounceEvent {
+    // A regular re-announce
+    #[default]
+    None,
+    Started,
+    Completed,
+    Stopped,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct AnnounceRequest {
+    pub info_hash: InfoHash,
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: AnnounceEvent,
+    // None lets the tracker pick, usually 50
+    pub num_want: Option<u32>,
+    // Random per-session value so the tracker can tell us apart when our IP changes
+    pub key: u32,
+    // Our addresses in the other family (BEP 7). A tracker only sees the one we connect over,
+    // so this is how dual-stack peers get listed under both
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct AnnounceResponse {
+    pub interval: Duration,
+    pub min_interval: Option<Duration>,
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+    pub peers: Vec<SocketAddr>,
+    // HTTP trackers may hand this out to be echoed back on the next announce
+    pub tracker_id: Option<String>,
+    // HTTP only. Something the user should see, though the announce went through
+    pub warning: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct ScrapeStats {
+    pub seeders: u32,
+    // Times the torrent has been downloaded to completion
+    pub completed: u32,
+    pub leechers: u32,
+}
+
+pub fn announce(
+    url: &str,
+    request: &AnnounceRequest,
+    timeout: Duration,
+) -> Result<AnnounceResponse, TrackerError> {
+    announce_via(url, request, None, timeout)
+}
+
+pub fn scrape(
+    url: &str,
+    info_hashes: &[InfoHash],
+    timeout: Duration,
+) -> Result<HashMap<InfoHash, ScrapeStats>, TrackerError> {
+    scrape_via(url, info_hashes, None, timeout)
+}
+
+// Like `announce`, through a SOCKS5 proxy if one is given
+pub fn announce_via(
+    url: &str,
+    request: &AnnounceRequest,
+    proxy: Option<&ProxyConfig>,
+    timeout: Duration,
+) -> Result<AnnounceResponse, TrackerError> {
+    match Url::parse(url)?.scheme {
+        "http" => http::announce(url, request, proxy, timeout),
+        "udp" => udp::announce(url, request, proxy, timeout),
+        scheme => Err(TrackerError::UnsupportedScheme(scheme.to_string())),
+    }
+}
+
+pub fn scrape_via(
+    url: &str,
+    info_hashes: &[InfoHash],
+    proxy: Option<&ProxyConfig>,
+    timeout: Duration,
+) -> Result<HashMap<InfoHash, ScrapeStats>, TrackerError> {
+    match Url::parse(url)?.scheme {
+        "http" => http::scrape(url, info_hashes, proxy, timeout),
+        "udp" => udp::scrape(url, info_hashes, proxy, timeout),
+        scheme => Err(TrackerError::UnsupportedScheme(scheme.to_string())),
+    }
+}
+
+// Just enough URL parsing for tracker URLs: scheme://host[:port][/path][?query]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub(crate) struct Url<'a> {
+    pub scheme: &'a str,
+    pub host: &'a str,
+    pub port: Option<u16>,
+    // Everything from the first `/`, query included
+    pub path: &'a str,
+}
+
+impl<'a> Url<'a> {
+    pub fn parse(url: &'a str) -> Result<Url<'a>, TrackerError> {
+        let (scheme, rest) = url.split_once("://").ok_or(TrackerError::InvalidUrl)?;
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(at) => rest.split_at(at),
+            None => (rest, ""),
+        };
+        // IPv6 literals are bracketed, so only a colon after the closing bracket starts a port
+        let port_at = authority
+            .rfind(':')
+            .filter(|at| !authority[*at..].contains(']'));
+        let (host, port) = match port_at {
+            Some(at) => {
+                let port = authority[at + 1..]
+                    .parse()
+                    .map_err(|_| TrackerError::InvalidUrl)?;
+                (&authority[..at], Some(port))
+            }
+            None => (authority, None),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(TrackerError::InvalidUrl);
+        }
+
+        Ok(Url {
+            scheme,
+            host,
+            port,
+            path,
+        })
+    }
+
+    // Every address the host has, through `dns`'s cache
+    pub fn resolve(&self, default_port: u16) -> Result<Vec<SocketAddr>, TrackerError> {
+        Ok(dns::resolve(self.host, self.port.unwrap_or(default_port))?)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        let url = Url::parse("http://tracker.example.com:6969/announce?passkey=x").unwrap();
+        assert_eq!(url.scheme, "http");
+        assert_eq!(url.host, "tracker.example.com");
+        assert_eq!(url.port, Some(6969));
+        assert_eq!(url.path, "/announce?passkey=x");
+
+        let url = Url::parse("udp://[2001:db8::1]:80").unwrap();
+        assert_eq!(url.host, "2001:db8::1");
+        assert_eq!(url.port, Some(80));
+        assert_eq!(url.path, "");
+    }
+
+    #[test]
+    fn test_bad_urls() {
+        assert_eq!(Url::parse("tracker:80"), Err(TrackerError::InvalidUrl));
+        assert_eq!(Url::parse("udp://host:x/"), Err(TrackerError::InvalidUrl));
+        assert_eq!(
+            scrape("wss://tracker/announce", &[], Duration::from_secs(1)),
+            Err(TrackerError::UnsupportedScheme("wss".to_string()))
+        );
+    }
+}
@@ -0,0 +1,354 @@
+// UDP trackers (BEP 15). Every exchange needs a connection ID first, which is just the tracker
+// proving we can receive at our address. Packets are fixed big-endian layouts, each tagged with
+// an action and a transaction ID we pick and check on the way back.
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::time::Duration;
+
+use super::{AnnounceEvent, AnnounceRequest, AnnounceResponse, ScrapeStats, TrackerError, Url};
+use crate::compact::CompactPeers;
+use crate::dns;
+use crate::infohash::InfoHash;
+use crate::proxy::{ProxyConfig, Target, UdpRelay, udp_socket_for};
+use crate::rng::Rng;
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+pub const ACTION_CONNECT: u32 = 0;
+pub const ACTION_ANNOUNCE: u32 = 1;
+pub const ACTION_SCRAPE: u32 = 2;
+pub const ACTION_ERROR: u32 = 3;
+
+// Scrapes with more info-hashes than this don't fit in a packet
+pub const MAX_SCRAPE: usize = 74;
+
+// Packets are lost all the time, so each request gets this many tries
+const ATTEMPTS: usize = 2;
+
+pub fn connect_request(tid: u32) -> Vec<u8> {
+    let mut buf = PROTOCOL_ID.to_be_bytes().to_vec();
+    buf.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+    buf.extend_from_slice(&tid.to_be_bytes());
+    buf
+}
+
+pub fn announce_request(connection_id: u64, tid: u32, request: &AnnounceRequest) -> Vec<u8> {
+    let event: u32 = match request.event {
+        AnnounceEvent::None => 0,
+        AnnounceEvent::Completed => 1,
+        AnnounceEvent::Started => 2,
+        AnnounceEvent::Stopped => 3,
+    };
+    let num_want = request
+        .num_want
+        .map_or(-1, |n| n.min(i32::MAX as u32) as i32);
+
+    let mut buf = header(connection_id, ACTION_ANNOUNCE, tid);
+    buf.extend_from_slice(&request.info_hash.0);
+    buf.extend_from_slice(&request.peer_id);
+    buf.extend_from_slice(&request.downloaded.to_be_bytes());
+    buf.extend_from_slice(&request.left.to_be_bytes());
+    buf.extend_from_slice(&request.uploaded.to_be_bytes());
+    buf.extend_from_slice(&event.to_be_bytes());
+    // IP: 0 means the address the packet came from
+    buf.extend_from_slice(&0u32.to_be_bytes());
+    buf.extend_from_slice(&request.key.to_be_bytes());
+    buf.extend_from_slice(&num_want.to_be_bytes());
+    buf.extend_from_slice(&request.port.to_be_bytes());
+    buf
+}
+
+pub fn scrape_request(connection_id: u64, tid: u32, info_hashes: &[InfoHash]) -> Vec<u8> {
+    let mut buf = header(connection_id, ACTION_SCRAPE, tid);
+    for info_hash in info_hashes.iter().take(MAX_SCRAPE) {
+        buf.extend_from_slice(&info_hash.0);
+    }
+    buf
+}
+
+// Checks the action and transaction ID and returns the rest of the packet
+pub fn parse_response(buf: &[u8], action: u32, tid: u32) -> Result<&[u8], TrackerError> {
+    if buf.len() < 8 {
+        return Err(TrackerError::InvalidResponse("too short"));
+    }
+    if read_u32(buf, 4) != tid {
+        return Err(TrackerError::InvalidResponse("transaction ID"));
+    }
+    match read_u32(buf, 0) {
+        ACTION_ERROR => Err(TrackerError::Failure(
+            String::from_utf8_lossy(&buf[8..]).into_owned(),
+        )),
+        got if got == action => Ok(&buf[8..]),
+        _ => Err(TrackerError::InvalidResponse("action")),
+    }
+}
+
+// `ipv6` is whether we talked to the tracker over IPv6, which decides the peer format
+pub fn parse_announce(payload: &[u8], ipv6: bool) -> Result<AnnounceResponse, TrackerError> {
+    if payload.len() < 12 {
+        return Err(TrackerError::InvalidResponse("too short"));
+    }
+    let peers = &payload[12..];
+    let peers = if ipv6 {
+        CompactPeers::parse::<SocketAddrV6>(peers)
+            .map(|peers| peers.into_iter().map(SocketAddr::V6).collect())
+    } else {
+        CompactPeers::parse::<SocketAddrV4>(peers)
+            .map(|peers| peers.into_iter().map(SocketAddr::V4).collect())
+    };
+
+    Ok(AnnounceResponse {
+        interval: Duration::from_secs(read_u32(payload, 0) as u64),
+        min_interval: None,
+        leechers: Some(read_u32(payload, 4)),
+        seeders: Some(read_u32(payload, 8)),
+        peers: peers.map_err(|_| TrackerError::InvalidResponse("peers"))?,
+        tracker_id: None,
+        warning: None,
+    })
+}
+
+pub fn parse_scrape(
+    payload: &[u8],
+    info_hashes: &[InfoHash],
+) -> Result<HashMap<InfoHash, ScrapeStats>, TrackerError> {
+    if !payload.len().is_multiple_of(12) {
+        return Err(TrackerError::InvalidResponse("scrape length"));
+    }
+    // Answers come back in the order we asked
+    Ok(info_hashes
+        .iter()
+        .zip(payload.chunks_exact(12))
+        .map(|(info_hash, stats)| {
+            let stats = ScrapeStats {
+                seeders: read_u32(stats, 0),
+                completed: read_u32(stats, 4),
+                leechers: read_u32(stats, 8),
+            };
+            (*info_hash, stats)
+        })
+        .collect())
+}
+
+pub fn announce(
+    url: &str,
+    request: &AnnounceRequest,
+    proxy: Option<&ProxyConfig>,
+    timeout: Duration,
+) -> Result<AnnounceResponse, TrackerError> {
+    let mut tracker = Tracker::connect(url, proxy, timeout)?;
+    let tid = tracker.rng.next_u64() as u32;
+    let packet = announce_request(tracker.connection_id, tid, request);
+    let response = tracker.exchange(&packet, ACTION_ANNOUNCE, tid)?;
+    parse_announce(&response, tracker.ipv6)
+}
+
+pub fn scrape(
+    url: &str,
+    info_hashes: &[InfoHash],
+    proxy: Option<&ProxyConfig>,
+    timeout: Duration,
+) -> Result<HashMap<InfoHash, ScrapeStats>, TrackerError> {
+    let mut tracker = Tracker::connect(url, proxy, timeout)?;
+    let mut stats = HashMap::new();
+    for chunk in info_hashes.chunks(MAX_SCRAPE) {
+        let tid = tracker.rng.next_u64() as u32;
+        let packet = scrape_request(tracker.connection_id, tid, chunk);
+        let response = tracker.exchange(&packet, ACTION_SCRAPE, tid)?;
+        stats.extend(parse_scrape(&response, chunk)?);
+    }
+    Ok(stats)
+}
+
+struct Tracker {
+    socket: UdpSocket,
+    // Through a proxy the tracker goes by name, for the proxy to resolve
+    target: Target,
+    relay: Option<UdpRelay>,
+    // Decides the peer list format. Through a proxy we only learn it from the first reply
+    ipv6: bool,
+    connection_id: u64,
+    rng: Rng,
+    buf: Vec<u8>,
+}
+
+impl Tracker {
+    fn connect(
+        url: &str,
+        proxy: Option<&ProxyConfig>,
+        timeout: Duration,
+    ) -> Result<Self, TrackerError> {
+        let parsed = Url::parse(url)?;
+        let relayed = match proxy {
+            Some(proxy) => {
+                let socket = udp_socket_for(proxy)?;
+                match proxy.associate(&socket, timeout) {
+                    Ok(relay) => Some((socket, relay)),
+                    Err(_) if proxy.allows_direct() => None,
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            None => None,
+        };
+        let (socket, relay) = match relayed {
+            Some(relayed) => relayed,
+            None => {
+                let addrs = parsed.resolve(80)?;
+                return dns::race(&addrs, move |addr| Tracker::direct(addr, timeout));
+            }
+        };
+        socket.set_read_timeout(Some(timeout))?;
+        let target = Target::Domain(parsed.host.to_string(), parsed.port.unwrap_or(80));
+        Tracker::new(socket, target, Some(relay)).handshake()
+    }
+
+    // Straight to the tracker at `addr`, one of the host's addresses racing the others
+    fn direct(addr: SocketAddr, timeout: Duration) -> Result<Self, TrackerError> {
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        socket.set_read_timeout(Some(timeout))?;
+        Tracker::new(socket, Target::Addr(addr), None).handshake()
+    }
+
+    fn new(socket: UdpSocket, target: Target, relay: Option<UdpRelay>) -> Self {
+        Tracker {
+            socket,
+            ipv6: matches!(target, Target::Addr(SocketAddr::V6(_))),
+            target,
+            relay,
+            connection_id: 0,
+            rng: Rng::new(),
+            buf: vec![0; 2048],
+        }
+    }
+
+    // Gets the connection ID everything else needs
+    fn handshake(mut self) -> Result<Self, TrackerError> {
+        let tid = self.rng.next_u64() as u32;
+        let response = self.exchange(&connect_request(tid), ACTION_CONNECT, tid)?;
+        if response.len() < 8 {
+            return Err(TrackerError::InvalidResponse("too short"));
+        }
+        self.connection_id = u64::from_be_bytes(response[..8].try_into().unwrap());
+        Ok(self)
+    }
+
+    fn exchange(&mut self, packet: &[u8], action: u32, tid: u32) -> Result<Vec<u8>, TrackerError> {
+        let mut last_err = TrackerError::Io(std::io::ErrorKind::TimedOut);
+        for _ in 0..ATTEMPTS {
+            match &self.relay {
+                Some(relay) => relay.send_to(&self.socket, packet, &self.target)?,
+                None => self.socket.send(packet)?,
+            };
+            match self.recv() {
+                Ok(payload) => return parse_response(&payload, action, tid).map(<[u8]>::to_vec),
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+
+    fn recv(&mut self) -> Result<Vec<u8>, TrackerError> {
+        let Some(relay) = &self.relay else {
+            let len = self.socket.recv(&mut self.buf)?;
+            return Ok(self.buf[..len].to_vec());
+        };
+        let (len, from) = self.socket.recv_from(&mut self.buf)?;
+        let (tracker, payload) = relay
+            .unwrap(from, &self.buf[..len])
+            .ok_or(TrackerError::InvalidResponse("not from the proxy relay"))?;
+        self.ipv6 = tracker.is_ipv6();
+        Ok(payload.to_vec())
+    }
+}
+
+fn header(connection_id: u64, action: u32, tid: u32) -> Vec<u8> {
+    let mut buf = connection_id.to_be_bytes().to_vec();
+    buf.extend_from_slice(&action.to_be_bytes());
+    buf.extend_from_slice(&tid.to_be_bytes());
+    buf
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_announce_request_layout() {
+        let request = AnnounceRequest {
+            info_hash: InfoHash([1; 20]),
+            peer_id: [2; 20],
+            port: 6881,
+            uploaded: 3,
+            downloaded: 4,
+            left: 5,
+            event: AnnounceEvent::Started,
+            num_want: None,
+            key: 9,
+            ipv4: None,
+            ipv6: None,
+        };
+        let buf = announce_request(0x1122, 7, &request);
+
+        assert_eq!(buf.len(), 98);
+        assert_eq!(read_u32(&buf, 8), ACTION_ANNOUNCE);
+        assert_eq!(read_u32(&buf, 12), 7);
+        assert_eq!(read_u32(&buf, 80), 2);
+        assert_eq!(&buf[92..96], &[0xff; 4]);
+        assert_eq!(&buf[96..], &6881u16.to_be_bytes());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let mut buf = ACTION_ANNOUNCE.to_be_bytes().to_vec();
+        buf.extend_from_slice(&5u32.to_be_bytes());
+        for n in [1800u32, 2, 10] {
+            buf.extend_from_slice(&n.to_be_bytes());
+        }
+        buf.extend_from_slice(&[1, 2, 3, 4, 0x1a, 0xe1]);
+
+        let response = parse_announce(parse_response(&buf, ACTION_ANNOUNCE, 5).unwrap(), false);
+        let response = response.unwrap();
+        assert_eq!(response.interval, Duration::from_secs(1800));
+        assert_eq!(response.seeders, Some(10));
+        assert_eq!(response.peers, vec!["1.2.3.4:6881".parse().unwrap()]);
+        assert_eq!(
+            parse_response(&buf, ACTION_ANNOUNCE, 6),
+            Err(TrackerError::InvalidResponse("transaction ID"))
+        );
+    }
+
+    #[test]
+    fn test_error_response() {
+        let mut buf = ACTION_ERROR.to_be_bytes().to_vec();
+        buf.extend_from_slice(&5u32.to_be_bytes());
+        buf.extend_from_slice(b"unregistered torrent");
+
+        assert_eq!(
+            parse_response(&buf, ACTION_SCRAPE, 5),
+            Err(TrackerError::Failure("unregistered torrent".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_scrape() {
+        let hashes = [InfoHash([1; 20]), InfoHash([2; 20])];
+        let payload: Vec<u8> = [4u32, 5, 6, 7, 8, 9]
+            .iter()
+            .flat_map(|n| n.to_be_bytes())
+            .collect();
+        let stats = parse_scrape(&payload, &hashes).unwrap();
+
+        assert_eq!(stats[&hashes[1]].seeders, 7);
+        assert_eq!(stats[&hashes[1]].leechers, 9);
+        assert_eq!(scrape_request(1, 2, &hashes).len(), 16 + 40);
+    }
+}
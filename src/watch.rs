@@ -0,0 +1,202 @@
+// Watch directories: .torrent files, and .magnet files holding a magnet link, dropped into one are
+// added to the session with that directory's defaults (save path, label, paused, ...). Each file
+// is then renamed, moved or deleted, so it's only ever added once. Files that don't parse are
+// renamed to `<name>.invalid` whatever the directory says, for the same reason.
+// There's no portable way to be told about new files, so directories are polled with `scan`. A
+// file is only taken once it's the same size as on the scan before, so one that's still being
+// written or downloaded into the directory isn't read half way.
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::session::{AddDefaults, Imported, Session};
+
+// What becomes of a file once its torrent is added
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub enum Consumed {
+    // To `<name>.added`, next to where it was
+    #[default]
+    Rename,
+    MoveTo(PathBuf),
+    Delete,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct WatchDir {
+    pub path: PathBuf,
+    pub defaults: AddDefaults,
+    pub consumed: Consumed,
+}
+
+impl WatchDir {
+    pub fn new(path: PathBuf) -> Self {
+        WatchDir {
+            path,
+            defaults: AddDefaults::default(),
+            consumed: Consumed::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Watcher {
+    dirs: Vec<WatchDir>,
+    // Size of each candidate file as of the last scan
+    seen: HashMap<PathBuf, u64>,
+}
+
+impl Watcher {
+    pub fn new(dirs: Vec<WatchDir>) -> Self {
+        Watcher {
+            dirs,
+            seen: HashMap::new(),
+        }
+    }
+
+    pub fn dirs(&self) -> &[WatchDir] {
+        &self.dirs
+    }
+
+    pub fn set_dirs(&mut self, dirs: Vec<WatchDir>) {
+        self.dirs = dirs;
+        self.seen.clear();
+    }
+
+    // Call every few seconds. Adds whatever's ready, in name order per directory. A directory
+    // that's missing or unreadable is skipped until it's back
+    pub fn scan(&mut self, session: &mut Session, now: Instant) -> Vec<Imported> {
+        let mut imported = vec![];
+        let mut seen = HashMap::new();
+        for dir in &self.dirs {
+            let Ok(mut paths) = candidates(&dir.path) else {
+                continue;
+            };
+            paths.sort();
+            for (path, len) in paths {
+                if self.seen.get(&path) != Some(&len) {
+                    seen.insert(path, len);
+                    continue;
+                }
+                let result = session.add_file(&path, &dir.defaults, now);
+                let consumed = match &result.result {
+                    Ok(_) => consume(&path, &dir.consumed),
+                    Err(_) => fs::rename(&path, with_suffix(&path, ".invalid")),
+                };
+                // Looked at again next time, and added again (merged, so harmlessly) if it's
+                // still there
+                if consumed.is_err() {
+                    seen.insert(path, len);
+                }
+                imported.push(result);
+            }
+        }
+        self.seen = seen;
+        imported
+    }
+}
+
+// .torrent and .magnet files in `dir`, not its subdirectories, with their sizes
+fn candidates(dir: &Path) -> io::Result<Vec<(PathBuf, u64)>> {
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let wanted = path.extension().is_some_and(|ext| {
+            ext.eq_ignore_ascii_case("torrent") || ext.eq_ignore_ascii_case("magnet")
+        });
+        match entry.metadata() {
+            Ok(metadata) if wanted && metadata.is_file() => paths.push((path, metadata.len())),
+            _ => {}
+        }
+    }
+    Ok(paths)
+}
+
+fn consume(path: &Path, consumed: &Consumed) -> io::Result<()> {
+    match consumed {
+        Consumed::Rename => fs::rename(path, with_suffix(path, ".added")),
+        Consumed::MoveTo(dir) => {
+            fs::create_dir_all(dir)?;
+            fs::rename(path, dir.join(path.file_name().unwrap()))
+        }
+        Consumed::Delete => fs::remove_file(path),
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    name.into()
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::infohash::InfoHash;
+    use crate::metainfo::Metainfo;
+    use crate::session::{Added, SessionError, TorrentHandle};
+    use crate::torrent::TorrentStatus;
+
+    #[test]
+    fn test_scan() {
+        let dir = std::env::temp_dir().join(format!("hurricane-watch-{}", std::process::id()));
+        let (inbox, paused) = (dir.join("inbox"), dir.join("paused"));
+        fs::create_dir_all(&inbox).unwrap();
+        fs::create_dir_all(&paused).unwrap();
+        let buf = include_bytes!("../bencode/tests/fixtures/sample.torrent");
+        fs::write(inbox.join("a.torrent"), buf).unwrap();
+        fs::write(inbox.join("b.torrent"), b"not bencode").unwrap();
+        fs::write(inbox.join("notes.txt"), b"").unwrap();
+        let magnet = format!("magnet:?xt=urn:btih:{}\n", InfoHash([7; 20]).to_hex());
+        fs::write(paused.join("c.magnet"), &magnet).unwrap();
+
+        let mut watch_paused = WatchDir::new(paused.clone());
+        watch_paused.defaults.paused = true;
+        watch_paused.defaults.label = Some("later".to_string());
+        watch_paused.consumed = Consumed::MoveTo(dir.join("done"));
+        let mut watcher = Watcher::new(vec![
+            WatchDir::new(inbox.clone()),
+            watch_paused,
+            WatchDir::new(dir.join("missing")),
+        ]);
+        let mut session = Session::new();
+        let now = Instant::now();
+
+        // Seen once, not taken until it's the same size on the next scan
+        assert!(watcher.scan(&mut session, now).is_empty());
+        let invalid =
+            SessionError::InvalidTorrent(Metainfo::from_bytes(b"not bencode").unwrap_err());
+        let results: Vec<_> = watcher
+            .scan(&mut session, now)
+            .into_iter()
+            .map(|i| (i.path.file_name().unwrap().to_owned(), i.result))
+            .collect();
+        assert_eq!(
+            results,
+            [
+                ("a.torrent".into(), Ok(Added::New(TorrentHandle(0)))),
+                ("b.torrent".into(), Err(invalid)),
+                ("c.magnet".into(), Ok(Added::New(TorrentHandle(1)))),
+            ]
+        );
+        assert!(inbox.join("a.torrent.added").exists());
+        assert!(inbox.join("b.torrent.invalid").exists());
+        assert!(dir.join("done/c.magnet").exists());
+        let torrent = session.get(TorrentHandle(1)).unwrap();
+        assert_eq!(torrent.status(), TorrentStatus::Paused);
+        assert_eq!(torrent.label(), Some("later"));
+        drop(torrent);
+
+        // A file still being written waits for the next scan
+        fs::write(inbox.join("d.torrent"), &buf[..10]).unwrap();
+        assert!(watcher.scan(&mut session, now).is_empty());
+        fs::write(inbox.join("d.torrent"), buf).unwrap();
+        assert!(watcher.scan(&mut session, now).is_empty());
+        let imported = watcher.scan(&mut session, now);
+        assert_eq!(imported[0].result, Ok(Added::Merged(TorrentHandle(0))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
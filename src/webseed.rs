@@ -0,0 +1,557 @@
+// HTTP web seeds (BEP 19). A web seed is a plain web server holding the torrent's files, listed
+// in the torrent's `url-list`. Blocks are fetched with range requests, split at file boundaries
+// the same way `Storage` lays pieces over files. Seeds share the piece picker with peers through
+// `PiecePicker::pick_web_seed`; seeds that fail or crawl get backed off and eventually dropped.
+// Corrupt data is handled separately from plain failures: CDNs truncate and mangle responses now
+// and then, so each seed has a budget of bad pieces before it's dropped for good.
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use crate::disk::Storage;
+use crate::dns;
+use crate::metainfo::Info;
+use crate::peer::Block;
+use crate::tracker::{TrackerError, Url, http::url_encode};
+
+// Consecutive failures before we give up on a seed for good
+pub const MAX_FAILURES: u32 = 5;
+
+// A seed delivering slower than this (bytes per second) is costing us more in occupied pieces
+// than it's worth, and gets backed off like a failure
+pub const MIN_RATE: f64 = 8.0 * 1024.0;
+
+// Backoff after the first failure. Doubles with each one after that
+const RETRY_BASE: Duration = Duration::from_secs(30);
+
+// Headers bigger than this are not from a server we want to talk to
+const MAX_HEADERS: u64 = 64 * 1024;
+
+// Redirects are common (mirrors, CDNs), loops aren't worth chasing
+const MAX_REDIRECTS: usize = 3;
+
+// A response cut short is picked up where it stopped, on a new connection, this many times
+const MAX_RESUMES: usize = 2;
+
+#[derive(Debug, Clone)]
+pub struct WebSeedConfig {
+    // Pieces from a seed that may fail their hash check before the seed is dropped
+    pub hash_failure_budget: u32,
+    // Each this many verified pieces from a seed earn back one failure from its budget
+    pub pieces_per_refund: u32,
+    // Times a seed gets to fetch a piece again after sending it corrupt. Past that the piece
+    // goes back to peers and other seeds
+    pub piece_retries: u32,
+}
+
+impl Default for WebSeedConfig {
+    fn default() -> Self {
+        WebSeedConfig {
+            hash_failure_budget: 5,
+            pieces_per_refund: 20,
+            piece_retries: 1,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum WebSeedError {
+    Io(io::ErrorKind),
+    InvalidUrl,
+    UnsupportedScheme(String),
+    // Anything but 206, or 200 for a range starting at 0
+    Status(u16),
+    InvalidResponse(&'static str),
+}
+
+impl WebSeedError {
+    // Errors that won't go away by asking again later
+    fn is_permanent(&self) -> bool {
+        match self {
+            WebSeedError::InvalidUrl | WebSeedError::UnsupportedScheme(_) => true,
+            WebSeedError::Status(status) => matches!(status, 404 | 410),
+            _ => false,
+        }
+    }
+}
+
+impl From<io::Error> for WebSeedError {
+    fn from(err: io::Error) -> Self {
+        WebSeedError::Io(err.kind())
+    }
+}
+
+impl From<TrackerError> for WebSeedError {
+    fn from(err: TrackerError) -> Self {
+        match err {
+            TrackerError::Io(kind) => WebSeedError::Io(kind),
+            _ => WebSeedError::InvalidUrl,
+        }
+    }
+}
+
+// One HTTP request: `len` bytes of a file starting at `start`
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct RangeRequest {
+    pub url: String,
+    pub start: u64,
+    pub len: u64,
+}
+
+// Where a file of the torrent lives on a seed. A URL ending in `/` is a directory the torrent's
+// name goes in; otherwise it's the file itself for single-file torrents and the torrent's
+// directory for multi-file ones
+pub fn file_url(base: &str, info: &Info, file: usize) -> String {
+    let path = &info.files[file].path;
+    // Multi-file paths start with the torrent's name, which the URL already stands for
+    let components = match (path.len() > 1, base.ends_with('/')) {
+        (false, false) => return base.to_string(),
+        (false, true) | (true, true) => &path[..],
+        (true, false) => &path[1..],
+    };
+
+    let mut url = base.trim_end_matches('/').to_string();
+    for component in components {
+        url.push('/');
+        url.push_str(&url_encode(component.as_bytes()));
+    }
+    url
+}
+
+#[derive(Debug, Clone)]
+pub struct WebSeed {
+    url: String,
+    file_urls: Vec<String>,
+    failures: u32,
+    retry_at: Option<Instant>,
+    busy: bool,
+    disabled: bool,
+    // What's left of the hash failure budget, and progress towards the next refund
+    budget: u32,
+    verified: u32,
+    // Pieces this seed sent corrupt, and how many times it has refetched each
+    retries: HashMap<u32, u32>,
+}
+
+impl WebSeed {
+    pub fn new(url: &str, info: &Info) -> Self {
+        WebSeed {
+            url: url.to_string(),
+            file_urls: (0..info.files.len())
+                .map(|file| file_url(url, info, file))
+                .collect(),
+            failures: 0,
+            retry_at: None,
+            busy: false,
+            disabled: false,
+            budget: 0,
+            verified: 0,
+            retries: HashMap::new(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.disabled
+    }
+
+    // Consecutive blocks are merged, so a run from `pick_web_seed` turns into one request per
+    // file it touches
+    pub fn requests(&self, storage: &Storage, blocks: &[Block]) -> Vec<RangeRequest> {
+        let mut runs: Vec<(Block, u64)> = vec![];
+        for block in blocks {
+            let start = storage.offset_of(block.piece, block.offset);
+            match runs.last_mut() {
+                Some((run, end)) if *end == start => {
+                    run.length += block.length;
+                    *end += block.length as u64;
+                }
+                _ => runs.push((*block, start + block.length as u64)),
+            }
+        }
+
+        let mut requests: Vec<RangeRequest> = vec![];
+        for (run, _) in runs {
+            for slice in storage.slices(run.piece, run.offset, run.length) {
+                let url = &self.file_urls[slice.file];
+                match requests.last_mut() {
+                    // Back to back runs in the same file
+                    Some(last) if last.url == *url && last.start + last.len == slice.offset => {
+                        last.len += slice.len;
+                    }
+                    _ => requests.push(RangeRequest {
+                        url: url.clone(),
+                        start: slice.offset,
+                        len: slice.len,
+                    }),
+                }
+            }
+        }
+        requests
+    }
+}
+
+// All the web seeds of one torrent. Each seed handles one batch at a time
+#[derive(Debug, Clone, Default)]
+pub struct WebSeeds {
+    config: WebSeedConfig,
+    seeds: Vec<WebSeed>,
+}
+
+impl WebSeeds {
+    pub fn new(urls: &[String], info: &Info) -> Self {
+        WebSeeds::with_config(urls, info, WebSeedConfig::default())
+    }
+
+    pub fn with_config(urls: &[String], info: &Info, config: WebSeedConfig) -> Self {
+        let seeds = urls
+            .iter()
+            .map(|url| WebSeed {
+                budget: config.hash_failure_budget,
+                ..WebSeed::new(url, info)
+            })
+            .collect();
+        WebSeeds { config, seeds }
+    }
+
+    pub fn len(&self) -> usize {
+        self.seeds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seeds.is_empty()
+    }
+
+    pub fn get(&self, seed: usize) -> &WebSeed {
+        &self.seeds[seed]
+    }
+
+    // A seed that's free to take a batch, now marked busy until `on_success` or `on_failure`
+    pub fn next_ready(&mut self, now: Instant) -> Option<usize> {
+        let seed = self.seeds.iter().position(|seed| {
+            !seed.busy && !seed.disabled && seed.retry_at.is_none_or(|at| at <= now)
+        })?;
+        self.seeds[seed].busy = true;
+        Some(seed)
+    }
+
+    // Hands back a seed `next_ready` gave us when there turned out to be nothing for it
+    pub fn release(&mut self, seed: usize) {
+        self.seeds[seed].busy = false;
+    }
+
+    // Returns false if the batch came in too slowly, in which case the seed is backed off and
+    // the caller may want to hand its next batch to peers instead
+    pub fn on_success(&mut self, seed: usize, bytes: u64, elapsed: Duration, now: Instant) -> bool {
+        let rate = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+        if rate < MIN_RATE {
+            self.back_off(seed, now);
+            return false;
+        }
+        let seed = &mut self.seeds[seed];
+        seed.busy = false;
+        seed.failures = 0;
+        seed.retry_at = None;
+        true
+    }
+
+    // The batch's blocks should go back to the picker with `abort_request`
+    pub fn on_failure(&mut self, seed: usize, err: &WebSeedError, now: Instant) {
+        if err.is_permanent() {
+            self.seeds[seed].busy = false;
+            self.seeds[seed].disabled = true;
+        } else {
+            self.back_off(seed, now);
+        }
+    }
+
+    // A piece from this seed passed its hash check
+    pub fn on_piece_verified(&mut self, seed: usize, piece: u32) {
+        let seed = &mut self.seeds[seed];
+        seed.retries.remove(&piece);
+        seed.verified += 1;
+        if seed.verified >= self.config.pieces_per_refund {
+            seed.verified = 0;
+            seed.budget = (seed.budget + 1).min(self.config.hash_failure_budget);
+        }
+    }
+
+    // A piece from this seed failed its hash check. Returns true if the seed should fetch it
+    // again itself, which happens on a new connection anyway. Otherwise the piece is up for
+    // grabs (`PiecePicker::piece_failed`), and the seed may have run out of budget and been
+    // dropped
+    pub fn on_hash_failure(&mut self, seed: usize, piece: u32) -> bool {
+        let piece_retries = self.config.piece_retries;
+        let seed = &mut self.seeds[seed];
+        seed.verified = 0;
+        if seed.budget == 0 {
+            seed.disabled = true;
+            return false;
+        }
+        seed.budget -= 1;
+
+        let retries = seed.retries.entry(piece).or_insert(0);
+        if *retries >= piece_retries {
+            seed.retries.remove(&piece);
+            return false;
+        }
+        *retries += 1;
+        true
+    }
+
+    fn back_off(&mut self, seed: usize, now: Instant) {
+        let seed = &mut self.seeds[seed];
+        seed.busy = false;
+        seed.failures += 1;
+        seed.disabled = seed.failures >= MAX_FAILURES;
+        seed.retry_at = Some(now + RETRY_BASE * (1 << (seed.failures - 1).min(5)));
+    }
+}
+
+// Fetches a batch, returning the data for its blocks back to back
+pub fn fetch_all(requests: &[RangeRequest], timeout: Duration) -> Result<Vec<u8>, WebSeedError> {
+    let mut data = vec![];
+    for request in requests {
+        data.extend(fetch(request, timeout)?);
+    }
+    Ok(data)
+}
+
+// Truncated responses are resumed from where they stopped before giving up
+pub fn fetch(request: &RangeRequest, timeout: Duration) -> Result<Vec<u8>, WebSeedError> {
+    let mut data = Vec::with_capacity(request.len as usize);
+    for _ in 0..=MAX_RESUMES {
+        let rest = RangeRequest {
+            url: request.url.clone(),
+            start: request.start + data.len() as u64,
+            len: request.len - data.len() as u64,
+        };
+        data.extend(fetch_once(&rest, timeout)?);
+        if data.len() as u64 == request.len {
+            return Ok(data);
+        }
+    }
+    Err(WebSeedError::InvalidResponse("short body"))
+}
+
+// HTTP/1.0 like the tracker client, so the body is never chunked and ends when the server
+// closes. Returns at most `request.len` bytes, fewer if the response was cut short
+fn fetch_once(request: &RangeRequest, timeout: Duration) -> Result<Vec<u8>, WebSeedError> {
+    let mut url = request.url.clone();
+    for _ in 0..=MAX_REDIRECTS {
+        let parsed = Url::parse(&url)?;
+        if parsed.scheme != "http" {
+            return Err(WebSeedError::UnsupportedScheme(parsed.scheme.to_string()));
+        }
+        let mut stream = dns::connect(parsed.host, parsed.port.unwrap_or(80), timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let path = if parsed.path.is_empty() {
+            "/"
+        } else {
+            parsed.path
+        };
+        write!(
+            stream,
+            "GET {} HTTP/1.0\r\nHost: {}\r\nRange: bytes={}-{}\r\nUser-Agent: Hurricane/{}\r\n\r\n",
+            path,
+            parsed.host,
+            request.start,
+            request.start + request.len - 1,
+            env!("CARGO_PKG_VERSION")
+        )?;
+
+        let mut response = vec![];
+        stream
+            .take(MAX_HEADERS + request.len)
+            .read_to_end(&mut response)?;
+        match parse_response(&response, request)? {
+            Response::Data(data) => return Ok(data),
+            Response::Redirect(location) => url = location,
+        }
+    }
+    Err(WebSeedError::InvalidResponse("too many redirects"))
+}
+
+#[derive(PartialEq, Debug)]
+enum Response {
+    Data(Vec<u8>),
+    Redirect(String),
+}
+
+fn parse_response(response: &[u8], request: &RangeRequest) -> Result<Response, WebSeedError> {
+    let header_end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or(WebSeedError::InvalidResponse("no headers"))?;
+    let headers = String::from_utf8_lossy(&response[..header_end]);
+    let mut lines = headers.split("\r\n");
+    let status: u16 = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or(WebSeedError::InvalidResponse("no status"))?;
+    let header = |name: &str| {
+        lines.clone().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.eq_ignore_ascii_case(name)
+                .then(|| value.trim().to_string())
+        })
+    };
+
+    let body = &response[header_end + 4..];
+    match status {
+        206 => {}
+        // Servers that ignore Range send the whole file, fine as long as we wanted its start
+        200 if request.start == 0 => {}
+        301 | 302 | 303 | 307 | 308 => {
+            let location = header("location").ok_or(WebSeedError::InvalidResponse("location"))?;
+            return Ok(Response::Redirect(location));
+        }
+        _ => return Err(WebSeedError::Status(status)),
+    }
+    let len = body.len().min(request.len as usize);
+    Ok(Response::Data(body[..len].to_vec()))
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::metainfo::FileEntry;
+
+    fn info(name: &str, files: &[(&str, u64)]) -> Info {
+        let total: u64 = files.iter().map(|(_, len)| len).sum();
+        Info {
+            name: name.to_string(),
+            piece_length: 16,
+            pieces: vec![[0; 20]; total.div_ceil(16) as usize],
+            files: files
+                .iter()
+                .map(|(path, length)| FileEntry {
+                    path: path.split('/').map(str::to_string).collect(),
+                    length: *length,
+                    pieces_root: None,
+                })
+                .collect(),
+            private: false,
+        }
+    }
+
+    #[test]
+    fn test_file_urls() {
+        let single = info("a b.iso", &[("a b.iso", 10)]);
+        let multi = info("dir", &[("dir/x/1.bin", 10), ("dir/2.bin", 10)]);
+
+        assert_eq!(file_url("http://h/a.iso", &single, 0), "http://h/a.iso");
+        assert_eq!(
+            file_url("http://h/pub/", &single, 0),
+            "http://h/pub/a%20b.iso"
+        );
+        assert_eq!(file_url("http://h/d", &multi, 0), "http://h/d/x/1.bin");
+        assert_eq!(file_url("http://h/", &multi, 1), "http://h/dir/2.bin");
+    }
+
+    #[test]
+    fn test_requests_split_at_files() {
+        let info = info("dir", &[("dir/1", 20), ("dir/2", 30)]);
+        let storage = Storage::new(&info, Path::new("/x"));
+        let seed = WebSeed::new("http://h/", &info);
+        let blocks = [Block::new(1, 0, 16), Block::new(2, 0, 16)];
+
+        assert_eq!(
+            seed.requests(&storage, &blocks),
+            vec![
+                RangeRequest {
+                    url: "http://h/dir/1".to_string(),
+                    start: 16,
+                    len: 4
+                },
+                RangeRequest {
+                    url: "http://h/dir/2".to_string(),
+                    start: 0,
+                    len: 28
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let request = RangeRequest {
+            url: "http://h/f".to_string(),
+            start: 10,
+            len: 4,
+        };
+
+        assert_eq!(
+            parse_response(b"HTTP/1.1 206 Partial\r\nA: b\r\n\r\nabcd", &request),
+            Ok(Response::Data(b"abcd".to_vec()))
+        );
+        assert_eq!(
+            parse_response(
+                b"HTTP/1.1 302 Found\r\nLocation: http://m/f\r\n\r\n",
+                &request
+            ),
+            Ok(Response::Redirect("http://m/f".to_string()))
+        );
+        assert_eq!(
+            parse_response(b"HTTP/1.1 200 OK\r\n\r\nabcdefghijklmn", &request),
+            Err(WebSeedError::Status(200))
+        );
+        assert_eq!(
+            parse_response(b"HTTP/1.1 206 Partial\r\n\r\nab", &request),
+            Ok(Response::Data(b"ab".to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_backoff() {
+        let info = info("f", &[("f", 10)]);
+        let mut seeds = WebSeeds::new(&["http://a/f".to_string(), "http://b/f".to_string()], &info);
+        let now = Instant::now();
+
+        assert_eq!(seeds.next_ready(now), Some(0));
+        assert_eq!(seeds.next_ready(now), Some(1));
+        assert_eq!(seeds.next_ready(now), None);
+        seeds.release(1);
+        assert_eq!(seeds.next_ready(now), Some(1));
+
+        seeds.on_failure(0, &WebSeedError::Io(io::ErrorKind::TimedOut), now);
+        assert!(!seeds.on_success(1, 100, Duration::from_secs(1), now));
+        assert_eq!(seeds.next_ready(now), None);
+        assert_eq!(seeds.next_ready(now + RETRY_BASE), Some(0));
+
+        seeds.on_failure(0, &WebSeedError::Status(404), now);
+        assert!(seeds.get(0).is_disabled());
+        assert_eq!(seeds.next_ready(now + RETRY_BASE * 100), Some(1));
+    }
+
+    #[test]
+    fn test_hash_failure_budget() {
+        let info = info("f", &[("f", 10)]);
+        let config = WebSeedConfig {
+            hash_failure_budget: 2,
+            pieces_per_refund: 2,
+            piece_retries: 1,
+        };
+        let mut seeds = WebSeeds::with_config(&["http://a/f".to_string()], &info, config);
+
+        // One refetch per piece, then it's someone else's problem
+        assert!(seeds.on_hash_failure(0, 7));
+        assert!(!seeds.on_hash_failure(0, 7));
+        assert!(!seeds.get(0).is_disabled());
+
+        // Verified pieces earn budget back
+        seeds.on_piece_verified(0, 1);
+        seeds.on_piece_verified(0, 2);
+        assert!(seeds.on_hash_failure(0, 3));
+        assert!(!seeds.on_hash_failure(0, 4));
+        assert!(seeds.get(0).is_disabled());
+    }
+}
@@ -0,0 +1,99 @@
+// The peer wire protocol over a WebRTC data channel. WebTorrent treats the channel as a byte
+// stream: the usual handshake and messages, cut into data channel messages wherever the sender
+// felt like it. So incoming messages are just appended to a buffer the regular decoders read
+// from, and outgoing bytes are cut to a size every browser accepts.
+use crate::peer::handshake::{HANDSHAKE_LEN, Handshake, HandshakeError};
+use crate::peer::message::{Message, MessageError};
+
+// Biggest data channel message all browsers handle without fragmentation trouble
+pub const MAX_CHANNEL_MESSAGE: usize = 16 * 1024;
+
+#[derive(Debug, Default)]
+pub struct ChannelStream {
+    inbound: Vec<u8>,
+    handshake_done: bool,
+}
+
+impl ChannelStream {
+    pub fn new() -> Self {
+        ChannelStream::default()
+    }
+
+    // A message arrived on the channel
+    pub fn push(&mut self, data: &[u8]) {
+        self.inbound.extend_from_slice(data);
+    }
+
+    // The peer's handshake, which comes before anything else
+    pub fn handshake(&mut self) -> Result<Option<Handshake>, HandshakeError> {
+        if self.handshake_done {
+            return Ok(None);
+        }
+        let Some(handshake) = Handshake::decode(&self.inbound)? else {
+            return Ok(None);
+        };
+        self.inbound.drain(..HANDSHAKE_LEN);
+        self.handshake_done = true;
+        Ok(Some(handshake))
+    }
+
+    // The next wire message, once the handshake is through
+    pub fn message(&mut self) -> Result<Option<Message>, MessageError> {
+        if !self.handshake_done {
+            return Ok(None);
+        }
+        let Some((message, len)) = Message::decode(&self.inbound)? else {
+            return Ok(None);
+        };
+        self.inbound.drain(..len);
+        Ok(Some(message))
+    }
+}
+
+// Encoded handshakes and messages, cut into data channel messages
+pub fn chunks(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    bytes.chunks(MAX_CHANNEL_MESSAGE)
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::infohash::InfoHash;
+    use crate::peer::handshake::Reserved;
+
+    #[test]
+    fn test_stream_across_channel_messages() {
+        let handshake = Handshake::new(Reserved::default(), InfoHash([1; 20]), [2; 20]);
+        let mut bytes = handshake.encode().to_vec();
+        bytes.extend(Message::Have(7).encode());
+        bytes.extend(Message::Unchoke.encode());
+
+        let mut stream = ChannelStream::new();
+        assert_eq!(stream.message(), Ok(None));
+        for chunk in bytes.chunks(5) {
+            stream.push(chunk);
+        }
+
+        assert_eq!(stream.handshake(), Ok(Some(handshake)));
+        assert_eq!(stream.handshake(), Ok(None));
+        assert_eq!(stream.message(), Ok(Some(Message::Have(7))));
+        assert_eq!(stream.message(), Ok(Some(Message::Unchoke)));
+        assert_eq!(stream.message(), Ok(None));
+    }
+
+    #[test]
+    fn test_chunks() {
+        let piece = Message::Piece {
+            piece: 0,
+            offset: 0,
+            data: vec![0; 16 * 1024],
+        }
+        .encode();
+        let sizes: Vec<usize> = chunks(&piece).map(<[u8]>::len).collect();
+
+        assert_eq!(
+            sizes,
+            vec![MAX_CHANNEL_MESSAGE, piece.len() - MAX_CHANNEL_MESSAGE]
+        );
+    }
+}
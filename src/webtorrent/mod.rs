@@ -0,0 +1,54 @@
+// WebTorrent: the swarm of browser peers, which can only talk WebRTC. Peers find each other
+// through WebSocket trackers that relay SDP offers and answers, then run the ordinary peer wire
+// protocol over a WebRTC data channel.
+// We do the parts that are BitTorrent: the tracker protocol and its signaling, WebSocket framing
+// for ws:// trackers and the wire protocol over data channel messages. The WebRTC stack itself
+// (ICE, DTLS, SCTP) is the embedder's: it turns our offer IDs into SDP offers, answers relayed
+// offers, and hands us the bytes from open channels. Like the HTTP tracker client, wss:// needs a
+// TLS stack we don't pull in, so it's up to whoever owns the socket. `webtorrent-webrtc/` at the
+// top of the repo does all of it with the `webrtc` crate, two peers in one process.
+pub mod channel;
+pub mod signal;
+pub mod tracker;
+pub mod websocket;
+
+#[derive(PartialEq, Debug)]
+pub enum WebTorrentError {
+    // Not JSON, or not the JSON we expected
+    InvalidJson,
+    MissingField(&'static str),
+    InvalidField(&'static str),
+    // The tracker's own failure reason
+    Failure(String),
+    WebSocket(&'static str),
+}
+
+impl From<serde_json::Error> for WebTorrentError {
+    fn from(_: serde_json::Error) -> Self {
+        WebTorrentError::InvalidJson
+    }
+}
+
+// WebTorrent trackers put 20 byte IDs in JSON strings one char per byte, U+0000 to U+00FF
+pub fn to_binary_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| *b as char).collect()
+}
+
+pub fn from_binary_string(s: &str) -> Option<Vec<u8>> {
+    s.chars().map(|c| u8::try_from(c as u32).ok()).collect()
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_binary_string_roundtrip() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let s = to_binary_string(&bytes);
+
+        assert_eq!(s.chars().count(), 256);
+        assert_eq!(from_binary_string(&s), Some(bytes));
+        assert_eq!(from_binary_string("\u{100}"), None);
+    }
+}
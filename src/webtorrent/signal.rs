@@ -0,0 +1,198 @@
+// Offer/answer bookkeeping for one torrent on one WebSocket tracker. We hand out offer IDs for
+// the embedder's WebRTC stack to create offers under, match answers coming back to them, and
+// turn offers relayed from other peers into connection requests. Offers nobody answers in time
+// are dropped so the stack can free their half-open connections.
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::tracker::{Offer, TrackerMessage, announce_message, answer_message};
+use crate::infohash::InfoHash;
+use crate::rng::Rng;
+use crate::tracker::AnnounceRequest;
+
+// How long an offer waits for an answer. Browsers give up on ICE around this point anyway
+pub const OFFER_TIMEOUT: Duration = Duration::from_secs(50);
+
+// Offers per announce. Each one costs the WebRTC stack a pending connection
+pub const MAX_OFFERS: usize = 10;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum SignalEvent {
+    // A peer answered one of our offers: give the answer to the connection made for `offer_id`
+    Answered {
+        peer_id: [u8; 20],
+        offer_id: [u8; 20],
+        sdp: String,
+    },
+    // A peer wants to connect: create an answer for its offer and pass it to `answer`
+    Offered {
+        peer_id: [u8; 20],
+        offer_id: [u8; 20],
+        sdp: String,
+    },
+    Announced {
+        interval: Duration,
+        seeders: Option<u32>,
+        leechers: Option<u32>,
+    },
+}
+
+#[derive(Debug)]
+pub struct Signaling {
+    info_hash: InfoHash,
+    peer_id: [u8; 20],
+    // Our offers waiting for an answer, by offer ID
+    pending: HashMap<[u8; 20], Instant>,
+    rng: Rng,
+}
+
+impl Signaling {
+    pub fn new(info_hash: InfoHash, peer_id: [u8; 20]) -> Self {
+        Signaling {
+            info_hash,
+            peer_id,
+            pending: HashMap::new(),
+            rng: Rng::new(),
+        }
+    }
+
+    // Fresh IDs for the stack to create offers under, topping up to `MAX_OFFERS` pending
+    pub fn new_offer_ids(&mut self, now: Instant) -> Vec<[u8; 20]> {
+        let count = MAX_OFFERS.saturating_sub(self.pending.len());
+        (0..count)
+            .map(|_| {
+                let mut offer_id = [0; 20];
+                self.rng.fill(&mut offer_id);
+                self.pending.insert(offer_id, now);
+                offer_id
+            })
+            .collect()
+    }
+
+    // The announce to send once the stack has made offers for the IDs from `new_offer_ids`
+    pub fn announce(&self, request: &AnnounceRequest, offers: &[Offer]) -> String {
+        announce_message(request, offers)
+    }
+
+    pub fn answer(&self, to_peer_id: [u8; 20], offer_id: [u8; 20], sdp: &str) -> String {
+        answer_message(self.info_hash, self.peer_id, to_peer_id, offer_id, sdp)
+    }
+
+    pub fn handle(&mut self, message: TrackerMessage) -> Option<SignalEvent> {
+        match message {
+            TrackerMessage::Announced {
+                info_hash,
+                interval,
+                seeders,
+                leechers,
+            } if info_hash == self.info_hash => Some(SignalEvent::Announced {
+                interval,
+                seeders,
+                leechers,
+            }),
+            TrackerMessage::Answer {
+                info_hash,
+                peer_id,
+                offer_id,
+                sdp,
+            } if info_hash == self.info_hash => {
+                // Late or duplicate answers have nothing left to attach to
+                self.pending.remove(&offer_id)?;
+                Some(SignalEvent::Answered {
+                    peer_id,
+                    offer_id,
+                    sdp,
+                })
+            }
+            // Trackers have been known to relay our own offers back to us
+            TrackerMessage::Offer {
+                info_hash,
+                peer_id,
+                offer_id,
+                sdp,
+            } if info_hash == self.info_hash && peer_id != self.peer_id => {
+                Some(SignalEvent::Offered {
+                    peer_id,
+                    offer_id,
+                    sdp,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    // Offers that went unanswered. The stack should close their connections
+    pub fn expire(&mut self, now: Instant) -> Vec<[u8; 20]> {
+        let expired: Vec<[u8; 20]> = self
+            .pending
+            .iter()
+            .filter(|(_, made)| now.saturating_duration_since(**made) >= OFFER_TIMEOUT)
+            .map(|(offer_id, _)| *offer_id)
+            .collect();
+        for offer_id in &expired {
+            self.pending.remove(offer_id);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    const INFO_HASH: InfoHash = InfoHash([1; 20]);
+
+    #[test]
+    fn test_answer_matches_offer() {
+        let now = Instant::now();
+        let mut signaling = Signaling::new(INFO_HASH, [2; 20]);
+        let ids = signaling.new_offer_ids(now);
+        assert_eq!(ids.len(), MAX_OFFERS);
+        assert!(signaling.new_offer_ids(now).is_empty());
+
+        let answer = |offer_id| TrackerMessage::Answer {
+            info_hash: INFO_HASH,
+            peer_id: [3; 20],
+            offer_id,
+            sdp: "a".to_string(),
+        };
+        assert_eq!(
+            signaling.handle(answer(ids[0])),
+            Some(SignalEvent::Answered {
+                peer_id: [3; 20],
+                offer_id: ids[0],
+                sdp: "a".to_string()
+            })
+        );
+        assert_eq!(signaling.handle(answer(ids[0])), None);
+        assert_eq!(signaling.new_offer_ids(now).len(), 1);
+    }
+
+    #[test]
+    fn test_ignores_own_offers() {
+        let mut signaling = Signaling::new(INFO_HASH, [2; 20]);
+        let offer = |peer_id| TrackerMessage::Offer {
+            info_hash: INFO_HASH,
+            peer_id,
+            offer_id: [4; 20],
+            sdp: "o".to_string(),
+        };
+
+        assert_eq!(signaling.handle(offer([2; 20])), None);
+        assert!(matches!(
+            signaling.handle(offer([3; 20])),
+            Some(SignalEvent::Offered { .. })
+        ));
+    }
+
+    #[test]
+    fn test_offers_expire() {
+        let now = Instant::now();
+        let mut signaling = Signaling::new(INFO_HASH, [2; 20]);
+        let ids = signaling.new_offer_ids(now);
+
+        assert!(signaling.expire(now + Duration::from_secs(1)).is_empty());
+        assert_eq!(signaling.expire(now + OFFER_TIMEOUT).len(), ids.len());
+        assert_eq!(signaling.new_offer_ids(now).len(), MAX_OFFERS);
+    }
+}
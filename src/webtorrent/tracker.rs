@@ -0,0 +1,252 @@
+// The WebSocket tracker protocol. Everything is a JSON text message with "action": "announce".
+// Our announce carries SDP offers for the tracker to hand to other peers; what comes back is
+// either the tracker's own answer (interval, counts), an offer relayed from another peer, or an
+// answer to one of our offers. Our answers to relayed offers go back through the tracker too.
+use std::time::Duration;
+
+use serde_json::{Map, Value, json};
+
+use super::{WebTorrentError, from_binary_string, to_binary_string};
+use crate::infohash::InfoHash;
+use crate::tracker::{AnnounceEvent, AnnounceRequest};
+
+// One SDP offer we made, for the tracker to relay to a random peer
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Offer {
+    pub offer_id: [u8; 20],
+    pub sdp: String,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum TrackerMessage {
+    // The tracker's response to an announce
+    Announced {
+        info_hash: InfoHash,
+        interval: Duration,
+        seeders: Option<u32>,
+        leechers: Option<u32>,
+    },
+    // Another peer's offer. Answer it with `answer_message`
+    Offer {
+        info_hash: InfoHash,
+        peer_id: [u8; 20],
+        offer_id: [u8; 20],
+        sdp: String,
+    },
+    // A peer answered one of our offers
+    Answer {
+        info_hash: InfoHash,
+        peer_id: [u8; 20],
+        offer_id: [u8; 20],
+        sdp: String,
+    },
+    // Informational, the announce still went through
+    Warning(String),
+}
+
+pub fn announce_message(request: &AnnounceRequest, offers: &[Offer]) -> String {
+    let mut message = json!({
+        "action": "announce",
+        "info_hash": to_binary_string(&request.info_hash.0),
+        "peer_id": to_binary_string(&request.peer_id),
+        "uploaded": request.uploaded,
+        "downloaded": request.downloaded,
+        "left": request.left,
+        // One offer per peer we'd like, so never ask for more than we've offered
+        "numwant": offers.len(),
+        "offers": offers
+            .iter()
+            .map(|offer| json!({
+                "offer": {"type": "offer", "sdp": offer.sdp},
+                "offer_id": to_binary_string(&offer.offer_id),
+            }))
+            .collect::<Vec<_>>(),
+    });
+    let event = match request.event {
+        AnnounceEvent::None => None,
+        AnnounceEvent::Started => Some("started"),
+        AnnounceEvent::Completed => Some("completed"),
+        AnnounceEvent::Stopped => Some("stopped"),
+    };
+    if let Some(event) = event {
+        message["event"] = event.into();
+    }
+    message.to_string()
+}
+
+pub fn answer_message(
+    info_hash: InfoHash,
+    peer_id: [u8; 20],
+    to_peer_id: [u8; 20],
+    offer_id: [u8; 20],
+    sdp: &str,
+) -> String {
+    json!({
+        "action": "announce",
+        "info_hash": to_binary_string(&info_hash.0),
+        "peer_id": to_binary_string(&peer_id),
+        "to_peer_id": to_binary_string(&to_peer_id),
+        "offer_id": to_binary_string(&offer_id),
+        "answer": {"type": "answer", "sdp": sdp},
+    })
+    .to_string()
+}
+
+pub fn decode(text: &str) -> Result<TrackerMessage, WebTorrentError> {
+    let value: Value = serde_json::from_str(text)?;
+    let message = value.as_object().ok_or(WebTorrentError::InvalidJson)?;
+    if let Some(reason) = message.get("failure reason").and_then(|r| r.as_str()) {
+        return Err(WebTorrentError::Failure(reason.to_string()));
+    }
+    if let Some(warning) = message.get("warning message").and_then(|w| w.as_str()) {
+        return Ok(TrackerMessage::Warning(warning.to_string()));
+    }
+    if message.get("action").and_then(|a| a.as_str()) != Some("announce") {
+        return Err(WebTorrentError::InvalidField("action"));
+    }
+
+    let info_hash = InfoHash(id(message, "info_hash")?);
+    let sdp = |key: &'static str| {
+        message
+            .get(key)
+            .and_then(|v| v.get("sdp"))
+            .and_then(|sdp| sdp.as_str())
+            .map(str::to_string)
+            .ok_or(WebTorrentError::InvalidField(key))
+    };
+    if message.contains_key("offer") {
+        return Ok(TrackerMessage::Offer {
+            info_hash,
+            peer_id: id(message, "peer_id")?,
+            offer_id: id(message, "offer_id")?,
+            sdp: sdp("offer")?,
+        });
+    }
+    if message.contains_key("answer") {
+        return Ok(TrackerMessage::Answer {
+            info_hash,
+            peer_id: id(message, "peer_id")?,
+            offer_id: id(message, "offer_id")?,
+            sdp: sdp("answer")?,
+        });
+    }
+
+    let count = |key: &str| {
+        message
+            .get(key)
+            .and_then(|v| v.as_u64())
+            .and_then(|v| u32::try_from(v).ok())
+    };
+    let interval = message
+        .get("interval")
+        .and_then(|v| v.as_u64())
+        .ok_or(WebTorrentError::MissingField("interval"))?;
+    Ok(TrackerMessage::Announced {
+        info_hash,
+        interval: Duration::from_secs(interval),
+        seeders: count("complete"),
+        leechers: count("incomplete"),
+    })
+}
+
+fn id(message: &Map<String, Value>, key: &'static str) -> Result<[u8; 20], WebTorrentError> {
+    let s = message
+        .get(key)
+        .and_then(|v| v.as_str())
+        .ok_or(WebTorrentError::MissingField(key))?;
+    from_binary_string(s)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(WebTorrentError::InvalidField(key))
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn request() -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: InfoHash([0xff; 20]),
+            peer_id: *b"-HU0010-abcdefghijkl",
+            port: 0,
+            uploaded: 0,
+            downloaded: 0,
+            left: 100,
+            event: AnnounceEvent::Started,
+            num_want: None,
+            key: 0,
+            ipv4: None,
+            ipv6: None,
+        }
+    }
+
+    #[test]
+    fn test_announce_message() {
+        let offer = Offer {
+            offer_id: [1; 20],
+            sdp: "v=0".to_string(),
+        };
+        let message: Value = serde_json::from_str(&announce_message(&request(), &[offer])).unwrap();
+
+        assert_eq!(message["info_hash"], "\u{ff}".repeat(20));
+        assert_eq!(message["event"], "started");
+        assert_eq!(message["numwant"], 1);
+        assert_eq!(message["offers"][0]["offer"]["sdp"], "v=0");
+        assert_eq!(message["offers"][0]["offer_id"], "\u{1}".repeat(20));
+    }
+
+    #[test]
+    fn test_decode_relayed() {
+        let offer = json!({
+            "action": "announce",
+            "info_hash": "\u{ff}".repeat(20),
+            "peer_id": "p".repeat(20),
+            "offer_id": "o".repeat(20),
+            "offer": {"type": "offer", "sdp": "v=0"},
+        });
+
+        assert_eq!(
+            decode(&offer.to_string()),
+            Ok(TrackerMessage::Offer {
+                info_hash: InfoHash([0xff; 20]),
+                peer_id: [b'p'; 20],
+                offer_id: [b'o'; 20],
+                sdp: "v=0".to_string(),
+            })
+        );
+
+        let answer = answer_message(
+            InfoHash([0xff; 20]),
+            [b'q'; 20],
+            [b'p'; 20],
+            [b'o'; 20],
+            "a",
+        );
+        let Ok(TrackerMessage::Answer { peer_id, sdp, .. }) = decode(&answer) else {
+            panic!("expected an answer");
+        };
+        assert_eq!((peer_id, sdp.as_str()), ([b'q'; 20], "a"));
+    }
+
+    #[test]
+    fn test_decode_announced() {
+        let text = format!(
+            r#"{{"action":"announce","interval":120,"info_hash":"{}","complete":3,"incomplete":4}}"#,
+            "a".repeat(20)
+        );
+
+        assert_eq!(
+            decode(&text),
+            Ok(TrackerMessage::Announced {
+                info_hash: InfoHash([b'a'; 20]),
+                interval: Duration::from_secs(120),
+                seeders: Some(3),
+                leechers: Some(4),
+            })
+        );
+        assert_eq!(
+            decode(r#"{"failure reason":"invalid info_hash"}"#),
+            Err(WebTorrentError::Failure("invalid info_hash".to_string()))
+        );
+        assert_eq!(decode("[1]"), Err(WebTorrentError::InvalidJson));
+    }
+}
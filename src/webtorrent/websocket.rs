@@ -0,0 +1,358 @@
+// Client side of WebSocket (RFC 6455), just enough for tracker connections: the HTTP upgrade,
+// masked frames out, text and binary messages in with fragments put back together, pings
+// answered and closes echoed. Sans-IO like the DHT: feed it bytes, drain `poll_transmit`.
+use std::collections::VecDeque;
+
+use sha1::{Digest, Sha1};
+
+use super::WebTorrentError;
+use crate::rng::Rng;
+
+// Appended to the client's key before hashing, per the RFC
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Tracker messages are a few KiB of SDP at most
+pub const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+// Longest response to the upgrade request we'll wait for
+const MAX_HANDSHAKE_LEN: usize = 16 * 1024;
+
+pub const OP_CONTINUATION: u8 = 0x0;
+pub const OP_TEXT: u8 = 0x1;
+pub const OP_BINARY: u8 = 0x2;
+pub const OP_CLOSE: u8 = 0x8;
+pub const OP_PING: u8 = 0x9;
+pub const OP_PONG: u8 = 0xa;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum WsMessage {
+    Text(String),
+    Binary(Vec<u8>),
+    // The server closed the connection. Our close has been queued in reply
+    Close,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+// Client frames are always masked, with a fresh key per frame
+pub fn encode_frame(opcode: u8, payload: &[u8], mask: [u8; 4]) -> Vec<u8> {
+    let mut buf = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..=125 => buf.push(0x80 | len as u8),
+        len @ 126..=0xffff => {
+            buf.push(0x80 | 126);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            buf.push(0x80 | 127);
+            buf.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    buf.extend_from_slice(&mask);
+    buf.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    buf
+}
+
+// None if the buffer doesn't hold a whole frame yet
+pub fn decode_frame(buf: &[u8]) -> Result<Option<(Frame, usize)>, WebTorrentError> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let masked = buf[1] & 0x80 != 0;
+    let (len, mut at) = match buf[1] & 0x7f {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() >= 10 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()), 10),
+        126 | 127 => return Ok(None),
+        len => (len as u64, 2),
+    };
+    if len > MAX_MESSAGE_LEN as u64 {
+        return Err(WebTorrentError::WebSocket("frame too large"));
+    }
+    let mask = if masked {
+        if buf.len() < at + 4 {
+            return Ok(None);
+        }
+        at += 4;
+        Some(<[u8; 4]>::try_from(&buf[at - 4..at]).unwrap())
+    } else {
+        None
+    };
+    let end = at + len as usize;
+    if buf.len() < end {
+        return Ok(None);
+    }
+
+    let mut payload = buf[at..end].to_vec();
+    if let Some(mask) = mask {
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+    }
+    let frame = Frame {
+        fin: buf[0] & 0x80 != 0,
+        opcode: buf[0] & 0x0f,
+        payload,
+    };
+    Ok(Some((frame, end)))
+}
+
+#[derive(Debug)]
+pub struct WebSocket {
+    // Sec-WebSocket-Key we sent, base64
+    key: String,
+    open: bool,
+    closed: bool,
+    inbound: Vec<u8>,
+    // A fragmented message so far, and its opcode
+    fragments: Option<(u8, Vec<u8>)>,
+    outbound: VecDeque<Vec<u8>>,
+    // Frames sent before the upgrade went through, which the server mustn't see before then
+    waiting: Vec<Vec<u8>>,
+    rng: Rng,
+}
+
+impl WebSocket {
+    // Queues the upgrade request. `path` includes the query, if any
+    pub fn new(host: &str, path: &str) -> Self {
+        let mut rng = Rng::new();
+        let mut key = [0; 16];
+        rng.fill(&mut key);
+        let key = base64(&key);
+        let path = if path.is_empty() { "/" } else { path };
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            path, host, key
+        );
+
+        WebSocket {
+            key,
+            open: false,
+            closed: false,
+            inbound: vec![],
+            fragments: None,
+            outbound: VecDeque::from([request.into_bytes()]),
+            waiting: vec![],
+            rng,
+        }
+    }
+
+    // The server accepted the upgrade. Messages sent before this are queued
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    pub fn send_text(&mut self, text: &str) {
+        self.send(OP_TEXT, text.as_bytes());
+    }
+
+    pub fn close(&mut self) {
+        if !self.closed {
+            self.send(OP_CLOSE, &[]);
+            self.closed = true;
+        }
+    }
+
+    pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+        self.outbound.pop_front()
+    }
+
+    pub fn handle_input(&mut self, buf: &[u8]) {
+        self.inbound.extend_from_slice(buf);
+    }
+
+    // The next complete message, once the upgrade is through
+    pub fn poll_message(&mut self) -> Result<Option<WsMessage>, WebTorrentError> {
+        if !self.open && !self.finish_handshake()? {
+            return Ok(None);
+        }
+
+        while let Some((frame, len)) = decode_frame(&self.inbound)? {
+            self.inbound.drain(..len);
+            match frame.opcode {
+                OP_PING => self.send(OP_PONG, &frame.payload),
+                OP_PONG => {}
+                OP_CLOSE => {
+                    self.close();
+                    return Ok(Some(WsMessage::Close));
+                }
+                OP_TEXT | OP_BINARY | OP_CONTINUATION => {
+                    let (opcode, payload) = match (frame.opcode, self.fragments.take()) {
+                        (OP_CONTINUATION, Some((opcode, mut buf))) => {
+                            buf.extend_from_slice(&frame.payload);
+                            (opcode, buf)
+                        }
+                        (OP_CONTINUATION, None) => {
+                            return Err(WebTorrentError::WebSocket("unexpected continuation"));
+                        }
+                        (opcode, _) => (opcode, frame.payload),
+                    };
+                    if payload.len() > MAX_MESSAGE_LEN {
+                        return Err(WebTorrentError::WebSocket("message too large"));
+                    }
+                    if !frame.fin {
+                        self.fragments = Some((opcode, payload));
+                        continue;
+                    }
+                    if opcode == OP_BINARY {
+                        return Ok(Some(WsMessage::Binary(payload)));
+                    }
+                    return String::from_utf8(payload)
+                        .map(|text| Some(WsMessage::Text(text)))
+                        .map_err(|_| WebTorrentError::WebSocket("text isn't UTF-8"));
+                }
+                _ => return Err(WebTorrentError::WebSocket("unknown opcode")),
+            }
+        }
+        Ok(None)
+    }
+
+    fn send(&mut self, opcode: u8, payload: &[u8]) {
+        if self.closed {
+            return;
+        }
+        let mask = (self.rng.next_u64() as u32).to_be_bytes();
+        let frame = encode_frame(opcode, payload, mask);
+        if self.open {
+            self.outbound.push_back(frame);
+        } else {
+            self.waiting.push(frame);
+        }
+    }
+
+    fn finish_handshake(&mut self) -> Result<bool, WebTorrentError> {
+        let Some(end) = self.inbound.windows(4).position(|w| w == b"\r\n\r\n") else {
+            if self.inbound.len() > MAX_HANDSHAKE_LEN {
+                return Err(WebTorrentError::WebSocket("handshake too large"));
+            }
+            return Ok(false);
+        };
+        let response = String::from_utf8_lossy(&self.inbound[..end]).into_owned();
+        self.inbound.drain(..end + 4);
+
+        let mut lines = response.split("\r\n");
+        if lines.next().and_then(|line| line.split(' ').nth(1)) != Some("101") {
+            return Err(WebTorrentError::WebSocket("upgrade refused"));
+        }
+        let accept = lines.find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("sec-websocket-accept")
+                .then(|| value.trim())
+        });
+        let expected = base64(&Sha1::digest(format!("{}{}", self.key, ACCEPT_GUID)));
+        if accept != Some(expected.as_str()) {
+            return Err(WebTorrentError::WebSocket("bad accept key"));
+        }
+        self.open = true;
+        self.outbound.extend(self.waiting.drain(..));
+        Ok(true)
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(CHARS[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    // A server frame, which is never masked
+    fn server_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut buf = encode_frame(opcode, payload, [0; 4]);
+        buf[0] = if fin { 0x80 | opcode } else { opcode };
+        buf[1] &= 0x7f;
+        buf.drain(buf.len() - payload.len() - 4..buf.len() - payload.len());
+        buf
+    }
+
+    fn accepted(ws: &WebSocket) -> Vec<u8> {
+        let accept = base64(&Sha1::digest(format!("{}{}", ws.key, ACCEPT_GUID)));
+        format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn test_base64() {
+        // The example from RFC 6455
+        let accept = Sha1::digest(format!("dGhlIHNhbXBsZSBub25jZQ=={}", ACCEPT_GUID));
+        assert_eq!(base64(&accept), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(base64(b"ab"), "YWI=");
+    }
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let payload = vec![7; 300];
+        let buf = encode_frame(OP_BINARY, &payload, [1, 2, 3, 4]);
+
+        assert_eq!(buf[1], 0x80 | 126);
+        assert_eq!(decode_frame(&buf[..100]), Ok(None));
+        let (frame, len) = decode_frame(&buf).unwrap().unwrap();
+        assert_eq!(len, buf.len());
+        assert_eq!(frame.payload, payload);
+        assert!(frame.fin);
+    }
+
+    #[test]
+    fn test_handshake_and_messages() {
+        let mut ws = WebSocket::new("tracker.example", "/announce");
+        let request = String::from_utf8(ws.poll_transmit().unwrap()).unwrap();
+        assert!(request.starts_with("GET /announce HTTP/1.1\r\nHost: tracker.example\r\n"));
+        ws.send_text("queued");
+        assert_eq!(ws.poll_transmit(), None);
+
+        let mut input = accepted(&ws);
+        input.extend(server_frame(false, OP_TEXT, b"hel"));
+        input.extend(server_frame(true, OP_PING, b"p"));
+        input.extend(server_frame(true, OP_CONTINUATION, b"lo"));
+        ws.handle_input(&input);
+
+        assert_eq!(
+            ws.poll_message(),
+            Ok(Some(WsMessage::Text("hello".to_string())))
+        );
+        assert!(ws.is_open());
+        let (queued, _) = decode_frame(&ws.poll_transmit().unwrap()).unwrap().unwrap();
+        assert_eq!(queued.payload, b"queued");
+        let (pong, _) = decode_frame(&ws.poll_transmit().unwrap()).unwrap().unwrap();
+        assert_eq!((pong.opcode, pong.payload), (OP_PONG, b"p".to_vec()));
+    }
+
+    #[test]
+    fn test_bad_accept_key() {
+        let mut ws = WebSocket::new("t", "/");
+        ws.handle_input(b"HTTP/1.1 101 OK\r\nSec-WebSocket-Accept: nope\r\n\r\n");
+
+        assert_eq!(
+            ws.poll_message(),
+            Err(WebTorrentError::WebSocket("bad accept key"))
+        );
+    }
+}
@@ -0,0 +1,316 @@
+// Two WebTorrent peers in one process over a real WebRTC stack, the `webrtc` crate: a leecher
+// offers a connection through the tracker signaling, the seed answers it, and once the data
+// channel is open the leecher downloads a piece over the wire protocol.
+//
+//     cd webtorrent-webrtc && cargo run
+//
+// The tracker is a stand-in that relays the offer and the answer in-process, the way a WebSocket
+// tracker would. Against a real one the same messages go over `webtorrent::websocket`.
+use std::sync::Arc;
+use std::time::Instant;
+
+use bytes::Bytes;
+use serde_json::{Value, json};
+use tokio::sync::{mpsc, oneshot};
+use webrtc::api::APIBuilder;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+use hurricane::infohash::InfoHash;
+use hurricane::peer::Block;
+use hurricane::peer::handshake::{Handshake, Reserved, generate_peer_id};
+use hurricane::peer::message::Message;
+use hurricane::rng::Rng;
+use hurricane::tracker::{AnnounceEvent, AnnounceRequest};
+use hurricane::webtorrent::channel::{ChannelStream, chunks};
+use hurricane::webtorrent::signal::{SignalEvent, Signaling};
+use hurricane::webtorrent::tracker::{self, Offer};
+
+const PIECE_LEN: usize = 64 * 1024;
+const BLOCK_LEN: u32 = 16 * 1024;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let mut rng = Rng::new();
+    // No .torrent here: a made-up info-hash and one piece of random data
+    let info_hash = InfoHash([7; 20]);
+    let mut piece = vec![0; PIECE_LEN];
+    rng.fill(&mut piece);
+    let seed_id = generate_peer_id(&mut rng);
+    let leech_id = generate_peer_id(&mut rng);
+    let mut seed_signal = Signaling::new(info_hash, seed_id);
+    let mut leech_signal = Signaling::new(info_hash, leech_id);
+
+    // The leecher's announce, with an offer for the tracker to hand out. One is plenty here; the
+    // other IDs expire unanswered
+    let offer_id = leech_signal.new_offer_ids(Instant::now())[0];
+    let leech = connection().await?;
+    let leech_channel = Channel::new(leech.create_data_channel("webtorrent", None).await?);
+    let sdp = local_sdp(&leech, leech.create_offer(None).await?).await?;
+    let request = AnnounceRequest {
+        info_hash,
+        peer_id: leech_id,
+        port: 0,
+        uploaded: 0,
+        downloaded: 0,
+        left: PIECE_LEN as u64,
+        event: AnnounceEvent::Started,
+        num_want: None,
+        key: rng.next_u64() as u32,
+        ipv4: None,
+        ipv6: None,
+    };
+    let announce = leech_signal.announce(&request, &[Offer { offer_id, sdp }]);
+
+    // The seed answers what the tracker relayed
+    let relayed = tracker::decode(&relay_offer(&announce)?).map_err(debug)?;
+    let Some(SignalEvent::Offered {
+        peer_id,
+        offer_id,
+        sdp,
+    }) = seed_signal.handle(relayed)
+    else {
+        return Err("the seed didn't get the offer".into());
+    };
+    let seed = connection().await?;
+    let (channels, mut incoming) = mpsc::unbounded_channel();
+    seed.on_data_channel(Box::new(move |data_channel| {
+        // Right away, so nothing the leecher sends once it's open gets missed
+        let _ = channels.send(Channel::new(data_channel));
+        Box::pin(async {})
+    }));
+    seed.set_remote_description(RTCSessionDescription::offer(sdp)?)
+        .await?;
+    let sdp = local_sdp(&seed, seed.create_answer(None).await?).await?;
+    let answer = seed_signal.answer(peer_id, offer_id, &sdp);
+
+    // And the answer goes back to the leecher
+    let relayed = tracker::decode(&relay_answer(&answer)?).map_err(debug)?;
+    let Some(SignalEvent::Answered { sdp, .. }) = leech_signal.handle(relayed) else {
+        return Err("the leecher didn't get the answer".into());
+    };
+    leech
+        .set_remote_description(RTCSessionDescription::answer(sdp)?)
+        .await?;
+
+    let seed_channel = incoming.recv().await.ok_or("no data channel")?;
+    let seeding = tokio::spawn(serve(seed_channel, info_hash, seed_id, piece.clone()));
+    let downloaded = download(leech_channel, info_hash, leech_id).await?;
+    seeding.await??;
+    leech.close().await?;
+    seed.close().await?;
+
+    if downloaded != piece {
+        return Err("the piece came through wrong".into());
+    }
+    println!(
+        "downloaded {} bytes over a WebRTC data channel",
+        downloaded.len()
+    );
+    Ok(())
+}
+
+async fn connection() -> Result<RTCPeerConnection, Error> {
+    let api = APIBuilder::new().build();
+    Ok(api.new_peer_connection(RTCConfiguration::default()).await?)
+}
+
+// WebTorrent doesn't trickle ICE: the SDP that goes to the tracker has every candidate in it
+async fn local_sdp(
+    connection: &RTCPeerConnection,
+    description: RTCSessionDescription,
+) -> Result<String, Error> {
+    let mut gathered = connection.gathering_complete_promise().await;
+    connection.set_local_description(description).await?;
+    let _ = gathered.recv().await;
+    let description = connection.local_description().await;
+    Ok(description.ok_or("no local description")?.sdp)
+}
+
+// What the tracker does with an announce's offers: each goes to some other peer in the swarm,
+// from the peer that announced. There's only the one peer here
+fn relay_offer(announce: &str) -> Result<String, Error> {
+    let announce: Value = serde_json::from_str(announce)?;
+    let offer = &announce["offers"][0];
+    Ok(json!({
+        "action": "announce",
+        "info_hash": announce["info_hash"],
+        "peer_id": announce["peer_id"],
+        "offer_id": offer["offer_id"],
+        "offer": offer["offer"],
+    })
+    .to_string())
+}
+
+// Answers go to their `to_peer_id` as they are
+fn relay_answer(answer: &str) -> Result<String, Error> {
+    let mut answer: Value = serde_json::from_str(answer)?;
+    answer.as_object_mut().and_then(|a| a.remove("to_peer_id"));
+    Ok(answer.to_string())
+}
+
+// Hands out the piece to whoever asks, until the channel closes
+async fn serve(
+    mut channel: Channel,
+    info_hash: InfoHash,
+    peer_id: [u8; 20],
+    piece: Vec<u8>,
+) -> Result<(), Error> {
+    channel.opened().await?;
+    let theirs = channel.handshake().await?;
+    if theirs.info_hash != info_hash {
+        return Err("handshake for another torrent".into());
+    }
+    let ours = Handshake::new(Reserved::default(), info_hash, peer_id);
+    channel.send(&ours.encode()).await?;
+    channel
+        .send(&Message::Bitfield(vec![0x80]).encode())
+        .await?;
+
+    while let Some(message) = channel.message().await? {
+        let reply = match message {
+            Message::Interested => Message::Unchoke,
+            Message::Request(block) => {
+                let start = block.offset as usize;
+                let data = piece.get(start..start + block.length as usize);
+                Message::Piece {
+                    piece: block.piece,
+                    offset: block.offset,
+                    data: data.ok_or("request past the piece")?.to_vec(),
+                }
+            }
+            _ => continue,
+        };
+        channel.send(&reply.encode()).await?;
+    }
+    Ok(())
+}
+
+// Asks the seed for the whole piece once it's unchoked us
+async fn download(
+    mut channel: Channel,
+    info_hash: InfoHash,
+    peer_id: [u8; 20],
+) -> Result<Vec<u8>, Error> {
+    channel.opened().await?;
+    let ours = Handshake::new(Reserved::default(), info_hash, peer_id);
+    channel.send(&ours.encode()).await?;
+    channel.handshake().await?;
+    channel.send(&Message::Interested.encode()).await?;
+
+    let mut piece = vec![0; PIECE_LEN];
+    let mut missing = PIECE_LEN;
+    while missing > 0 {
+        match channel.message().await?.ok_or("the seed hung up")? {
+            Message::Unchoke => {
+                for offset in (0..PIECE_LEN as u32).step_by(BLOCK_LEN as usize) {
+                    let block = Block {
+                        piece: 0,
+                        offset,
+                        length: BLOCK_LEN,
+                    };
+                    channel.send(&Message::Request(block).encode()).await?;
+                }
+            }
+            Message::Piece { offset, data, .. } => {
+                piece[offset as usize..offset as usize + data.len()].copy_from_slice(&data);
+                missing -= data.len();
+            }
+            _ => {}
+        }
+    }
+    channel.data_channel.close().await?;
+    Ok(piece)
+}
+
+// A data channel carrying the wire protocol: what arrives goes through a `ChannelStream`, what
+// we send is cut with `chunks`
+struct Channel {
+    data_channel: Arc<RTCDataChannel>,
+    open: Option<oneshot::Receiver<()>>,
+    inbox: mpsc::UnboundedReceiver<Bytes>,
+    stream: ChannelStream,
+}
+
+impl Channel {
+    fn new(data_channel: Arc<RTCDataChannel>) -> Self {
+        let (sender, inbox) = mpsc::unbounded_channel();
+        data_channel.on_message(Box::new(move |message: DataChannelMessage| {
+            let _ = sender.send(message.data);
+            Box::pin(async {})
+        }));
+        // The channel closing drops the sender, which ends `inbox`
+        let closing = data_channel.clone();
+        data_channel.on_close(Box::new(move || {
+            closing.on_message(Box::new(|_| Box::pin(async {})));
+            Box::pin(async {})
+        }));
+        let (opened, open) = oneshot::channel();
+        let mut opened = Some(opened);
+        data_channel.on_open(Box::new(move || {
+            if let Some(opened) = opened.take() {
+                let _ = opened.send(());
+            }
+            Box::pin(async {})
+        }));
+        Channel {
+            data_channel,
+            open: Some(open),
+            inbox,
+            stream: ChannelStream::new(),
+        }
+    }
+
+    async fn opened(&mut self) -> Result<(), Error> {
+        if let Some(open) = self.open.take() {
+            open.await?;
+        }
+        Ok(())
+    }
+
+    async fn send(&self, bytes: &[u8]) -> Result<(), Error> {
+        for chunk in chunks(bytes) {
+            self.data_channel
+                .send(&Bytes::copy_from_slice(chunk))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn handshake(&mut self) -> Result<Handshake, Error> {
+        loop {
+            if let Some(handshake) = self.stream.handshake().map_err(debug)? {
+                return Ok(handshake);
+            }
+            let data = self
+                .inbox
+                .recv()
+                .await
+                .ok_or("closed before the handshake")?;
+            self.stream.push(&data);
+        }
+    }
+
+    // None once the channel is closed
+    async fn message(&mut self) -> Result<Option<Message>, Error> {
+        loop {
+            if let Some(message) = self.stream.message().map_err(debug)? {
+                return Ok(Some(message));
+            }
+            match self.inbox.recv().await {
+                Some(data) => self.stream.push(&data),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+// Our errors only have Debug
+fn debug(err: impl std::fmt::Debug) -> Error {
+    format!("{:?}", err).into()
+}